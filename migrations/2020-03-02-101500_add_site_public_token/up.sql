@@ -0,0 +1,6 @@
+CREATE TABLE site_public_token (
+	site_id INTEGER NOT NULL,
+	token VARCHAR(64) NOT NULL UNIQUE,
+	PRIMARY KEY (site_id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
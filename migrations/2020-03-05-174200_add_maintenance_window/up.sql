@@ -0,0 +1,9 @@
+CREATE TABLE maintenance_window (
+	id SERIAL NOT NULL,
+	site_id INTEGER NOT NULL,
+	start_time TIMESTAMP NOT NULL,
+	end_time TIMESTAMP NOT NULL,
+	description VARCHAR(255),
+	PRIMARY KEY (id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+CREATE TABLE alarm_event (
+	id SERIAL NOT NULL,
+	channel_id INTEGER NOT NULL,
+	started_at TIMESTAMP NOT NULL,
+	ended_at TIMESTAMP,
+	peak_value DOUBLE PRECISION NOT NULL,
+	extreme_type CHAR NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
+
+CREATE INDEX alarm_event_channel_started_at ON alarm_event (channel_id, started_at);
@@ -0,0 +1,6 @@
+CREATE TABLE email_user_contact (
+	email VARCHAR(255) NOT NULL,
+	user_id INTEGER NOT NULL,
+	PRIMARY KEY (email),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+DROP TABLE modbus_register;
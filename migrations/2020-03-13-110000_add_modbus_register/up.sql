@@ -0,0 +1,13 @@
+CREATE TABLE modbus_register (
+	channel_id INTEGER NOT NULL,
+	host VARCHAR(255) NOT NULL,
+	port INTEGER NOT NULL DEFAULT 502,
+	unit_id INTEGER NOT NULL DEFAULT 1,
+	address INTEGER NOT NULL,
+	register_type CHAR(1) NOT NULL,
+	value_type CHAR(1) NOT NULL,
+	scale DOUBLE PRECISION NOT NULL DEFAULT 1,
+	value_offset DOUBLE PRECISION NOT NULL DEFAULT 0,
+	PRIMARY KEY (channel_id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
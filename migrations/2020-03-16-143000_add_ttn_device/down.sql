@@ -0,0 +1 @@
+DROP TABLE ttn_device;
@@ -0,0 +1,9 @@
+CREATE TABLE ttn_device (
+	sensor_id INTEGER NOT NULL,
+	device_id VARCHAR(255) NOT NULL,
+	payload_format CHAR(1) NOT NULL,
+	PRIMARY KEY (sensor_id),
+	FOREIGN KEY(sensor_id) REFERENCES sensor (id) ON DELETE CASCADE
+);
+
+CREATE INDEX ttn_device_device_id_idx ON ttn_device (device_id);
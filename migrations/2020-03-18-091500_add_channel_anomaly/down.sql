@@ -0,0 +1,2 @@
+DROP TABLE anomaly_scan;
+DROP TABLE channel_anomaly;
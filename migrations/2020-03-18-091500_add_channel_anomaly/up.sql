@@ -0,0 +1,19 @@
+CREATE TABLE channel_anomaly (
+	id SERIAL NOT NULL,
+	channel_id INTEGER NOT NULL,
+	started_at TIMESTAMP NOT NULL,
+	ended_at TIMESTAMP NOT NULL,
+	score DOUBLE PRECISION NOT NULL,
+	kind CHAR NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
+
+CREATE INDEX channel_anomaly_channel_started_at ON channel_anomaly (channel_id, started_at);
+
+CREATE TABLE anomaly_scan (
+	channel_id INTEGER NOT NULL,
+	clock TIMESTAMP NOT NULL,
+	PRIMARY KEY (channel_id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE peer_group_channel;
+DROP TABLE peer_group;
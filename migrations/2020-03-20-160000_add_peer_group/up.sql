@@ -0,0 +1,13 @@
+CREATE TABLE peer_group (
+	id SERIAL NOT NULL,
+	name VARCHAR(255) NOT NULL,
+	PRIMARY KEY (id)
+);
+
+CREATE TABLE peer_group_channel (
+	peer_group_id INTEGER NOT NULL,
+	channel_id INTEGER NOT NULL,
+	PRIMARY KEY (peer_group_id, channel_id),
+	FOREIGN KEY(peer_group_id) REFERENCES peer_group (id) ON DELETE CASCADE,
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+CREATE TABLE reading_annotation (
+	id SERIAL NOT NULL,
+	channel_id INTEGER NOT NULL,
+	start_time TIMESTAMP NOT NULL,
+	end_time TIMESTAMP NOT NULL,
+	reason VARCHAR(255) NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
+
+CREATE INDEX reading_annotation_channel_start_time ON reading_annotation (channel_id, start_time);
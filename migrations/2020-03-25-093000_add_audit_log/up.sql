@@ -0,0 +1,12 @@
+CREATE TABLE audit_log (
+	id SERIAL NOT NULL,
+	user_id INTEGER,
+	username VARCHAR(32) NOT NULL,
+	action VARCHAR(64) NOT NULL,
+	target VARCHAR(255) NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL
+);
+
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
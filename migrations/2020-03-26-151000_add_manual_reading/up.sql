@@ -0,0 +1,13 @@
+CREATE TABLE manual_reading (
+	id SERIAL NOT NULL,
+	channel_id INTEGER NOT NULL,
+	user_id INTEGER,
+	taken_at TIMESTAMP NOT NULL,
+	value DOUBLE PRECISION NOT NULL,
+	note VARCHAR(255),
+	PRIMARY KEY (id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE,
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL
+);
+
+CREATE INDEX manual_reading_channel_taken_at ON manual_reading (channel_id, taken_at);
@@ -0,0 +1,12 @@
+CREATE TABLE sensor_calibration (
+	id SERIAL NOT NULL,
+	sensor_id INTEGER NOT NULL,
+	calibrated_at TIMESTAMP NOT NULL,
+	certificate_id VARCHAR(255) NOT NULL,
+	next_due_at TIMESTAMP NOT NULL,
+	reminder_sent BOOLEAN NOT NULL DEFAULT FALSE,
+	PRIMARY KEY (id),
+	FOREIGN KEY(sensor_id) REFERENCES sensor (id) ON DELETE CASCADE
+);
+
+CREATE INDEX sensor_calibration_sensor_calibrated_at ON sensor_calibration (sensor_id, calibrated_at);
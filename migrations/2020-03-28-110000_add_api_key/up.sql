@@ -0,0 +1,11 @@
+CREATE TABLE api_key (
+	id SERIAL NOT NULL,
+	name VARCHAR(64) NOT NULL,
+	key_hash CHAR(64) NOT NULL,
+	site_id INTEGER,
+	created_at TIMESTAMP NOT NULL,
+	revoked_at TIMESTAMP,
+	PRIMARY KEY (id),
+	UNIQUE (key_hash),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
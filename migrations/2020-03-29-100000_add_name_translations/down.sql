@@ -0,0 +1,3 @@
+ALTER TABLE channel DROP COLUMN name_translations;
+ALTER TABLE sensor DROP COLUMN name_translations;
+ALTER TABLE site DROP COLUMN name_translations;
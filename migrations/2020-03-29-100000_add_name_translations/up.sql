@@ -0,0 +1,3 @@
+ALTER TABLE site ADD COLUMN name_translations JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE sensor ADD COLUMN name_translations JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE channel ADD COLUMN name_translations JSONB NOT NULL DEFAULT '{}';
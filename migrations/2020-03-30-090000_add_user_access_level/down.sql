@@ -0,0 +1 @@
+ALTER TABLE user_access DROP COLUMN level;
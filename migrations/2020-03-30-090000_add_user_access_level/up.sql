@@ -0,0 +1 @@
+ALTER TABLE user_access ADD COLUMN level CHAR NOT NULL DEFAULT 'v';
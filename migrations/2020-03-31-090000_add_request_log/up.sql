@@ -0,0 +1,14 @@
+CREATE TABLE request_log (
+	id SERIAL NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	user_id INTEGER,
+	site_id INTEGER,
+	operation_name VARCHAR(128),
+	complexity BIGINT NOT NULL,
+	row_count BIGINT NOT NULL,
+	duration_ms INTEGER NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL,
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE SET NULL
+);
+CREATE INDEX request_log_created_at_idx ON request_log (created_at);
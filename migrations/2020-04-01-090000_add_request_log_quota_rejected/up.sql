@@ -0,0 +1 @@
+ALTER TABLE request_log ADD COLUMN quota_rejected BOOLEAN NOT NULL DEFAULT FALSE;
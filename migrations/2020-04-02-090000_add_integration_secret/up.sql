@@ -0,0 +1,7 @@
+CREATE TABLE integration_secret (
+	name VARCHAR(64) NOT NULL,
+	nonce BYTEA NOT NULL,
+	ciphertext BYTEA NOT NULL,
+	updated_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (name)
+);
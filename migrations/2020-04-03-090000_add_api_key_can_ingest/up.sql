@@ -0,0 +1 @@
+ALTER TABLE api_key ADD COLUMN can_ingest BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+DROP TABLE channel_mute;
+
+ALTER TABLE alarm_event DROP COLUMN acknowledge_note;
+ALTER TABLE alarm_event DROP COLUMN acknowledged_by;
+ALTER TABLE alarm_event DROP COLUMN acknowledged_at;
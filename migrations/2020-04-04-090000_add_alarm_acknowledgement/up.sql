@@ -0,0 +1,13 @@
+ALTER TABLE alarm_event ADD COLUMN acknowledged_at TIMESTAMP;
+ALTER TABLE alarm_event ADD COLUMN acknowledged_by INTEGER REFERENCES user_account (id) ON DELETE SET NULL;
+ALTER TABLE alarm_event ADD COLUMN acknowledge_note VARCHAR(255);
+
+CREATE TABLE channel_mute (
+	channel_id INTEGER NOT NULL,
+	muted_until TIMESTAMP NOT NULL,
+	muted_by INTEGER,
+	created_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (channel_id),
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE,
+	FOREIGN KEY(muted_by) REFERENCES user_account (id) ON DELETE SET NULL
+);
@@ -0,0 +1,16 @@
+ALTER TABLE user_access ALTER COLUMN level DROP DEFAULT;
+ALTER TABLE user_access ALTER COLUMN level TYPE CHAR USING (CASE level
+	WHEN 'admin' THEN 'a'
+	WHEN 'manage' THEN 'm'
+	ELSE 'v'
+END);
+ALTER TABLE user_access ALTER COLUMN level SET DEFAULT 'v';
+
+ALTER TABLE user_account ALTER COLUMN permission TYPE CHAR USING (CASE permission
+	WHEN 'admin' THEN 'a'
+	WHEN 'site_manager' THEN 'm'
+	ELSE 'u'
+END);
+
+DROP TYPE access_level;
+DROP TYPE permission_type;
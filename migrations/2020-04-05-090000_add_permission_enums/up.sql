@@ -0,0 +1,17 @@
+CREATE TYPE permission_type AS ENUM ('user', 'site_manager', 'admin');
+CREATE TYPE access_level AS ENUM ('view', 'manage', 'admin');
+
+-- The unknown values had the lowest permission anyway
+ALTER TABLE user_account ALTER COLUMN permission TYPE permission_type USING (CASE permission
+	WHEN 'a' THEN 'admin'
+	WHEN 'm' THEN 'site_manager'
+	ELSE 'user'
+END)::permission_type;
+
+ALTER TABLE user_access ALTER COLUMN level DROP DEFAULT;
+ALTER TABLE user_access ALTER COLUMN level TYPE access_level USING (CASE level
+	WHEN 'a' THEN 'admin'
+	WHEN 'm' THEN 'manage'
+	ELSE 'view'
+END)::access_level;
+ALTER TABLE user_access ALTER COLUMN level SET DEFAULT 'view';
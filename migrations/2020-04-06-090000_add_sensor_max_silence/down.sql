@@ -0,0 +1,2 @@
+ALTER TABLE sensor DROP COLUMN no_data_since;
+ALTER TABLE sensor DROP COLUMN max_silence;
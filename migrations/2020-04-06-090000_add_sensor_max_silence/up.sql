@@ -0,0 +1,2 @@
+ALTER TABLE sensor ADD COLUMN max_silence INTEGER;
+ALTER TABLE sensor ADD COLUMN no_data_since TIMESTAMP;
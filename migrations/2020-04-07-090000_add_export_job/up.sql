@@ -0,0 +1,14 @@
+CREATE TABLE export_job (
+	id SERIAL NOT NULL,
+	user_id INTEGER,
+	channel_id INTEGER NOT NULL,
+	format VARCHAR(8) NOT NULL,
+	status CHAR NOT NULL,
+	row_count BIGINT NOT NULL,
+	error VARCHAR(255),
+	created_at TIMESTAMP NOT NULL,
+	finished_at TIMESTAMP,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL,
+	FOREIGN KEY(channel_id) REFERENCES channel (id) ON DELETE CASCADE
+);
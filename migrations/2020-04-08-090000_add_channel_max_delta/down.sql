@@ -0,0 +1 @@
+ALTER TABLE channel DROP COLUMN max_delta_per_hour;
@@ -0,0 +1 @@
+ALTER TABLE channel ADD COLUMN max_delta_per_hour NUMERIC;
@@ -0,0 +1,3 @@
+ALTER TABLE channel DROP COLUMN alarm_pending_since;
+ALTER TABLE channel DROP COLUMN alarm_delay;
+ALTER TABLE channel DROP COLUMN hysteresis;
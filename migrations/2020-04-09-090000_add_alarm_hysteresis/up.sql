@@ -0,0 +1,3 @@
+ALTER TABLE channel ADD COLUMN hysteresis NUMERIC;
+ALTER TABLE channel ADD COLUMN alarm_delay INTEGER;
+ALTER TABLE channel ADD COLUMN alarm_pending_since TIMESTAMP;
@@ -0,0 +1,8 @@
+CREATE TABLE site_webhook (
+	id SERIAL NOT NULL,
+	site_id INTEGER NOT NULL,
+	url VARCHAR(2048) NOT NULL,
+	secret VARCHAR(255) NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE alarm_escalation;
+DROP TABLE escalation_policy;
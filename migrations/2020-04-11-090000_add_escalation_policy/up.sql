@@ -0,0 +1,18 @@
+CREATE TABLE escalation_policy (
+	id SERIAL NOT NULL,
+	site_id INTEGER,
+	delay_minutes INTEGER NOT NULL,
+	target CHAR NOT NULL,
+	user_ids INTEGER[] NOT NULL DEFAULT '{}',
+	PRIMARY KEY (id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
+
+CREATE TABLE alarm_escalation (
+	alarm_event_id INTEGER NOT NULL,
+	policy_id INTEGER NOT NULL,
+	sent_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (alarm_event_id, policy_id),
+	FOREIGN KEY(alarm_event_id) REFERENCES alarm_event (id) ON DELETE CASCADE,
+	FOREIGN KEY(policy_id) REFERENCES escalation_policy (id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+CREATE TABLE user_preference (
+	user_id INTEGER NOT NULL,
+	key VARCHAR(255) NOT NULL,
+	value TEXT NOT NULL,
+	updated_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (user_id, key),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE CASCADE
+);
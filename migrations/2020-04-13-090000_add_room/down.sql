@@ -0,0 +1,2 @@
+ALTER TABLE sensor DROP COLUMN room_id;
+DROP TABLE room;
@@ -0,0 +1,12 @@
+CREATE TABLE room (
+	id SERIAL NOT NULL,
+	site_id INTEGER NOT NULL,
+	name VARCHAR(255) NOT NULL,
+	floor INTEGER,
+	loc_x INTEGER,
+	loc_y INTEGER,
+	PRIMARY KEY (id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
+
+ALTER TABLE sensor ADD COLUMN room_id INTEGER REFERENCES room (id) ON DELETE SET NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE sensor DROP COLUMN map_id;
+DROP TABLE site_map;
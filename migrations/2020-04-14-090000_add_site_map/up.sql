@@ -0,0 +1,11 @@
+CREATE TABLE site_map (
+	id SERIAL NOT NULL,
+	site_id INTEGER NOT NULL,
+	name VARCHAR(255) NOT NULL,
+	image_width INTEGER,
+	image_height INTEGER,
+	PRIMARY KEY (id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
+
+ALTER TABLE sensor ADD COLUMN map_id INTEGER REFERENCES site_map (id) ON DELETE SET NULL;
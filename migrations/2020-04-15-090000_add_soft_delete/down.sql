@@ -0,0 +1,3 @@
+ALTER TABLE channel DROP COLUMN deleted_at;
+ALTER TABLE sensor DROP COLUMN deleted_at;
+ALTER TABLE site DROP COLUMN deleted_at;
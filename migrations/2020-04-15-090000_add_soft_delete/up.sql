@@ -0,0 +1,3 @@
+ALTER TABLE site ADD COLUMN deleted_at TIMESTAMP;
+ALTER TABLE sensor ADD COLUMN deleted_at TIMESTAMP;
+ALTER TABLE channel ADD COLUMN deleted_at TIMESTAMP;
@@ -0,0 +1,10 @@
+CREATE TABLE pending_action (
+	id SERIAL NOT NULL,
+	action VARCHAR(32) NOT NULL,
+	target_id INTEGER NOT NULL,
+	requested_by INTEGER NOT NULL,
+	requested_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(requested_by) REFERENCES user_account (id) ON DELETE CASCADE
+);
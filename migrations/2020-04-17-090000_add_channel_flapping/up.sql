@@ -0,0 +1 @@
+ALTER TABLE channel ADD COLUMN flapping_since TIMESTAMP;
@@ -0,0 +1,9 @@
+DROP TRIGGER set_updated_at ON alarm_event;
+DROP TRIGGER set_updated_at ON channel;
+DROP TRIGGER set_updated_at ON sensor;
+DROP TRIGGER set_updated_at ON site;
+
+ALTER TABLE alarm_event DROP COLUMN updated_at;
+ALTER TABLE channel DROP COLUMN updated_at;
+ALTER TABLE sensor DROP COLUMN updated_at;
+ALTER TABLE site DROP COLUMN updated_at;
@@ -0,0 +1,14 @@
+ALTER TABLE site ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE sensor ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE channel ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE alarm_event ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+SELECT diesel_manage_updated_at('site');
+SELECT diesel_manage_updated_at('sensor');
+SELECT diesel_manage_updated_at('channel');
+SELECT diesel_manage_updated_at('alarm_event');
+
+CREATE INDEX site_updated_at_idx ON site (updated_at);
+CREATE INDEX sensor_updated_at_idx ON sensor (updated_at);
+CREATE INDEX channel_updated_at_idx ON channel (updated_at);
+CREATE INDEX alarm_event_updated_at_idx ON alarm_event (updated_at);
@@ -0,0 +1,18 @@
+-- Kept after the channel is deleted, the manifests prove what has been published
+CREATE TABLE export_manifest (
+	id SERIAL NOT NULL,
+	user_id INTEGER,
+	channel_id INTEGER NOT NULL,
+	format VARCHAR(8) NOT NULL,
+	range_start TIMESTAMP NOT NULL,
+	range_end TIMESTAMP NOT NULL,
+	include_invalid BOOLEAN NOT NULL,
+	row_count BIGINT,
+	sha256 CHAR(64),
+	created_at TIMESTAMP NOT NULL,
+	completed_at TIMESTAMP,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL
+);
+
+CREATE INDEX export_manifest_sha256_idx ON export_manifest (sha256);
@@ -0,0 +1,14 @@
+-- period: 'd' daily, 'w' weekly; format: 'h' HTML, 'p' PDF
+CREATE TABLE report_subscription (
+	id SERIAL NOT NULL,
+	user_id INTEGER NOT NULL,
+	site_id INTEGER NOT NULL,
+	period CHAR(1) NOT NULL,
+	format CHAR(1) NOT NULL,
+	last_period_end TIMESTAMP,
+	created_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (id),
+	UNIQUE (user_id, site_id, period),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE CASCADE,
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
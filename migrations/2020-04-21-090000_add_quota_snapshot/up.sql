@@ -0,0 +1,9 @@
+-- Balances saved on shutdown and restored on the next start, pool: 'r' read, 'w' write
+CREATE TABLE quota_snapshot (
+	user_id INTEGER NOT NULL,
+	pool CHAR(1) NOT NULL,
+	balance BIGINT NOT NULL,
+	saved_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (user_id, pool),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE site_webhook DROP COLUMN status_changes;
+DROP TABLE site_status;
@@ -0,0 +1,9 @@
+-- Last status of the site seen by the alarm check, status: 'o' ok, 'a' alarm, 's' stale data
+CREATE TABLE site_status (
+	site_id INTEGER NOT NULL,
+	status CHAR(1) NOT NULL,
+	changed_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (site_id),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
+ALTER TABLE site_webhook ADD COLUMN status_changes BOOLEAN NOT NULL DEFAULT FALSE;
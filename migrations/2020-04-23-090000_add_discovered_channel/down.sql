@@ -0,0 +1 @@
+DROP TABLE discovered_channel;
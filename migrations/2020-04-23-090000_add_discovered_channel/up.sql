@@ -0,0 +1,10 @@
+-- Cnr ids with readings that no channel maps to, remembered so that the admins are notified once
+CREATE TABLE discovered_channel (
+	site_id INTEGER NOT NULL,
+	sensor_id_cnr VARCHAR(50) NOT NULL,
+	channel_id_cnr VARCHAR(50) NOT NULL,
+	last_reading_at TIMESTAMP NOT NULL,
+	notified_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (site_id, sensor_id_cnr, channel_id_cnr),
+	FOREIGN KEY(site_id) REFERENCES site (id) ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- Export of every channel of some sites, written in the background as a zip of CSV files
+CREATE TABLE bulk_export_job (
+	id SERIAL NOT NULL,
+	user_id INTEGER,
+	site_ids INTEGER[] NOT NULL,
+	range_start TIMESTAMP NOT NULL,
+	range_end TIMESTAMP NOT NULL,
+	include_invalid BOOLEAN NOT NULL,
+	status CHAR NOT NULL,
+	channel_count INTEGER NOT NULL DEFAULT 0,
+	row_count BIGINT NOT NULL DEFAULT 0,
+	error VARCHAR(255),
+	created_at TIMESTAMP NOT NULL,
+	finished_at TIMESTAMP,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE SET NULL
+);
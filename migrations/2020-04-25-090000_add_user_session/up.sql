@@ -0,0 +1,14 @@
+-- Sessions opened by the identity cookies, deleting a session logs its cookie out
+CREATE TABLE user_session (
+	id SERIAL NOT NULL,
+	user_id INTEGER NOT NULL,
+	secret_hash CHAR(64) NOT NULL,
+	user_agent VARCHAR(255),
+	address VARCHAR(64),
+	created_at TIMESTAMP NOT NULL,
+	last_seen_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (id),
+	FOREIGN KEY(user_id) REFERENCES user_account (id) ON DELETE CASCADE
+);
+
+CREATE INDEX user_session_user_id_idx ON user_session (user_id);
@@ -0,0 +1,4 @@
+ALTER TABLE user_account DROP COLUMN email_token_sent_at;
+ALTER TABLE user_account DROP COLUMN email_token_hash;
+ALTER TABLE user_account DROP COLUMN email_verified_at;
+ALTER TABLE user_account DROP COLUMN email;
@@ -0,0 +1,13 @@
+-- Email of the account, the notifications are only emailed once it's verified with the token
+-- sent to it (only the hash of the token is stored)
+ALTER TABLE user_account ADD COLUMN email VARCHAR(255);
+ALTER TABLE user_account ADD COLUMN email_verified_at TIMESTAMP;
+ALTER TABLE user_account ADD COLUMN email_token_hash CHAR(64);
+ALTER TABLE user_account ADD COLUMN email_token_sent_at TIMESTAMP;
+
+-- The users already receiving emails keep them: their first contact becomes the verified email
+-- of the account. Their other contacts stop receiving emails, addEmailContact only accepts the
+-- verified email of the account.
+UPDATE user_account SET email = contact.email, email_verified_at = NOW()
+FROM (SELECT user_id, MIN(email) AS email FROM email_user_contact GROUP BY user_id) AS contact
+WHERE contact.user_id = user_account.id;
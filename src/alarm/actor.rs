@@ -1,38 +1,90 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
+use chrono::Utc;
 use diesel::PgConnection;
 use diesel::r2d2::ConnectionManager;
-use log::{error, info};
+use futures::FutureExt;
+use futures::lock::Mutex;
+use log::{error, info, warn};
 use r2d2::PooledConnection;
 
 use crate::AppData;
 use crate::contact::Contacter;
+use crate::siem::SiemSink;
 
 use super::controller::check_measures;
+use super::escalation::escalate_alarms;
+use super::flapping::FlappingPolicy;
+use super::metrics::AlarmMetrics;
 
 pub struct AlarmActor {
     pub app_data: AppData,
     pub sleep_interval: Duration,
+    /// How often the unacknowledged alarms are checked against the escalation policies
+    pub escalation_interval: Duration,
+    /// Held while the measures are checked, so that a shutdown can wait for the running check
+    check_lock: Arc<Mutex<()>>,
+    /// Set once the server is shutting down, no new check is started
+    draining: Arc<AtomicBool>,
 }
 
 impl AlarmActor {
+    pub fn new(app_data: AppData, sleep_interval: Duration, escalation_interval: Duration) -> Self {
+        AlarmActor {
+            app_data,
+            sleep_interval,
+            escalation_interval,
+            check_lock: Arc::new(Mutex::new(())),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
 
     async fn on_tick_async2(
         start: Instant,
         contacter: Contacter,
+        metrics: AlarmMetrics,
+        flapping: Option<FlappingPolicy>,
+        siem: SiemSink,
+        clock_chunk_size: usize,
         connection: PooledConnection<ConnectionManager<PgConnection>>,
-        sensor_pool: mysql::Pool
+        sensor_pool: mysql::Pool,
+        check_lock: Arc<Mutex<()>>,
+        draining: Arc<AtomicBool>,
     ) {
-        let res = check_measures(&contacter, &connection, &sensor_pool).await;
+        // The checks never overlap, the clocks of a check would be overwritten by the other
+        let _guard = check_lock.lock().await;
+        if draining.load(Ordering::SeqCst) {
+            return
+        }
+        // A panic in a single tick must not take down the following ones
+        let res = AssertUnwindSafe(check_measures(&contacter, &metrics, flapping.as_ref(), &siem, clock_chunk_size, &connection, &sensor_pool))
+            .catch_unwind()
+            .await;
         match res {
-            Ok(()) => {},
-            Err(description) => error!("Error during measurement check: {}", description),
+            Ok(Ok(())) => metrics.record_success(start.elapsed()),
+            Ok(Err(description)) => {
+                error!("Error during measurement check: {}", description);
+                metrics.record_failure(start.elapsed());
+            },
+            Err(panic) => {
+                let description = panic.downcast_ref::<&str>().map(|x| x.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                error!("Panic during measurement check: {}", description);
+                metrics.record_failure(start.elapsed());
+            },
         }
         info!("Measurement checked in {}ms", start.elapsed().as_millis());
     }
 
     fn on_tick_async(&mut self) -> Option<impl Future<Output=()>> {
+        if self.draining.load(Ordering::SeqCst) {
+            return None
+        }
         let start = Instant::now();
 
         let sensor_pool = self.app_data.sensor_pool.clone();
@@ -42,11 +94,23 @@ fn on_tick_async(&mut self) -> Option<impl Future<Output=()>> {
             Ok(x) => x,
             Err(desc) => {
                 error!("Error in connection pool: {}", desc);
+                self.app_data.alarm_metrics.record_failure(start.elapsed());
                 return None
             },
         };
 
-        let mes_result = Self::on_tick_async2(start, self.app_data.contacter.clone(), connection, sensor_pool);
+        let mes_result = Self::on_tick_async2(
+            start,
+            self.app_data.contacter.clone(),
+            self.app_data.alarm_metrics.clone(),
+            self.app_data.flapping.clone(),
+            self.app_data.siem.clone(),
+            self.app_data.tuning.alarm_clock_chunk_size,
+            connection,
+            sensor_pool,
+            self.check_lock.clone(),
+            self.draining.clone(),
+        );
 
         Some(mes_result)
     }
@@ -59,6 +123,64 @@ fn on_tick(&mut self, ctx: &mut Context<Self>) {
     }
 }
 
+impl AlarmActor {
+    fn on_escalation_tick(&mut self, ctx: &mut Context<Self>) {
+        if self.draining.load(Ordering::SeqCst) {
+            return
+        }
+        let contacter = self.app_data.contacter.clone();
+        let connection = match self.app_data.pool.get() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error in connection pool: {}", err);
+                return
+            },
+        };
+
+        let res = async move {
+            match escalate_alarms(&contacter, &connection, Utc::now().naive_utc()).await {
+                Ok(0) => {},
+                Ok(count) => info!("Escalated {} unacknowledged alarms", count),
+                Err(err) => error!("Error during alarm escalation: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+/// Checks the measures immediately, without waiting for the next tick (ex. after new readings
+/// have been received)
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CheckMeasures;
+
+impl Handler<CheckMeasures> for AlarmActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CheckMeasures, ctx: &mut Context<Self>) {
+        self.on_tick(ctx);
+    }
+}
+
+/// Stops the measure checks, the response is sent once the running check (if any) is complete so
+/// that its site clocks are saved before the server exits
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Drain;
+
+impl Handler<Drain> for AlarmActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Drain, _ctx: &mut Context<Self>) -> Self::Result {
+        info!("draining the alarm actor");
+        self.draining.store(true, Ordering::SeqCst);
+        let check_lock = self.check_lock.clone();
+        Box::pin(async move {
+            let _guard = check_lock.lock().await;
+        })
+    }
+}
+
 impl Actor for AlarmActor {
     type Context = Context<Self>;
 
@@ -69,6 +191,16 @@ fn started(&mut self, ctx: &mut Context<Self>) {
             .finish()
             .spawn(ctx);
 
+        IntervalFunc::new(self.escalation_interval, Self::on_escalation_tick)
+            .finish()
+            .spawn(ctx);
+
         self.on_tick(ctx);
     }
 }
+
+impl Supervised for AlarmActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the alarm actor");
+    }
+}
@@ -1,11 +1,12 @@
 use core::fmt::{Display, Error as FormatError, Formatter};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::string::ToString;
 
 use bigdecimal::{BigDecimal, ToPrimitive};
 use chrono::prelude::*;
 use diesel::{
+    Connection as DieselConnection,
     pg::PgConnection,
     pg::upsert::*,
     prelude::*,
@@ -13,74 +14,21 @@
 };
 use log::{debug, warn};
 use mysql::error::Error as MysqlError;
-use mysql::error::Result as MysqlResult;
-use mysql::params;
+use mysql::FromRowError;
 
 use crate::contact::{
     Contacter, MeasureExtremeType
 };
 use crate::models::IdType;
 use crate::schema::site;
+use crate::siem::{SiemEvent, SiemSink};
 
-type Connection = PgConnection;
-
-/// Loads the last measure in a channel using the chronological order, returning min_measure, max_measure, timestamp
-/// The channel must be specified fully by the site, the sensor and the channel ids.
-pub fn load_last_channel_measure(site_id: &str, sensor_id: &str, channel_id: &str, conn: &mysql::Pool) -> MysqlResult<Option<(f64, f64, NaiveDateTime)>> {
-    let mut result = conn.prep_exec(
-        "SELECT valore_min, valore_max, data FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id AND canale = :channel_id ORDER BY data DESC LIMIT 1;",
-        params!{
-            "site_id" => site_id,
-            "sensor_id" => sensor_id,
-            "channel_id" => channel_id
-        }
-    )?;
-    match result.next() {
-        Some(row) => Ok(Some(mysql::from_row::<(f64, f64, NaiveDateTime)>(row?))),
-        None => Ok(None),
-    }
-}
+use super::flapping::{check_flapping, clear_flapping, FlappingPolicy};
+use super::metrics::AlarmMetrics;
+use super::readings::{ReadingsStore, SiteData};
+use super::site_status::update_site_status;
 
-/// Loads the last measure of the site (among every channel)
-/// # Panics
-/// If the site has no measures (this should be revisited but it should never happen).
-pub fn load_last_site_measure(site_id: &str, conn: &mysql::Pool) -> MysqlResult<Option<(f64, f64, NaiveDateTime)>> {
-    let mut result = conn.prep_exec(
-        "SELECT valore_min, valore_max, data FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT 1;",
-        params!{"site_id" => site_id}
-    )?;
-    match result.next() {
-        Some(row) => Ok(Some(mysql::from_row::<(f64, f64, NaiveDateTime)>(row?))),
-        None => Ok(None)
-    }
-}
-
-#[derive(Debug)]
-struct SiteData {
-    pub min_value: f64,
-    pub max_value: f64,
-    pub sensor_id: String,
-    pub channel_id: String,
-}
-
-/// Loads all of the measures that are newer than the clocks, and returns the minimum value and
-/// the maximum value for every channel.
-/// As the site id is provided as the parameter it is not returned.
-fn load_channel_data(cnr_id: &str, clock: NaiveDateTime, conn: &mysql::Pool) -> MysqlResult<Vec<SiteData>> {
-    let result = conn.prep_exec(
-        "SELECT min(valore_min), max(valore_max), idsensore, canale FROM t_rilevamento_dati WHERE idsito = :site_id AND data > :clock GROUP BY idsito, idstazione, idsensore, canale;",
-        params!{
-            "site_id" => cnr_id,
-            "clock" => clock
-        }
-    )?;
-    let data: Vec<SiteData> = result.map(|row| {
-        let (min_value, max_value, sensor_id, channel_id) =
-            mysql::from_row::<(f64, f64, String, String)>(row.unwrap());
-        SiteData { min_value, max_value, sensor_id, channel_id }
-    }).collect();
-    Ok(data)
-}
+type Connection = PgConnection;
 
 #[derive(Debug, Queryable)]
 pub struct SiteClockData(IdType, Option<String>, NaiveDateTime);
@@ -93,11 +41,12 @@ pub struct SiteClockUpdateData {
 }
 
 /// Loads id, cnr_id and clock for every available site.
-/// Sites without a cnr_id are not returned.
+/// Sites without a cnr_id and the deleted ones are not returned.
 pub fn load_site_clocks(conn: &Connection) -> QueryResult<Vec<SiteClockData>> {
     use crate::schema::site::dsl::*;
     site.select((id, id_cnr, clock))
         .filter(id_cnr.is_not_null())
+        .filter(deleted_at.is_null())
         .load::<SiteClockData>(conn)
 }
 
@@ -142,52 +91,63 @@ pub fn save_site_clocks(conn: &Connection, clocks: &[SiteClockUpdateData]) -> Qu
 
 #[derive(Queryable)]
 struct ChannelAlarmDataRaw {
-    site_id: IdType,
     sensor_id: IdType,
     sensor_cnr_id: Option<String>,
     channel_id: IdType,
     channel_cnr_id: Option<String>,
     range_min: Option<BigDecimal>,
     range_max: Option<BigDecimal>,
+    max_delta_per_hour: Option<BigDecimal>,
+    alarm_delay: Option<i32>,
+    alarm_pending_since: Option<NaiveDateTime>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct ChannelAlarmData {
-    site_id: IdType,
     sensor_id: IdType,
     sensor_cnr_id: String,
     channel_id: IdType,
     channel_cnr_id: String,
     range_min: f64,
     range_max: f64,
+    max_delta_per_hour: f64,
+    /// How long the channel must stay out of range before the alarm begins
+    alarm_delay: chrono::Duration,
+    alarm_pending_since: Option<NaiveDateTime>,
 }
 
-/// Loads all of the data related to alarms for every enabled channel.
+/// Loads all of the data related to alarms for every enabled channel of the site.
 /// The site cnr id isn't returned (as it is already present with the clock).
 /// The sensors and channels that don't have the cnr_id are not returned.
 /// If a channel doesn't have a min_value it is replaced with -inf, and if the
-/// max_value is not present it is replaced with +inf.
-fn load_channels_alarm_data(conn: &Connection) -> QueryResult<Vec<ChannelAlarmData>> {
-    use crate::schema::site::dsl as site_dsl;
+/// max_value (or a positive max_delta_per_hour) is not present it is replaced with +inf.
+fn load_channels_alarm_data(conn: &Connection, site_id: IdType) -> QueryResult<Vec<ChannelAlarmData>> {
     use crate::schema::sensor::dsl as sensor_dsl;
     use crate::schema::channel::dsl as channel_dsl;
 
     let data = channel_dsl::channel
-        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
         .filter(sensor_dsl::enabled.eq(true))
+        .filter(channel_dsl::deleted_at.is_null())
         .filter(channel_dsl::id_cnr.is_not_null())
         .filter(sensor_dsl::id_cnr.is_not_null())
-        .select((site_dsl::id, sensor_dsl::id, sensor_dsl::id_cnr, channel_dsl::id, channel_dsl::id_cnr, channel_dsl::range_min, channel_dsl::range_max))
+        .select((sensor_dsl::id, sensor_dsl::id_cnr, channel_dsl::id, channel_dsl::id_cnr, channel_dsl::range_min, channel_dsl::range_max, channel_dsl::max_delta_per_hour,
+                 channel_dsl::alarm_delay, channel_dsl::alarm_pending_since))
         .load::<ChannelAlarmDataRaw>(conn)?
         .iter()
         .map(|x| ChannelAlarmData {
-            site_id: x.site_id,
             sensor_id: x.sensor_id,
             sensor_cnr_id: x.sensor_cnr_id.as_ref().map(|x| x.to_string()).unwrap_or_else(|| "".to_string()),
             channel_id: x.channel_id,
             channel_cnr_id: x.channel_cnr_id.as_ref().map(|x| x.to_string()).unwrap_or_else(||  "".to_string()),
             range_min: x.range_min.as_ref().and_then(|x| x.to_f64()).unwrap_or(std::f64::NEG_INFINITY),
             range_max: x.range_max.as_ref().and_then(|x| x.to_f64()).unwrap_or(std::f64::INFINITY),
+            max_delta_per_hour: x.max_delta_per_hour.as_ref().and_then(|x| x.to_f64())
+                .filter(|x| *x > 0.0)
+                .unwrap_or(std::f64::INFINITY),
+            alarm_delay: chrono::Duration::seconds(x.alarm_delay.unwrap_or_default().max(0) as i64),
+            alarm_pending_since: x.alarm_pending_since,
         }).collect();
     Ok(data)
 }
@@ -195,158 +155,545 @@ fn load_channels_alarm_data(conn: &Connection) -> QueryResult<Vec<ChannelAlarmDa
 #[derive(Queryable)]
 struct AlarmedChannelDataRaw {
     channel_id: IdType,
-    site_cnr_id: Option<String>,
     sensor_cnr_id: Option<String>,
     channel_cnr_id: Option<String>,
     range_min: Option<BigDecimal>,
     range_max: Option<BigDecimal>,
+    hysteresis: Option<BigDecimal>,
 }
 
+#[derive(Debug, Clone)]
 struct AlarmedChannelData {
     channel_id: IdType,
-    site_cnr_id: String,
     sensor_cnr_id: String,
     channel_cnr_id: String,
     range_min: f64,
     range_max: f64,
+    /// The alarm only ends when the measures are inside the range by this margin
+    hysteresis: f64,
 }
 
-/// Loads the data for the alarmed channels.
-fn load_alarmed_data(conn: &Connection) -> QueryResult<Vec<AlarmedChannelData>> {
-    use crate::schema::site::dsl as site_dsl;
+/// Loads the data for the alarmed channels of the site, ordered by channel id.
+fn load_alarmed_data(conn: &Connection, site_id: IdType) -> QueryResult<Vec<AlarmedChannelData>> {
     use crate::schema::sensor::dsl as sensor_dsl;
     use crate::schema::channel::dsl as channel_dsl;
 
     Ok(channel_dsl::channel
-        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
         .filter(channel_dsl::alarmed.eq(true))
-        .select((channel_dsl::id, site_dsl::id_cnr, sensor_dsl::id_cnr, channel_dsl::id_cnr, channel_dsl::range_min, channel_dsl::range_max))
+        .select((channel_dsl::id, sensor_dsl::id_cnr, channel_dsl::id_cnr, channel_dsl::range_min, channel_dsl::range_max, channel_dsl::hysteresis))
         .order_by(channel_dsl::id.asc())
         .load::<AlarmedChannelDataRaw>(conn)?
         .iter()
         .map(|x| AlarmedChannelData {
             channel_id: x.channel_id,
-            site_cnr_id: x.site_cnr_id.as_ref().map(|x| x.to_string()).unwrap_or_else(|| "".to_string()),
             sensor_cnr_id: x.sensor_cnr_id.as_ref().map(|x| x.to_string()).unwrap_or_else(||  "".to_string()),
             channel_cnr_id: x.channel_cnr_id.as_ref().map(|x| x.to_string()).unwrap_or_else(|| "".to_string()),
             range_min: x.range_min.as_ref().and_then(|x| x.to_f64()).unwrap_or(std::f64::NEG_INFINITY),
             range_max: x.range_max.as_ref().and_then(|x| x.to_f64()).unwrap_or(std::f64::INFINITY),
+            hysteresis: x.hysteresis.as_ref().and_then(|x| x.to_f64()).unwrap_or(0.0).max(0.0),
         }).collect())
 }
 
+#[derive(Queryable)]
+struct SensorSilenceDataRaw {
+    sensor_id: IdType,
+    sensor_cnr_id: Option<String>,
+    max_silence: Option<i32>,
+    no_data_since: Option<NaiveDateTime>,
+    channel_cnr_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct SensorSilenceData {
+    sensor_id: IdType,
+    sensor_cnr_id: String,
+    channel_cnr_ids: Vec<String>,
+    max_silence: chrono::Duration,
+    no_data_since: Option<NaiveDateTime>,
+}
+
+/// Loads the enabled sensors of the site that have a max silence, ordered by sensor id.
+/// The sensors without any channel with a cnr id are not returned, as they can't report anything.
+fn load_sensors_silence_data(conn: &Connection, site_id: IdType) -> QueryResult<Vec<SensorSilenceData>> {
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+
+    let rows = sensor_dsl::sensor
+        .inner_join(channel_dsl::channel)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::enabled.eq(true))
+        .filter(sensor_dsl::max_silence.gt(0))
+        .filter(channel_dsl::deleted_at.is_null())
+        .filter(sensor_dsl::id_cnr.is_not_null())
+        .filter(channel_dsl::id_cnr.is_not_null())
+        .select((sensor_dsl::id, sensor_dsl::id_cnr, sensor_dsl::max_silence, sensor_dsl::no_data_since, channel_dsl::id_cnr))
+        .order_by(sensor_dsl::id.asc())
+        .load::<SensorSilenceDataRaw>(conn)?;
+
+    let mut data: Vec<SensorSilenceData> = Vec::new();
+    for row in rows {
+        let channel_cnr_id = row.channel_cnr_id.unwrap_or_default();
+        match data.last_mut() {
+            Some(x) if x.sensor_id == row.sensor_id => x.channel_cnr_ids.push(channel_cnr_id),
+            _ => data.push(SensorSilenceData {
+                sensor_id: row.sensor_id,
+                sensor_cnr_id: row.sensor_cnr_id.unwrap_or_default(),
+                channel_cnr_ids: vec![channel_cnr_id],
+                max_silence: chrono::Duration::seconds(row.max_silence.unwrap_or_default() as i64),
+                no_data_since: row.no_data_since,
+            }),
+        }
+    }
+    Ok(data)
+}
+
+/// Loads the channels of the site that have readings marked as invalid after the clock, since only
+/// the extremes of the new readings are known their alarms are not checked until the invalid
+/// interval is over.
+fn load_invalid_channels(conn: &Connection, site_id: IdType, clock: NaiveDateTime) -> QueryResult<HashSet<IdType>> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::reading_annotation::dsl as annotation_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+
+    let ids = annotation_dsl::reading_annotation
+        .inner_join(channel_dsl::channel.inner_join(sensor_dsl::sensor))
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(annotation_dsl::end_time.ge(clock))
+        .filter(annotation_dsl::start_time.le(Utc::now().naive_utc()))
+        .select(annotation_dsl::channel_id)
+        .distinct()
+        .load::<IdType>(conn)?;
+    Ok(ids.into_iter().collect())
+}
+
+fn flush_site_clocks(conn: &Connection, metrics: &AlarmMetrics, clocks: &mut Vec<SiteClockUpdateData>) -> QueryResult<()> {
+    if clocks.is_empty() {
+        return Ok(())
+    }
+    save_site_clocks(conn, clocks)?;
+    for x in clocks.drain(..) {
+        metrics.update_site_clock(x.id, x.clock);
+    }
+    Ok(())
+}
+
+/// Where the alarm changes are reported, besides the database
+struct AlarmOutputs<'a> {
+    contacter: &'a Contacter,
+    /// None if the flapping channels aren't detected
+    flapping: Option<&'a FlappingPolicy>,
+    siem: &'a SiemSink,
+}
+
 /// Main function, checks all of the new data and manages alarms.
 ///
 /// Every site has its own clock for which the measure timestamps are checked against.
-/// For each site the saved clock is queried, then the new measures are downloaded and checked for
-/// alarms, finally the last measure is queried and its timestamp is used as the new site clock.
-/// To save bandwidth we only download the minimum and the maximum measure for each channel, letting
-/// the DBMS do the computations.
-/// Then the alarmed channels are computed: for each alarmed channel the last measure found is
-/// queried, then if its within the min-max range the alarm is terminated.
-pub async fn check_measures(contacter: &Contacter, conn: &Connection, pool: &mysql::Pool) -> Result<(), DatabaseError> {
+/// The sites are processed one at a time so that only the data of a single site is kept in
+/// memory, the new clocks are then saved in chunks of `clock_chunk_size` so that an error
+/// in a site does not throw away the work done for the previous ones.
+pub async fn check_measures<R: ReadingsStore>(contacter: &Contacter, metrics: &AlarmMetrics, flapping: Option<&FlappingPolicy>, siem: &SiemSink, clock_chunk_size: usize, conn: &Connection, store: &R) -> Result<(), DatabaseError> {
     let clocks = load_site_clocks(conn)?;
+    let outputs = AlarmOutputs { contacter, flapping, siem };
 
-    let mut clocks_data: Vec<(IdType, (f64, f64, NaiveDateTime))> = vec![];
-    let mut channel_data: Vec<(IdType, String, Vec<SiteData>)> = vec![];
-    let mut updated_clocks: Vec<SiteClockUpdateData> = vec![];
-    updated_clocks.reserve(clocks.len());
-
-    let alarmed_data: Vec<AlarmedChannelData> = load_alarmed_data(conn)?;
+    let mut updated_clocks: Vec<SiteClockUpdateData> = Vec::with_capacity(clock_chunk_size);
 
-    for SiteClockData(site_id, cnr_id, clock) in clocks.iter() {
+    for SiteClockData(site_id, cnr_id, clock) in clocks {
         let cnr_id = if let Some(x) = cnr_id { x } else { continue };
 
-        let data = load_channel_data(cnr_id, *clock, pool)?;
-
-        let last_measure = match load_last_site_measure(cnr_id, pool)? {
-            Some(x) => x,
-            None => continue,
+        let res = check_site_measures(&outputs, conn, store, site_id, &cnr_id, clock).await;
+        let new_clock = match res {
+            Ok(x) => x,
+            Err(e) => {
+                // Save the progress done until now
+                flush_site_clocks(conn, metrics, &mut updated_clocks)?;
+                return Err(e)
+            }
         };
 
-        debug!(" checking: {} = {} ({:?})", site_id, cnr_id, data);
+        if let Some(new_clock) = new_clock {
+            updated_clocks.push(SiteClockUpdateData {
+                id: site_id,
+                clock: new_clock,
+            });
+        }
+        if updated_clocks.len() >= clock_chunk_size {
+            flush_site_clocks(conn, metrics, &mut updated_clocks)?;
+        }
+    }
+    flush_site_clocks(conn, metrics, &mut updated_clocks)?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum AlarmAction {
+    Begin {
+        channel_id: IdType,
+        measure: f64,
+        measure_type: MeasureExtremeType,
+    },
+    /// The channel is still alarmed and a new extreme measure has been found
+    Peak {
+        channel_id: IdType,
+        measure: f64,
+        measure_type: MeasureExtremeType,
+    },
+    End {
+        channel_id: IdType,
+    },
+    /// The channel went out of range but the alarm waits for its delay
+    Pending {
+        channel_id: IdType,
+        since: NaiveDateTime,
+    },
+    /// The channel went back in range before the end of the delay
+    PendingCancel {
+        channel_id: IdType,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+enum SilenceAction {
+    /// The sensor hasn't reported for longer than its max silence
+    Begin {
+        sensor_id: IdType,
+        last_measure: Option<NaiveDateTime>,
+    },
+    End {
+        sensor_id: IdType,
+    },
+}
+
+/// Compares the newest measure of every sensor (among its channels) against its max silence,
+/// the sensors that never reported are considered silent.
+fn evaluate_site_silence<R: ReadingsStore>(
+    store: &R,
+    cnr_id: &str,
+    now: NaiveDateTime,
+    sensors: &[SensorSilenceData]
+) -> Result<Vec<SilenceAction>, DatabaseError> {
+    if sensors.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    let channels: Vec<(&str, &str)> = sensors.iter()
+        .flat_map(|x| x.channel_cnr_ids.iter().map(move |c| (x.sensor_cnr_id.as_str(), c.as_str())))
+        .collect();
+    let last_measures = store.load_last_channel_measures(cnr_id, &channels)?;
+
+    let mut actions = Vec::new();
+    for sensor in sensors {
+        let last_measure = sensor.channel_cnr_ids.iter()
+            .filter_map(|x| last_measures.get(&(sensor.sensor_cnr_id.clone(), x.clone())))
+            .map(|x| x.2)
+            .max();
+        let silent = last_measure.map_or(true, |x| now - x > sensor.max_silence);
 
-        clocks_data.push((*site_id, last_measure));
-        updated_clocks.push(SiteClockUpdateData {
-            id: *site_id,
-            clock: last_measure.2,
-        });
-        channel_data.push((*site_id, cnr_id.to_string(), data));
+        if silent && sensor.no_data_since.is_none() {
+            actions.push(SilenceAction::Begin { sensor_id: sensor.sensor_id, last_measure });
+        } else if !silent && sensor.no_data_since.is_some() {
+            actions.push(SilenceAction::End { sensor_id: sensor.sensor_id });
+        }
     }
-    save_site_clocks(conn, &updated_clocks)?;
+    Ok(actions)
+}
 
+/// Raises the NoData alarms of the site sensors that stopped reporting and ends the ones of the
+/// sensors that started again.
+async fn check_site_silence<R: ReadingsStore>(contacter: &Contacter, conn: &Connection, store: &R, site_id: IdType, cnr_id: &str, now: NaiveDateTime) -> Result<(), DatabaseError> {
+    use crate::schema::sensor::dsl;
 
-    let channels_alarm_data = load_channels_alarm_data(conn)?;
+    let sensors = load_sensors_silence_data(conn, site_id)?;
+    for action in evaluate_site_silence(store, cnr_id, now, &sensors)? {
+        match action {
+            SilenceAction::Begin { sensor_id, last_measure } => {
+                warn!("no_data_begin({} {:?})", sensor_id, last_measure);
+                diesel::update(dsl::sensor.find(sensor_id))
+                    .set(dsl::no_data_since.eq(now))
+                    .execute(conn)?;
+                contacter.send_no_data(conn, sensor_id, last_measure).await?;
+            },
+            SilenceAction::End { sensor_id } => {
+                warn!("no_data_end({})", sensor_id);
+                diesel::update(dsl::sensor.find(sensor_id))
+                    .set(dsl::no_data_since.eq(Option::<NaiveDateTime>::None))
+                    .execute(conn)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Computes the alarm changes of a single site, returning the new clock of the site (or None if
+/// the site has no measures) with the actions to take.
+///
+/// The new measures are downloaded and checked for alarms, to save bandwidth we only download the
+/// minimum and the maximum measure for each channel, letting the DBMS do the computations.
+/// A channel is also alarmed when two consecutive measures differ by more than its max delta per
+/// hour (the rate of change is computed by the store too).
+/// A channel with an alarm delay only becomes pending when it goes out of range, the alarm begins
+/// if it's still out of range (checking the last measure) when the delay is over, measured on the
+/// site clock. The rate of change alarms are events, they aren't delayed.
+/// Then the alarmed channels are computed: the last measure of every alarmed channel is queried
+/// (all at once), then if its within the min-max range shrunk by the hysteresis (and the channel
+/// didn't change too fast) the alarm is terminated.
+/// The alarmed channels must be sorted by channel id.
+fn evaluate_site_alarms<R: ReadingsStore>(
+    store: &R,
+    cnr_id: &str,
+    clock: NaiveDateTime,
+    channels_alarm_data: &[ChannelAlarmData],
+    alarmed_data: &[AlarmedChannelData]
+) -> Result<Option<(NaiveDateTime, Vec<AlarmAction>)>, DatabaseError> {
+    let data: Vec<SiteData> = store.load_channel_data(cnr_id, clock)?;
 
-    //println!(" alarm data: {:?}", channels_alarm_data);
-    let params_to_alarm_data: HashMap<(IdType, &str, &str), &ChannelAlarmData> = channels_alarm_data.iter()
+    let last_measure = match store.load_last_site_measure(cnr_id)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    debug!(" checking: {} ({:?})", cnr_id, data);
+
+    let params_to_alarm_data: HashMap<(&str, &str), &ChannelAlarmData> = channels_alarm_data.iter()
         .map(|x| (
-            (x.site_id, x.sensor_cnr_id.as_str(), x.channel_cnr_id.as_str()),
+            (x.sensor_cnr_id.as_str(), x.channel_cnr_id.as_str()),
             x
         ))
         .collect();
 
-    for (site_id, _site_cnr_id, data) in channel_data {
-        for channel_data in data {
-            let alarm_data = params_to_alarm_data.get(&(site_id, &channel_data.sensor_id, &channel_data.channel_id));
-            if let Some(alarm_data) = alarm_data {
-                if channel_data.min_value < alarm_data.range_min || channel_data.max_value > alarm_data.range_max {
-                    if let Err(_insert_index) = alarmed_data.binary_search_by_key(&alarm_data.channel_id, |x| { x.channel_id }) {
-                        // New alarm found
-                        // Should we insert it in the alarm_data? I don't think it is useful: we
-                        // shouldn't check this alarm in this tick. Or we could have alarms that
-                        // last 0 seconds.
-                        /*alarmed_data.insert(insert_index, AlarmedChannelData {
-                            channel_id: alarm_data.channel_id,
-                            site_cnr_id: site_cnr_id.clone(),
-                            sensor_cnr_id: alarm_data.sensor_cnr_id.clone(),
-                            channel_cnr_id: alarm_data.channel_cnr_id.clone(),
-                            range_min: alarm_data.range_min,
-                            range_max: alarm_data.range_max,
-                        });*/
-                        let (measure, measure_type) = if channel_data.min_value < alarm_data.range_min {
-                            (channel_data.min_value, MeasureExtremeType::Min)
-                        } else {
-                            (channel_data.max_value, MeasureExtremeType::Max)
-                        };
-                        alarm_begin(contacter, conn, alarm_data.channel_id, measure, measure_type).await?;
-                    }
-                }
+    let mut actions = Vec::new();
+    // The channels that are still changing too fast, their alarms can't end even if in range
+    let mut changing_channels = HashSet::new();
+
+    for channel_data in data {
+        let alarm_data = params_to_alarm_data.get(&(channel_data.sensor_id.as_str(), channel_data.channel_id.as_str()));
+        if let Some(alarm_data) = alarm_data {
+            let too_fast = channel_data.max_delta.filter(|x| *x > alarm_data.max_delta_per_hour);
+            let (measure, measure_type) = if channel_data.min_value < alarm_data.range_min {
+                (channel_data.min_value, MeasureExtremeType::Min)
+            } else if channel_data.max_value > alarm_data.range_max {
+                (channel_data.max_value, MeasureExtremeType::Max)
+            } else if let Some(delta) = too_fast {
+                (delta, MeasureExtremeType::Delta)
+            } else {
+                continue
+            };
+            let channel_id = alarm_data.channel_id;
+            if too_fast.is_some() {
+                changing_channels.insert(channel_id);
+            }
+            if alarmed_data.binary_search_by_key(&channel_id, |x| { x.channel_id }).is_ok() {
+                actions.push(AlarmAction::Peak { channel_id, measure, measure_type });
+            } else if alarm_data.alarm_delay.is_zero() || measure_type == MeasureExtremeType::Delta {
+                // New alarm found
+                actions.push(AlarmAction::Begin { channel_id, measure, measure_type });
+            } else if alarm_data.alarm_pending_since.is_none() {
+                actions.push(AlarmAction::Pending { channel_id, since: last_measure.2 });
             }
         }
     }
 
+    // Alarm checks (and pending alarm checks)
+    let pending_data: Vec<&ChannelAlarmData> = channels_alarm_data.iter()
+        .filter(|x| x.alarm_pending_since.is_some())
+        .filter(|x| alarmed_data.binary_search_by_key(&x.channel_id, |a| a.channel_id).is_err())
+        .collect();
+    let checked_ids: Vec<(&str, &str)> = alarmed_data.iter()
+        .map(|x| (x.sensor_cnr_id.as_str(), x.channel_cnr_id.as_str()))
+        .chain(pending_data.iter().map(|x| (x.sensor_cnr_id.as_str(), x.channel_cnr_id.as_str())))
+        .collect();
+    let last_measures = store.load_last_channel_measures(cnr_id, &checked_ids)?;
+
+    for pending in pending_data {
+        let key = (pending.sensor_cnr_id.clone(), pending.channel_cnr_id.clone());
+        let (measure_min, measure_max, _measure_time) = match last_measures.get(&key) {
+            Some(x) => *x,
+            None => continue,
+        };
+        let channel_id = pending.channel_id;
+        let (measure, measure_type) = if measure_min < pending.range_min {
+            (measure_min, MeasureExtremeType::Min)
+        } else if measure_max > pending.range_max {
+            (measure_max, MeasureExtremeType::Max)
+        } else {
+            actions.push(AlarmAction::PendingCancel { channel_id });
+            continue
+        };
+        let since = pending.alarm_pending_since.unwrap_or(last_measure.2);
+        if last_measure.2 - since >= pending.alarm_delay {
+            actions.push(AlarmAction::Begin { channel_id, measure, measure_type });
+        }
+    }
+
     for alarm in alarmed_data {
-        // Alarm checks
-        if let Some((measure_min, measure_max,  _measure_time)) = load_last_channel_measure(&alarm.site_cnr_id, &alarm.sensor_cnr_id, &alarm.channel_cnr_id, pool)? {
-            if measure_min > alarm.range_min && measure_max < alarm.range_max {
-                alarm_end(conn, alarm.channel_id)?;
+        let key = (alarm.sensor_cnr_id.clone(), alarm.channel_cnr_id.clone());
+        if let Some((measure_min, measure_max,  _measure_time)) = last_measures.get(&key) {
+            let in_range = *measure_min > alarm.range_min + alarm.hysteresis && *measure_max < alarm.range_max - alarm.hysteresis;
+            if in_range && !changing_channels.contains(&alarm.channel_id) {
+                actions.push(AlarmAction::End { channel_id: alarm.channel_id });
             }
         }
     }
 
-    Ok(())
+    Ok(Some((last_measure.2, actions)))
+}
+
+/// Checks the measures of a single site and applies the alarm changes, returning the new clock of
+/// the site (or None if the site has no measures). The site status is updated in both cases.
+async fn check_site_measures<R: ReadingsStore>(outputs: &AlarmOutputs<'_>, conn: &Connection, store: &R, site_id: IdType, cnr_id: &str, clock: NaiveDateTime) -> Result<Option<NaiveDateTime>, DatabaseError> {
+    // Loaded before the new alarms begin, so that they aren't checked in the same tick (or we
+    // could have alarms that last 0 seconds).
+    let alarmed_data = load_alarmed_data(conn, site_id)?;
+    let mut channels_alarm_data = load_channels_alarm_data(conn, site_id)?;
+    let invalid_channels = load_invalid_channels(conn, site_id, clock)?;
+    channels_alarm_data.retain(|x| !invalid_channels.contains(&x.channel_id));
+
+    // Checked even if the site has no new measures, that's when it matters
+    check_site_silence(outputs.contacter, conn, store, site_id, cnr_id, Utc::now().naive_utc()).await?;
+    if let Some(policy) = outputs.flapping {
+        clear_flapping(conn, policy, site_id, Utc::now().naive_utc())?;
+    }
+
+    let (new_clock, actions) = match evaluate_site_alarms(store, cnr_id, clock, &channels_alarm_data, &alarmed_data)? {
+        Some(x) => x,
+        None => {
+            update_site_status(outputs.contacter, conn, site_id, Utc::now().naive_utc()).await?;
+            return Ok(None)
+        },
+    };
+
+    for action in actions {
+        match action {
+            AlarmAction::Begin { channel_id, measure, measure_type } => {
+                alarm_begin(outputs, conn, channel_id, measure, measure_type).await?
+            },
+            AlarmAction::Peak { channel_id, measure, measure_type } => alarm_peak(conn, channel_id, measure, measure_type)?,
+            AlarmAction::End { channel_id } => alarm_end(outputs.siem, conn, channel_id)?,
+            AlarmAction::Pending { channel_id, since } => alarm_pending(conn, channel_id, Some(since))?,
+            AlarmAction::PendingCancel { channel_id } => alarm_pending(conn, channel_id, None)?,
+        }
+    }
+    update_site_status(outputs.contacter, conn, site_id, Utc::now().naive_utc()).await?;
+
+    Ok(Some(new_clock))
 }
 
-async fn alarm_begin(contacter: &Contacter, conn: &Connection, channel_id: IdType, measure: f64, measure_type: MeasureExtremeType) -> Result<(), DatabaseError> {
+async fn alarm_begin(outputs: &AlarmOutputs<'_>, conn: &Connection, channel_id: IdType, measure: f64, measure_type: MeasureExtremeType) -> Result<(), DatabaseError> {
     use crate::schema::channel::dsl;
+    use crate::schema::alarm_event::dsl as event_dsl;
     warn!("alarm_begin({} {} {:?})", channel_id, measure, measure_type);
 
+    let now = Utc::now().naive_utc();
+    conn.transaction::<_, DieselError, _>(|| {
+        diesel::update(dsl::channel.find(channel_id))
+            .set((dsl::alarmed.eq(true), dsl::alarm_pending_since.eq(Option::<NaiveDateTime>::None)))
+            .execute(conn)?;
+
+        diesel::insert_into(event_dsl::alarm_event)
+            .values((
+                event_dsl::channel_id.eq(channel_id),
+                event_dsl::started_at.eq(now),
+                event_dsl::peak_value.eq(measure),
+                event_dsl::extreme_type.eq(measure_type.to_char()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })?;
+    outputs.siem.send(SiemEvent::AlarmBegin { time: now, channel_id, value: measure, extreme: measure_type });
+
+    if let Some(policy) = outputs.flapping {
+        if check_flapping(outputs.contacter, conn, policy, channel_id, Utc::now().naive_utc()).await? {
+            debug!("channel {} is flapping, alarm not notified", channel_id);
+            return Ok(())
+        }
+    }
+    if load_silenced_channels(conn, &[channel_id], Utc::now().naive_utc())?.contains(&channel_id) {
+        debug!("channel {} is silenced, alarm not notified", channel_id);
+        return Ok(())
+    }
+    outputs.contacter.send_alarm(conn, channel_id, measure, measure_type).await?;
+
+    Ok(())
+}
+
+/// Marks the channel as out of range since the given time (or back in range with None) while
+/// waiting for its alarm delay.
+fn alarm_pending(conn: &Connection, channel_id: IdType, since: Option<NaiveDateTime>) -> QueryResult<()> {
+    use crate::schema::channel::dsl;
+    debug!("alarm_pending({} {:?})", channel_id, since);
+
     diesel::update(dsl::channel.find(channel_id))
-        .set(dsl::alarmed.eq(true))
+        .set(dsl::alarm_pending_since.eq(since))
         .execute(conn)?;
+    Ok(())
+}
+
+/// Returns the channels (between the given ones) whose alarms shouldn't be notified: the ones
+/// muted until after now and the ones with an acknowledged ongoing alarm.
+pub fn load_silenced_channels(conn: &Connection, channel_ids: &[IdType], now: NaiveDateTime) -> QueryResult<HashSet<IdType>> {
+    use crate::schema::alarm_event::dsl as event_dsl;
+    use crate::schema::channel_mute::dsl as mute_dsl;
+
+    let mut silenced: HashSet<IdType> = mute_dsl::channel_mute
+        .filter(mute_dsl::channel_id.eq_any(channel_ids))
+        .filter(mute_dsl::muted_until.gt(now))
+        .select(mute_dsl::channel_id)
+        .load::<IdType>(conn)?
+        .into_iter()
+        .collect();
+
+    silenced.extend(event_dsl::alarm_event
+        .filter(event_dsl::channel_id.eq_any(channel_ids))
+        .filter(event_dsl::ended_at.is_null())
+        .filter(event_dsl::acknowledged_at.is_not_null())
+        .select(event_dsl::channel_id)
+        .load::<IdType>(conn)?);
+    Ok(silenced)
+}
 
-    contacter.send_alarm(conn, channel_id, measure, measure_type).await?;
+/// Updates the peak of the open alarm event of the channel, only if the new measure is more
+/// extreme than the old one (in the same direction).
+fn alarm_peak(conn: &Connection, channel_id: IdType, measure: f64, measure_type: MeasureExtremeType) -> QueryResult<()> {
+    use crate::schema::alarm_event::dsl;
+
+    let open_events = dsl::alarm_event
+        .filter(dsl::channel_id.eq(channel_id))
+        .filter(dsl::ended_at.is_null())
+        .filter(dsl::extreme_type.eq(measure_type.to_char()));
+
+    match measure_type {
+        MeasureExtremeType::Min => diesel::update(open_events.filter(dsl::peak_value.gt(measure)))
+            .set(dsl::peak_value.eq(measure))
+            .execute(conn)?,
+        MeasureExtremeType::Max | MeasureExtremeType::Delta => diesel::update(open_events.filter(dsl::peak_value.lt(measure)))
+            .set(dsl::peak_value.eq(measure))
+            .execute(conn)?,
+    };
 
     Ok(())
 }
 
-fn alarm_end(conn: &Connection, channel_id: IdType) -> QueryResult<()> {
+fn alarm_end(siem: &SiemSink, conn: &Connection, channel_id: IdType) -> QueryResult<()> {
     use crate::schema::channel::dsl;
+    use crate::schema::alarm_event::dsl as event_dsl;
     warn!("alarm_end({})", channel_id);
 
-    diesel::update(dsl::channel.find(channel_id))
-        .set(dsl::alarmed.eq(false))
-        .execute(conn)?;
+    let now = Utc::now().naive_utc();
+    conn.transaction::<_, DieselError, _>(|| {
+        diesel::update(dsl::channel.find(channel_id))
+            .set(dsl::alarmed.eq(false))
+            .execute(conn)?;
+
+        diesel::update(event_dsl::alarm_event
+            .filter(event_dsl::channel_id.eq(channel_id))
+            .filter(event_dsl::ended_at.is_null()))
+            .set(event_dsl::ended_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    })?;
+    siem.send(SiemEvent::AlarmEnd { time: now, channel_id });
 
     // TODO: Reset fcm?
 
@@ -378,8 +725,329 @@ fn from(error: MysqlError) -> DatabaseError {
     }
 }
 
+impl From<FromRowError> for DatabaseError {
+    fn from(error: FromRowError) -> DatabaseError {
+        MysqlError::from(error).into()
+    }
+}
+
 impl From<String> for DatabaseError {
     fn from(error: String) -> DatabaseError {
         DatabaseError(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use super::super::readings::{DELTA_LOOKBEHIND_HOURS, Measure};
+
+    struct Reading {
+        site_id: &'static str,
+        sensor_id: &'static str,
+        channel_id: &'static str,
+        min: f64,
+        max: f64,
+        date: NaiveDateTime,
+    }
+
+    /// In-memory replacement of the sensor database
+    #[derive(Default)]
+    struct MemoryReadingsStore {
+        readings: Vec<Reading>,
+    }
+
+    impl MemoryReadingsStore {
+        fn add(mut self, sensor_id: &'static str, channel_id: &'static str, min: f64, max: f64, minute: u32) -> Self {
+            self.readings.push(Reading { site_id: "site", sensor_id, channel_id, min, max, date: at(minute) });
+            self
+        }
+    }
+
+    impl ReadingsStore for MemoryReadingsStore {
+        fn load_channel_data(&self, site_id: &str, clock: NaiveDateTime) -> Result<Vec<SiteData>, DatabaseError> {
+            let since = clock - chrono::Duration::hours(DELTA_LOOKBEHIND_HOURS);
+            let mut res: Vec<SiteData> = Vec::new();
+            for x in self.readings.iter().filter(|x| x.site_id == site_id && x.date > clock) {
+                let delta = self.readings.iter()
+                    .filter(|p| p.site_id == site_id && p.sensor_id == x.sensor_id && p.channel_id == x.channel_id)
+                    .filter(|p| p.date < x.date && p.date > since)
+                    .max_by_key(|p| p.date)
+                    .map(|p| (x.min - p.min).abs() * 3600.0 / (x.date - p.date).num_seconds() as f64);
+                match res.iter_mut().find(|d| d.sensor_id == x.sensor_id && d.channel_id == x.channel_id) {
+                    Some(d) => {
+                        d.min_value = d.min_value.min(x.min);
+                        d.max_value = d.max_value.max(x.max);
+                        d.max_delta = d.max_delta.into_iter().chain(delta).reduce(f64::max);
+                    },
+                    None => res.push(SiteData {
+                        min_value: x.min,
+                        max_value: x.max,
+                        max_delta: delta,
+                        sensor_id: x.sensor_id.to_string(),
+                        channel_id: x.channel_id.to_string(),
+                    }),
+                }
+            }
+            Ok(res)
+        }
+
+        fn load_last_site_measure(&self, site_id: &str) -> Result<Option<Measure>, DatabaseError> {
+            Ok(self.readings.iter()
+                .filter(|x| x.site_id == site_id)
+                .max_by_key(|x| x.date)
+                .map(|x| (x.min, x.max, x.date)))
+        }
+
+        fn load_last_channel_measure(&self, site_id: &str, sensor_id: &str, channel_id: &str) -> Result<Option<Measure>, DatabaseError> {
+            Ok(self.readings.iter()
+                .filter(|x| x.site_id == site_id && x.sensor_id == sensor_id && x.channel_id == channel_id)
+                .max_by_key(|x| x.date)
+                .map(|x| (x.min, x.max, x.date)))
+        }
+    }
+
+    fn at(minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 1, 1).and_hms(12, minute, 0)
+    }
+
+    fn channel(channel_id: IdType, channel_cnr_id: &str, range_min: f64, range_max: f64) -> ChannelAlarmData {
+        ChannelAlarmData {
+            sensor_id: 1,
+            sensor_cnr_id: "s1".to_string(),
+            channel_id,
+            channel_cnr_id: channel_cnr_id.to_string(),
+            range_min,
+            range_max,
+            max_delta_per_hour: std::f64::INFINITY,
+            alarm_delay: chrono::Duration::zero(),
+            alarm_pending_since: None,
+        }
+    }
+
+    fn alarmed(data: &ChannelAlarmData) -> AlarmedChannelData {
+        AlarmedChannelData {
+            channel_id: data.channel_id,
+            sensor_cnr_id: data.sensor_cnr_id.clone(),
+            channel_cnr_id: data.channel_cnr_id.clone(),
+            range_min: data.range_min,
+            range_max: data.range_max,
+            hysteresis: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_alarm_evaluation() {
+        let temp = channel(1, "c1", 10.0, 20.0);
+        let humidity = channel(2, "c2", 40.0, 60.0);
+        let pressure = ChannelAlarmData {
+            max_delta_per_hour: 30.0,
+            ..channel(3, "c3", 40.0, 60.0)
+        };
+        let co2 = ChannelAlarmData {
+            alarm_delay: chrono::Duration::minutes(10),
+            ..channel(4, "c4", 10.0, 20.0)
+        };
+        let co2_pending = ChannelAlarmData {
+            alarm_pending_since: Some(at(1)),
+            ..co2.clone()
+        };
+        let channels = vec![temp.clone(), humidity.clone(), pressure.clone(), co2.clone()];
+
+        struct Case {
+            name: &'static str,
+            store: MemoryReadingsStore,
+            clock: NaiveDateTime,
+            alarmed: Vec<AlarmedChannelData>,
+            expected: Option<(NaiveDateTime, Vec<AlarmAction>)>,
+        }
+        let with_pending = vec![temp.clone(), humidity.clone(), pressure.clone(), co2_pending];
+
+        let cases = vec![
+            Case {
+                name: "no readings",
+                store: MemoryReadingsStore::default(),
+                clock: at(0),
+                alarmed: vec![],
+                expected: None,
+            },
+            Case {
+                name: "in range",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 12.0, 13.0, 1)
+                    .add("s1", "c2", 50.0, 51.0, 2),
+                clock: at(0),
+                alarmed: vec![],
+                expected: Some((at(2), vec![])),
+            },
+            Case {
+                name: "begin min and max",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 5.0, 13.0, 1)
+                    .add("s1", "c2", 50.0, 70.0, 2),
+                clock: at(0),
+                alarmed: vec![],
+                expected: Some((at(2), vec![
+                    AlarmAction::Begin { channel_id: 1, measure: 5.0, measure_type: MeasureExtremeType::Min },
+                    AlarmAction::Begin { channel_id: 2, measure: 70.0, measure_type: MeasureExtremeType::Max },
+                ])),
+            },
+            Case {
+                name: "readings older than the clock are ignored",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 5.0, 13.0, 1)
+                    .add("s1", "c1", 12.0, 13.0, 3),
+                clock: at(2),
+                alarmed: vec![],
+                expected: Some((at(3), vec![])),
+            },
+            Case {
+                name: "already alarmed channels don't begin again",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 5.0, 13.0, 1),
+                clock: at(0),
+                alarmed: vec![alarmed(&temp)],
+                expected: Some((at(1), vec![
+                    AlarmAction::Peak { channel_id: 1, measure: 5.0, measure_type: MeasureExtremeType::Min },
+                ])),
+            },
+            Case {
+                name: "alarm ends when the last measure is in range",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 5.0, 13.0, 1)
+                    .add("s1", "c1", 12.0, 13.0, 2),
+                clock: at(0),
+                alarmed: vec![alarmed(&temp)],
+                expected: Some((at(2), vec![
+                    AlarmAction::Peak { channel_id: 1, measure: 5.0, measure_type: MeasureExtremeType::Min },
+                    AlarmAction::End { channel_id: 1 },
+                ])),
+            },
+            Case {
+                name: "alarmed channels in range have no peak",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c2", 30.0, 50.0, 1)
+                    .add("s1", "c2", 45.0, 50.0, 2),
+                clock: at(1),
+                alarmed: vec![alarmed(&humidity)],
+                expected: Some((at(2), vec![AlarmAction::End { channel_id: 2 }])),
+            },
+            Case {
+                name: "begin delta",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c3", 45.0, 45.0, 10)
+                    .add("s1", "c3", 55.0, 55.0, 20),
+                clock: at(15),
+                alarmed: vec![],
+                expected: Some((at(20), vec![
+                    AlarmAction::Begin { channel_id: 3, measure: 60.0, measure_type: MeasureExtremeType::Delta },
+                ])),
+            },
+            Case {
+                name: "delta alarm doesn't end while the channel changes too fast",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c3", 45.0, 45.0, 10)
+                    .add("s1", "c3", 55.0, 55.0, 20)
+                    .add("s1", "c3", 56.0, 56.0, 50),
+                clock: at(15),
+                alarmed: vec![alarmed(&pressure)],
+                expected: Some((at(50), vec![
+                    AlarmAction::Peak { channel_id: 3, measure: 60.0, measure_type: MeasureExtremeType::Delta },
+                ])),
+            },
+            Case {
+                name: "delta alarm ends when the channel is stable",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c3", 55.0, 55.0, 20)
+                    .add("s1", "c3", 56.0, 56.0, 50),
+                clock: at(30),
+                alarmed: vec![alarmed(&pressure)],
+                expected: Some((at(50), vec![AlarmAction::End { channel_id: 3 }])),
+            },
+            Case {
+                name: "alarm doesn't end inside the hysteresis",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 19.0, 19.5, 2),
+                clock: at(1),
+                alarmed: vec![AlarmedChannelData { hysteresis: 1.0, ..alarmed(&temp) }],
+                expected: Some((at(2), vec![])),
+            },
+            Case {
+                name: "alarm ends past the hysteresis",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c1", 18.0, 18.5, 2),
+                clock: at(1),
+                alarmed: vec![AlarmedChannelData { hysteresis: 1.0, ..alarmed(&temp) }],
+                expected: Some((at(2), vec![AlarmAction::End { channel_id: 1 }])),
+            },
+            Case {
+                name: "delayed alarm is pending",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c4", 25.0, 25.0, 1),
+                clock: at(0),
+                alarmed: vec![],
+                expected: Some((at(1), vec![AlarmAction::Pending { channel_id: 4, since: at(1) }])),
+            },
+            Case {
+                name: "unknown channels are ignored",
+                store: MemoryReadingsStore::default()
+                    .add("s1", "c9", -100.0, 100.0, 4),
+                clock: at(0),
+                alarmed: vec![],
+                expected: Some((at(4), vec![])),
+            },
+        ];
+
+        for case in cases {
+            let res = evaluate_site_alarms(&case.store, "site", case.clock, &channels, &case.alarmed).unwrap();
+            assert_eq!(res, case.expected, "case: {}", case.name);
+        }
+
+        // The pending alarm begins when the delay is over, if the channel is still out of range
+        let store = MemoryReadingsStore::default()
+            .add("s1", "c4", 25.0, 25.0, 1)
+            .add("s1", "c4", 26.0, 26.0, 8);
+        assert_eq!(evaluate_site_alarms(&store, "site", at(1), &with_pending, &[]).unwrap(), Some((at(8), vec![])));
+        let store = store.add("s1", "c4", 24.0, 24.0, 11);
+        assert_eq!(evaluate_site_alarms(&store, "site", at(8), &with_pending, &[]).unwrap(), Some((at(11), vec![
+            AlarmAction::Begin { channel_id: 4, measure: 24.0, measure_type: MeasureExtremeType::Max },
+        ])));
+        let store = MemoryReadingsStore::default()
+            .add("s1", "c4", 25.0, 25.0, 1)
+            .add("s1", "c4", 15.0, 15.0, 5);
+        assert_eq!(evaluate_site_alarms(&store, "site", at(1), &with_pending, &[]).unwrap(), Some((at(5), vec![
+            AlarmAction::PendingCancel { channel_id: 4 },
+        ])));
+    }
+
+    #[test]
+    fn test_silence_evaluation() {
+        let sensor = |sensor_id: IdType, sensor_cnr_id: &str, no_data_since: Option<NaiveDateTime>| SensorSilenceData {
+            sensor_id,
+            sensor_cnr_id: sensor_cnr_id.to_string(),
+            channel_cnr_ids: vec!["c1".to_string(), "c2".to_string()],
+            max_silence: chrono::Duration::minutes(10),
+            no_data_since,
+        };
+        let store = MemoryReadingsStore::default()
+            .add("s1", "c1", 1.0, 1.0, 5)
+            .add("s1", "c2", 1.0, 1.0, 25)
+            .add("s2", "c1", 1.0, 1.0, 10);
+
+        // s1 reported at minute 25, s2 at minute 10 and s3 never
+        let sensors = vec![sensor(1, "s1", None), sensor(2, "s2", None), sensor(3, "s3", None)];
+        assert_eq!(evaluate_site_silence(&store, "site", at(30), &sensors).unwrap(), vec![
+            SilenceAction::Begin { sensor_id: 2, last_measure: Some(at(10)) },
+            SilenceAction::Begin { sensor_id: 3, last_measure: None },
+        ]);
+
+        // Raised only once, and ended when the sensor reports again
+        let sensors = vec![sensor(1, "s1", Some(at(20))), sensor(2, "s2", Some(at(20)))];
+        assert_eq!(evaluate_site_silence(&store, "site", at(30), &sensors).unwrap(), vec![
+            SilenceAction::End { sensor_id: 1 },
+        ]);
+        assert_eq!(evaluate_site_silence(&store, "site", at(30), &[]).unwrap(), vec![]);
+    }
+}
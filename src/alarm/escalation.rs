@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::contact::{Contacter, EscalationData};
+use crate::models::{AccessLevel, EscalationPolicy, IdType, PermissionType};
+
+/// Contact group notified by an escalation policy
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum EscalationTarget {
+    /// Every admin
+    Admins,
+    /// The users that can manage the site of the alarm
+    SiteManagers,
+    /// The users listed in the policy
+    Users,
+}
+
+impl EscalationTarget {
+    pub fn from_char(name: &str) -> Option<EscalationTarget> {
+        match name {
+            "a" => Some(EscalationTarget::Admins),
+            "m" => Some(EscalationTarget::SiteManagers),
+            "u" => Some(EscalationTarget::Users),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            EscalationTarget::Admins => "a",
+            EscalationTarget::SiteManagers => "m",
+            EscalationTarget::Users => "u",
+        }
+    }
+}
+
+fn get_target_users(conn: &PgConnection, policy: &EscalationPolicy, site_id: IdType) -> QueryResult<Vec<IdType>> {
+    use crate::schema::{
+        user_access::dsl as access_dsl,
+        user_account::dsl as user_dsl,
+    };
+
+    match EscalationTarget::from_char(&policy.target) {
+        Some(EscalationTarget::Admins) => user_dsl::user_account
+            .filter(user_dsl::permission.eq(PermissionType::Admin))
+            .select(user_dsl::id)
+            .load(conn),
+        Some(EscalationTarget::SiteManagers) => access_dsl::user_access
+            .filter(access_dsl::site_id.eq(site_id))
+            .filter(access_dsl::level.eq_any(vec![AccessLevel::Manage, AccessLevel::Admin]))
+            .select(access_dsl::user_id)
+            .load(conn),
+        Some(EscalationTarget::Users) => Ok(policy.user_ids.clone()),
+        None => {
+            warn!("Unknown target {:?} of escalation policy {}", policy.target, policy.id);
+            Ok(Vec::new())
+        },
+    }
+}
+
+/// Notifies the contact groups of the escalation policies about the ongoing alarms that nobody
+/// acknowledged within the delay of the policy, every alarm is escalated once per policy.
+/// Returns the number of escalations sent.
+pub async fn escalate_alarms(contacter: &Contacter, conn: &PgConnection, now: NaiveDateTime) -> Result<usize, String> {
+    use crate::schema::{
+        alarm_escalation::dsl as escalation_dsl,
+        alarm_event::dsl as event_dsl,
+        channel::dsl as channel_dsl,
+        escalation_policy::dsl as policy_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let policies = policy_dsl::escalation_policy
+        .order(policy_dsl::id)
+        .load::<EscalationPolicy>(conn)
+        .map_err(|x| x.to_string())?;
+    if policies.is_empty() {
+        return Ok(0)
+    }
+
+    let alarms = event_dsl::alarm_event
+        .inner_join(channel_dsl::channel.inner_join(sensor_dsl::sensor.inner_join(site_dsl::site)))
+        .filter(event_dsl::ended_at.is_null())
+        .filter(event_dsl::acknowledged_at.is_null())
+        .select((event_dsl::id, event_dsl::started_at, site_dsl::id, site_dsl::name, sensor_dsl::name, channel_dsl::name))
+        .order(event_dsl::id)
+        .load::<(IdType, NaiveDateTime, IdType, Option<String>, Option<String>, Option<String>)>(conn)
+        .map_err(|x| x.to_string())?;
+
+    let escalated: HashSet<(IdType, IdType)> = escalation_dsl::alarm_escalation
+        .filter(escalation_dsl::alarm_event_id.eq_any(alarms.iter().map(|x| x.0).collect::<Vec<_>>()))
+        .select((escalation_dsl::alarm_event_id, escalation_dsl::policy_id))
+        .load(conn)
+        .map_err(|x| x.to_string())?
+        .into_iter()
+        .collect();
+
+    let mut sent = 0;
+    for (event_id, started_at, site_id, site_name, sensor_name, channel_name) in alarms {
+        let due = policies.iter()
+            .filter(|x| x.site_id.map_or(true, |x| x == site_id))
+            .filter(|x| started_at + Duration::minutes(x.delay_minutes.into()) <= now)
+            .filter(|x| !escalated.contains(&(event_id, x.id)));
+
+        for policy in due {
+            let data = EscalationData {
+                user_ids: get_target_users(conn, policy, site_id).map_err(|x| x.to_string())?,
+                site_name: site_name.clone().unwrap_or_else(|| "?".to_string()),
+                sensor_name: sensor_name.clone().unwrap_or_else(|| "?".to_string()),
+                channel_name: channel_name.clone().unwrap_or_else(|| "?".to_string()),
+                started_at,
+                delay_minutes: policy.delay_minutes,
+            };
+            if let Err(e) = contacter.send_escalation(conn, &data).await {
+                warn!("Cannot escalate the alarm {} with policy {}: {}", event_id, policy.id, e);
+                continue
+            }
+            diesel::insert_into(escalation_dsl::alarm_escalation)
+                .values((
+                    escalation_dsl::alarm_event_id.eq(event_id),
+                    escalation_dsl::policy_id.eq(policy.id),
+                    escalation_dsl::sent_at.eq(now),
+                ))
+                .execute(conn)
+                .map_err(|x| x.to_string())?;
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
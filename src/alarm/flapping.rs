@@ -0,0 +1,117 @@
+//! Channels that keep entering and exiting alarm (ex. a reading hovering around the range limit)
+//! would notify the users at every transition. When `ALARM_FLAPPING_THRESHOLD` is set, a channel
+//! that begins more alarms than that within the window is marked as flapping: its alarms are still
+//! recorded but not notified, and the admins are notified once. The mark is cleared when the
+//! channel doesn't begin any alarm for a whole window.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::config::Resolver;
+use crate::contact::Contacter;
+use crate::models::IdType;
+
+use super::controller::DatabaseError;
+
+#[derive(Clone, Debug)]
+pub struct FlappingPolicy {
+    /// Alarms that can begin within the window before the channel is marked as flapping
+    pub max_alarms: u32,
+    pub window: Duration,
+}
+
+/// `[flapping]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlappingSection {
+    pub threshold: Option<u32>,
+    pub window_minutes: Option<u64>,
+}
+
+impl FlappingPolicy {
+    /// Reads the policy, returns None if the flapping detection is disabled
+    pub fn resolve(r: &mut Resolver, file: FlappingSection) -> Option<FlappingPolicy> {
+        let max_alarms = r.optional("ALARM_FLAPPING_THRESHOLD", file.threshold);
+        let minutes = r.optional("ALARM_FLAPPING_WINDOW_MINUTES", file.window_minutes).unwrap_or(60);
+        Some(FlappingPolicy { max_alarms: max_alarms?, window: Duration::from_secs(minutes * 60) })
+    }
+
+    fn window_start(&self, now: NaiveDateTime) -> NaiveDateTime {
+        now - chrono::Duration::from_std(self.window).unwrap_or_else(|_| chrono::Duration::hours(1))
+    }
+}
+
+/// Called after a new alarm of the channel began, returns true if the alarm must not be notified.
+/// The channel is marked as flapping (notifying the admins) when too many alarms began in the
+/// window, the ones before the mark are notified as usual.
+pub async fn check_flapping(contacter: &Contacter, conn: &PgConnection, policy: &FlappingPolicy, channel_id: IdType, now: NaiveDateTime) -> Result<bool, DatabaseError> {
+    use crate::schema::alarm_event::dsl as event_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+
+    let flapping_since = channel_dsl::channel.find(channel_id)
+        .select(channel_dsl::flapping_since)
+        .first::<Option<NaiveDateTime>>(conn)?;
+    if flapping_since.is_some() {
+        return Ok(true)
+    }
+
+    let alarms = event_dsl::alarm_event
+        .filter(event_dsl::channel_id.eq(channel_id))
+        .filter(event_dsl::started_at.gt(policy.window_start(now)))
+        .count()
+        .get_result::<i64>(conn)?;
+    if alarms <= policy.max_alarms as i64 {
+        return Ok(false)
+    }
+
+    warn!("channel {} is flapping ({} alarms)", channel_id, alarms);
+    diesel::update(channel_dsl::channel.find(channel_id))
+        .set(channel_dsl::flapping_since.eq(now))
+        .execute(conn)?;
+    contacter.send_flapping(conn, channel_id, alarms, policy.window.as_secs() / 60).await?;
+    Ok(true)
+}
+
+/// Clears the mark of the flapping channels of the site that didn't begin any alarm in the last
+/// window, returns how many were cleared.
+pub fn clear_flapping(conn: &PgConnection, policy: &FlappingPolicy, site_id: IdType, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::schema::alarm_event::dsl as event_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+
+    let since = policy.window_start(now);
+    let flapping = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(channel_dsl::flapping_since.le(since))
+        .select(channel_dsl::id)
+        .load::<IdType>(conn)?;
+    if flapping.is_empty() {
+        return Ok(0)
+    }
+
+    let active: HashSet<IdType> = event_dsl::alarm_event
+        .filter(event_dsl::channel_id.eq_any(&flapping))
+        .filter(event_dsl::started_at.gt(since))
+        .select(event_dsl::channel_id)
+        .load::<IdType>(conn)?
+        .into_iter()
+        .collect();
+    let quiet: Vec<IdType> = flapping.into_iter()
+        .filter(|x| !active.contains(x))
+        .collect();
+
+    let cleared = diesel::update(channel_dsl::channel.filter(channel_dsl::id.eq_any(&quiet)))
+        .set(channel_dsl::flapping_since.eq(Option::<NaiveDateTime>::None))
+        .execute(conn)?;
+    if cleared > 0 {
+        info!("channels {:?} stopped flapping", quiet);
+    }
+    Ok(cleared)
+}
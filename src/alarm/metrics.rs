@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{HttpResponse, web};
+use chrono::{NaiveDateTime, Utc};
+
+use crate::AppData;
+use crate::models::IdType;
+
+#[derive(Default)]
+struct MetricsData {
+    last_success: Option<NaiveDateTime>,
+    last_failure: Option<NaiveDateTime>,
+    last_duration: Option<Duration>,
+    failures: u64,
+    site_clocks: BTreeMap<IdType, NaiveDateTime>,
+}
+
+/// Health information about the alarm checks, exported in the prometheus text format so that
+/// operators can be alerted when the measure checks silently stop working.
+#[derive(Clone, Default)]
+pub struct AlarmMetrics {
+    data: Arc<Mutex<MetricsData>>,
+}
+
+impl AlarmMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_success(&self, duration: Duration) {
+        let mut data = self.data.lock().unwrap();
+        data.last_success = Some(Utc::now().naive_utc());
+        data.last_duration = Some(duration);
+    }
+
+    pub fn record_failure(&self, duration: Duration) {
+        let mut data = self.data.lock().unwrap();
+        data.last_failure = Some(Utc::now().naive_utc());
+        data.last_duration = Some(duration);
+        data.failures += 1;
+    }
+
+    pub fn update_site_clock(&self, site_id: IdType, clock: NaiveDateTime) {
+        self.data.lock().unwrap().site_clocks.insert(site_id, clock);
+    }
+
+    pub fn last_success(&self) -> Option<NaiveDateTime> {
+        self.data.lock().unwrap().last_success
+    }
+
+    pub fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut res = String::new();
+
+        // Writing to a String cannot fail
+        let _ = writeln!(res, "# HELP oldmusa_alarm_check_last_success_timestamp_seconds Time of the last successful alarm check.");
+        let _ = writeln!(res, "# TYPE oldmusa_alarm_check_last_success_timestamp_seconds gauge");
+        let _ = writeln!(res, "oldmusa_alarm_check_last_success_timestamp_seconds {}", data.last_success.map(|x| x.timestamp()).unwrap_or(0));
+
+        let _ = writeln!(res, "# HELP oldmusa_alarm_check_last_failure_timestamp_seconds Time of the last failed alarm check.");
+        let _ = writeln!(res, "# TYPE oldmusa_alarm_check_last_failure_timestamp_seconds gauge");
+        let _ = writeln!(res, "oldmusa_alarm_check_last_failure_timestamp_seconds {}", data.last_failure.map(|x| x.timestamp()).unwrap_or(0));
+
+        let _ = writeln!(res, "# HELP oldmusa_alarm_check_duration_seconds Duration of the last alarm check.");
+        let _ = writeln!(res, "# TYPE oldmusa_alarm_check_duration_seconds gauge");
+        let _ = writeln!(res, "oldmusa_alarm_check_duration_seconds {}", data.last_duration.map(|x| x.as_secs_f64()).unwrap_or(0.0));
+
+        let _ = writeln!(res, "# HELP oldmusa_alarm_check_failures_total Number of failed alarm checks.");
+        let _ = writeln!(res, "# TYPE oldmusa_alarm_check_failures_total counter");
+        let _ = writeln!(res, "oldmusa_alarm_check_failures_total {}", data.failures);
+
+        let _ = writeln!(res, "# HELP oldmusa_site_clock_timestamp_seconds Timestamp of the last measure processed for the site.");
+        let _ = writeln!(res, "# TYPE oldmusa_site_clock_timestamp_seconds gauge");
+        for (site_id, clock) in data.site_clocks.iter() {
+            let _ = writeln!(res, "oldmusa_site_clock_timestamp_seconds{{site_id=\"{}\"}} {}", site_id, clock.timestamp());
+        }
+
+        res
+    }
+}
+
+pub async fn metrics(ctx: web::Data<AppData>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(ctx.alarm_metrics.render())
+}
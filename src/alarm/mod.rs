@@ -1,4 +1,15 @@
 mod actor;
 mod controller;
+mod escalation;
+mod flapping;
+mod metrics;
+mod readings;
+mod site_status;
 
-pub use actor::AlarmActor;
+pub use actor::{AlarmActor, CheckMeasures, Drain};
+pub use controller::{DatabaseError, load_silenced_channels};
+pub use escalation::{EscalationTarget, escalate_alarms};
+pub use flapping::{FlappingPolicy, FlappingSection, check_flapping, clear_flapping};
+pub use metrics::{AlarmMetrics, metrics};
+pub use readings::{Measure, NewReading, ReadingsStore, ReadingsWriter};
+pub use site_status::{evaluate_site_status, load_affected_sensors, load_site_status, update_site_status};
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use mysql::params;
+use mysql::Value;
+
+use super::controller::DatabaseError;
+
+/// A single measure as seen by the alarm controller: (min_measure, max_measure, timestamp)
+pub type Measure = (f64, f64, NaiveDateTime);
+
+/// Readings older than the clock by more than this aren't compared with the new ones to compute
+/// the rate of change
+pub const DELTA_LOOKBEHIND_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteData {
+    pub min_value: f64,
+    pub max_value: f64,
+    /// Fastest change per hour between two consecutive readings (None if there's only one)
+    pub max_delta: Option<f64>,
+    pub sensor_id: String,
+    pub channel_id: String,
+}
+
+/// A reading acquired by the server itself (ex. from a Modbus device), identified by cnr ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewReading {
+    pub site_id: String,
+    pub room_id: String,
+    pub station_id: String,
+    pub sensor_id: String,
+    pub channel_id: String,
+    pub value: f64,
+    pub measure_unit: String,
+    pub date: NaiveDateTime,
+}
+
+/// Destination of the readings that are not acquired by the CNR chain.
+pub trait ReadingsWriter {
+    fn insert_readings(&self, readings: &[NewReading]) -> Result<(), DatabaseError>;
+}
+
+/// Source of the readings used by the alarm controller.
+///
+/// Every id used here is a cnr id, the mapping between them and the configured channels is done
+/// by the controller.
+pub trait ReadingsStore {
+    /// Loads all of the measures that are newer than the clock, and returns the minimum value, the
+    /// maximum value and the fastest change for every channel of the site.
+    /// Every new measure is compared with the previous one to compute its change per hour, even
+    /// if the previous one is older than the clock (up to DELTA_LOOKBEHIND_HOURS).
+    fn load_channel_data(&self, site_id: &str, clock: NaiveDateTime) -> Result<Vec<SiteData>, DatabaseError>;
+
+    /// Loads the last measure of the site (among every channel).
+    /// Returns None if the site has no measures.
+    fn load_last_site_measure(&self, site_id: &str) -> Result<Option<Measure>, DatabaseError>;
+
+    /// Loads the last measure in a channel using the chronological order.
+    /// The channel must be specified fully by the site, the sensor and the channel ids.
+    fn load_last_channel_measure(&self, site_id: &str, sensor_id: &str, channel_id: &str) -> Result<Option<Measure>, DatabaseError>;
+
+    /// Loads the last measure of every (sensor, channel) pair of the site, the channels without
+    /// any measure are not returned.
+    fn load_last_channel_measures(&self, site_id: &str, channels: &[(&str, &str)]) -> Result<HashMap<(String, String), Measure>, DatabaseError> {
+        let mut res = HashMap::new();
+        for (sensor_id, channel_id) in channels {
+            if let Some(measure) = self.load_last_channel_measure(site_id, sensor_id, channel_id)? {
+                res.insert((sensor_id.to_string(), channel_id.to_string()), measure);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl ReadingsStore for mysql::Pool {
+    fn load_channel_data(&self, site_id: &str, clock: NaiveDateTime) -> Result<Vec<SiteData>, DatabaseError> {
+        // The average is compared when present, the aggregated readings have no single value
+        let result = self.prep_exec(
+            "SELECT min(valore_min), max(valore_max), max(delta), idsensore, canale FROM (\
+                SELECT valore_min, valore_max, data, idstazione, idsensore, canale, \
+                ABS(COALESCE(valore_med, valore_min) - LAG(COALESCE(valore_med, valore_min)) OVER w) * 3600 \
+                / NULLIF(TIMESTAMPDIFF(SECOND, LAG(data) OVER w, data), 0) AS delta \
+                FROM t_rilevamento_dati WHERE idsito = :site_id AND data > :since \
+                WINDOW w AS (PARTITION BY idstazione, idsensore, canale ORDER BY data)\
+             ) AS tmp WHERE data > :clock GROUP BY idstazione, idsensore, canale;",
+            params!{
+                "site_id" => site_id,
+                "since" => clock - chrono::Duration::hours(DELTA_LOOKBEHIND_HOURS),
+                "clock" => clock
+            }
+        )?;
+        result.map(|row| {
+            let (min_value, max_value, max_delta, sensor_id, channel_id) =
+                mysql::from_row_opt::<(f64, f64, Option<f64>, String, String)>(row?)?;
+            Ok(SiteData { min_value, max_value, max_delta, sensor_id, channel_id })
+        }).collect()
+    }
+
+    fn load_last_site_measure(&self, site_id: &str) -> Result<Option<Measure>, DatabaseError> {
+        let mut result = self.prep_exec(
+            "SELECT valore_min, valore_max, data FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT 1;",
+            params!{"site_id" => site_id}
+        )?;
+        match result.next() {
+            Some(row) => Ok(Some(mysql::from_row_opt::<Measure>(row?)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn load_last_channel_measure(&self, site_id: &str, sensor_id: &str, channel_id: &str) -> Result<Option<Measure>, DatabaseError> {
+        let mut result = self.prep_exec(
+            "SELECT valore_min, valore_max, data FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id AND canale = :channel_id ORDER BY data DESC LIMIT 1;",
+            params!{
+                "site_id" => site_id,
+                "sensor_id" => sensor_id,
+                "channel_id" => channel_id
+            }
+        )?;
+        match result.next() {
+            Some(row) => Ok(Some(mysql::from_row_opt::<Measure>(row?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn load_last_channel_measures(&self, site_id: &str, channels: &[(&str, &str)]) -> Result<HashMap<(String, String), Measure>, DatabaseError> {
+        if channels.is_empty() {
+            return Ok(HashMap::new())
+        }
+
+        // A single query instead of one for each channel: the rows are numbered by date for every
+        // (sensor, channel) pair and only the newest one is kept.
+        let filter = vec!["(?, ?)"; channels.len()].join(", ");
+        let query = format!(
+            "SELECT valore_min, valore_max, data, idsensore, canale FROM (\
+                SELECT valore_min, valore_max, data, idsensore, canale, \
+                ROW_NUMBER() OVER (PARTITION BY idsensore, canale ORDER BY data DESC) AS row_index \
+                FROM t_rilevamento_dati WHERE idsito = ? AND (idsensore, canale) IN ({})\
+             ) AS tmp WHERE row_index = 1;",
+            filter
+        );
+
+        let mut params: Vec<Value> = Vec::with_capacity(1 + channels.len() * 2);
+        params.push(site_id.into());
+        for (sensor_id, channel_id) in channels {
+            params.push((*sensor_id).into());
+            params.push((*channel_id).into());
+        }
+
+        let result = self.prep_exec(query, params)?;
+        result.map(|row| {
+            let (min_value, max_value, date, sensor_id, channel_id) =
+                mysql::from_row_opt::<(f64, f64, NaiveDateTime, String, String)>(row?)?;
+            Ok(((sensor_id, channel_id), (min_value, max_value, date)))
+        }).collect()
+    }
+}
+
+impl ReadingsWriter for mysql::Pool {
+    fn insert_readings(&self, readings: &[NewReading]) -> Result<(), DatabaseError> {
+        if readings.is_empty() {
+            return Ok(())
+        }
+
+        let mut stmt = self.prepare(
+            "INSERT INTO t_rilevamento_dati (idsito, idstanza, idstazione, idsensore, canale, valore_min, valore_med, valore_max, data, misura) \
+             VALUES (:site_id, :room_id, :station_id, :sensor_id, :channel_id, :value, :value, :value, :date, :measure_unit);"
+        )?;
+        for reading in readings {
+            stmt.execute(params!{
+                "site_id" => &reading.site_id,
+                "room_id" => &reading.room_id,
+                "station_id" => &reading.station_id,
+                "sensor_id" => &reading.sensor_id,
+                "channel_id" => &reading.channel_id,
+                "value" => reading.value,
+                "date" => reading.date,
+                "measure_unit" => &reading.measure_unit,
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Status of a whole site, so that the building management systems don't have to follow every
+//! single alarm: a site is in ALARM while any of its channels is alarmed, STALE while any of its
+//! sensors is silent (and no channel is alarmed) and OK otherwise. The status is recomputed after
+//! every check of the site measures and its changes are posted to the site webhooks that asked
+//! for them.
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use log::info;
+
+use crate::contact::{AffectedSensorData, Contacter, SiteStatus, SiteStatusData};
+use crate::models::IdType;
+
+use super::controller::DatabaseError;
+
+/// Loads the enabled sensors of the site with alarmed channels or without data, ordered by id.
+pub fn load_affected_sensors(conn: &PgConnection, site_id: IdType) -> QueryResult<Vec<AffectedSensorData>> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+
+    let alarmed = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::enabled.eq(true))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(channel_dsl::deleted_at.is_null())
+        .filter(channel_dsl::alarmed.eq(true))
+        .select((sensor_dsl::id, sensor_dsl::name, channel_dsl::name))
+        .order((sensor_dsl::id, channel_dsl::id))
+        .load::<(IdType, Option<String>, Option<String>)>(conn)?;
+
+    let silent = sensor_dsl::sensor
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::enabled.eq(true))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(sensor_dsl::no_data_since.is_not_null())
+        .select((sensor_dsl::id, sensor_dsl::name))
+        .load::<(IdType, Option<String>)>(conn)?;
+
+    let mut sensors: Vec<AffectedSensorData> = Vec::new();
+    for (sensor_id, sensor_name, channel_name) in alarmed {
+        let channel_name = channel_name.unwrap_or_else(|| "?".to_string());
+        match sensors.last_mut() {
+            Some(x) if x.sensor_id == sensor_id => x.alarmed_channels.push(channel_name),
+            _ => sensors.push(AffectedSensorData {
+                sensor_id,
+                sensor_name: sensor_name.unwrap_or_else(|| "?".to_string()),
+                alarmed_channels: vec![channel_name],
+                no_data: false,
+            }),
+        }
+    }
+    for (sensor_id, sensor_name) in silent {
+        match sensors.iter_mut().find(|x| x.sensor_id == sensor_id) {
+            Some(x) => x.no_data = true,
+            None => sensors.push(AffectedSensorData {
+                sensor_id,
+                sensor_name: sensor_name.unwrap_or_else(|| "?".to_string()),
+                alarmed_channels: Vec::new(),
+                no_data: true,
+            }),
+        }
+    }
+    sensors.sort_by_key(|x| x.sensor_id);
+    Ok(sensors)
+}
+
+pub fn evaluate_site_status(sensors: &[AffectedSensorData]) -> SiteStatus {
+    if sensors.iter().any(|x| !x.alarmed_channels.is_empty()) {
+        SiteStatus::Alarm
+    } else if sensors.iter().any(|x| x.no_data) {
+        SiteStatus::Stale
+    } else {
+        SiteStatus::Ok
+    }
+}
+
+/// Last status saved for the site, OK if it never changed
+pub fn load_site_status(conn: &PgConnection, site_id: IdType) -> QueryResult<SiteStatus> {
+    use crate::schema::site_status::dsl;
+
+    let status = dsl::site_status.find(site_id)
+        .select(dsl::status)
+        .first::<String>(conn)
+        .optional()?;
+    Ok(status.and_then(|x| SiteStatus::from_char(&x)).unwrap_or(SiteStatus::Ok))
+}
+
+/// Recomputes the status of the site, saving and notifying it if it changed.
+/// Returns the new status if it changed.
+pub async fn update_site_status(contacter: &Contacter, conn: &PgConnection, site_id: IdType, now: NaiveDateTime) -> Result<Option<SiteStatus>, DatabaseError> {
+    use crate::schema::site::dsl as site_dsl;
+    use crate::schema::site_status::dsl;
+
+    let previous = load_site_status(conn, site_id)?;
+    let sensors = load_affected_sensors(conn, site_id)?;
+    let status = evaluate_site_status(&sensors);
+    if status == previous {
+        return Ok(None)
+    }
+    info!("site_status({} {:?} -> {:?})", site_id, previous, status);
+
+    diesel::insert_into(dsl::site_status)
+        .values((dsl::site_id.eq(site_id), dsl::status.eq(status.to_char()), dsl::changed_at.eq(now)))
+        .on_conflict(dsl::site_id)
+        .do_update()
+        .set((dsl::status.eq(excluded(dsl::status)), dsl::changed_at.eq(excluded(dsl::changed_at))))
+        .execute(conn)?;
+
+    let site_name = site_dsl::site.find(site_id)
+        .select(site_dsl::name)
+        .first::<Option<String>>(conn)?
+        .unwrap_or_else(|| "?".to_string());
+    let data = SiteStatusData {
+        site_id,
+        site_name,
+        previous,
+        status,
+        sensors,
+    };
+    contacter.send_site_status(conn, &data).await?;
+    Ok(Some(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(alarmed_channels: &[&str], no_data: bool) -> AffectedSensorData {
+        AffectedSensorData {
+            sensor_id: 1,
+            sensor_name: "Hall".to_string(),
+            alarmed_channels: alarmed_channels.iter().map(|x| x.to_string()).collect(),
+            no_data,
+        }
+    }
+
+    #[test]
+    fn test_site_status_evaluation() {
+        assert_eq!(evaluate_site_status(&[]), SiteStatus::Ok);
+        assert_eq!(evaluate_site_status(&[sensor(&[], true)]), SiteStatus::Stale);
+        assert_eq!(evaluate_site_status(&[sensor(&[], true), sensor(&["Temperature"], false)]), SiteStatus::Alarm);
+        assert_eq!(evaluate_site_status(&[sensor(&["Temperature"], true)]), SiteStatus::Alarm);
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::scanner::scan_anomalies;
+
+pub struct AnomalyActor {
+    pub app_data: AppData,
+    pub scan_interval: Duration,
+}
+
+impl AnomalyActor {
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let start = Instant::now();
+        let pool = self.app_data.pool.clone();
+        let sensor_pool = self.app_data.sensor_pool.clone();
+
+        let res = async move {
+            match web::block(move || scan_anomalies(&pool, &sensor_pool, Utc::now().naive_utc())).await {
+                Ok(count) => info!("Found {} anomalies in {}ms", count, start.elapsed().as_millis()),
+                Err(err) => error!("Error during anomaly scan: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for AnomalyActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the anomaly actor");
+
+        IntervalFunc::new(self.scan_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for AnomalyActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the anomaly actor");
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, Timelike};
+
+/// Readings in the previous 24 hours are used as the rolling window
+const ROLLING_WINDOW_HOURS: i64 = 24;
+/// Days used to compute the expected value at the same hour of the day
+const SEASONAL_WINDOW_DAYS: i64 = 7;
+const MIN_ROLLING_SAMPLES: usize = 12;
+const MIN_SEASONAL_SAMPLES: usize = 3;
+/// Flat windows (ex. a stuck sensor) don't have a meaningful deviation
+const MIN_DEVIATION: f64 = 1e-9;
+/// Readings with a score over this are considered anomalous
+pub const ANOMALY_SCORE_THRESHOLD: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum AnomalyKind {
+    /// The reading is far from the ones of the previous hours
+    Rolling,
+    /// The reading is far from the ones at the same hour of the previous days
+    Seasonal,
+}
+
+impl AnomalyKind {
+    pub fn from_char(name: &str) -> Option<AnomalyKind> {
+        match name {
+            "r" => Some(AnomalyKind::Rolling),
+            "s" => Some(AnomalyKind::Seasonal),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            AnomalyKind::Rolling => "r",
+            AnomalyKind::Seasonal => "s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyPeriod {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub max_score: f64,
+    pub kind: AnomalyKind,
+}
+
+fn z_score(value: f64, samples: impl Iterator<Item=f64>) -> Option<(f64, usize)> {
+    let (count, sum, square_sum) = samples.fold((0usize, 0.0, 0.0), |(count, sum, square_sum), x| {
+        (count + 1, sum + x, square_sum + x * x)
+    });
+    if count == 0 {
+        return None
+    }
+    let mean = sum / count as f64;
+    let deviation = (square_sum / count as f64 - mean * mean).max(0.0).sqrt();
+    if deviation < MIN_DEVIATION {
+        return None
+    }
+    Some(((value - mean).abs() / deviation, count))
+}
+
+/// Scores every reading newer than `since` (the older ones are only used as history) and groups
+/// the consecutive anomalous ones in periods.
+/// The readings outside of the channel range are skipped, they already trigger an alarm.
+pub fn find_anomalies(readings: &mut [(NaiveDateTime, f64)], since: NaiveDateTime, range: (Option<f64>, Option<f64>)) -> Vec<AnomalyPeriod> {
+    readings.sort_by_key(|x| x.0);
+
+    let mut hourly: HashMap<(NaiveDate, u32), (f64, usize)> = HashMap::new();
+    for (date, value) in readings.iter() {
+        let entry = hourly.entry((date.date(), date.hour())).or_insert((0.0, 0));
+        entry.0 += value;
+        entry.1 += 1;
+    }
+
+    let mut periods: Vec<AnomalyPeriod> = Vec::new();
+    let mut last_anomalous = false;
+    let mut window_start = 0;
+
+    for index in 0..readings.len() {
+        let (date, value) = readings[index];
+        while readings[window_start].0 <= date - Duration::hours(ROLLING_WINDOW_HOURS) {
+            window_start += 1;
+        }
+        if date <= since {
+            continue
+        }
+
+        let in_range = range.0.map_or(true, |min| value >= min) && range.1.map_or(true, |max| value <= max);
+        let rolling = z_score(value, readings[window_start..index].iter().map(|x| x.1))
+            .filter(|x| x.1 >= MIN_ROLLING_SAMPLES)
+            .map(|x| (x.0, AnomalyKind::Rolling));
+        let seasonal = z_score(value, (1..=SEASONAL_WINDOW_DAYS).filter_map(|days| {
+            hourly.get(&((date - Duration::days(days)).date(), date.hour()))
+                .map(|(sum, count)| sum / *count as f64)
+        }))
+            .filter(|x| x.1 >= MIN_SEASONAL_SAMPLES)
+            .map(|x| (x.0, AnomalyKind::Seasonal));
+
+        let score = match (rolling, seasonal) {
+            (Some(a), Some(b)) => Some(if a.0 >= b.0 { a } else { b }),
+            (a, b) => a.or(b),
+        };
+
+        let anomalous = match score {
+            Some((score, kind)) if in_range && score >= ANOMALY_SCORE_THRESHOLD => {
+                match periods.last_mut() {
+                    Some(period) if last_anomalous => {
+                        period.end = date;
+                        if score > period.max_score {
+                            period.max_score = score;
+                            period.kind = kind;
+                        }
+                    },
+                    _ => periods.push(AnomalyPeriod { start: date, end: date, max_score: score, kind }),
+                }
+                true
+            },
+            _ => false,
+        };
+        last_anomalous = anomalous;
+    }
+
+    periods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_anomalies() {
+        let start = NaiveDateTime::from_timestamp(1583020800, 0);
+        let mut readings: Vec<(NaiveDateTime, f64)> = (0..48)
+            .map(|i| (start + Duration::hours(i), 20.0 + (i % 3) as f64 * 0.5))
+            .collect();
+        // A two hour spike that stays in range
+        readings[40].1 = 30.0;
+        readings[41].1 = 31.0;
+
+        let since = start + Duration::hours(24);
+        let periods = find_anomalies(&mut readings.clone(), since, (Some(0.0), Some(40.0)));
+        assert_eq!(periods.len(), 1);
+        assert_eq!(periods[0].start, start + Duration::hours(40));
+        assert_eq!(periods[0].end, start + Duration::hours(41));
+        assert_eq!(periods[0].kind, AnomalyKind::Rolling);
+
+        // The spike is out of range, the alarms will take care of it
+        assert_eq!(find_anomalies(&mut readings.clone(), since, (None, Some(25.0))), vec![]);
+
+        // Readings older than since are never reported
+        assert_eq!(find_anomalies(&mut readings, start + Duration::hours(42), (None, None)), vec![]);
+    }
+}
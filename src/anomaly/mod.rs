@@ -0,0 +1,7 @@
+mod actor;
+mod detector;
+mod scanner;
+
+pub use actor::AnomalyActor;
+pub use detector::{AnomalyKind, AnomalyPeriod, find_anomalies};
+pub use scanner::scan_anomalies;
@@ -0,0 +1,103 @@
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use log::warn;
+
+use crate::models::{IdType, Pool};
+use crate::web::db_helper::{load_channel_readings, load_invalid_intervals, query_channel_cnr_ids};
+use crate::web::range_recommendation::reading_value;
+
+use super::detector::find_anomalies;
+
+/// How far back the first scan of a channel goes
+const FIRST_SCAN_DAYS: i64 = 1;
+/// History loaded before the scanned readings to compute the expected values
+const HISTORY_DAYS: i64 = 7;
+
+type ChannelScanData = (IdType, Option<String>, Option<BigDecimal>, Option<BigDecimal>, Option<NaiveDateTime>);
+
+fn scan_channel(pool: &Pool, sensor_pool: &mysql::Pool, channel: ChannelScanData, now: NaiveDateTime) -> Result<usize, String> {
+    use crate::schema::{
+        anomaly_scan::dsl as scan_dsl,
+        channel_anomaly::dsl as anomaly_dsl,
+    };
+
+    let (channel_id, id_cnr, range_min, range_max, clock) = channel;
+    let ids = match query_channel_cnr_ids(pool, channel_id, id_cnr.as_deref()).map_err(|x| x.to_string())? {
+        Some(x) => x,
+        None => return Ok(0),
+    };
+    let since = clock.unwrap_or_else(|| now - Duration::days(FIRST_SCAN_DAYS));
+
+    let history_start = since - Duration::days(HISTORY_DAYS);
+    let conn = pool.get().map_err(|x| x.to_string())?;
+    let invalid = load_invalid_intervals(&conn, channel_id, history_start, now).map_err(|x| x.to_string())?;
+    std::mem::drop(conn);
+    let readings = load_channel_readings(sensor_pool, &ids, history_start, now, &invalid)
+        .map_err(|x| x.to_string())?;
+    let new_clock = readings.iter().map(|x| x.date).max().unwrap_or(since).max(since);
+    let mut values: Vec<(NaiveDateTime, f64)> = readings.iter()
+        .map(|x| (x.date, reading_value(x)))
+        .collect();
+
+    let range = (range_min.and_then(|x| x.to_f64()), range_max.and_then(|x| x.to_f64()));
+    let periods = find_anomalies(&mut values, since, range);
+
+    let conn = pool.get().map_err(|x| x.to_string())?;
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        for period in periods.iter() {
+            diesel::insert_into(anomaly_dsl::channel_anomaly)
+                .values((
+                    anomaly_dsl::channel_id.eq(channel_id),
+                    anomaly_dsl::started_at.eq(period.start),
+                    anomaly_dsl::ended_at.eq(period.end),
+                    anomaly_dsl::score.eq(period.max_score),
+                    anomaly_dsl::kind.eq(period.kind.to_char()),
+                ))
+                .execute(&conn)?;
+        }
+
+        diesel::insert_into(scan_dsl::anomaly_scan)
+            .values((scan_dsl::channel_id.eq(channel_id), scan_dsl::clock.eq(new_clock)))
+            .on_conflict(scan_dsl::channel_id)
+            .do_update()
+            .set(scan_dsl::clock.eq(new_clock))
+            .execute(&conn)?;
+        Ok(())
+    }).map_err(|x| x.to_string())?;
+
+    Ok(periods.len())
+}
+
+/// Scores the readings that arrived since the last scan of every enabled channel and stores the
+/// anomalous periods.
+/// Returns the number of anomalies found.
+pub fn scan_anomalies(pool: &Pool, sensor_pool: &mysql::Pool, now: NaiveDateTime) -> Result<usize, String> {
+    use crate::schema::{
+        anomaly_scan::dsl as scan_dsl,
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+    };
+
+    let channels = {
+        let conn = pool.get().map_err(|x| x.to_string())?;
+        channel_dsl::channel
+            .inner_join(sensor_dsl::sensor)
+            .left_join(scan_dsl::anomaly_scan)
+            .filter(sensor_dsl::enabled.eq(true))
+            .filter(channel_dsl::deleted_at.is_null())
+            .select((channel_dsl::id, channel_dsl::id_cnr, channel_dsl::range_min, channel_dsl::range_max, scan_dsl::clock.nullable()))
+            .load::<ChannelScanData>(&conn)
+            .map_err(|x| x.to_string())?
+    };
+
+    let mut found = 0;
+    for channel in channels {
+        let channel_id = channel.0;
+        match scan_channel(pool, sensor_pool, channel, now) {
+            Ok(x) => found += x,
+            Err(err) => warn!("Cannot scan anomalies of channel {}: {}", channel_id, err),
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::reminder::send_calibration_reminders;
+
+pub struct CalibrationActor {
+    pub app_data: AppData,
+    pub check_interval: Duration,
+}
+
+impl CalibrationActor {
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let contacter = self.app_data.contacter.clone();
+        let connection = match self.app_data.pool.get() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error in connection pool: {}", err);
+                return
+            },
+        };
+
+        let res = async move {
+            match send_calibration_reminders(&contacter, &connection, Utc::now().naive_utc()).await {
+                Ok(0) => {},
+                Ok(count) => info!("Sent {} calibration reminders", count),
+                Err(err) => error!("Error during calibration check: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for CalibrationActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the calibration actor");
+
+        IntervalFunc::new(self.check_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for CalibrationActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the calibration actor");
+    }
+}
@@ -0,0 +1,5 @@
+mod actor;
+mod reminder;
+
+pub use actor::CalibrationActor;
+pub use reminder::{latest_calibrations, send_calibration_reminders};
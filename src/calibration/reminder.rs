@@ -0,0 +1,50 @@
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::contact::Contacter;
+use crate::models::SensorCalibration;
+
+/// How long before the due date the reminder is sent
+const REMINDER_ADVANCE_DAYS: i64 = 14;
+
+/// Loads the last calibration of every sensor (the older ones are only kept as history), ordered
+/// by sensor id.
+pub fn latest_calibrations(conn: &PgConnection) -> QueryResult<Vec<SensorCalibration>> {
+    use crate::schema::sensor_calibration::dsl;
+
+    let mut calibrations = dsl::sensor_calibration
+        .order((dsl::sensor_id, dsl::calibrated_at.desc(), dsl::id.desc()))
+        .load::<SensorCalibration>(conn)?;
+    calibrations.dedup_by_key(|x| x.sensor_id);
+    Ok(calibrations)
+}
+
+/// Notifies the users of the sensors that must be calibrated again in the next
+/// REMINDER_ADVANCE_DAYS days, every calibration is only reminded once.
+/// Returns the number of reminders sent.
+pub async fn send_calibration_reminders(contacter: &Contacter, conn: &PgConnection, now: NaiveDateTime) -> Result<usize, String> {
+    use crate::schema::sensor_calibration::dsl;
+
+    let limit = now + Duration::days(REMINDER_ADVANCE_DAYS);
+    let due: Vec<SensorCalibration> = latest_calibrations(conn)
+        .map_err(|x| x.to_string())?
+        .into_iter()
+        .filter(|x| !x.reminder_sent && x.next_due_at <= limit)
+        .collect();
+
+    let mut sent = 0;
+    for calibration in due {
+        if let Err(e) = contacter.send_calibration_reminder(conn, calibration.sensor_id, &calibration.certificate_id, calibration.next_due_at).await {
+            warn!("Cannot send the calibration reminder of sensor {}: {}", calibration.sensor_id, e);
+            continue
+        }
+        diesel::update(dsl::sensor_calibration.find(calibration.id))
+            .set(dsl::reminder_sent.eq(true))
+            .execute(conn)
+            .map_err(|x| x.to_string())?;
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,178 @@
+//! Command line of the server binary, the operators can manage the users and check the databases
+//! without crafting GraphQL requests against a running server.
+
+use std::io::BufRead;
+
+use chrono::NaiveDateTime;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use diesel::prelude::*;
+
+use crate::AppData;
+use crate::models::{IdType, PermissionType, User};
+use crate::web::errors::{ServiceError, ServiceResult};
+use crate::web::health_service::check_readiness;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Starts the web server and the actors (the default)
+    Serve,
+    /// The password is read from stdin if it's not given
+    CreateUser { username: String, password: Option<String>, permission: PermissionType },
+    ResetPassword { username: String, password: Option<String> },
+    RunMigrations,
+    CheckSensors,
+    SeedDemo { days: i64 },
+}
+
+fn build_cli() -> App<'static, 'static> {
+    let password = Arg::with_name("password")
+        .long("password")
+        .takes_value(true)
+        .help("New password, read from stdin if missing so that it doesn't end in the shell history");
+
+    App::new("oldmusa-server")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("serve")
+            .about("Starts the server (default)"))
+        .subcommand(SubCommand::with_name("create-user")
+            .about("Creates a user")
+            .arg(Arg::with_name("username").required(true))
+            .arg(password.clone())
+            .arg(Arg::with_name("permission")
+                .long("permission")
+                .takes_value(true)
+                .possible_values(&["user", "site-manager", "admin"])
+                .default_value("user")))
+        .subcommand(SubCommand::with_name("reset-password")
+            .about("Changes the password of a user")
+            .arg(Arg::with_name("username").required(true))
+            .arg(password))
+        .subcommand(SubCommand::with_name("run-migrations")
+            .about("Applies the pending database migrations"))
+        .subcommand(SubCommand::with_name("check-sensors")
+            .about("Checks the connection to the databases and prints the last reading"))
+        .subcommand(SubCommand::with_name("seed-demo")
+            .about("Creates the demo museum with fake readings")
+            .arg(Arg::with_name("days").default_value("7")))
+}
+
+fn parse_permission(name: &str) -> PermissionType {
+    match name {
+        "admin" => PermissionType::Admin,
+        "site-manager" => PermissionType::SiteManager,
+        _ => PermissionType::User,
+    }
+}
+
+fn parse_matches(matches: &ArgMatches) -> Result<Command, clap::Error> {
+    let username = |x: &ArgMatches| x.value_of("username").unwrap().to_string();
+    let password = |x: &ArgMatches| x.value_of("password").map(|x| x.to_string());
+
+    Ok(match matches.subcommand() {
+        ("create-user", Some(x)) => Command::CreateUser {
+            username: username(x),
+            password: password(x),
+            permission: parse_permission(x.value_of("permission").unwrap()),
+        },
+        ("reset-password", Some(x)) => Command::ResetPassword { username: username(x), password: password(x) },
+        ("run-migrations", _) => Command::RunMigrations,
+        ("check-sensors", _) => Command::CheckSensors,
+        ("seed-demo", Some(x)) => Command::SeedDemo {
+            days: clap::value_t!(x, "days", i64)?,
+        },
+        _ => Command::Serve,
+    })
+}
+
+/// Parses the arguments (including the binary name), the errors also cover --help and --version
+pub fn parse_command<I, T>(args: I) -> Result<Command, clap::Error>
+    where I: IntoIterator<Item = T>, T: Into<std::ffi::OsString> + Clone {
+    parse_matches(&build_cli().get_matches_from_safe(args)?)
+}
+
+/// Reads the password from the first line of stdin
+pub fn read_password() -> std::io::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+pub fn create_user(ctx: &AppData, username: String, password: String, permission: PermissionType) -> ServiceResult<User> {
+    use crate::schema::user_account::dsl;
+
+    if password.is_empty() {
+        return Err(ServiceError::BadRequest("Empty password".to_string()))
+    }
+    let conn = ctx.pool.get()?;
+    let existing = dsl::user_account
+        .filter(dsl::username.eq(&username))
+        .select(dsl::id)
+        .first::<IdType>(&conn)
+        .optional()?;
+    if existing.is_some() {
+        return Err(ServiceError::AlreadyPresent("User".to_string()))
+    }
+    std::mem::drop(conn);
+
+    ctx.auth_cache.add_user(ctx, username, password, permission)
+}
+
+/// Replaces the password of the user, the sessions opened before are invalidated.
+pub fn reset_password(ctx: &AppData, username: &str, password: String) -> ServiceResult<User> {
+    use crate::schema::user_account::dsl;
+
+    if password.is_empty() {
+        return Err(ServiceError::BadRequest("Empty password".to_string()))
+    }
+    let conn = ctx.pool.get()?;
+    let id = dsl::user_account
+        .filter(dsl::username.eq(username))
+        .select(dsl::id)
+        .first::<IdType>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("User".to_string()))?;
+    std::mem::drop(conn);
+
+    ctx.auth_cache.update_user(ctx, id, None, Some(password), None)
+}
+
+/// Checks both databases and returns the date of the last reading in the sensor database
+pub fn check_sensors(ctx: &AppData) -> Result<Option<NaiveDateTime>, String> {
+    let readiness = check_readiness(ctx);
+    if let Some(e) = readiness.postgres.error {
+        return Err(format!("Cannot use the database: {}", e))
+    }
+    if let Some(e) = readiness.sensor_database.error {
+        return Err(format!("Cannot use the sensor database: {}", e))
+    }
+
+    let row = ctx.sensor_pool.first_exec("SELECT MAX(data) FROM t_rilevamento_dati;", ())
+        .map_err(|x| x.to_string())?;
+    match row {
+        Some(row) => mysql::from_row_opt::<Option<NaiveDateTime>>(row).map_err(|x| x.to_string()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(vec!["oldmusa-server"]).unwrap(), Command::Serve);
+        assert_eq!(parse_command(vec!["oldmusa-server", "serve"]).unwrap(), Command::Serve);
+        assert_eq!(
+            parse_command(vec!["oldmusa-server", "create-user", "mario", "--permission", "site-manager"]).unwrap(),
+            Command::CreateUser { username: "mario".to_string(), password: None, permission: PermissionType::SiteManager }
+        );
+        assert_eq!(
+            parse_command(vec!["oldmusa-server", "reset-password", "mario", "--password", "secret"]).unwrap(),
+            Command::ResetPassword { username: "mario".to_string(), password: Some("secret".to_string()) }
+        );
+        assert_eq!(parse_command(vec!["oldmusa-server", "seed-demo"]).unwrap(), Command::SeedDemo { days: 7 });
+        assert!(parse_command(vec!["oldmusa-server", "seed-demo", "many"]).is_err());
+        assert!(parse_command(vec!["oldmusa-server", "create-user"]).is_err());
+        assert!(parse_command(vec!["oldmusa-server", "create-user", "mario", "--permission", "root"]).is_err());
+    }
+}
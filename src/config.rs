@@ -0,0 +1,714 @@
+//! Startup configuration of the server.
+//!
+//! The settings are read from the file in `CONFIG_FILE` (TOML, or YAML if the file ends with
+//! .yaml/.yml), then every environment variable that is set replaces the value of the file, so
+//! that the deployments that only use the environment keep working. Instead of stopping at the
+//! first problem every missing or invalid setting is reported at once.
+//!
+//! Example:
+//! ```toml
+//! bind_address = "0.0.0.0:8080"
+//! fcm_api_key = "..."
+//!
+//! [database]
+//! url = "postgres://oldmusa@localhost/oldmusa"
+//! sensor_url = "mysql://cnr@localhost/sensors"
+//!
+//! [secrets]
+//! cookie_key = "..."
+//! password_key = "..."
+//!
+//! [root]
+//! default_password = "..."
+//!
+//! [alarm]
+//! check_interval = 60
+//!
+//! [quota]
+//! read_max_balance = 10000
+//!
+//! [cors]
+//! allowed_origins = ["https://dashboard.example.com"]
+//! allow_credentials = true
+//!
+//! [tls]
+//! cert_file = "/etc/oldmusa/fullchain.pem"
+//! key_file = "/etc/oldmusa/privkey.pem"
+//!
+//! [smtp]
+//! host = "smtp.example.com"
+//! from = "oldmusa@example.com"
+//!
+//! [mqtt]
+//! broker_url = "mqtt://broker.example.com"
+//!
+//! [trash]
+//! purge_interval = 3600
+//! ```
+//!
+//! The optional modules have their own sections (`[siem]`, `[site_maps]`, `[cookie]`...), the
+//! background jobs (`[mqtt]`, `[modbus]`, `[anomaly]`, `[calibration]`, `[discovery]`,
+//! `[report]` and `[trash]`) only run when their interval (or the broker) is set.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::alarm::{FlappingPolicy, FlappingSection};
+use crate::contact::{EmailConfig, SmtpSection, WebhookConfig, WebhookSection};
+use crate::mqtt::MqttBrokerConfig;
+use crate::secrets::parse_key;
+use crate::siem::{SiemConfig, SiemSection};
+use crate::tuning::{TuningConfig, TuningSection};
+use crate::warmup::{WarmupConfig, WarmupSection};
+use crate::web::access_monitor::{AccessMonitorConfig, AccessMonitorSection};
+use crate::web::admin_network::{AdminNetworkPolicy, AdminNetworkSection};
+use crate::web::approval::{ApprovalPolicy, ApprovalSection};
+use crate::web::export_job::{ExportJobConfig, ExportJobSection};
+use crate::web::graphql_cache::{GraphQLCacheConfig, GraphQLCacheSection};
+use crate::web::identity_cookie::{IdentityCookieConfig, IdentityCookieSection};
+use crate::web::login_throttle::{LoginThrottleConfig, LoginThrottleSection};
+use crate::web::map_storage::{MapStorageConfig, MapStorageSection};
+use crate::web::policy::{AccessPolicy, AccessPolicySection};
+use crate::web::quota::{QuotaCostConfig, QuotaCostSection, QuotaWarningConfig, QuotaWarningSection};
+use crate::web::readings_cache::{ReadingsCacheConfig, ReadingsCacheSection};
+use crate::web::site_map_service::{MapImageConfig, MapImageSection};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    pub url: Option<String>,
+    pub sensor_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsSection {
+    pub cookie_key: Option<String>,
+    pub password_key: Option<String>,
+    /// Encrypts the integration secrets stored in the database
+    pub integrations_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RootSection {
+    pub default_password: Option<String>,
+    pub password_override: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlarmSection {
+    /// Seconds between two measure checks
+    pub check_interval: Option<u64>,
+    pub escalation_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaSection {
+    pub disabled: Option<bool>,
+    pub read_max_balance: Option<i64>,
+    pub read_refill_rate: Option<u64>,
+    pub write_max_balance: Option<i64>,
+    pub write_refill_rate: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSection {
+    pub allowed_origins: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSection {
+    pub broker_url: Option<String>,
+    pub client_id: Option<String>,
+    /// Seconds between two writes of the buffered messages
+    pub flush_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModbusSection {
+    pub poll_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalySection {
+    pub scan_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationSection {
+    pub check_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoverySection {
+    pub check_interval: Option<u64>,
+    pub lookback_hours: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportSection {
+    pub check_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashSection {
+    pub purge_interval: Option<u64>,
+    pub retention_days: Option<u64>,
+}
+
+/// Content of the configuration file, every setting is optional as it can come from the
+/// environment
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub bind_address: Option<String>,
+    pub dev_mode: Option<bool>,
+    pub fcm_api_key: Option<String>,
+    pub database: DatabaseSection,
+    pub secrets: SecretsSection,
+    pub root: RootSection,
+    pub alarm: AlarmSection,
+    pub quota: QuotaSection,
+    pub cors: CorsSection,
+    pub tls: TlsSection,
+    pub request_log_sample_rate: Option<f64>,
+    /// Seconds given to the running requests on shutdown
+    pub shutdown_timeout: Option<u64>,
+    pub quota_costs: QuotaCostSection,
+    pub quota_warning: QuotaWarningSection,
+    pub admin_network: AdminNetworkSection,
+    pub siem: SiemSection,
+    pub flapping: FlappingSection,
+    pub approval: ApprovalSection,
+    pub access_monitor: AccessMonitorSection,
+    pub access_policy: AccessPolicySection,
+    pub export_jobs: ExportJobSection,
+    pub site_maps: MapStorageSection,
+    pub map_images: MapImageSection,
+    pub readings_cache: ReadingsCacheSection,
+    pub graphql_cache: GraphQLCacheSection,
+    pub cookie: IdentityCookieSection,
+    pub warmup: WarmupSection,
+    pub smtp: SmtpSection,
+    pub webhooks: WebhookSection,
+    pub mqtt: MqttSection,
+    pub modbus: ModbusSection,
+    pub anomaly: AnomalySection,
+    pub calibration: CalibrationSection,
+    pub discovery: DiscoverySection,
+    pub report: ReportSection,
+    pub trash: TrashSection,
+    pub tuning: TuningSection,
+    pub login_throttle: LoginThrottleSection,
+}
+
+impl ConfigFile {
+    pub fn parse(path: &Path, content: &str) -> Result<ConfigFile, String> {
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or_default();
+        match extension {
+            "yaml" | "yml" => serde_yaml::from_str(content).map_err(|x| x.to_string()),
+            _ => toml::from_str(content).map_err(|x| x.to_string()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<ConfigFile, String> {
+        let content = std::fs::read_to_string(path).map_err(|x| x.to_string())?;
+        Self::parse(path, &content)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuotaConfig {
+    pub read_max_balance: i64,
+    pub read_refill_rate: u64,
+    pub write_max_balance: i64,
+    pub write_refill_rate: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser, CORS is disabled if empty
+    pub allowed_origins: Vec<String>,
+    /// Lets the browsers send the auth cookie with the requests
+    pub allow_credentials: bool,
+    /// Seconds the browsers can cache the preflight responses
+    pub max_age: usize,
+}
+
+/// PEM files of the certificate served by the server itself, for the deploys without a reverse
+/// proxy
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// Certificate followed by its chain
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttConfig {
+    pub broker: MqttBrokerConfig,
+    pub flush_interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind_address: String,
+    pub database_url: String,
+    pub sensor_database_url: String,
+    pub cookie_secret_key: String,
+    pub password_secret_key: String,
+    pub root_default_password: String,
+    pub root_password_override: bool,
+    /// None if the push notifications are disabled
+    pub fcm_api_key: Option<String>,
+    pub alarm_check_interval: Duration,
+    pub escalation_check_interval: Duration,
+    /// None if the request quota is disabled
+    pub quota: Option<QuotaConfig>,
+    pub cors: CorsConfig,
+    /// None if the server speaks plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// Enables the development helpers of the API, never set in production
+    pub dev_mode: bool,
+    /// Share of the requests recorded in the request log
+    pub request_log_sample_rate: f64,
+    /// Seconds given to the running requests (and to the alarm check) on shutdown
+    pub shutdown_timeout: u64,
+    /// None if the integration secrets can't be stored in the database
+    pub secrets_key: Option<[u8; 32]>,
+    pub quota_costs: QuotaCostConfig,
+    pub quota_warning: QuotaWarningConfig,
+    pub admin_network: Option<AdminNetworkPolicy>,
+    pub siem: Option<SiemConfig>,
+    pub flapping: Option<FlappingPolicy>,
+    pub approvals: Option<ApprovalPolicy>,
+    pub access_monitor: AccessMonitorConfig,
+    pub access_policy: AccessPolicy,
+    pub export_jobs: ExportJobConfig,
+    pub site_maps: MapStorageConfig,
+    pub map_images: MapImageConfig,
+    pub readings_cache: ReadingsCacheConfig,
+    pub graphql_cache: GraphQLCacheConfig,
+    pub identity_cookie: IdentityCookieConfig,
+    pub warmup: WarmupConfig,
+    /// None if the email notifications are disabled
+    pub email: Option<EmailConfig>,
+    pub webhooks: WebhookConfig,
+    /// The background jobs below are disabled when they're None
+    pub mqtt: Option<MqttConfig>,
+    pub modbus_poll_interval: Option<Duration>,
+    pub anomaly_scan_interval: Option<Duration>,
+    pub calibration_check_interval: Option<Duration>,
+    pub discovery_check_interval: Option<Duration>,
+    /// How far back the discovery looks for new channels
+    pub discovery_lookback: Duration,
+    pub report_check_interval: Option<Duration>,
+    pub trash_purge_interval: Option<Duration>,
+    /// The entities stay in the trash for this time before being purged
+    pub trash_retention: Duration,
+    pub tuning: TuningConfig,
+    pub login_throttle: LoginThrottleConfig,
+}
+
+/// Every problem found in the configuration
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for error in self.0.iter() {
+            writeln!(f, " - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads the settings, giving the precedence to the environment.
+/// The problems are collected instead of stopping at the first one, the modules use it to read
+/// their own sections.
+pub struct Resolver<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(env: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        Resolver { env, errors: Vec::new() }
+    }
+
+    /// Problems found so far
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    pub fn error(&mut self, error: String) {
+        self.errors.push(error);
+    }
+
+    pub fn optional<T: FromStr>(&mut self, env_name: &str, file_value: Option<T>) -> Option<T> {
+        match (self.env)(env_name) {
+            Some(x) => match x.parse() {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    self.errors.push(format!("Cannot parse {}", env_name));
+                    None
+                },
+            },
+            None => file_value,
+        }
+    }
+
+    pub fn required<T: FromStr>(&mut self, env_name: &str, file_name: &str, file_value: Option<T>) -> Option<T> {
+        let errors = self.errors.len();
+        let res = self.optional(env_name, file_value);
+        // A value that can't be parsed has already been reported
+        if res.is_none() && self.errors.len() == errors {
+            self.errors.push(format!("{} (or {} in the configuration file) must be set", env_name, file_name));
+        }
+        res
+    }
+
+    /// A flag is set by any non empty value, like ROOT_PASSWORD_OVERRIDE always did
+    pub fn flag(&self, env_name: &str, file_value: Option<bool>) -> bool {
+        match (self.env)(env_name) {
+            Some(x) => !x.is_empty(),
+            None => file_value.unwrap_or(false),
+        }
+    }
+
+    /// A value that needs its own parser (ex. an enum), the file value is parsed in the same way
+    pub fn parsed<T, F>(&mut self, env_name: &str, file_value: Option<String>, parse: F) -> Option<T>
+        where F: FnOnce(&str) -> Result<T, String> {
+        let value = (self.env)(env_name).or(file_value)?;
+        match parse(&value) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.errors.push(format!("Cannot parse {}: {}", env_name, e));
+                None
+            },
+        }
+    }
+
+    /// A comma separated list in the environment or an array in the file
+    pub fn list(&self, env_name: &str, file_value: Option<Vec<String>>) -> Vec<String> {
+        match (self.env)(env_name) {
+            Some(x) => x.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect(),
+            None => file_value.unwrap_or_default(),
+        }
+    }
+}
+
+/// Seconds between two runs of an optional job, None if the job is disabled
+fn job_interval(r: &mut Resolver, env_name: &str, file_value: Option<u64>) -> Option<Duration> {
+    let secs = r.optional(env_name, file_value)?;
+    if secs == 0 {
+        r.error(format!("{} must be positive", env_name));
+        return None
+    }
+    Some(Duration::from_secs(secs))
+}
+
+impl Config {
+    /// Combines the file with the environment, returning every missing or invalid setting.
+    pub fn resolve<E: Fn(&str) -> Option<String>>(file: ConfigFile, env: &E) -> Result<Config, ConfigError> {
+        let mut r = Resolver::new(env);
+
+        let database_url = r.required("DATABASE_URL", "database.url", file.database.url);
+        let sensor_database_url = r.required("SENSOR_DATABASE_URL", "database.sensor_url", file.database.sensor_url);
+        let cookie_secret_key = r.required("COOKIE_SECRET_KEY", "secrets.cookie_key", file.secrets.cookie_key);
+        let password_secret_key = r.required("PASSWORD_SECRET_KEY", "secrets.password_key", file.secrets.password_key);
+        let root_default_password = r.required("ROOT_DEFAULT_PASSWORD", "root.default_password", file.root.default_password);
+        let root_password_override = r.flag("ROOT_PASSWORD_OVERRIDE", file.root.password_override);
+        let alarm_check_interval = r.required("MEASURE_CONTROL_SLEEP_TIME", "alarm.check_interval", file.alarm.check_interval);
+        let escalation_check_interval = r.optional("ESCALATION_CHECK_INTERVAL", file.alarm.escalation_interval).unwrap_or(60);
+        let bind_address = r.optional("BIND_ADDRESS", file.bind_address).unwrap_or_else(|| "0.0.0.0:8080".to_string());
+        let fcm_api_key = r.optional("FCM_API_KEY", file.fcm_api_key);
+        let dev_mode = r.flag("DEV_MODE", file.dev_mode);
+
+        let quota_disabled = r.flag("QUOTA_DISABLED", file.quota.disabled);
+        let quota = QuotaConfig {
+            read_max_balance: r.optional("QUOTA_READ_MAX_BALANCE", file.quota.read_max_balance).unwrap_or(10000),
+            read_refill_rate: r.optional("QUOTA_READ_REFILL_RATE", file.quota.read_refill_rate).unwrap_or(10),
+            write_max_balance: r.optional("QUOTA_WRITE_MAX_BALANCE", file.quota.write_max_balance).unwrap_or(3000),
+            write_refill_rate: r.optional("QUOTA_WRITE_REFILL_RATE", file.quota.write_refill_rate).unwrap_or(3),
+        };
+
+        let cors = CorsConfig {
+            allowed_origins: r.list("CORS_ALLOWED_ORIGINS", file.cors.allowed_origins),
+            allow_credentials: r.optional("CORS_ALLOW_CREDENTIALS", file.cors.allow_credentials).unwrap_or(true),
+            max_age: r.optional("CORS_MAX_AGE", file.cors.max_age).unwrap_or(3600),
+        };
+
+        let tls = match (r.optional("TLS_CERT_FILE", file.tls.cert_file), r.optional("TLS_KEY_FILE", file.tls.key_file)) {
+            (Some(cert_file), Some(key_file)) => Some(TlsConfig { cert_file, key_file }),
+            (None, None) => None,
+            _ => {
+                r.errors.push("TLS_CERT_FILE and TLS_KEY_FILE (or the tls section) must be set together".to_string());
+                None
+            },
+        };
+
+        let request_log_sample_rate = r.optional("REQUEST_LOG_SAMPLE_RATE", file.request_log_sample_rate).unwrap_or(1.0);
+        let shutdown_timeout = r.optional("SHUTDOWN_TIMEOUT", file.shutdown_timeout).unwrap_or(30);
+        // An empty key disables the secrets, like an unset one
+        let secrets_key = r.parsed("SECRETS_KEY", file.secrets.integrations_key, |x| match x {
+            "" => Ok(None),
+            x => parse_key(x).map(Some),
+        }).flatten();
+        let quota_costs = QuotaCostConfig::resolve(&mut r, file.quota_costs);
+        let quota_warning = QuotaWarningConfig::resolve(&mut r, file.quota_warning);
+        let admin_network = AdminNetworkPolicy::resolve(&mut r, file.admin_network);
+        let siem = SiemConfig::resolve(&mut r, file.siem);
+        let flapping = FlappingPolicy::resolve(&mut r, file.flapping);
+        let approvals = ApprovalPolicy::resolve(&mut r, file.approval);
+        let access_monitor = AccessMonitorConfig::resolve(&mut r, file.access_monitor);
+        let access_policy = AccessPolicy::resolve(&mut r, file.access_policy);
+        let export_jobs = ExportJobConfig::resolve(&mut r, file.export_jobs);
+        let site_maps = MapStorageConfig::resolve(&mut r, file.site_maps);
+        let map_images = MapImageConfig::resolve(&mut r, file.map_images);
+        let readings_cache = ReadingsCacheConfig::resolve(&mut r, file.readings_cache);
+        let graphql_cache = GraphQLCacheConfig::resolve(&mut r, file.graphql_cache);
+        let identity_cookie = IdentityCookieConfig::resolve(&mut r, file.cookie, tls.is_some());
+        let warmup = WarmupConfig::resolve(&mut r, file.warmup);
+        let email = EmailConfig::resolve(&mut r, file.smtp);
+        let webhooks = WebhookConfig::resolve(&mut r, file.webhooks);
+
+        let mqtt_client_id = r.optional("MQTT_CLIENT_ID", file.mqtt.client_id).unwrap_or_else(|| "oldmusa-server".to_string());
+        let mqtt_flush_interval = r.optional("MQTT_FLUSH_INTERVAL", file.mqtt.flush_interval).unwrap_or(5);
+        let mqtt = r.parsed("MQTT_BROKER_URL", file.mqtt.broker_url, |x| MqttBrokerConfig::from_url(x, mqtt_client_id))
+            .map(|broker| MqttConfig { broker, flush_interval: Duration::from_secs(mqtt_flush_interval) });
+        let modbus_poll_interval = job_interval(&mut r, "MODBUS_POLL_INTERVAL", file.modbus.poll_interval);
+        let anomaly_scan_interval = job_interval(&mut r, "ANOMALY_SCAN_INTERVAL", file.anomaly.scan_interval);
+        let calibration_check_interval = job_interval(&mut r, "CALIBRATION_CHECK_INTERVAL", file.calibration.check_interval);
+        let discovery_check_interval = job_interval(&mut r, "DISCOVERY_CHECK_INTERVAL", file.discovery.check_interval);
+        let discovery_lookback_hours = r.optional("DISCOVERY_LOOKBACK_HOURS", file.discovery.lookback_hours).unwrap_or(24);
+        let report_check_interval = job_interval(&mut r, "REPORT_CHECK_INTERVAL", file.report.check_interval);
+        let trash_purge_interval = job_interval(&mut r, "TRASH_PURGE_INTERVAL", file.trash.purge_interval);
+        let trash_retention_days = r.optional("TRASH_RETENTION_DAYS", file.trash.retention_days).unwrap_or(30);
+        let tuning = TuningConfig::resolve(&mut r, file.tuning);
+        let login_throttle = LoginThrottleConfig::resolve(&mut r, file.login_throttle);
+
+        if alarm_check_interval == Some(0) {
+            r.errors.push("The alarm check interval must be positive".to_string());
+        }
+        if !quota_disabled && (quota.read_refill_rate == 0 || quota.write_refill_rate == 0) {
+            r.errors.push("The quota refill rates must be positive".to_string());
+        }
+        if let Some(origin) = cors.allowed_origins.iter().find(|x| !x.starts_with("http://") && !x.starts_with("https://")) {
+            r.errors.push(format!("Invalid CORS origin {}", origin));
+        }
+
+        match (database_url, sensor_database_url, cookie_secret_key, password_secret_key, root_default_password, alarm_check_interval) {
+            (Some(database_url), Some(sensor_database_url), Some(cookie_secret_key), Some(password_secret_key), Some(root_default_password), Some(alarm_check_interval))
+                if r.errors.is_empty() => Ok(Config {
+                bind_address,
+                database_url,
+                sensor_database_url,
+                cookie_secret_key,
+                password_secret_key,
+                root_default_password,
+                root_password_override,
+                fcm_api_key,
+                alarm_check_interval: Duration::from_secs(alarm_check_interval),
+                escalation_check_interval: Duration::from_secs(escalation_check_interval),
+                quota: if quota_disabled { None } else { Some(quota) },
+                cors,
+                tls,
+                dev_mode,
+                request_log_sample_rate,
+                shutdown_timeout,
+                secrets_key,
+                quota_costs,
+                quota_warning,
+                admin_network,
+                siem,
+                flapping,
+                approvals,
+                access_monitor,
+                access_policy,
+                export_jobs,
+                site_maps,
+                map_images,
+                readings_cache,
+                graphql_cache,
+                identity_cookie,
+                warmup,
+                email,
+                webhooks,
+                mqtt,
+                modbus_poll_interval,
+                anomaly_scan_interval,
+                calibration_check_interval,
+                discovery_check_interval,
+                discovery_lookback: Duration::from_secs(discovery_lookback_hours * 3600),
+                report_check_interval,
+                trash_purge_interval,
+                trash_retention: Duration::from_secs(trash_retention_days * 24 * 3600),
+                tuning,
+                login_throttle,
+            }),
+            _ => Err(ConfigError(r.errors)),
+        }
+    }
+
+    /// Loads the file in CONFIG_FILE (if set) and applies the environment
+    pub fn load() -> Result<Config, ConfigError> {
+        let file = match std::env::var("CONFIG_FILE") {
+            Ok(path) => ConfigFile::read(Path::new(&path))
+                .map_err(|e| ConfigError(vec![format!("Cannot read {}: {}", path, e)]))?,
+            Err(_) => ConfigFile::default(),
+        };
+        Self::resolve(file, &|name: &str| std::env::var(name).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|x| (x.0.to_string(), x.1.to_string())).collect();
+        move |name: &str| vars.get(name).cloned()
+    }
+
+    const FILE: &str = r#"
+        bind_address = "127.0.0.1:9000"
+
+        [database]
+        url = "postgres://localhost/oldmusa"
+        sensor_url = "mysql://localhost/sensors"
+
+        [secrets]
+        cookie_key = "cookie"
+        password_key = "password"
+
+        [root]
+        default_password = "root"
+
+        [alarm]
+        check_interval = 30
+
+        [quota]
+        read_max_balance = 500
+
+        [cors]
+        allowed_origins = ["https://dashboard.example.com"]
+        allow_credentials = false
+    "#;
+
+    #[test]
+    fn test_file_with_env_overrides() {
+        let file = ConfigFile::parse(Path::new("oldmusa.toml"), FILE).unwrap();
+        let config = Config::resolve(file, &env(&[("MEASURE_CONTROL_SLEEP_TIME", "10"), ("QUOTA_WRITE_MAX_BALANCE", "50")])).unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:9000");
+        assert_eq!(config.database_url, "postgres://localhost/oldmusa");
+        assert_eq!(config.alarm_check_interval, Duration::from_secs(10));
+        assert_eq!(config.escalation_check_interval, Duration::from_secs(60));
+        assert_eq!(config.quota, Some(QuotaConfig {
+            read_max_balance: 500,
+            read_refill_rate: 10,
+            write_max_balance: 50,
+            write_refill_rate: 3,
+        }));
+        assert_eq!(config.cors.allowed_origins, vec!["https://dashboard.example.com".to_string()]);
+        assert!(!config.cors.allow_credentials);
+        assert_eq!(config.tls, None);
+
+        let yaml = "database:\n  url: postgres://localhost/oldmusa\nquota:\n  disabled: true\n";
+        let file = ConfigFile::parse(Path::new("oldmusa.yaml"), yaml).unwrap();
+        assert_eq!(file.database.url.as_deref(), Some("postgres://localhost/oldmusa"));
+        assert_eq!(file.quota.disabled, Some(true));
+
+        assert!(ConfigFile::parse(Path::new("oldmusa.toml"), "unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_errors_listed_at_once() {
+        let err = Config::resolve(ConfigFile::default(), &env(&[("DATABASE_URL", "postgres://localhost"), ("CORS_MAX_AGE", "soon")])).unwrap_err();
+        assert_eq!(err.0, vec![
+            "SENSOR_DATABASE_URL (or database.sensor_url in the configuration file) must be set".to_string(),
+            "COOKIE_SECRET_KEY (or secrets.cookie_key in the configuration file) must be set".to_string(),
+            "PASSWORD_SECRET_KEY (or secrets.password_key in the configuration file) must be set".to_string(),
+            "ROOT_DEFAULT_PASSWORD (or root.default_password in the configuration file) must be set".to_string(),
+            "MEASURE_CONTROL_SLEEP_TIME (or alarm.check_interval in the configuration file) must be set".to_string(),
+            "Cannot parse CORS_MAX_AGE".to_string(),
+        ]);
+
+        let err = Config::resolve(ConfigFile::default(), &env(&[("TLS_CERT_FILE", "cert.pem")])).unwrap_err();
+        assert!(err.0.contains(&"TLS_CERT_FILE and TLS_KEY_FILE (or the tls section) must be set together".to_string()));
+
+        let err = Config::resolve(ConfigFile::default(), &env(&[("QUOTA_READ_REFILL_RATE", "0")])).unwrap_err();
+        assert!(err.0.contains(&"The quota refill rates must be positive".to_string()));
+
+        let err = Config::resolve(ConfigFile::default(), &env(&[("MEASURE_CONTROL_SLEEP_TIME", "often")])).unwrap_err();
+        assert!(err.0.contains(&"Cannot parse MEASURE_CONTROL_SLEEP_TIME".to_string()));
+        assert!(!err.0.iter().any(|x| x.starts_with("MEASURE_CONTROL_SLEEP_TIME")));
+    }
+
+    #[test]
+    fn test_module_sections() {
+        let sections = r#"
+            [flapping]
+            threshold = 5
+
+            [trash]
+            purge_interval = 3600
+
+            [login_throttle]
+            lockout_secs = 60
+        "#;
+        let file = ConfigFile::parse(Path::new("oldmusa.toml"), &format!("{}{}", FILE, sections)).unwrap();
+        let config = Config::resolve(file, &env(&[])).unwrap();
+        assert_eq!(config.flapping.map(|x| x.max_alarms), Some(5));
+        assert_eq!(config.trash_purge_interval, Some(Duration::from_secs(3600)));
+        assert_eq!(config.trash_retention, Duration::from_secs(30 * 24 * 3600));
+        assert_eq!(config.mqtt, None);
+        assert_eq!(config.modbus_poll_interval, None);
+        assert_eq!(config.login_throttle.lockout, Duration::from_secs(60));
+        assert_eq!(config.login_throttle.free_failures, 3);
+
+        let file = ConfigFile::parse(Path::new("oldmusa.toml"), &format!("{}\n[site_maps]\nstorage = \"s3\"\n", FILE)).unwrap();
+        let err = Config::resolve(file, &env(&[
+            ("ALARM_FLAPPING_WINDOW_MINUTES", "soon"),
+            ("MQTT_BROKER_URL", "http://broker"),
+            ("MODBUS_POLL_INTERVAL", "0"),
+        ])).unwrap_err();
+        for error in &[
+            "Cannot parse ALARM_FLAPPING_WINDOW_MINUTES",
+            "SITE_MAPS_S3_ENDPOINT (or site_maps.s3.endpoint in the configuration file) must be set",
+            "Cannot parse MQTT_BROKER_URL: Unsupported broker url 'http://broker', only mqtt:// is supported",
+            "MODBUS_POLL_INTERVAL must be positive",
+        ] {
+            assert!(err.0.contains(&error.to_string()), "missing error {}", error);
+        }
+    }
+}
@@ -1,82 +1,578 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
+use chrono::NaiveDateTime;
 use diesel::PgConnection;
 use diesel::prelude::*;
 use log::warn;
 
 use crate::models::IdType;
 
+use super::email::{EmailConfig, EmailContacter};
 use super::fcm::FcmContacter;
+use super::webhook::{WebhookConfig, WebhookContacter};
 
 pub type DbConnection = PgConnection;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
 pub enum MeasureExtremeType {
-    Min, Max
+    Min, Max,
+    /// The value changed faster than the max delta per hour of the channel
+    Delta,
+}
+
+impl MeasureExtremeType {
+    pub fn from_char(name: &str) -> Option<MeasureExtremeType> {
+        match name {
+            "m" => Some(MeasureExtremeType::Min),
+            "M" => Some(MeasureExtremeType::Max),
+            "d" => Some(MeasureExtremeType::Delta),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            MeasureExtremeType::Min => "m",
+            MeasureExtremeType::Max => "M",
+            MeasureExtremeType::Delta => "d",
+        }
+    }
+}
+
+/// Overall status of a site, computed by the alarm check
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum SiteStatus {
+    Ok,
+    /// At least a channel is alarmed
+    Alarm,
+    /// No channel is alarmed but at least a sensor stopped reporting
+    Stale,
+}
+
+impl SiteStatus {
+    pub fn from_char(name: &str) -> Option<SiteStatus> {
+        match name {
+            "o" => Some(SiteStatus::Ok),
+            "a" => Some(SiteStatus::Alarm),
+            "s" => Some(SiteStatus::Stale),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            SiteStatus::Ok => "o",
+            SiteStatus::Alarm => "a",
+            SiteStatus::Stale => "s",
+        }
+    }
+
+    /// Name used in the webhook payloads
+    pub fn name(self) -> &'static str {
+        match self {
+            SiteStatus::Ok => "ok",
+            SiteStatus::Alarm => "alarm",
+            SiteStatus::Stale => "stale",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SensorRangeAlarmData {
     pub site_id: IdType,
     pub site_name: String,
+    /// Room of the sensor, if it's placed in one
+    pub room_name: Option<String>,
     pub sensor_name: String,
     pub channel_name: String,
     pub value: String,
 }
 
+impl SensorRangeAlarmData {
+    /// Name of the channel shown in the messages, prefixed by the room (ex. "Sala 3 — Umidità")
+    pub fn channel_label(&self) -> String {
+        match &self.room_name {
+            Some(room) => format!("{} — {}", room, self.channel_name),
+            None => self.channel_name.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CalibrationReminderData {
+    pub site_id: IdType,
+    pub site_name: String,
+    pub sensor_name: String,
+    pub certificate_id: String,
+    pub next_due_at: NaiveDateTime,
+}
+
+/// A sensor that stopped reporting for longer than its max silence
+#[derive(Debug)]
+pub struct NoDataAlarmData {
+    pub site_id: IdType,
+    pub site_name: String,
+    pub sensor_name: String,
+    /// Timestamp of the newest reading, None if the sensor never reported
+    pub last_measure: Option<NaiveDateTime>,
+}
+
+/// A sensor that contributes to the status of its site
+#[derive(Debug, Clone, PartialEq)]
+pub struct AffectedSensorData {
+    pub sensor_id: IdType,
+    pub sensor_name: String,
+    /// Names of the alarmed channels
+    pub alarmed_channels: Vec<String>,
+    /// The sensor stopped reporting for longer than its max silence
+    pub no_data: bool,
+}
+
+/// A site whose status changed (ex. from OK to ALARM)
+#[derive(Debug)]
+pub struct SiteStatusData {
+    pub site_id: IdType,
+    pub site_name: String,
+    pub previous: SiteStatus,
+    pub status: SiteStatus,
+    /// Sensors with alarmed channels or without data, empty when the site goes back to OK
+    pub sensors: Vec<AffectedSensorData>,
+}
+
+/// A client that keeps hitting resources it can't access (see the access monitor)
+#[derive(Debug)]
+pub struct AccessAlertData {
+    /// Username or address of the client
+    pub source: String,
+    pub failures: usize,
+    pub window_secs: u64,
+    pub throttled: bool,
+}
+
+/// A background export that finished writing its file
+#[derive(Debug)]
+pub struct ExportReadyData {
+    pub user_id: IdType,
+    pub channel_name: String,
+    /// Signed download link of the file
+    pub link: String,
+    pub rows: u64,
+}
+
+/// A bulk export of some sites that finished writing its zip file
+#[derive(Debug)]
+pub struct BulkExportReadyData {
+    pub user_id: IdType,
+    pub site_names: Vec<String>,
+    /// Signed download link of the file
+    pub link: String,
+    pub channels: usize,
+    pub rows: u64,
+}
+
+/// Periodic summary of a site for a subscribed user (see the report module)
+#[derive(Debug)]
+pub struct ReportData {
+    pub user_id: IdType,
+    pub site_name: String,
+    /// Ex. "Daily report 2020-01-01"
+    pub title: String,
+    /// The report as HTML, it's the body of the email
+    pub html: String,
+    /// The report as PDF, attached to the email if the user asked for it
+    pub pdf: Option<Vec<u8>>,
+    /// Name of the attached file
+    pub file_name: String,
+}
+
+/// An alarm nobody acknowledged within the delay of an escalation policy
+#[derive(Debug)]
+pub struct EscalationData {
+    /// Users of the contact group of the policy
+    pub user_ids: Vec<IdType>,
+    pub site_name: String,
+    pub sensor_name: String,
+    pub channel_name: String,
+    pub started_at: NaiveDateTime,
+    pub delay_minutes: i32,
+}
+
+/// A channel that entered and exited alarm too often, its alarms aren't notified for a while
+#[derive(Debug)]
+pub struct FlappingData {
+    pub site_name: String,
+    pub sensor_name: String,
+    pub channel_name: String,
+    /// Alarms that began within the window
+    pub alarms: i64,
+    pub window_minutes: u64,
+}
+
+/// Cnr ids of a site with readings but no channel (see the discovery module)
+#[derive(Debug)]
+pub struct DiscoveryData {
+    pub site_id: IdType,
+    pub site_name: String,
+    /// Sensor and channel cnr ids
+    pub channels: Vec<(String, String)>,
+    /// GraphQL mutation that creates the missing sensors and channels
+    pub mutation: String,
+}
+
+/// A user that spent most of the quota of a pool, their clients should slow down
+#[derive(Debug)]
+pub struct QuotaWarningData {
+    pub user_id: IdType,
+    pub pool: String,
+    pub balance: i64,
+    pub max_balance: i64,
+}
+
+/// Token that verifies the new email of an account, sent to that email
+#[derive(Debug)]
+pub struct EmailVerificationData {
+    pub username: String,
+    pub email: String,
+    pub token: String,
+}
+
+/// Credentials that replace the ones of the environment (see the secrets module)
+#[derive(Debug, Default)]
+pub struct ContactOverrides {
+    pub fcm_api_key: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct Contacter {
-    fcm_client: Option<Arc<FcmContacter>>,
+    /// Configuration of the environment, the overrides are applied on top of it
+    fcm_key: Option<String>,
+    email_config: Option<EmailConfig>,
+    fcm_client: Arc<RwLock<Option<Arc<FcmContacter>>>>,
+    email_client: Arc<RwLock<Option<Arc<EmailContacter>>>>,
+    /// Always enabled, it only posts to the sites with webhooks
+    webhook_client: Arc<WebhookContacter>,
 }
 
 impl Contacter {
-    pub fn new(fcm_key: Option<String>) -> Self {
+    pub fn new(fcm_key: Option<String>, email_config: Option<EmailConfig>) -> Self {
         Contacter {
-            fcm_client: fcm_key.map(|x| Arc::new(FcmContacter::new(x)))
+            fcm_client: Arc::new(RwLock::new(fcm_key.clone().map(|x| Arc::new(FcmContacter::new(x))))),
+            email_client: Arc::new(RwLock::new(email_config.clone().map(|x| Arc::new(EmailContacter::new(x))))),
+            webhook_client: Arc::new(WebhookContacter::new(WebhookConfig::default())),
+            fcm_key,
+            email_config,
         }
     }
 
-    pub fn new_from_env() -> Self {
-        let fcm_api_key = std::env::var("FCM_API_KEY").ok();
+    /// Rebuilds the clients with the overridden credentials, every clone of the contacter sees
+    /// the new clients.
+    /// Emails can only be enabled by the environment as the SMTP host isn't a secret.
+    pub fn apply_overrides(&self, overrides: ContactOverrides) {
+        let fcm_key = overrides.fcm_api_key.or_else(|| self.fcm_key.clone());
+        *self.fcm_client.write().unwrap() = fcm_key.map(|x| Arc::new(FcmContacter::new(x)));
 
+        let (smtp_username, smtp_password) = (overrides.smtp_username, overrides.smtp_password);
+        let email_config = self.email_config.clone().map(|mut config| {
+            if smtp_username.is_some() || smtp_password.is_some() {
+                let (username, password) = config.credentials.take().unzip();
+                config.credentials = smtp_username.or(username).zip(smtp_password.or(password));
+            }
+            config
+        });
+        *self.email_client.write().unwrap() = email_config.map(|x| Arc::new(EmailContacter::new(x)));
+    }
+
+    /// Returns true if the FCM and email notifications are enabled
+    pub fn enabled_clients(&self) -> (bool, bool) {
+        (self.fcm_client.read().unwrap().is_some(), self.email_client.read().unwrap().is_some())
+    }
+
+    /// Builds the contacter with the settings of the configuration
+    pub fn from_config(fcm_api_key: Option<String>, email_config: Option<EmailConfig>, webhook_config: WebhookConfig) -> Self {
         if fcm_api_key.is_none() {
             warn!("No FCM apy key found, disabling");
         }
+        if email_config.is_none() {
+            warn!("No SMTP_HOST found, disabling email notifications");
+        }
 
-        Self::new(fcm_api_key)
+        let mut contacter = Self::new(fcm_api_key, email_config);
+        contacter.webhook_client = Arc::new(WebhookContacter::new(webhook_config));
+        contacter
     }
 
-    pub async fn send_alarm(&self, conn: &DbConnection, channel_id: IdType, measure: f64, _measure_type: MeasureExtremeType) -> Result<(), String> {
+    pub async fn send_alarm(&self, conn: &DbConnection, channel_id: IdType, measure: f64, measure_type: MeasureExtremeType) -> Result<(), String> {
         use crate::schema::{
             channel::dsl as channel_dsl,
+            room::dsl as room_dsl,
             sensor::dsl as sensor_dsl,
             site::dsl as site_dsl,
         };
 
         let data = channel_dsl::channel.find(channel_id)
-            .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
-            .select((site_dsl::id, site_dsl::name, sensor_dsl::name, channel_dsl::name, channel_dsl::measure_unit))
-            .get_result::<(IdType, Option<String>, Option<String>, Option<String>, Option<String>)>(conn)
+            .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site).left_join(room_dsl::room))
+            .select((site_dsl::id, site_dsl::name, sensor_dsl::name, channel_dsl::name, channel_dsl::measure_unit, room_dsl::name.nullable()))
+            .get_result::<(IdType, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>)>(conn)
             .map_err(|x| x.to_string())?;
 
         let payload = SensorRangeAlarmData {
             site_id: data.0,
             site_name: data.1.unwrap_or_else(|| "?".to_string()),
+            room_name: data.5,
             sensor_name: data.2.unwrap_or_else(||  "?".to_string()),
             channel_name: data.3.unwrap_or_else(|| "?".to_string()),
-            value: format!("{} {}", measure, data.4.unwrap_or_else(|| "".to_string()))
+            value: match measure_type {
+                MeasureExtremeType::Delta => format!("changing by {} {}/h", measure, data.4.unwrap_or_else(|| "".to_string())),
+                _ => format!("{} {}", measure, data.4.unwrap_or_else(|| "".to_string())),
+            }
         };
 
-        if let Some(fcm) = self.fcm_client.as_ref() {
+        // The clients are cloned so that the locks aren't held while sending
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
             fcm.send_alarm(conn, &payload).await?;
         } else {
             warn!("FCM disabled, skipping alarm notification")
         }
 
+        if let Some(email) = email_client {
+            email.send_alarm(conn, &payload).await?;
+        }
+
+        self.webhook_client.send_alarm(conn, &payload).await?;
+
         Ok(())
     }
-}
+    /// Notifies the users of the sensor's site that the sensor must be calibrated again.
+    pub async fn send_calibration_reminder(&self, conn: &DbConnection, sensor_id: IdType, certificate_id: &str, next_due_at: NaiveDateTime) -> Result<(), String> {
+        use crate::schema::{
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
 
+        let data = sensor_dsl::sensor.find(sensor_id)
+            .inner_join(site_dsl::site)
+            .select((site_dsl::id, site_dsl::name, sensor_dsl::name))
+            .get_result::<(IdType, Option<String>, Option<String>)>(conn)
+            .map_err(|x| x.to_string())?;
 
+        let payload = CalibrationReminderData {
+            site_id: data.0,
+            site_name: data.1.unwrap_or_else(|| "?".to_string()),
+            sensor_name: data.2.unwrap_or_else(|| "?".to_string()),
+            certificate_id: certificate_id.to_string(),
+            next_due_at,
+        };
 
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
 
+        if let Some(fcm) = fcm_client {
+            fcm.send_calibration_reminder(conn, &payload).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_calibration_reminder(conn, &payload).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the users of the sensor's site that the sensor stopped sending readings.
+    pub async fn send_no_data(&self, conn: &DbConnection, sensor_id: IdType, last_measure: Option<NaiveDateTime>) -> Result<(), String> {
+        use crate::schema::{
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
+
+        let data = sensor_dsl::sensor.find(sensor_id)
+            .inner_join(site_dsl::site)
+            .select((site_dsl::id, site_dsl::name, sensor_dsl::name))
+            .get_result::<(IdType, Option<String>, Option<String>)>(conn)
+            .map_err(|x| x.to_string())?;
+
+        let payload = NoDataAlarmData {
+            site_id: data.0,
+            site_name: data.1.unwrap_or_else(|| "?".to_string()),
+            sensor_name: data.2.unwrap_or_else(|| "?".to_string()),
+            last_measure,
+        };
+
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_no_data(conn, &payload).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_no_data(conn, &payload).await?;
+        }
+
+        self.webhook_client.send_no_data(conn, &payload).await?;
+
+        Ok(())
+    }
+
+    /// Posts the new status of a site to its webhooks, only sent to the building management
+    /// systems as the users are already notified of the single alarms.
+    pub async fn send_site_status(&self, conn: &DbConnection, data: &SiteStatusData) -> Result<(), String> {
+        self.webhook_client.send_site_status(conn, data).await
+    }
+
+    /// Notifies the user that requested a background export that its file can be downloaded.
+    pub async fn send_export_ready(&self, conn: &DbConnection, data: &ExportReadyData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_export_ready(conn, data).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_export_ready(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the admin that requested a bulk export that its zip file can be downloaded.
+    pub async fn send_bulk_export_ready(&self, conn: &DbConnection, data: &BulkExportReadyData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_bulk_export_ready(conn, data).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_bulk_export_ready(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Emails a site report to the subscribed user, only sent by email as push notifications
+    /// can't carry the document.
+    pub async fn send_report(&self, conn: &DbConnection, data: &ReportData) -> Result<(), String> {
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(email) = email_client {
+            email.send_report(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the admins that a client is probably enumerating the ids it can't access.
+    pub async fn send_access_alert(&self, conn: &DbConnection, data: &AccessAlertData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_access_alert(conn, data).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_access_alert(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the contact group of an escalation policy about an alarm not yet acknowledged.
+    pub async fn send_escalation(&self, conn: &DbConnection, data: &EscalationData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_escalation(conn, data).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_escalation(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the admins that the alarms of a channel won't be notified as it's flapping.
+    pub async fn send_flapping(&self, conn: &DbConnection, channel_id: IdType, alarms: i64, window_minutes: u64) -> Result<(), String> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
+
+        let data = channel_dsl::channel.find(channel_id)
+            .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+            .select((site_dsl::name, sensor_dsl::name, channel_dsl::name))
+            .get_result::<(Option<String>, Option<String>, Option<String>)>(conn)
+            .map_err(|x| x.to_string())?;
+
+        let payload = FlappingData {
+            site_name: data.0.unwrap_or_else(|| "?".to_string()),
+            sensor_name: data.1.unwrap_or_else(|| "?".to_string()),
+            channel_name: data.2.unwrap_or_else(|| "?".to_string()),
+            alarms,
+            window_minutes,
+        };
+
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_flapping(conn, &payload).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_flapping(conn, &payload).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the admins that a site has readings of sensors or channels not configured yet.
+    pub async fn send_discovery(&self, conn: &DbConnection, data: &DiscoveryData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_discovery(conn, data).await?;
+        }
+
+        if let Some(email) = email_client {
+            email.send_discovery(conn, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the verification token to the new email of an account, it's the only email sent
+    /// to an unverified address.
+    pub async fn send_email_verification(&self, data: &EmailVerificationData) -> Result<(), String> {
+        let email_client = self.email_client.read().unwrap().clone();
+
+        if let Some(email) = email_client {
+            email.send_email_verification(data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Warns the devices of the user that their quota is almost exhausted, only sent as push
+    /// notification as it's only meaningful to the running clients.
+    pub async fn send_quota_warning(&self, conn: &DbConnection, data: &QuotaWarningData) -> Result<(), String> {
+        let fcm_client = self.fcm_client.read().unwrap().clone();
+
+        if let Some(fcm) = fcm_client {
+            fcm.send_quota_warning(conn, data).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+
+use actix_web::web;
+use diesel::prelude::*;
+use lettre::{ClientSecurity, ClientTlsParameters, SendableEmail, SmtpClient, Transport};
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::smtp::client::net::DEFAULT_TLS_PROTOCOLS;
+use lettre_email::{Email, EmailBuilder, mime};
+use log::info;
+use native_tls::TlsConnector;
+use serde::Deserialize;
+
+use crate::config::Resolver;
+use crate::models::{IdType, PermissionType};
+
+use super::contacter::DbConnection;
+use super::contacter::{AccessAlertData, BulkExportReadyData, CalibrationReminderData, DiscoveryData, EmailVerificationData, EscalationData, ExportReadyData, FlappingData, NoDataAlarmData, ReportData, SensorRangeAlarmData};
+
+sql_function! {
+    /// The addresses are compared ignoring the case, they're stored as the users typed them
+    fn lower(x: diesel::sql_types::Nullable<diesel::sql_types::Text>) -> diesel::sql_types::Nullable<diesel::sql_types::Text>;
+}
+
+const SMTP_SUBMISSIONS_PORT: u16 = 465;
+const SMTP_SUBMISSION_PORT: u16 = 587;
+
+/// `[smtp]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: Option<String>,
+    pub insecure: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    /// Disables TLS, only useful for local relays
+    pub insecure: bool,
+}
+
+impl EmailConfig {
+    /// Reads the SMTP configuration, returns None if SMTP_HOST is not set.
+    /// Port 465 uses a TLS wrapped connection, any other port requires STARTTLS.
+    pub fn resolve(r: &mut Resolver, file: SmtpSection) -> Option<EmailConfig> {
+        let host = r.optional("SMTP_HOST", file.host).filter(|x: &String| !x.is_empty());
+        let port = r.optional("SMTP_PORT", file.port).unwrap_or(SMTP_SUBMISSION_PORT);
+        let username = r.optional("SMTP_USERNAME", file.username);
+        let password = r.optional("SMTP_PASSWORD", file.password);
+        let from = r.optional("SMTP_FROM", file.from);
+        let insecure = r.flag("SMTP_INSECURE", file.insecure);
+
+        let host = host?;
+        Some(EmailConfig {
+            from: from.unwrap_or_else(|| format!("oldmusa@{}", host)),
+            host,
+            port,
+            credentials: username.zip(password),
+            insecure,
+        })
+    }
+}
+
+pub struct EmailContacter {
+    config: EmailConfig,
+}
+
+impl EmailContacter {
+    pub fn new(config: EmailConfig) -> Self {
+        EmailContacter {
+            config
+        }
+    }
+
+    fn get_email_site_receivers(&self, conn: &DbConnection, site_id: IdType) -> Result<Vec<String>, String> {
+        use crate::schema::{
+            user_account::dsl as user_dsl,
+            email_user_contact::dsl as email_dsl,
+            user_access::dsl as user_access_dsl,
+        };
+
+        let mut users: Vec<String> = user_access_dsl::user_access.inner_join(user_dsl::user_account.inner_join(email_dsl::email_user_contact))
+            .filter(user_access_dsl::site_id.eq(site_id))
+            .filter(user_dsl::email_verified_at.is_not_null())
+            .filter(lower(user_dsl::email).eq(lower(email_dsl::email.nullable())))
+            .select(email_dsl::email)
+            .distinct()
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())?;
+
+        let mut admins = self.get_email_admin_receivers(conn)?;
+
+        let mut res: HashSet<String> = users.drain(..).chain(admins.drain(..)).collect();
+
+        Ok(res.drain().collect())
+    }
+
+    fn get_email_admin_receivers(&self, conn: &DbConnection) -> Result<Vec<String>, String> {
+        use crate::schema::{
+            user_account::dsl as user_dsl,
+            email_user_contact::dsl as email_dsl,
+        };
+
+        user_dsl::user_account.inner_join(email_dsl::email_user_contact)
+            .filter(user_dsl::permission.eq(PermissionType::Admin))
+            .filter(user_dsl::email_verified_at.is_not_null())
+            .filter(lower(user_dsl::email).eq(lower(email_dsl::email.nullable())))
+            .select(email_dsl::email)
+            .distinct()
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())
+    }
+
+    /// Email contacts of the users, like the other receivers only the contacts matching the
+    /// verified email of their account are used
+    fn get_email_user_receivers(&self, conn: &DbConnection, user_ids: &[IdType]) -> Result<Vec<String>, String> {
+        use crate::schema::{
+            user_account::dsl as user_dsl,
+            email_user_contact::dsl as email_dsl,
+        };
+
+        user_dsl::user_account.inner_join(email_dsl::email_user_contact)
+            .filter(user_dsl::id.eq_any(user_ids))
+            .filter(user_dsl::email_verified_at.is_not_null())
+            .filter(lower(user_dsl::email).eq(lower(email_dsl::email.nullable())))
+            .select(email_dsl::email)
+            .distinct()
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())
+    }
+
+    pub async fn send_alarm(&self, conn: &DbConnection, data: &SensorRangeAlarmData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Alarm in {}: {}", data.site_name, data.channel_label());
+        let body = format!(
+            "The channel \"{}\" of the sensor \"{}\" in the site \"{}\" went out of range.\r\n\r\nRead value: {}\r\n",
+            data.channel_label(), data.sensor_name, data.site_name, data.value
+        );
+
+        self.send_to_site(conn, data.site_id, &subject, &body).await
+    }
+
+    pub async fn send_calibration_reminder(&self, conn: &DbConnection, data: &CalibrationReminderData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Calibration due in {}: {}", data.site_name, data.sensor_name);
+        let body = format!(
+            "The sensor \"{}\" in the site \"{}\" must be calibrated by {}.\r\n\r\nLast certificate: {}\r\n",
+            data.sensor_name, data.site_name, data.next_due_at.format("%Y-%m-%d"), data.certificate_id
+        );
+
+        self.send_to_site(conn, data.site_id, &subject, &body).await
+    }
+
+    pub async fn send_no_data(&self, conn: &DbConnection, data: &NoDataAlarmData) -> Result<(), String> {
+        let subject = format!("[OldMusa] No data from {}: {}", data.site_name, data.sensor_name);
+        let last_measure = data.last_measure
+            .map(|x| x.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let body = format!(
+            "The sensor \"{}\" in the site \"{}\" stopped sending readings.\r\n\r\nLast reading: {}\r\n",
+            data.sensor_name, data.site_name, last_measure
+        );
+
+        self.send_to_site(conn, data.site_id, &subject, &body).await
+    }
+
+    pub async fn send_access_alert(&self, conn: &DbConnection, data: &AccessAlertData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Repeated access failures from {}", data.source);
+        let body = format!(
+            "The client {} had {} unauthorized or not found requests in {} seconds, it could be enumerating the sites and sensors.\r\n{}",
+            data.source, data.failures, data.window_secs,
+            if data.throttled { "Its requests are rejected for a while.\r\n" } else { "" }
+        );
+
+        let receivers = self.get_email_admin_receivers(conn)?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_export_ready(&self, conn: &DbConnection, data: &ExportReadyData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Export of {} ready", data.channel_name);
+        let body = format!(
+            "The export of the channel \"{}\" ({} readings) can be downloaded from:\r\n{}\r\n",
+            data.channel_name, data.rows, data.link
+        );
+
+        let receivers = self.get_email_user_receivers(conn, &[data.user_id])?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_bulk_export_ready(&self, conn: &DbConnection, data: &BulkExportReadyData) -> Result<(), String> {
+        let subject = "[OldMusa] Bulk export ready".to_string();
+        let body = format!(
+            "The export of the sites {} ({} channels, {} readings) can be downloaded from:\r\n{}\r\n",
+            data.site_names.join(", "), data.channels, data.rows, data.link
+        );
+
+        let receivers = self.get_email_user_receivers(conn, &[data.user_id])?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_escalation(&self, conn: &DbConnection, data: &EscalationData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Unacknowledged alarm in {}: {}", data.site_name, data.channel_name);
+        let body = format!(
+            "The alarm of the channel \"{}\" of the sensor \"{}\" in the site \"{}\" started at {} and wasn't acknowledged within {} minutes.\r\n",
+            data.channel_name, data.sensor_name, data.site_name,
+            data.started_at.format("%Y-%m-%d %H:%M:%S UTC"), data.delay_minutes
+        );
+
+        let receivers = self.get_email_user_receivers(conn, &data.user_ids)?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_flapping(&self, conn: &DbConnection, data: &FlappingData) -> Result<(), String> {
+        let subject = format!("[OldMusa] Flapping channel in {}: {}", data.site_name, data.channel_name);
+        let body = format!(
+            "The channel \"{}\" of the sensor \"{}\" in the site \"{}\" began {} alarms in {} minutes.\r\n\r\nIts alarms won't be notified until it stays quiet for {} minutes.\r\n",
+            data.channel_name, data.sensor_name, data.site_name, data.alarms, data.window_minutes, data.window_minutes
+        );
+
+        let receivers = self.get_email_admin_receivers(conn)?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_discovery(&self, conn: &DbConnection, data: &DiscoveryData) -> Result<(), String> {
+        let subject = format!("[OldMusa] New channels found in {}", data.site_name);
+        let channels: String = data.channels.iter()
+            .map(|(sensor, channel)| format!("- sensor {}, channel {}\r\n", sensor, channel))
+            .collect();
+        let body = format!(
+            "The CNR readings of the site \"{}\" contain channels that aren't configured:\r\n{}\r\nThey can be created with the following mutation:\r\n{}\r\n",
+            data.site_name, channels, data.mutation
+        );
+
+        let receivers = self.get_email_admin_receivers(conn)?;
+        self.send_to(receivers, &subject, &body).await
+    }
+
+    pub async fn send_report(&self, conn: &DbConnection, data: &ReportData) -> Result<(), String> {
+        let subject = format!("[OldMusa] {} of {}", data.title, data.site_name);
+
+        let receivers = self.get_email_user_receivers(conn, &[data.user_id])?;
+        self.send_each(receivers, |builder| {
+            let builder = builder.subject(subject.as_str()).html(data.html.as_str());
+            match &data.pdf {
+                Some(pdf) => builder.attachment(pdf, &data.file_name, &mime::APPLICATION_PDF)?.build(),
+                None => builder.build(),
+            }
+        }).await
+    }
+
+    pub async fn send_email_verification(&self, data: &EmailVerificationData) -> Result<(), String> {
+        let subject = "[OldMusa] Email verification";
+        let body = format!(
+            "The email of the account \"{}\" can be verified with the code:\r\n{}\r\n\r\nThe code expires in 24 hours, ignore this email if you didn't ask for it.\r\n",
+            data.username, data.token
+        );
+
+        self.send_to(vec![data.email.clone()], subject, &body).await
+    }
+
+    /// Sends the email to every user that can see the site (and to the admins)
+    async fn send_to_site(&self, conn: &DbConnection, site_id: IdType, subject: &str, body: &str) -> Result<(), String> {
+        let receivers = self.get_email_site_receivers(conn, site_id)?;
+        self.send_to(receivers, subject, body).await
+    }
+
+    async fn send_to(&self, receivers: Vec<String>, subject: &str, body: &str) -> Result<(), String> {
+        self.send_each(receivers, |builder| builder.subject(subject).text(body).build()).await
+    }
+
+    /// Sends an email to every receiver, build completes the email (already addressed) with its
+    /// subject and content
+    async fn send_each<F>(&self, receivers: Vec<String>, build: F) -> Result<(), String>
+        where F: Fn(EmailBuilder) -> Result<Email, lettre_email::error::Error> {
+        if receivers.is_empty() {
+            return Ok(())
+        }
+
+        let mut emails: Vec<(String, SendableEmail)> = Vec::new();
+        for receiver in receivers {
+            let email = build(EmailBuilder::new()
+                .to(receiver.as_str())
+                .from(self.config.from.as_str()));
+
+            match email {
+                Ok(x) => emails.push((receiver, x.into())),
+                Err(err) => info!("Error building email for {}: {:?}", receiver, err),
+            }
+        }
+
+        // The SMTP client is blocking from the host lookup on, keep it out of the arbiter of the caller
+        let config = self.config.clone();
+        web::block(move || {
+            let mut transport = create_client(&config)?.transport();
+            for (receiver, email) in emails {
+                if let Err(err) = transport.send(email) {
+                    info!("Error sending email to {}: {:?}", receiver, err);
+                }
+            }
+            transport.close();
+            Ok::<_, String>(())
+        }).await.map_err(|x| x.to_string())
+    }
+}
+
+/// Resolves the host and prepares the TLS connector, both are blocking
+fn create_client(config: &EmailConfig) -> Result<SmtpClient, String> {
+    let security = if config.insecure {
+        ClientSecurity::None
+    } else {
+        let mut tls_builder = TlsConnector::builder();
+        tls_builder.min_protocol_version(Some(DEFAULT_TLS_PROTOCOLS[0]));
+        let connector = tls_builder.build().map_err(|x| x.to_string())?;
+        let parameters = ClientTlsParameters::new(config.host.clone(), connector);
+
+        if config.port == SMTP_SUBMISSIONS_PORT {
+            ClientSecurity::Wrapper(parameters)
+        } else {
+            ClientSecurity::Required(parameters)
+        }
+    };
+
+    let mut client = SmtpClient::new((config.host.as_str(), config.port), security)
+        .map_err(|x| x.to_string())?
+        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
+
+    if let Some((username, password)) = config.credentials.as_ref() {
+        client = client.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    Ok(client)
+}
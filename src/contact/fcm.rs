@@ -8,7 +8,7 @@
 use crate::models::{IdType, PermissionType};
 
 use super::contacter::DbConnection;
-use super::contacter::SensorRangeAlarmData;
+use super::contacter::{AccessAlertData, BulkExportReadyData, CalibrationReminderData, DiscoveryData, EscalationData, ExportReadyData, FlappingData, NoDataAlarmData, QuotaWarningData, SensorRangeAlarmData};
 
 const FCM_MAX_RECIPIENTS: u32 = 1000;
 
@@ -40,25 +40,35 @@ fn get_fcm_site_receivers(&self, conn: &DbConnection, site_id: IdType) -> Result
             .load::<String>(conn)
             .map_err(|x| x.to_string())?;
 
-        let mut admins: Vec<String> = user_dsl::user_account.inner_join(fcm_dsl::fcm_user_contact)
-            .filter(user_dsl::permission.eq(PermissionType::Admin.to_char()))
-            .select(fcm_dsl::registration_id)
-            .distinct()
-            .order_by(fcm_dsl::registration_id.asc())
-            .load::<String>(conn)
-            .map_err(|x| x.to_string())?;
+        let mut admins = self.get_fcm_admin_receivers(conn)?;
 
         let mut res: HashSet<String> = users.drain(..).chain(admins.drain(..)).collect();
 
         Ok(res.drain().collect())
     }
 
+    fn get_fcm_admin_receivers(&self, conn: &DbConnection) -> Result<Vec<String>, String> {
+        use crate::schema::{
+            user_account::dsl as user_dsl,
+            fcm_user_contact::dsl as fcm_dsl,
+        };
+
+        user_dsl::user_account.inner_join(fcm_dsl::fcm_user_contact)
+            .filter(user_dsl::permission.eq(PermissionType::Admin))
+            .select(fcm_dsl::registration_id)
+            .distinct()
+            .order_by(fcm_dsl::registration_id.asc())
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())
+    }
+
     pub async fn send_alarm(&self, conn: &DbConnection, data: &SensorRangeAlarmData) -> Result<(), String> {
         let payload = SensorRangeAlarmMessagePayload {
             mex_type: "sensor_range_alarm".to_string(),
             site_name: data.site_name.to_string(),
+            room_name: data.room_name.clone(),
             sensor_name: data.sensor_name.to_string(),
-            channel_name: data.channel_name.to_string(),
+            channel_name: data.channel_label(),
             value: data.value.to_string(),
         };
 
@@ -68,6 +78,165 @@ pub async fn send_alarm(&self, conn: &DbConnection, data: &SensorRangeAlarmData)
         Ok(())
     }
 
+    pub async fn send_calibration_reminder(&self, conn: &DbConnection, data: &CalibrationReminderData) -> Result<(), String> {
+        let payload = CalibrationReminderMessagePayload {
+            mex_type: "calibration_reminder".to_string(),
+            site_name: data.site_name.to_string(),
+            sensor_name: data.sensor_name.to_string(),
+            certificate_id: data.certificate_id.to_string(),
+            next_due_at: data.next_due_at.timestamp(),
+        };
+
+        let contacted = self.get_fcm_site_receivers(conn, data.site_id)?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_no_data(&self, conn: &DbConnection, data: &NoDataAlarmData) -> Result<(), String> {
+        let payload = NoDataAlarmMessagePayload {
+            mex_type: "no_data_alarm".to_string(),
+            site_name: data.site_name.to_string(),
+            sensor_name: data.sensor_name.to_string(),
+            last_measure: data.last_measure.map(|x| x.timestamp()),
+        };
+
+        let contacted = self.get_fcm_site_receivers(conn, data.site_id)?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_access_alert(&self, conn: &DbConnection, data: &AccessAlertData) -> Result<(), String> {
+        let payload = AccessAlertMessagePayload {
+            mex_type: "access_alert".to_string(),
+            source: data.source.clone(),
+            failures: data.failures,
+            window_secs: data.window_secs,
+            throttled: data.throttled,
+        };
+
+        let contacted = self.get_fcm_admin_receivers(conn)?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_export_ready(&self, conn: &DbConnection, data: &ExportReadyData) -> Result<(), String> {
+        use crate::schema::fcm_user_contact::dsl as fcm_dsl;
+
+        let payload = ExportReadyMessagePayload {
+            mex_type: "export_ready".to_string(),
+            channel_name: data.channel_name.clone(),
+            link: data.link.clone(),
+            rows: data.rows,
+        };
+
+        let contacted = fcm_dsl::fcm_user_contact
+            .filter(fcm_dsl::user_id.eq(data.user_id))
+            .select(fcm_dsl::registration_id)
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_bulk_export_ready(&self, conn: &DbConnection, data: &BulkExportReadyData) -> Result<(), String> {
+        use crate::schema::fcm_user_contact::dsl as fcm_dsl;
+
+        let payload = BulkExportReadyMessagePayload {
+            mex_type: "bulk_export_ready".to_string(),
+            site_names: data.site_names.clone(),
+            link: data.link.clone(),
+            channels: data.channels,
+            rows: data.rows,
+        };
+
+        let contacted = fcm_dsl::fcm_user_contact
+            .filter(fcm_dsl::user_id.eq(data.user_id))
+            .select(fcm_dsl::registration_id)
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_escalation(&self, conn: &DbConnection, data: &EscalationData) -> Result<(), String> {
+        use crate::schema::fcm_user_contact::dsl as fcm_dsl;
+
+        let payload = EscalationMessagePayload {
+            mex_type: "alarm_escalation".to_string(),
+            site_name: data.site_name.clone(),
+            sensor_name: data.sensor_name.clone(),
+            channel_name: data.channel_name.clone(),
+            started_at: data.started_at.timestamp(),
+            delay_minutes: data.delay_minutes,
+        };
+
+        let contacted = fcm_dsl::fcm_user_contact
+            .filter(fcm_dsl::user_id.eq_any(&data.user_ids))
+            .select(fcm_dsl::registration_id)
+            .distinct()
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_flapping(&self, conn: &DbConnection, data: &FlappingData) -> Result<(), String> {
+        let payload = FlappingMessagePayload {
+            mex_type: "channel_flapping".to_string(),
+            site_name: data.site_name.clone(),
+            sensor_name: data.sensor_name.clone(),
+            channel_name: data.channel_name.clone(),
+            alarms: data.alarms,
+            window_minutes: data.window_minutes,
+        };
+
+        let contacted = self.get_fcm_admin_receivers(conn)?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_discovery(&self, conn: &DbConnection, data: &DiscoveryData) -> Result<(), String> {
+        let payload = DiscoveryMessagePayload {
+            mex_type: "channels_discovered".to_string(),
+            site_id: data.site_id,
+            site_name: data.site_name.clone(),
+            channels: data.channels.len(),
+            mutation: data.mutation.clone(),
+        };
+
+        let contacted = self.get_fcm_admin_receivers(conn)?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
+    pub async fn send_quota_warning(&self, conn: &DbConnection, data: &QuotaWarningData) -> Result<(), String> {
+        use crate::schema::fcm_user_contact::dsl as fcm_dsl;
+
+        let payload = QuotaWarningMessagePayload {
+            mex_type: "quota_warning".to_string(),
+            pool: data.pool.clone(),
+            balance: data.balance,
+            max_balance: data.max_balance,
+        };
+
+        let contacted = fcm_dsl::fcm_user_contact
+            .filter(fcm_dsl::user_id.eq(data.user_id))
+            .select(fcm_dsl::registration_id)
+            .load::<String>(conn)
+            .map_err(|x| x.to_string())?;
+
+        self.send_message(&payload, contacted).await;
+        Ok(())
+    }
+
     pub async fn send_message<T: Serialize>(&self, message: &T, ids: Vec<String>) {
         for id_chunks in ids.chunks(FCM_MAX_RECIPIENTS as usize) {
             let mut builder = MessageBuilder::new_multi(&self.api_key, id_chunks);
@@ -86,7 +255,97 @@ struct SensorRangeAlarmMessagePayload {
     #[serde(rename="type")]
     mex_type: String,
     site_name: String,
+    room_name: Option<String>,
     sensor_name: String,
     channel_name: String,
     value: String,
 }
+
+#[derive(Debug, Serialize)]
+struct CalibrationReminderMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_name: String,
+    sensor_name: String,
+    certificate_id: String,
+    next_due_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct NoDataAlarmMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_name: String,
+    sensor_name: String,
+    last_measure: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccessAlertMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    source: String,
+    failures: usize,
+    window_secs: u64,
+    throttled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportReadyMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    channel_name: String,
+    link: String,
+    rows: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkExportReadyMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_names: Vec<String>,
+    link: String,
+    channels: usize,
+    rows: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct EscalationMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_name: String,
+    sensor_name: String,
+    channel_name: String,
+    started_at: i64,
+    delay_minutes: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct FlappingMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_name: String,
+    sensor_name: String,
+    channel_name: String,
+    alarms: i64,
+    window_minutes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscoveryMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    site_id: IdType,
+    site_name: String,
+    channels: usize,
+    mutation: String,
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaWarningMessagePayload {
+    #[serde(rename="type")]
+    mex_type: String,
+    pool: String,
+    balance: i64,
+    max_balance: i64,
+}
@@ -1,6 +1,22 @@
 mod contacter;
+mod email;
 mod fcm;
+mod webhook;
 
+pub use contacter::AccessAlertData;
+pub use contacter::AffectedSensorData;
+pub use contacter::BulkExportReadyData;
+pub use contacter::ContactOverrides;
 pub use contacter::Contacter;
+pub use contacter::DiscoveryData;
+pub use contacter::EmailVerificationData;
+pub use contacter::EscalationData;
+pub use contacter::ExportReadyData;
 pub use contacter::MeasureExtremeType;
+pub use contacter::QuotaWarningData;
+pub use contacter::ReportData;
+pub use contacter::SiteStatus;
+pub use contacter::SiteStatusData;
+pub use email::{EmailConfig, SmtpSection};
 
+pub use webhook::{sign_payload, WebhookConfig, WebhookSection, EVENT_HEADER, SIGNATURE_HEADER};
@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Client, Request, StatusCode};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::Resolver;
+use crate::models::IdType;
+
+use super::contacter::DbConnection;
+use super::contacter::{NoDataAlarmData, SensorRangeAlarmData, SiteStatusData};
+
+pub const SIGNATURE_HEADER: &str = "X-OldMusa-Signature";
+pub const EVENT_HEADER: &str = "X-OldMusa-Event";
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Deliveries tried for every payload, the first one included
+    pub max_attempts: u32,
+    /// Wait before the first retry, it doubles at every retry
+    pub initial_backoff: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// `[webhooks]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSection {
+    pub max_attempts: Option<u32>,
+    pub backoff_ms: Option<u64>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl WebhookConfig {
+    pub fn resolve(r: &mut Resolver, file: WebhookSection) -> Self {
+        let default = WebhookConfig::default();
+        WebhookConfig {
+            max_attempts: r.optional("WEBHOOK_MAX_ATTEMPTS", file.max_attempts).map_or(default.max_attempts, |x| x.max(1)),
+            initial_backoff: r.optional("WEBHOOK_BACKOFF_MS", file.backoff_ms).map_or(default.initial_backoff, Duration::from_millis),
+            timeout: r.optional("WEBHOOK_TIMEOUT_SECS", file.timeout_secs).map_or(default.timeout, Duration::from_secs),
+        }
+    }
+}
+
+/// Signature of the body sent in the signature header, the receivers compute the HMAC-SHA256 of
+/// the raw body with the secret of the webhook and compare it.
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.input(body);
+    format!("sha256={}", hex::encode(mac.result().code()))
+}
+
+/// Posts the alarms as JSON to the urls configured for their site, so that they can reach the
+/// building management and ticketing systems.
+/// The deliveries run in the background, a failed delivery is retried with an exponential backoff.
+pub struct WebhookContacter {
+    config: WebhookConfig,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+}
+
+impl WebhookContacter {
+    pub fn new(config: WebhookConfig) -> Self {
+        WebhookContacter {
+            config,
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    fn get_site_webhooks(&self, conn: &DbConnection, site_id: IdType) -> Result<Vec<(String, String)>, String> {
+        use crate::schema::site_webhook::dsl;
+
+        dsl::site_webhook
+            .filter(dsl::site_id.eq(site_id))
+            .select((dsl::url, dsl::secret))
+            .load::<(String, String)>(conn)
+            .map_err(|x| x.to_string())
+    }
+
+    fn get_site_status_webhooks(&self, conn: &DbConnection, site_id: IdType) -> Result<Vec<(String, String)>, String> {
+        use crate::schema::site_webhook::dsl;
+
+        dsl::site_webhook
+            .filter(dsl::site_id.eq(site_id))
+            .filter(dsl::status_changes.eq(true))
+            .select((dsl::url, dsl::secret))
+            .load::<(String, String)>(conn)
+            .map_err(|x| x.to_string())
+    }
+
+    pub async fn send_alarm(&self, conn: &DbConnection, data: &SensorRangeAlarmData) -> Result<(), String> {
+        let payload = SensorRangeAlarmWebhookPayload {
+            mex_type: "sensor_range_alarm",
+            site_id: data.site_id,
+            site_name: data.site_name.clone(),
+            room_name: data.room_name.clone(),
+            sensor_name: data.sensor_name.clone(),
+            channel_name: data.channel_name.clone(),
+            value: data.value.clone(),
+        };
+
+        let webhooks = self.get_site_webhooks(conn, data.site_id)?;
+        self.send_message(payload.mex_type, &payload, webhooks)
+    }
+
+    pub async fn send_no_data(&self, conn: &DbConnection, data: &NoDataAlarmData) -> Result<(), String> {
+        let payload = NoDataAlarmWebhookPayload {
+            mex_type: "no_data_alarm",
+            site_id: data.site_id,
+            site_name: data.site_name.clone(),
+            sensor_name: data.sensor_name.clone(),
+            last_measure: data.last_measure.map(|x| x.timestamp()),
+        };
+
+        let webhooks = self.get_site_webhooks(conn, data.site_id)?;
+        self.send_message(payload.mex_type, &payload, webhooks)
+    }
+
+    pub async fn send_site_status(&self, conn: &DbConnection, data: &SiteStatusData) -> Result<(), String> {
+        let payload = SiteStatusWebhookPayload {
+            mex_type: "site_status_changed",
+            site_id: data.site_id,
+            site_name: data.site_name.clone(),
+            previous_status: data.previous.name(),
+            status: data.status.name(),
+            sensors: data.sensors.iter()
+                .map(|x| AffectedSensorPayload {
+                    sensor_id: x.sensor_id,
+                    sensor_name: x.sensor_name.clone(),
+                    alarmed_channels: x.alarmed_channels.clone(),
+                    no_data: x.no_data,
+                })
+                .collect(),
+        };
+
+        let webhooks = self.get_site_status_webhooks(conn, data.site_id)?;
+        self.send_message(payload.mex_type, &payload, webhooks)
+    }
+
+    fn send_message<T: Serialize>(&self, event: &'static str, message: &T, webhooks: Vec<(String, String)>) -> Result<(), String> {
+        if webhooks.is_empty() {
+            return Ok(())
+        }
+        let body = serde_json::to_vec(message).map_err(|x| x.to_string())?;
+
+        for (url, secret) in webhooks {
+            let delivery = deliver(self.client.clone(), self.config.clone(), event, url, sign_payload(&secret, &body), body.clone());
+            actix_rt::spawn(async move {
+                if let Err(err) = delivery.await {
+                    warn!("{}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Posts the body to the url, retrying on network errors, timeouts, 429 and 5xx responses.
+/// Returns the number of attempts needed.
+pub async fn deliver(
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    config: WebhookConfig,
+    event: &str,
+    url: String,
+    signature: String,
+    body: Vec<u8>,
+) -> Result<u32, String> {
+    let mut backoff = config.initial_backoff;
+
+    for attempt in 1..=config.max_attempts {
+        let request = Request::post(url.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .header(SIGNATURE_HEADER, signature.as_str())
+            .body(Body::from(body.clone()))
+            .map_err(|x| format!("Invalid webhook {}: {}", url, x))?;
+
+        let error = match actix_rt::time::timeout(config.timeout, client.request(request)).await {
+            Ok(Ok(res)) if res.status().is_success() => return Ok(attempt),
+            Ok(Ok(res)) if !is_retryable(res.status()) => {
+                return Err(format!("Webhook {} rejected the payload: {}", url, res.status()))
+            },
+            Ok(Ok(res)) => res.status().to_string(),
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+
+        if attempt < config.max_attempts {
+            info!("Webhook {} failed ({}), retrying in {}ms", url, error, backoff.as_millis());
+            actix_rt::time::delay_for(backoff).await;
+            backoff *= 2;
+        }
+    }
+    Err(format!("Webhook {} failed after {} attempts", url, config.max_attempts))
+}
+
+#[derive(Debug, Serialize)]
+struct SensorRangeAlarmWebhookPayload {
+    #[serde(rename="type")]
+    mex_type: &'static str,
+    site_id: IdType,
+    site_name: String,
+    room_name: Option<String>,
+    sensor_name: String,
+    channel_name: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NoDataAlarmWebhookPayload {
+    #[serde(rename="type")]
+    mex_type: &'static str,
+    site_id: IdType,
+    site_name: String,
+    sensor_name: String,
+    last_measure: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct AffectedSensorPayload {
+    sensor_id: IdType,
+    sensor_name: String,
+    alarmed_channels: Vec<String>,
+    no_data: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SiteStatusWebhookPayload {
+    #[serde(rename="type")]
+    mex_type: &'static str,
+    site_id: IdType,
+    site_name: String,
+    previous_status: &'static str,
+    status: &'static str,
+    sensors: Vec<AffectedSensorPayload>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_webhook_delivery() {
+        assert_eq!(
+            sign_payload("secret", b"{}"),
+            "sha256=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13"
+        );
+
+        // The receiver fails the first delivery and accepts the second one
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in &["503 Service Unavailable", "204 No Content"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0u8; 4096];
+                let len = stream.read(&mut buffer).unwrap();
+                requests.push(String::from_utf8_lossy(&buffer[..len]).to_string());
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+
+        let config = WebhookConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            timeout: Duration::from_secs(5),
+        };
+        let contacter = WebhookContacter::new(config.clone());
+        let body = br#"{"type":"sensor_range_alarm"}"#.to_vec();
+        let signature = sign_payload("secret", &body);
+        let attempts = actix_rt::System::new("webhook").block_on(
+            deliver(contacter.client.clone(), config, "sensor_range_alarm", url, signature.clone(), body)
+        );
+        assert_eq!(attempts, Ok(2));
+
+        let requests = receiver.join().unwrap();
+        let request = requests[1].to_lowercase();
+        assert!(request.starts_with("post /hook"));
+        assert!(request.contains(&format!("x-oldmusa-signature: {}", signature)));
+        assert!(request.contains("x-oldmusa-event: sensor_range_alarm"));
+        assert!(requests[1].ends_with(r#"{"type":"sensor_range_alarm"}"#));
+    }
+}
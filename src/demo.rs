@@ -0,0 +1,239 @@
+//! Demo museum used by the local environments (`oldmusa-server seed-demo [days]`).
+//!
+//! It creates a couple of sites with their sensors, channels and floor plan, then fills the sensor
+//! store with synthetic readings (a daily cycle with some noise) for the last days. The site
+//! clocks are set to the current time so that the alarm controller only checks the new readings.
+
+
+use chrono::{Duration, NaiveDateTime, Timelike};
+use diesel::prelude::*;
+use plotters::prelude::*;
+
+use crate::alarm::{NewReading, ReadingsWriter};
+use crate::AppData;
+use crate::models::{IdType, Pool};
+use crate::web::site_map_service::{get_file_from_site, store_image};
+
+/// Station id used for the synthetic readings
+const DEMO_STATION_ID: &str = "demo";
+const READINGS_INTERVAL_MINUTES: i64 = 15;
+const FLOOR_PLAN_SIZE: (u32, u32) = (800, 500);
+
+struct DemoChannel {
+    id_cnr: &'static str,
+    name: &'static str,
+    measure_unit: &'static str,
+    range: (f64, f64),
+    /// Mean value of the readings and amplitude of their daily cycle
+    base: f64,
+    daily_swing: f64,
+}
+
+struct DemoSensor {
+    id_cnr: &'static str,
+    name: &'static str,
+    location: (i32, i32),
+    channels: &'static [DemoChannel],
+}
+
+struct DemoSite {
+    id_cnr: &'static str,
+    name: &'static str,
+    /// Rooms of the floor plan as (x, y, width, height)
+    rooms: &'static [(i32, i32, i32, i32)],
+    sensors: &'static [DemoSensor],
+}
+
+const CLIMATE_CHANNELS: &[DemoChannel] = &[
+    DemoChannel { id_cnr: "1", name: "Temperature", measure_unit: "°C", range: (18.0, 24.0), base: 21.0, daily_swing: 2.0 },
+    DemoChannel { id_cnr: "2", name: "Humidity", measure_unit: "%", range: (45.0, 60.0), base: 52.0, daily_swing: 5.0 },
+];
+
+const GALLERY_CHANNELS: &[DemoChannel] = &[
+    DemoChannel { id_cnr: "1", name: "Temperature", measure_unit: "°C", range: (18.0, 24.0), base: 20.5, daily_swing: 2.5 },
+    DemoChannel { id_cnr: "2", name: "Humidity", measure_unit: "%", range: (45.0, 60.0), base: 50.0, daily_swing: 6.0 },
+    DemoChannel { id_cnr: "3", name: "Illuminance", measure_unit: "lx", range: (0.0, 150.0), base: 60.0, daily_swing: 60.0 },
+];
+
+const ARCHIVE_CHANNELS: &[DemoChannel] = &[
+    DemoChannel { id_cnr: "1", name: "Temperature", measure_unit: "°C", range: (14.0, 18.0), base: 16.0, daily_swing: 0.8 },
+    DemoChannel { id_cnr: "2", name: "Humidity", measure_unit: "%", range: (35.0, 50.0), base: 42.0, daily_swing: 2.0 },
+];
+
+const DEMO_SITES: &[DemoSite] = &[
+    DemoSite {
+        id_cnr: "DEMO-HALL",
+        name: "Demo museum - Main hall",
+        rooms: &[(20, 20, 360, 460), (380, 20, 400, 220), (380, 240, 400, 240)],
+        sensors: &[
+            DemoSensor { id_cnr: "S1", name: "Entrance", location: (200, 400), channels: CLIMATE_CHANNELS },
+            DemoSensor { id_cnr: "S2", name: "Paintings gallery", location: (580, 130), channels: GALLERY_CHANNELS },
+            DemoSensor { id_cnr: "S3", name: "Sculptures gallery", location: (580, 360), channels: GALLERY_CHANNELS },
+        ],
+    },
+    DemoSite {
+        id_cnr: "DEMO-ARCHIVE",
+        name: "Demo museum - Archive",
+        rooms: &[(20, 20, 760, 200), (20, 220, 380, 260), (400, 220, 380, 260)],
+        sensors: &[
+            DemoSensor { id_cnr: "S1", name: "Manuscripts", location: (400, 120), channels: ARCHIVE_CHANNELS },
+            DemoSensor { id_cnr: "S2", name: "Photographs", location: (210, 350), channels: ARCHIVE_CHANNELS },
+        ],
+    },
+];
+
+#[derive(Debug)]
+pub struct DemoSeedReport {
+    pub site_ids: Vec<IdType>,
+    pub sensors: usize,
+    pub channels: usize,
+    pub readings: usize,
+}
+
+/// Synthetic reading of the channel, the daily cycle peaks in the afternoon
+fn demo_value(channel: &DemoChannel, date: NaiveDateTime, index: usize) -> f64 {
+    let hour = date.num_seconds_from_midnight() as f64 / 3600.0;
+    let cycle = ((hour - 9.0) / 24.0 * 2.0 * std::f64::consts::PI).sin();
+    // Deterministic noise, so that two runs give the same readings
+    let noise = (index as f64 * 12.9898).sin() * 0.1;
+    let value = channel.base + channel.daily_swing * (cycle + noise);
+    (value.max(0.0) * 10.0).round() / 10.0
+}
+
+fn render_floor_plan(site: &DemoSite) -> Result<Vec<u8>, String> {
+    let (width, height) = FLOOR_PLAN_SIZE;
+    let mut buffer = vec![0u8; (width * height * 3) as usize];
+    let error = |x: String| format!("Cannot render the floor plan: {}", x);
+
+    {
+        let root = BitMapBackend::with_buffer(&mut buffer, (width, height)).into_drawing_area();
+        root.fill(&WHITE).map_err(|x| error(x.to_string()))?;
+        for (x, y, w, h) in site.rooms.iter() {
+            root.draw(&Rectangle::new([(*x, *y), (x + w, y + h)], RGBColor(240, 236, 228).filled()))
+                .map_err(|x| error(x.to_string()))?;
+            root.draw(&Rectangle::new([(*x, *y), (x + w, y + h)], BLACK.stroke_width(4)))
+                .map_err(|x| error(x.to_string()))?;
+        }
+        root.present().map_err(|x| error(x.to_string()))?;
+    }
+
+    let mut res = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut res, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|x| error(x.to_string()))?;
+        writer.write_image_data(&buffer).map_err(|x| error(x.to_string()))?;
+    }
+    Ok(res)
+}
+
+fn create_demo_site(pool: &Pool, site: &DemoSite, now: NaiveDateTime) -> Result<(IdType, usize, usize), String> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let conn = pool.get().map_err(|x| x.to_string())?;
+    conn.transaction(|| {
+        let site_id = diesel::insert_into(site_dsl::site)
+            .values((
+                site_dsl::id_cnr.eq(site.id_cnr),
+                site_dsl::name.eq(site.name),
+                site_dsl::clock.eq(now),
+                site_dsl::image_width.eq(FLOOR_PLAN_SIZE.0 as i32),
+                site_dsl::image_height.eq(FLOOR_PLAN_SIZE.1 as i32),
+            ))
+            .returning(site_dsl::id)
+            .get_result::<IdType>(&conn)?;
+
+        let mut channels = 0;
+        for sensor in site.sensors.iter() {
+            let sensor_id = diesel::insert_into(sensor_dsl::sensor)
+                .values((
+                    sensor_dsl::site_id.eq(site_id),
+                    sensor_dsl::id_cnr.eq(sensor.id_cnr),
+                    sensor_dsl::name.eq(sensor.name),
+                    sensor_dsl::loc_x.eq(sensor.location.0),
+                    sensor_dsl::loc_y.eq(sensor.location.1),
+                ))
+                .returning(sensor_dsl::id)
+                .get_result::<IdType>(&conn)?;
+
+            let values: Vec<_> = sensor.channels.iter()
+                .map(|channel| (
+                    channel_dsl::sensor_id.eq(sensor_id),
+                    channel_dsl::id_cnr.eq(channel.id_cnr),
+                    channel_dsl::name.eq(channel.name),
+                    channel_dsl::measure_unit.eq(channel.measure_unit),
+                    channel_dsl::range_min.eq(bigdecimal::BigDecimal::from(channel.range.0)),
+                    channel_dsl::range_max.eq(bigdecimal::BigDecimal::from(channel.range.1)),
+                    channel_dsl::alarmed.eq(true),
+                ))
+                .collect();
+            channels += diesel::insert_into(channel_dsl::channel)
+                .values(&values)
+                .execute(&conn)?;
+        }
+        Ok((site_id, site.sensors.len(), channels))
+    }).map_err(|x: diesel::result::Error| x.to_string())
+}
+
+/// Creates the demo sites and writes `days` days of readings before `now` for each of their
+/// channels.
+/// Fails if the demo sites are already present, they have to be deleted before seeding again.
+pub fn seed_demo<W: ReadingsWriter>(ctx: &AppData, writer: &W, now: NaiveDateTime, days: i64) -> Result<DemoSeedReport, String> {
+    use crate::schema::site::dsl as site_dsl;
+
+    let cnr_ids: Vec<&str> = DEMO_SITES.iter().map(|x| x.id_cnr).collect();
+    let existing = site_dsl::site
+        .filter(site_dsl::id_cnr.eq_any(cnr_ids))
+        .select(site_dsl::id)
+        .load::<IdType>(&ctx.pool.get().map_err(|x| x.to_string())?)
+        .map_err(|x| x.to_string())?;
+    if !existing.is_empty() {
+        return Err(format!("The demo museum is already present (sites {:?}), delete it before seeding again", existing))
+    }
+
+    let now = now.with_nanosecond(0).unwrap_or(now);
+    let start = now - Duration::days(days);
+    let mut report = DemoSeedReport { site_ids: Vec::new(), sensors: 0, channels: 0, readings: 0 };
+
+    for site in DEMO_SITES.iter() {
+        let (site_id, sensors, channels) = create_demo_site(&ctx.pool, site, now)?;
+        report.site_ids.push(site_id);
+        report.sensors += sensors;
+        report.channels += channels;
+
+        let plan = render_floor_plan(site)?;
+        store_image(&*ctx.site_maps, &ctx.map_images, &get_file_from_site(site_id), plan)
+            .map_err(|x| format!("Cannot write the floor plan: {}", x))?;
+
+        let mut readings = Vec::new();
+        for sensor in site.sensors.iter() {
+            for channel in sensor.channels.iter() {
+                let mut date = start;
+                let mut index = 0;
+                while date <= now {
+                    readings.push(NewReading {
+                        site_id: site.id_cnr.to_string(),
+                        room_id: "".to_string(),
+                        station_id: DEMO_STATION_ID.to_string(),
+                        sensor_id: sensor.id_cnr.to_string(),
+                        channel_id: channel.id_cnr.to_string(),
+                        value: demo_value(channel, date, index),
+                        measure_unit: channel.measure_unit.to_string(),
+                        date,
+                    });
+                    date += Duration::minutes(READINGS_INTERVAL_MINUTES);
+                    index += 1;
+                }
+            }
+        }
+        writer.insert_readings(&readings).map_err(|x| x.to_string())?;
+        report.readings += readings.len();
+    }
+
+    Ok(report)
+}
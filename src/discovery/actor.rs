@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::scan::notify_discovered_channels;
+
+pub struct DiscoveryActor {
+    pub app_data: AppData,
+    pub check_interval: Duration,
+    /// How far back the readings are searched
+    pub lookback: Duration,
+}
+
+impl DiscoveryActor {
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let contacter = self.app_data.contacter.clone();
+        let sensor_pool = self.app_data.sensor_pool.clone();
+        let lookback = chrono::Duration::from_std(self.lookback).unwrap();
+        let connection = match self.app_data.pool.get() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error in connection pool: {}", err);
+                return
+            },
+        };
+
+        let res = async move {
+            match notify_discovered_channels(&contacter, &connection, &sensor_pool, Utc::now().naive_utc(), lookback).await {
+                Ok(0) => {},
+                Ok(count) => info!("Notified {} discovered channels", count),
+                Err(err) => error!("Error during channel discovery: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for DiscoveryActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the discovery actor");
+
+        IntervalFunc::new(self.check_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for DiscoveryActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the discovery actor");
+    }
+}
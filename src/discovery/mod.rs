@@ -0,0 +1,5 @@
+mod actor;
+mod scan;
+
+pub use actor::DiscoveryActor;
+pub use scan::{discovery_mutation, notify_discovered_channels};
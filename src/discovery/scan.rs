@@ -0,0 +1,129 @@
+//! Periodic search of the cnr ids that produce readings in the configured sites but that aren't
+//! mapped to any channel yet, the admins receive the mutation that creates them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::contact::{Contacter, DiscoveryData};
+use crate::models::IdType;
+use crate::web::cnr_orphans::{find_orphans, load_cnr_activity, load_mapped_ids};
+
+/// Builds the createDiscoveredChannels mutation that creates the given (sensor, channel) cnr ids
+pub fn discovery_mutation(site_id: IdType, channels: &[(String, String)]) -> String {
+    let quote = |x: &str| serde_json::to_string(x).unwrap();
+    let channels: Vec<String> = channels.iter()
+        .map(|(sensor, channel)| format!("{{ sensorIdCnr: {}, channelIdCnr: {} }}", quote(sensor), quote(channel)))
+        .collect();
+    format!(
+        "mutation {{ createDiscoveredChannels(siteId: {}, channels: [{}]) {{ id name }} }}",
+        site_id, channels.join(", ")
+    )
+}
+
+/// Notifies the admins of the channels that had readings in the last lookback but that aren't
+/// configured, only the sites with a cnr id are searched and every channel is notified once.
+/// Returns the number of channels notified.
+pub async fn notify_discovered_channels(contacter: &Contacter, conn: &PgConnection, sensor_pool: &mysql::Pool, now: NaiveDateTime, lookback: Duration) -> Result<usize, String> {
+    use crate::schema::{
+        discovered_channel::dsl as discovered_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let sites: HashMap<String, (IdType, Option<String>)> = site_dsl::site
+        .filter(site_dsl::id_cnr.is_not_null())
+        .filter(site_dsl::deleted_at.is_null())
+        .select((site_dsl::id, site_dsl::id_cnr, site_dsl::name))
+        .load::<(IdType, Option<String>, Option<String>)>(conn)
+        .map_err(|x| x.to_string())?
+        .into_iter()
+        .filter_map(|(id, id_cnr, name)| id_cnr.map(|x| (x, (id, name))))
+        .collect();
+    if sites.is_empty() {
+        return Ok(0)
+    }
+
+    let (sensors, channels) = load_mapped_ids(conn, false).map_err(|x| x.to_string())?;
+    let activity = load_cnr_activity(sensor_pool, now - lookback).map_err(|x| x.to_string())?;
+    let report = find_orphans(&sensors, &channels, activity);
+
+    let notified: HashSet<(IdType, String, String)> = discovered_dsl::discovered_channel
+        .select((discovered_dsl::site_id, discovered_dsl::sensor_id_cnr, discovered_dsl::channel_id_cnr))
+        .load::<(IdType, String, String)>(conn)
+        .map_err(|x| x.to_string())?
+        .into_iter()
+        .collect();
+
+    // Grouped by site id, the activity is already sorted by sensor and channel
+    let mut discovered: BTreeMap<IdType, Vec<(String, String, NaiveDateTime)>> = BTreeMap::new();
+    for x in report.unmapped {
+        let site_id = match sites.get(&x.site_id_cnr) {
+            Some((id, _)) => *id,
+            None => continue,
+        };
+        if notified.contains(&(site_id, x.sensor_id_cnr.clone(), x.channel_id_cnr.clone())) {
+            continue
+        }
+        discovered.entry(site_id).or_default().push((x.sensor_id_cnr, x.channel_id_cnr, x.last_reading_at));
+    }
+
+    let site_names: HashMap<IdType, String> = sites.into_iter()
+        .map(|(_, (id, name))| (id, name.unwrap_or_else(|| "?".to_string())))
+        .collect();
+
+    let mut count = 0;
+    for (site_id, found) in discovered {
+        let ids: Vec<(String, String)> = found.iter()
+            .map(|(sensor, channel, _)| (sensor.clone(), channel.clone()))
+            .collect();
+        let data = DiscoveryData {
+            site_id,
+            site_name: site_names.get(&site_id).cloned().unwrap_or_else(|| "?".to_string()),
+            mutation: discovery_mutation(site_id, &ids),
+            channels: ids,
+        };
+        if let Err(e) = contacter.send_discovery(conn, &data).await {
+            warn!("Cannot notify the channels discovered in site {}: {}", site_id, e);
+            continue
+        }
+
+        let rows: Vec<_> = found.into_iter()
+            .map(|(sensor, channel, last_reading_at)| (
+                discovered_dsl::site_id.eq(site_id),
+                discovered_dsl::sensor_id_cnr.eq(sensor),
+                discovered_dsl::channel_id_cnr.eq(channel),
+                discovered_dsl::last_reading_at.eq(last_reading_at),
+                discovered_dsl::notified_at.eq(now),
+            ))
+            .collect();
+        count += rows.len();
+        diesel::insert_into(discovered_dsl::discovered_channel)
+            .values(rows)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .map_err(|x| x.to_string())?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_mutation() {
+        let channels = vec![
+            ("S1".to_string(), "1".to_string()),
+            ("S\"2".to_string(), "2".to_string()),
+        ];
+        assert_eq!(
+            discovery_mutation(3, &channels),
+            "mutation { createDiscoveredChannels(siteId: 3, channels: [\
+            { sensorIdCnr: \"S1\", channelIdCnr: \"1\" }, { sensorIdCnr: \"S\\\"2\", channelIdCnr: \"2\" }\
+            ]) { id name } }"
+        );
+    }
+}
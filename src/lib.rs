@@ -5,6 +5,8 @@
 extern crate dotenv;
 #[macro_use]
 extern crate juniper;
+#[macro_use]
+extern crate lazy_static;
 
 use std::sync::Arc;
 
@@ -12,22 +14,38 @@
 use diesel::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
+use log::warn;
 
 pub use web::api_service;
 pub use web::quota;
 
 use crate::models::PermissionType;
 use crate::web::errors::ServiceResult;
-use crate::web::graphql_schema::{create_schema, Schema};
+use crate::web::graphql_schema::{AdminSchema, create_admin_schema, create_schema, Schema};
 
 pub mod alarm;
+pub mod anomaly;
+pub mod calibration;
+pub mod cli;
+pub mod config;
 pub mod contact;
+pub mod demo;
+pub mod discovery;
+pub mod modbus;
 pub mod web;
 pub mod schema;
 pub mod schema_sensor;
 pub mod models;
+pub mod mqtt;
 pub mod models_sensor;
+pub mod redact;
+pub mod report;
+pub mod secrets;
 pub mod security;
+pub mod siem;
+pub mod trash;
+pub mod tuning;
+pub mod warmup;
 
 
 embed_migrations!();
@@ -37,9 +55,44 @@ pub struct AppData {
     pub pool: models::Pool,
     pub sensor_pool: mysql::Pool,
     pub graphql_schema: Arc<Schema>,
+    /// Schema of /api/admin/graphql
+    pub admin_graphql_schema: Arc<AdminSchema>,
     pub auth_cache: security::AuthCache,
     pub contacter: contact::Contacter,
-    pub quota_bank: Option<web::quota::AppData>,
+    pub quota_bank: Option<web::quota::QuotaBank>,
+    pub quota_costs: web::quota::QuotaCostConfig,
+    pub quota_warning: web::quota::QuotaWarningConfig,
+    pub alarm_metrics: alarm::AlarmMetrics,
+    /// Fraction of the GraphQL requests recorded in the request log (between 0 and 1)
+    pub request_log_sample_rate: f64,
+    /// Key of the integration secrets, None if they're disabled
+    pub secret_box: Option<secrets::SecretBox>,
+    /// Networks allowed to run the admin operations, None if they aren't restricted
+    pub admin_network: Option<web::admin_network::AdminNetworkPolicy>,
+    /// Limit of the alarms of a channel before it's marked as flapping, None if it's disabled
+    pub flapping: Option<alarm::FlappingPolicy>,
+    /// Window to confirm the destructive admin actions, None if they don't need a second admin
+    pub approvals: Option<web::approval::ApprovalPolicy>,
+    /// Where the audit log entries and the alarm events are forwarded
+    pub siem: siem::SiemSink,
+    /// Tracks the clients with repeated authorization failures
+    pub access_monitor: web::access_monitor::AccessMonitor,
+    /// Errors given for the hidden resources and the forbidden actions
+    pub access_policy: web::policy::AccessPolicy,
+    /// Where and when the big exports are written in the background
+    pub export_jobs: web::export_job::ExportJobConfig,
+    /// Where the site map images are kept
+    pub site_maps: Arc<dyn web::map_storage::MapStorage>,
+    /// Limits of the uploaded site maps
+    pub map_images: web::site_map_service::MapImageConfig,
+    /// Past channel readings already loaded from the sensor database
+    pub readings_cache: web::readings_cache::ReadingsCache,
+    /// Caching of the GraphQL queries sent with GET and their persisted queries
+    pub graphql_cache: web::graphql_cache::GraphQLCache,
+    /// Query limits and batch sizes adjustable per deployment
+    pub tuning: tuning::TuningConfig,
+    /// Enables the helpers for the client developers (ex. sampleReadings), never set in production
+    pub dev_mode: bool,
 }
 
 impl AppData {
@@ -48,7 +101,7 @@ pub fn new(
         database_url: String,
         sensor_database_url: String,
         contacter: contact::Contacter,
-        quota_bank: Option<web::quota::AppData>
+        quota_bank: Option<web::quota::QuotaBank>
     ) -> Self {
         let pool = {
             let manager = ConnectionManager::<PgConnection>::new(database_url);
@@ -61,7 +114,26 @@ pub fn new(
         AppData {
             pool, sensor_pool, contacter, quota_bank,
             graphql_schema: Arc::new(create_schema()),
+            admin_graphql_schema: Arc::new(create_admin_schema()),
             auth_cache: security::AuthCache::new(password_secret_key),
+            alarm_metrics: alarm::AlarmMetrics::new(),
+            quota_costs: web::quota::QuotaCostConfig::default(),
+            quota_warning: web::quota::QuotaWarningConfig::default(),
+            request_log_sample_rate: 1.0,
+            secret_box: None,
+            admin_network: None,
+            flapping: None,
+            approvals: None,
+            siem: siem::SiemSink::default(),
+            access_monitor: web::access_monitor::AccessMonitor::new(Default::default()),
+            access_policy: web::policy::AccessPolicy::default(),
+            export_jobs: web::export_job::ExportJobConfig::default(),
+            site_maps: Arc::new(web::map_storage::FilesystemStorage::default()),
+            map_images: web::site_map_service::MapImageConfig::default(),
+            readings_cache: web::readings_cache::ReadingsCache::default(),
+            graphql_cache: web::graphql_cache::GraphQLCache::default(),
+            tuning: tuning::TuningConfig::default(),
+            dev_mode: false,
         }
     }
 
@@ -71,6 +143,21 @@ pub fn setup_migrations(&self) -> ServiceResult<()> {
         Ok(())
     }
 
+    /// Prepares the site maps storage (ex. creates the directory) and checks that the maps can be
+    /// written, a low free space is only logged as it can be freed while the server runs.
+    pub fn setup_storage(&self) -> Result<(), String> {
+        self.site_maps.setup()?;
+
+        let status = self.site_maps.check();
+        if !status.writable {
+            return Err(format!("Invalid site maps storage {}: {}", status.directory, status.error.unwrap_or_default()))
+        }
+        if !status.healthy() {
+            warn!("Low free space for the site maps in {}: {:?} bytes", status.directory, status.free_bytes);
+        }
+        Ok(())
+    }
+
     pub fn setup_root_password(&self, password: String, replace: bool) -> ServiceResult<()> {
         use crate::schema::user_account::dsl;
         use crate::models::User;
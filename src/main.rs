@@ -1,12 +1,33 @@
 use actix::prelude::*;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_identity::IdentityService;
 use actix_web::{App, HttpServer, middleware, web};
+use log::{info, warn};
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
 
 use oldmusa_server::*;
 use std::time::Duration;
 
-fn expect_env_var(name: &str) -> String {
-    std::env::var(name).unwrap_or_else(|_| panic!("{} must be set", name))
+fn init_quota_bank(config: &Option<config::QuotaConfig>) -> Option<quota::QuotaBank> {
+    let config = match config {
+        Some(x) => x,
+        None => {
+            warn!("Request quota is disabled");
+            return None
+        },
+    };
+
+    let read = quota::init(config.read_max_balance, config.read_refill_rate);
+    let write = quota::init(config.write_max_balance, config.write_refill_rate);
+    Some(quota::QuotaBank::new(read, write))
+}
+
+/// Loads the certificate served by the server, the files are only read at startup
+fn init_tls(config: &config::TlsConfig) -> std::io::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&config.key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.cert_file)?;
+    builder.check_private_key()?;
+    Ok(builder)
 }
 
 #[actix_rt::main]
@@ -14,53 +35,244 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     env_logger::init();
 
-    let database_url = expect_env_var("DATABASE_URL");
-    let sensor_database_url = expect_env_var("SENSOR_DATABASE_URL");
-    let cookie_secret_key = expect_env_var("COOKIE_SECRET_KEY");
-    let password_secret_key = expect_env_var("PASSWORD_SECRET_KEY");
-
-    let root_default_password = expect_env_var("ROOT_DEFAULT_PASSWORD");
-    let root_password_override = std::env::var("ROOT_PASSWORD_OVERRIDE").map(|x| !x.is_empty()).unwrap_or(false);
+    let command = cli::parse_command(std::env::args()).unwrap_or_else(|e| e.exit());
 
-    let quota_bank = quota::init(10000, 10);
+    let config = config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+    let cookie_secret_key = config.cookie_secret_key.clone();
+    let quota_bank = init_quota_bank(&config.quota);
 
     // create db connection pool
-    let data = AppData::new(
-        password_secret_key,
-        database_url,
-        sensor_database_url,
-        contact::Contacter::new_from_env(),
-        Some(quota_bank)
+    let mut data = AppData::new(
+        config.password_secret_key.clone(),
+        config.database_url.clone(),
+        config.sensor_database_url.clone(),
+        contact::Contacter::from_config(config.fcm_api_key.clone(), config.email.clone(), config.webhooks.clone()),
+        quota_bank
     );
-    let domain: String = std::env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    data.quota_costs = config.quota_costs.clone();
+    data.quota_warning = config.quota_warning.clone();
+    data.request_log_sample_rate = config.request_log_sample_rate;
+    data.dev_mode = config.dev_mode;
+    if data.dev_mode {
+        warn!("Development mode enabled, don't use it in production");
+    }
+    data.secret_box = config.secrets_key.map(secrets::SecretBox::new);
+    data.admin_network = config.admin_network.clone();
+    if let Some(siem_config) = config.siem.clone() {
+        data.siem = siem::SiemSink::new(siem_config);
+    }
+    data.flapping = config.flapping.clone();
+    data.approvals = config.approvals.clone();
+    data.access_monitor = oldmusa_server::web::access_monitor::AccessMonitor::new(config.access_monitor.clone());
+    data.access_policy = config.access_policy.clone();
+    data.export_jobs = config.export_jobs.clone();
+    data.site_maps = oldmusa_server::web::map_storage::open(config.site_maps.clone());
+    data.map_images = config.map_images.clone();
+    data.readings_cache = oldmusa_server::web::readings_cache::ReadingsCache::new(config.readings_cache.clone());
+    data.graphql_cache = oldmusa_server::web::graphql_cache::GraphQLCache::new(config.graphql_cache.clone());
+    data.tuning = config.tuning.clone();
+    data.auth_cache.login_throttle = oldmusa_server::web::login_throttle::LoginThrottle::new(config.login_throttle.clone());
+    if data.secret_box.is_none() {
+        warn!("No SECRETS_KEY found, the integration credentials can only be set from the environment");
+    }
+    let identity_cookie = config.identity_cookie.clone();
+    if !config.cors.allowed_origins.is_empty() && config.cors.allow_credentials && identity_cookie.same_site != Some(actix_web::cookie::SameSite::None) {
+        info!("The auth cookie is only sent by the allowed origins on the same site, the others need COOKIE_SAME_SITE=none");
+    }
 
     data.setup_migrations().unwrap();
-    data.setup_root_password(root_default_password, root_password_override).unwrap();
+    if command == cli::Command::RunMigrations {
+        println!("Migrations applied");
+        return Ok(())
+    }
+    if let Some(bank) = data.quota_bank.as_ref() {
+        let conn = data.pool.get().expect("Cannot connect to the database");
+        match bank.restore_snapshot(&conn, chrono::Utc::now().naive_utc()) {
+            Ok(0) => {},
+            Ok(n) => info!("Restored {} quota balances", n),
+            Err(e) => warn!("Cannot restore the quota balances: {}", e),
+        }
+    }
+    data.setup_storage().unwrap_or_else(|e| panic!("{}", e));
+    data.setup_root_password(config.root_default_password.clone(), config.root_password_override).unwrap();
+    secrets::apply_secrets(&data).expect("Cannot load the integration secrets");
 
-    let actor = alarm::AlarmActor {
-        app_data: data.clone(),
-        sleep_interval: Duration::from_secs(expect_env_var("MEASURE_CONTROL_SLEEP_TIME").parse().expect("Cannot parse MEASURE_CONTROL_SLEEP_TIME"))
-    };
-    actor.start();
+    match command {
+        cli::Command::Serve | cli::Command::RunMigrations => {},
+        cli::Command::CreateUser { username, password, permission } => {
+            let password = password.map_or_else(cli::read_password, Ok)?;
+            let user = cli::create_user(&data, username, password, permission)
+                .unwrap_or_else(|e| panic!("Cannot create the user: {}", e));
+            println!("User {} created with id {}", user.username, user.id);
+            return Ok(())
+        },
+        cli::Command::ResetPassword { username, password } => {
+            let password = password.map_or_else(cli::read_password, Ok)?;
+            cli::reset_password(&data, &username, password)
+                .unwrap_or_else(|e| panic!("Cannot reset the password: {}", e));
+            println!("Password of {} changed", username);
+            return Ok(())
+        },
+        cli::Command::CheckSensors => {
+            match cli::check_sensors(&data) {
+                Ok(Some(last)) => println!("Databases reachable, last reading at {}", last),
+                Ok(None) => println!("Databases reachable, the sensor database has no readings"),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+            return Ok(())
+        },
+        cli::Command::SeedDemo { days } => {
+            let report = demo::seed_demo(&data, &data.sensor_pool, chrono::Utc::now().naive_utc(), days)
+                .unwrap_or_else(|e| panic!("Cannot seed the demo museum: {}", e));
+            println!(
+                "Demo museum created: sites {:?}, {} sensors, {} channels, {} readings",
+                report.site_ids, report.sensors, report.channels, report.readings
+            );
+            return Ok(())
+        },
+    }
+
+    warmup::warm_up(&data, &config.warmup, chrono::Utc::now().naive_utc());
+
+    let actor = alarm::AlarmActor::new(
+        data.clone(),
+        config.alarm_check_interval,
+        config.escalation_check_interval,
+    );
+    let alarm_actor = Supervisor::start(move |_| actor);
+
+    // MQTT is optional, it's only needed for the sensors that publish their readings to a broker
+    match config.mqtt.clone() {
+        Some(mqtt_config) => {
+            let actor = mqtt::MqttActor::new(
+                data.clone(),
+                mqtt_config.broker,
+                mqtt_config.flush_interval,
+                alarm_actor.clone(),
+            );
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No MQTT_BROKER_URL found, disabling MQTT ingestion"),
+    }
+
+    // Modbus polling is optional, it's only needed for devices outside the CNR acquisition chain
+    match config.modbus_poll_interval {
+        Some(poll_interval) => {
+            let actor = modbus::ModbusActor {
+                app_data: data.clone(),
+                poll_interval,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No MODBUS_POLL_INTERVAL found, disabling modbus polling"),
+    }
+
+    match config.anomaly_scan_interval {
+        Some(scan_interval) => {
+            let actor = anomaly::AnomalyActor {
+                app_data: data.clone(),
+                scan_interval,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No ANOMALY_SCAN_INTERVAL found, disabling anomaly detection"),
+    }
+
+    match config.calibration_check_interval {
+        Some(check_interval) => {
+            let actor = calibration::CalibrationActor {
+                app_data: data.clone(),
+                check_interval,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No CALIBRATION_CHECK_INTERVAL found, disabling calibration reminders"),
+    }
+
+    match config.discovery_check_interval {
+        Some(check_interval) => {
+            let actor = discovery::DiscoveryActor {
+                app_data: data.clone(),
+                check_interval,
+                lookback: config.discovery_lookback,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No DISCOVERY_CHECK_INTERVAL found, disabling channel discovery"),
+    }
+
+    match config.report_check_interval {
+        Some(check_interval) => {
+            let actor = report::ReportActor {
+                app_data: data.clone(),
+                check_interval,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No REPORT_CHECK_INTERVAL found, disabling site reports"),
+    }
+
+    // Without the purge the deleted entities stay in the trash until they're restored
+    match config.trash_purge_interval {
+        Some(purge_interval) => {
+            let actor = trash::TrashActor {
+                app_data: data.clone(),
+                purge_interval,
+                retention: config.trash_retention,
+            };
+            Supervisor::start(move |_| actor);
+        },
+        None => warn!("No TRASH_PURGE_INTERVAL found, disabling trash purge"),
+    }
+
+    let shutdown_timeout = config.shutdown_timeout;
+    let app_data = data.clone();
+    let cors_config = config.cors.clone();
 
     // Start http server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .data(data.clone())
-            .wrap(IdentityService::new(
-                // <- create identity middleware
-                CookieIdentityPolicy::new(cookie_secret_key.as_bytes())    // <- create cookie identity policy
-                    .name("auth-cookie")
-                    .domain(domain.as_str())
-                    .secure(false)))
+            .wrap(IdentityService::new(identity_cookie.policy(cookie_secret_key.as_bytes())))
             // enable logger
             .wrap(middleware::Logger::default())
+            .wrap(oldmusa_server::web::cors::cors_middleware(&cors_config))
             // limit the maximum amount of data that server will accept
             .data(web::JsonConfig::default().limit(4096))
             .configure(api_service::config)
             .service(web::resource("/stest").route(web::get().to(test_sensor)))
-    })
-        .bind("0.0.0.0:8080")?
+    });
+    let server = match &config.tls {
+        Some(tls) => {
+            info!("Serving HTTPS with the certificate {}", tls.cert_file.display());
+            server.bind_openssl(&config.bind_address, init_tls(tls)?)?
+        },
+        None => server.bind(&config.bind_address)?,
+    };
+    server
+        // On SIGTERM the server stops accepting connections and waits for the running requests
+        .shutdown_timeout(shutdown_timeout)
         .run()
-        .await
+        .await?;
+
+    info!("HTTP server stopped, waiting for the running alarm check");
+    match actix_rt::time::timeout(Duration::from_secs(shutdown_timeout), alarm_actor.send(alarm::Drain)).await {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => warn!("Cannot drain the alarm actor: {}", e),
+        Err(_) => warn!("The alarm check did not finish in {} seconds", shutdown_timeout),
+    }
+
+    if let Some(bank) = app_data.quota_bank.as_ref() {
+        let saved = app_data.pool.get()
+            .map_err(|x| x.to_string())
+            .and_then(|conn| bank.save_snapshot(&conn, chrono::Utc::now().naive_utc()).map_err(|x| x.to_string()));
+        match saved {
+            Ok(n) => info!("Saved {} quota balances", n),
+            Err(e) => warn!("Cannot save the quota balances: {}", e),
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::web;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::poller::poll_registers;
+
+/// Timeout of every network operation with a single device
+const MODBUS_DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ModbusActor {
+    pub app_data: AppData,
+    pub poll_interval: Duration,
+}
+
+impl ModbusActor {
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let start = Instant::now();
+        let pool = self.app_data.pool.clone();
+        let sensor_pool = self.app_data.sensor_pool.clone();
+        let readings_cache = self.app_data.readings_cache.clone();
+
+        // The modbus client is blocking, keep it out of the actor thread
+        let res = async move {
+            match web::block(move || poll_registers(&pool, &sensor_pool, &readings_cache, MODBUS_DEVICE_TIMEOUT)).await {
+                Ok(count) => info!("Polled {} modbus registers in {}ms", count, start.elapsed().as_millis()),
+                Err(err) => error!("Error during modbus polling: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for ModbusActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the modbus actor");
+
+        IntervalFunc::new(self.poll_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for ModbusActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the modbus actor");
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use derive_more::Display;
+
+const MODBUS_PROTOCOL_ID: u16 = 0;
+const FUNCTION_READ_HOLDING_REGISTERS: u8 = 0x03;
+const FUNCTION_READ_INPUT_REGISTERS: u8 = 0x04;
+const EXCEPTION_FLAG: u8 = 0x80;
+/// Maximum number of registers that can be read with a single request
+pub const MAX_READ_REGISTERS: u16 = 125;
+
+#[derive(Debug, Display)]
+pub enum ModbusError {
+    #[display(fmt = "IO error: {}", _0)]
+    Io(std::io::Error),
+
+    #[display(fmt = "Modbus exception {}", _0)]
+    Exception(u8),
+
+    #[display(fmt = "Invalid response: {}", _0)]
+    InvalidResponse(&'static str),
+}
+
+impl From<std::io::Error> for ModbusError {
+    fn from(x: std::io::Error) -> Self {
+        ModbusError::Io(x)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum ModbusRegisterType {
+    Holding,
+    Input,
+}
+
+impl ModbusRegisterType {
+    pub fn from_char(name: &str) -> Option<ModbusRegisterType> {
+        match name {
+            "h" => Some(ModbusRegisterType::Holding),
+            "i" => Some(ModbusRegisterType::Input),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            ModbusRegisterType::Holding => "h",
+            ModbusRegisterType::Input => "i",
+        }
+    }
+
+    fn function_code(self) -> u8 {
+        match self {
+            ModbusRegisterType::Holding => FUNCTION_READ_HOLDING_REGISTERS,
+            ModbusRegisterType::Input => FUNCTION_READ_INPUT_REGISTERS,
+        }
+    }
+}
+
+/// How the raw registers are decoded into a value
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum ModbusValueType {
+    /// A single unsigned register
+    U16,
+    /// A single signed register
+    I16,
+    /// Two registers holding a big-endian IEEE 754 float (high word first)
+    F32,
+}
+
+impl ModbusValueType {
+    pub fn from_char(name: &str) -> Option<ModbusValueType> {
+        match name {
+            "u" => Some(ModbusValueType::U16),
+            "s" => Some(ModbusValueType::I16),
+            "f" => Some(ModbusValueType::F32),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            ModbusValueType::U16 => "u",
+            ModbusValueType::I16 => "s",
+            ModbusValueType::F32 => "f",
+        }
+    }
+
+    pub fn register_count(self) -> u16 {
+        match self {
+            ModbusValueType::U16 | ModbusValueType::I16 => 1,
+            ModbusValueType::F32 => 2,
+        }
+    }
+
+    pub fn decode(self, registers: &[u16]) -> Option<f64> {
+        match self {
+            ModbusValueType::U16 => registers.first().map(|x| *x as f64),
+            ModbusValueType::I16 => registers.first().map(|x| *x as i16 as f64),
+            ModbusValueType::F32 => {
+                if registers.len() < 2 {
+                    return None
+                }
+                let bits = ((registers[0] as u32) << 16) | registers[1] as u32;
+                Some(f32::from_bits(bits) as f64)
+            },
+        }
+    }
+}
+
+/// Minimal blocking Modbus/TCP client, only the register reading functions are supported.
+pub struct ModbusClient {
+    stream: TcpStream,
+    transaction_id: u16,
+}
+
+impl ModbusClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<ModbusClient, ModbusError> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or(ModbusError::InvalidResponse("cannot resolve address"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        Ok(ModbusClient {
+            stream,
+            transaction_id: 0,
+        })
+    }
+
+    pub fn read_registers(&mut self, unit_id: u8, register_type: ModbusRegisterType, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        if count == 0 || count > MAX_READ_REGISTERS {
+            return Err(ModbusError::InvalidResponse("invalid register count"))
+        }
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+
+        let request = encode_read_request(self.transaction_id, unit_id, register_type.function_code(), address, count);
+        self.stream.write_all(&request)?;
+
+        let mut header = [0u8; 7];
+        self.stream.read_exact(&mut header)?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if length < 2 {
+            return Err(ModbusError::InvalidResponse("frame too short"))
+        }
+        let mut pdu = vec![0u8; length - 1];
+        self.stream.read_exact(&mut pdu)?;
+
+        let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+        if transaction_id != self.transaction_id {
+            return Err(ModbusError::InvalidResponse("transaction id mismatch"))
+        }
+
+        decode_read_response(register_type.function_code(), count, &pdu)
+    }
+}
+
+fn encode_read_request(transaction_id: u16, unit_id: u8, function: u8, address: u16, count: u16) -> [u8; 12] {
+    let mut frame = [0u8; 12];
+    frame[0..2].copy_from_slice(&transaction_id.to_be_bytes());
+    frame[2..4].copy_from_slice(&MODBUS_PROTOCOL_ID.to_be_bytes());
+    frame[4..6].copy_from_slice(&6u16.to_be_bytes());// unit id + pdu
+    frame[6] = unit_id;
+    frame[7] = function;
+    frame[8..10].copy_from_slice(&address.to_be_bytes());
+    frame[10..12].copy_from_slice(&count.to_be_bytes());
+    frame
+}
+
+/// Decodes the PDU of a read response (the MBAP header must be already stripped)
+fn decode_read_response(function: u8, count: u16, pdu: &[u8]) -> Result<Vec<u16>, ModbusError> {
+    match pdu.first() {
+        Some(x) if *x == function | EXCEPTION_FLAG => {
+            return Err(ModbusError::Exception(pdu.get(1).copied().unwrap_or(0)))
+        },
+        Some(x) if *x == function => {},
+        _ => return Err(ModbusError::InvalidResponse("unexpected function code")),
+    }
+
+    let byte_count = *pdu.get(1).ok_or(ModbusError::InvalidResponse("missing byte count"))? as usize;
+    if byte_count != count as usize * 2 || pdu.len() < 2 + byte_count {
+        return Err(ModbusError::InvalidResponse("wrong byte count"))
+    }
+
+    Ok(pdu[2..2 + byte_count].chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let request = encode_read_request(0x0102, 7, FUNCTION_READ_HOLDING_REGISTERS, 0x0010, 2);
+        assert_eq!(request, [0x01, 0x02, 0, 0, 0, 6, 7, 0x03, 0x00, 0x10, 0x00, 0x02]);
+
+        let registers = decode_read_response(FUNCTION_READ_HOLDING_REGISTERS, 2, &[0x03, 4, 0x41, 0xC8, 0x00, 0x00]).unwrap();
+        assert_eq!(registers, vec![0x41C8, 0x0000]);
+        assert_eq!(ModbusValueType::F32.decode(&registers), Some(25.0));
+        assert_eq!(ModbusValueType::I16.decode(&[0xFFFF]), Some(-1.0));
+        assert_eq!(ModbusValueType::U16.decode(&[0xFFFF]), Some(65535.0));
+
+        match decode_read_response(FUNCTION_READ_INPUT_REGISTERS, 1, &[0x84, 0x02]) {
+            Err(ModbusError::Exception(2)) => {},
+            x => panic!("Unexpected result {:?}", x),
+        }
+        assert!(decode_read_response(FUNCTION_READ_INPUT_REGISTERS, 2, &[0x04, 2, 0x00, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,7 @@
+mod actor;
+mod client;
+mod poller;
+
+pub use actor::ModbusActor;
+pub use client::{ModbusClient, ModbusError, ModbusRegisterType, ModbusValueType};
+pub use poller::poll_registers;
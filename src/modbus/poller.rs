@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::alarm::{NewReading, ReadingsWriter};
+use crate::models::{IdType, ModbusRegister, Pool};
+use crate::web::db_helper::query_channel_cnr_ids;
+use crate::web::readings_cache::ReadingsCache;
+
+use super::client::{ModbusClient, ModbusRegisterType, ModbusValueType};
+
+/// Station id used for the readings acquired through Modbus
+const MODBUS_STATION_ID: &str = "modbus";
+
+struct RegisterTarget {
+    register: ModbusRegister,
+    channel_id_cnr: Option<String>,
+    measure_unit: Option<String>,
+}
+
+/// Reads every configured register and writes the (scaled) values in the readings store.
+/// The registers are grouped by device so that every device is only contacted once, the cached
+/// buckets that contain the new readings are dropped.
+/// Returns the number of readings written.
+pub fn poll_registers<W: ReadingsWriter>(pool: &Pool, writer: &W, cache: &ReadingsCache, timeout: Duration) -> Result<usize, String> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        modbus_register::dsl as modbus_dsl,
+    };
+
+    let targets = {
+        let conn = pool.get().map_err(|x| x.to_string())?;
+        modbus_dsl::modbus_register.inner_join(channel_dsl::channel)
+            .filter(channel_dsl::deleted_at.is_null())
+            .select((modbus_dsl::modbus_register::all_columns(), channel_dsl::id_cnr, channel_dsl::measure_unit))
+            .load::<(ModbusRegister, Option<String>, Option<String>)>(&conn)
+            .map_err(|x| x.to_string())?
+    };
+
+    let mut devices: HashMap<(String, i32), Vec<RegisterTarget>> = HashMap::new();
+    for (register, channel_id_cnr, measure_unit) in targets {
+        devices.entry((register.host.clone(), register.port))
+            .or_default()
+            .push(RegisterTarget { register, channel_id_cnr, measure_unit });
+    }
+
+    let now = Utc::now().naive_utc();
+    let mut readings = Vec::new();
+
+    for ((host, port), targets) in devices {
+        let port = match u16::try_from(port) {
+            Ok(x) => x,
+            Err(_) => {
+                warn!("Invalid modbus port {} for {}", port, host);
+                continue
+            }
+        };
+        let mut client = match ModbusClient::connect((host.as_str(), port), timeout) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Cannot connect to modbus device {}:{}: {}", host, port, err);
+                continue
+            }
+        };
+
+        for target in targets {
+            match read_target(&mut client, pool, &target) {
+                Ok(Some((site_id, sensor_id, channel_id, value))) => readings.push(NewReading {
+                    site_id,
+                    room_id: "".to_string(),
+                    station_id: MODBUS_STATION_ID.to_string(),
+                    sensor_id,
+                    channel_id,
+                    value,
+                    measure_unit: target.measure_unit.clone().unwrap_or_default(),
+                    date: now,
+                }),
+                Ok(None) => {},
+                Err(err) => warn!("Cannot read modbus register of channel {}: {}", target.register.channel_id, err),
+            }
+        }
+    }
+
+    writer.insert_readings(&readings).map_err(|x| x.to_string())?;
+    cache.invalidate(&readings, now);
+    Ok(readings.len())
+}
+
+/// Reads a single register, returns None if the channel cannot be mapped to the readings store
+fn read_target(client: &mut ModbusClient, pool: &Pool, target: &RegisterTarget) -> Result<Option<(String, String, String, f64)>, String> {
+    let register = &target.register;
+    let channel_id: IdType = register.channel_id;
+
+    let ids = query_channel_cnr_ids(pool, channel_id, target.channel_id_cnr.as_deref())
+        .map_err(|x| x.to_string())?;
+    let (site_id, sensor_id, channel_id) = match ids {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let register_type = ModbusRegisterType::from_char(&register.register_type)
+        .ok_or_else(|| format!("Invalid register type '{}'", register.register_type))?;
+    let value_type = ModbusValueType::from_char(&register.value_type)
+        .ok_or_else(|| format!("Invalid value type '{}'", register.value_type))?;
+    let unit_id = u8::try_from(register.unit_id).map_err(|_| "Invalid unit id".to_string())?;
+    let address = u16::try_from(register.address).map_err(|_| "Invalid address".to_string())?;
+
+    let registers = client.read_registers(unit_id, register_type, address, value_type.register_count())
+        .map_err(|x| x.to_string())?;
+    let value = value_type.decode(&registers)
+        .ok_or_else(|| "Not enough registers".to_string())?;
+
+    Ok(Some((site_id, sensor_id, channel_id, value * register.scale + register.value_offset)))
+}
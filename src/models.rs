@@ -1,6 +1,14 @@
+use std::io::Write;
+
 use bigdecimal::BigDecimal;
 use derive_more::Display;
-use diesel::{PgConnection, r2d2::ConnectionManager};
+use diesel::{
+    deserialize::{self, FromSql},
+    pg::Pg,
+    PgConnection,
+    r2d2::ConnectionManager,
+    serialize::{self, IsNull, Output, ToSql},
+};
 
 use super::schema::*;
 
@@ -9,25 +17,88 @@
 
 pub type IdType = i32;
 
-#[derive(Debug, Display, juniper::GraphQLEnum, PartialEq)]
+/// Postgres `permission_type` enum
+#[derive(SqlType, QueryId)]
+#[postgres(type_name = "permission_type")]
+pub struct PermissionTypeSql;
+
+#[derive(Clone, Copy, Debug, Display, juniper::GraphQLEnum, PartialEq, AsExpression, FromSqlRow)]
+#[sql_type = "PermissionTypeSql"]
 pub enum PermissionType {
     User,
+    /// Can edit the sensors, the channels and the maps of the sites they have access to
+    SiteManager,
     Admin
 }
 
 impl PermissionType {
-    pub fn from_char(name: &str) -> Option<PermissionType> {
-        match name {
-            "u" => Some(PermissionType::User),
-            "a" => Some(PermissionType::Admin),
-            _ => None,
+    fn db_name(self) -> &'static [u8] {
+        match self {
+            PermissionType::User => b"user",
+            PermissionType::SiteManager => b"site_manager",
+            PermissionType::Admin => b"admin",
         }
     }
+}
+
+impl ToSql<PermissionTypeSql, Pg> for PermissionType {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.db_name())?;
+        Ok(IsNull::No)
+    }
+}
 
-    pub fn to_char(&self) -> &str {
+impl FromSql<PermissionTypeSql, Pg> for PermissionType {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"user" => Ok(PermissionType::User),
+            b"site_manager" => Ok(PermissionType::SiteManager),
+            b"admin" => Ok(PermissionType::Admin),
+            x => Err(format!("Unknown permission type {:?}", String::from_utf8_lossy(x)).into()),
+        }
+    }
+}
+
+/// Postgres `access_level` enum
+#[derive(SqlType, QueryId)]
+#[postgres(type_name = "access_level")]
+pub struct AccessLevelSql;
+
+/// What a user can do in a site they have access to
+#[derive(Debug, Clone, Copy, Display, juniper::GraphQLEnum, PartialEq, PartialOrd, AsExpression, FromSqlRow)]
+#[sql_type = "AccessLevelSql"]
+pub enum AccessLevel {
+    View,
+    /// Can edit the sensors, the channels and the map of the site
+    Manage,
+    /// Can also edit the site itself and give access to it
+    Admin,
+}
+
+impl AccessLevel {
+    fn db_name(self) -> &'static [u8] {
         match self {
-            PermissionType::User => "u",
-            PermissionType::Admin => "a",
+            AccessLevel::View => b"view",
+            AccessLevel::Manage => b"manage",
+            AccessLevel::Admin => b"admin",
+        }
+    }
+}
+
+impl ToSql<AccessLevelSql, Pg> for AccessLevel {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.db_name())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<AccessLevelSql, Pg> for AccessLevel {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"view" => Ok(AccessLevel::View),
+            b"manage" => Ok(AccessLevel::Manage),
+            b"admin" => Ok(AccessLevel::Admin),
+            x => Err(format!("Unknown access level {:?}", String::from_utf8_lossy(x)).into()),
         }
     }
 }
@@ -39,7 +110,25 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
     pub last_password_change: chrono::NaiveDateTime,
-    pub permission: String,
+    pub permission: PermissionType,
+    pub email: Option<String>,
+    /// Null until the email is verified, the notifications are only emailed after
+    pub email_verified_at: Option<chrono::NaiveDateTime>,
+    /// Hash of the token sent to the email to verify it
+    pub email_token_hash: Option<String>,
+    pub email_token_sent_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Session opened by an identity cookie, the cookie is logged out when it's deleted
+#[derive(Clone, Debug, Queryable)]
+pub struct UserSession {
+    pub id: IdType,
+    pub user_id: IdType,
+    pub secret_hash: String,
+    pub user_agent: Option<String>,
+    pub address: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
 }
 
 #[derive(Debug, Queryable)]
@@ -50,22 +139,313 @@ pub struct Site {
     pub clock: chrono::NaiveDateTime,
     pub image_width: Option<i32>,
     pub image_height: Option<i32>,
+    /// Translated names (locale -> name)
+    pub name_translations: serde_json::Value,
+    /// When the site was moved to the trash, None if it isn't deleted
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    /// Last change of the site, set by the database
+    pub updated_at: chrono::NaiveDateTime,
 }
 pub type SiteAllColumns = (
     site::dsl::id, site::dsl::name, site::dsl::id_cnr, site::dsl::clock, site::dsl::image_width,
-    site::dsl::image_height
+    site::dsl::image_height, site::dsl::name_translations, site::dsl::deleted_at, site::dsl::updated_at
 );
 pub const SITE_ALL_COLUMNS: SiteAllColumns = (
     site::dsl::id, site::dsl::name, site::dsl::id_cnr, site::dsl::clock, site::dsl::image_width,
-    site::dsl::image_height
+    site::dsl::image_height, site::dsl::name_translations, site::dsl::deleted_at, site::dsl::updated_at
+);
+
+
+#[derive(Debug, Queryable)]
+pub struct AlarmEvent {
+    pub id: IdType,
+    pub channel_id: IdType,
+    pub started_at: chrono::NaiveDateTime,
+    pub ended_at: Option<chrono::NaiveDateTime>,
+    pub peak_value: f64,
+    pub extreme_type: String,
+    pub acknowledged_at: Option<chrono::NaiveDateTime>,
+    pub acknowledged_by: Option<IdType>,
+    pub acknowledge_note: Option<String>,
+    /// Last change of the event, set by the database
+    pub updated_at: chrono::NaiveDateTime,
+}
+pub type AlarmEventAllColumns = (
+    alarm_event::dsl::id, alarm_event::dsl::channel_id, alarm_event::dsl::started_at,
+    alarm_event::dsl::ended_at, alarm_event::dsl::peak_value, alarm_event::dsl::extreme_type,
+    alarm_event::dsl::acknowledged_at, alarm_event::dsl::acknowledged_by, alarm_event::dsl::acknowledge_note,
+    alarm_event::dsl::updated_at
+);
+pub const ALARM_EVENT_ALL_COLUMNS: AlarmEventAllColumns = (
+    alarm_event::dsl::id, alarm_event::dsl::channel_id, alarm_event::dsl::started_at,
+    alarm_event::dsl::ended_at, alarm_event::dsl::peak_value, alarm_event::dsl::extreme_type,
+    alarm_event::dsl::acknowledged_at, alarm_event::dsl::acknowledged_by, alarm_event::dsl::acknowledge_note,
+    alarm_event::dsl::updated_at
 );
 
+/// The alarms of the channel aren't notified until muted_until
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[table_name="channel_mute"]
+pub struct ChannelMute {
+    pub channel_id: IdType,
+    pub muted_until: chrono::NaiveDateTime,
+    pub muted_by: Option<IdType>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Export too big to be streamed, its file is written in the background (see the export_job module)
+#[derive(Debug, Queryable)]
+pub struct ExportJob {
+    pub id: IdType,
+    pub user_id: Option<IdType>,
+    pub channel_id: IdType,
+    pub format: String,
+    pub status: String,
+    pub row_count: i64,
+    pub error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub finished_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Export of every channel of some sites as a zip of CSV files (see the bulk_export module)
+#[derive(Debug, Queryable)]
+pub struct BulkExportJob {
+    pub id: IdType,
+    pub user_id: Option<IdType>,
+    pub site_ids: Vec<IdType>,
+    pub range_start: chrono::NaiveDateTime,
+    pub range_end: chrono::NaiveDateTime,
+    pub include_invalid: bool,
+    pub status: String,
+    pub channel_count: i32,
+    pub row_count: i64,
+    pub error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub finished_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Checksum of an exported dataset, so that it can be proven unmodified (see the export_manifest
+/// module). The checksum and the row count are set once the export is complete
+#[derive(Debug, Queryable)]
+pub struct ExportManifest {
+    pub id: IdType,
+    pub user_id: Option<IdType>,
+    pub channel_id: IdType,
+    pub format: String,
+    pub range_start: chrono::NaiveDateTime,
+    pub range_end: chrono::NaiveDateTime,
+    pub include_invalid: bool,
+    pub row_count: Option<i64>,
+    pub sha256: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub completed_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Destructive action waiting for the confirmation of a second admin (see the approval module)
+#[derive(Debug, Queryable)]
+pub struct PendingAction {
+    pub id: IdType,
+    pub action: String,
+    pub target_id: IdType,
+    pub requested_by: IdType,
+    pub requested_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Queryable)]
+pub struct ApiKey {
+    pub id: IdType,
+    pub name: String,
+    pub key_hash: String,
+    pub site_id: Option<IdType>,
+    pub created_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    /// The key can push readings with the ingest endpoint
+    pub can_ingest: bool,
+}
+
+#[derive(Debug, Queryable)]
+pub struct AuditLogEntry {
+    pub id: IdType,
+    pub user_id: Option<IdType>,
+    pub username: String,
+    pub action: String,
+    pub target: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+pub struct IntegrationSecret {
+    pub name: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+pub struct RequestLogEntry {
+    pub id: IdType,
+    pub created_at: chrono::NaiveDateTime,
+    pub user_id: Option<IdType>,
+    pub site_id: Option<IdType>,
+    pub operation_name: Option<String>,
+    pub complexity: i64,
+    pub row_count: i64,
+    pub duration_ms: i32,
+    pub quota_rejected: bool,
+}
+
+#[derive(Debug, Queryable)]
+pub struct ChannelAnomaly {
+    pub id: IdType,
+    pub channel_id: IdType,
+    pub started_at: chrono::NaiveDateTime,
+    pub ended_at: chrono::NaiveDateTime,
+    pub score: f64,
+    pub kind: String,
+}
+
+#[derive(Debug, Queryable)]
+pub struct SiteWebhook {
+    pub id: IdType,
+    pub site_id: IdType,
+    pub url: String,
+    /// Key of the HMAC signature of the payloads
+    pub secret: String,
+    /// Whether the changes of the site status are posted too, besides the alarms
+    pub status_changes: bool,
+}
+
+/// Contacts notified when an alarm isn't acknowledged within delay_minutes (see alarm::escalation)
+#[derive(Debug, Queryable)]
+pub struct EscalationPolicy {
+    pub id: IdType,
+    /// None if the policy applies to every site
+    pub site_id: Option<IdType>,
+    pub delay_minutes: i32,
+    pub target: String,
+    pub user_ids: Vec<IdType>,
+}
+
+#[derive(Debug, Queryable)]
+pub struct MaintenanceWindow {
+    pub id: IdType,
+    pub site_id: IdType,
+    pub start_time: chrono::NaiveDateTime,
+    pub end_time: chrono::NaiveDateTime,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Queryable)]
+pub struct ManualReading {
+    pub id: IdType,
+    pub channel_id: IdType,
+    pub user_id: Option<IdType>,
+    pub taken_at: chrono::NaiveDateTime,
+    pub value: f64,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Queryable)]
+pub struct ModbusRegister {
+    pub channel_id: IdType,
+    pub host: String,
+    pub port: i32,
+    pub unit_id: i32,
+    pub address: i32,
+    pub register_type: String,
+    pub value_type: String,
+    pub scale: f64,
+    pub value_offset: f64,
+}
+
+#[derive(Debug, Queryable)]
+pub struct PeerGroup {
+    pub id: IdType,
+    pub name: String,
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name="peer_group_channel"]
+pub struct PeerGroupChannel {
+    pub peer_group_id: IdType,
+    pub channel_id: IdType,
+}
+
+#[derive(Debug, Queryable)]
+pub struct ReadingAnnotation {
+    pub id: IdType,
+    pub channel_id: IdType,
+    pub start_time: chrono::NaiveDateTime,
+    pub end_time: chrono::NaiveDateTime,
+    pub reason: String,
+}
+
+/// Summary of a site periodically emailed to a user (see the report module)
+#[derive(Debug, Queryable)]
+pub struct ReportSubscription {
+    pub id: IdType,
+    pub user_id: IdType,
+    pub site_id: IdType,
+    pub period: String,
+    pub format: String,
+    /// End of the last period reported, None if no report has been sent yet
+    pub last_period_end: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+pub struct SensorCalibration {
+    pub id: IdType,
+    pub sensor_id: IdType,
+    pub calibrated_at: chrono::NaiveDateTime,
+    pub certificate_id: String,
+    pub next_due_at: chrono::NaiveDateTime,
+    pub reminder_sent: bool,
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name="site_public_token"]
+pub struct SitePublicToken {
+    pub site_id: IdType,
+    pub token: String,
+}
+
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[table_name="ttn_device"]
+pub struct TtnDevice {
+    pub sensor_id: IdType,
+    pub device_id: String,
+    pub payload_format: String,
+}
+
+/// Additional map of a site (ex. a floor), its image is stored by the site_map_service
+#[derive(Debug, Queryable)]
+pub struct SiteMap {
+    pub id: IdType,
+    pub site_id: IdType,
+    pub name: String,
+    pub image_width: Option<i32>,
+    pub image_height: Option<i32>,
+}
+
+/// Room of a site, the sensors can be placed in one
+#[derive(Debug, Queryable)]
+pub struct Room {
+    pub id: IdType,
+    pub site_id: IdType,
+    pub name: String,
+    pub floor: Option<i32>,
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+}
 
 #[derive(Debug, Queryable, Insertable)]
 #[table_name="user_access"]
 pub struct UserAccess {
     pub user_id: IdType,
     pub site_id: IdType,
+    pub level: AccessLevel,
 }
 
 #[derive(Debug, Queryable, Insertable)]
@@ -81,14 +461,34 @@ pub struct Sensor {
     pub loc_y: Option<i32>,
 
     pub enabled: bool,
+
+    /// Translated names (locale -> name)
+    pub name_translations: serde_json::Value,
+
+    /// Seconds without new readings after which the NoData alarm is raised
+    pub max_silence: Option<i32>,
+    /// When the NoData alarm was raised, None if the sensor is reporting
+    pub no_data_since: Option<chrono::NaiveDateTime>,
+
+    pub room_id: Option<IdType>,
+    /// Map the sensor is placed on, None for the main map of the site
+    pub map_id: Option<IdType>,
+    /// When the sensor (or its site) was moved to the trash, None if it isn't deleted
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    /// Last change of the sensor, set by the database
+    pub updated_at: chrono::NaiveDateTime,
 }
 pub type SensorAllColumns = (
     sensor::dsl::id, sensor::dsl::site_id, sensor::dsl::id_cnr, sensor::dsl::name,
-    sensor::dsl::loc_x, sensor::dsl::loc_y, sensor::dsl::enabled
+    sensor::dsl::loc_x, sensor::dsl::loc_y, sensor::dsl::enabled, sensor::dsl::name_translations,
+    sensor::dsl::max_silence, sensor::dsl::no_data_since, sensor::dsl::room_id,
+    sensor::dsl::map_id, sensor::dsl::deleted_at, sensor::dsl::updated_at
 );
 pub const SENSOR_ALL_COLUMNS: SensorAllColumns = (
     sensor::dsl::id, sensor::dsl::site_id, sensor::dsl::id_cnr, sensor::dsl::name,
-    sensor::dsl::loc_x, sensor::dsl::loc_y, sensor::dsl::enabled
+    sensor::dsl::loc_x, sensor::dsl::loc_y, sensor::dsl::enabled, sensor::dsl::name_translations,
+    sensor::dsl::max_silence, sensor::dsl::no_data_since, sensor::dsl::room_id,
+    sensor::dsl::map_id, sensor::dsl::deleted_at, sensor::dsl::updated_at
 );
 
 #[derive(Debug, Queryable, Insertable)]
@@ -106,18 +506,48 @@ pub struct Channel {
     pub range_max: Option<BigDecimal>,
 
     pub alarmed: bool,
+
+    /// Translated names (locale -> name)
+    pub name_translations: serde_json::Value,
+
+    /// Fastest change allowed between two consecutive readings (per hour), None or 0 if unlimited
+    pub max_delta_per_hour: Option<BigDecimal>,
+
+    /// Margin inside the range that the readings must reach for the alarm to end
+    pub hysteresis: Option<BigDecimal>,
+    /// Seconds that the channel must stay out of range before the alarm begins
+    pub alarm_delay: Option<i32>,
+    /// The channel is out of range since then, but the alarm delay isn't over yet
+    pub alarm_pending_since: Option<chrono::NaiveDateTime>,
+    /// When the channel (or its sensor) was moved to the trash, None if it isn't deleted
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    /// The channel kept entering and exiting alarm since then, its alarms aren't notified
+    pub flapping_since: Option<chrono::NaiveDateTime>,
+    /// Last change of the channel, set by the database
+    pub updated_at: chrono::NaiveDateTime,
 }
 pub type ChannelAllColumns = (
     channel::dsl::id, channel::dsl::sensor_id, channel::dsl::id_cnr, channel::dsl::name,
     channel::dsl::measure_unit, channel::dsl::range_min, channel::dsl::range_max,
-    channel::dsl::alarmed
+    channel::dsl::alarmed, channel::dsl::name_translations, channel::dsl::max_delta_per_hour,
+    channel::dsl::hysteresis, channel::dsl::alarm_delay, channel::dsl::alarm_pending_since,
+    channel::dsl::deleted_at, channel::dsl::flapping_since, channel::dsl::updated_at
 );
 pub const CHANNEL_ALL_COLUMNS: ChannelAllColumns = (
     channel::dsl::id, channel::dsl::sensor_id, channel::dsl::id_cnr, channel::dsl::name,
     channel::dsl::measure_unit, channel::dsl::range_min, channel::dsl::range_max,
-    channel::dsl::alarmed
+    channel::dsl::alarmed, channel::dsl::name_translations, channel::dsl::max_delta_per_hour,
+    channel::dsl::hysteresis, channel::dsl::alarm_delay, channel::dsl::alarm_pending_since,
+    channel::dsl::deleted_at, channel::dsl::flapping_since, channel::dsl::updated_at
 );
 
+#[derive(Debug, Queryable, Insertable)]
+#[table_name="email_user_contact"]
+pub struct EmailUserContact {
+    pub email: String,
+    pub user_id: IdType,
+}
+
 #[derive(Debug, Queryable, Insertable)]
 #[table_name="fcm_user_contact"]
 pub struct FcmUserContact {
@@ -126,3 +556,13 @@ pub struct FcmUserContact {
 }
 
 
+
+/// Setting stored by the clients for the user (ex. default site, units, chart settings)
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[table_name="user_preference"]
+pub struct UserPreference {
+    pub user_id: IdType,
+    pub key: String,
+    pub value: String,
+    pub updated_at: chrono::NaiveDateTime,
+}
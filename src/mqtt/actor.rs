@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info, warn};
+
+use crate::AppData;
+use crate::alarm::{AlarmActor, CheckMeasures};
+
+use super::client::{MqttBrokerConfig, MqttClient, MqttError, MqttMessage};
+use super::ingest::{MQTT_READINGS_TOPIC_FILTER, store_messages};
+
+/// Timeout of the connection to the broker
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait between two connection attempts
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Received messages kept at most while waiting to be stored, the oldest are dropped
+const MQTT_MAX_PENDING_MESSAGES: usize = 10000;
+
+type PendingMessages = Arc<Mutex<Vec<(MqttMessage, NaiveDateTime)>>>;
+
+/// Receives the readings from the MQTT broker and stores them every flush interval, the alarm
+/// actor is notified after every write so that the new readings are checked immediately.
+pub struct MqttActor {
+    pub app_data: AppData,
+    pub broker: MqttBrokerConfig,
+    pub flush_interval: Duration,
+    pub alarm_actor: Addr<AlarmActor>,
+    pending: PendingMessages,
+    listening: bool,
+}
+
+impl MqttActor {
+    pub fn new(app_data: AppData, broker: MqttBrokerConfig, flush_interval: Duration, alarm_actor: Addr<AlarmActor>) -> Self {
+        MqttActor {
+            app_data,
+            broker,
+            flush_interval,
+            alarm_actor,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            listening: false,
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let messages = std::mem::take(&mut *self.pending.lock().unwrap());
+        if messages.is_empty() {
+            return
+        }
+        let start = Instant::now();
+        let pool = self.app_data.pool.clone();
+        let sensor_pool = self.app_data.sensor_pool.clone();
+        let readings_cache = self.app_data.readings_cache.clone();
+        let alarm_actor = self.alarm_actor.clone();
+
+        let res = async move {
+            match web::block(move || store_messages(&pool, &sensor_pool, &readings_cache, &messages)).await {
+                Ok(0) => {},
+                Ok(count) => {
+                    info!("Stored {} MQTT readings in {}ms", count, start.elapsed().as_millis());
+                    alarm_actor.do_send(CheckMeasures);
+                },
+                Err(err) => error!("Error storing the MQTT readings: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+/// Receives the messages until the connection fails
+fn listen(broker: &MqttBrokerConfig, pending: &PendingMessages) -> Result<(), MqttError> {
+    let mut client = MqttClient::new(broker, MQTT_CONNECT_TIMEOUT);
+    client.subscribe(MQTT_READINGS_TOPIC_FILTER)?;
+    info!("Subscribing to {} on {}:{}", MQTT_READINGS_TOPIC_FILTER, broker.host, broker.port);
+
+    loop {
+        let message = client.next_message()?;
+        let mut pending = pending.lock().unwrap();
+        if pending.len() >= MQTT_MAX_PENDING_MESSAGES {
+            pending.remove(0);
+        }
+        pending.push((message, Utc::now().naive_utc()));
+    }
+}
+
+impl Actor for MqttActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the mqtt actor");
+
+        // The client is blocking, it runs in its own thread for the whole life of the server
+        if !self.listening {
+            self.listening = true;
+            let broker = self.broker.clone();
+            let pending = self.pending.clone();
+            std::thread::spawn(move || loop {
+                if let Err(err) = listen(&broker, &pending) {
+                    warn!("MQTT connection to {}:{} lost: {}", broker.host, broker.port, err);
+                }
+                std::thread::sleep(MQTT_RECONNECT_DELAY);
+            });
+        }
+
+        IntervalFunc::new(self.flush_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for MqttActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the mqtt actor");
+    }
+}
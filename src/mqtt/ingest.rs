@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::warn;
+use serde::Deserialize;
+
+use crate::alarm::{NewReading, ReadingsWriter};
+use crate::models::{IdType, Pool};
+use crate::web::db_helper::query_channel_cnr_ids;
+use crate::web::readings_cache::ReadingsCache;
+
+use super::client::MqttMessage;
+
+/// Station id used for the readings received through MQTT
+const MQTT_STATION_ID: &str = "mqtt";
+/// Filter of the topics with the readings: site/{site cnr id}/sensor/{sensor id}/channel/{channel id}
+pub const MQTT_READINGS_TOPIC_FILTER: &str = "site/+/sensor/+/channel/+";
+
+#[derive(Deserialize)]
+struct JsonPayload {
+    value: f64,
+    timestamp: Option<DateTime<Utc>>,
+}
+
+/// Parses a readings topic into the site cnr id, the sensor id and the channel id
+fn parse_topic(topic: &str) -> Option<(&str, IdType, IdType)> {
+    let parts: Vec<&str> = topic.split('/').collect();
+    match parts.as_slice() {
+        ["site", site_cnr_id, "sensor", sensor_id, "channel", channel_id] if !site_cnr_id.is_empty() => {
+            Some((site_cnr_id, sensor_id.parse().ok()?, channel_id.parse().ok()?))
+        },
+        _ => None,
+    }
+}
+
+/// Parses the payload, either a plain number or `{"value": 21.5, "timestamp": "2020-04-03T10:00:00Z"}`
+/// (the reception time is used if the timestamp is missing)
+fn parse_payload(payload: &[u8], received_at: NaiveDateTime) -> Option<(NaiveDateTime, f64)> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    let (date, value) = match text.parse::<f64>() {
+        Ok(value) => (received_at, value),
+        Err(_) => {
+            let payload: JsonPayload = serde_json::from_str(text).ok()?;
+            (payload.timestamp.map_or(received_at, |x| x.naive_utc()), payload.value)
+        },
+    };
+    Some((date, value)).filter(|(_, value)| value.is_finite())
+}
+
+/// Finds the channel of the topic, returns its cnr ids and its measure unit
+fn find_topic_channel(pool: &Pool, site_cnr_id: &str, sensor_id: IdType, channel_id: IdType) -> Result<Option<(String, String, String, String)>, String> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let channel = {
+        let conn = pool.get().map_err(|x| x.to_string())?;
+        channel_dsl::channel.find(channel_id)
+            .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+            .filter(sensor_dsl::id.eq(sensor_id))
+            .filter(site_dsl::id_cnr.eq(site_cnr_id))
+            .filter(channel_dsl::deleted_at.is_null())
+            .select((channel_dsl::id_cnr, channel_dsl::measure_unit))
+            .first::<(Option<String>, Option<String>)>(&conn)
+            .optional()
+            .map_err(|x| x.to_string())?
+    };
+    let (channel_id_cnr, measure_unit) = match channel {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let ids = query_channel_cnr_ids(pool, channel_id, channel_id_cnr.as_deref())
+        .map_err(|x| x.to_string())?;
+    Ok(ids.map(|(site, sensor, channel)| (site, sensor, channel, measure_unit.unwrap_or_default())))
+}
+
+/// Stores the readings of the received messages, the messages with an unknown topic or an invalid
+/// payload are skipped, the cached buckets that contain the new readings are dropped.
+/// Returns the number of readings written.
+pub fn store_messages<W: ReadingsWriter>(pool: &Pool, writer: &W, cache: &ReadingsCache, messages: &[(MqttMessage, NaiveDateTime)]) -> Result<usize, String> {
+    let mut channels = HashMap::new();
+    let mut readings = Vec::new();
+
+    for (message, received_at) in messages {
+        let (site_cnr_id, sensor_id, channel_id) = match parse_topic(&message.topic) {
+            Some(x) => x,
+            None => {
+                warn!("Unknown MQTT topic {}", message.topic);
+                continue
+            },
+        };
+        let (date, value) = match parse_payload(&message.payload, *received_at) {
+            Some(x) => x,
+            None => {
+                warn!("Invalid MQTT payload in {}", message.topic);
+                continue
+            },
+        };
+
+        if !channels.contains_key(message.topic.as_str()) {
+            let channel = find_topic_channel(pool, site_cnr_id, sensor_id, channel_id)?;
+            if channel.is_none() {
+                warn!("No channel found for MQTT topic {}", message.topic);
+            }
+            channels.insert(message.topic.as_str(), channel);
+        }
+        if let Some((site_id, sensor_id, channel_id, measure_unit)) = &channels[message.topic.as_str()] {
+            readings.push(NewReading {
+                site_id: site_id.clone(),
+                room_id: "".to_string(),
+                station_id: MQTT_STATION_ID.to_string(),
+                sensor_id: sensor_id.clone(),
+                channel_id: channel_id.clone(),
+                value,
+                measure_unit: measure_unit.clone(),
+                date,
+            });
+        }
+    }
+
+    writer.insert_readings(&readings).map_err(|x| x.to_string())?;
+    cache.invalidate(&readings, Utc::now().naive_utc());
+    Ok(readings.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(parse_topic("site/CNR1/sensor/12/channel/34"), Some(("CNR1", 12, 34)));
+        assert_eq!(parse_topic("site//sensor/12/channel/34"), None);
+        assert_eq!(parse_topic("site/CNR1/sensor/x/channel/34"), None);
+        assert_eq!(parse_topic("site/CNR1/sensor/12/channel/34/extra"), None);
+
+        let now = NaiveDate::from_ymd(2020, 4, 3).and_hms(10, 0, 0);
+        assert_eq!(parse_payload(b" 21.5\n", now), Some((now, 21.5)));
+        assert_eq!(
+            parse_payload(br#"{"value": 3, "timestamp": "2020-04-03T08:30:00Z"}"#, now),
+            Some((NaiveDate::from_ymd(2020, 4, 3).and_hms(8, 30, 0), 3.0))
+        );
+        assert_eq!(parse_payload(br#"{"value": 3}"#, now), Some((now, 3.0)));
+        assert_eq!(parse_payload(b"NaN", now), None);
+        assert_eq!(parse_payload(b"{}", now), None);
+    }
+}
@@ -0,0 +1,7 @@
+mod actor;
+mod client;
+mod ingest;
+
+pub use actor::MqttActor;
+pub use client::{MqttBrokerConfig, MqttClient, MqttError, MqttMessage};
+pub use ingest::{MQTT_READINGS_TOPIC_FILTER, store_messages};
@@ -0,0 +1,100 @@
+//! Redaction of the sensitive values (passwords, tokens, FCM registration ids...) before they
+//! reach the logs or the error messages sent to the clients.
+//!
+//! A value is sensitive if its key contains one of the default words or one of the comma
+//! separated words in `LOG_REDACT_KEYS`, the case and the underscores are ignored
+//! (so `registrationId` and `registration_id` are both matched by `registrationid`).
+
+use serde_json::Value;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+const DEFAULT_SENSITIVE_KEYS: &[&str] = &[
+    "password", "token", "secret", "key", "registrationid", "authorization", "cookie",
+];
+
+lazy_static! {
+    static ref SENSITIVE_KEYS: Vec<String> = {
+        let extra = std::env::var("LOG_REDACT_KEYS").unwrap_or_default();
+        DEFAULT_SENSITIVE_KEYS.iter()
+            .map(|x| x.to_string())
+            .chain(extra.split(',').map(normalize_key).filter(|x| !x.is_empty()))
+            .collect()
+    };
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|x| *x != '_' && !x.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+pub fn is_sensitive_key(key: &str) -> bool {
+    let key = normalize_key(key);
+    SENSITIVE_KEYS.iter().any(|x| key.contains(x.as_str()))
+}
+
+/// Copy of the value where every member with a sensitive key is replaced by REDACTED
+pub fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(members) => Value::Object(members.iter()
+            .map(|(key, value)| {
+                let value = if is_sensitive_key(key) {
+                    Value::String(REDACTED.to_string())
+                } else {
+                    redact_json(value)
+                };
+                (key.clone(), value)
+            })
+            .collect()),
+        Value::Array(items) => Value::Array(items.iter().map(redact_json).collect()),
+        x => x.clone(),
+    }
+}
+
+/// Redacts the values of the sensitive columns in the details of a postgres error
+/// (ex. `Key (registration_id)=(abc) already exists.`)
+pub fn redact_db_details(details: &str) -> String {
+    let (columns_start, columns_end, values_end) = match (details.find('('), details.find(")=("), details.rfind(')')) {
+        (Some(a), Some(b), Some(c)) if a < b && b + 2 < c => (a, b, c),
+        _ => return details.to_string(),
+    };
+    if !details[columns_start + 1..columns_end].split(',').any(is_sensitive_key) {
+        return details.to_string()
+    }
+    format!("{}({}{}", &details[..columns_end + 2], REDACTED, &details[values_end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let variables = json!({
+            "username": "paolo",
+            "data": { "password": "hunter2", "permission": "USER" },
+            "registrationId": "fcm-id",
+            "contacts": [{ "authToken": "abc" }],
+        });
+        assert_eq!(redact_json(&variables), json!({
+            "username": "paolo",
+            "data": { "password": REDACTED, "permission": "USER" },
+            "registrationId": REDACTED,
+            "contacts": [{ "authToken": REDACTED }],
+        }));
+
+        assert_eq!(
+            redact_db_details("Key (registration_id)=(fcm-id) already exists."),
+            format!("Key (registration_id)=({}) already exists.", REDACTED)
+        );
+        assert_eq!(
+            redact_db_details("Key (username)=(paolo) already exists."),
+            "Key (username)=(paolo) already exists."
+        );
+        assert_eq!(redact_db_details("no details"), "no details");
+    }
+}
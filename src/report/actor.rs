@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::sender::{prepare_due_reports, send_reports};
+
+pub struct ReportActor {
+    pub app_data: AppData,
+    pub check_interval: Duration,
+}
+
+impl ReportActor {
+    fn on_tick(&mut self, ctx: &mut Context<Self>) {
+        let contacter = self.app_data.contacter.clone();
+        let pool = self.app_data.pool.clone();
+        let sensor_pool = self.app_data.sensor_pool.clone();
+
+        let res = async move {
+            let now = Utc::now().naive_utc();
+            let build_pool = pool.clone();
+            let reports = match web::block(move || prepare_due_reports(&build_pool, &sensor_pool, now)).await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("Error during report generation: {}", err);
+                    return
+                },
+            };
+            if reports.is_empty() {
+                return
+            }
+
+            let connection = match pool.get() {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("Error in connection pool: {}", err);
+                    return
+                },
+            };
+            match send_reports(&contacter, &connection, reports).await {
+                Ok(count) => info!("Sent {} site reports", count),
+                Err(err) => error!("Error sending the site reports: {}", err),
+            }
+        };
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for ReportActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the report actor");
+
+        IntervalFunc::new(self.check_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for ReportActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the report actor");
+    }
+}
@@ -0,0 +1,9 @@
+mod actor;
+mod render;
+mod sender;
+mod summary;
+
+pub use actor::ReportActor;
+pub use render::{render_html, render_pdf};
+pub use sender::{prepare_due_reports, send_reports, PendingReport};
+pub use summary::{build_site_summary, ChannelSummary, ReportFormat, ReportPeriod, SiteSummary};
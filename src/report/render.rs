@@ -0,0 +1,197 @@
+use std::fmt::Write;
+
+use super::summary::{ChannelSummary, ReportPeriod, SiteSummary};
+
+/// A4 in points
+const PAGE_WIDTH: i32 = 595;
+const PAGE_HEIGHT: i32 = 842;
+const PAGE_MARGIN: i32 = 50;
+const FONT_SIZE: i32 = 10;
+const LINE_HEIGHT: i32 = 14;
+
+pub fn report_title(summary: &SiteSummary) -> String {
+    let name = match summary.period {
+        ReportPeriod::Daily => "Daily report",
+        ReportPeriod::Weekly => "Weekly report",
+    };
+    format!("{} {}", name, summary.start.format("%Y-%m-%d"))
+}
+
+pub fn format_duration(secs: i64) -> String {
+    format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+}
+
+fn format_value(value: Option<f64>, channel: &ChannelSummary) -> String {
+    match (value, &channel.measure_unit) {
+        (Some(x), Some(unit)) => format!("{:.2} {}", x, unit),
+        (Some(x), None) => format!("{:.2}", x),
+        (None, _) => "-".to_string(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn period_description(summary: &SiteSummary) -> String {
+    format!(
+        "From {} to {} (UTC), {} alarms",
+        summary.start.format("%Y-%m-%d %H:%M"), summary.end.format("%Y-%m-%d %H:%M"), summary.total_alarms()
+    )
+}
+
+pub fn render_html(summary: &SiteSummary) -> String {
+    let mut html = String::new();
+    let title = format!("{}: {}", report_title(summary), summary.site_name);
+
+    html += "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">";
+    write!(html, "<title>{0}</title></head><body><h1>{0}</h1>", escape_html(&title)).unwrap();
+    write!(html, "<p>{}</p>", period_description(summary)).unwrap();
+
+    if summary.channels.is_empty() {
+        html += "<p>The site has no channels.</p>";
+    } else {
+        html += "<table border=\"1\" cellpadding=\"4\" style=\"border-collapse: collapse\">\
+                 <tr><th>Sensor</th><th>Channel</th><th>Alarms</th><th>Out of range</th><th>Min</th><th>Max</th></tr>";
+        for channel in summary.channels.iter() {
+            write!(
+                html, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&channel.sensor_name), escape_html(&channel.channel_name), channel.alarms,
+                format_duration(channel.out_of_range_secs),
+                escape_html(&format_value(channel.min, channel)), escape_html(&format_value(channel.max, channel))
+            ).unwrap();
+        }
+        html += "</table>";
+    }
+    html += "</body></html>\n";
+    html
+}
+
+/// Escapes a string for a PDF literal, the characters outside of ASCII aren't available in the
+/// standard fonts encoding and are replaced
+fn escape_pdf(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '(' | ')' => {
+                res.push('\\');
+                res.push(c);
+            },
+            ' '..='~' => res.push(c),
+            _ => res.push('?'),
+        }
+    }
+    res
+}
+
+/// Renders the report as a plain text PDF (one line per channel) with the Helvetica standard
+/// font, so that no font has to be embedded
+pub fn render_pdf(summary: &SiteSummary) -> Vec<u8> {
+    let mut lines = vec![
+        format!("{}: {}", report_title(summary), summary.site_name),
+        period_description(summary),
+        String::new(),
+    ];
+    if summary.channels.is_empty() {
+        lines.push("The site has no channels.".to_string());
+    }
+    for channel in summary.channels.iter() {
+        lines.push(format!(
+            "{} / {}: {} alarms, out of range {}, min {}, max {}",
+            channel.sensor_name, channel.channel_name, channel.alarms, format_duration(channel.out_of_range_secs),
+            format_value(channel.min, channel), format_value(channel.max, channel)
+        ));
+    }
+
+    let lines_per_page = ((PAGE_HEIGHT - 2 * PAGE_MARGIN) / LINE_HEIGHT) as usize;
+    let pages: Vec<&[String]> = lines.chunks(lines_per_page).collect();
+
+    // 1: catalog, 2: page tree, 3: font, then a page and its content for every page
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            (0..pages.len()).map(|x| format!("{} 0 R", 4 + 2 * x)).collect::<Vec<_>>().join(" "),
+            pages.len()
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_string(),
+    ];
+    for (index, page) in pages.iter().enumerate() {
+        let mut content = format!("BT /F1 {} Tf {} TL {} {} Td", FONT_SIZE, LINE_HEIGHT, PAGE_MARGIN, PAGE_HEIGHT - PAGE_MARGIN);
+        for line in page.iter() {
+            write!(content, " ({}) Tj T*", escape_pdf(line)).unwrap();
+        }
+        content += " ET";
+
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH, PAGE_HEIGHT, 5 + 2 * index
+        ));
+        objects.push(format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content));
+    }
+
+    let mut pdf = "%PDF-1.4\n".to_string();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        write!(pdf, "{} 0 obj\n{}\nendobj\n", index + 1, object).unwrap();
+    }
+    let xref_offset = pdf.len();
+    write!(pdf, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).unwrap();
+    for offset in offsets {
+        write!(pdf, "{:010} 00000 n \n", offset).unwrap();
+    }
+    write!(pdf, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref_offset).unwrap();
+    pdf.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn summary(channels: usize) -> SiteSummary {
+        let start = NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+        SiteSummary {
+            site_id: 1,
+            site_name: "Museum <A>".to_string(),
+            period: ReportPeriod::Daily,
+            start,
+            end: start + chrono::Duration::days(1),
+            channels: (0..channels).map(|x| ChannelSummary {
+                channel_id: x as i32,
+                sensor_name: "Hall (north)".to_string(),
+                channel_name: "Temperatura è".to_string(),
+                measure_unit: Some("°C".to_string()),
+                alarms: 2,
+                out_of_range_secs: 3900,
+                min: Some(18.5),
+                max: None,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = render_html(&summary(2));
+        assert!(html.contains("<h1>Daily report 2020-01-01: Museum &lt;A&gt;</h1>"));
+        assert!(html.contains("<td>2</td><td>1h 05m</td><td>18.50 °C</td><td>-</td>"));
+        assert!(html.contains("4 alarms"));
+    }
+
+    #[test]
+    fn test_render_pdf() {
+        let pdf = String::from_utf8(render_pdf(&summary(100))).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        assert!(pdf.contains("/Count 2 "));
+        assert!(pdf.contains("(Hall \\(north\\) / Temperatura ?: 2 alarms, out of range 1h 05m, min 18.50 ?C, max -) Tj"));
+
+        let xref = pdf.rfind("xref\n").unwrap();
+        assert!(pdf.ends_with(&format!("startxref\n{}\n%%EOF\n", xref)));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::contact::{Contacter, ReportData};
+use crate::models::{IdType, PermissionType, Pool, ReportSubscription};
+
+use super::render::{render_html, render_pdf, report_title};
+use super::summary::{build_site_summary, ReportFormat, ReportPeriod, SiteSummary};
+
+/// A report rendered for a subscription, waiting to be sent
+#[derive(Debug)]
+pub struct PendingReport {
+    pub subscription_id: IdType,
+    pub period_end: NaiveDateTime,
+    pub data: ReportData,
+}
+
+fn can_see_site(conn: &PgConnection, user_id: IdType, permission: PermissionType, site_id: IdType) -> QueryResult<bool> {
+    use crate::schema::user_access::dsl;
+
+    if permission == PermissionType::Admin {
+        return Ok(true)
+    }
+    let count: i64 = dsl::user_access.count()
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::site_id.eq(site_id))
+        .get_result(conn)?;
+    Ok(count != 0)
+}
+
+/// Renders the reports of the subscriptions whose last period has not been reported yet.
+/// Only the last completed period is reported, the periods missed while the server was down are
+/// skipped. The subscriptions of the users that can't see the site anymore are ignored.
+pub fn prepare_due_reports(pool: &Pool, sensor_pool: &mysql::Pool, now: NaiveDateTime) -> Result<Vec<PendingReport>, String> {
+    use crate::schema::{
+        report_subscription::dsl as subscription_dsl,
+        site::dsl as site_dsl,
+        user_account::dsl as user_dsl,
+    };
+
+    let conn = pool.get().map_err(|x| x.to_string())?;
+    let subscriptions = subscription_dsl::report_subscription
+        .inner_join(user_dsl::user_account)
+        .inner_join(site_dsl::site)
+        .filter(site_dsl::deleted_at.is_null())
+        .order(subscription_dsl::id)
+        .select((subscription_dsl::report_subscription::all_columns(), user_dsl::permission))
+        .load::<(ReportSubscription, PermissionType)>(&conn)
+        .map_err(|x| x.to_string())?;
+
+    // Many users can subscribe to the same site, the summary is only built once
+    let mut summaries: HashMap<(IdType, NaiveDateTime), SiteSummary> = HashMap::new();
+    let mut reports = Vec::new();
+    for (subscription, permission) in subscriptions {
+        let (period, format) = match (ReportPeriod::from_char(&subscription.period), ReportFormat::from_char(&subscription.format)) {
+            (Some(period), Some(format)) => (period, format),
+            _ => {
+                warn!("Invalid report subscription {}", subscription.id);
+                continue
+            }
+        };
+        let period_end = period.last_end(now);
+        if subscription.last_period_end.map_or(false, |x| x >= period_end) {
+            continue
+        }
+        if !can_see_site(&conn, subscription.user_id, permission, subscription.site_id).map_err(|x| x.to_string())? {
+            continue
+        }
+
+        let key = (subscription.site_id, period_end);
+        if !summaries.contains_key(&key) {
+            match build_site_summary(pool, sensor_pool, subscription.site_id, period, period_end) {
+                Ok(x) => summaries.insert(key, x),
+                Err(err) => {
+                    warn!("Cannot build the report of site {}: {}", subscription.site_id, err);
+                    continue
+                }
+            };
+        }
+        let summary = &summaries[&key];
+
+        let title = report_title(summary);
+        reports.push(PendingReport {
+            subscription_id: subscription.id,
+            period_end,
+            data: ReportData {
+                user_id: subscription.user_id,
+                site_name: summary.site_name.clone(),
+                file_name: format!("{}.pdf", title.replace(' ', "_").to_lowercase()),
+                title,
+                html: render_html(summary),
+                pdf: if format == ReportFormat::Pdf { Some(render_pdf(summary)) } else { None },
+            },
+        });
+    }
+    Ok(reports)
+}
+
+/// Emails the reports and marks their periods as reported, a report that can't be sent is
+/// retried on the next check.
+/// Returns the number of reports sent.
+pub async fn send_reports(contacter: &Contacter, conn: &PgConnection, reports: Vec<PendingReport>) -> Result<usize, String> {
+    use crate::schema::report_subscription::dsl;
+
+    let mut sent = 0;
+    for report in reports {
+        if let Err(e) = contacter.send_report(conn, &report.data).await {
+            warn!("Cannot send the report of subscription {}: {}", report.subscription_id, e);
+            continue
+        }
+        diesel::update(dsl::report_subscription.find(report.subscription_id))
+            .set(dsl::last_period_end.eq(report.period_end))
+            .execute(conn)
+            .map_err(|x| x.to_string())?;
+        sent += 1;
+    }
+    Ok(sent)
+}
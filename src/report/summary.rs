@@ -0,0 +1,226 @@
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime};
+use diesel::prelude::*;
+use log::warn;
+
+use crate::models::{IdType, Pool};
+use crate::web::db_helper::{load_channel_reading_stats, load_invalid_intervals, query_channel_cnr_ids};
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum ReportPeriod {
+    /// From midnight to midnight (UTC)
+    Daily,
+    /// From monday to monday (UTC)
+    Weekly,
+}
+
+impl ReportPeriod {
+    pub fn from_char(name: &str) -> Option<ReportPeriod> {
+        match name {
+            "d" => Some(ReportPeriod::Daily),
+            "w" => Some(ReportPeriod::Weekly),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            ReportPeriod::Daily => "d",
+            ReportPeriod::Weekly => "w",
+        }
+    }
+
+    pub fn length(self) -> Duration {
+        match self {
+            ReportPeriod::Daily => Duration::days(1),
+            ReportPeriod::Weekly => Duration::weeks(1),
+        }
+    }
+
+    /// End of the last period completed before now
+    pub fn last_end(self, now: NaiveDateTime) -> NaiveDateTime {
+        let midnight = now.date().and_time(NaiveTime::from_hms(0, 0, 0));
+        match self {
+            ReportPeriod::Daily => midnight,
+            ReportPeriod::Weekly => midnight - Duration::days(now.weekday().num_days_from_monday() as i64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum ReportFormat {
+    /// The report is the body of the email
+    Html,
+    /// The report is also attached to the email as a PDF
+    Pdf,
+}
+
+impl ReportFormat {
+    pub fn from_char(name: &str) -> Option<ReportFormat> {
+        match name {
+            "h" => Some(ReportFormat::Html),
+            "p" => Some(ReportFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            ReportFormat::Html => "h",
+            ReportFormat::Pdf => "p",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSummary {
+    pub channel_id: IdType,
+    pub sensor_name: String,
+    pub channel_name: String,
+    pub measure_unit: Option<String>,
+    /// Alarms started in the period
+    pub alarms: usize,
+    /// Seconds the channel spent in alarm during the period
+    pub out_of_range_secs: i64,
+    /// Lowest and highest readings of the period (the invalid ones are skipped)
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteSummary {
+    pub site_id: IdType,
+    pub site_name: String,
+    pub period: ReportPeriod,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// Ordered by sensor and channel
+    pub channels: Vec<ChannelSummary>,
+}
+
+impl SiteSummary {
+    pub fn total_alarms(&self) -> usize {
+        self.channels.iter().map(|x| x.alarms).sum()
+    }
+}
+
+/// Part of the alarm between started_at and ended_at (still going on if None) that falls between
+/// start and end, in seconds
+pub fn overlap_secs(started_at: NaiveDateTime, ended_at: Option<NaiveDateTime>, start: NaiveDateTime, end: NaiveDateTime) -> i64 {
+    let from = started_at.max(start);
+    let to = ended_at.unwrap_or(end).min(end);
+    (to - from).num_seconds().max(0)
+}
+
+type ReportChannelData = (IdType, Option<String>, Option<String>, Option<String>, Option<String>);
+
+/// Collects the alarms and the readings of the site channels between start and end.
+/// A channel whose readings can't be loaded is still reported, without min and max.
+pub fn build_site_summary(pool: &Pool, sensor_pool: &mysql::Pool, site_id: IdType, period: ReportPeriod, end: NaiveDateTime) -> Result<SiteSummary, String> {
+    use crate::schema::{
+        alarm_event::dsl as alarm_dsl,
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let start = end - period.length();
+    let conn = pool.get().map_err(|x| x.to_string())?;
+
+    let site_name = site_dsl::site.find(site_id)
+        .select(site_dsl::name)
+        .first::<Option<String>>(&conn)
+        .map_err(|x| x.to_string())?
+        .unwrap_or_else(|| "?".to_string());
+
+    let channels = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(channel_dsl::deleted_at.is_null())
+        .order((sensor_dsl::id, channel_dsl::id))
+        .select((channel_dsl::id, channel_dsl::id_cnr, sensor_dsl::name, channel_dsl::name, channel_dsl::measure_unit))
+        .load::<ReportChannelData>(&conn)
+        .map_err(|x| x.to_string())?;
+
+    let channel_ids: Vec<IdType> = channels.iter().map(|x| x.0).collect();
+    let alarms = alarm_dsl::alarm_event
+        .filter(alarm_dsl::channel_id.eq_any(&channel_ids))
+        .filter(alarm_dsl::started_at.lt(end))
+        .filter(alarm_dsl::ended_at.is_null().or(alarm_dsl::ended_at.gt(start)))
+        .select((alarm_dsl::channel_id, alarm_dsl::started_at, alarm_dsl::ended_at))
+        .load::<(IdType, NaiveDateTime, Option<NaiveDateTime>)>(&conn)
+        .map_err(|x| x.to_string())?;
+    std::mem::drop(conn);
+
+    let mut summaries = Vec::with_capacity(channels.len());
+    for (channel_id, id_cnr, sensor_name, channel_name, measure_unit) in channels {
+        let channel_alarms = alarms.iter().filter(|x| x.0 == channel_id);
+
+        let mut summary = ChannelSummary {
+            channel_id,
+            sensor_name: sensor_name.unwrap_or_else(|| "?".to_string()),
+            channel_name: channel_name.unwrap_or_else(|| "?".to_string()),
+            measure_unit,
+            alarms: channel_alarms.clone().filter(|x| x.1 >= start).count(),
+            out_of_range_secs: channel_alarms.map(|x| overlap_secs(x.1, x.2, start, end)).sum(),
+            min: None,
+            max: None,
+        };
+
+        let stats = query_channel_cnr_ids(pool, channel_id, id_cnr.as_deref())
+            .and_then(|ids| {
+                let ids = match ids {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                let invalid = load_invalid_intervals(&*pool.get()?, channel_id, start, end)?;
+                load_channel_reading_stats(sensor_pool, &ids, start, end, &invalid).map(Some)
+            });
+        match stats {
+            Ok(Some(stats)) => {
+                summary.min = stats.min;
+                summary.max = stats.max;
+            },
+            Ok(None) => {},
+            Err(err) => warn!("Cannot load the readings of channel {} for the report: {}", channel_id, err),
+        }
+        summaries.push(summary);
+    }
+
+    Ok(SiteSummary {
+        site_id,
+        site_name,
+        period,
+        start,
+        end,
+        channels: summaries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn date(d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 1, d).and_hms(h, 0, 0)
+    }
+
+    #[test]
+    fn test_last_end() {
+        // 2020-01-08 is a wednesday
+        assert_eq!(ReportPeriod::Daily.last_end(date(8, 10)), date(8, 0));
+        assert_eq!(ReportPeriod::Weekly.last_end(date(8, 10)), date(6, 0));
+        assert_eq!(ReportPeriod::Weekly.last_end(date(6, 0)), date(6, 0));
+    }
+
+    #[test]
+    fn test_overlap_secs() {
+        let (start, end) = (date(2, 0), date(3, 0));
+        assert_eq!(overlap_secs(date(1, 20), Some(date(2, 2)), start, end), 2 * 3600);
+        assert_eq!(overlap_secs(date(2, 10), Some(date(2, 11)), start, end), 3600);
+        assert_eq!(overlap_secs(date(2, 22), None, start, end), 2 * 3600);
+        assert_eq!(overlap_secs(date(1, 10), Some(date(1, 11)), start, end), 0);
+    }
+}
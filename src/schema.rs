@@ -1,3 +1,73 @@
+table! {
+    alarm_escalation (alarm_event_id, policy_id) {
+        alarm_event_id -> Int4,
+        policy_id -> Int4,
+        sent_at -> Timestamp,
+    }
+}
+
+table! {
+    alarm_event (id) {
+        id -> Int4,
+        channel_id -> Int4,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        peak_value -> Float8,
+        extreme_type -> Bpchar,
+        acknowledged_at -> Nullable<Timestamp>,
+        acknowledged_by -> Nullable<Int4>,
+        acknowledge_note -> Nullable<Varchar>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    anomaly_scan (channel_id) {
+        channel_id -> Int4,
+        clock -> Timestamp,
+    }
+}
+
+table! {
+    api_key (id) {
+        id -> Int4,
+        name -> Varchar,
+        key_hash -> Bpchar,
+        site_id -> Nullable<Int4>,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        can_ingest -> Bool,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        username -> Varchar,
+        action -> Varchar,
+        target -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    bulk_export_job (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        site_ids -> Array<Int4>,
+        range_start -> Timestamp,
+        range_end -> Timestamp,
+        include_invalid -> Bool,
+        status -> Bpchar,
+        channel_count -> Int4,
+        row_count -> Int8,
+        error -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     channel (id) {
         id -> Int4,
@@ -8,6 +78,91 @@
         range_min -> Nullable<Numeric>,
         range_max -> Nullable<Numeric>,
         alarmed -> Bool,
+        name_translations -> Jsonb,
+        max_delta_per_hour -> Nullable<Numeric>,
+        hysteresis -> Nullable<Numeric>,
+        alarm_delay -> Nullable<Int4>,
+        alarm_pending_since -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        flapping_since -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    channel_anomaly (id) {
+        id -> Int4,
+        channel_id -> Int4,
+        started_at -> Timestamp,
+        ended_at -> Timestamp,
+        score -> Float8,
+        kind -> Bpchar,
+    }
+}
+
+table! {
+    channel_mute (channel_id) {
+        channel_id -> Int4,
+        muted_until -> Timestamp,
+        muted_by -> Nullable<Int4>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    discovered_channel (site_id, sensor_id_cnr, channel_id_cnr) {
+        site_id -> Int4,
+        sensor_id_cnr -> Varchar,
+        channel_id_cnr -> Varchar,
+        last_reading_at -> Timestamp,
+        notified_at -> Timestamp,
+    }
+}
+
+table! {
+    email_user_contact (email) {
+        email -> Varchar,
+        user_id -> Int4,
+    }
+}
+
+table! {
+    escalation_policy (id) {
+        id -> Int4,
+        site_id -> Nullable<Int4>,
+        delay_minutes -> Int4,
+        target -> Bpchar,
+        user_ids -> Array<Int4>,
+    }
+}
+
+table! {
+    export_job (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        channel_id -> Int4,
+        format -> Varchar,
+        status -> Bpchar,
+        row_count -> Int8,
+        error -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    export_manifest (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        channel_id -> Int4,
+        format -> Varchar,
+        range_start -> Timestamp,
+        range_end -> Timestamp,
+        include_invalid -> Bool,
+        row_count -> Nullable<Int8>,
+        sha256 -> Nullable<Bpchar>,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
     }
 }
 
@@ -18,6 +173,131 @@
     }
 }
 
+table! {
+    integration_secret (name) {
+        name -> Varchar,
+        nonce -> Bytea,
+        ciphertext -> Bytea,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    maintenance_window (id) {
+        id -> Int4,
+        site_id -> Int4,
+        start_time -> Timestamp,
+        end_time -> Timestamp,
+        description -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    manual_reading (id) {
+        id -> Int4,
+        channel_id -> Int4,
+        user_id -> Nullable<Int4>,
+        taken_at -> Timestamp,
+        value -> Float8,
+        note -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    modbus_register (channel_id) {
+        channel_id -> Int4,
+        host -> Varchar,
+        port -> Int4,
+        unit_id -> Int4,
+        address -> Int4,
+        register_type -> Bpchar,
+        value_type -> Bpchar,
+        scale -> Float8,
+        value_offset -> Float8,
+    }
+}
+
+table! {
+    peer_group (id) {
+        id -> Int4,
+        name -> Varchar,
+    }
+}
+
+table! {
+    peer_group_channel (peer_group_id, channel_id) {
+        peer_group_id -> Int4,
+        channel_id -> Int4,
+    }
+}
+
+table! {
+    pending_action (id) {
+        id -> Int4,
+        action -> Varchar,
+        target_id -> Int4,
+        requested_by -> Int4,
+        requested_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    quota_snapshot (user_id, pool) {
+        user_id -> Int4,
+        pool -> Bpchar,
+        balance -> Int8,
+        saved_at -> Timestamp,
+    }
+}
+
+table! {
+    reading_annotation (id) {
+        id -> Int4,
+        channel_id -> Int4,
+        start_time -> Timestamp,
+        end_time -> Timestamp,
+        reason -> Varchar,
+    }
+}
+
+table! {
+    report_subscription (id) {
+        id -> Int4,
+        user_id -> Int4,
+        site_id -> Int4,
+        period -> Bpchar,
+        format -> Bpchar,
+        last_period_end -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    request_log (id) {
+        id -> Int4,
+        created_at -> Timestamp,
+        user_id -> Nullable<Int4>,
+        site_id -> Nullable<Int4>,
+        operation_name -> Nullable<Varchar>,
+        complexity -> Int8,
+        row_count -> Int8,
+        duration_ms -> Int4,
+        quota_rejected -> Bool,
+    }
+}
+
+table! {
+    room (id) {
+        id -> Int4,
+        site_id -> Int4,
+        name -> Varchar,
+        floor -> Nullable<Int4>,
+        loc_x -> Nullable<Int4>,
+        loc_y -> Nullable<Int4>,
+    }
+}
+
 table! {
     sensor (id) {
         id -> Int4,
@@ -27,6 +307,24 @@
         loc_x -> Nullable<Int4>,
         loc_y -> Nullable<Int4>,
         enabled -> Bool,
+        name_translations -> Jsonb,
+        max_silence -> Nullable<Int4>,
+        no_data_since -> Nullable<Timestamp>,
+        room_id -> Nullable<Int4>,
+        map_id -> Nullable<Int4>,
+        deleted_at -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    sensor_calibration (id) {
+        id -> Int4,
+        sensor_id -> Int4,
+        calibrated_at -> Timestamp,
+        certificate_id -> Varchar,
+        next_due_at -> Timestamp,
+        reminder_sent -> Bool,
     }
 }
 
@@ -38,37 +336,189 @@
         clock -> Timestamp,
         image_width -> Nullable<Int4>,
         image_height -> Nullable<Int4>,
+        name_translations -> Jsonb,
+        deleted_at -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    site_map (id) {
+        id -> Int4,
+        site_id -> Int4,
+        name -> Varchar,
+        image_width -> Nullable<Int4>,
+        image_height -> Nullable<Int4>,
+    }
+}
+
+table! {
+    site_public_token (site_id) {
+        site_id -> Int4,
+        token -> Varchar,
     }
 }
 
 table! {
+    site_status (site_id) {
+        site_id -> Int4,
+        status -> Bpchar,
+        changed_at -> Timestamp,
+    }
+}
+
+table! {
+    site_webhook (id) {
+        id -> Int4,
+        site_id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        status_changes -> Bool,
+    }
+}
+
+table! {
+    ttn_device (sensor_id) {
+        sensor_id -> Int4,
+        device_id -> Varchar,
+        payload_format -> Bpchar,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::AccessLevelSql;
+
     user_access (user_id, site_id) {
         user_id -> Int4,
         site_id -> Int4,
+        level -> AccessLevelSql,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::models::PermissionTypeSql;
+
     user_account (id) {
         id -> Int4,
         username -> Varchar,
         password_hash -> Varchar,
         last_password_change -> Timestamp,
-        permission -> Bpchar,
+        permission -> PermissionTypeSql,
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+        email_token_hash -> Nullable<Bpchar>,
+        email_token_sent_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_preference (user_id, key) {
+        user_id -> Int4,
+        key -> Varchar,
+        value -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    user_session (id) {
+        id -> Int4,
+        user_id -> Int4,
+        secret_hash -> Bpchar,
+        user_agent -> Nullable<Varchar>,
+        address -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
     }
 }
 
+joinable!(alarm_escalation -> alarm_event (alarm_event_id));
+joinable!(alarm_escalation -> escalation_policy (policy_id));
+joinable!(alarm_event -> channel (channel_id));
+joinable!(alarm_event -> user_account (acknowledged_by));
+joinable!(anomaly_scan -> channel (channel_id));
+joinable!(api_key -> site (site_id));
+joinable!(audit_log -> user_account (user_id));
+joinable!(bulk_export_job -> user_account (user_id));
 joinable!(channel -> sensor (sensor_id));
+joinable!(channel_anomaly -> channel (channel_id));
+joinable!(channel_mute -> channel (channel_id));
+joinable!(channel_mute -> user_account (muted_by));
+joinable!(discovered_channel -> site (site_id));
+joinable!(email_user_contact -> user_account (user_id));
+joinable!(escalation_policy -> site (site_id));
+joinable!(export_job -> channel (channel_id));
+joinable!(export_job -> user_account (user_id));
+joinable!(export_manifest -> user_account (user_id));
 joinable!(fcm_user_contact -> user_account (user_id));
+joinable!(maintenance_window -> site (site_id));
+joinable!(manual_reading -> channel (channel_id));
+joinable!(manual_reading -> user_account (user_id));
+joinable!(modbus_register -> channel (channel_id));
+joinable!(peer_group_channel -> channel (channel_id));
+joinable!(peer_group_channel -> peer_group (peer_group_id));
+joinable!(pending_action -> user_account (requested_by));
+joinable!(quota_snapshot -> user_account (user_id));
+joinable!(reading_annotation -> channel (channel_id));
+joinable!(report_subscription -> site (site_id));
+joinable!(report_subscription -> user_account (user_id));
+joinable!(request_log -> site (site_id));
+joinable!(request_log -> user_account (user_id));
+joinable!(room -> site (site_id));
+joinable!(sensor -> room (room_id));
 joinable!(sensor -> site (site_id));
+joinable!(sensor -> site_map (map_id));
+joinable!(sensor_calibration -> sensor (sensor_id));
+joinable!(site_map -> site (site_id));
+joinable!(site_public_token -> site (site_id));
+joinable!(site_status -> site (site_id));
+joinable!(site_webhook -> site (site_id));
+joinable!(ttn_device -> sensor (sensor_id));
 joinable!(user_access -> site (site_id));
 joinable!(user_access -> user_account (user_id));
+joinable!(user_preference -> user_account (user_id));
+joinable!(user_session -> user_account (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    alarm_escalation,
+    alarm_event,
+    anomaly_scan,
+    api_key,
+    audit_log,
+    bulk_export_job,
     channel,
+    channel_anomaly,
+    channel_mute,
+    discovered_channel,
+    email_user_contact,
+    escalation_policy,
+    export_job,
+    export_manifest,
     fcm_user_contact,
+    integration_secret,
+    maintenance_window,
+    manual_reading,
+    modbus_register,
+    peer_group,
+    peer_group_channel,
+    pending_action,
+    quota_snapshot,
+    reading_annotation,
+    report_subscription,
+    request_log,
+    room,
     sensor,
+    sensor_calibration,
     site,
+    site_map,
+    site_public_token,
+    site_status,
+    site_webhook,
+    ttn_device,
     user_access,
     user_account,
+    user_preference,
+    user_session,
 );
@@ -0,0 +1,172 @@
+//! Credentials of the third-party integrations (FCM, SMTP) stored encrypted in the database, so
+//! that the admins can rotate them at runtime without touching the environment.
+//!
+//! The secrets are encrypted with AES-256-GCM, the key is read from `SECRETS_KEY`
+//! (64 hex characters) and is never stored in the database.
+//! A secret saved in the database overrides the matching environment variable.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+use crate::AppData;
+use crate::contact::ContactOverrides;
+use crate::models::IntegrationSecret;
+use crate::web::errors::{ServiceError, ServiceResult};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum IntegrationSecretName {
+    FcmApiKey,
+    SmtpUsername,
+    SmtpPassword,
+}
+
+impl IntegrationSecretName {
+    pub fn from_key(name: &str) -> Option<IntegrationSecretName> {
+        match name {
+            "fcm_api_key" => Some(IntegrationSecretName::FcmApiKey),
+            "smtp_username" => Some(IntegrationSecretName::SmtpUsername),
+            "smtp_password" => Some(IntegrationSecretName::SmtpPassword),
+            _ => None,
+        }
+    }
+
+    pub fn to_key(self) -> &'static str {
+        match self {
+            IntegrationSecretName::FcmApiKey => "fcm_api_key",
+            IntegrationSecretName::SmtpUsername => "smtp_username",
+            IntegrationSecretName::SmtpPassword => "smtp_password",
+        }
+    }
+}
+
+/// Parses the key given in SECRETS_KEY
+pub fn parse_key(key: &str) -> Result<[u8; 32], String> {
+    let key = hex::decode(key).ok()
+        .filter(|x| x.len() == 32)
+        .ok_or_else(|| "it must be 64 hex characters".to_string())?;
+    let mut res = [0u8; 32];
+    res.copy_from_slice(&key);
+    Ok(res)
+}
+
+#[derive(Clone)]
+pub struct SecretBox {
+    key: [u8; 32],
+}
+
+impl SecretBox {
+    pub fn new(key: [u8; 32]) -> Self {
+        SecretBox { key }
+    }
+
+    /// Returns the random nonce and the ciphertext (with the authentication tag appended)
+    pub fn encrypt(&self, plaintext: &str) -> ServiceResult<(Vec<u8>, Vec<u8>)> {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        let mut tag = [0u8; TAG_LEN];
+        let mut ciphertext = rand_bytes(&mut nonce)
+            .and_then(|_| encrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(&nonce), &[], plaintext.as_bytes(), &mut tag))
+            .map_err(|x| ServiceError::InternalServerError(format!("Encryption error: {}", x)))?;
+        ciphertext.extend_from_slice(&tag);
+        Ok((nonce, ciphertext))
+    }
+
+    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> ServiceResult<String> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(ServiceError::InternalServerError("Truncated secret".to_string()))
+        }
+        let (data, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+        let plaintext = decrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(nonce), &[], data, tag)
+            .map_err(|_| ServiceError::InternalServerError("Cannot decrypt secret, wrong SECRETS_KEY?".to_string()))?;
+        String::from_utf8(plaintext)
+            .map_err(|_| ServiceError::InternalServerError("Invalid secret encoding".to_string()))
+    }
+}
+
+fn secret_box(app: &AppData) -> ServiceResult<&SecretBox> {
+    app.secret_box.as_ref()
+        .ok_or_else(|| ServiceError::BadRequest("Encrypted secrets are disabled, SECRETS_KEY is not set".to_string()))
+}
+
+pub fn store_secret(app: &AppData, name: IntegrationSecretName, value: &str) -> ServiceResult<()> {
+    use crate::schema::integration_secret::dsl;
+
+    let (nonce, ciphertext) = secret_box(app)?.encrypt(value)?;
+    let now = Utc::now().naive_utc();
+    let conn = app.pool.get()?;
+
+    diesel::insert_into(dsl::integration_secret)
+        .values((
+            dsl::name.eq(name.to_key()),
+            dsl::nonce.eq(&nonce),
+            dsl::ciphertext.eq(&ciphertext),
+            dsl::updated_at.eq(now),
+        ))
+        .on_conflict(dsl::name)
+        .do_update()
+        .set((
+            dsl::nonce.eq(&nonce),
+            dsl::ciphertext.eq(&ciphertext),
+            dsl::updated_at.eq(now),
+        ))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Deletes the secret, returns false if it wasn't stored
+pub fn delete_secret(app: &AppData, name: IntegrationSecretName) -> ServiceResult<bool> {
+    use crate::schema::integration_secret::dsl;
+
+    let conn = app.pool.get()?;
+    let deleted = diesel::delete(dsl::integration_secret.find(name.to_key()))
+        .execute(&conn)?;
+    Ok(deleted > 0)
+}
+
+pub fn list_secrets(app: &AppData) -> ServiceResult<Vec<IntegrationSecret>> {
+    use crate::schema::integration_secret::dsl;
+
+    let conn = app.pool.get()?;
+    Ok(dsl::integration_secret.order(dsl::name).load(&conn)?)
+}
+
+/// Decrypts the stored secrets and applies them to the contacter, the secrets that aren't stored
+/// fall back to the environment
+pub fn apply_secrets(app: &AppData) -> ServiceResult<()> {
+    let mut overrides = ContactOverrides::default();
+    if let Some(secret_box) = app.secret_box.as_ref() {
+        for secret in list_secrets(app)? {
+            let value = Some(secret_box.decrypt(&secret.nonce, &secret.ciphertext)?);
+            match IntegrationSecretName::from_key(&secret.name) {
+                Some(IntegrationSecretName::FcmApiKey) => overrides.fcm_api_key = value,
+                Some(IntegrationSecretName::SmtpUsername) => overrides.smtp_username = value,
+                Some(IntegrationSecretName::SmtpPassword) => overrides.smtp_password = value,
+                None => {},
+            }
+        }
+    }
+    app.contacter.apply_overrides(overrides);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_box() {
+        let secret_box = SecretBox::new([7; 32]);
+        let (nonce, ciphertext) = secret_box.encrypt("smtp password").unwrap();
+        assert_eq!(secret_box.decrypt(&nonce, &ciphertext).unwrap(), "smtp password");
+
+        // Any change to the ciphertext or to the key is detected
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(secret_box.decrypt(&nonce, &tampered).is_err());
+        assert!(SecretBox::new([8; 32]).decrypt(&nonce, &ciphertext).is_err());
+    }
+}
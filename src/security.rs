@@ -1,12 +1,22 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use argonautica::{Hasher, Verifier};
 use chrono::{prelude::*, Utc};
-use diesel::{prelude::*, result::DatabaseErrorKind, result::Error as DBError};
+use diesel::{pg::PgConnection, prelude::*, result::DatabaseErrorKind, result::Error as DBError};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
 use crate::AppData;
-use crate::models::{IdType, PermissionType, User, UserAccess};
+use crate::models::{AccessLevel, ApiKey, IdType, PermissionType, User, UserAccess, UserSession};
 use crate::schema::user_account;
 use crate::web::errors::{ServiceError, ServiceResult};
+use crate::web::login_throttle::{LoginSource, LoginThrottle};
 
 pub fn hash_password(secret_key: &str, password: &str) -> Result<String, ServiceError> {
     Hasher::default()
@@ -14,7 +24,8 @@ pub fn hash_password(secret_key: &str, password: &str) -> Result<String, Service
         .with_secret_key(secret_key)
         .hash()
         .map_err(|err| {
-            dbg!(err.clone());
+            // Only the error kind is logged, the hasher could still hold the password
+            error!("Hashing error: {}", err);
             ServiceError::InternalServerError(format!("Hashing error: {}", err))
         })
 }
@@ -25,11 +36,10 @@ pub fn verify_hash(secret_key: &str, hash: &str, password: &str) -> bool {
         .with_password(password)
         .with_secret_key(secret_key)
         .verify()
-        .map_err(|err| {
-            // TODO: better error log
-            dbg!(err)
+        .unwrap_or_else(|err| {
+            warn!("Password verification error: {}", err);
+            false
         })
-        .unwrap_or_else(|_| false)
 }
 
 #[derive(Insertable, AsChangeset)]
@@ -38,27 +48,97 @@ pub struct UserInputDb {
     pub username: Option<String>,
     pub password_hash: Option<String>,
     pub last_password_change: Option<chrono::NaiveDateTime>,
-    pub permission: Option<String>,
+    pub permission: Option<PermissionType>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct IdentityCookie {
     id: IdType,
     timestamp: NaiveDateTime,
+    /// Id of the server side session, the cookies without one are from before the sessions and
+    /// they're logged out
+    session: IdType,
+    /// Only its hash is stored in the session, a leaked database can't be used to forge cookies
+    secret: String,
+}
+
+/// Where a session has been opened from, shown to the user in the session list
+#[derive(Clone, Debug, Default)]
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+/// How often the last use of a session is written to the database, in seconds
+const SESSION_SEEN_RESOLUTION_SECS: i64 = 5 * 60;
+
+/// How long a bearer token is valid
+pub const TOKEN_LIFETIME_DAYS: i64 = 30;
+
+/// How long the token sent to verify an email is valid
+const EMAIL_TOKEN_LIFETIME_HOURS: i64 = 24;
+
+/// Header of every bearer token, they are JWTs signed with HMAC-SHA256
+const TOKEN_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenClaims {
+    /// User id
+    sub: IdType,
+    /// Last password change of the user (in milliseconds), a password change invalidates the
+    /// token like it does with the cookies
+    pwd: i64,
+    iat: i64,
+    exp: i64,
 }
 
+/// How long a loaded user is reused before reading it again from the database
+const USER_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Number of cached users after which the expired ones are evicted
+const USER_CACHE_EVICTION_SIZE: usize = 1024;
+
 #[derive(Clone)]
 pub struct AuthCache {
     password_secret_key: String,
+    /// Users loaded by id with their load time, every change to a user passes through the
+    /// AuthCache so the entries are invalidated on update and delete.
+    users: Arc<Mutex<HashMap<IdType, (Instant, User)>>>,
+    /// Sessions loaded by id, like the users they're invalidated when they're revoked (the other
+    /// servers see the revocation within USER_CACHE_TTL)
+    sessions: Arc<Mutex<HashMap<IdType, (Instant, UserSession)>>>,
+    /// Wrong passwords of the usernames and of the addresses
+    pub login_throttle: LoginThrottle,
 }
 
-impl AuthCache {// TODO, implement a cache
+impl AuthCache {
     pub fn new(password_secret_key: String) -> Self {
         AuthCache {
-            password_secret_key
+            password_secret_key,
+            users: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            login_throttle: LoginThrottle::default(),
         }
     }
 
+    fn cached_user(&self, id: IdType) -> Option<User> {
+        let users = self.users.lock().unwrap();
+        users.get(&id)
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < USER_CACHE_TTL)
+            .map(|(_, user)| user.clone())
+    }
+
+    fn cache_user(&self, user: &User) {
+        let mut users = self.users.lock().unwrap();
+        if users.len() >= USER_CACHE_EVICTION_SIZE {
+            users.retain(|_, (loaded_at, _)| loaded_at.elapsed() < USER_CACHE_TTL);
+        }
+        users.insert(user.id, (Instant::now(), user.clone()));
+    }
+
+    fn invalidate_user(&self, id: IdType) {
+        self.users.lock().unwrap().remove(&id);
+    }
+
     pub fn add_user(&self, ctx: &AppData, username: String, password: String, permission: PermissionType) -> ServiceResult<User> {
         use crate::schema::user_account::dsl;
 
@@ -69,7 +149,7 @@ pub fn add_user(&self, ctx: &AppData, username: String, password: String, permis
             username: Some(username),
             password_hash: Some(password_hash),
             last_password_change: Some(now),
-            permission: Some(permission.to_char().to_string()),
+            permission: Some(permission),
         };
 
         let conn = ctx.pool.get()?;
@@ -89,19 +169,56 @@ fn find_user_by_username(&self, ctx: &AppData, username: String) -> ServiceResul
     pub fn find_user_by_id(&self, ctx: &AppData, id: IdType) -> ServiceResult<Option<User>> {
         use crate::schema::user_account::dsl;
 
+        if let Some(user) = self.cached_user(id) {
+            return Ok(Some(user))
+        }
+
         let conn = ctx.pool.get()?;
-        Ok(dsl::user_account.find(id).first::<User>(&conn).optional()?)
+        let user = dsl::user_account.find(id).first::<User>(&conn).optional()?;
+        match &user {
+            Some(x) => self.cache_user(x),
+            None => self.invalidate_user(id),
+        }
+        Ok(user)
     }
 
-    pub fn verify_user(&self, ctx: &AppData, username: String, password: String) -> ServiceResult<User> {
+    /// Loads the users in the cache with a single query, returns how many have been found
+    pub fn preload_users(&self, ctx: &AppData, ids: &[IdType]) -> ServiceResult<usize> {
+        use crate::schema::user_account::dsl;
+
+        let conn = ctx.pool.get()?;
+        let users = dsl::user_account.filter(dsl::id.eq_any(ids)).load::<User>(&conn)?;
+        for user in users.iter() {
+            self.cache_user(user);
+        }
+        Ok(users.len())
+    }
+
+    /// Checks the password of the user, the address (if known) is throttled together with the
+    /// username after the wrong passwords (see the login_throttle module)
+    pub fn verify_user(&self, ctx: &AppData, username: String, password: String, address: Option<IpAddr>) -> ServiceResult<User> {
+        let mut sources = vec![LoginSource::username(&username)];
+        sources.extend(address.map(LoginSource::Address));
+        let now = Instant::now();
+        if let Some(wait) = self.login_throttle.wait_time(now, &sources) {
+            let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+            return Err(ServiceError::LoginThrottled(secs))
+        }
+
+        // The unknown usernames are counted too, they're how the usernames are guessed
         let user = match self.find_user_by_username(ctx, username)? {
-            None => return Err(ServiceError::NotFound("username".to_string())),
+            None => {
+                self.login_throttle.record_failure(now, &sources);
+                return Err(ServiceError::NotFound("username".to_string()))
+            },
             Some(u) => u
         };
 
         if !verify_hash(self.password_secret_key.as_str(), user.password_hash.as_str(), password.as_str()) {
+            self.login_throttle.record_failure(now, &sources);
             Err(ServiceError::WrongPassword)
         } else {
+            self.login_throttle.record_success(&sources);
             Ok(user)
         }
     }
@@ -121,20 +238,82 @@ pub fn update_user(&self, ctx: &AppData, id: IdType, username: Option<String>, p
             username,
             password_hash: new_passw_hash,
             last_password_change: new_change_time,
-            permission: permission.map(|x| x.to_char().to_string())
+            permission,
         };
 
         let conn = ctx.pool.get()?;
 
-        Ok(diesel::update(dsl::user_account.find(id))
+        // Invalidated before the update so that a failure can't leave a stale user in the cache
+        self.invalidate_user(id);
+        let user: User = diesel::update(dsl::user_account.find(id))
             .set(&data)
-            .get_result(&conn)?)
+            .get_result(&conn)?;
+        self.cache_user(&user);
+        std::mem::drop(conn);
+
+        // The password change already logs out the old cookies, their sessions are useless
+        if new_change_time.is_some() {
+            self.revoke_sessions(ctx, id, None)?;
+        }
+        Ok(user)
+    }
+
+    /// Replaces the email of the user (None removes it), the email stays unverified until
+    /// verify_email is called with the returned token
+    pub fn set_email(&self, ctx: &AppData, id: IdType, email: Option<String>) -> ServiceResult<(User, Option<String>)> {
+        use crate::schema::user_account::dsl;
+
+        let token = email.as_ref().map(|_| Uuid::new_v4().to_simple().to_string());
+        let now = Utc::now().naive_utc();
+        let conn = ctx.pool.get()?;
+
+        self.invalidate_user(id);
+        let user: User = diesel::update(dsl::user_account.find(id))
+            .set((
+                dsl::email.eq(email),
+                dsl::email_verified_at.eq(None::<NaiveDateTime>),
+                dsl::email_token_hash.eq(token.as_deref().map(hash_api_key)),
+                dsl::email_token_sent_at.eq(token.as_ref().map(|_| now)),
+            ))
+            .get_result(&conn)?;
+        self.cache_user(&user);
+        Ok((user, token))
+    }
+
+    /// Marks the email of the user as verified if the token is the last one sent to it
+    pub fn verify_email(&self, ctx: &AppData, id: IdType, token: &str) -> ServiceResult<User> {
+        use crate::schema::user_account::dsl;
+
+        let conn = ctx.pool.get()?;
+        // Not taken from the cache, the token could have been sent by another server
+        let user = dsl::user_account.find(id).first::<User>(&conn)?;
+        let now = Utc::now().naive_utc();
+        let valid = match (&user.email_token_hash, user.email_token_sent_at) {
+            (Some(hash), Some(sent_at)) => *hash == hash_api_key(token) &&
+                now - sent_at < chrono::Duration::hours(EMAIL_TOKEN_LIFETIME_HOURS),
+            _ => false,
+        };
+        if !valid {
+            return Err(ServiceError::BadRequest("Invalid or expired token".to_string()))
+        }
+
+        self.invalidate_user(id);
+        let user: User = diesel::update(dsl::user_account.find(id))
+            .set((
+                dsl::email_verified_at.eq(Some(now)),
+                dsl::email_token_hash.eq(None::<String>),
+                dsl::email_token_sent_at.eq(None::<NaiveDateTime>),
+            ))
+            .get_result(&conn)?;
+        self.cache_user(&user);
+        Ok(user)
     }
 
     pub fn delete_user(&self, ctx: &AppData, id: IdType) -> ServiceResult<()> {
         use crate::schema::user_account::dsl;
         let conn = ctx.pool.get()?;
 
+        self.invalidate_user(id);
         let del_count = diesel::delete(dsl::user_account.find(id))
             .execute(&conn)?;
 
@@ -145,14 +324,14 @@ pub fn delete_user(&self, ctx: &AppData, id: IdType) -> ServiceResult<()> {
         }
     }
 
-    pub fn give_access(&self, ctx: &AppData, user_id: IdType, site_id: IdType) -> ServiceResult<()> {
+    /// Gives access to the site, if the user already has access only the level is changed
+    pub fn give_access(&self, conn: &PgConnection, user_id: IdType, site_id: IdType, level: AccessLevel) -> ServiceResult<()> {
         use crate::schema::user_access::dsl;
-        let conn = ctx.pool.get()?;
 
         let inserted = diesel::insert_into(dsl::user_access)
-            .values(UserAccess { user_id, site_id })
+            .values(UserAccess { user_id, site_id, level })
             .on_conflict_do_nothing()
-            .execute(&conn);
+            .execute(conn);
 
 
         match inserted {
@@ -163,24 +342,28 @@ pub fn give_access(&self, ctx: &AppData, user_id: IdType, site_id: IdType) -> Se
             Err(x) => {
                 Err(x.into())
             },
-            Ok(insert_count) => {
-                if insert_count == 0 {
+            Ok(0) => {
+                let updated = diesel::update(dsl::user_access.find((user_id, site_id)))
+                    .filter(dsl::level.ne(level))
+                    .set(dsl::level.eq(level))
+                    .execute(conn)?;
+                if updated == 0 {
                     Err(ServiceError::AlreadyPresent("Access".to_string()))
                 } else {
                     Ok(())
                 }
             },
+            Ok(_) => Ok(()),
         }
     }
 
-    pub fn revoke_access(&self, ctx: &AppData, user_id: IdType, site_id: IdType) -> ServiceResult<()>{
+    pub fn revoke_access(&self, conn: &PgConnection, user_id: IdType, site_id: IdType) -> ServiceResult<()>{
         use crate::schema::user_access::dsl;
-        let conn = ctx.pool.get()?;
 
         let deleted_count = diesel::delete(dsl::user_access)
             .filter(dsl::user_id.eq(user_id))
             .filter(dsl::site_id.eq(site_id))
-            .execute(&conn)?;
+            .execute(conn)?;
 
         if deleted_count == 0 {
             Err(ServiceError::NotFound("user or site".to_string()))
@@ -203,20 +386,167 @@ pub fn has_access(&self, ctx: &AppData, user_id: IdType, site_id: IdType) -> Ser
     }
 
     pub fn ensure_access(&self, ctx: &AppData, user_id: IdType, site_id: IdType) -> ServiceResult<()> {
-        if !self.has_access(ctx, user_id, site_id)? {
-            Err(ServiceError::Unauthorized)
+        let visible = self.has_access(ctx, user_id, site_id)?;
+        ctx.access_policy.check_visible("Site", visible)
+    }
+
+    fn url_mac(&self, data: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(self.password_secret_key.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.input(data.as_bytes());
+        mac
+    }
+
+    /// Signs the data (usually an url path) so that it can be shared without requiring a login
+    pub fn sign_url(&self, data: &str) -> String {
+        hex::encode(self.url_mac(data).result().code())
+    }
+
+    pub fn verify_url_signature(&self, data: &str, signature: &str) -> bool {
+        match hex::decode(signature) {
+            Ok(signature) => self.url_mac(data).verify(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn token_mac(&self, data: &str) -> Hmac<Sha256> {
+        // Derived from the url key so that an url signature can never be used as a token one
+        let key = self.url_mac("bearer-token").result().code();
+        let mut mac = Hmac::<Sha256>::new_varkey(&key)
+            .expect("HMAC accepts keys of any size");
+        mac.input(data.as_bytes());
+        mac
+    }
+
+    /// Creates a bearer token (JWT) for the user, returning it with its expiration
+    pub fn create_token(&self, user: &User) -> (String, NaiveDateTime) {
+        let now = Utc::now().naive_utc();
+        let expiration = now + chrono::Duration::days(TOKEN_LIFETIME_DAYS);
+        let claims = TokenClaims {
+            sub: user.id,
+            pwd: user.last_password_change.timestamp_millis(),
+            iat: now.timestamp(),
+            exp: expiration.timestamp(),
+        };
+
+        let encode = |x: &[u8]| base64::encode_config(x, base64::URL_SAFE_NO_PAD);
+        let payload = format!(
+            "{}.{}",
+            encode(TOKEN_HEADER.as_bytes()),
+            encode(serde_json::to_string(&claims).unwrap().as_bytes())
+        );
+        let signature = encode(&self.token_mac(&payload).result().code());
+        (format!("{}.{}", payload, signature), expiration)
+    }
+
+    /// Returns the user of the bearer token, None if the token is invalid or expired
+    pub fn parse_token(&self, ctx: &AppData, token: &str) -> ServiceResult<Option<User>> {
+        let decode = |x: &str| base64::decode_config(x, base64::URL_SAFE_NO_PAD).ok();
+
+        let (payload, signature) = match token.rfind('.') {
+            Some(index) => (&token[..index], &token[index + 1..]),
+            None => return Ok(None),
+        };
+        let valid_signature = decode(signature)
+            .map(|x| self.token_mac(payload).verify(&x).is_ok())
+            .unwrap_or(false);
+        if !valid_signature {
+            return Ok(None)
+        }
+
+        // The header is signed by us, there's no need to check it
+        let claims: Option<TokenClaims> = payload.split('.').nth(1)
+            .and_then(decode)
+            .and_then(|x| serde_json::from_slice(&x).ok());
+        let claims = match claims {
+            Some(x) if x.exp > Utc::now().timestamp() => x,
+            _ => return Ok(None),
+        };
+
+        let user = match self.find_user_by_id(ctx, claims.sub)? {
+            None => return Ok(None),
+            Some(u) => u,
+        };
+        if user.last_password_change.timestamp_millis() > claims.pwd {
+            Ok(None)
         } else {
-            Ok(())
+            Ok(Some(user))
         }
     }
 
-    pub fn save_identity(&self, user: &User) -> String {
-        serde_json::to_string(&IdentityCookie {
+    /// Opens a new session for the user, returns the identity to store in its cookie
+    pub fn open_session(&self, ctx: &AppData, user: &User, client: &SessionClient) -> ServiceResult<String> {
+        use crate::schema::user_session::dsl;
+
+        let secret = format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple());
+        let now = Utc::now().naive_utc();
+        let conn = ctx.pool.get()?;
+        let session: UserSession = diesel::insert_into(dsl::user_session)
+            .values((
+                dsl::user_id.eq(user.id),
+                dsl::secret_hash.eq(hash_api_key(&secret)),
+                dsl::user_agent.eq(client.user_agent.as_ref().map(|x| x.chars().take(255).collect::<String>())),
+                dsl::address.eq(client.address.map(|x| x.to_string())),
+                dsl::created_at.eq(now),
+                dsl::last_seen_at.eq(now),
+            ))
+            .get_result(&conn)?;
+
+        let cookie = IdentityCookie {
             id: user.id,
             timestamp: user.last_password_change,
-        }).unwrap()
+            session: session.id,
+            secret,
+        };
+        self.sessions.lock().unwrap().insert(session.id, (Instant::now(), session));
+        Ok(serde_json::to_string(&cookie).unwrap())
+    }
+
+    fn find_session(&self, ctx: &AppData, id: IdType) -> ServiceResult<Option<UserSession>> {
+        use crate::schema::user_session::dsl;
+
+        let cached = self.sessions.lock().unwrap().get(&id)
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < USER_CACHE_TTL)
+            .map(|(_, session)| session.clone());
+        if cached.is_some() {
+            return Ok(cached)
+        }
+
+        let conn = ctx.pool.get()?;
+        let session = dsl::user_session.find(id)
+            .first::<UserSession>(&conn)
+            .optional()?;
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= USER_CACHE_EVICTION_SIZE {
+            sessions.retain(|_, (loaded_at, _)| loaded_at.elapsed() < USER_CACHE_TTL);
+        }
+        match &session {
+            Some(x) => sessions.insert(id, (Instant::now(), x.clone())),
+            None => sessions.remove(&id),
+        };
+        Ok(session)
+    }
+
+    /// Records the use of the session, at most once every SESSION_SEEN_RESOLUTION_SECS
+    fn touch_session(&self, ctx: &AppData, session: &UserSession) -> ServiceResult<()> {
+        use crate::schema::user_session::dsl;
+
+        let now = Utc::now().naive_utc();
+        if now - session.last_seen_at < chrono::Duration::seconds(SESSION_SEEN_RESOLUTION_SECS) {
+            return Ok(())
+        }
+        let conn = ctx.pool.get()?;
+        diesel::update(dsl::user_session.find(session.id))
+            .set(dsl::last_seen_at.eq(now))
+            .execute(&conn)?;
+        if let Some((_, cached)) = self.sessions.lock().unwrap().get_mut(&session.id) {
+            cached.last_seen_at = now;
+        }
+        Ok(())
     }
 
+    /// Returns the user of the identity cookie, None if the cookie is invalid, its password has
+    /// been changed or its session has been revoked
     pub fn parse_identity(&self, ctx: &AppData, identity: &str) -> ServiceResult<Option<User>> {
         let cookie: Option<IdentityCookie> = serde_json::from_str(identity).ok();
         let cookie = match cookie {
@@ -229,13 +559,66 @@ pub fn parse_identity(&self, ctx: &AppData, identity: &str) -> ServiceResult<Opt
             Some(u) => u,
         };
         if user.last_password_change > cookie.timestamp {
-            Ok(None)
-        } else {
-            Ok(Some(user))
+            return Ok(None)
         }
+
+        let session = match self.find_session(ctx, cookie.session)? {
+            Some(x) if x.user_id == user.id && x.secret_hash == hash_api_key(&cookie.secret) => x,
+            _ => return Ok(None),
+        };
+        self.touch_session(ctx, &session)?;
+        Ok(Some(user))
+    }
+
+    /// Id of the session of the identity cookie, it isn't checked
+    pub fn identity_session(&self, identity: &str) -> Option<IdType> {
+        serde_json::from_str::<IdentityCookie>(identity).ok().map(|x| x.session)
+    }
+
+    /// Deletes a session of the user (or all of them if it's None), their cookies are logged out.
+    /// Returns the number of deleted sessions
+    pub fn revoke_sessions(&self, ctx: &AppData, user_id: IdType, session_id: Option<IdType>) -> ServiceResult<usize> {
+        use crate::schema::user_session::dsl;
+
+        let conn = ctx.pool.get()?;
+        let deleted = match session_id {
+            Some(id) => diesel::delete(dsl::user_session.filter(dsl::user_id.eq(user_id)).filter(dsl::id.eq(id)))
+                .execute(&conn)?,
+            None => diesel::delete(dsl::user_session.filter(dsl::user_id.eq(user_id)))
+                .execute(&conn)?,
+        };
+        self.sessions.lock().unwrap()
+            .retain(|id, (_, x)| x.user_id != user_id || session_id.map_or(false, |revoked| revoked != *id));
+        Ok(deleted)
     }
 }
 
+/// Header used by the services (dashboards, scripts) to authenticate with an api key
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Only the hash of the api keys is stored, they're random so there's no need for a slow hash
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Returns the api key if it exists and it hasn't been revoked
+pub fn find_api_key(ctx: &AppData, key: &str) -> ServiceResult<Option<ApiKey>> {
+    use crate::schema::api_key::dsl;
+
+    let conn = ctx.pool.get()?;
+    Ok(dsl::api_key
+        .filter(dsl::key_hash.eq(hash_api_key(key)))
+        .filter(dsl::revoked_at.is_null())
+        .first::<ApiKey>(&conn)
+        .optional()?)
+}
+
+/// Who is making an HTTP request, the api keys can only be used to read data
+pub enum Principal {
+    User(User),
+    ApiKey(ApiKey),
+}
+
 pub trait PermissionCheckable {
     fn get_permission(&self) -> PermissionType;
 
@@ -252,16 +635,57 @@ fn ensure_admin(&self) -> ServiceResult<()> {
     fn ensure_sensor_visible(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResult<()>;
 
     fn ensure_channel_visible(&self, ctx: &AppData, channel_id: IdType) -> ServiceResult<()>;
+
+    /// Checks the access level in the site, the decision is taken by the access policy of the app
+    fn ensure_site_level(&self, ctx: &AppData, site_id: IdType, level: AccessLevel) -> ServiceResult<()>;
+
+    fn ensure_site_manager(&self, ctx: &AppData, site_id: IdType) -> ServiceResult<()> {
+        self.ensure_site_level(ctx, site_id, AccessLevel::Manage)
+    }
+
+    fn ensure_sensor_manager(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResult<()> {
+        self.ensure_site_level(ctx, sensor_site_id(ctx, sensor_id)?, AccessLevel::Manage)
+    }
+
+    fn ensure_channel_manager(&self, ctx: &AppData, channel_id: IdType) -> ServiceResult<()> {
+        self.ensure_site_level(ctx, channel_site_id(ctx, channel_id)?, AccessLevel::Manage)
+    }
+}
+
+fn sensor_site_id(ctx: &AppData, sensor_id: IdType) -> ServiceResult<IdType> {
+    use crate::schema::sensor::dsl as sensor_dsl;
+    let conn = ctx.pool.get()?;
+
+    sensor_dsl::sensor
+        .find(sensor_id)
+        .select(sensor_dsl::site_id)
+        .first::<IdType>(&conn)
+        .optional()?
+        .ok_or_else(|| ctx.access_policy.hidden("Sensor"))
+}
+
+pub(crate) fn channel_site_id(ctx: &AppData, channel_id: IdType) -> ServiceResult<IdType> {
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+    let conn = ctx.pool.get()?;
+
+    channel_dsl::channel
+        .inner_join(sensor_dsl::sensor)
+        .filter(channel_dsl::id.eq(channel_id))
+        .select(sensor_dsl::site_id)
+        .first::<IdType>(&conn)
+        .optional()?
+        .ok_or_else(|| ctx.access_policy.hidden("Channel"))
 }
 
 impl PermissionCheckable for User {
     fn get_permission(&self) -> PermissionType {
-        PermissionType::from_char(self.permission.as_str()).unwrap_or(PermissionType::User)
+        self.permission
     }
 
     fn ensure_site_visible(&self, ctx: &AppData, site_id: IdType) -> ServiceResult<()> {
         use crate::schema::user_access::dsl;
-        if PermissionType::from_char(self.permission.as_str()) .unwrap_or(PermissionType::User) == PermissionType::Admin {
+        if self.permission == PermissionType::Admin {
             return Ok(())
         }
         let conn = ctx.pool.get()?;
@@ -271,17 +695,13 @@ fn ensure_site_visible(&self, ctx: &AppData, site_id: IdType) -> ServiceResult<(
             .filter(dsl::site_id.eq(site_id))
             .get_result(&conn)?;
 
-        if count == 0 {
-            Err(ServiceError::NotFound("Site".to_string()))
-        } else {
-            Ok(())
-        }
+        ctx.access_policy.check_visible("Site", count != 0)
     }
 
     fn ensure_sensor_visible(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResult<()> {
         use crate::schema::user_access::dsl;
         use crate::schema::sensor::dsl as sensor_dsl;
-        if PermissionType::from_char(self.permission.as_str()) .unwrap_or(PermissionType::User) == PermissionType::Admin {
+        if self.permission == PermissionType::Admin {
             return Ok(())
         }
         let conn = ctx.pool.get()?;
@@ -296,18 +716,14 @@ fn ensure_sensor_visible(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResu
             .filter(dsl::site_id.nullable().eq(site_id))
             .get_result(&conn)?;
 
-        if count == 0 {
-            Err(ServiceError::NotFound("Sensor".to_string()))
-        } else {
-            Ok(())
-        }
+        ctx.access_policy.check_visible("Sensor", count != 0)
     }
 
     fn ensure_channel_visible(&self, ctx: &AppData, channel_id: IdType) -> ServiceResult<()> {
         use crate::schema::user_access::dsl;
         use crate::schema::sensor::dsl as sensor_dsl;
         use crate::schema::channel::dsl as channel_dsl;
-        if PermissionType::from_char(self.permission.as_str()) .unwrap_or(PermissionType::User) == PermissionType::Admin {
+        if self.permission == PermissionType::Admin {
             return Ok(())
         }
         let conn = ctx.pool.get()?;
@@ -327,12 +743,121 @@ fn ensure_channel_visible(&self, ctx: &AppData, channel_id: IdType) -> ServiceRe
             .filter(dsl::site_id.nullable().eq(site_id))
             .get_result(&conn)?;
 
-        if count == 0 {
-            Err(ServiceError::NotFound("Channel".to_string()))
-        } else {
-            Ok(())
+        ctx.access_policy.check_visible("Channel", count != 0)
+    }
+
+    fn ensure_site_level(&self, ctx: &AppData, site_id: IdType, level: AccessLevel) -> ServiceResult<()> {
+        use crate::schema::user_access::dsl;
+        let permission = self.get_permission();
+        if permission == PermissionType::Admin {
+            return Ok(())
         }
+        let conn = ctx.pool.get()?;
+
+        let granted = dsl::user_access
+            .filter(dsl::user_id.eq(self.id))
+            .filter(dsl::site_id.eq(site_id))
+            .select(dsl::level)
+            .first::<AccessLevel>(&conn)
+            .optional()?
+            .map(|granted| {
+                // The site managers can manage every site they have access to
+                if permission == PermissionType::SiteManager && granted < AccessLevel::Manage {
+                    AccessLevel::Manage
+                } else {
+                    granted
+                }
+            });
+
+        ctx.access_policy.check_level("Site", granted, level)
+    }
+}
+
+impl PermissionCheckable for ApiKey {
+    fn get_permission(&self) -> PermissionType {
+        PermissionType::User
+    }
+
+    fn ensure_site_visible(&self, ctx: &AppData, site_id: IdType) -> ServiceResult<()> {
+        ctx.access_policy.check_visible("Site", self.site_id.map_or(true, |x| x == site_id))
+    }
+
+    fn ensure_sensor_visible(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResult<()> {
+        use crate::schema::sensor::dsl as sensor_dsl;
+        let key_site_id = match self.site_id {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+        let conn = ctx.pool.get()?;
+
+        let site_id = sensor_dsl::sensor
+            .find(sensor_id)
+            .select(sensor_dsl::site_id)
+            .first::<IdType>(&conn)
+            .optional()?;
+
+        ctx.access_policy.check_visible("Sensor", site_id == Some(key_site_id))
+    }
+
+    fn ensure_channel_visible(&self, ctx: &AppData, channel_id: IdType) -> ServiceResult<()> {
+        use crate::schema::sensor::dsl as sensor_dsl;
+        use crate::schema::channel::dsl as channel_dsl;
+        let key_site_id = match self.site_id {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+        let conn = ctx.pool.get()?;
+
+        let site_id = channel_dsl::channel
+            .inner_join(sensor_dsl::sensor)
+            .filter(channel_dsl::id.eq(channel_id))
+            .select(sensor_dsl::site_id)
+            .first::<IdType>(&conn)
+            .optional()?;
+
+        ctx.access_policy.check_visible("Channel", site_id == Some(key_site_id))
+    }
+
+    fn ensure_site_level(&self, ctx: &AppData, site_id: IdType, level: AccessLevel) -> ServiceResult<()> {
+        // The api keys can only read data
+        let visible = self.site_id.map_or(true, |x| x == site_id);
+        ctx.access_policy.check_level("Site", Some(AccessLevel::View).filter(|_| visible), level)
     }
 }
 
+impl PermissionCheckable for Principal {
+    fn get_permission(&self) -> PermissionType {
+        match self {
+            Principal::User(x) => x.get_permission(),
+            Principal::ApiKey(x) => x.get_permission(),
+        }
+    }
+
+    fn ensure_site_visible(&self, ctx: &AppData, site_id: IdType) -> ServiceResult<()> {
+        match self {
+            Principal::User(x) => x.ensure_site_visible(ctx, site_id),
+            Principal::ApiKey(x) => x.ensure_site_visible(ctx, site_id),
+        }
+    }
+
+    fn ensure_sensor_visible(&self, ctx: &AppData, sensor_id: IdType) -> ServiceResult<()> {
+        match self {
+            Principal::User(x) => x.ensure_sensor_visible(ctx, sensor_id),
+            Principal::ApiKey(x) => x.ensure_sensor_visible(ctx, sensor_id),
+        }
+    }
 
+    fn ensure_channel_visible(&self, ctx: &AppData, channel_id: IdType) -> ServiceResult<()> {
+        match self {
+            Principal::User(x) => x.ensure_channel_visible(ctx, channel_id),
+            Principal::ApiKey(x) => x.ensure_channel_visible(ctx, channel_id),
+        }
+    }
+
+    fn ensure_site_level(&self, ctx: &AppData, site_id: IdType, level: AccessLevel) -> ServiceResult<()> {
+        match self {
+            Principal::User(x) => x.ensure_site_level(ctx, site_id, level),
+            Principal::ApiKey(x) => x.ensure_site_level(ctx, site_id, level),
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Forwarding of the audit log entries and of the alarm events to the SIEM of the institution, so
+//! that its security team can ingest them without access to the database.
+//!
+//! `SIEM_SYSLOG_ADDR` (host:port) sends every event as a RFC 5424 syslog datagram over UDP, while
+//! `SIEM_HTTP_URL` posts it to a log shipping endpoint. `SIEM_FORMAT` chooses between `json`
+//! (the default) and `cef` (ArcSight Common Event Format).
+//! The events are sent by a background thread, when the queue is full they are dropped (the audit
+//! log in the database stays complete).
+
+use std::net::UdpSocket;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::Resolver;
+use crate::contact::MeasureExtremeType;
+use crate::models::IdType;
+
+/// Events waiting to be sent before the new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Syslog facility of the messages (local0)
+const SYSLOG_FACILITY: u8 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SiemFormat {
+    Json,
+    Cef,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SiemTarget {
+    /// Address of a syslog collector listening on UDP
+    Syslog(String),
+    /// Url of a log shipping endpoint, every event is posted on its own
+    Http(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct SiemConfig {
+    pub target: SiemTarget,
+    pub format: SiemFormat,
+}
+
+/// `[siem]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiemSection {
+    pub syslog_addr: Option<String>,
+    pub http_url: Option<String>,
+    /// json (the default) or cef
+    pub format: Option<String>,
+}
+
+impl SiemConfig {
+    /// Reads the configuration, returns None if the forwarding is disabled
+    pub fn resolve(r: &mut Resolver, file: SiemSection) -> Option<SiemConfig> {
+        let syslog_addr = r.optional("SIEM_SYSLOG_ADDR", file.syslog_addr);
+        let http_url = r.optional("SIEM_HTTP_URL", file.http_url);
+        let format = r.parsed("SIEM_FORMAT", file.format, |x| match x {
+            "cef" => Ok(SiemFormat::Cef),
+            "json" => Ok(SiemFormat::Json),
+            _ => Err(format!("unknown format {}, it should be json or cef", x)),
+        });
+        let target = match (syslog_addr, http_url) {
+            (Some(addr), _) => SiemTarget::Syslog(addr),
+            (_, Some(url)) => SiemTarget::Http(url),
+            _ => return None,
+        };
+        Some(SiemConfig { target, format: format.unwrap_or(SiemFormat::Json) })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SiemEvent {
+    /// An entry of the audit log
+    Audit {
+        time: NaiveDateTime,
+        user_id: Option<IdType>,
+        username: String,
+        action: String,
+        target: String,
+    },
+    AlarmBegin {
+        time: NaiveDateTime,
+        channel_id: IdType,
+        value: f64,
+        extreme: MeasureExtremeType,
+    },
+    AlarmEnd {
+        time: NaiveDateTime,
+        channel_id: IdType,
+    },
+}
+
+impl SiemEvent {
+    fn time(&self) -> NaiveDateTime {
+        match self {
+            SiemEvent::Audit { time, .. } | SiemEvent::AlarmBegin { time, .. } | SiemEvent::AlarmEnd { time, .. } => *time,
+        }
+    }
+
+    /// Identifier of the kind of event (the CEF signature id)
+    fn kind(&self) -> &'static str {
+        match self {
+            SiemEvent::Audit { .. } => "audit",
+            SiemEvent::AlarmBegin { .. } => "alarm_begin",
+            SiemEvent::AlarmEnd { .. } => "alarm_end",
+        }
+    }
+
+    /// Syslog severity, the beginning of an alarm is a warning
+    fn syslog_severity(&self) -> u8 {
+        match self {
+            SiemEvent::AlarmBegin { .. } => 4,
+            _ => 5,
+        }
+    }
+
+    fn cef_severity(&self) -> u8 {
+        match self {
+            SiemEvent::AlarmBegin { .. } => 7,
+            _ => 3,
+        }
+    }
+}
+
+fn format_time(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn extreme_name(extreme: MeasureExtremeType) -> &'static str {
+    match extreme {
+        MeasureExtremeType::Min => "min",
+        MeasureExtremeType::Max => "max",
+        MeasureExtremeType::Delta => "delta",
+    }
+}
+
+pub fn format_json(event: &SiemEvent) -> String {
+    let mut value = match event {
+        SiemEvent::Audit { user_id, username, action, target, .. } => json!({
+            "user_id": user_id,
+            "username": username,
+            "action": action,
+            "target": target,
+        }),
+        SiemEvent::AlarmBegin { channel_id, value, extreme, .. } => json!({
+            "channel_id": channel_id,
+            "value": value,
+            "extreme": extreme_name(*extreme),
+        }),
+        SiemEvent::AlarmEnd { channel_id, .. } => json!({
+            "channel_id": channel_id,
+        }),
+    };
+    value["type"] = json!(event.kind());
+    value["time"] = json!(format_time(event.time()));
+    value.to_string()
+}
+
+fn escape_cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn escape_cef_extension(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+pub fn format_cef(event: &SiemEvent) -> String {
+    let (name, extensions) = match event {
+        SiemEvent::Audit { user_id, username, action, target, .. } => (
+            format!("Admin action {}", action),
+            vec![
+                ("suid", user_id.map(|x| x.to_string()).unwrap_or_default()),
+                ("suser", username.clone()),
+                ("act", action.clone()),
+                ("msg", target.clone()),
+            ],
+        ),
+        SiemEvent::AlarmBegin { channel_id, value, extreme, .. } => (
+            "Alarm began".to_string(),
+            vec![
+                ("cs1Label", "channelId".to_string()),
+                ("cs1", channel_id.to_string()),
+                ("cfp1Label", "value".to_string()),
+                ("cfp1", value.to_string()),
+                ("cs2Label", "extreme".to_string()),
+                ("cs2", extreme_name(*extreme).to_string()),
+            ],
+        ),
+        SiemEvent::AlarmEnd { channel_id, .. } => (
+            "Alarm ended".to_string(),
+            vec![
+                ("cs1Label", "channelId".to_string()),
+                ("cs1", channel_id.to_string()),
+            ],
+        ),
+    };
+
+    let extensions: Vec<String> = std::iter::once(("rt", event.time().timestamp_millis().to_string()))
+        .chain(extensions)
+        .map(|(key, value)| format!("{}={}", key, escape_cef_extension(&value)))
+        .collect();
+    format!(
+        "CEF:0|OldMusa|oldmusa-server|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"), event.kind(), escape_cef_header(&name), event.cef_severity(), extensions.join(" ")
+    )
+}
+
+/// Wraps the message in a RFC 5424 syslog frame
+fn syslog_frame(severity: u8, time: NaiveDateTime, hostname: &str, message: &str) -> String {
+    format!(
+        "<{}>1 {} {} oldmusa {} - - {}",
+        SYSLOG_FACILITY * 8 + severity, format_time(time), hostname, std::process::id(), message
+    )
+}
+
+struct QueuedEvent {
+    severity: u8,
+    time: NaiveDateTime,
+    message: String,
+}
+
+/// Queue of the events to forward, disabled sinks ignore every event
+#[derive(Clone, Default)]
+pub struct SiemSink {
+    sender: Option<(SiemFormat, SyncSender<QueuedEvent>)>,
+}
+
+impl SiemSink {
+    /// Starts the thread that sends the events to the target
+    pub fn new(config: SiemConfig) -> SiemSink {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let SiemConfig { target, format } = config;
+        std::thread::Builder::new()
+            .name("siem".to_string())
+            .spawn(move || {
+                let res = match target {
+                    SiemTarget::Syslog(addr) => run_syslog(&addr, receiver),
+                    SiemTarget::Http(url) => run_http(&url, format, receiver),
+                };
+                if let Err(e) = res {
+                    error!("SIEM forwarding stopped: {}", e);
+                }
+            })
+            .expect("Cannot start the SIEM thread");
+        SiemSink { sender: Some((format, sender)) }
+    }
+
+    pub fn send(&self, event: SiemEvent) {
+        let (format, sender) = match &self.sender {
+            Some(x) => x,
+            None => return,
+        };
+        let message = match format {
+            SiemFormat::Json => format_json(&event),
+            SiemFormat::Cef => format_cef(&event),
+        };
+        let queued = QueuedEvent { severity: event.syslog_severity(), time: event.time(), message };
+        match sender.try_send(queued) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => warn!("SIEM queue full, {} event dropped", event.kind()),
+            Err(TrySendError::Disconnected(_)) => warn!("SIEM forwarding stopped, {} event dropped", event.kind()),
+        }
+    }
+}
+
+fn run_syslog(addr: &str, receiver: Receiver<QueuedEvent>) -> Result<(), String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|x| x.to_string())?;
+    let mut buffer = [0u8; 256];
+    let hostname = nix::unistd::gethostname(&mut buffer).ok()
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty())
+        .unwrap_or("-")
+        .to_string();
+
+    for event in receiver {
+        let frame = syslog_frame(event.severity, event.time, &hostname, &event.message);
+        if let Err(e) = socket.send_to(frame.as_bytes(), addr) {
+            warn!("Cannot send event to syslog {}: {}", addr, e);
+        }
+    }
+    Ok(())
+}
+
+fn run_http(url: &str, format: SiemFormat, receiver: Receiver<QueuedEvent>) -> Result<(), String> {
+    let mut runtime = actix_rt::Runtime::new().map_err(|x| x.to_string())?;
+    let client: Client<_, Body> = Client::builder().build(HttpsConnector::new());
+    let content_type = match format {
+        SiemFormat::Json => "application/json",
+        SiemFormat::Cef => "text/plain",
+    };
+
+    for event in receiver {
+        let request = Request::post(url)
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            .body(Body::from(event.message))
+            .map_err(|x| format!("Invalid SIEM url {}: {}", url, x))?;
+        let res = runtime.block_on(actix_rt::time::timeout(HTTP_TIMEOUT, client.request(request)));
+        match res {
+            Ok(Ok(x)) if x.status().is_success() => {},
+            Ok(Ok(x)) => warn!("SIEM endpoint {} rejected the event: {}", url, x.status()),
+            Ok(Err(e)) => warn!("Cannot send event to {}: {}", url, e),
+            Err(_) => warn!("Cannot send event to {}: timed out", url),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn audit() -> SiemEvent {
+        SiemEvent::Audit {
+            time: NaiveDate::from_ymd(2020, 4, 1).and_hms(10, 30, 0),
+            user_id: Some(3),
+            username: "mario".to_string(),
+            action: "deleteSite".to_string(),
+            target: "site 4 | a=b".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let value: serde_json::Value = serde_json::from_str(&format_json(&audit())).unwrap();
+        assert_eq!(value, json!({
+            "type": "audit",
+            "time": "2020-04-01T10:30:00.000Z",
+            "user_id": 3,
+            "username": "mario",
+            "action": "deleteSite",
+            "target": "site 4 | a=b",
+        }));
+
+        let alarm = SiemEvent::AlarmBegin {
+            time: NaiveDate::from_ymd(2020, 4, 1).and_hms(10, 30, 0),
+            channel_id: 7,
+            value: 25.5,
+            extreme: MeasureExtremeType::Max,
+        };
+        let value: serde_json::Value = serde_json::from_str(&format_json(&alarm)).unwrap();
+        assert_eq!(value["extreme"], json!("max"));
+        assert_eq!(value["value"], json!(25.5));
+    }
+
+    #[test]
+    fn test_cef_format() {
+        let cef = format_cef(&audit());
+        let prefix = format!("CEF:0|OldMusa|oldmusa-server|{}|audit|Admin action deleteSite|3|", env!("CARGO_PKG_VERSION"));
+        assert!(cef.starts_with(&prefix), "{}", cef);
+        assert!(cef.ends_with("rt=1585737000000 suid=3 suser=mario act=deleteSite msg=site 4 | a\\=b"), "{}", cef);
+
+        let end = SiemEvent::AlarmEnd {
+            time: NaiveDate::from_ymd(2020, 4, 1).and_hms(10, 30, 0),
+            channel_id: 7,
+        };
+        assert!(format_cef(&end).ends_with("|alarm_end|Alarm ended|3|rt=1585737000000 cs1Label=channelId cs1=7"));
+    }
+
+    #[test]
+    fn test_syslog_frame() {
+        let time = NaiveDate::from_ymd(2020, 4, 1).and_hms(10, 30, 0);
+        let frame = syslog_frame(4, time, "museum", "{}");
+        assert_eq!(frame, format!("<132>1 2020-04-01T10:30:00.000Z museum oldmusa {} - - {{}}", std::process::id()));
+    }
+}
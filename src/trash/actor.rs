@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::AppData;
+
+use super::purge::purge_trash;
+
+pub struct TrashActor {
+    pub app_data: AppData,
+    pub purge_interval: Duration,
+    /// How long the deleted entities are kept in the trash
+    pub retention: Duration,
+}
+
+impl TrashActor {
+    fn on_tick(&mut self, _ctx: &mut Context<Self>) {
+        let retention = match chrono::Duration::from_std(self.retention) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Invalid trash retention: {}", err);
+                return
+            },
+        };
+
+        match purge_trash(&self.app_data, Utc::now().naive_utc() - retention) {
+            Ok(report) => if report != Default::default() {
+                info!("Purged {} sites, {} sensors and {} channels from the trash", report.sites, report.sensors, report.channels);
+            },
+            Err(err) => error!("Error during trash purge: {}", err),
+        }
+    }
+}
+
+impl Actor for TrashActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        info!("starting the trash actor");
+
+        IntervalFunc::new(self.purge_interval, Self::on_tick)
+            .finish()
+            .spawn(ctx);
+    }
+}
+
+impl Supervised for TrashActor {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        warn!("restarting the trash actor");
+    }
+}
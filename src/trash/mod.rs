@@ -0,0 +1,5 @@
+mod actor;
+mod purge;
+
+pub use actor::TrashActor;
+pub use purge::{purge_trash, PurgeReport};
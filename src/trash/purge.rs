@@ -0,0 +1,68 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use log::warn;
+
+use crate::AppData;
+use crate::models::IdType;
+use crate::web::errors::{ServiceError, ServiceResult};
+use crate::web::site_map_service::{delete_image, get_file_from_site, get_file_from_site_map};
+
+/// Entities deleted for good by a purge
+#[derive(Debug, Default, PartialEq)]
+pub struct PurgeReport {
+    pub sites: usize,
+    pub sensors: usize,
+    pub channels: usize,
+}
+
+/// Deletes for good the sites, sensors and channels moved to the trash before deleted_before,
+/// with their alarm history and site maps.
+/// A site is kept in the trash if one of its images cannot be deleted, it's retried by the next
+/// purge.
+pub fn purge_trash(app: &AppData, deleted_before: NaiveDateTime) -> ServiceResult<PurgeReport> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+        site_map::dsl as map_dsl,
+    };
+
+    let conn = app.pool.get()?;
+
+    let site_ids = site_dsl::site
+        .filter(site_dsl::deleted_at.lt(deleted_before))
+        .select(site_dsl::id)
+        .load::<IdType>(&conn)?;
+
+    let mut purged_site_ids = Vec::with_capacity(site_ids.len());
+    for site_id in site_ids {
+        let map_ids = map_dsl::site_map.filter(map_dsl::site_id.eq(site_id))
+            .select(map_dsl::id)
+            .load::<IdType>(&conn)?;
+        let image_keys = map_ids.into_iter()
+            .map(|map_id| get_file_from_site_map(site_id, map_id))
+            .chain(std::iter::once(get_file_from_site(site_id)));
+
+        let mut deleted = true;
+        for image_key in image_keys {
+            if let Err(err) = delete_image(&*app.site_maps, &image_key) {
+                warn!("Cannot delete the image {} of the site {}: {}", image_key, site_id, err);
+                deleted = false;
+                break
+            }
+        }
+        if deleted {
+            purged_site_ids.push(site_id);
+        }
+    }
+
+    conn.transaction::<_, ServiceError, _>(|| {
+        let channels = diesel::delete(channel_dsl::channel.filter(channel_dsl::deleted_at.lt(deleted_before)))
+            .execute(&conn)?;
+        let sensors = diesel::delete(sensor_dsl::sensor.filter(sensor_dsl::deleted_at.lt(deleted_before)))
+            .execute(&conn)?;
+        let sites = diesel::delete(site_dsl::site.filter(site_dsl::id.eq_any(purged_site_ids)))
+            .execute(&conn)?;
+        Ok(PurgeReport { sites, sensors, channels })
+    })
+}
@@ -0,0 +1,96 @@
+//! Sizes of the queries and batches that used to be hardcoded, the defaults fit a museum with a
+//! few sensors but the bigger deployments (or the slower sensor databases) can change them with
+//! the `[tuning]` section of the configuration file (or the TUNING_* variables).
+
+use serde::Deserialize;
+
+use crate::config::Resolver;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TuningConfig {
+    /// Last readings of a site scanned to find its sensors and channels (autoCreate, cnrSensorIds)
+    pub discovery_site_readings: u32,
+    /// Last readings of a sensor scanned to find its channels
+    pub discovery_sensor_readings: u32,
+    /// How many site clocks are buffered by the alarm check before being written to the database
+    pub alarm_clock_chunk_size: usize,
+    /// Coins charged to check if a site (or site map) has an image
+    pub image_check_cost: i64,
+}
+
+impl Default for TuningConfig {
+    fn default() -> Self {
+        TuningConfig {
+            discovery_site_readings: 1000,
+            discovery_sensor_readings: 100,
+            alarm_clock_chunk_size: 32,
+            image_check_cost: 1,
+        }
+    }
+}
+
+/// `[tuning]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuningSection {
+    pub discovery_site_readings: Option<u32>,
+    pub discovery_sensor_readings: Option<u32>,
+    pub alarm_clock_chunk_size: Option<usize>,
+    pub image_check_cost: Option<i64>,
+}
+
+impl TuningConfig {
+    pub fn resolve(r: &mut Resolver, file: TuningSection) -> Self {
+        let default = TuningConfig::default();
+        let res = TuningConfig {
+            discovery_site_readings: r.optional("TUNING_DISCOVERY_SITE_READINGS", file.discovery_site_readings)
+                .unwrap_or(default.discovery_site_readings),
+            discovery_sensor_readings: r.optional("TUNING_DISCOVERY_SENSOR_READINGS", file.discovery_sensor_readings)
+                .unwrap_or(default.discovery_sensor_readings),
+            alarm_clock_chunk_size: r.optional("TUNING_ALARM_CLOCK_CHUNK_SIZE", file.alarm_clock_chunk_size)
+                .unwrap_or(default.alarm_clock_chunk_size),
+            image_check_cost: r.optional("TUNING_IMAGE_CHECK_COST", file.image_check_cost)
+                .unwrap_or(default.image_check_cost),
+        };
+        if res.discovery_site_readings == 0 || res.discovery_sensor_readings == 0 {
+            r.error("The TUNING_DISCOVERY_* variables must be positive".to_string());
+        }
+        if res.alarm_clock_chunk_size == 0 {
+            r.error("TUNING_ALARM_CLOCK_CHUNK_SIZE must be positive".to_string());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(vars: &[(&str, &str)], file: TuningSection) -> (TuningConfig, Vec<String>) {
+        let env = |name: &str| vars.iter().find(|x| x.0 == name).map(|x| x.1.to_string());
+        let mut r = Resolver::new(&env);
+        let config = TuningConfig::resolve(&mut r, file);
+        (config, r.errors().to_vec())
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(&[], TuningSection::default()), (TuningConfig::default(), vec![]));
+
+        let file = TuningSection { discovery_sensor_readings: Some(200), alarm_clock_chunk_size: Some(16), ..TuningSection::default() };
+        let (config, errors) = resolve(&[("TUNING_DISCOVERY_SITE_READINGS", "5000"), ("TUNING_ALARM_CLOCK_CHUNK_SIZE", "8")], file);
+        assert!(errors.is_empty());
+        assert_eq!(config.discovery_site_readings, 5000);
+        assert_eq!(config.discovery_sensor_readings, 200);
+        assert_eq!(config.alarm_clock_chunk_size, 8);
+    }
+
+    #[test]
+    fn test_zero_chunk_size() {
+        let (_, errors) = resolve(&[("TUNING_ALARM_CLOCK_CHUNK_SIZE", "0"), ("TUNING_IMAGE_CHECK_COST", "free")], TuningSection::default());
+        assert_eq!(errors, vec![
+            "Cannot parse TUNING_IMAGE_CHECK_COST".to_string(),
+            "TUNING_ALARM_CLOCK_CHUNK_SIZE must be positive".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Warm-up run before the HTTP server starts, so that the first requests after a deploy don't pay
+//! for the cold connections and caches.
+//!
+//! The recently active users and the most viewed sites are taken from the request log: the users
+//! are loaded in the auth cache and the last day of hourly readings of the sites' channels is
+//! loaded in the readings cache (and in the memory of the sensor database).
+
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::config::Resolver;
+use crate::models::IdType;
+use crate::web::db_helper::{query_channel_cnr_ids, ReadingsAggregation};
+use crate::web::errors::ServiceResult;
+
+#[derive(Clone, Debug)]
+pub struct WarmupConfig {
+    /// Connections opened in the sensor database pool
+    pub sensor_connections: usize,
+    /// How far back the request log is read
+    pub window: Duration,
+    /// Maximum number of users loaded in the auth cache
+    pub users: i64,
+    /// Maximum number of sites whose readings are primed
+    pub sites: i64,
+    /// Period of the primed readings, ending now
+    pub readings_period: Duration,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        WarmupConfig {
+            sensor_connections: 4,
+            window: Duration::from_secs(24 * 3600),
+            users: 100,
+            sites: 5,
+            readings_period: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+/// `[warmup]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupSection {
+    pub sensor_connections: Option<usize>,
+    pub window_hours: Option<u64>,
+    pub users: Option<i64>,
+    pub sites: Option<i64>,
+    pub readings_hours: Option<u64>,
+}
+
+impl WarmupConfig {
+    pub fn resolve(r: &mut Resolver, file: WarmupSection) -> Self {
+        let default = WarmupConfig::default();
+        WarmupConfig {
+            sensor_connections: r.optional("WARMUP_SENSOR_CONNECTIONS", file.sensor_connections).unwrap_or(default.sensor_connections),
+            window: r.optional("WARMUP_WINDOW_HOURS", file.window_hours).map_or(default.window, |x| Duration::from_secs(x * 3600)),
+            users: r.optional("WARMUP_USERS", file.users).unwrap_or(default.users),
+            sites: r.optional("WARMUP_SITES", file.sites).unwrap_or(default.sites),
+            readings_period: r.optional("WARMUP_READINGS_HOURS", file.readings_hours).map_or(default.readings_period, |x| Duration::from_secs(x * 3600)),
+        }
+    }
+}
+
+/// What has been warmed up
+#[derive(Debug, Default, PartialEq)]
+pub struct WarmupReport {
+    pub sensor_connections: usize,
+    pub users: usize,
+    pub sites: usize,
+    pub channels: usize,
+}
+
+/// Time that comes the duration before now, the epoch if it's too far back
+fn time_before(now: NaiveDateTime, duration: Duration) -> NaiveDateTime {
+    chrono::Duration::from_std(duration).ok()
+        .and_then(|x| now.checked_sub_signed(x))
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0))
+}
+
+/// Opens the connections of the sensor pool, they're kept idle once returned
+fn open_sensor_connections(app: &AppData, count: usize) -> ServiceResult<usize> {
+    let connections = (0..count)
+        .map(|_| app.sensor_pool.get_conn())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(connections.len())
+}
+
+/// Users that made a request after since, the most recent first
+fn recent_users(app: &AppData, since: NaiveDateTime, limit: i64) -> ServiceResult<Vec<IdType>> {
+    use crate::schema::request_log::dsl;
+
+    let conn = app.pool.get()?;
+    let users = dsl::request_log
+        .filter(dsl::created_at.ge(since))
+        .filter(dsl::user_id.is_not_null())
+        .group_by(dsl::user_id)
+        .select(dsl::user_id)
+        .order(diesel::dsl::max(dsl::created_at).desc())
+        .limit(limit)
+        .load::<Option<IdType>>(&conn)?;
+    Ok(users.into_iter().flatten().collect())
+}
+
+/// Sites with the most requests after since
+fn most_viewed_sites(app: &AppData, since: NaiveDateTime, limit: i64) -> ServiceResult<Vec<IdType>> {
+    use crate::schema::request_log::dsl;
+    use crate::schema::site::dsl as site_dsl;
+
+    let conn = app.pool.get()?;
+    let sites = dsl::request_log
+        .inner_join(site_dsl::site)
+        .filter(dsl::created_at.ge(since))
+        .filter(site_dsl::deleted_at.is_null())
+        .group_by(site_dsl::id)
+        .select(site_dsl::id)
+        .order(count_star().desc())
+        .limit(limit)
+        .load::<IdType>(&conn)?;
+    Ok(sites)
+}
+
+/// Queries the hourly readings of the channels of the site, returns the number of channels
+fn prime_site_readings(app: &AppData, site_id: IdType, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<usize> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+
+    let conn = app.pool.get()?;
+    let channels = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(channel_dsl::deleted_at.is_null())
+        .filter(channel_dsl::id_cnr.is_not_null())
+        .select((channel_dsl::id, channel_dsl::id_cnr))
+        .load::<(IdType, Option<String>)>(&conn)?;
+    std::mem::drop(conn);
+
+    let mut primed = 0;
+    for (channel_id, id_cnr) in channels {
+        if let Some(ids) = query_channel_cnr_ids(&app.pool, channel_id, id_cnr.as_deref())? {
+            app.readings_cache.load(&app.sensor_pool, &ids, start, end, &[], ReadingsAggregation::Hourly, end)?;
+            primed += 1;
+        }
+    }
+    Ok(primed)
+}
+
+/// Runs every step of the warm-up, a failing step is only logged as the server works without it
+pub fn warm_up(app: &AppData, config: &WarmupConfig, now: NaiveDateTime) -> WarmupReport {
+    let started = Instant::now();
+    let mut report = WarmupReport::default();
+
+    match open_sensor_connections(app, config.sensor_connections) {
+        Ok(x) => report.sensor_connections = x,
+        Err(e) => warn!("Warm-up: cannot open the sensor database connections: {}", e),
+    }
+
+    let since = time_before(now, config.window);
+    if config.users > 0 {
+        let users = recent_users(app, since, config.users)
+            .and_then(|ids| app.auth_cache.preload_users(app, &ids));
+        match users {
+            Ok(x) => report.users = x,
+            Err(e) => warn!("Warm-up: cannot preload the recent users: {}", e),
+        }
+    }
+
+    if config.sites > 0 {
+        let sites = most_viewed_sites(app, since, config.sites).unwrap_or_else(|e| {
+            warn!("Warm-up: cannot find the most viewed sites: {}", e);
+            Vec::new()
+        });
+        let start = time_before(now, config.readings_period);
+        for site_id in sites {
+            match prime_site_readings(app, site_id, start, now) {
+                Ok(x) => {
+                    report.sites += 1;
+                    report.channels += x;
+                },
+                Err(e) => warn!("Warm-up: cannot prime the readings of the site {}: {}", site_id, e),
+            }
+        }
+    }
+
+    info!("Warm-up done in {}ms: {:?}", started.elapsed().as_millis(), report);
+    report
+}
@@ -0,0 +1,193 @@
+//! Detection of the clients that keep asking for resources they can't access, as it's how an
+//! enumeration of the site and sensor ids looks like.
+//!
+//! The UNAUTHORIZED and NOT_FOUND errors of every GraphQL request are counted per user and per
+//! peer address, when a source reaches `ACCESS_ALERT_THRESHOLD` failures (default 20) in
+//! `ACCESS_ALERT_WINDOW_SECS` seconds (default 60) the admins are notified.
+//! If `ACCESS_ALERT_THROTTLE_SECS` is set the source is also rejected for that long.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::config::Resolver;
+use crate::contact::AccessAlertData;
+use crate::models::IdType;
+
+/// Sources remembered at most, the ones without recent failures are evicted first.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AccessSource {
+    User(IdType),
+    Address(IpAddr),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessMonitorConfig {
+    pub threshold: usize,
+    pub window: Duration,
+    /// How long a source is rejected after the alert, None if it's only reported
+    pub throttle: Option<Duration>,
+}
+
+impl Default for AccessMonitorConfig {
+    fn default() -> Self {
+        AccessMonitorConfig {
+            threshold: 20,
+            window: Duration::from_secs(60),
+            throttle: None,
+        }
+    }
+}
+
+/// `[access_monitor]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessMonitorSection {
+    pub alert_threshold: Option<usize>,
+    pub alert_window_secs: Option<u64>,
+    pub throttle_secs: Option<u64>,
+}
+
+impl AccessMonitorConfig {
+    pub fn resolve(r: &mut Resolver, file: AccessMonitorSection) -> Self {
+        let default = AccessMonitorConfig::default();
+        AccessMonitorConfig {
+            threshold: r.optional("ACCESS_ALERT_THRESHOLD", file.alert_threshold).unwrap_or(default.threshold),
+            window: r.optional("ACCESS_ALERT_WINDOW_SECS", file.alert_window_secs).map_or(default.window, Duration::from_secs),
+            throttle: r.optional("ACCESS_ALERT_THROTTLE_SECS", file.throttle_secs).filter(|x| *x > 0).map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SourceState {
+    failures: VecDeque<Instant>,
+    /// The source isn't reported again until this instant
+    alerted_until: Option<Instant>,
+    throttled_until: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub struct AccessMonitor {
+    pub config: AccessMonitorConfig,
+    sources: Arc<Mutex<HashMap<AccessSource, SourceState>>>,
+}
+
+impl AccessMonitor {
+    pub fn new(config: AccessMonitorConfig) -> Self {
+        AccessMonitor {
+            config,
+            sources: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Records the failed requests of the source, returns the number of failures in the window
+    /// if the admins should be alerted (at most once per window).
+    pub fn record_failures(&self, now: Instant, source: AccessSource, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None
+        }
+        let mut sources = self.sources.lock().unwrap();
+        if sources.len() >= MAX_TRACKED_SOURCES && !sources.contains_key(&source) {
+            let window = self.config.window;
+            sources.retain(|_, state| state.failures.back().is_some_and(|x| now.duration_since(*x) < window));
+        }
+
+        let state = sources.entry(source).or_default();
+        state.failures.extend(std::iter::repeat_n(now, count));
+        while state.failures.front().is_some_and(|x| now.duration_since(*x) >= self.config.window) {
+            state.failures.pop_front();
+        }
+        // Only the failures that can reach the threshold are kept
+        while state.failures.len() > self.config.threshold {
+            state.failures.pop_front();
+        }
+
+        if state.failures.len() < self.config.threshold || state.alerted_until.is_some_and(|x| x > now) {
+            return None
+        }
+        state.alerted_until = Some(now + self.config.window);
+        if let Some(throttle) = self.config.throttle {
+            state.throttled_until = Some(now + throttle);
+        }
+        Some(state.failures.len())
+    }
+
+    pub fn is_throttled(&self, now: Instant, source: AccessSource) -> bool {
+        self.sources.lock().unwrap().get(&source)
+            .and_then(|x| x.throttled_until)
+            .is_some_and(|x| x > now)
+    }
+}
+
+/// Records the failed requests of every source of the request, the admins are notified in the
+/// background about the sources that reached the threshold.
+pub fn report_failures(app: &AppData, sources: &[(AccessSource, String)], count: usize) {
+    let now = Instant::now();
+    for (source, name) in sources {
+        let failures = match app.access_monitor.record_failures(now, *source, count) {
+            Some(x) => x,
+            None => continue,
+        };
+        let data = AccessAlertData {
+            source: name.clone(),
+            failures,
+            window_secs: app.access_monitor.config.window.as_secs(),
+            throttled: app.access_monitor.config.throttle.is_some(),
+        };
+        warn!("Repeated access failures from {}: {} in {}s", data.source, data.failures, data.window_secs);
+
+        if app.contacter.enabled_clients() == (false, false) {
+            continue
+        }
+        let contacter = app.contacter.clone();
+        let connection = match app.pool.get() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Error in connection pool: {}", err);
+                continue
+            },
+        };
+        actix_rt::spawn(async move {
+            if let Err(err) = contacter.send_access_alert(&connection, &data).await {
+                error!("Error sending access alert: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_monitor() {
+        let monitor = AccessMonitor::new(AccessMonitorConfig {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            throttle: Some(Duration::from_secs(30)),
+        });
+        let user = AccessSource::User(1);
+        let start = Instant::now();
+
+        assert_eq!(monitor.record_failures(start, user, 2), None);
+        // The old failures leave the window
+        assert_eq!(monitor.record_failures(start + Duration::from_secs(61), user, 1), None);
+        let now = start + Duration::from_secs(62);
+        assert_eq!(monitor.record_failures(now, user, 2), Some(3));
+        assert!(monitor.is_throttled(now, user));
+        assert!(!monitor.is_throttled(now, AccessSource::User(2)));
+
+        // Reported once per window
+        assert_eq!(monitor.record_failures(now, user, 5), None);
+        assert!(!monitor.is_throttled(now + Duration::from_secs(31), user));
+        assert_eq!(monitor.record_failures(now + Duration::from_secs(61), user, 3), Some(3));
+    }
+}
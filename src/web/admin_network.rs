@@ -0,0 +1,123 @@
+//! Optional restriction of the admin operations to trusted networks.
+//!
+//! When `ADMIN_ALLOWED_NETWORKS` (comma separated CIDR ranges, ex. `10.8.0.0/16,::1/128`) or
+//! `ADMIN_VPN_HEADER` (a header added by the VPN gateway) is set, the admin schema
+//! (`/api/admin/graphql`) is only served to a peer address inside the ranges or to the requests
+//! carrying the header. The main schema is unaffected, so the users can still edit their own data.
+//! The peer address is the one of the socket, X-Forwarded-For is never trusted.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::HttpRequest;
+use serde::Deserialize;
+
+use crate::config::Resolver;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    /// Parses a CIDR range, a plain address is a range with only itself
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let address: IpAddr = parts.next().unwrap_or_default().parse()
+            .map_err(|_| format!("Invalid network address '{}'", s))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(x) => x.parse().ok().filter(|x| *x <= max_prefix)
+                .ok_or_else(|| format!("Invalid network prefix '{}'", s))?,
+            None => max_prefix,
+        };
+        Ok(IpNetwork { address, prefix })
+    }
+}
+
+/// `[admin_network]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminNetworkSection {
+    pub networks: Option<Vec<String>>,
+    pub vpn_header: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AdminNetworkPolicy {
+    pub networks: Vec<IpNetwork>,
+    pub vpn_header: Option<String>,
+}
+
+impl AdminNetworkPolicy {
+    /// Reads the policy, returns None if the admin operations aren't restricted
+    pub fn resolve(r: &mut Resolver, file: AdminNetworkSection) -> Option<AdminNetworkPolicy> {
+        let networks = r.list("ADMIN_ALLOWED_NETWORKS", file.networks);
+        let vpn_header = r.optional("ADMIN_VPN_HEADER", file.vpn_header).filter(|x: &String| !x.is_empty());
+        if networks.is_empty() && vpn_header.is_none() {
+            return None
+        }
+
+        let networks = networks.iter()
+            .filter_map(|x| match x.parse() {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    r.error(format!("Cannot parse ADMIN_ALLOWED_NETWORKS: {}", e));
+                    None
+                },
+            })
+            .collect();
+        Some(AdminNetworkPolicy { networks, vpn_header })
+    }
+
+    pub fn allows(&self, req: &HttpRequest) -> bool {
+        let from_vpn = self.vpn_header.as_ref()
+            .and_then(|x| req.headers().get(x.as_str()))
+            .is_some_and(|x| !x.is_empty());
+        let from_network = req.peer_addr()
+            .is_some_and(|addr| self.networks.iter().any(|net| net.contains(addr.ip())));
+        from_vpn || from_network
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_network() {
+        let net: IpNetwork = "10.8.0.0/16".parse().unwrap();
+        assert!(net.contains("10.8.3.4".parse().unwrap()));
+        assert!(!net.contains("10.9.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let host: IpNetwork = "::1".parse().unwrap();
+        assert!(host.contains("::1".parse().unwrap()));
+        assert!(!host.contains("::2".parse().unwrap()));
+
+        let any: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.168.1.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("vpn".parse::<IpNetwork>().is_err());
+    }
+}
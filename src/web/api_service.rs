@@ -1,18 +1,58 @@
 use actix_web::web;
 
-use super::graphql_service::{graphiql, graphql};
-use super::site_map_service::{image_delete, image_download, image_upload};
+use crate::alarm::metrics;
+
+use super::bulk_export::download_bulk_export;
+use super::calendar_service::site_calendar;
+use super::chart_service::channel_chart;
+use super::export_job::download_export_job;
+use super::export_service::export_channel_readings;
+use super::grafana_service::{grafana_query, grafana_search, grafana_test};
+use super::graphql_service::{admin_graphql, graphiql, graphql, graphql_get};
+use super::health_service::{healthz, readyz};
+use super::ingest_service::ingest_readings;
+use super::public_service::current_conditions;
+use super::rest_service;
+use super::site_map_service::{image_delete, image_download, image_upload, map_image_delete, map_image_download, map_image_upload};
+use super::ttn_service::ttn_uplink;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
+    // Probes of the orchestrators, outside of /api so that they're never behind the auth proxies
+    cfg.service(web::resource("/healthz").route(web::get().to(healthz)))
+        .service(web::resource("/readyz").route(web::get().to(readyz)));
     cfg.service(
         web::scope("/api")
-            .service(web::resource("/graphql").route(web::post().to(graphql)))
+            .configure(rest_service::config)
+            .service(
+                web::resource("/graphql")
+                    .route(web::get().to(graphql_get))
+                    .route(web::post().to(graphql))
+            )
             .service(web::resource("/graphiql").route(web::get().to(graphiql)))
+            .service(web::resource("/admin/graphql").route(web::post().to(admin_graphql)))
+            .service(web::resource("/calendar/site/{site_id}.ics").route(web::get().to(site_calendar)))
+            .service(web::resource("/chart/channel/{channel_id}.png").route(web::get().to(channel_chart)))
+            .service(web::resource("/export/channel/{channel_id}/readings").route(web::get().to(export_channel_readings)))
+            .service(web::resource("/export/job/{job_id}").route(web::get().to(download_export_job)))
+            .service(web::resource("/export/bulk/{job_id}").route(web::get().to(download_bulk_export)))
+            .service(web::resource("/grafana/").route(web::get().to(grafana_test)))
+            .service(web::resource("/grafana/search").route(web::post().to(grafana_search)))
+            .service(web::resource("/grafana/query").route(web::post().to(grafana_query)))
+            .service(web::resource("/ingest/readings").route(web::post().to(ingest_readings)))
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(web::resource("/public/site/{token}/current").route(web::get().to(current_conditions)))
+            .service(web::resource("/ttn/site/{site_id}/uplink").route(web::post().to(ttn_uplink)))
             .service(
                 web::resource("/site_map/{site_id}")
                     .route(web::get().to(image_download))
                     .route(web::post().to(image_upload))
                     .route(web::delete().to(image_delete))
             )
+            .service(
+                web::resource("/site_map/{site_id}/{map_id}")
+                    .route(web::get().to(map_image_download))
+                    .route(web::post().to(map_image_upload))
+                    .route(web::delete().to(map_image_delete))
+            )
     );
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+//! Optional two-person approval of the destructive admin actions.
+//!
+//! When `ADMIN_APPROVAL_WINDOW_MINUTES` is set, deleteSite, deleteUser and deleteSiteMap don't
+//! run right away: they record a pending action and fail with APPROVAL_PENDING (the extensions
+//! contain its id). A different admin must then confirm it within the window, the action runs
+//! with the permissions of the confirming admin.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde::Deserialize;
+
+use crate::config::Resolver;
+use crate::models::{IdType, PendingAction};
+
+use super::errors::{ServiceError, ServiceResult};
+
+#[derive(Clone, Debug)]
+pub struct ApprovalPolicy {
+    /// The pending actions that aren't confirmed in this time are discarded
+    pub window: Duration,
+}
+
+/// `[approval]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApprovalSection {
+    pub window_minutes: Option<u64>,
+}
+
+impl ApprovalPolicy {
+    /// Reads the policy, returns None if the approvals are disabled
+    pub fn resolve(r: &mut Resolver, file: ApprovalSection) -> Option<ApprovalPolicy> {
+        let minutes = r.optional("ADMIN_APPROVAL_WINDOW_MINUTES", file.window_minutes)?;
+        Some(ApprovalPolicy { window: Duration::from_secs(minutes * 60) })
+    }
+}
+
+#[derive(Clone, Copy, Debug, juniper::GraphQLEnum, PartialEq)]
+pub enum PendingActionKind {
+    DeleteSite,
+    DeleteUser,
+    DeleteSiteMap,
+}
+
+impl PendingActionKind {
+    /// Name of the mutation that requested the action
+    pub fn name(self) -> &'static str {
+        match self {
+            PendingActionKind::DeleteSite => "deleteSite",
+            PendingActionKind::DeleteUser => "deleteUser",
+            PendingActionKind::DeleteSiteMap => "deleteSiteMap",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PendingActionKind> {
+        match name {
+            "deleteSite" => Some(PendingActionKind::DeleteSite),
+            "deleteUser" => Some(PendingActionKind::DeleteUser),
+            "deleteSiteMap" => Some(PendingActionKind::DeleteSiteMap),
+            _ => None,
+        }
+    }
+}
+
+/// Records the action as pending and returns its id, an action already waiting for the same
+/// target is reused. The expired actions are discarded.
+pub fn request_approval(conn: &PgConnection, policy: &ApprovalPolicy, kind: PendingActionKind, target_id: IdType, user_id: IdType, now: NaiveDateTime) -> ServiceResult<IdType> {
+    use crate::schema::pending_action::dsl;
+
+    let window = chrono::Duration::from_std(policy.window)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    diesel::delete(dsl::pending_action.filter(dsl::expires_at.le(now)))
+        .execute(conn)?;
+
+    let existing = dsl::pending_action
+        .filter(dsl::action.eq(kind.name()))
+        .filter(dsl::target_id.eq(target_id))
+        .select(dsl::id)
+        .first::<IdType>(conn)
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id)
+    }
+
+    Ok(diesel::insert_into(dsl::pending_action)
+        .values((
+            dsl::action.eq(kind.name()),
+            dsl::target_id.eq(target_id),
+            dsl::requested_by.eq(user_id),
+            dsl::requested_at.eq(now),
+            dsl::expires_at.eq(now + window),
+        ))
+        .returning(dsl::id)
+        .get_result(conn)?)
+}
+
+/// Returns the pending action to run, it must be confirmed by an admin that didn't request it.
+/// It's only discarded after running, so that a failed action can be confirmed again.
+pub fn find_pending_action(conn: &PgConnection, id: IdType, user_id: IdType, now: NaiveDateTime) -> ServiceResult<(PendingActionKind, IdType)> {
+    use crate::schema::pending_action::dsl;
+
+    let action = dsl::pending_action.find(id)
+        .filter(dsl::expires_at.gt(now))
+        .first::<PendingAction>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Pending action".to_string()))?;
+    if action.requested_by == user_id {
+        return Err(ServiceError::BadRequest("The action must be confirmed by another admin".to_string()))
+    }
+    let kind = PendingActionKind::from_name(&action.action)
+        .ok_or_else(|| ServiceError::InternalServerError(format!("Invalid pending action {}", action.action)))?;
+    Ok((kind, action.target_id))
+}
+
+/// Deletes the pending action, returns false if it didn't exist
+pub fn discard_pending_action(conn: &PgConnection, id: IdType) -> ServiceResult<bool> {
+    use crate::schema::pending_action::dsl;
+
+    let count = diesel::delete(dsl::pending_action.find(id))
+        .execute(conn)?;
+    Ok(count == 1)
+}
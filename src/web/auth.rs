@@ -0,0 +1,156 @@
+//! Credentials of the requests to the non-GraphQL routes, so that every route authenticates the
+//! same way. The credentials are copied out of the request when it's extracted, they can then be
+//! checked in the blocking thread pool:
+//! - the identity cookie set by the login mutation,
+//! - the `Authorization: Bearer` tokens of the clients that can't keep cookies,
+//! - the `X-Api-Key` header of the services, only for the read-only routes and the ingestion,
+//! - the `Authorization: Basic` header, only for the clients that can't use anything else (Grafana),
+//! - the public token of a site in the `token` query parameter (share links).
+
+use std::net::IpAddr;
+
+use actix_identity::RequestIdentity;
+use actix_web::{dev::Payload, Error, FromRequest, HttpRequest, web};
+use actix_web::http::header;
+use futures::future::{ok, Ready};
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::models::{ApiKey, User};
+use crate::security::{API_KEY_HEADER, find_api_key, Principal};
+
+use super::errors::{ServiceError, ServiceResult};
+
+#[derive(Deserialize)]
+struct ShareTokenQuery {
+    token: Option<String>,
+}
+
+pub struct Authenticator {
+    bearer_token: Option<String>,
+    /// Username and password of the basic authentication
+    basic: Option<(String, String)>,
+    identity: Option<String>,
+    /// Value of the `X-Api-Key` header, Err if it isn't valid text
+    api_key: Option<Result<String, ()>>,
+    share_token: Option<String>,
+    /// Peer address, the wrong passwords are throttled per address
+    address: Option<IpAddr>,
+}
+
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(base64::decode(value.trim()).ok()?).ok()?;
+    let separator = decoded.find(':')?;
+    Some((decoded[..separator].to_string(), decoded[separator + 1..].to_string()))
+}
+
+impl Authenticator {
+    /// Reads the credentials of the request, the identity is passed by the routes that also
+    /// change it (ex. GraphQL)
+    pub fn new(req: &HttpRequest, identity: Option<String>) -> Authenticator {
+        let authorization = req.headers().get(header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok());
+        Authenticator {
+            bearer_token: authorization.and_then(|x| x.strip_prefix("Bearer ")).map(|x| x.to_string()),
+            basic: authorization.and_then(|x| x.strip_prefix("Basic ")).and_then(parse_basic),
+            identity,
+            api_key: req.headers().get(API_KEY_HEADER)
+                .map(|x| x.to_str().map(|x| x.to_string()).map_err(|_| ())),
+            share_token: web::Query::<ShareTokenQuery>::from_query(req.query_string()).ok()
+                .and_then(|x| x.into_inner().token),
+            address: req.peer_addr().map(|x| x.ip()),
+        }
+    }
+
+    /// Returns true if the request has a login or an api key (the share tokens aren't counted)
+    pub fn is_present(&self) -> bool {
+        self.bearer_token.is_some() || self.identity.is_some() || self.api_key.is_some()
+    }
+
+    /// Public token of a site, the route decides what it grants
+    pub fn share_token(&self) -> Option<&str> {
+        self.share_token.as_deref()
+    }
+
+    /// Finds the logged user, the bearer token (used by the non-browser clients) takes precedence
+    /// over the identity cookie.
+    pub fn user(&self, ctx: &AppData) -> ServiceResult<Option<User>> {
+        if let Some(token) = &self.bearer_token {
+            return ctx.auth_cache.parse_token(ctx, token)
+        }
+        self.identity.as_ref()
+            .and_then(|x| ctx.auth_cache.parse_identity(ctx, x).transpose())
+            .transpose()
+    }
+
+    pub fn user_required(&self, ctx: &AppData) -> ServiceResult<User> {
+        self.user(ctx)?.ok_or(ServiceError::LoginRequired)
+    }
+
+    /// Like user_required but the api keys are also accepted
+    pub fn principal_required(&self, ctx: &AppData) -> ServiceResult<Principal> {
+        match &self.api_key {
+            Some(Ok(key)) => find_api_key(ctx, key)?.map(Principal::ApiKey).ok_or(ServiceError::Unauthorized),
+            Some(Err(())) => Err(ServiceError::Unauthorized),
+            None => self.user_required(ctx).map(Principal::User),
+        }
+    }
+
+    /// Only accepts the api keys, for the services that never log in. Returns None if the key
+    /// doesn't exist or it has been revoked.
+    pub fn api_key(&self, ctx: &AppData) -> ServiceResult<Option<ApiKey>> {
+        match &self.api_key {
+            Some(Ok(key)) => find_api_key(ctx, key),
+            _ => Err(ServiceError::LoginRequired),
+        }
+    }
+
+    /// Only accepts the api keys and the basic authentication, for the clients that can't keep a
+    /// session.
+    pub fn principal_with_password(&self, ctx: &AppData) -> ServiceResult<Principal> {
+        match (&self.api_key, &self.basic) {
+            (Some(Ok(key)), _) => find_api_key(ctx, key)?.map(Principal::ApiKey).ok_or(ServiceError::Unauthorized),
+            (Some(Err(())), _) => Err(ServiceError::Unauthorized),
+            (None, Some((username, password))) => ctx.auth_cache.verify_user(ctx, username.clone(), password.clone(), self.address).map(Principal::User),
+            (None, None) => Err(ServiceError::LoginRequired),
+        }
+    }
+}
+
+impl FromRequest for Authenticator {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ok(Authenticator::new(req, req.get_identity()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_request_credentials() {
+        let req = TestRequest::with_uri("/api/chart/channel/1.png?start=x&token=abc")
+            .header(header::AUTHORIZATION, "Bearer tok.en")
+            .to_http_request();
+        let auth = Authenticator::new(&req, None);
+        assert_eq!(auth.bearer_token.as_deref(), Some("tok.en"));
+        assert_eq!(auth.basic, None);
+        assert_eq!(auth.share_token(), Some("abc"));
+        assert!(auth.is_present());
+
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, format!("Basic {}", base64::encode("grafana:pass:word")))
+            .to_http_request();
+        let auth = Authenticator::new(&req, None);
+        assert_eq!(auth.basic, Some(("grafana".to_string(), "pass:word".to_string())));
+        assert_eq!(auth.share_token(), None);
+        // The basic authentication is only accepted where it's asked for
+        assert!(!auth.is_present());
+    }
+}
@@ -0,0 +1,17 @@
+//! The diesel and mysql queries are synchronous, the HTTP handlers run them in the blocking thread
+//! pool so that a slow query (usually on the sensor database) can't starve the actix workers.
+
+use actix_web::error::BlockingError;
+use actix_web::web;
+
+use super::errors::{ServiceError, ServiceResult};
+
+/// Runs the operation in the blocking thread pool
+pub async fn run_blocking<T, F>(operation: F) -> ServiceResult<T>
+    where T: Send + 'static,
+          F: FnOnce() -> ServiceResult<T> + Send + 'static {
+    web::block(operation).await.map_err(|err| match err {
+        BlockingError::Error(x) => x,
+        BlockingError::Canceled => ServiceError::InternalServerError("Request canceled".to_string()),
+    })
+}
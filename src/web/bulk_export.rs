@@ -0,0 +1,336 @@
+//! Exports of every channel of some sites in a date range, used for the yearly handover of the
+//! data to the conservation institutes.
+//!
+//! Like the export jobs the file is written by a separate thread: a zip with a CSV file for every
+//! channel (grouped by site) is written in the export directory and the admin that asked for it
+//! is notified with a signed download link. The files are deleted after the export retention time.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::{info, warn};
+use zip::write::FileOptions;
+
+use crate::AppData;
+use crate::contact::BulkExportReadyData;
+use crate::models::{BulkExportJob, IdType};
+
+use super::blocking::run_blocking;
+use super::db_helper::{load_invalid_intervals, query_channel_cnr_ids};
+use super::errors::{ServiceError, ServiceResult};
+use super::export_job::{ExportJobQuery, ExportJobStatus};
+use super::export_service::{ExportQuery, write_csv};
+
+/// Export requested by an admin
+pub struct BulkExportRequest {
+    pub user_id: IdType,
+    pub site_ids: Vec<IdType>,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// Also export the readings marked as invalid
+    pub include_invalid: bool,
+}
+
+/// Channel written in the zip
+struct ExportedChannel {
+    id: IdType,
+    id_cnr: Option<String>,
+    /// Path of its CSV file in the zip
+    entry: String,
+}
+
+fn bulk_export_data(job_id: IdType) -> String {
+    format!("export/bulk/{}", job_id)
+}
+
+/// Returns the path (with the signature) of the zip of the bulk export.
+pub fn bulk_export_path(ctx: &AppData, job_id: IdType) -> String {
+    let signature = ctx.auth_cache.sign_url(&bulk_export_data(job_id));
+    format!("/api/export/bulk/{}?signature={}", job_id, signature)
+}
+
+fn bulk_export_file(ctx: &AppData, job_id: IdType) -> PathBuf {
+    ctx.export_jobs.directory.join(format!("bulk_{}.zip", job_id))
+}
+
+/// Makes the name usable as a part of a path in the zip (on every system it's extracted in)
+fn sanitize_entry_part(name: &str) -> String {
+    let name: String = name.trim().chars()
+        .map(|x| if x.is_alphanumeric() || x == '-' || x == '_' || x == '.' { x } else { '_' })
+        .collect();
+    name.trim_start_matches('.').to_string()
+}
+
+fn channel_entry(site_id: IdType, site_name: Option<&str>, sensor_name: Option<&str>, channel_id: IdType, channel_name: Option<&str>) -> String {
+    let with_name = |id: IdType, name: Option<&str>| match name.map(sanitize_entry_part).filter(|x| !x.is_empty()) {
+        Some(name) => format!("{}_{}", id, name),
+        None => id.to_string(),
+    };
+    let sensor = sensor_name.map(sanitize_entry_part).filter(|x| !x.is_empty()).unwrap_or_else(|| "sensor".to_string());
+    format!("{}/{}/{}.csv", with_name(site_id, site_name), sensor, with_name(channel_id, channel_name))
+}
+
+/// Deletes the bulk exports older than the retention time and their files.
+fn delete_expired_exports(ctx: &AppData, now: NaiveDateTime) -> ServiceResult<()> {
+    use crate::schema::bulk_export_job::dsl;
+
+    let retention = chrono::Duration::from_std(ctx.export_jobs.retention)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+    let conn = ctx.pool.get()?;
+    let expired = dsl::bulk_export_job
+        .filter(dsl::created_at.lt(now - retention))
+        .select(dsl::id)
+        .load::<IdType>(&conn)?;
+
+    for id in expired.iter() {
+        let path = bulk_export_file(ctx, *id);
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Cannot delete the expired bulk export {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    diesel::delete(dsl::bulk_export_job.filter(dsl::id.eq_any(expired)))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Registers the export and starts writing its zip in a separate thread.
+pub fn start_bulk_export(ctx: &AppData, request: BulkExportRequest) -> ServiceResult<BulkExportJob> {
+    use crate::schema::{
+        bulk_export_job::dsl,
+        site::dsl as site_dsl,
+    };
+
+    if request.end < request.start {
+        return Err(ServiceError::BadRequest("start is after end".to_string()))
+    }
+    if request.site_ids.is_empty() {
+        return Err(ServiceError::BadRequest("No site to export".to_string()))
+    }
+
+    let now = Utc::now().naive_utc();
+    if let Err(err) = delete_expired_exports(ctx, now) {
+        warn!("Cannot delete the expired bulk exports: {}", err);
+    }
+
+    let conn = ctx.pool.get()?;
+    let found = site_dsl::site
+        .filter(site_dsl::id.eq_any(&request.site_ids))
+        .filter(site_dsl::deleted_at.is_null())
+        .count()
+        .get_result::<i64>(&conn)?;
+    let mut site_ids = request.site_ids.clone();
+    site_ids.sort();
+    site_ids.dedup();
+    if found != site_ids.len() as i64 {
+        return Err(ServiceError::NotFound("Site".to_string()))
+    }
+
+    std::fs::create_dir_all(&ctx.export_jobs.directory)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    let job = diesel::insert_into(dsl::bulk_export_job)
+        .values((
+            dsl::user_id.eq(request.user_id),
+            dsl::site_ids.eq(&site_ids),
+            dsl::range_start.eq(request.start),
+            dsl::range_end.eq(request.end),
+            dsl::include_invalid.eq(request.include_invalid),
+            dsl::status.eq(ExportJobStatus::Running.to_char()),
+            dsl::created_at.eq(now),
+        ))
+        .get_result::<BulkExportJob>(&conn)?;
+    std::mem::drop(conn);
+
+    let ctx = ctx.clone();
+    let job_id = job.id;
+    let request = BulkExportRequest { site_ids, ..request };
+    std::thread::spawn(move || run_bulk_export(&ctx, job_id, request));
+    Ok(job)
+}
+
+/// Channels of the sites that aren't in the trash, ordered as they're written in the zip
+fn load_exported_channels(conn: &PgConnection, site_ids: &[IdType]) -> QueryResult<Vec<ExportedChannel>> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let rows = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .filter(site_dsl::id.eq_any(site_ids))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(channel_dsl::deleted_at.is_null())
+        .order((site_dsl::id, sensor_dsl::id, channel_dsl::id))
+        .select((site_dsl::id, site_dsl::name, sensor_dsl::name, channel_dsl::id, channel_dsl::name, channel_dsl::id_cnr))
+        .load::<(IdType, Option<String>, Option<String>, IdType, Option<String>, Option<String>)>(conn)?;
+
+    Ok(rows.into_iter()
+        .map(|(site_id, site_name, sensor_name, id, name, id_cnr)| ExportedChannel {
+            id,
+            id_cnr,
+            entry: channel_entry(site_id, site_name.as_deref(), sensor_name.as_deref(), id, name.as_deref()),
+        })
+        .collect())
+}
+
+/// Writes the zip to a temporary path, moved in place only when it's complete.
+/// Returns the exported channels and readings.
+fn write_bulk_export(ctx: &AppData, job_id: IdType, request: &BulkExportRequest) -> ServiceResult<(usize, u64)> {
+    let io_error = |x: std::io::Error| ServiceError::InternalServerError(x.to_string());
+    let zip_error = |x: zip::result::ZipError| ServiceError::InternalServerError(x.to_string());
+    let path = bulk_export_file(ctx, job_id);
+    let partial = path.with_extension("part");
+
+    let conn = ctx.pool.get()?;
+    let channels = load_exported_channels(&conn, &request.site_ids)?;
+    let query = ExportQuery::new(request.start, request.end, request.include_invalid);
+
+    let file = File::create(&partial).map_err(io_error)?;
+    let mut zip = zip::ZipWriter::new(BufWriter::new(file));
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let mut rows = 0;
+    for channel in channels.iter() {
+        let invalid = if request.include_invalid {
+            Vec::new()
+        } else {
+            load_invalid_intervals(&conn, channel.id, request.start, request.end)?
+        };
+        let ids = query_channel_cnr_ids(&ctx.pool, channel.id, channel.id_cnr.as_deref())?;
+
+        zip.start_file(channel.entry.as_str(), options).map_err(zip_error)?;
+        rows += write_csv(&ctx.sensor_pool, (ids, invalid), &query, &mut zip)?.rows;
+    }
+    zip.finish().map_err(zip_error)?;
+    std::fs::rename(&partial, &path).map_err(io_error)?;
+    Ok((channels.len(), rows))
+}
+
+fn run_bulk_export(ctx: &AppData, job_id: IdType, request: BulkExportRequest) {
+    use crate::schema::{
+        bulk_export_job::dsl,
+        site::dsl as site_dsl,
+    };
+
+    let start = std::time::Instant::now();
+    let result = write_bulk_export(ctx, job_id, &request);
+    let (status, error, channels, rows) = match &result {
+        Ok((channels, rows)) => {
+            info!("Bulk export {} wrote {} channels ({} rows) in {}s", job_id, channels, rows, start.elapsed().as_secs());
+            (ExportJobStatus::Done, None, *channels, *rows)
+        },
+        Err(err) => {
+            warn!("Bulk export {} failed: {}", job_id, err);
+            (ExportJobStatus::Failed, Some(err.to_string().chars().take(255).collect::<String>()), 0, 0)
+        },
+    };
+
+    let notify = move || -> ServiceResult<()> {
+        let conn = ctx.pool.get()?;
+        diesel::update(dsl::bulk_export_job.find(job_id))
+            .set((
+                dsl::status.eq(status.to_char()),
+                dsl::error.eq(error),
+                dsl::channel_count.eq(channels as i32),
+                dsl::row_count.eq(rows as i64),
+                dsl::finished_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&conn)?;
+
+        if status == ExportJobStatus::Done {
+            let site_names = site_dsl::site
+                .filter(site_dsl::id.eq_any(&request.site_ids))
+                .order(site_dsl::id)
+                .select((site_dsl::id, site_dsl::name))
+                .load::<(IdType, Option<String>)>(&conn)?
+                .into_iter()
+                .map(|(id, name)| name.unwrap_or_else(|| format!("#{}", id)))
+                .collect();
+            let data = BulkExportReadyData {
+                user_id: request.user_id,
+                site_names,
+                link: format!("{}{}", ctx.export_jobs.base_url, bulk_export_path(ctx, job_id)),
+                channels,
+                rows,
+            };
+            // The export thread has no runtime and the FCM client needs one
+            actix_rt::System::new("bulk-export").block_on(ctx.contacter.send_bulk_export_ready(&conn, &data))
+                .map_err(ServiceError::InternalServerError)?;
+        }
+        Ok(())
+    };
+    if let Err(err) = notify() {
+        warn!("Cannot complete the bulk export {}: {}", job_id, err);
+    }
+}
+
+/// Downloads the zip of a bulk export, the url is signed by the server (see bulk_export_path) so
+/// that the link sent to the admin works without logging in.
+/// While the zip is being written the response is a 202 with the status of the export.
+pub async fn download_bulk_export(
+    ctx: web::Data<AppData>,
+    req: HttpRequest,
+    job_id: web::Path<IdType>,
+    query: web::Query<ExportJobQuery>,
+) -> ServiceResult<HttpResponse> {
+    use crate::schema::bulk_export_job::dsl;
+
+    let job_id = *job_id;
+    if !ctx.auth_cache.verify_url_signature(&bulk_export_data(job_id), &query.signature) {
+        return Err(ServiceError::Unauthorized)
+    }
+
+    let job_ctx = ctx.clone();
+    let job = run_blocking(move || -> ServiceResult<Option<BulkExportJob>> {
+        let conn = job_ctx.pool.get()?;
+        Ok(dsl::bulk_export_job.find(job_id).first::<BulkExportJob>(&conn).optional()?)
+    }).await?
+        .ok_or_else(|| ServiceError::NotFound("Bulk export".to_string()))?;
+
+    match ExportJobStatus::from_char(&job.status) {
+        Some(ExportJobStatus::Running) => Ok(HttpResponse::Accepted().json(serde_json::json!({
+            "jobId": job.id,
+            "status": "RUNNING",
+        }))),
+        Some(ExportJobStatus::Done) => {
+            let file = NamedFile::open(bulk_export_file(&ctx, job.id))
+                .map_err(|_| ServiceError::NotFound("Export file".to_string()))?
+                .set_content_type("application/zip".parse().unwrap())
+                .set_content_disposition(ContentDisposition {
+                    disposition: DispositionType::Attachment,
+                    parameters: vec![DispositionParam::Filename(format!(
+                        "readings_{}_{}.zip", job.range_start.format("%Y%m%d"), job.range_end.format("%Y%m%d")
+                    ))],
+                });
+            file.respond_to(&req).await
+                .map_err(|x| ServiceError::InternalServerError(x.to_string()))
+        },
+        _ => Err(ServiceError::InternalServerError(format!(
+            "Export failed: {}", job.error.unwrap_or_default()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_entry() {
+        assert_eq!(
+            channel_entry(3, Some("Museo Civico"), Some("Sala 1"), 12, Some("Temperatura (°C)")),
+            "3_Museo_Civico/Sala_1/12_Temperatura___C_.csv"
+        );
+        assert_eq!(channel_entry(3, None, Some("../.."), 12, Some("  ")), "3/_../12.csv");
+        assert_eq!(channel_entry(3, Some("a/b"), None, 12, None), "3_a_b/sensor/12.csv");
+    }
+}
@@ -0,0 +1,175 @@
+use actix_web::{HttpResponse, web};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::contact::MeasureExtremeType;
+use crate::models::{ALARM_EVENT_ALL_COLUMNS, AlarmEvent, IdType, MaintenanceWindow, Site};
+
+use super::blocking::run_blocking;
+use super::errors::{ServiceError, ServiceResult};
+
+/// Maximum number of past alarms contained in the feed
+const CALENDAR_MAX_ALARMS: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct CalendarQuery {
+    signature: String,
+}
+
+/// A single VEVENT of the feed
+struct CalendarEvent {
+    uid: String,
+    start: NaiveDateTime,
+    end: Option<NaiveDateTime>,
+    summary: String,
+    description: Option<String>,
+}
+
+fn site_calendar_data(site_id: IdType) -> String {
+    format!("calendar/site/{}", site_id)
+}
+
+/// Returns the path (with the signature) of the calendar feed of the site.
+pub fn calendar_path(ctx: &AppData, site_id: IdType) -> String {
+    let signature = ctx.auth_cache.sign_url(&site_calendar_data(site_id));
+    format!("/api/calendar/site/{}.ics?signature={}", site_id, signature)
+}
+
+fn format_date(date: NaiveDateTime) -> String {
+    date.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+/// Writes a content line folding it at 75 octets as required by RFC 5545.
+fn write_line(out: &mut String, line: &str) {
+    let mut line_len = 0;
+    for c in line.chars() {
+        if line_len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            line_len = 1;
+        }
+        out.push(c);
+        line_len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn render_calendar(site: &Site, events: &[CalendarEvent]) -> String {
+    let mut res = String::new();
+    let now = format_date(Utc::now().naive_utc());
+
+    write_line(&mut res, "BEGIN:VCALENDAR");
+    write_line(&mut res, "VERSION:2.0");
+    write_line(&mut res, "PRODID:-//OldMusa//OldMusa Server//EN");
+    write_line(&mut res, &format!("X-WR-CALNAME:{}", escape_text(site.name.as_deref().unwrap_or("OldMusa"))));
+
+    for event in events {
+        write_line(&mut res, "BEGIN:VEVENT");
+        write_line(&mut res, &format!("UID:{}", event.uid));
+        write_line(&mut res, &format!("DTSTAMP:{}", now));
+        write_line(&mut res, &format!("DTSTART:{}", format_date(event.start)));
+        if let Some(end) = event.end {
+            write_line(&mut res, &format!("DTEND:{}", format_date(end)));
+        }
+        write_line(&mut res, &format!("SUMMARY:{}", escape_text(&event.summary)));
+        if let Some(description) = &event.description {
+            write_line(&mut res, &format!("DESCRIPTION:{}", escape_text(description)));
+        }
+        write_line(&mut res, "END:VEVENT");
+    }
+
+    write_line(&mut res, "END:VCALENDAR");
+    res
+}
+
+fn load_calendar(ctx: &AppData, site_id: IdType) -> ServiceResult<String> {
+    use crate::schema::alarm_event::dsl as event_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::maintenance_window::dsl as window_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::site::dsl as site_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let site = site_dsl::site.find(site_id)
+        .filter(site_dsl::deleted_at.is_null())
+        .first::<Site>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Site".to_string()))?;
+
+    let windows: Vec<MaintenanceWindow> = window_dsl::maintenance_window
+        .filter(window_dsl::site_id.eq(site_id))
+        .order(window_dsl::start_time)
+        .load(&conn)?;
+
+    let alarms: Vec<(AlarmEvent, Option<String>, Option<String>)> = event_dsl::alarm_event
+        .inner_join(channel_dsl::channel.inner_join(sensor_dsl::sensor))
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(channel_dsl::deleted_at.is_null())
+        .order(event_dsl::started_at.desc())
+        .limit(CALENDAR_MAX_ALARMS)
+        .select((ALARM_EVENT_ALL_COLUMNS, sensor_dsl::name, channel_dsl::name))
+        .load(&conn)?;
+
+    let mut events: Vec<CalendarEvent> = windows.into_iter().map(|window| {
+        CalendarEvent {
+            uid: format!("maintenance-{}@oldmusa", window.id),
+            start: window.start_time,
+            end: Some(window.end_time),
+            summary: format!("Maintenance: {}", site.name.as_deref().unwrap_or("site")),
+            description: window.description,
+        }
+    }).collect();
+
+    events.extend(alarms.into_iter().map(|(alarm, sensor_name, channel_name)| {
+        let description = match MeasureExtremeType::from_char(&alarm.extreme_type) {
+            Some(MeasureExtremeType::Min) => format!("Peak minimum value: {}", alarm.peak_value),
+            Some(MeasureExtremeType::Max) => format!("Peak maximum value: {}", alarm.peak_value),
+            Some(MeasureExtremeType::Delta) => format!("Peak change per hour: {}", alarm.peak_value),
+            // A kind this feed doesn't know, better no label than a wrong one
+            None => format!("Peak value: {}", alarm.peak_value),
+        };
+        CalendarEvent {
+            uid: format!("alarm-{}@oldmusa", alarm.id),
+            start: alarm.started_at,
+            end: alarm.ended_at,
+            summary: format!(
+                "Alarm: {} ({})",
+                channel_name.as_deref().unwrap_or("channel"),
+                sensor_name.as_deref().unwrap_or("sensor")
+            ),
+            description: Some(description),
+        }
+    }));
+
+    Ok(render_calendar(&site, &events))
+}
+
+/// iCalendar feed of the site (maintenance windows and alarms), the url is signed by the server (see calendar_path) so that
+/// calendar applications can subscribe to it without logging in.
+pub async fn site_calendar(
+    ctx: web::Data<AppData>,
+    site_id: web::Path<IdType>,
+    query: web::Query<CalendarQuery>,
+) -> ServiceResult<HttpResponse> {
+    let site_id = *site_id;
+    if !ctx.auth_cache.verify_url_signature(&site_calendar_data(site_id), &query.signature) {
+        return Err(ServiceError::Unauthorized)
+    }
+
+    let calendar = run_blocking(move || load_calendar(&ctx, site_id)).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(calendar))
+}
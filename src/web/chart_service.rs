@@ -0,0 +1,150 @@
+use actix_web::{HttpResponse, web};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use plotters::prelude::*;
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::models::IdType;
+use crate::security::PermissionCheckable;
+
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::db_helper::{load_channel_readings, load_invalid_intervals, load_live_channel, query_channel_cnr_ids};
+use super::errors::{ServiceError, ServiceResult};
+
+const CHART_DEFAULT_WIDTH: u32 = 800;
+const CHART_DEFAULT_HEIGHT: u32 = 400;
+const CHART_MAX_SIZE: u32 = 2000;
+
+#[derive(Deserialize)]
+pub struct ChartQuery {
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+fn ensure_token_grants_channel(ctx: &AppData, token: &str, channel_id: IdType) -> ServiceResult<()> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::site_public_token::dsl as token_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let count: i64 = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor.inner_join(token_dsl::site_public_token.on(token_dsl::site_id.eq(sensor_dsl::site_id))))
+        .filter(channel_dsl::id.eq(channel_id))
+        .filter(token_dsl::token.eq(token))
+        .count()
+        .get_result(&conn)?;
+
+    ctx.access_policy.check_visible("Channel", count != 0)
+}
+
+fn render_chart(points: &[(i64, f64, f64, f64)], (start, end): (i64, i64), (width, height): (u32, u32)) -> ServiceResult<Vec<u8>> {
+    let mut buffer = vec![0u8; (width * height * 3) as usize];
+
+    {
+        let root = BitMapBackend::with_buffer(&mut buffer, (width, height)).into_drawing_area();
+        root.fill(&WHITE).map_err(chart_error)?;
+
+        let value_min = points.iter().map(|x| x.1).fold(f64::INFINITY, f64::min);
+        let value_max = points.iter().map(|x| x.3).fold(f64::NEG_INFINITY, f64::max);
+        let (value_min, value_max) = if value_min > value_max {
+            (0.0, 1.0)// No points
+        } else {
+            let margin = ((value_max - value_min) * 0.1).max(0.5);
+            (value_min - margin, value_max + margin)
+        };
+
+        // The text rendering is not available without system fonts, so the chart only shows the
+        // data (the caller already knows the range that was requested).
+        let mut chart = ChartBuilder::on(&root)
+            .margin(10)
+            .build_cartesian_2d(start..end.max(start + 1), value_min..value_max)
+            .map_err(chart_error)?;
+
+        chart.plotting_area().fill(&RGBColor(250, 250, 250)).map_err(chart_error)?;
+
+        chart.draw_series(std::iter::once(Polygon::new(
+            points.iter().map(|x| (x.0, x.3))
+                .chain(points.iter().rev().map(|x| (x.0, x.1)))
+                .collect::<Vec<_>>(),
+            BLUE.mix(0.2).filled(),
+        ))).map_err(chart_error)?;
+
+        chart.draw_series(LineSeries::new(
+            points.iter().map(|x| (x.0, x.2)),
+            BLUE.stroke_width(2),
+        )).map_err(chart_error)?;
+
+        root.present().map_err(chart_error)?;
+    }
+
+    let mut res = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut res, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(chart_error)?;
+        writer.write_image_data(&buffer).map_err(chart_error)?;
+    }
+    Ok(res)
+}
+
+fn chart_error<E: std::fmt::Display>(error: E) -> ServiceError {
+    ServiceError::InternalServerError(format!("Chart error: {}", error))
+}
+
+fn load_channel_chart(ctx: &AppData, auth: &Authenticator, channel_id: IdType, query: &ChartQuery) -> ServiceResult<Vec<u8>> {
+    // The public token of the site is used instead of the login
+    match auth.share_token() {
+        Some(token) => ensure_token_grants_channel(ctx, token, channel_id)?,
+        None => auth.principal_required(ctx)?.ensure_channel_visible(ctx, channel_id)?,
+    }
+
+    if query.end < query.start {
+        return Err(ServiceError::BadRequest("start is after end".to_string()))
+    }
+
+    let width = query.width.unwrap_or(CHART_DEFAULT_WIDTH).clamp(1, CHART_MAX_SIZE);
+    let height = query.height.unwrap_or(CHART_DEFAULT_HEIGHT).clamp(1, CHART_MAX_SIZE);
+
+    let conn = ctx.pool.get()?;
+    let id_cnr = load_live_channel(&conn, channel_id)?.id_cnr;
+    let invalid = load_invalid_intervals(&conn, channel_id, query.start, query.end)?;
+    std::mem::drop(conn);
+
+    let readings = match query_channel_cnr_ids(&ctx.pool, channel_id, id_cnr.as_deref())? {
+        Some(ids) => load_channel_readings(&ctx.sensor_pool, &ids, query.start, query.end, &invalid)?,
+        None => Vec::new(),
+    };
+
+    let mut points: Vec<(i64, f64, f64, f64)> = readings.iter()
+        .map(|x| {
+            let max = x.value_max.unwrap_or(x.value_min);
+            (x.date.timestamp(), x.value_min, x.value_avg.unwrap_or((x.value_min + max) / 2.0), max)
+        })
+        .collect();
+    points.sort_by_key(|x| x.0);
+
+    render_chart(&points, (query.start.timestamp(), query.end.timestamp()), (width, height))
+}
+
+/// Renders the readings of a channel as a PNG line chart (the average value is drawn as a line and
+/// the min-max range as a band), so that it can be embedded in emails and reports.
+pub async fn channel_chart(
+    ctx: web::Data<AppData>,
+    auth: Authenticator,
+    channel_id: web::Path<IdType>,
+    query: web::Query<ChartQuery>,
+) -> ServiceResult<HttpResponse> {
+    let channel_id = *channel_id;
+
+    let image = run_blocking(move || load_channel_chart(&ctx, &auth, channel_id, &query)).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .body(image))
+}
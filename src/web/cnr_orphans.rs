@@ -0,0 +1,176 @@
+//! Mapping drift between the configured sensors and the readings database: configured cnr ids
+//! that stopped producing readings and cnr ids with readings that no channel points to.
+
+use std::collections::HashSet;
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use mysql::params;
+
+use crate::models::IdType;
+
+/// A (site, sensor, channel) triple of cnr ids found in the readings database
+#[derive(Clone, Debug, PartialEq, juniper::GraphQLObject)]
+pub struct CnrChannelActivity {
+    pub site_id_cnr: String,
+    pub sensor_id_cnr: String,
+    pub channel_id_cnr: String,
+    pub last_reading_at: NaiveDateTime,
+}
+
+/// A configured sensor with its cnr ids
+pub struct MappedSensor {
+    pub id: IdType,
+    pub site_id_cnr: Option<String>,
+    pub sensor_id_cnr: Option<String>,
+}
+
+/// A configured channel with its cnr ids
+pub struct MappedChannel {
+    pub id: IdType,
+    pub site_id_cnr: Option<String>,
+    pub sensor_id_cnr: Option<String>,
+    pub channel_id_cnr: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct OrphanReport {
+    /// Sensors mapped to cnr ids without any recent reading
+    pub stale_sensor_ids: Vec<IdType>,
+    /// Channels mapped to cnr ids without any recent reading
+    pub stale_channel_ids: Vec<IdType>,
+    /// Recent readings that don't belong to any configured channel
+    pub unmapped: Vec<CnrChannelActivity>,
+}
+
+/// Loads the cnr ids of the sensors and the channels that aren't deleted, the disabled sensors are
+/// skipped if only_enabled is true.
+pub fn load_mapped_ids(conn: &PgConnection, only_enabled: bool) -> QueryResult<(Vec<MappedSensor>, Vec<MappedChannel>)> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let mut sensors = sensor_dsl::sensor
+        .inner_join(site_dsl::site)
+        .filter(sensor_dsl::deleted_at.is_null())
+        .select((sensor_dsl::id, site_dsl::id_cnr, sensor_dsl::id_cnr))
+        .order(sensor_dsl::id)
+        .into_boxed();
+    let mut channels = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .filter(channel_dsl::deleted_at.is_null())
+        .select((channel_dsl::id, site_dsl::id_cnr, sensor_dsl::id_cnr, channel_dsl::id_cnr))
+        .order(channel_dsl::id)
+        .into_boxed();
+    if only_enabled {
+        sensors = sensors.filter(sensor_dsl::enabled.eq(true));
+        channels = channels.filter(sensor_dsl::enabled.eq(true));
+    }
+
+    let sensors = sensors
+        .load::<(IdType, Option<String>, Option<String>)>(conn)?
+        .into_iter()
+        .map(|(id, site_id_cnr, sensor_id_cnr)| MappedSensor { id, site_id_cnr, sensor_id_cnr })
+        .collect();
+    let channels = channels
+        .load::<(IdType, Option<String>, Option<String>, Option<String>)>(conn)?
+        .into_iter()
+        .map(|(id, site_id_cnr, sensor_id_cnr, channel_id_cnr)| MappedChannel { id, site_id_cnr, sensor_id_cnr, channel_id_cnr })
+        .collect();
+    Ok((sensors, channels))
+}
+
+/// Loads the cnr ids that produced readings after since, with their last reading.
+/// It scans every reading after since so it should only be run by the admins.
+pub fn load_cnr_activity(pool: &mysql::Pool, since: NaiveDateTime) -> Result<Vec<CnrChannelActivity>, mysql::Error> {
+    let result = pool.prep_exec(
+        "SELECT idsito, idsensore, canale, MAX(data) FROM t_rilevamento_dati WHERE data > :since \
+         GROUP BY idsito, idsensore, canale ORDER BY idsito, idsensore, canale;",
+        params!{"since" => since}
+    )?;
+    result.map(|row| {
+        let (site_id_cnr, sensor_id_cnr, channel_id_cnr, last_reading_at) =
+            mysql::from_row_opt::<(String, String, String, NaiveDateTime)>(row?)?;
+        Ok(CnrChannelActivity { site_id_cnr, sensor_id_cnr, channel_id_cnr, last_reading_at })
+    }).collect()
+}
+
+/// Compares the configured cnr ids with the ones found in the readings, the sensors and the
+/// channels without a complete cnr mapping are ignored.
+pub fn find_orphans(sensors: &[MappedSensor], channels: &[MappedChannel], activity: Vec<CnrChannelActivity>) -> OrphanReport {
+    let active_sensors: HashSet<(&str, &str)> = activity.iter()
+        .map(|x| (x.site_id_cnr.as_str(), x.sensor_id_cnr.as_str()))
+        .collect();
+    let active_channels: HashSet<(&str, &str, &str)> = activity.iter()
+        .map(|x| (x.site_id_cnr.as_str(), x.sensor_id_cnr.as_str(), x.channel_id_cnr.as_str()))
+        .collect();
+
+    let stale_sensor_ids = sensors.iter()
+        .filter(|x| match (&x.site_id_cnr, &x.sensor_id_cnr) {
+            (Some(site), Some(sensor)) => !active_sensors.contains(&(site.as_str(), sensor.as_str())),
+            _ => false,
+        })
+        .map(|x| x.id)
+        .collect();
+
+    let mut mapped_channels = HashSet::new();
+    let mut stale_channel_ids = Vec::new();
+    for channel in channels {
+        if let (Some(site), Some(sensor), Some(cnr_channel)) = (&channel.site_id_cnr, &channel.sensor_id_cnr, &channel.channel_id_cnr) {
+            let key = (site.as_str(), sensor.as_str(), cnr_channel.as_str());
+            if !active_channels.contains(&key) {
+                stale_channel_ids.push(channel.id);
+            }
+            mapped_channels.insert(key);
+        }
+    }
+
+    let unmapped = activity.iter()
+        .filter(|x| !mapped_channels.contains(&(x.site_id_cnr.as_str(), x.sensor_id_cnr.as_str(), x.channel_id_cnr.as_str())))
+        .cloned()
+        .collect();
+
+    OrphanReport { stale_sensor_ids, stale_channel_ids, unmapped }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn test_find_orphans() {
+        let cnr = |x: &str| Some(x.to_string());
+        let last_reading_at = NaiveDate::from_ymd(2020, 4, 1).and_hms(12, 0, 0);
+        let activity = |sensor: &str, channel: &str| CnrChannelActivity {
+            site_id_cnr: "S1".to_string(),
+            sensor_id_cnr: sensor.to_string(),
+            channel_id_cnr: channel.to_string(),
+            last_reading_at,
+        };
+
+        let sensors = vec![
+            MappedSensor { id: 1, site_id_cnr: cnr("S1"), sensor_id_cnr: cnr("A") },
+            MappedSensor { id: 2, site_id_cnr: cnr("S1"), sensor_id_cnr: cnr("B") },
+            // Not mapped
+            MappedSensor { id: 3, site_id_cnr: cnr("S1"), sensor_id_cnr: None },
+        ];
+        let channels = vec![
+            MappedChannel { id: 10, site_id_cnr: cnr("S1"), sensor_id_cnr: cnr("A"), channel_id_cnr: cnr("1") },
+            MappedChannel { id: 11, site_id_cnr: cnr("S1"), sensor_id_cnr: cnr("A"), channel_id_cnr: cnr("2") },
+            MappedChannel { id: 20, site_id_cnr: cnr("S1"), sensor_id_cnr: cnr("B"), channel_id_cnr: cnr("1") },
+            MappedChannel { id: 30, site_id_cnr: cnr("S1"), sensor_id_cnr: None, channel_id_cnr: cnr("1") },
+        ];
+        let report = find_orphans(&sensors, &channels, vec![activity("A", "1"), activity("A", "3"), activity("C", "1")]);
+
+        assert_eq!(report, OrphanReport {
+            stale_sensor_ids: vec![2],
+            stale_channel_ids: vec![11, 20],
+            unmapped: vec![activity("A", "3"), activity("C", "1")],
+        });
+    }
+}
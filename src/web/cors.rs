@@ -0,0 +1,28 @@
+//! Cross-origin access for the web frontends served from another origin, so that they can call
+//! the API directly instead of proxying every request.
+//!
+//! With the credentials allowed the browsers also send the auth cookie, but only if the frontend is
+//! on the same site of the server or if the cookie is `SameSite=None` (see identity_cookie).
+
+use actix_cors::{Cors, CorsFactory};
+use actix_web::http::header;
+use actix_web::middleware::Condition;
+
+use crate::config::CorsConfig;
+
+/// Builds the CORS middleware, it's disabled without allowed origins
+pub fn cors_middleware(config: &CorsConfig) -> Condition<CorsFactory> {
+    let mut cors = Cors::new()
+        .allowed_methods(vec!["GET", "POST", "DELETE"])
+        .allowed_headers(vec![header::CONTENT_TYPE, header::AUTHORIZATION, header::HeaderName::from_static("x-api-key")])
+        // The file name of the exports
+        .expose_headers(vec![header::CONTENT_DISPOSITION])
+        .max_age(config.max_age);
+    for origin in config.allowed_origins.iter() {
+        cors = cors.allowed_origin(origin);
+    }
+    if config.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    Condition::new(!config.allowed_origins.is_empty(), cors.finish())
+}
@@ -0,0 +1,117 @@
+//! Age of the newest reading of the channels, an acquisition chain that falls behind shows up
+//! here before the NoData alarms are raised.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use mysql::Value;
+
+use crate::models::IdType;
+
+use super::errors::ServiceResult;
+
+#[derive(Clone, Debug, PartialEq, juniper::GraphQLObject)]
+pub struct DataLatency {
+    pub channel_id: IdType,
+    pub last_reading_at: NaiveDateTime,
+    /// Seconds between the newest reading and now
+    pub seconds: f64,
+    /// Seconds between two readings as reported by the acquisition chain, null if unknown
+    pub sampling_step: Option<f64>,
+    /// Readings that should have arrived after the newest one (seconds / samplingStep), null if
+    /// the sampling step is unknown
+    pub missed_steps: Option<f64>,
+}
+
+pub fn data_latency(channel_id: IdType, last_reading_at: NaiveDateTime, step: Option<f32>, now: NaiveDateTime) -> DataLatency {
+    let seconds = (now - last_reading_at).num_milliseconds().max(0) as f64 / 1000.0;
+    let sampling_step = step.filter(|x| *x > 0.0).map(f64::from);
+    DataLatency {
+        channel_id,
+        last_reading_at,
+        seconds,
+        sampling_step,
+        missed_steps: sampling_step.map(|x| seconds / x),
+    }
+}
+
+/// Date and step of the newest reading of a (sensor, channel) pair
+pub type LastReading = (NaiveDateTime, Option<f32>);
+
+/// Loads the date and the step of the newest reading of every (sensor, channel) pair of the
+/// site in a single query, the pairs without readings are not returned.
+pub fn load_last_reading_times(pool: &mysql::Pool, site_id: &str, channels: &[(&str, &str)]) -> ServiceResult<HashMap<(String, String), LastReading>> {
+    if channels.is_empty() {
+        return Ok(HashMap::new())
+    }
+
+    let filter = vec!["(?, ?)"; channels.len()].join(", ");
+    let query = format!(
+        "SELECT data, step, idsensore, canale FROM (\
+            SELECT data, step, idsensore, canale, \
+            ROW_NUMBER() OVER (PARTITION BY idsensore, canale ORDER BY data DESC) AS row_index \
+            FROM t_rilevamento_dati WHERE idsito = ? AND (idsensore, canale) IN ({})\
+         ) AS tmp WHERE row_index = 1;",
+        filter
+    );
+
+    let mut params: Vec<Value> = Vec::with_capacity(1 + channels.len() * 2);
+    params.push(site_id.into());
+    for (sensor_id, channel_id) in channels {
+        params.push((*sensor_id).into());
+        params.push((*channel_id).into());
+    }
+
+    let result = pool.prep_exec(query, params)?;
+    let mut res = HashMap::new();
+    for row in result {
+        let (date, step, sensor_id, channel_id) = mysql::from_row_opt::<(NaiveDateTime, Option<f32>, String, String)>(row?)
+            .map_err(mysql::Error::from)?;
+        res.insert((sensor_id, channel_id), (date, step));
+    }
+    Ok(res)
+}
+
+/// Latency of every channel with readings, the channels are given with their cnr ids
+pub fn load_data_latencies(pool: &mysql::Pool, channels: &[(IdType, (String, String, String))], now: NaiveDateTime) -> ServiceResult<Vec<DataLatency>> {
+    let mut sites: HashMap<&str, Vec<(IdType, &str, &str)>> = HashMap::new();
+    for (id, (site, sensor, channel)) in channels {
+        sites.entry(site.as_str()).or_default().push((*id, sensor.as_str(), channel.as_str()));
+    }
+
+    let mut res = Vec::new();
+    for (site, site_channels) in sites {
+        let pairs: Vec<(&str, &str)> = site_channels.iter().map(|x| (x.1, x.2)).collect();
+        let last_readings = load_last_reading_times(pool, site, &pairs)?;
+        for (id, sensor, channel) in site_channels {
+            if let Some((date, step)) = last_readings.get(&(sensor.to_string(), channel.to_string())) {
+                res.push(data_latency(id, *date, *step, now));
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate};
+
+    use super::*;
+
+    #[test]
+    fn test_data_latency() {
+        let now = NaiveDate::from_ymd(2020, 4, 20).and_hms(12, 0, 0);
+
+        let latency = data_latency(3, now - Duration::minutes(15), Some(300.0), now);
+        assert_eq!(latency.seconds, 900.0);
+        assert_eq!(latency.sampling_step, Some(300.0));
+        assert_eq!(latency.missed_steps, Some(3.0));
+
+        // Unknown step
+        let latency = data_latency(3, now - Duration::minutes(15), Some(0.0), now);
+        assert_eq!((latency.sampling_step, latency.missed_steps), (None, None));
+
+        // Readings from a clock ahead of the server's
+        assert_eq!(data_latency(3, now + Duration::seconds(5), None, now).seconds, 0.0);
+    }
+}
@@ -0,0 +1,32 @@
+//! Connection given to the resolvers, it's either a new connection of the pool or the one of the
+//! transaction opened by `Context::transaction`, so that every query of the transaction uses the
+//! same connection.
+//!
+//! It can't be used directly by diesel (the borrowed connection isn't Send), the queries take the
+//! inner connection with `&*conn`.
+
+use std::cell::Ref;
+use std::ops::Deref;
+
+use diesel::pg::PgConnection;
+use diesel::r2d2::ConnectionManager;
+use r2d2::PooledConnection;
+
+pub type PooledPgConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+pub enum ContextConnection<'a> {
+    Pooled(PooledPgConnection),
+    /// The connection of the open transaction
+    Transaction(Ref<'a, PooledPgConnection>),
+}
+
+impl<'a> Deref for ContextConnection<'a> {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match self {
+            ContextConnection::Pooled(x) => x,
+            ContextConnection::Transaction(x) => x,
+        }
+    }
+}
@@ -1,11 +1,27 @@
 use std::collections::HashMap;
 
+use chrono::NaiveDateTime;
 use diesel::{PgConnection, prelude::*};
 use mysql::params;
+use serde::Deserialize;
+use utoipa::ToSchema;
 
-use crate::models::IdType;
+use crate::models::{Channel, IdType, Pool};
 use crate::schema::*;
-use crate::web::errors::ServiceResult;
+use crate::web::errors::{ServiceError, ServiceResult};
+use crate::web::graphql_schema::ReadingData;
+
+/// How the readings are grouped before being returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, juniper::GraphQLEnum, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadingsAggregation {
+    /// Every reading as stored
+    Raw,
+    /// One reading per hour (min of the minimums, average, max of the maximums)
+    Hourly,
+    /// One reading per day (min of the minimums, average, max of the maximums)
+    Daily,
+}
 
 #[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
 #[table_name="sensor"]
@@ -74,12 +90,14 @@ fn guess_channel_info(m_type: &str) -> ChannelDetectedData {
     }
 }
 
-pub fn auto_create_site(site_id: IdType, cnr_id: &str, conn: &PgConnection, mysql_conn: &mysql::Pool) -> ServiceResult<()> {
+/// Creates the sensors and channels of the site found in its last `readings` readings
+pub fn auto_create_site(site_id: IdType, cnr_id: &str, readings: u32, conn: &PgConnection, mysql_conn: &mysql::Pool) -> ServiceResult<()> {
     use crate::schema::sensor::dsl as sensor_dsl;
     use crate::schema::channel::dsl as channel_dsl;
 
-    let res = mysql_conn.prep_exec("SELECT DISTINCT idsensore, canale, misura FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT 1000) AS tmp;", params!{
-        "site_id" => cnr_id
+    let res = mysql_conn.prep_exec("SELECT DISTINCT idsensore, canale, misura FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT :n) AS tmp;", params!{
+        "site_id" => cnr_id,
+        "n" => readings,
     })?;
 
     struct ChannelData {
@@ -129,12 +147,14 @@ struct ChannelData {
     Ok(())
 }
 
-pub fn auto_create_sensor(site_cnr_id: &str, sensor_id: IdType, cnr_id: &str, conn: &PgConnection, mysql_conn: &mysql::Pool) -> ServiceResult<()> {
+/// Creates the channels of the sensor found in its last `readings` readings
+pub fn auto_create_sensor(site_cnr_id: &str, sensor_id: IdType, cnr_id: &str, readings: u32, conn: &PgConnection, mysql_conn: &mysql::Pool) -> ServiceResult<()> {
     use crate::schema::channel::dsl as channel_dsl;
 
-    let res = mysql_conn.prep_exec("SELECT DISTINCT canale, misura FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id ORDER BY data DESC LIMIT 100) AS tmp;", params!{
+    let res = mysql_conn.prep_exec("SELECT DISTINCT canale, misura FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id ORDER BY data DESC LIMIT :n) AS tmp;", params!{
         "site_id" => site_cnr_id,
         "sensor_id" => cnr_id,
+        "n" => readings,
     })?;
 
     let channels: Vec<AutoChannelData> = res.map(|row| {
@@ -156,3 +176,272 @@ pub fn auto_create_sensor(site_cnr_id: &str, sensor_id: IdType, cnr_id: &str, co
 
     Ok(())
 }
+
+/// Loads the channel, it's not found if it's in the trash or if its sensor or site are
+pub fn load_live_channel(conn: &PgConnection, channel_id: IdType) -> ServiceResult<Channel> {
+    channel::table.inner_join(sensor::table.inner_join(site::table))
+        .filter(channel::id.eq(channel_id))
+        .filter(channel::deleted_at.is_null())
+        .filter(sensor::deleted_at.is_null())
+        .filter(site::deleted_at.is_null())
+        .select(channel::all_columns)
+        .first::<Channel>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Channel".to_string()))
+}
+
+/// Finds the cnr ids (site, sensor, channel) of a channel, the channel cnr id can also contain
+/// the sensor id or both the site and the sensor ids separated by dots.
+pub fn query_channel_cnr_ids(pool: &Pool, channel_id: IdType, channel_id_cnr: Option<&str>) -> ServiceResult<Option<(String, String, String)>> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    let mut channel = match channel_id_cnr {
+        None => return Ok(None),
+        Some(x) => x.to_string()
+    };
+
+    let first_separator = channel.find('.');
+    let second_separator = if let Some(findex) = first_separator {
+        channel[findex + 1..].find('.')
+    } else { None };
+
+    if let (Some(first_index), Some(second_index)) = (first_separator, second_separator) {
+        // Shortcut, we already know site, sensor and channel ids, we just need to parse them
+        // Don't even need to open a connection
+        // format: site.sensor.channel
+        //             |      ^second_index
+        //             ^first_index
+        return Ok(Some((
+            channel[0..first_index].to_string(),
+            channel[first_index + 1..second_index].to_string(),
+            channel[second_index + 1..].to_string()
+        )));
+    }
+
+    // No shortcut allowed, we need at least the cnr_site_id
+    // Since we need it we'll get both site_id and sensor_id from the query and then we'll
+    // override the sensor_id if a separator is present (to maximize efficiency we should
+    // separate the queries but it's not that important, the inner joins always take place so
+    // we could only remove the extra sensor_id string...)
+
+    let conn = pool.get()?;
+
+    let mut site_sensor = channel_dsl::channel.find(channel_id)
+        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .select((site_dsl::id_cnr, sensor_dsl::id_cnr))
+        .get_result::<(Option<String>, Option<String>)>(&conn)?;
+
+    if let Some(first_index) = first_separator {
+        // format: sensor.channel
+        site_sensor.1 = Some(channel[0..first_index].to_string());
+        channel = channel[first_index + 1..].to_string();
+    }
+
+    let res = if let (Some(site_id), Some(sensor_id)) = site_sensor {
+        Some((site_id, sensor_id, channel))
+    } else { None };
+
+    Ok(res)
+}
+
+/// Readings interval (start, end) marked as invalid, both ends included
+pub type InvalidInterval = (NaiveDateTime, NaiveDateTime);
+
+/// Loads the intervals of the channel marked as invalid that overlap the start-end range.
+pub fn load_invalid_intervals(conn: &PgConnection, channel_id: IdType, start: NaiveDateTime, end: NaiveDateTime) -> QueryResult<Vec<InvalidInterval>> {
+    use crate::schema::reading_annotation::dsl;
+
+    dsl::reading_annotation
+        .filter(dsl::channel_id.eq(channel_id))
+        .filter(dsl::start_time.le(end))
+        .filter(dsl::end_time.ge(start))
+        .select((dsl::start_time, dsl::end_time))
+        .load(conn)
+}
+
+/// Builds the condition and the parameters that select the readings of a channel between start
+/// and end, skipping the excluded intervals.
+fn channel_readings_filter(ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval]) -> (String, mysql::Params) {
+    let mut condition = "data >= :start AND data <= :end AND idsito = :site_id AND idsensore = :sensor_id \
+                         AND canale = :channel_id".to_string();
+    let mut params = params! {
+        "start" => start,
+        "end" => end,
+        "site_id" => &ids.0,
+        "sensor_id" => &ids.1,
+        "channel_id" => &ids.2,
+    };
+    for (index, (interval_start, interval_end)) in excluded.iter().enumerate() {
+        condition += &format!(" AND data NOT BETWEEN :invalid_start_{0} AND :invalid_end_{0}", index);
+        params.push((format!("invalid_start_{}", index), (*interval_start).into()));
+        params.push((format!("invalid_end_{}", index), (*interval_end).into()));
+    }
+    (condition, params.into())
+}
+
+/// Loads the readings of a channel (identified by its cnr ids) between start and end, the readings
+/// in the excluded intervals are skipped.
+pub fn load_channel_readings(mysql_conn: &mysql::Pool, ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval]) -> ServiceResult<Vec<ReadingData>> {
+    let mut res = Vec::new();
+    for_each_channel_reading(mysql_conn, ids, start, end, excluded, |reading| {
+        res.push(reading);
+        true
+    })?;
+    Ok(res)
+}
+
+/// Counts the readings that load_channel_readings would return, without loading them.
+pub fn count_channel_readings(mysql_conn: &mysql::Pool, ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval]) -> ServiceResult<u64> {
+    let (condition, params) = channel_readings_filter(ids, start, end, excluded);
+    let count = mysql_conn.first_exec(
+        format!("SELECT COUNT(*) FROM t_rilevamento_dati WHERE {};", condition),
+        params
+    ).map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+    Ok(count.map(mysql::from_row::<u64>).unwrap_or(0))
+}
+
+/// Loads the newest n readings of a channel, in chronological order.
+pub fn load_last_channel_readings(mysql_conn: &mysql::Pool, ids: &(String, String, String), n: u32) -> ServiceResult<Vec<ReadingData>> {
+    let result = mysql_conn.prep_exec(
+        "SELECT data, valore_min, valore_med, valore_max, scarto, errore FROM t_rilevamento_dati \
+         WHERE idsito = :site_id AND idsensore = :sensor_id AND canale = :channel_id ORDER BY data DESC LIMIT :n;",
+        params! {
+            "site_id" => &ids.0,
+            "sensor_id" => &ids.1,
+            "channel_id" => &ids.2,
+            "n" => n,
+        }
+    ).map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    let mut res = Vec::new();
+    for row in result {
+        let row = row.map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+        let (date, value_min, value_avg, value_max, deviation, error) =
+            mysql::from_row::<(NaiveDateTime, f64, Option<f64>, Option<f64>, Option<f64>, Option<String>)>(row);
+        res.push(ReadingData { date, value_min, value_avg, value_max, deviation, error });
+    }
+    res.reverse();
+    Ok(res)
+}
+
+/// Like load_channel_readings but the readings are passed one at a time to the callback (in
+/// chronological order) without keeping them in memory, the iteration stops when it returns false.
+pub fn for_each_channel_reading<F: FnMut(ReadingData) -> bool>(mysql_conn: &mysql::Pool, ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval], mut callback: F) -> ServiceResult<()> {
+    let (condition, params) = channel_readings_filter(ids, start, end, excluded);
+    let result = mysql_conn.prep_exec(
+        format!(
+            "SELECT data, valore_min, valore_med, valore_max, scarto, errore FROM t_rilevamento_dati \
+             WHERE {} ORDER BY data;",
+            condition
+        ),
+        params
+    ).map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    for row in result {
+        let row = row.map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+        let (date, value_min, value_avg, value_max, deviation, error) =
+            mysql::from_row::<(NaiveDateTime, f64, Option<f64>, Option<f64>, Option<f64>, Option<String>)>(row);
+        let reading = ReadingData {
+            date,
+            value_min,
+            value_avg,
+            value_max,
+            deviation,
+            error,
+        };
+        if !callback(reading) {
+            break
+        }
+    }
+    Ok(())
+}
+
+/// Loads the readings of a channel grouping them by hour or day on the database side, the date of
+/// every reading is the start of its group.
+pub fn load_channel_readings_aggregated(mysql_conn: &mysql::Pool, ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval], aggregation: ReadingsAggregation) -> ServiceResult<Vec<ReadingData>> {
+    let group = match aggregation {
+        ReadingsAggregation::Raw => return load_channel_readings(mysql_conn, ids, start, end, excluded),
+        ReadingsAggregation::Hourly => "TIMESTAMP(DATE_FORMAT(data, '%Y-%m-%d %H:00:00'))",
+        ReadingsAggregation::Daily => "TIMESTAMP(DATE(data))",
+    };
+
+    let (condition, params) = channel_readings_filter(ids, start, end, excluded);
+    let result = mysql_conn.prep_exec(
+        format!(
+            "SELECT {} AS bucket, MIN(valore_min), AVG(COALESCE(valore_med, valore_min)), MAX(COALESCE(valore_max, valore_min)) \
+             FROM t_rilevamento_dati WHERE {} GROUP BY bucket ORDER BY bucket;",
+            group, condition
+        ),
+        params
+    );
+
+    result.map(|qres| {
+        qres.map(|row| {
+            let (date, value_min, value_avg, value_max) =
+                mysql::from_row::<(NaiveDateTime, f64, Option<f64>, Option<f64>)>(row.unwrap());
+            ReadingData {
+                date,
+                value_min,
+                value_avg,
+                value_max,
+                deviation: None,
+                error: None,
+            }
+        }).collect()
+    }).map_err(|x| ServiceError::InternalServerError(x.to_string()))
+}
+
+/// Statistics of the readings of a channel over a period, the values are null if there are no
+/// readings
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct ReadingStats {
+    pub count: i32,
+    /// Lowest minimum
+    pub min: Option<f64>,
+    /// Highest maximum
+    pub max: Option<f64>,
+    /// Mean of the average values
+    pub mean: Option<f64>,
+    /// Population standard deviation of the average values
+    pub stddev: Option<f64>,
+    /// 95th percentile of the average values (nearest rank)
+    pub p95: Option<f64>,
+}
+
+impl ReadingStats {
+    pub fn empty() -> Self {
+        ReadingStats { count: 0, min: None, max: None, mean: None, stddev: None, p95: None }
+    }
+}
+
+/// Computes the statistics of the readings of a channel between start and end on the database
+/// side, the readings in the excluded intervals are skipped.
+pub fn load_channel_reading_stats(mysql_conn: &mysql::Pool, ids: &(String, String, String), start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval]) -> ServiceResult<ReadingStats> {
+    let (condition, params) = channel_readings_filter(ids, start, end, excluded);
+    let row = mysql_conn.first_exec(
+        format!(
+            "SELECT COUNT(*), MIN(value_min), MAX(value_max), AVG(value), STDDEV_POP(value), \
+             MIN(CASE WHEN dist >= 0.95 THEN value END) FROM (\
+                SELECT valore_min AS value_min, COALESCE(valore_max, valore_min) AS value_max, \
+                COALESCE(valore_med, valore_min) AS value, \
+                CUME_DIST() OVER (ORDER BY COALESCE(valore_med, valore_min)) AS dist \
+                FROM t_rilevamento_dati WHERE {}\
+             ) AS readings;",
+            condition
+        ),
+        params
+    ).map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    Ok(match row {
+        Some(row) => {
+            let (count, min, max, mean, stddev, p95) =
+                mysql::from_row::<(i64, Option<f64>, Option<f64>, Option<f64>, Option<f64>, Option<f64>)>(row);
+            ReadingStats { count: count.min(i32::MAX as i64) as i32, min, max, mean, stddev, p95 }
+        },
+        None => ReadingStats::empty(),
+    })
+}
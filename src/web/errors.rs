@@ -1,9 +1,12 @@
-use actix_web::{http::StatusCode, ResponseError, web::HttpResponse};
+use actix_web::{http::header, http::StatusCode, ResponseError, web::HttpResponse};
 use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
 use juniper::FieldError;
 use mysql::Error as MySqlError;
 
+use crate::alarm::DatabaseError;
+use crate::redact::redact_db_details;
+
 #[derive(Debug, Display)]
 pub enum ServiceError {
     #[display(fmt = "Internal Server Error: {}", _0)]
@@ -29,6 +32,28 @@ pub enum ServiceError {
 
     #[display(fmt = "Too Many Requests")]
     TooManyRequests,
+
+    /// Too many wrong passwords, contains the seconds to wait before the next attempt
+    #[display(fmt = "Login Throttled: {}s", _0)]
+    LoginThrottled(u64),
+
+    /// The action must be confirmed by a second admin, contains the id of the pending action
+    #[display(fmt = "Approval Pending: {}", _0)]
+    ApprovalPending(i32),
+}
+
+impl ServiceError {
+    /// Returns true if the error has been caused by the client's request (and not by a server
+    /// failure).
+    pub fn is_client_error(&self) -> bool {
+        match self {
+            ServiceError::InternalServerError(_) | ServiceError::TooManyRequests |
+            ServiceError::LoginThrottled(_) => false,
+            ServiceError::BadRequest(_) | ServiceError::NotFound(_) | ServiceError::Unauthorized |
+            ServiceError::WrongPassword | ServiceError::LoginRequired | ServiceError::AlreadyPresent(_) |
+            ServiceError::ApprovalPending(_) => true,
+        }
+    }
 }
 
 impl juniper::IntoFieldError for ServiceError {
@@ -83,7 +108,24 @@ fn into_field_error(self) -> FieldError {
                 graphql_value!({
                     "type": "TOO_MANY_REQUESTS"
                 })
-            )
+            ),
+            ServiceError::LoginThrottled(secs) => {
+                let secs = secs.min(i32::max_value() as u64) as i32;
+                FieldError::new(
+                    "Too many wrong passwords, retry later",
+                    graphql_value!({
+                        "type": "LOGIN_THROTTLED",
+                        "retryAfter": secs
+                    })
+                )
+            },
+            ServiceError::ApprovalPending(id) => FieldError::new(
+                "The action must be confirmed by another admin",
+                graphql_value!({
+                    "type": "APPROVAL_PENDING",
+                    "pendingActionId": id
+                })
+            ),
         }
     }
 }
@@ -92,11 +134,14 @@ impl From<DBError> for ServiceError {
     fn from(error: DBError) -> ServiceError {
         match error {
             DBError::DatabaseError(kind, info) => {
-                let message = info.details().unwrap_or_else(|| info.message()).to_string();
+                let message = redact_db_details(info.details().unwrap_or_else(|| info.message()));
                 if let DatabaseErrorKind::UniqueViolation = kind {
                     ServiceError::AlreadyPresent(message)
                 } else {
-                    ServiceError::InternalServerError(format!("DB error, {:?} {:?}", kind, info))
+                    ServiceError::InternalServerError(format!(
+                        "DB error, {:?} {} (table: {:?}, constraint: {:?})",
+                        kind, message, info.table_name(), info.constraint_name()
+                    ))
                 }
             }
             err => ServiceError::InternalServerError(format!("DB error, {}", err)),
@@ -116,6 +161,12 @@ fn from(error: MySqlError) -> ServiceError {
     }
 }
 
+impl From<DatabaseError> for ServiceError {
+    fn from(error: DatabaseError) -> ServiceError {
+        ServiceError::InternalServerError(format!("Readings error: {}", error))
+    }
+}
+
 impl ResponseError for ServiceError {
     fn error_response(&self) -> HttpResponse {
         match self {
@@ -127,6 +178,10 @@ fn error_response(&self) -> HttpResponse {
             ServiceError::LoginRequired => HttpResponse::Unauthorized().message_body("Login required".into()),
             ServiceError::AlreadyPresent(x) => HttpResponse::BadRequest().message_body(format!("{} Already Present", x).into()),
             ServiceError::TooManyRequests => HttpResponse::new(StatusCode::TOO_MANY_REQUESTS),
+            ServiceError::LoginThrottled(x) => HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, x.to_string())
+                .finish(),
+            ServiceError::ApprovalPending(x) => HttpResponse::Accepted().message_body(format!("Approval Pending: {}", x).into()),
         }
     }
 }
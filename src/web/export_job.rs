@@ -0,0 +1,316 @@
+//! Background exports of the date ranges too big to be sent in a single response.
+//!
+//! The file is written by a separate thread in the export directory, so that the database
+//! isn't kept busy by a client that downloads slowly. When it's ready the user that asked for it
+//! is notified with a signed download link, the files are deleted after the retention time.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::config::Resolver;
+use crate::contact::ExportReadyData;
+use crate::models::{ExportJob, IdType};
+
+use super::blocking::run_blocking;
+use super::errors::{ServiceError, ServiceResult};
+use super::export_manifest::{complete_manifest, ExportDigest};
+use super::export_service::{build_xlsx, ExportFormat, ExportQuery, ExportSource, write_csv};
+
+#[derive(Clone, Debug)]
+pub struct ExportJobConfig {
+    /// Exports with more readings than this are written in the background
+    pub row_threshold: u64,
+    pub directory: PathBuf,
+    /// The jobs (and their files) are deleted after this time
+    pub retention: Duration,
+    /// Prepended to the download links sent to the users, e.g. https://oldmusa.example.com
+    pub base_url: String,
+}
+
+impl Default for ExportJobConfig {
+    fn default() -> Self {
+        ExportJobConfig {
+            row_threshold: 500_000,
+            directory: PathBuf::from("exports"),
+            retention: Duration::from_secs(7 * 24 * 3600),
+            base_url: String::new(),
+        }
+    }
+}
+
+/// `[export_jobs]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportJobSection {
+    pub row_threshold: Option<u64>,
+    pub directory: Option<PathBuf>,
+    pub retention_hours: Option<u64>,
+    pub base_url: Option<String>,
+}
+
+impl ExportJobConfig {
+    pub fn resolve(r: &mut Resolver, file: ExportJobSection) -> Self {
+        let default = ExportJobConfig::default();
+        ExportJobConfig {
+            row_threshold: r.optional("EXPORT_JOB_ROW_THRESHOLD", file.row_threshold).unwrap_or(default.row_threshold),
+            directory: r.optional("EXPORT_JOB_DIR", file.directory).unwrap_or(default.directory),
+            retention: r.optional("EXPORT_JOB_RETENTION_HOURS", file.retention_hours).map_or(default.retention, |x| Duration::from_secs(x * 3600)),
+            base_url: r.optional("EXPORT_JOB_BASE_URL", file.base_url).map_or(default.base_url, |x: String| x.trim_end_matches('/').to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, juniper::GraphQLEnum)]
+pub enum ExportJobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+impl ExportJobStatus {
+    pub fn from_char(name: &str) -> Option<ExportJobStatus> {
+        match name {
+            "r" => Some(ExportJobStatus::Running),
+            "d" => Some(ExportJobStatus::Done),
+            "f" => Some(ExportJobStatus::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            ExportJobStatus::Running => "r",
+            ExportJobStatus::Done => "d",
+            ExportJobStatus::Failed => "f",
+        }
+    }
+}
+
+/// Export that has to be written in the background
+pub struct ExportJobRequest {
+    /// User notified when the file is ready, None for the api keys
+    pub user_id: Option<IdType>,
+    pub channel_id: IdType,
+    pub format: ExportFormat,
+    pub source: ExportSource,
+    pub query: ExportQuery,
+    /// Readings to export, as counted before starting
+    pub rows: u64,
+    /// Manifest completed with the checksum of the file
+    pub manifest_id: IdType,
+}
+
+#[derive(Deserialize)]
+pub struct ExportJobQuery {
+    pub(crate) signature: String,
+}
+
+fn export_job_data(job_id: IdType) -> String {
+    format!("export/job/{}", job_id)
+}
+
+/// Returns the path (with the signature) of the file of the export job.
+pub fn export_job_path(ctx: &AppData, job_id: IdType) -> String {
+    let signature = ctx.auth_cache.sign_url(&export_job_data(job_id));
+    format!("/api/export/job/{}?signature={}", job_id, signature)
+}
+
+fn export_job_file(ctx: &AppData, job_id: IdType, format: ExportFormat) -> PathBuf {
+    ctx.export_jobs.directory.join(format!("job_{}.{}", job_id, format.name()))
+}
+
+/// Deletes the jobs older than the retention time and their files.
+fn delete_expired_jobs(ctx: &AppData, now: NaiveDateTime) -> ServiceResult<()> {
+    use crate::schema::export_job::dsl;
+
+    let retention = chrono::Duration::from_std(ctx.export_jobs.retention)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+    let conn = ctx.pool.get()?;
+    let expired = dsl::export_job
+        .filter(dsl::created_at.lt(now - retention))
+        .select((dsl::id, dsl::format))
+        .load::<(IdType, String)>(&conn)?;
+
+    for (id, format) in expired.iter() {
+        let path = match ExportFormat::from_name(format) {
+            Some(format) => export_job_file(ctx, *id, format),
+            None => continue,
+        };
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Cannot delete the expired export {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    let ids: Vec<IdType> = expired.iter().map(|x| x.0).collect();
+    diesel::delete(dsl::export_job.filter(dsl::id.eq_any(ids)))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Registers the job and starts writing its file in a separate thread, returns the job id.
+pub fn start_export_job(ctx: &AppData, request: ExportJobRequest) -> ServiceResult<IdType> {
+    use crate::schema::export_job::dsl;
+
+    let now = Utc::now().naive_utc();
+    if let Err(err) = delete_expired_jobs(ctx, now) {
+        warn!("Cannot delete the expired exports: {}", err);
+    }
+    std::fs::create_dir_all(&ctx.export_jobs.directory)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    let conn = ctx.pool.get()?;
+    let job_id = diesel::insert_into(dsl::export_job)
+        .values((
+            dsl::user_id.eq(request.user_id),
+            dsl::channel_id.eq(request.channel_id),
+            dsl::format.eq(request.format.name()),
+            dsl::status.eq(ExportJobStatus::Running.to_char()),
+            dsl::row_count.eq(request.rows as i64),
+            dsl::created_at.eq(now),
+        ))
+        .returning(dsl::id)
+        .get_result::<IdType>(&conn)?;
+    std::mem::drop(conn);
+
+    let ctx = ctx.clone();
+    std::thread::spawn(move || run_export_job(&ctx, job_id, request));
+    Ok(job_id)
+}
+
+/// Writes the file to a temporary path, moved in place only when it's complete.
+fn write_export_file(ctx: &AppData, job_id: IdType, format: ExportFormat, source: ExportSource, query: &ExportQuery) -> ServiceResult<ExportDigest> {
+    let io_error = |x: std::io::Error| ServiceError::InternalServerError(x.to_string());
+    let path = export_job_file(ctx, job_id, format);
+    let partial = path.with_extension("part");
+
+    let digest = match format {
+        ExportFormat::Csv => {
+            let file = File::create(&partial).map_err(io_error)?;
+            write_csv(&ctx.sensor_pool, source, query, BufWriter::new(file))?
+        },
+        ExportFormat::Xlsx => {
+            let (data, digest) = build_xlsx(ctx, source, query)?;
+            std::fs::write(&partial, data).map_err(io_error)?;
+            digest
+        },
+    };
+    std::fs::rename(&partial, &path).map_err(io_error)?;
+    Ok(digest)
+}
+
+fn run_export_job(ctx: &AppData, job_id: IdType, request: ExportJobRequest) {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        export_job::dsl,
+    };
+
+    let start = std::time::Instant::now();
+    let ExportJobRequest { user_id, channel_id, format, source, query, rows, manifest_id } = request;
+    let result = write_export_file(ctx, job_id, format, source, &query);
+    let (status, error) = match &result {
+        Ok(digest) => {
+            if let Err(err) = complete_manifest(&ctx.pool, manifest_id, digest) {
+                warn!("Cannot complete the manifest of the export job {}: {}", job_id, err);
+            }
+            info!("Export job {} wrote {} rows in {}s", job_id, rows, start.elapsed().as_secs());
+            (ExportJobStatus::Done, None)
+        },
+        Err(err) => {
+            warn!("Export job {} failed: {}", job_id, err);
+            (ExportJobStatus::Failed, Some(err.to_string().chars().take(255).collect::<String>()))
+        },
+    };
+
+    let notify = move || -> ServiceResult<()> {
+        let conn = ctx.pool.get()?;
+        diesel::update(dsl::export_job.find(job_id))
+            .set((
+                dsl::status.eq(status.to_char()),
+                dsl::error.eq(error),
+                dsl::finished_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&conn)?;
+
+        if let (ExportJobStatus::Done, Some(user_id)) = (status, user_id) {
+            let channel_name = channel_dsl::channel.find(channel_id)
+                .select(channel_dsl::name)
+                .first::<Option<String>>(&conn)?
+                .unwrap_or_else(|| "?".to_string());
+            let data = ExportReadyData {
+                user_id,
+                channel_name,
+                link: format!("{}{}", ctx.export_jobs.base_url, export_job_path(ctx, job_id)),
+                rows,
+            };
+            // The job thread has no runtime and the FCM client needs one
+            actix_rt::System::new("export").block_on(ctx.contacter.send_export_ready(&conn, &data))
+                .map_err(ServiceError::InternalServerError)?;
+        }
+        Ok(())
+    };
+    if let Err(err) = notify() {
+        warn!("Cannot complete the export job {}: {}", job_id, err);
+    }
+}
+
+/// Downloads the file of an export job, the url is signed by the server (see export_job_path) so
+/// that the link sent to the user works without logging in.
+/// While the file is being written the response is a 202 with the status of the job.
+pub async fn download_export_job(
+    ctx: web::Data<AppData>,
+    req: HttpRequest,
+    job_id: web::Path<IdType>,
+    query: web::Query<ExportJobQuery>,
+) -> ServiceResult<HttpResponse> {
+    use crate::schema::export_job::dsl;
+
+    let job_id = *job_id;
+    if !ctx.auth_cache.verify_url_signature(&export_job_data(job_id), &query.signature) {
+        return Err(ServiceError::Unauthorized)
+    }
+
+    let job_ctx = ctx.clone();
+    let job = run_blocking(move || -> ServiceResult<Option<ExportJob>> {
+        let conn = job_ctx.pool.get()?;
+        Ok(dsl::export_job.find(job_id).first::<ExportJob>(&conn).optional()?)
+    }).await?
+        .ok_or_else(|| ServiceError::NotFound("Export job".to_string()))?;
+
+    let format = ExportFormat::from_name(&job.format)
+        .ok_or_else(|| ServiceError::InternalServerError(format!("Invalid export format {}", job.format)))?;
+    match ExportJobStatus::from_char(&job.status) {
+        Some(ExportJobStatus::Running) => Ok(HttpResponse::Accepted().json(serde_json::json!({
+            "jobId": job.id,
+            "status": "RUNNING",
+            "rows": job.row_count,
+        }))),
+        Some(ExportJobStatus::Done) => {
+            let path = export_job_file(&ctx, job.id, format);
+            let file = NamedFile::open(path)
+                .map_err(|_| ServiceError::NotFound("Export file".to_string()))?
+                .set_content_type(format.content_type().parse().unwrap())
+                .set_content_disposition(ContentDisposition {
+                    disposition: DispositionType::Attachment,
+                    parameters: vec![DispositionParam::Filename(format!("channel_{}.{}", job.channel_id, format.name()))],
+                });
+            file.respond_to(&req).await
+                .map_err(|x| ServiceError::InternalServerError(x.to_string()))
+        },
+        _ => Err(ServiceError::InternalServerError(format!(
+            "Export failed: {}", job.error.unwrap_or_default()
+        ))),
+    }
+}
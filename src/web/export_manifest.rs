@@ -0,0 +1,130 @@
+//! Manifests of the exported datasets: the channel, the range, the number of rows and the SHA-256
+//! of the file are stored when an export completes, so that a dataset published in a study can be
+//! proven unmodified by looking up its checksum (verifyExport).
+//!
+//! The manifest is created before the export starts, an export that didn't complete (ex. the
+//! client went away) keeps a manifest without checksum.
+
+use std::io::Write;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::models::{ExportManifest, IdType, Pool};
+
+use super::errors::ServiceResult;
+use super::export_service::{ExportFormat, ExportQuery};
+
+/// What has been written by an export
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportDigest {
+    pub rows: u64,
+    /// Hex encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Computes the SHA-256 of everything written through it
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: Sha256::new() }
+    }
+
+    /// Returns the hex encoded SHA-256 of the written data
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.result())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Normalizes a checksum given by a client
+pub fn parse_sha256(checksum: &str) -> Option<String> {
+    let checksum = checksum.trim().to_lowercase();
+    if checksum.len() != 64 || !checksum.chars().all(|x| x.is_ascii_hexdigit()) {
+        return None
+    }
+    Some(checksum)
+}
+
+/// Registers the export before it starts, returns the manifest id
+pub fn create_manifest(pool: &Pool, user_id: Option<IdType>, channel_id: IdType, format: ExportFormat, query: &ExportQuery) -> ServiceResult<IdType> {
+    use crate::schema::export_manifest::dsl;
+
+    let conn = pool.get()?;
+    Ok(diesel::insert_into(dsl::export_manifest)
+        .values((
+            dsl::user_id.eq(user_id),
+            dsl::channel_id.eq(channel_id),
+            dsl::format.eq(format.name()),
+            dsl::range_start.eq(query.start),
+            dsl::range_end.eq(query.end),
+            dsl::include_invalid.eq(query.include_invalid.unwrap_or(false)),
+            dsl::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(dsl::id)
+        .get_result::<IdType>(&conn)?)
+}
+
+/// Stores the checksum of the completed export
+pub fn complete_manifest(pool: &Pool, manifest_id: IdType, digest: &ExportDigest) -> ServiceResult<()> {
+    use crate::schema::export_manifest::dsl;
+
+    let conn = pool.get()?;
+    diesel::update(dsl::export_manifest.find(manifest_id))
+        .set((
+            dsl::row_count.eq(digest.rows as i64),
+            dsl::sha256.eq(&digest.sha256),
+            dsl::completed_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Completed manifests with the checksum, oldest first
+pub fn find_manifests(conn: &PgConnection, sha256: &str) -> QueryResult<Vec<ExportManifest>> {
+    use crate::schema::export_manifest::dsl;
+
+    dsl::export_manifest
+        .filter(dsl::sha256.eq(sha256))
+        .filter(dsl::completed_at.is_not_null())
+        .order(dsl::id)
+        .load::<ExportManifest>(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(b"date,value\n").unwrap();
+        writer.write_all(b"2020-01-01 00:00:00,21.5\n").unwrap();
+        let expected = hex::encode(Sha256::digest(b"date,value\n2020-01-01 00:00:00,21.5\n"));
+        assert_eq!(writer.finish(), expected);
+    }
+
+    #[test]
+    fn test_parse_sha256() {
+        let checksum = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert_eq!(parse_sha256(checksum), Some(checksum.to_lowercase()));
+        assert_eq!(parse_sha256("e3b0"), None);
+        assert_eq!(parse_sha256(&"z".repeat(64)), None);
+    }
+}
@@ -0,0 +1,319 @@
+use actix_web::{HttpResponse, web};
+use actix_web::http::header;
+use chrono::NaiveDateTime;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use log::warn;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use simple_excel_writer::{Row, Workbook};
+
+use crate::AppData;
+use crate::models::IdType;
+use crate::security::{PermissionCheckable, Principal};
+
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::db_helper::{count_channel_readings, for_each_channel_reading, InvalidInterval, load_invalid_intervals, load_live_channel, query_channel_cnr_ids};
+use super::errors::{ServiceError, ServiceResult};
+use super::export_job::{export_job_path, ExportJobRequest, start_export_job};
+use super::export_manifest::{complete_manifest, create_manifest, ExportDigest, HashingWriter};
+use super::graphql_schema::ReadingData;
+
+/// Header with the id of the manifest of the export, its checksum is stored once it's complete
+pub const EXPORT_MANIFEST_HEADER: &str = "X-Export-Manifest";
+
+/// Rows written in a single chunk of the CSV stream
+const CSV_CHUNK_ROWS: usize = 1000;
+/// Excel can't hold more rows than this in a single sheet
+const XLSX_MAX_ROWS: usize = 1_048_575;
+
+const EXPORT_HEADER: [&str; 6] = ["date", "value_min", "value_avg", "value_max", "deviation", "error"];
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "csv" => Some(ExportFormat::Csv),
+            "xlsx" => Some(ExportFormat::Xlsx),
+            _ => None,
+        }
+    }
+
+    /// Name of the format, also used as the file extension
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub(crate) start: NaiveDateTime,
+    pub(crate) end: NaiveDateTime,
+    format: Option<ExportFormat>,
+    /// Also export the readings marked as invalid
+    pub(crate) include_invalid: Option<bool>,
+}
+
+impl ExportQuery {
+    pub(crate) fn new(start: NaiveDateTime, end: NaiveDateTime, include_invalid: bool) -> Self {
+        ExportQuery { start, end, format: Some(ExportFormat::Csv), include_invalid: Some(include_invalid) }
+    }
+}
+
+type CnrIds = (String, String, String);
+
+/// Channel readings to export: the cnr ids of the channel (None if it has no readings) and the
+/// intervals to skip
+pub(crate) type ExportSource = (Option<CnrIds>, Vec<InvalidInterval>);
+
+/// Checks the request and returns what should be exported and the id of the user that asked for
+/// it (None for the api keys)
+fn prepare_export(ctx: &AppData, user: ServiceResult<Principal>, channel_id: IdType, query: &ExportQuery) -> ServiceResult<(ExportSource, Option<IdType>)> {
+    let principal = user?;
+    principal.ensure_channel_visible(ctx, channel_id)?;
+    let user_id = match &principal {
+        Principal::User(user) => Some(user.id),
+        Principal::ApiKey(_) => None,
+    };
+
+    if query.end < query.start {
+        return Err(ServiceError::BadRequest("start is after end".to_string()))
+    }
+
+    let conn = ctx.pool.get()?;
+    let id_cnr = load_live_channel(&conn, channel_id)?.id_cnr;
+    let invalid = if query.include_invalid.unwrap_or(false) {
+        Vec::new()
+    } else {
+        load_invalid_intervals(&conn, channel_id, query.start, query.end)?
+    };
+    std::mem::drop(conn);
+
+    Ok(((query_channel_cnr_ids(&ctx.pool, channel_id, id_cnr.as_deref())?, invalid), user_id))
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    value.map(|x| x.to_string()).unwrap_or_default()
+}
+
+fn write_csv_reading<W: std::io::Write>(writer: &mut csv::Writer<W>, reading: &ReadingData) -> csv::Result<()> {
+    writer.write_record(&[
+        reading.date.format("%Y-%m-%d %H:%M:%S").to_string(),
+        reading.value_min.to_string(),
+        format_optional(reading.value_avg),
+        format_optional(reading.value_max),
+        format_optional(reading.deviation),
+        reading.error.clone().unwrap_or_default(),
+    ])
+}
+
+/// Reads the channel readings and sends them to the client as CSV chunks, it stops as soon as the
+/// client goes away.
+/// Returns what has been sent, None if the export didn't complete.
+fn stream_csv(sensor_pool: &mysql::Pool, (ids, invalid): ExportSource, query: &ExportQuery, mut sender: mpsc::Sender<Result<web::Bytes, ServiceError>>) -> Option<ExportDigest> {
+    let csv_error = |x: csv::Error| ServiceError::InternalServerError(x.to_string());
+    let new_writer = || csv::Writer::from_writer(Vec::new());
+    let mut writer = new_writer();
+    let mut hasher = Sha256::new();
+    let mut rows = 0;
+    let mut stopped = false;
+    let mut result = writer.write_record(EXPORT_HEADER).map_err(csv_error);
+
+    if let (Ok(()), Some(ids)) = (&result, ids) {
+        result = for_each_channel_reading(sensor_pool, &ids, query.start, query.end, &invalid, |reading| {
+            if write_csv_reading(&mut writer, &reading).is_err() {
+                stopped = true;
+                return false
+            }
+            rows += 1;
+            if rows % CSV_CHUNK_ROWS != 0 {
+                return true
+            }
+            let sent = match std::mem::replace(&mut writer, new_writer()).into_inner() {
+                Ok(chunk) => {
+                    hasher.input(&chunk);
+                    block_on(sender.send(Ok(chunk.into()))).is_ok()
+                },
+                Err(_) => false,
+            };
+            stopped = !sent;
+            sent
+        });
+    }
+
+    let last = result.and_then(|()| writer.into_inner().map_err(|x| csv_error(x.into_error().into())));
+    let digest = last.as_ref().ok().map(|chunk| {
+        hasher.input(chunk);
+        ExportDigest { rows: rows as u64, sha256: hex::encode(hasher.result()) }
+    });
+    let sent = block_on(sender.send(last.map(|x| x.into()))).is_ok();
+    digest.filter(|_| sent && !stopped)
+}
+
+/// Writes every reading of the export as CSV.
+pub(crate) fn write_csv<W: std::io::Write>(sensor_pool: &mysql::Pool, (ids, invalid): ExportSource, query: &ExportQuery, out: W) -> ServiceResult<ExportDigest> {
+    let csv_error = |x: csv::Error| ServiceError::InternalServerError(x.to_string());
+    let mut out = HashingWriter::new(out);
+    let mut writer = csv::Writer::from_writer(&mut out);
+    let mut write_error = None;
+    let mut rows = 0;
+    writer.write_record(EXPORT_HEADER).map_err(csv_error)?;
+
+    if let Some(ids) = ids {
+        for_each_channel_reading(sensor_pool, &ids, query.start, query.end, &invalid, |reading| {
+            match write_csv_reading(&mut writer, &reading) {
+                Ok(()) => {
+                    rows += 1;
+                    true
+                },
+                Err(err) => {
+                    write_error = Some(err);
+                    false
+                },
+            }
+        })?;
+    }
+    if let Some(err) = write_error {
+        return Err(csv_error(err))
+    }
+    writer.flush().map_err(|x| csv_error(x.into()))?;
+    std::mem::drop(writer);
+    Ok(ExportDigest { rows, sha256: out.finish() })
+}
+
+/// Builds the xlsx file, returned with its digest
+pub(crate) fn build_xlsx(ctx: &AppData, (ids, invalid): ExportSource, query: &ExportQuery) -> ServiceResult<(Vec<u8>, ExportDigest)> {
+    let xlsx_error = |x: std::io::Error| ServiceError::InternalServerError(x.to_string());
+
+    let mut readings = Vec::new();
+    if let Some(ids) = ids {
+        for_each_channel_reading(&ctx.sensor_pool, &ids, query.start, query.end, &invalid, |reading| {
+            readings.push(reading);
+            readings.len() < XLSX_MAX_ROWS
+        })?;
+    }
+
+    let mut workbook = Workbook::create_in_memory();
+    let mut sheet = workbook.create_sheet("readings");
+    workbook.write_sheet(&mut sheet, |writer| {
+        writer.append_row(Row::from_iter(EXPORT_HEADER.iter().cloned()))?;
+        for reading in readings.iter() {
+            let mut row = Row::new();
+            row.add_cell(reading.date.format("%Y-%m-%d %H:%M:%S").to_string());
+            row.add_cell(reading.value_min);
+            for value in [reading.value_avg, reading.value_max, reading.deviation].iter() {
+                match value {
+                    Some(x) => row.add_cell(*x),
+                    None => row.add_empty_cells(1),
+                }
+            }
+            row.add_cell(reading.error.clone().unwrap_or_default());
+            writer.append_row(row)?;
+        }
+        Ok(())
+    }).map_err(xlsx_error)?;
+
+    let data = workbook.close()
+        .map_err(xlsx_error)?
+        .ok_or_else(|| ServiceError::InternalServerError("Empty workbook".to_string()))?;
+    let digest = ExportDigest { rows: readings.len() as u64, sha256: hex::encode(Sha256::digest(&data)) };
+    Ok((data, digest))
+}
+
+/// Downloads the readings of a channel between start and end as a CSV (streamed) or xlsx file,
+/// the readings marked as invalid are skipped unless include_invalid is set.
+/// When there are more readings than the export job threshold the file is written in the
+/// background instead: the response is a 202 with the id of the job and its signed download path.
+/// Every export gets a manifest (its id is in the X-Export-Manifest header or in the job response)
+/// with the checksum of the file, looked up by verifyExport.
+pub async fn export_channel_readings(
+    ctx: web::Data<AppData>,
+    auth: Authenticator,
+    channel_id: web::Path<IdType>,
+    query: web::Query<ExportQuery>,
+) -> ServiceResult<HttpResponse> {
+    let channel_id = *channel_id;
+    let query = query.into_inner();
+    let format = query.format.unwrap_or(ExportFormat::Csv);
+
+    let prepare_ctx = ctx.clone();
+    let (source, user_id, rows, query, manifest_id) = run_blocking(move || {
+        let user = auth.principal_required(&prepare_ctx);
+        let (source, user_id) = prepare_export(&prepare_ctx, user, channel_id, &query)?;
+        let rows = match &source.0 {
+            Some(ids) => count_channel_readings(&prepare_ctx.sensor_pool, ids, query.start, query.end, &source.1)?,
+            None => 0,
+        };
+        let manifest_id = create_manifest(&prepare_ctx.pool, user_id, channel_id, format, &query)?;
+        Ok((source, user_id, rows, query, manifest_id))
+    }).await?;
+
+    // Too many readings to be sent in a single response, the file is written in the background
+    if rows > ctx.export_jobs.row_threshold {
+        let job_ctx = ctx.clone();
+        let request = ExportJobRequest { user_id, channel_id, format, source, query, rows, manifest_id };
+        let job_id = run_blocking(move || start_export_job(&job_ctx, request)).await?;
+
+        return Ok(HttpResponse::Accepted().json(serde_json::json!({
+            "jobId": job_id,
+            "status": "RUNNING",
+            "rows": rows,
+            "downloadPath": export_job_path(&ctx, job_id),
+            "manifestId": manifest_id,
+        })))
+    }
+
+    let content_disposition = format!("attachment; filename=\"channel_{}.{}\"", channel_id, format.name());
+    match format {
+        ExportFormat::Csv => {
+            let (sender, receiver) = mpsc::channel(1);
+            let stream_ctx = ctx.clone();
+            // A long export shouldn't hold one of the blocking pool threads, the errors are sent
+            // to the client through the stream
+            std::thread::spawn(move || {
+                if let Some(digest) = stream_csv(&stream_ctx.sensor_pool, source, &query, sender) {
+                    if let Err(err) = complete_manifest(&stream_ctx.pool, manifest_id, &digest) {
+                        warn!("Cannot complete the export manifest {}: {}", manifest_id, err);
+                    }
+                }
+            });
+
+            Ok(HttpResponse::Ok()
+                .content_type(format.content_type())
+                .header(header::CONTENT_DISPOSITION, content_disposition)
+                .header(EXPORT_MANIFEST_HEADER, manifest_id.to_string())
+                .streaming(receiver))
+        },
+        ExportFormat::Xlsx => {
+            let data = run_blocking(move || {
+                let (data, digest) = build_xlsx(&ctx, source, &query)?;
+                complete_manifest(&ctx.pool, manifest_id, &digest)?;
+                Ok(data)
+            }).await?;
+
+            Ok(HttpResponse::Ok()
+                .content_type(format.content_type())
+                .header(header::CONTENT_DISPOSITION, content_disposition)
+                .header(EXPORT_MANIFEST_HEADER, manifest_id.to_string())
+                .body(data))
+        },
+    }
+}
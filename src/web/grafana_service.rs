@@ -0,0 +1,158 @@
+//! Grafana JSON datasource protocol (simple-json-datasource), every channel visible to the user is
+//! exposed as a target named by its id.
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::AppData;
+use crate::models::{IdType, PermissionType};
+use crate::security::{PermissionCheckable, Principal};
+
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::db_helper::{load_channel_readings, load_invalid_intervals, query_channel_cnr_ids};
+use super::errors::{ServiceError, ServiceResult};
+
+#[derive(Serialize)]
+struct SearchResult {
+    text: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub struct QueryRange {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct QueryTarget {
+    target: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    range: QueryRange,
+    targets: Vec<QueryTarget>,
+    max_data_points: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct TimeSeries {
+    target: String,
+    /// [value, unix timestamp in milliseconds]
+    datapoints: Vec<(f64, i64)>,
+}
+
+fn search_channels(ctx: &AppData, principal: &Principal) -> ServiceResult<Vec<SearchResult>> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::site::dsl as site_dsl;
+    use crate::schema::user_access::dsl as access_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let query = channel_dsl::channel
+        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .filter(channel_dsl::deleted_at.is_null())
+        .select((channel_dsl::id, site_dsl::name, sensor_dsl::name, channel_dsl::name))
+        .order(channel_dsl::id)
+        .into_boxed();
+
+    let query = match principal {
+        Principal::User(user) if user.get_permission() == PermissionType::Admin => query,
+        Principal::User(user) => {
+            let visible_sites = access_dsl::user_access
+                .filter(access_dsl::user_id.eq(user.id))
+                .select(access_dsl::site_id);
+            query.filter(sensor_dsl::site_id.eq_any(visible_sites))
+        },
+        Principal::ApiKey(key) => match key.site_id {
+            Some(site_id) => query.filter(sensor_dsl::site_id.eq(site_id)),
+            None => query,
+        },
+    };
+
+    let channels = query.load::<(IdType, Option<String>, Option<String>, Option<String>)>(&conn)?;
+
+    Ok(channels.into_iter().map(|(id, site_name, sensor_name, channel_name)| {
+        SearchResult {
+            text: format!(
+                "{} / {} / {}",
+                site_name.as_deref().unwrap_or("?"),
+                sensor_name.as_deref().unwrap_or("?"),
+                channel_name.as_deref().unwrap_or("?")
+            ),
+            value: id.to_string(),
+        }
+    }).collect())
+}
+
+fn query_channels(ctx: &AppData, principal: &Principal, request: &QueryRequest) -> ServiceResult<Vec<TimeSeries>> {
+    use crate::schema::channel::dsl as channel_dsl;
+
+    let conn = ctx.pool.get()?;
+    let start = request.range.from.naive_utc();
+    let end = request.range.to.naive_utc();
+
+    request.targets.iter().map(|target| {
+        let channel_id: IdType = target.target.parse()
+            .map_err(|_| ServiceError::BadRequest(format!("Invalid target: {}", target.target)))?;
+        principal.ensure_channel_visible(ctx, channel_id)?;
+
+        let id_cnr = channel_dsl::channel.find(channel_id)
+            .select(channel_dsl::id_cnr)
+            .first::<Option<String>>(&conn)?;
+
+        let readings = match query_channel_cnr_ids(&ctx.pool, channel_id, id_cnr.as_deref())? {
+            Some(ids) => {
+                let invalid = load_invalid_intervals(&conn, channel_id, start, end)?;
+                load_channel_readings(&ctx.sensor_pool, &ids, start, end, &invalid)?
+            },
+            None => Vec::new(),
+        };
+
+        let mut datapoints: Vec<(f64, i64)> = readings.iter()
+            .map(|x| (x.value_avg.unwrap_or(x.value_min), x.date.timestamp_millis()))
+            .collect();
+        datapoints.sort_by_key(|x| x.1);
+
+        // Grafana asks for a maximum number of points, keep one every n to satisfy it
+        if let Some(max_points) = request.max_data_points.filter(|x| *x > 0) {
+            if datapoints.len() > max_points {
+                let step = datapoints.len().div_ceil(max_points);
+                datapoints = datapoints.into_iter().step_by(step).collect();
+            }
+        }
+
+        Ok(TimeSeries {
+            target: target.target.clone(),
+            datapoints,
+        })
+    }).collect()
+}
+
+/// Used by Grafana to test the connection (and the credentials).
+pub async fn grafana_test(ctx: web::Data<AppData>, auth: Authenticator) -> ServiceResult<HttpResponse> {
+    run_blocking(move || auth.principal_with_password(&ctx)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn grafana_search(ctx: web::Data<AppData>, auth: Authenticator) -> ServiceResult<HttpResponse> {
+    let res = run_blocking(move || {
+        let principal = auth.principal_with_password(&ctx)?;
+        search_channels(&ctx, &principal)
+    }).await?;
+    Ok(HttpResponse::Ok().json(res))
+}
+
+pub async fn grafana_query(ctx: web::Data<AppData>, auth: Authenticator, data: web::Json<QueryRequest>) -> ServiceResult<HttpResponse> {
+    let res = run_blocking(move || {
+        let principal = auth.principal_with_password(&ctx)?;
+        query_channels(&ctx, &principal, &data)
+    }).await?;
+    Ok(HttpResponse::Ok().json(res))
+}
@@ -0,0 +1,217 @@
+//! HTTP caching of the GraphQL queries sent with GET.
+//!
+//! Only queries can be sent with GET, so that a cached response can't hide a side effect. The
+//! responses of the anonymous requests (ex. a site read with its public token) are the same for
+//! every client and can be cached by the CDNs for `max_age` seconds, the others are private.
+//! Every successful response has an ETag so the clients can revalidate it with If-None-Match.
+//!
+//! To keep the urls short the clients can send the SHA-256 of the query instead of the query
+//! (automatic persisted queries): the first request with an unknown hash fails with
+//! PersistedQueryNotFound and the client retries with both the query and its hash.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use juniper::parser::{Lexer, Token};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Resolver;
+
+#[derive(Clone, Debug)]
+pub struct GraphQLCacheConfig {
+    /// Seconds the public responses can be cached
+    pub max_age: u32,
+    /// Maximum number of persisted queries kept in memory, the oldest are forgotten first
+    pub max_persisted_queries: usize,
+}
+
+impl Default for GraphQLCacheConfig {
+    fn default() -> Self {
+        GraphQLCacheConfig {
+            max_age: 60,
+            max_persisted_queries: 1000,
+        }
+    }
+}
+
+/// `[graphql_cache]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphQLCacheSection {
+    pub max_age: Option<u32>,
+    pub persisted_queries: Option<usize>,
+}
+
+impl GraphQLCacheConfig {
+    pub fn resolve(r: &mut Resolver, file: GraphQLCacheSection) -> Self {
+        let default = GraphQLCacheConfig::default();
+        GraphQLCacheConfig {
+            max_age: r.optional("GRAPHQL_GET_MAX_AGE", file.max_age).unwrap_or(default.max_age),
+            max_persisted_queries: r.optional("GRAPHQL_PERSISTED_QUERIES", file.persisted_queries).unwrap_or(default.max_persisted_queries),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PersistedQueries {
+    queries: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct GraphQLCache {
+    pub config: GraphQLCacheConfig,
+    persisted: Arc<Mutex<PersistedQueries>>,
+}
+
+impl GraphQLCache {
+    pub fn new(config: GraphQLCacheConfig) -> Self {
+        GraphQLCache {
+            config,
+            persisted: Default::default(),
+        }
+    }
+
+    /// Query registered with the hash, if any
+    pub fn persisted_query(&self, hash: &str) -> Option<String> {
+        self.persisted.lock().unwrap().queries.get(hash).cloned()
+    }
+
+    /// Remembers the query, the hash must have been verified with query_hash
+    pub fn persist_query(&self, hash: String, query: String) {
+        if self.config.max_persisted_queries == 0 {
+            return
+        }
+        let mut persisted = self.persisted.lock().unwrap();
+        if persisted.queries.contains_key(&hash) {
+            return
+        }
+        while persisted.order.len() >= self.config.max_persisted_queries {
+            if let Some(oldest) = persisted.order.pop_front() {
+                persisted.queries.remove(&oldest);
+            }
+        }
+        persisted.order.push_back(hash.clone());
+        persisted.queries.insert(hash, query);
+    }
+}
+
+/// Hex encoded SHA-256 of the query, as computed by the clients
+pub fn query_hash(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+/// Strong ETag of a response body
+pub fn body_etag(body: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(Sha256::digest(body))[..32])
+}
+
+/// Checks an If-None-Match header against the ETag of the response
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x == etag || x.strip_prefix("W/") == Some(etag))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+/// Finds the kind of the operation that would be executed, without parsing the whole document.
+/// Returns None if the operation can't be found (the execution would fail anyway).
+pub fn operation_kind(document: &str, operation_name: Option<&str>) -> Option<OperationKind> {
+    let mut operations: Vec<(OperationKind, Option<&str>)> = Vec::new();
+    // Operation or fragment whose selection set hasn't started yet
+    let mut pending: Option<(Option<OperationKind>, Option<&str>)> = None;
+    let mut expect_name = false;
+    let mut curly_depth = 0usize;
+    let mut paren_depth = 0usize;
+
+    for token in Lexer::new(document) {
+        let token = token.ok()?.item;
+        let top_level = curly_depth == 0 && paren_depth == 0;
+        match token {
+            Token::CurlyOpen => {
+                if top_level {
+                    match pending.take() {
+                        Some((Some(kind), name)) => operations.push((kind, name)),
+                        Some((None, _)) => {},// Fragment
+                        None => operations.push((OperationKind::Query, None)),
+                    }
+                }
+                curly_depth += 1;
+            },
+            Token::CurlyClose => curly_depth = curly_depth.checked_sub(1)?,
+            Token::ParenOpen => paren_depth += 1,
+            Token::ParenClose => paren_depth = paren_depth.checked_sub(1)?,
+            Token::Name(name) if top_level && pending.is_none() => {
+                let kind = match name {
+                    "query" => Some(OperationKind::Query),
+                    "mutation" => Some(OperationKind::Mutation),
+                    "subscription" => Some(OperationKind::Subscription),
+                    "fragment" => None,
+                    _ => return None,
+                };
+                pending = Some((kind, None));
+                expect_name = true;
+                continue
+            },
+            Token::Name(name) if top_level && expect_name => {
+                if let Some((_, pending_name)) = pending.as_mut() {
+                    *pending_name = Some(name);
+                }
+            },
+            _ => {},
+        }
+        expect_name = false;
+    }
+
+    match operation_name {
+        Some(name) => operations.iter().find(|x| x.1 == Some(name)).map(|x| x.0),
+        None if operations.len() == 1 => Some(operations[0].0),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_kind() {
+        assert_eq!(operation_kind("{ userMe { id } }", None), Some(OperationKind::Query));
+        assert_eq!(operation_kind("query q($a: Int = 1) { site(id: $a) { id } }", None), Some(OperationKind::Query));
+        assert_eq!(operation_kind("mutation { logout }", None), Some(OperationKind::Mutation));
+
+        let document = "fragment f on Site { id } query a { sites { ...f } } mutation b($x: Input = { a: 1 }) { logout }";
+        assert_eq!(operation_kind(document, Some("a")), Some(OperationKind::Query));
+        assert_eq!(operation_kind(document, Some("b")), Some(OperationKind::Mutation));
+        assert_eq!(operation_kind(document, Some("f")), None);
+        assert_eq!(operation_kind(document, None), None);
+        assert_eq!(operation_kind("{ unclosed ", None), Some(OperationKind::Query));
+        assert_eq!(operation_kind("} {", None), None);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = body_etag(b"{}");
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"other\", W/{}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"other\"", &etag));
+    }
+
+    #[test]
+    fn test_persisted_queries() {
+        let cache = GraphQLCache::new(GraphQLCacheConfig { max_persisted_queries: 2, ..Default::default() });
+        for query in ["{ a }", "{ b }", "{ c }"].iter() {
+            cache.persist_query(query_hash(query), query.to_string());
+        }
+        assert_eq!(cache.persisted_query(&query_hash("{ a }")), None);
+        assert_eq!(cache.persisted_query(&query_hash("{ c }")), Some("{ c }".to_string()));
+    }
+}
@@ -1,45 +1,94 @@
 extern crate dotenv;
 
-use std::cell::RefCell;
-use std::fs;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::string::ToString;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Instant;
 
 use bigdecimal::{BigDecimal, ToPrimitive};
 use chrono::{NaiveDateTime, Utc};
 use derive_more::Display;
-use diesel::{
-    pg::PgConnection,
-    prelude::*,
-};
-use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
 use juniper::RootNode;
+use log::error;
 use mysql::params;
-use r2d2::PooledConnection;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::AppData;
-use crate::models::{Channel, CHANNEL_ALL_COLUMNS, FcmUserContact, IdType, PermissionType, Sensor,
-                    SENSOR_ALL_COLUMNS, Site, SITE_ALL_COLUMNS, User, UserAccess};
+use crate::alarm::{EscalationTarget, load_silenced_channels, load_site_status, NewReading, ReadingsWriter};
+use crate::anomaly::AnomalyKind;
+use crate::calibration::latest_calibrations;
+use crate::contact::{EmailVerificationData, MeasureExtremeType, SiteStatus};
+use crate::modbus::{ModbusRegisterType, ModbusValueType};
+use crate::models::{AccessLevel, ALARM_EVENT_ALL_COLUMNS, AlarmEvent, ApiKey, AuditLogEntry, BulkExportJob, Channel, CHANNEL_ALL_COLUMNS, ChannelAnomaly, ChannelMute, EmailUserContact, EscalationPolicy, ExportManifest, FcmUserContact, IdType, IntegrationSecret, MaintenanceWindow, ManualReading, ModbusRegister, PeerGroup, PeerGroupChannel, PendingAction, PermissionType, ReadingAnnotation, ReportSubscription, RequestLogEntry, Room, Sensor, SensorCalibration, SiteMap, SiteWebhook,
+                    SENSOR_ALL_COLUMNS, Site, SITE_ALL_COLUMNS, SitePublicToken, TtnDevice, User, UserAccess, UserPreference, UserSession};
+use crate::quota::{QuotaCostConfig, QuotaPool};
+use crate::report::{build_site_summary, render_html, ReportFormat, ReportPeriod};
 use crate::schema::*;
-use crate::security::PermissionCheckable;
-use crate::web::db_helper::auto_create_sensor;
-use crate::web::errors::ServiceError::InternalServerError;
-use crate::web::site_map_service::get_file_from_site;
-
+use crate::secrets::{apply_secrets, delete_secret, IntegrationSecretName, list_secrets, store_secret};
+use crate::security::{channel_site_id, hash_api_key, PermissionCheckable, SessionClient};
+use crate::siem::SiemEvent;
+use crate::web::db_helper::{auto_create_sensor, InvalidInterval, load_channel_reading_stats, load_channel_readings, load_last_channel_readings, load_channel_readings_aggregated, load_invalid_intervals, query_channel_cnr_ids, ReadingStats, ReadingsAggregation};
+use crate::web::calendar_service::calendar_path;
+use crate::web::db_connection::{ContextConnection, PooledPgConnection};
+use crate::web::map_storage::StorageStatus;
+use crate::web::site_map_service::{delete_image, get_file_from_site, get_file_from_site_map};
+use crate::web::ttn_service::{TtnPayloadFormat, ttn_uplink_path};
+
+use super::approval::{discard_pending_action, find_pending_action, PendingActionKind, request_approval};
+use super::bulk_export::{bulk_export_path, BulkExportRequest, start_bulk_export};
+use super::cnr_orphans::{CnrChannelActivity, find_orphans, load_cnr_activity, load_mapped_ids, OrphanReport};
+use super::data_latency::{DataLatency, load_data_latencies};
 use super::db_helper::auto_create_site;
 use super::errors::{ServiceError, ServiceResult};
-
-const REQ_COINS_MODIFIER_DB_QUERY: i64 = 10;
-const REQ_COINS_MODIFIER_FCM_OP: i64 = 300;
-const REQ_COINS_MODIFIER_PASSWORD_CHANGE: i64 = 400;
-const REQ_COINS_MODIFIER_LOGIN: i64 = 300;
+use super::export_job::ExportJobStatus;
+use super::export_manifest::{find_manifests, parse_sha256};
+use super::graphql_service::quota_retry_after;
+use super::ingest_service::ingest_channel_readings;
+use super::pagination::{PageInfo, PageRequest};
+use super::peer_comparison::{compare_with_peers, PeerDivergence};
+use super::public_service::{load_site_conditions, SiteConditions};
+use super::range_recommendation::{recommend_range, RangeRecommendation, reading_value};
+use super::request_log::{load_usage, load_usage_stats, RequestUsage, UsageStats};
+use super::schema_changes::{current_snapshot, diff_snapshots, previous_release_snapshot, SchemaChange};
+use super::site_config::{import_site_config, load_site_config, SiteConfig};
+use super::sync::{load_changes, parse_cursor, SyncChanges};
+
+/// Maximum number of alarm events returned by a single history query
+const ALARM_HISTORY_MAX_EVENTS: i64 = 1000;
+/// Station id used for the readings entered by hand
+const MANUAL_STATION_ID: &str = "manual";
+/// Maximum number of entries returned by a single audit log query
+const AUDIT_LOG_MAX_ENTRIES: i64 = 1000;
+/// Maximum number of entries returned by a single request log query
+const REQUEST_LOG_MAX_ENTRIES: i64 = 1000;
+/// Maximum number of preferences stored for a single user
+const USER_PREFERENCES_MAX_COUNT: i64 = 100;
+/// Maximum length in bytes of a preference value
+const USER_PREFERENCE_MAX_VALUE_LEN: usize = 16 * 1024;
 
 pub struct Context {
     pub app: Arc<AppData>,
     pub identity: RefCell<Option<String>>,
     user: RefCell<Option<User>>,
-    rem_coins: AtomicI64,
+    rem_read_coins: AtomicI64,
+    rem_write_coins: AtomicI64,
+    quota_pool: Cell<QuotaPool>,
+    spending: RefCell<Vec<(QuotaPool, &'static str, i64)>>,
+    row_count: Cell<i64>,
+    connection_count: Cell<i64>,
+    site_id: Cell<Option<IdType>>,
+    /// Pool that ran out of coins, if a resolver has been rejected
+    quota_rejected: Cell<Option<QuotaPool>>,
+    /// Where the request comes from, recorded in the sessions opened by the login
+    client: RefCell<SessionClient>,
+    /// Connection of the open transaction, shared by every query of the request
+    transaction: RefCell<Option<PooledPgConnection>>,
+    /// Verification emails sent after the request, the resolvers run outside of the arbiter
+    email_verifications: RefCell<Vec<EmailVerificationData>>,
 }
 
 impl Context {
@@ -47,18 +96,53 @@ pub fn new(
         app_data: Arc<AppData>,
         original_identity: Option<String>,
         original_user: Option<User>,
-        remainig_coins: i64
+        remaining_read_coins: i64,
+        remaining_write_coins: i64,
     ) -> Context {
         Context {
             app: app_data,
             identity: RefCell::new(original_identity),
             user: RefCell::new(original_user),
-            rem_coins: AtomicI64::new(remainig_coins),
+            rem_read_coins: AtomicI64::new(remaining_read_coins),
+            rem_write_coins: AtomicI64::new(remaining_write_coins),
+            quota_pool: Cell::new(QuotaPool::Read),
+            spending: RefCell::new(Vec::new()),
+            row_count: Cell::new(0),
+            connection_count: Cell::new(0),
+            site_id: Cell::new(None),
+            quota_rejected: Cell::new(None),
+            client: RefCell::new(SessionClient::default()),
+            transaction: RefCell::new(None),
+            email_verifications: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the connection of the open transaction, or a new connection of the pool
+    pub fn get_connection(&self) -> ServiceResult<ContextConnection<'_>> {
+        self.connection_count.set(self.connection_count.get() + 1);
+        let transaction = self.transaction.borrow();
+        if transaction.is_some() {
+            return Ok(ContextConnection::Transaction(Ref::map(transaction, |x| x.as_ref().unwrap())))
         }
+        Ok(ContextConnection::Pooled(self.app.pool.get()?))
     }
 
-    pub fn get_connection(&self) -> ServiceResult<PooledConnection<ConnectionManager<PgConnection>>> {
-        Ok(self.app.pool.get()?)
+    /// Runs the resolver in a database transaction, every connection taken from the context is
+    /// part of the transaction until the resolver returns. The transaction is rolled back if the
+    /// resolver fails.
+    /// Nested calls join the outer transaction, so a failure rolls back the whole mutation.
+    pub fn transaction<T, F: FnOnce() -> ServiceResult<T>>(&self, resolver: F) -> ServiceResult<T> {
+        if self.transaction.borrow().is_some() {
+            return resolver()
+        }
+
+        *self.transaction.borrow_mut() = Some(self.app.pool.get()?);
+        let res = {
+            let conn = self.get_connection()?;
+            conn.transaction(resolver)
+        };
+        self.transaction.replace(None);
+        res
     }
 
     pub fn raw_user_id(&self) -> Option<IdType> {
@@ -74,19 +158,94 @@ pub fn get_user_required(&self) -> ServiceResult<User> {
         self.get_user()?.ok_or(ServiceError::LoginRequired)
     }
 
-    pub fn save_user(&self, user: Option<User>) {
-        if let Some(user) = user {
-            let id_str = self.app.auth_cache.save_identity(&user);
-            self.identity.replace(Some(id_str));
-            self.user.replace(Some(user));
-        } else {
-            self.identity.replace(None);
-            self.user.replace(None);
+    /// Opens a new session for the user, its identity cookie is sent with the response
+    pub fn save_user(&self, user: User) -> ServiceResult<()> {
+        let id_str = self.app.auth_cache.open_session(&self.app, &user, &self.client.borrow())?;
+        self.identity.replace(Some(id_str));
+        self.user.replace(Some(user));
+        Ok(())
+    }
+
+    /// Removes the identity cookie, the session itself is revoked by the caller (if needed)
+    pub fn forget_user(&self) {
+        self.identity.replace(None);
+        self.user.replace(None);
+    }
+
+    /// Id of the session of the identity cookie, None for the bearer tokens
+    pub fn session_id(&self) -> Option<IdType> {
+        self.identity.borrow().as_ref().and_then(|x| self.app.auth_cache.identity_session(x))
+    }
+
+    fn coins(&self, pool: QuotaPool) -> &AtomicI64 {
+        match pool {
+            QuotaPool::Read => &self.rem_read_coins,
+            QuotaPool::Write => &self.rem_write_coins,
+        }
+    }
+
+    pub fn costs(&self) -> &QuotaCostConfig {
+        &self.app.quota_costs
+    }
+
+    /// Spends the coins from the pool of the resolver that is currently running, the operation
+    /// is shown to the user to explain what is consuming its quota and can have its cost
+    /// overridden in the configuration.
+    pub fn spend_request_coins(&self, operation: &'static str, amount: i64) {
+        let amount = self.costs().operation_cost(operation, amount);
+        let pool = self.quota_pool.get();
+        self.coins(pool).fetch_sub(amount, Ordering::Relaxed);
+        self.spending.borrow_mut().push((pool, operation, amount));
+    }
+
+    /// Total coins spent in this request, used as its complexity score in the request log.
+    pub fn spent_coins(&self) -> i64 {
+        self.spending.borrow().iter().map(|x| x.2).sum()
+    }
+
+    /// Counts the rows loaded by a resolver for the request log.
+    pub fn count_rows(&self, rows: usize) {
+        self.row_count.set(self.row_count.get() + rows as i64);
+    }
+
+    pub fn row_count(&self) -> i64 {
+        self.row_count.get()
+    }
+
+    /// Connections taken by the resolvers, it grows with the database round trips of the request.
+    pub fn connection_count(&self) -> i64 {
+        self.connection_count.get()
+    }
+
+    /// Marks the site the request is about, only the first one is kept.
+    pub fn touch_site(&self, site_id: IdType) {
+        if self.site_id.get().is_none() {
+            self.site_id.set(Some(site_id));
         }
     }
 
-    pub fn spend_request_coins(&self, amount: i64) {
-        self.rem_coins.fetch_sub(amount, Ordering::Relaxed);
+    pub fn site_id(&self) -> Option<IdType> {
+        self.site_id.get()
+    }
+
+    /// True if a resolver has been rejected because the user ran out of quota.
+    pub fn quota_rejected(&self) -> bool {
+        self.quota_rejected.get().is_some()
+    }
+
+    /// Pool that ran out of coins, the client is told when it will be refilled
+    pub fn rejected_pool(&self) -> Option<QuotaPool> {
+        self.quota_rejected.get()
+    }
+
+    /// Returns the coins spent by every operation in this request.
+    pub fn take_spending(&self) -> Vec<(QuotaPool, &'static str, i64)> {
+        self.spending.replace(Vec::new())
+    }
+
+    /// Returns the verification emails queued by the resolvers of this request.
+    pub fn take_email_verifications(&self) -> Vec<EmailVerificationData> {
+        self.email_verifications.replace(Vec::new())
     }
 
     pub fn check_request_balance(&self) -> ServiceResult<()> {
@@ -97,30 +256,171 @@ pub fn check_request_balance(&self) -> ServiceResult<()> {
             },
             _ => {}// Continue checking
         }
-        let balance = self.rem_coins.load(Ordering::Relaxed);
+        let pool = self.quota_pool.get();
+        let balance = self.get_quota_coins(pool);
         if balance <= 0 {
+            self.quota_rejected.set(Some(pool));
             Err(ServiceError::TooManyRequests)
         } else {
             Ok(())
         }
     }
 
-    pub fn get_quota_coins(&self) -> i64 {
-        self.rem_coins.load(Ordering::Relaxed)
+    pub fn get_quota_coins(&self, pool: QuotaPool) -> i64 {
+        self.coins(pool).load(Ordering::Relaxed)
+    }
+
+    /// Runs a resolver that modifies the server state, charging it to the write pool so that
+    /// heavy reads cannot starve the writes (and vice versa).
+    pub fn charge_writes<T, F: FnOnce() -> ServiceResult<T>>(&self, resolver: F) -> ServiceResult<T> {
+        let old_pool = self.quota_pool.replace(QuotaPool::Write);
+        let res = self.refund_on_client_error(resolver);
+        self.quota_pool.set(old_pool);
+        res
+    }
+
+    pub fn set_client(&self, client: SessionClient) {
+        self.client.replace(client);
+    }
+
+    /// Peer address of the request, None if it's unknown (ex. in the tests)
+    pub fn client_address(&self) -> Option<std::net::IpAddr> {
+        self.client.borrow().address
+    }
+
+    /// Runs an admin resolver recording it in the audit log if it succeeds, the target describes
+    /// what has been modified (ex. "site 12").
+    /// The resolver has already done its work when the log is written, so a failure to record it is
+    /// only logged.
+    pub fn audited<T, R, F>(&self, action: &'static str, target: R, resolver: F) -> ServiceResult<T>
+        where R: FnOnce(&T) -> String,
+              F: FnOnce() -> ServiceResult<T> {
+        use crate::schema::audit_log::dsl;
+
+        // The resolver could change the logged user (ex. a password change)
+        let user = self.user.borrow().clone();
+        let res = resolver()?;
+        let target = target(&res);
+
+        let (user_id, username) = match user {
+            Some(x) => (Some(x.id), x.username),
+            None => (None, "".to_string()),
+        };
+        let now = Utc::now().naive_utc();
+        let inserted = self.get_connection().and_then(|conn| {
+            Ok(diesel::insert_into(dsl::audit_log)
+                .values((
+                    dsl::user_id.eq(user_id),
+                    dsl::username.eq(&username),
+                    dsl::action.eq(action),
+                    dsl::target.eq(&target),
+                    dsl::created_at.eq(now),
+                ))
+                .execute(&*conn)?)
+        });
+        if let Err(e) = inserted {
+            error!("Cannot record {} of {} by {} in the audit log: {}", action, target, username, e);
+        }
+        self.app.siem.send(SiemEvent::Audit { time: now, user_id, username, action: action.to_string(), target });
+        Ok(res)
+    }
+
+    /// Runs a destructive action, when the approvals are enabled it's only recorded as pending
+    /// until another admin confirms it (see the approval module)
+    pub fn approved<F>(&self, kind: PendingActionKind, target_id: IdType, action: F) -> ServiceResult<bool>
+        where F: FnOnce() -> ServiceResult<bool> {
+        let policy = match self.app.approvals.as_ref() {
+            Some(x) => x,
+            None => return action(),
+        };
+
+        let user = self.get_user_required()?;
+        let id = self.audited("requestApproval", |id| format!("pending action {} {} {}", id, kind.name(), target_id), || {
+            let conn = self.get_connection()?;
+            request_approval(&conn, policy, kind, target_id, user.id, Utc::now().naive_utc())
+        })?;
+        Err(ServiceError::ApprovalPending(id))
+    }
+
+    /// Runs a resolver giving back the coins that it spent if it fails because of a client error
+    /// (ex. a mistyped id), so that only the successful operations are charged.
+    pub fn refund_on_client_error<T, F: FnOnce() -> ServiceResult<T>>(&self, resolver: F) -> ServiceResult<T> {
+        let pool = self.quota_pool.get();
+        let initial_coins = self.get_quota_coins(pool);
+        let initial_spending_len = self.spending.borrow().len();
+        let res = resolver();
+        if let Err(ref error) = res {
+            let spent = initial_coins - self.get_quota_coins(pool);
+            if error.is_client_error() && spent > 0 {
+                self.coins(pool).fetch_add(spent, Ordering::Relaxed);
+                self.spending.borrow_mut().truncate(initial_spending_len);
+            }
+        }
+        res
     }
 }
 
 impl juniper::Context for Context {}
 
+#[derive(Clone, Copy, Debug, juniper::GraphQLEnum)]
+pub enum TranslatableType {
+    Site,
+    Sensor,
+    Channel,
+}
+
+impl TranslatableType {
+    fn table_name(self) -> &'static str {
+        match self {
+            TranslatableType::Site => "site",
+            TranslatableType::Sensor => "sensor",
+            TranslatableType::Channel => "channel",
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct NameTranslation {
+    pub locale: String,
+    pub name: String,
+}
+
+/// Finds the name in the locale, a regional locale (ex. it-IT) falls back to its language (it)
+/// and then to the default name.
+fn localized_name(name: &Option<String>, translations: &serde_json::Value, locale: Option<&str>) -> Option<String> {
+    let translated = locale.and_then(|locale| {
+        let language = locale.split('-').next().unwrap_or(locale);
+        translations.get(locale)
+            .or_else(|| translations.get(language))
+            .and_then(|x| x.as_str())
+    });
+    translated.map(|x| x.to_string()).or_else(|| name.clone())
+}
+
+fn name_translations(translations: &serde_json::Value) -> Vec<NameTranslation> {
+    translations.as_object()
+        .map(|x| x.iter()
+            .filter_map(|(locale, name)| name.as_str().map(|name| NameTranslation {
+                locale: locale.clone(),
+                name: name.to_string(),
+            }))
+            .collect())
+        .unwrap_or_default()
+}
+
 #[derive(Debug, Display, juniper::GraphQLEnum, PartialEq)]
 pub enum SensorStateType {
     Ok,
     Disabled,
     Alarm,
+    /// Every alarmed channel has been acknowledged or muted
+    AlarmAcknowledged,
+    /// The sensor hasn't reported for longer than its max silence
+    NoData,
     Error,
 }
 
-#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+#[derive(Debug, Clone, juniper::GraphQLObject, PartialEq, Serialize, Deserialize)]
 pub struct ReadingData {
     pub date: NaiveDateTime,
     pub value_min: f64,
@@ -130,6 +430,314 @@ pub struct ReadingData {
     pub error: Option<String>,
 }
 
+#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+pub struct OperationSpending {
+    pub operation: String,
+    pub coins: i32,
+}
+
+#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+#[graphql(description = "Quota state of a single pool of the current user")]
+pub struct QuotaInfo {
+    pub pool: QuotaPool,
+    pub max_balance: i32,
+    #[graphql(description = "Coins given back every second")]
+    pub refill_rate: i32,
+    pub balance: i32,
+    #[graphql(description = "Seconds to wait before the balance is positive again (0 if it already is)")]
+    pub retry_after: i32,
+    #[graphql(description = "Coins spent in the last few minutes grouped by operation")]
+    pub recent_spending: Vec<OperationSpending>,
+}
+
+#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+#[graphql(description = "Remaining quota of a pool of the current user, to back off before being rejected")]
+pub struct QuotaStatus {
+    pub remaining: i32,
+    pub max_balance: i32,
+    pub refill_per_second: i32,
+}
+
+impl From<QuotaInfo> for QuotaStatus {
+    fn from(info: QuotaInfo) -> Self {
+        QuotaStatus {
+            remaining: info.balance,
+            max_balance: info.max_balance,
+            refill_per_second: info.refill_rate,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct SensorPage {
+    pub nodes: Vec<Sensor>,
+    pub page_info: PageInfo,
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct ChannelPage {
+    pub nodes: Vec<Channel>,
+    pub page_info: PageInfo,
+}
+
+/// Deleted entities that can still be restored, the sensors and channels deleted together with
+/// their parent aren't listed as they're restored with it
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct Trash {
+    pub sites: Vec<Site>,
+    pub sensors: Vec<Sensor>,
+    pub channels: Vec<Channel>,
+}
+
+/// Alarm state of a channel
+#[derive(juniper::GraphQLObject)]
+pub struct ChannelAlarmInfo {
+    pub alarmed: bool,
+    /// The channel is out of range since then but its alarm delay isn't over yet
+    pub pending_since: Option<NaiveDateTime>,
+    /// The channel entered and exited alarm too often, its alarms aren't notified until it
+    /// stays quiet for a while
+    pub flapping: bool,
+    pub flapping_since: Option<NaiveDateTime>,
+}
+
+/// A newly created api key, the key itself can't be retrieved later (only its hash is stored)
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct CreatedApiKey {
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+/// Bearer token for the clients that can't keep cookies, it's sent as `Authorization: Bearer <token>`
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct AuthToken {
+    pub token: String,
+    pub expires_at: NaiveDateTime,
+    pub user: User,
+}
+
+fn clamp_to_i32(x: i64) -> i32 {
+    x.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+}
+
+/// Quota state of the user in the pool, None if the quota is disabled
+fn load_quota_info(ctx: &Context, user_id: IdType, pool: QuotaPool, now: Instant) -> Option<QuotaInfo> {
+    let pool_data = ctx.app.quota_bank.as_ref()?.pool(pool);
+    let balance = ctx.get_quota_coins(pool);
+    let retry_after = if balance > 0 { 0 } else { quota_retry_after(&ctx.app, user_id, pool).unwrap_or(0) };
+
+    Some(QuotaInfo {
+        pool,
+        max_balance: clamp_to_i32(pool_data.max_balance()),
+        refill_rate: clamp_to_i32(pool_data.balance_per_second() as i64),
+        balance: clamp_to_i32(balance),
+        retry_after: clamp_to_i32(retry_after as i64),
+        recent_spending: pool_data.get_recent_spending(now, user_id).into_iter()
+            .map(|(operation, coins)| OperationSpending { operation, coins: clamp_to_i32(coins) })
+            .collect(),
+    })
+}
+
+/// Checks that the room exists and belongs to the site, so that a sensor can be placed in it
+fn ensure_room_in_site(conn: &PgConnection, room_id: IdType, site_id: IdType) -> ServiceResult<()> {
+    use crate::schema::room::dsl;
+
+    let room_site_id = dsl::room.find(room_id)
+        .select(dsl::site_id)
+        .first::<IdType>(conn)
+        .optional()?;
+    if room_site_id != Some(site_id) {
+        return Err(ServiceError::BadRequest("The room is not in the site of the sensor".to_string()))
+    }
+    Ok(())
+}
+
+/// Checks that the map exists and belongs to the site, so that a sensor can be placed on it
+fn ensure_map_in_site(conn: &PgConnection, map_id: IdType, site_id: IdType) -> ServiceResult<()> {
+    use crate::schema::site_map::dsl;
+
+    let map_site_id = dsl::site_map.find(map_id)
+        .select(dsl::site_id)
+        .first::<IdType>(conn)
+        .optional()?;
+    if map_site_id != Some(site_id) {
+        return Err(ServiceError::BadRequest("The map is not in the site of the sensor".to_string()))
+    }
+    Ok(())
+}
+
+/// Date in which a loaded entity was moved to the trash, the query result is None if it doesn't
+/// exist and Some(None) if it isn't deleted
+fn trashed_at(deleted_at: Option<Option<NaiveDateTime>>, name: &str) -> ServiceResult<NaiveDateTime> {
+    match deleted_at {
+        Some(Some(x)) => Ok(x),
+        Some(None) => Err(ServiceError::BadRequest(format!("{} is not deleted", name))),
+        None => Err(ServiceError::NotFound(name.to_string())),
+    }
+}
+
+fn map_site_id(ctx: &Context, map_id: IdType) -> ServiceResult<IdType> {
+    use crate::schema::site_map::dsl;
+
+    dsl::site_map.find(map_id)
+        .select(dsl::site_id)
+        .first::<IdType>(&*ctx.get_connection()?)
+        .optional()?
+        .ok_or_else(|| ctx.app.access_policy.hidden("Site map"))
+}
+
+/// Runs deleteSite, the permissions are checked again as it can be confirmed by another admin
+fn delete_site_now(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    ctx.get_user_required()?.ensure_admin()?;
+    ctx.audited("deleteSite", |_| format!("site {}", id), || ctx.transaction(|| {
+        let conn = ctx.get_connection()?;
+        let now = Utc::now().naive_utc();
+
+        let del_count = diesel::update(site_dsl::site.find(id).filter(site_dsl::deleted_at.is_null()))
+            .set(site_dsl::deleted_at.eq(now))
+            .execute(&*conn)?;
+        if del_count != 1 {
+            return Err(ServiceError::NotFound("Site".to_string()))
+        }
+
+        // The children already in the trash keep their date, so they aren't restored with the site
+        let sensor_ids = sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(id)).select(sensor_dsl::id);
+        diesel::update(channel_dsl::channel.filter(channel_dsl::sensor_id.eq_any(sensor_ids)))
+            .filter(channel_dsl::deleted_at.is_null())
+            .set(channel_dsl::deleted_at.eq(now))
+            .execute(&*conn)?;
+        diesel::update(sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(id)))
+            .filter(sensor_dsl::deleted_at.is_null())
+            .set(sensor_dsl::deleted_at.eq(now))
+            .execute(&*conn)?;
+        Ok(true)
+    }))
+}
+
+/// Runs deleteUser, the permissions are checked again as it can be confirmed by another admin
+fn delete_user_now(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+    ctx.audited("deleteUser", |_| format!("user {}", id), || {
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            user.ensure_admin()?;
+            if user.id == id {
+                return Err(ServiceError::Unauthorized)// TODO: different error
+            }
+            ctx.app.auth_cache.delete_user(&ctx.app, id)?;
+            Ok(true)
+        })
+    })
+}
+
+/// Runs updateUser, only the admins can edit the other users or change usernames and permissions
+fn update_user_account(ctx: &Context, id: IdType, data: UserUpdateInput) -> ServiceResult<User> {
+    ctx.audited("updateUser", |_| format!("user {}", id), || {
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+
+            if id != user.id || data.username.as_ref().is_some() || data.permission.as_ref().is_some() {
+                user.ensure_admin()?
+            }
+
+            let own_password_changed = id == user.id && data.password.as_ref().is_some();
+            ctx.spend_request_coins("updateUser", 10 * ctx.costs().db_query + if own_password_changed { ctx.costs().password_change } else { 0 });
+
+            let res = ctx.app.auth_cache.update_user(&ctx.app, id, data.username, data.password, data.permission)?;
+
+            if own_password_changed {
+                ctx.save_user(res.clone())?;
+            }
+
+            Ok(res)
+        })
+    })
+}
+
+/// Runs giveUserAccess, either every access is given or none is.
+/// The site admins can only give access to their sites
+fn give_site_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>, level: Option<AccessLevel>) -> ServiceResult<bool> {
+    let user = ctx.get_user_required()?;
+    for site_id in site_ids.iter() {
+        user.ensure_site_level(&ctx.app, *site_id, AccessLevel::Admin)?;
+    }
+    let level = level.unwrap_or(AccessLevel::View);
+    let target = format!("user {} sites {:?} level {}", user_id, site_ids, level);
+    ctx.audited("giveUserAccess", |_| target, || ctx.transaction(|| {
+        let conn = ctx.get_connection()?;
+        for site_id in site_ids {
+            ctx.app.auth_cache.give_access(&conn, user_id, site_id, level)?;
+        }
+        Ok(true)
+    }))
+}
+
+/// Runs revokeUserAccess, the site admins can only revoke the access to their sites
+fn revoke_site_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>) -> ServiceResult<bool> {
+    let user = ctx.get_user_required()?;
+    for site_id in site_ids.iter() {
+        user.ensure_site_level(&ctx.app, *site_id, AccessLevel::Admin)?;
+    }
+    let target = format!("user {} sites {:?}", user_id, site_ids);
+    ctx.audited("revokeUserAccess", |_| target, || ctx.transaction(|| {
+        let conn = ctx.get_connection()?;
+        for site_id in site_ids {
+            ctx.app.auth_cache.revoke_access(&conn, user_id, site_id)?;
+        }
+        Ok(true)
+    }))
+}
+
+/// Deletes the map with its image, the sensors placed on it lose their position
+fn delete_site_map_now(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::site_map::dsl;
+
+    let site_id = map_site_id(ctx, id)?;
+    ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+
+    ctx.audited("deleteSiteMap", |_| format!("map {} of site {}", id, site_id), || ctx.transaction(|| {
+        let conn = ctx.get_connection()?;
+        diesel::update(sensor_dsl::sensor.filter(sensor_dsl::map_id.eq(id)))
+            .set((
+                sensor_dsl::map_id.eq(Option::<IdType>::None),
+                sensor_dsl::loc_x.eq(Option::<i32>::None),
+                sensor_dsl::loc_y.eq(Option::<i32>::None),
+            ))
+            .execute(&*conn)?;
+        let del_count = diesel::delete(dsl::site_map.find(id))
+            .execute(&*conn)?;
+        if del_count != 1 {
+            return Err(ServiceError::NotFound("Site map".to_string()))
+        }
+
+        delete_image(&*ctx.app.site_maps, &get_file_from_site_map(site_id, id))
+            .map_err(ServiceError::InternalServerError)?;
+        Ok(true)
+    }))
+}
+
+fn room_site_id(ctx: &Context, room_id: IdType) -> ServiceResult<IdType> {
+    use crate::schema::room::dsl;
+
+    dsl::room.find(room_id)
+        .select(dsl::site_id)
+        .first::<IdType>(&*ctx.get_connection()?)
+        .optional()?
+        .ok_or_else(|| ctx.app.access_policy.hidden("Room"))
+}
+
 fn load_user_sites(ctx: &Context, user_id: IdType) -> ServiceResult<Vec<Site>> {
     use crate::schema::user_access::dsl as user_access;
     use crate::schema::site::dsl as site_dsl;
@@ -138,9 +746,11 @@ fn load_user_sites(ctx: &Context, user_id: IdType) -> ServiceResult<Vec<Site>> {
 
     let users = user_access::user_access.filter(user_access::user_id.eq(user_id))
         .inner_join(site_dsl::site)
+        .filter(site_dsl::deleted_at.is_null())
         .select(SITE_ALL_COLUMNS)
-        .load::<Site>(&conn)?;
-    ctx.spend_request_coins(users.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
+        .load::<Site>(&*conn)?;
+    ctx.count_rows(users.len());
+    ctx.spend_request_coins("sites", users.len() as i64 * ctx.costs().db_query);
     Ok(users)
 }
 
@@ -153,12 +763,66 @@ fn load_user_sites_filtered(ctx: &Context, user_id: IdType, ids: Vec<IdType>) ->
     let users = user_access::user_access.filter(user_access::user_id.eq(user_id))
         .inner_join(site_dsl::site)
         .filter(site_dsl::id.eq_any(ids))
+        .filter(site_dsl::deleted_at.is_null())
         .select(SITE_ALL_COLUMNS)
-        .load::<Site>(&conn)?;
-    ctx.spend_request_coins(users.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
+        .load::<Site>(&*conn)?;
+    ctx.count_rows(users.len());
+    ctx.spend_request_coins("sites", users.len() as i64 * ctx.costs().db_query);
     Ok(users)
 }
 
+#[juniper::object(
+    description = "A setting stored by the clients for the current user",
+    Context = Context,
+)]
+impl UserPreference {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+}
+
+#[juniper::object(
+    description = "A login of the current user kept by an identity cookie",
+    Context = Context,
+)]
+impl UserSession {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    /// User-Agent header of the login request
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// Address of the client that logged in
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    /// Last request of the session, updated every few minutes
+    pub fn last_seen_at(&self) -> NaiveDateTime {
+        self.last_seen_at
+    }
+
+    /// The session is the one of the request
+    pub fn current(&self, ctx: &Context) -> bool {
+        ctx.session_id() == Some(self.id)
+    }
+}
+
 #[juniper::object(
     description = "An user account",
     Context = Context,
@@ -173,12 +837,31 @@ pub fn username(&self) -> &str {
     }
 
     pub fn permission(&self) -> PermissionType {
-        PermissionType::from_char(self.permission.as_str()).expect("Wrong permission found!")
+        self.permission
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    /// The notifications are only emailed to a verified email (see verifyEmail)
+    pub fn email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
     }
 
     pub fn sites(&self, ctx: &Context) -> ServiceResult<Vec<Site>> {
         load_user_sites(ctx, self.id)
     }
+
+    /// Sites the user has access to, with the access level
+    pub fn site_access(&self, ctx: &Context) -> ServiceResult<Vec<UserAccess>> {
+        use crate::schema::user_access::dsl;
+        let conn = ctx.get_connection()?;
+        Ok(dsl::user_access
+            .filter(dsl::user_id.eq(self.id))
+            .order(dsl::site_id)
+            .load::<UserAccess>(&*conn)?)
+    }
 }
 
 #[juniper::object(
@@ -190,14 +873,29 @@ pub fn id(&self) -> IdType {
         self.id
     }
 
-    pub fn name(&self) -> Option<&str> {
-        self.name.as_ref().map(|x| x.as_str())
+    /// The name in the requested locale, the default name is used when there's no translation
+    pub fn name(&self, locale: Option<String>) -> Option<String> {
+        localized_name(&self.name, &self.name_translations, locale.as_deref())
+    }
+
+    pub fn name_translations(&self) -> Vec<NameTranslation> {
+        name_translations(&self.name_translations)
     }
 
     pub fn id_cnr(&self) -> Option<&str> {
         self.id_cnr.as_ref().map(|x| x.as_str())
     }
 
+    /// When the site was moved to the trash, null if it isn't deleted
+    pub fn deleted_at(&self) -> Option<NaiveDateTime> {
+        self.deleted_at
+    }
+
+    /// Last change of the site, used by changesSince
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+
     pub fn image_width(&self) -> Option<i32> {
         self.image_width
     }
@@ -212,743 +910,3908 @@ pub fn sensors(&self, ctx: &Context) -> ServiceResult<Vec<Sensor>> {
         let connection = ctx.get_connection()?;
         // TODO: paging
         let sensors = sensor.filter(site_id.eq(self.id))
-            .load::<Sensor>(&connection)?;
-        ctx.spend_request_coins(sensors.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
+            .filter(deleted_at.is_null())
+            .load::<Sensor>(&*connection)?;
+        ctx.count_rows(sensors.len());
+        ctx.spend_request_coins("Site.sensors", sensors.len() as i64 * ctx.costs().db_query);
         Ok(sensors)
     }
 
-    /// Guesses the cnr sensor ids under this site based on recent readings,
-    /// Admin privileges are required for this operation as it puts some stress on the database
-    fn cnr_sensor_ids(&self, ctx: &Context) -> ServiceResult<Vec<String>> {
-        ctx.get_user_required()?.ensure_admin()?;
-        let conn = &ctx.app.sensor_pool;
+    /// Rooms of the site, ordered by floor and name
+    pub fn rooms(&self, ctx: &Context) -> ServiceResult<Vec<Room>> {
+        use crate::schema::room::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let rooms = dsl::room.filter(dsl::site_id.eq(self.id))
+            .order((dsl::floor, dsl::name))
+            .load::<Room>(&*connection)?;
+        ctx.count_rows(rooms.len());
+        ctx.spend_request_coins("Site.rooms", ctx.costs().db_query);
+        Ok(rooms)
+    }
 
-        let id_cnr = match self.id_cnr.as_ref() {
-            None => return Ok(Vec::new()),
-            Some(x) => x,
+    /// Additional maps of the site (ex. the floors), the main one is the image of the site
+    pub fn maps(&self, ctx: &Context) -> ServiceResult<Vec<SiteMap>> {
+        use crate::schema::site_map::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let maps = dsl::site_map.filter(dsl::site_id.eq(self.id))
+            .order(dsl::id)
+            .load::<SiteMap>(&*connection)?;
+        ctx.spend_request_coins("Site.maps", ctx.costs().db_query);
+        Ok(maps)
+    }
+
+    /// Channel of the enabled sensors with the oldest newest reading, null if no channel has
+    /// readings
+    pub fn worst_data_latency(&self, ctx: &Context) -> ServiceResult<Option<DataLatency>> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
         };
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let channels = channel_dsl::channel
+            .inner_join(sensor_dsl::sensor)
+            .filter(sensor_dsl::site_id.eq(self.id))
+            .filter(sensor_dsl::enabled.eq(true))
+            .filter(channel_dsl::deleted_at.is_null())
+            .select((channel_dsl::id, channel_dsl::id_cnr))
+            .load::<(IdType, Option<String>)>(&*connection)?;
+        std::mem::drop(connection);
+
+        let mut ids = Vec::with_capacity(channels.len());
+        for (id, id_cnr) in channels {
+            if let Some(cnr_ids) = query_channel_cnr_ids(&ctx.app.pool, id, id_cnr.as_deref())? {
+                ids.push((id, cnr_ids));
+            }
+        }
+        ctx.spend_request_coins("Site.worstDataLatency", ctx.costs().db_query * 10);
+        if ids.is_empty() {
+            return Ok(None)
+        }
 
-        let res = conn.prep_exec("SELECT DISTINCT idsensore FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT 1000) AS tmp;", params!{
-            "site_id" => id_cnr
-        })?;
-        let names: Vec<String> = res.map(|row| {
-            mysql::from_row::<String>(row.unwrap())
-        }).collect();
-        Ok(names)
+        let latencies = load_data_latencies(&ctx.app.sensor_pool, &ids, Utc::now().naive_utc())?;
+        Ok(latencies.into_iter().max_by(|a, b| a.seconds.partial_cmp(&b.seconds).unwrap_or(std::cmp::Ordering::Equal)))
     }
 
-    fn has_image(&self, ctx: &Context) -> ServiceResult<bool> {
-        ctx.spend_request_coins(1);
-        Ok(get_file_from_site(self.id)
-            .map_err(|x| ServiceError::InternalServerError(x.to_string()))?
-            .exists())
-    }
-}
+    /// Paginated version of sensors, ordered by id
+    pub fn sensor_page(&self, ctx: &Context, first: Option<i32>, after: Option<String>, last: Option<i32>, before: Option<String>) -> ServiceResult<SensorPage> {
+        use crate::schema::sensor::dsl::*;
+        ctx.check_request_balance()?;
+        let page = PageRequest::new(first, after, last, before)?;
+        let connection = ctx.get_connection()?;
 
-#[juniper::object(
-    description = "A user access entry",
-    Context = Context,
-)]
-impl UserAccess {
-    pub fn user_id(&self) -> IdType {
-        self.user_id
+        let mut query = sensor.filter(site_id.eq(self.id))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        if let Some(x) = page.after {
+            query = query.filter(id.gt(x));
+        }
+        if let Some(x) = page.before {
+            query = query.filter(id.lt(x));
+        }
+        query = if page.backwards { query.order(id.desc()) } else { query.order(id.asc()) };
+        let sensors = query.limit(page.query_limit())
+            .load::<Sensor>(&*connection)?;
+
+        ctx.count_rows(sensors.len());
+        ctx.spend_request_coins("Site.sensorPage", sensors.len() as i64 * ctx.costs().db_query);
+        let (nodes, page_info) = page.finish(sensors, |x| x.id);
+        Ok(SensorPage { nodes, page_info })
     }
 
-    pub fn site_id(&self) -> IdType {
-        self.site_id
+    pub fn maintenance_windows(&self, ctx: &Context) -> ServiceResult<Vec<MaintenanceWindow>> {
+        use crate::schema::maintenance_window::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let windows = dsl::maintenance_window.filter(dsl::site_id.eq(self.id))
+            .order(dsl::start_time)
+            .load::<MaintenanceWindow>(&*connection)?;
+        ctx.spend_request_coins("Site.maintenanceWindows", ctx.costs().db_query);
+        Ok(windows)
     }
 
-    pub fn user(&self, ctx: &Context) -> ServiceResult<User> {
-        use crate::schema::user_account::dsl::*;
-        let connection = ctx.app.pool.get()?;
-        Ok(user_account.find(self.user_id).first::<User>(&connection)?)
+    /// Status of the whole site computed by the last alarm check
+    pub fn status(&self, ctx: &Context) -> ServiceResult<SiteStatus> {
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let status = load_site_status(&*connection, self.id)?;
+        ctx.spend_request_coins("Site.status", ctx.costs().db_query);
+        Ok(status)
     }
 
-    pub fn site(&self, ctx: &Context) -> ServiceResult<Site> {
-        use crate::schema::site::dsl::*;
+    /// Urls notified of the alarms of the site, only visible to its managers
+    pub fn webhooks(&self, ctx: &Context) -> ServiceResult<Vec<SiteWebhook>> {
+        use crate::schema::site_webhook::dsl;
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, self.id)?;
+        let connection = ctx.get_connection()?;
+        let webhooks = dsl::site_webhook.filter(dsl::site_id.eq(self.id))
+            .order(dsl::id)
+            .load::<SiteWebhook>(&*connection)?;
+        ctx.spend_request_coins("Site.webhooks", ctx.costs().db_query);
+        Ok(webhooks)
+    }
+
+    /// Alarms of every channel of the site that overlap the start-end range, newest first
+    pub fn alarm_history(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<AlarmEvent>> {
+        use crate::schema::alarm_event::dsl;
+        use crate::schema::channel::dsl as channel_dsl;
+        use crate::schema::sensor::dsl as sensor_dsl;
+        ctx.check_request_balance()?;
         let connection = ctx.get_connection()?;
-        Ok(site.find(self.site_id).first::<Site>(&connection)?)
+
+        let events = dsl::alarm_event
+            .inner_join(channel_dsl::channel.inner_join(sensor_dsl::sensor))
+            .filter(sensor_dsl::site_id.eq(self.id))
+            .filter(dsl::started_at.le(end))
+            .filter(dsl::ended_at.is_null().or(dsl::ended_at.ge(start)))
+            .order(dsl::started_at.desc())
+            .limit(ALARM_HISTORY_MAX_EVENTS)
+            .select(ALARM_EVENT_ALL_COLUMNS)
+            .load::<AlarmEvent>(&*connection)?;
+        ctx.spend_request_coins("Site.alarmHistory", 2 * ctx.costs().db_query);
+        Ok(events)
+    }
+
+    /// Signed path of the iCalendar feed of the site, it can be subscribed without logging in
+    fn calendar_url(&self, ctx: &Context) -> ServiceResult<String> {
+        ctx.get_user_required()?.ensure_site_visible(&ctx.app, self.id)?;
+        Ok(calendar_path(&ctx.app, self.id))
+    }
+
+    /// Url that The Things Network webhooks should use to send the uplinks of the site's devices,
+    /// admin only
+    fn ttn_uplink_url(&self, ctx: &Context) -> ServiceResult<String> {
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, self.id)?;
+        Ok(ttn_uplink_path(&ctx.app, self.id))
+    }
+
+    /// Token used to read the current conditions of the site without an account, admin only
+    fn public_token(&self, ctx: &Context) -> ServiceResult<Option<String>> {
+        use crate::schema::site_public_token::dsl;
+        ctx.get_user_required()?.ensure_site_level(&ctx.app, self.id, AccessLevel::Admin)?;
+        let conn = ctx.get_connection()?;
+
+        Ok(dsl::site_public_token.find(self.id)
+            .select(dsl::token)
+            .first::<String>(&*conn)
+            .optional()?)
+    }
+
+    /// Guesses the cnr sensor ids under this site based on recent readings,
+    /// Admin privileges are required for this operation as it puts some stress on the database
+    fn cnr_sensor_ids(&self, ctx: &Context) -> ServiceResult<Vec<String>> {
+        ctx.get_user_required()?.ensure_admin()?;
+        let conn = &ctx.app.sensor_pool;
+
+        let id_cnr = match self.id_cnr.as_ref() {
+            None => return Ok(Vec::new()),
+            Some(x) => x,
+        };
+
+        let res = conn.prep_exec("SELECT DISTINCT idsensore FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id ORDER BY data DESC LIMIT :n) AS tmp;", params!{
+            "site_id" => id_cnr,
+            "n" => ctx.app.tuning.discovery_site_readings,
+        })?;
+        let names: Vec<String> = res.map(|row| {
+            mysql::from_row::<String>(row.unwrap())
+        }).collect();
+        Ok(names)
+    }
+
+    fn has_image(&self, ctx: &Context) -> ServiceResult<bool> {
+        ctx.spend_request_coins("Site.hasImage", ctx.app.tuning.image_check_cost);
+        ctx.app.site_maps.exists(&get_file_from_site(self.id))
+            .map_err(ServiceError::InternalServerError)
     }
 }
 
 #[juniper::object(
-    description = "A sensor",
+    description = "A user access entry",
     Context = Context,
 )]
-impl Sensor {
-    pub fn id(&self) -> IdType {
-        self.id
+impl UserAccess {
+    pub fn user_id(&self) -> IdType {
+        self.user_id
     }
 
     pub fn site_id(&self) -> IdType {
         self.site_id
     }
 
-    pub fn id_cnr(&self) -> Option<&str> {
-        self.id_cnr.as_ref().map(|x| x.as_str())
+    pub fn level(&self) -> AccessLevel {
+        self.level
     }
 
-    pub fn name(&self) -> Option<&str> {
-        self.name.as_ref().map(|x| x.as_str())
+    pub fn user(&self, ctx: &Context) -> ServiceResult<User> {
+        use crate::schema::user_account::dsl::*;
+        let connection = ctx.app.pool.get()?;
+        Ok(user_account.find(self.user_id).first::<User>(&*connection)?)
     }
 
-    pub fn loc_x(&self) -> Option<i32> {
-        self.loc_x
+    pub fn site(&self, ctx: &Context) -> ServiceResult<Site> {
+        use crate::schema::site::dsl::*;
+        let connection = ctx.get_connection()?;
+        Ok(site.find(self.site_id).first::<Site>(&*connection)?)
     }
+}
 
-    pub fn loc_y(&self) -> Option<i32> {
-        self.loc_y
+#[juniper::object(
+    description = "A past (or ongoing) alarm of a channel",
+    Context = Context,
+)]
+impl AlarmEvent {
+    pub fn id(&self) -> IdType {
+        self.id
     }
 
-    pub fn enabled(&self) -> bool {
-        self.enabled
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
     }
 
-    pub fn status(&self, ctx: &Context) -> ServiceResult<SensorStateType> {
-        use crate::schema::channel::dsl::*;
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_DB_QUERY);
+    pub fn started_at(&self) -> NaiveDateTime {
+        self.started_at
+    }
 
-        if !self.enabled {
-            return Ok(SensorStateType::Disabled)
-        }
+    /// None if the alarm is still ongoing
+    pub fn ended_at(&self) -> Option<NaiveDateTime> {
+        self.ended_at
+    }
 
-        let connection = ctx.get_connection()?;
+    /// Most extreme measure registered during the alarm
+    pub fn peak_value(&self) -> f64 {
+        self.peak_value
+    }
 
-        let alarmed_count: i64 = channel.count()
-            .filter(sensor_id.eq(self.id))
-            .filter(alarmed.eq(true))
-            .get_result(&connection)?;
+    pub fn extreme_type(&self) -> MeasureExtremeType {
+        MeasureExtremeType::from_char(self.extreme_type.as_str()).unwrap_or(MeasureExtremeType::Max)
+    }
 
-        if alarmed_count > 0 {
-            return Ok(SensorStateType::Alarm)
-        }
+    /// None if nobody acknowledged the alarm
+    pub fn acknowledged_at(&self) -> Option<NaiveDateTime> {
+        self.acknowledged_at
+    }
 
-        Ok(SensorStateType::Ok)
+    /// Id of the user that acknowledged the alarm (None if the user has been deleted)
+    pub fn acknowledged_by(&self) -> Option<IdType> {
+        self.acknowledged_by
     }
 
-    pub fn site(&self, ctx: &Context) -> ServiceResult<Site> {
-        use crate::schema::site::dsl::*;
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_DB_QUERY);
-        let connection = ctx.get_connection()?;
-        Ok(site.find(self.site_id).first::<Site>(&connection)?)
+    pub fn acknowledge_note(&self) -> Option<&str> {
+        self.acknowledge_note.as_deref()
     }
 
-    pub fn channels(&self, ctx: &Context) -> ServiceResult<Vec<Channel>> {
+    /// Last change of the event (end or acknowledgement), used by changesSince
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+
+    pub fn channel(&self, ctx: &Context) -> ServiceResult<Channel> {
         use crate::schema::channel::dsl::*;
         ctx.check_request_balance()?;
-
+        ctx.spend_request_coins("AlarmEvent.channel", ctx.costs().db_query);
         let connection = ctx.get_connection()?;
-        // TODO: paging
-        let channels = channel.filter(sensor_id.eq(self.id))
-            .load::<Channel>(&connection)?;
-        ctx.spend_request_coins(channels.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
-        Ok(channels)
+        Ok(channel.find(self.channel_id).first::<Channel>(&*connection)?)
     }
+}
 
-    /// Guesses the cnr channel ids under this sensor based on recent readings,
-    /// Admin privileges are required for this operation as it puts some stress on the database
-    fn cnr_channel_ids(&self, ctx: &Context) -> ServiceResult<Vec<String>> {
-        ctx.get_user_required()?.ensure_admin()?;
-        use crate::schema::site::dsl as site_dsl;
-
-        let conn = &ctx.app.sensor_pool;
+#[juniper::object(
+    description = "A read-only key used by the services to access the HTTP api (X-Api-Key header)",
+    Context = Context,
+)]
+impl ApiKey {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
 
-        let sensor_cnr_id = match self.id_cnr.as_ref() {
-            None => return Ok(Vec::new()),
-            Some(x) => x,
-        };
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
+    /// The only site the key can read, null if it can read every site
+    pub fn site(&self, ctx: &Context) -> ServiceResult<Option<Site>> {
+        use crate::schema::site::dsl::*;
         let connection = ctx.get_connection()?;
-        let site_cnr_id = site_dsl::site.find(self.site_id)
-            .select(site_dsl::id_cnr)
-            .get_result::<Option<String>>(&connection)?;
+        Ok(match self.site_id {
+            Some(x) => Some(site.find(x).select(SITE_ALL_COLUMNS).first::<Site>(&*connection)?),
+            None => None,
+        })
+    }
 
-        let site_cnr_id = match site_cnr_id {
-            None => return Ok(Vec::new()),
-            Some(x) => x,
-        };
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
 
-        let res = conn.prep_exec("SELECT DISTINCT canale FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id ORDER BY data DESC LIMIT 100) AS tmp;", params!{
-            "site_id" => site_cnr_id,
-            "sensor_id" => sensor_cnr_id,
-        })?;
-        let names: Vec<String> = res.map(|row| {
-            mysql::from_row::<String>(row.unwrap())
-        }).collect();
+    pub fn revoked_at(&self) -> Option<NaiveDateTime> {
+        self.revoked_at
+    }
 
-        Ok(names)
+    /// The key can push readings, not only read them
+    pub fn can_ingest(&self) -> bool {
+        self.can_ingest
     }
 }
 
-impl Channel {
-    fn query_cnr_ids(&self, ctx: &Context) -> ServiceResult<Option<(String, String, String)>> {
-        use crate::schema::{
-            channel::dsl as channel_dsl,
-            sensor::dsl as sensor_dsl,
-            site::dsl as site_dsl,
-        };
-
-        let mut channel = match self.id_cnr.as_ref() {
-            None => return Ok(None),
-            Some(x) => x.clone()
-        };
-
-        let first_separator = channel.find('.');
-        let second_separator = if let Some(findex) = first_separator {
-            channel[findex + 1..].find('.')
-        } else { None };
-
-        if let (Some(first_index), Some(second_index)) = (first_separator, second_separator) {
-            // Shortcut, we already know site, sensor and channel ids, we just need to parse them
-            // Don't even need to open a connection
-            // format: site.sensor.channel
-            //             |      ^second_index
-            //             ^first_index
-            return Ok(Some((
-                channel[0..first_index].to_string(),
-                channel[first_index + 1..second_index].to_string(),
-                channel[second_index + 1..].to_string()
-            )));
-        }
-
-        // No shortcut allowed, we need at least the cnr_site_id
-        // Since we need it we'll get both site_id and sensor_id from the query and then we'll
-        // override the sensor_id if a separator is present (to maximize efficiency we should
-        // separate the queries but it's not that important, the inner joins always take place so
-        // we could only remove the extra sensor_id string...)
+#[juniper::object(
+    description = "An operation done by an admin",
+    Context = Context,
+)]
+impl AuditLogEntry {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
 
-        let conn = ctx.get_connection()?;
+    /// Id of the user that did the operation, null if it has been deleted
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
+    }
 
-        let mut site_sensor = channel_dsl::channel.find(self.id)
-            .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
-            .select((site_dsl::id_cnr, sensor_dsl::id_cnr))
-            .get_result::<(Option<String>, Option<String>)>(&conn)?;
+    pub fn username(&self) -> &str {
+        &self.username
+    }
 
-        if let Some(first_index) = first_separator {
-            // format: sensor.channel
-            site_sensor.1 = Some(channel[0..first_index].to_string());
-            channel = channel[first_index + 1..].to_string();
-        }
+    /// Name of the mutation (ex. deleteSite)
+    pub fn action(&self) -> &str {
+        &self.action
+    }
 
-        let res = if let (Some(site_id), Some(sensor_id)) = site_sensor {
-            Some((site_id, sensor_id, channel))
-        } else { None };
+    /// What has been modified (ex. site 12)
+    pub fn target(&self) -> &str {
+        &self.target
+    }
 
-        Ok(res)
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
     }
 }
 
 #[juniper::object(
-    description = "A sensor channel",
+    description = "A destructive action waiting for the confirmation of a second admin",
     Context = Context,
 )]
-impl Channel {
+impl PendingAction {
     pub fn id(&self) -> IdType {
         self.id
     }
 
-    pub fn sensor_id(&self) -> IdType {
-        self.sensor_id
+    pub fn action(&self) -> ServiceResult<PendingActionKind> {
+        PendingActionKind::from_name(&self.action)
+            .ok_or_else(|| ServiceError::InternalServerError(format!("Invalid pending action {}", self.action)))
     }
 
-    pub fn id_cnr(&self) -> Option<&str> {
-        self.id_cnr.as_ref().map(|x| x.as_str())
+    /// Id of the site, of the user or of the site map to delete
+    pub fn target_id(&self) -> IdType {
+        self.target_id
     }
 
-    pub fn name(&self) -> Option<&str> {
-        self.name.as_ref().map(|x| x.as_str())
+    pub fn requested_by(&self, ctx: &Context) -> ServiceResult<Option<User>> {
+        ctx.app.auth_cache.find_user_by_id(&ctx.app, self.requested_by)
     }
 
-    pub fn measure_unit(&self) -> Option<&str> {
-        self.measure_unit.as_ref().map(|x| x.as_str())
+    pub fn requested_at(&self) -> NaiveDateTime {
+        self.requested_at
     }
 
+    /// The action is discarded if it isn't confirmed by then
+    pub fn expires_at(&self) -> NaiveDateTime {
+        self.expires_at
+    }
+}
 
-    pub fn range_min(&self) -> Option<f64> {
-        self.range_min.as_ref().and_then(|x| x.to_f64())
+#[juniper::object(
+    description = "Checksum of an exported dataset, proves that a published file wasn't modified",
+    Context = Context,
+)]
+impl ExportManifest {
+    pub fn id(&self) -> IdType {
+        self.id
     }
 
-    pub fn range_max(&self) -> Option<f64> {
-        self.range_max.as_ref().and_then(|x| x.to_f64())
+    /// Id of the user that asked for the export, null for the api keys (or if the user has been
+    /// deleted)
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
     }
 
-    pub fn alarmed(&self) -> bool {
-        self.alarmed
+    /// The channel could have been deleted since the export
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
     }
 
-    pub fn sensor(&self, ctx: &Context) -> ServiceResult<Sensor> {
-        use crate::schema::sensor::dsl::*;
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_DB_QUERY);
-        let connection = ctx.get_connection()?;
-        Ok(sensor.find(self.sensor_id).first::<Sensor>(&connection)?)
+    pub fn format(&self) -> &str {
+        &self.format
     }
 
-    pub fn readings(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<ReadingData>> {
-        ctx.check_request_balance()?;
+    pub fn range_start(&self) -> NaiveDateTime {
+        self.range_start
+    }
 
-        let ids = self.query_cnr_ids(ctx)?;
+    pub fn range_end(&self) -> NaiveDateTime {
+        self.range_end
+    }
 
-        let ids = match ids {
-            Some(x) => x,
-            None => return Ok(Vec::new()),
-        };
+    /// The readings marked as invalid were exported too
+    pub fn include_invalid(&self) -> bool {
+        self.include_invalid
+    }
 
-        let result = ctx.app.sensor_pool.prep_exec(
-            "SELECT data, valore_min, valore_med, valore_max, scarto, errore FROM t_rilevamento_dati \
-             WHERE data >= :start AND data <= :end AND idsito = :site_id AND idsensore = :sensor_id \
-             AND canale = :channel_id;",
-            params! {
-            "start" => start,
-            "end" => end,
-            "site_id" => ids.0,
-            "sensor_id" => ids.1,
-            "channel_id" => ids.2,
-        });
+    /// Exported readings, null if the export didn't complete
+    pub fn row_count(&self) -> Option<i32> {
+        self.row_count.map(clamp_to_i32)
+    }
 
-        let data: Vec<ReadingData> = result.map(|qres| {
-            qres.map(|row| {
-                let (date, value_min, value_avg, value_max, deviation, error) =
-                    mysql::from_row::<(NaiveDateTime, f64, Option<f64>, Option<f64>, Option<f64>, Option<String>)>(row.unwrap());
-                ReadingData {
-                    date,
-                    value_min,
-                    value_avg,
-                    value_max,
-                    deviation,
-                    error,
-                }
-            }).collect()
-        }).map_err(|x| InternalServerError(x.to_string()))?;
+    /// Hex encoded SHA-256 of the file, null if the export didn't complete
+    pub fn sha256(&self) -> Option<&str> {
+        self.sha256.as_deref()
+    }
 
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_DB_QUERY * 10); // TODO: adjust value
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
 
-        Ok(data)
+    pub fn completed_at(&self) -> Option<NaiveDateTime> {
+        self.completed_at
     }
 }
 
-
-pub struct QueryRoot;
-
 #[juniper::object(
-    Context = Context
+    description = "Export of every channel of some sites as a zip of CSV files",
+    Context = Context,
 )]
-impl QueryRoot {
-    fn api_version() -> &str {
-        "1.0"
+impl BulkExportJob {
+    pub fn id(&self) -> IdType {
+        self.id
     }
 
-    fn user_me(ctx: &Context) -> ServiceResult<Option<User>> {
-        ctx.get_user()
+    /// Admin that asked for the export, null if it has been deleted
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
     }
 
-    fn users(ctx: &Context) -> ServiceResult<Vec<User>> {
-        use crate::schema::user_account::dsl::*;
-        ctx.get_user_required()?.ensure_admin()?;
-
-        let connection = ctx.get_connection()?;
-        Ok(user_account.load::<User>(&connection)?)
+    pub fn site_ids(&self) -> &[IdType] {
+        &self.site_ids
     }
 
-    fn sites(ctx: &Context, ids: Option<Vec<IdType>>) -> ServiceResult<Vec<Site>> {
-        let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
-
-        let len = ids.as_ref().map(|x| x.len());
+    pub fn range_start(&self) -> NaiveDateTime {
+        self.range_start
+    }
 
-        // TODO: LIMIT
-        let sites: Vec<Site> = match PermissionType::from_char(user.permission.as_str()).unwrap() {
-            PermissionType::Admin => {
-                use crate::schema::site::dsl as site_dsl;
+    pub fn range_end(&self) -> NaiveDateTime {
+        self.range_end
+    }
 
-                let conn = ctx.get_connection()?;
-                if let Some(filter_ids) = ids {
-                    site_dsl::site.filter(site_dsl::id.eq_any(filter_ids)).load::<Site>(&conn)?
-                } else {
-                    site_dsl::site.load::<Site>(&conn)?
-                }
-            },
-            PermissionType::User => {
-                if let Some(filter_ids) = ids {
-                    load_user_sites_filtered(ctx, user.id, filter_ids)?
-                } else {
-                    load_user_sites(ctx, user.id)?
-                }
-            }
-        };
+    /// The readings marked as invalid were exported too
+    pub fn include_invalid(&self) -> bool {
+        self.include_invalid
+    }
 
-        if let Some(l) = len {
-            if l != sites.len() {
-                return Err(ServiceError::NotFound("Site".to_string()))
-            }
-        }
+    pub fn status(&self) -> Option<ExportJobStatus> {
+        ExportJobStatus::from_char(&self.status)
+    }
 
-        Ok(sites)
+    /// Exported channels, set once the export is done
+    pub fn channel_count(&self) -> i32 {
+        self.channel_count
     }
 
-    fn sensors(ctx: &Context, ids: Vec<IdType>) -> ServiceResult<Vec<Sensor>> {
-        use crate::schema::user_access::dsl as user_access;
-        use crate::schema::site::dsl as site_dsl;
-        use crate::schema::sensor::dsl as sensor_dsl;
+    /// Exported readings, set once the export is done
+    pub fn row_count(&self) -> i32 {
+        clamp_to_i32(self.row_count)
+    }
 
-        let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
-        let conn = ctx.get_connection()?;
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
 
-        let is_admin =  PermissionType::from_char(user.permission.as_str()).unwrap_or(PermissionType::User) == PermissionType::Admin;
-        let ids_len = ids.len();
+    /// Signed path of the zip, it can be downloaded without logging in until the export expires
+    pub fn download_path(&self, ctx: &Context) -> String {
+        bulk_export_path(&ctx.app, self.id)
+    }
 
-        let sensors = if is_admin {
-            sensor_dsl::sensor
-                .filter(sensor_dsl::id.eq_any(ids))
-                .load::<Sensor>(&conn)?
-        } else {
-            let sensors = user_access::user_access
-                .filter(user_access::user_id.eq(user.id))
-                .inner_join(site_dsl::site.inner_join(sensor_dsl::sensor))
-                .filter(sensor_dsl::id.eq_any(ids))
-                .select(SENSOR_ALL_COLUMNS)
-                .load::<Sensor>(&conn)?;
-            ctx.spend_request_coins(sensors.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
-            sensors
-        };
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
 
-        if sensors.len() != ids_len {
-            return Err(ServiceError::NotFound("Sensor".to_string()))
-        }
-        Ok(sensors)
+    pub fn finished_at(&self) -> Option<NaiveDateTime> {
+        self.finished_at
     }
+}
 
-    fn channels(ctx: &Context, ids: Vec<IdType>) -> ServiceResult<Vec<Channel>> {
-        use crate::schema::user_access::dsl as user_access;
-        use crate::schema::site::dsl as site_dsl;
-        use crate::schema::sensor::dsl as sensor_dsl;
-        use crate::schema::channel::dsl as channel_dsl;
+pub struct SystemStatus {
+    storage: StorageStatus,
+}
 
+#[juniper::object(
+    description = "Mapping drift between the configured cnr ids and the readings database",
+    Context = Context,
+)]
+impl OrphanReport {
+    /// Enabled sensors whose cnr ids have no recent reading
+    pub fn stale_sensors(&self, ctx: &Context) -> ServiceResult<Vec<Sensor>> {
+        use crate::schema::sensor::dsl;
+        let conn = ctx.get_connection()?;
+
+        Ok(dsl::sensor.filter(dsl::id.eq_any(&self.stale_sensor_ids))
+            .order(dsl::id)
+            .load::<Sensor>(&*conn)?)
+    }
+
+    /// Channels of the enabled sensors whose cnr ids have no recent reading
+    pub fn stale_channels(&self, ctx: &Context) -> ServiceResult<Vec<Channel>> {
+        use crate::schema::channel::dsl;
+        let conn = ctx.get_connection()?;
+
+        Ok(dsl::channel.filter(dsl::id.eq_any(&self.stale_channel_ids))
+            .order(dsl::id)
+            .load::<Channel>(&*conn)?)
+    }
+
+    /// Recent readings that don't belong to any configured channel
+    pub fn unmapped_channels(&self) -> &[CnrChannelActivity] {
+        &self.unmapped
+    }
+}
+
+#[juniper::object(
+    description = "Health of the resources used by the server",
+    Context = Context,
+)]
+impl SystemStatus {
+    /// Storage of the site maps
+    pub fn storage(&self) -> &StorageStatus {
+        &self.storage
+    }
+}
+
+#[juniper::object(
+    description = "State of a storage",
+    Context = Context,
+)]
+impl StorageStatus {
+    /// Directory or bucket url
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Null if the free space can't be read or if the storage has no limit
+    pub fn free_bytes(&self) -> Option<f64> {
+        self.free_bytes.map(|x| x as f64)
+    }
+
+    pub fn min_free_bytes(&self) -> f64 {
+        self.min_free_bytes as f64
+    }
+
+    /// Writable and with enough free space
+    pub fn healthy(&self) -> bool {
+        StorageStatus::healthy(self)
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+pub struct IntegrationStatus {
+    fcm_enabled: bool,
+    email_enabled: bool,
+    secrets_enabled: bool,
+    secrets: Vec<IntegrationSecret>,
+}
+
+#[juniper::object(
+    description = "State of the third-party integrations",
+    Context = Context,
+)]
+impl IntegrationStatus {
+    pub fn fcm_enabled(&self) -> bool {
+        self.fcm_enabled
+    }
+
+    pub fn email_enabled(&self) -> bool {
+        self.email_enabled
+    }
+
+    /// False if SECRETS_KEY is not set, the credentials can only be changed in the environment
+    pub fn secrets_enabled(&self) -> bool {
+        self.secrets_enabled
+    }
+
+    pub fn secrets(&self) -> &Vec<IntegrationSecret> {
+        &self.secrets
+    }
+}
+
+#[juniper::object(
+    description = "A credential stored encrypted in the database",
+    Context = Context,
+)]
+impl IntegrationSecret {
+    pub fn name(&self) -> Option<IntegrationSecretName> {
+        IntegrationSecretName::from_key(&self.name)
+    }
+
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+}
+
+#[juniper::object(
+    description = "A sampled GraphQL request",
+    Context = Context,
+)]
+impl RequestLogEntry {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    /// Null for anonymous requests or deleted users
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
+    }
+
+    /// Site the request is about, null if it isn't about a single site
+    pub fn site_id(&self) -> Option<IdType> {
+        self.site_id
+    }
+
+    /// Operation name sent by the client, if any
+    pub fn operation_name(&self) -> Option<&str> {
+        self.operation_name.as_deref()
+    }
+
+    /// Quota coins spent by the request
+    pub fn complexity(&self) -> f64 {
+        self.complexity as f64
+    }
+
+    /// Rows loaded by the list and readings fields
+    pub fn row_count(&self) -> f64 {
+        self.row_count as f64
+    }
+
+    pub fn duration_ms(&self) -> i32 {
+        self.duration_ms
+    }
+
+    /// True if part of the request was rejected for exceeding the quota
+    pub fn quota_rejected(&self) -> bool {
+        self.quota_rejected
+    }
+}
+
+#[juniper::object(
+    description = "A period where the channel readings were unusual without crossing the range",
+    Context = Context,
+)]
+impl ChannelAnomaly {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
+    }
+
+    pub fn started_at(&self) -> NaiveDateTime {
+        self.started_at
+    }
+
+    pub fn ended_at(&self) -> NaiveDateTime {
+        self.ended_at
+    }
+
+    /// Highest score of the period (deviations from the expected value)
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    pub fn kind(&self) -> Option<AnomalyKind> {
+        AnomalyKind::from_char(&self.kind)
+    }
+}
+
+#[juniper::object(
+    description = "A group of channels that should read the same values (ex. rooms with the same target climate)",
+    Context = Context,
+)]
+impl PeerGroup {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channels(&self, ctx: &Context) -> ServiceResult<Vec<Channel>> {
+        use crate::schema::channel::dsl as channel_dsl;
+        use crate::schema::peer_group_channel::dsl as peer_dsl;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("PeerGroup.channels", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+
+        Ok(peer_dsl::peer_group_channel.inner_join(channel_dsl::channel)
+            .filter(peer_dsl::peer_group_id.eq(self.id))
+            .filter(channel_dsl::deleted_at.is_null())
+            .select(CHANNEL_ALL_COLUMNS)
+            .order(channel_dsl::id)
+            .load::<Channel>(&*connection)?)
+    }
+}
+
+#[juniper::object(
+    description = "An url that receives the alarms of a site, the payloads are signed with its secret",
+    Context = Context,
+)]
+impl SiteWebhook {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Whether the changes of the site status (OK, ALARM, STALE) are posted too
+    pub fn status_changes(&self) -> bool {
+        self.status_changes
+    }
+}
+
+#[juniper::object(
+    description = "A summary of a site periodically emailed to the current user",
+    Context = Context,
+)]
+impl ReportSubscription {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn period(&self) -> Option<ReportPeriod> {
+        ReportPeriod::from_char(&self.period)
+    }
+
+    pub fn format(&self) -> Option<ReportFormat> {
+        ReportFormat::from_char(&self.format)
+    }
+
+    /// End of the last period reported, null if no report has been sent yet
+    pub fn last_period_end(&self) -> Option<NaiveDateTime> {
+        self.last_period_end
+    }
+}
+
+#[juniper::object(
+    description = "Contacts notified when an alarm isn't acknowledged in time",
+    Context = Context,
+)]
+impl EscalationPolicy {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    /// Null if the policy applies to every site
+    pub fn site_id(&self) -> Option<IdType> {
+        self.site_id
+    }
+
+    /// Minutes from the start of the alarm after which the contacts are notified
+    pub fn delay_minutes(&self) -> i32 {
+        self.delay_minutes
+    }
+
+    pub fn target(&self) -> Option<EscalationTarget> {
+        EscalationTarget::from_char(&self.target)
+    }
+
+    /// Users notified when the target is USERS
+    pub fn user_ids(&self) -> &[IdType] {
+        &self.user_ids
+    }
+}
+
+#[juniper::object(
+    description = "A scheduled maintenance of a site",
+    Context = Context,
+)]
+impl MaintenanceWindow {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn start_time(&self) -> NaiveDateTime {
+        self.start_time
+    }
+
+    pub fn end_time(&self) -> NaiveDateTime {
+        self.end_time
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+}
+
+#[juniper::object(
+    description = "An interval of the channel readings marked as invalid (probe fault, calibration...)",
+    Context = Context,
+)]
+impl ReadingAnnotation {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
+    }
+
+    pub fn start_time(&self) -> NaiveDateTime {
+        self.start_time
+    }
+
+    pub fn end_time(&self) -> NaiveDateTime {
+        self.end_time
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+#[juniper::object(
+    description = "A calibration of a sensor instrument",
+    Context = Context,
+)]
+impl SensorCalibration {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn sensor_id(&self) -> IdType {
+        self.sensor_id
+    }
+
+    pub fn calibrated_at(&self) -> NaiveDateTime {
+        self.calibrated_at
+    }
+
+    /// Id of the calibration certificate
+    pub fn certificate_id(&self) -> &str {
+        &self.certificate_id
+    }
+
+    /// When the sensor must be calibrated again
+    pub fn next_due_at(&self) -> NaiveDateTime {
+        self.next_due_at
+    }
+
+    /// True if the users have already been reminded of the next calibration
+    pub fn reminder_sent(&self) -> bool {
+        self.reminder_sent
+    }
+
+    pub fn sensor(&self, ctx: &Context) -> ServiceResult<Sensor> {
+        use crate::schema::sensor::dsl::*;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("SensorCalibration.sensor", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+        Ok(sensor.find(self.sensor_id).first::<Sensor>(&*connection)?)
+    }
+}
+
+#[juniper::object(
+    description = "A spot measurement taken with a handheld instrument",
+    Context = Context,
+)]
+impl ManualReading {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
+    }
+
+    /// Id of the user that entered the reading, null if it has been deleted
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
+    }
+
+    pub fn taken_at(&self) -> NaiveDateTime {
+        self.taken_at
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+}
+
+#[juniper::object(
+    description = "A Modbus register polled by the server to acquire the readings of a channel",
+    Context = Context,
+)]
+impl ModbusRegister {
+    pub fn channel_id(&self) -> IdType {
+        self.channel_id
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> i32 {
+        self.port
+    }
+
+    pub fn unit_id(&self) -> i32 {
+        self.unit_id
+    }
+
+    pub fn address(&self) -> i32 {
+        self.address
+    }
+
+    pub fn register_type(&self) -> Option<ModbusRegisterType> {
+        ModbusRegisterType::from_char(&self.register_type)
+    }
+
+    pub fn value_type(&self) -> Option<ModbusValueType> {
+        ModbusValueType::from_char(&self.value_type)
+    }
+
+    /// The read value is multiplied by the scale and then the offset is added
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn offset(&self) -> f64 {
+        self.value_offset
+    }
+}
+
+#[juniper::object(
+    description = "A LoRaWAN device that sends its readings through The Things Network",
+    Context = Context,
+)]
+impl TtnDevice {
+    pub fn sensor_id(&self) -> IdType {
+        self.sensor_id
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn payload_format(&self) -> Option<TtnPayloadFormat> {
+        TtnPayloadFormat::from_char(&self.payload_format)
+    }
+}
+
+#[juniper::object(
+    description = "An additional map of a site, its image is at /api/site_map/{siteId}/{id}",
+    Context = Context,
+)]
+impl SiteMap {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn image_width(&self) -> Option<i32> {
+        self.image_width
+    }
+
+    pub fn image_height(&self) -> Option<i32> {
+        self.image_height
+    }
+
+    fn has_image(&self, ctx: &Context) -> ServiceResult<bool> {
+        ctx.spend_request_coins("SiteMap.hasImage", ctx.app.tuning.image_check_cost);
+        ctx.app.site_maps.exists(&get_file_from_site_map(self.site_id, self.id))
+            .map_err(ServiceError::InternalServerError)
+    }
+
+    /// Sensors placed on the map
+    pub fn sensors(&self, ctx: &Context) -> ServiceResult<Vec<Sensor>> {
+        use crate::schema::sensor::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let sensors = dsl::sensor.filter(dsl::map_id.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
+            .order(dsl::id)
+            .load::<Sensor>(&*connection)?;
+        ctx.count_rows(sensors.len());
+        ctx.spend_request_coins("SiteMap.sensors", sensors.len() as i64 * ctx.costs().db_query);
+        Ok(sensors)
+    }
+}
+
+#[juniper::object(
+    description = "A room of a site",
+    Context = Context,
+)]
+impl Room {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn floor(&self) -> Option<i32> {
+        self.floor
+    }
+
+    /// Position of the room in the site map
+    pub fn loc_x(&self) -> Option<i32> {
+        self.loc_x
+    }
+
+    pub fn loc_y(&self) -> Option<i32> {
+        self.loc_y
+    }
+
+    pub fn sensors(&self, ctx: &Context) -> ServiceResult<Vec<Sensor>> {
+        use crate::schema::sensor::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+        let sensors = dsl::sensor.filter(dsl::room_id.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
+            .order(dsl::id)
+            .load::<Sensor>(&*connection)?;
+        ctx.count_rows(sensors.len());
+        ctx.spend_request_coins("Room.sensors", sensors.len() as i64 * ctx.costs().db_query);
+        Ok(sensors)
+    }
+}
+
+#[juniper::object(
+    description = "A sensor",
+    Context = Context,
+)]
+impl Sensor {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn site_id(&self) -> IdType {
+        self.site_id
+    }
+
+    pub fn id_cnr(&self) -> Option<&str> {
+        self.id_cnr.as_ref().map(|x| x.as_str())
+    }
+
+    /// When the sensor was moved to the trash, null if it isn't deleted
+    pub fn deleted_at(&self) -> Option<NaiveDateTime> {
+        self.deleted_at
+    }
+
+    /// Last change of the sensor, used by changesSince
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+
+    /// The name in the requested locale, the default name is used when there's no translation
+    pub fn name(&self, locale: Option<String>) -> Option<String> {
+        localized_name(&self.name, &self.name_translations, locale.as_deref())
+    }
+
+    pub fn name_translations(&self) -> Vec<NameTranslation> {
+        name_translations(&self.name_translations)
+    }
+
+    pub fn loc_x(&self) -> Option<i32> {
+        self.loc_x
+    }
+
+    pub fn loc_y(&self) -> Option<i32> {
+        self.loc_y
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn room_id(&self) -> Option<IdType> {
+        self.room_id
+    }
+
+    /// Map the location refers to, null for the main map of the site
+    pub fn map_id(&self) -> Option<IdType> {
+        self.map_id
+    }
+
+    pub fn room(&self, ctx: &Context) -> ServiceResult<Option<Room>> {
+        use crate::schema::room::dsl;
+        let room_id = match self.room_id {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("Sensor.room", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+        Ok(dsl::room.find(room_id).first::<Room>(&*connection).optional()?)
+    }
+
+    /// Calibrations of the sensor, newest first
+    pub fn calibrations(&self, ctx: &Context) -> ServiceResult<Vec<SensorCalibration>> {
+        use crate::schema::sensor_calibration::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+
+        let calibrations = dsl::sensor_calibration
+            .filter(dsl::sensor_id.eq(self.id))
+            .order(dsl::calibrated_at.desc())
+            .load::<SensorCalibration>(&*connection)?;
+        ctx.spend_request_coins("Sensor.calibrations", ctx.costs().db_query);
+        Ok(calibrations)
+    }
+
+    /// TTN device that sends the readings of the sensor, if any (admin only)
+    pub fn ttn_device(&self, ctx: &Context) -> ServiceResult<Option<TtnDevice>> {
+        use crate::schema::ttn_device::dsl;
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, self.id)?;
+        let connection = ctx.get_connection()?;
+
+        Ok(dsl::ttn_device.find(self.id)
+            .first::<TtnDevice>(&*connection)
+            .optional()?)
+    }
+
+    pub fn status(&self, ctx: &Context) -> ServiceResult<SensorStateType> {
+        use crate::schema::channel::dsl::*;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("Sensor.status", ctx.costs().db_query);
+
+        if !self.enabled {
+            return Ok(SensorStateType::Disabled)
+        }
+
+        let connection = ctx.get_connection()?;
+
+        let alarmed_ids: Vec<IdType> = channel.select(id)
+            .filter(sensor_id.eq(self.id))
+            .filter(alarmed.eq(true))
+            .load(&*connection)?;
+
+        if !alarmed_ids.is_empty() {
+            let silenced = load_silenced_channels(&connection, &alarmed_ids, Utc::now().naive_utc())?;
+            if alarmed_ids.iter().all(|x| silenced.contains(x)) {
+                return Ok(SensorStateType::AlarmAcknowledged)
+            }
+            return Ok(SensorStateType::Alarm)
+        }
+
+        if self.no_data_since.is_some() {
+            return Ok(SensorStateType::NoData)
+        }
+
+        Ok(SensorStateType::Ok)
+    }
+
+    /// Seconds without new readings after which the NoData alarm is raised (None or 0 if it's
+    /// never raised)
+    pub fn max_silence(&self) -> Option<i32> {
+        self.max_silence
+    }
+
+    /// When the NoData alarm was raised, None if the sensor is reporting
+    pub fn no_data_since(&self) -> Option<NaiveDateTime> {
+        self.no_data_since
+    }
+
+    pub fn site(&self, ctx: &Context) -> ServiceResult<Site> {
+        use crate::schema::site::dsl::*;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("Sensor.site", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+        Ok(site.find(self.site_id).first::<Site>(&*connection)?)
+    }
+
+    pub fn channels(&self, ctx: &Context) -> ServiceResult<Vec<Channel>> {
+        use crate::schema::channel::dsl::*;
+        ctx.check_request_balance()?;
+
+        let connection = ctx.get_connection()?;
+        // TODO: paging
+        let channels = channel.filter(sensor_id.eq(self.id))
+            .filter(deleted_at.is_null())
+            .load::<Channel>(&*connection)?;
+        ctx.count_rows(channels.len());
+        ctx.spend_request_coins("Sensor.channels", channels.len() as i64 * ctx.costs().db_query);
+        Ok(channels)
+    }
+
+    /// Paginated version of channels, ordered by id
+    pub fn channel_page(&self, ctx: &Context, first: Option<i32>, after: Option<String>, last: Option<i32>, before: Option<String>) -> ServiceResult<ChannelPage> {
+        use crate::schema::channel::dsl::*;
+        ctx.check_request_balance()?;
+        let page = PageRequest::new(first, after, last, before)?;
+        let connection = ctx.get_connection()?;
+
+        let mut query = channel.filter(sensor_id.eq(self.id))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        if let Some(x) = page.after {
+            query = query.filter(id.gt(x));
+        }
+        if let Some(x) = page.before {
+            query = query.filter(id.lt(x));
+        }
+        query = if page.backwards { query.order(id.desc()) } else { query.order(id.asc()) };
+        let channels = query.limit(page.query_limit())
+            .load::<Channel>(&*connection)?;
+
+        ctx.count_rows(channels.len());
+        ctx.spend_request_coins("Sensor.channelPage", channels.len() as i64 * ctx.costs().db_query);
+        let (nodes, page_info) = page.finish(channels, |x| x.id);
+        Ok(ChannelPage { nodes, page_info })
+    }
+
+    /// Guesses the cnr channel ids under this sensor based on recent readings,
+    /// Admin privileges are required for this operation as it puts some stress on the database
+    fn cnr_channel_ids(&self, ctx: &Context) -> ServiceResult<Vec<String>> {
+        ctx.get_user_required()?.ensure_admin()?;
+        use crate::schema::site::dsl as site_dsl;
+
+        let conn = &ctx.app.sensor_pool;
+
+        let sensor_cnr_id = match self.id_cnr.as_ref() {
+            None => return Ok(Vec::new()),
+            Some(x) => x,
+        };
+
+        let connection = ctx.get_connection()?;
+        let site_cnr_id = site_dsl::site.find(self.site_id)
+            .select(site_dsl::id_cnr)
+            .get_result::<Option<String>>(&*connection)?;
+
+        let site_cnr_id = match site_cnr_id {
+            None => return Ok(Vec::new()),
+            Some(x) => x,
+        };
+
+        let res = conn.prep_exec("SELECT DISTINCT canale FROM (SELECT * FROM t_rilevamento_dati WHERE idsito = :site_id AND idsensore = :sensor_id ORDER BY data DESC LIMIT :n) AS tmp;", params!{
+            "site_id" => site_cnr_id,
+            "sensor_id" => sensor_cnr_id,
+            "n" => ctx.app.tuning.discovery_sensor_readings,
+        })?;
+        let names: Vec<String> = res.map(|row| {
+            mysql::from_row::<String>(row.unwrap())
+        }).collect();
+
+        Ok(names)
+    }
+}
+
+impl Channel {
+    fn query_cnr_ids(&self, ctx: &Context) -> ServiceResult<Option<(String, String, String)>> {
+        query_channel_cnr_ids(&ctx.app.pool, self.id, self.id_cnr.as_deref())
+    }
+}
+
+/// Intervals of the channel readings marked as invalid between start and end, empty if the
+/// invalid readings should be included.
+fn channel_invalid_intervals(ctx: &Context, channel_id: IdType, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<Vec<InvalidInterval>> {
+    if include_invalid.unwrap_or(false) {
+        return Ok(Vec::new())
+    }
+    let conn = ctx.get_connection()?;
+    Ok(load_invalid_intervals(&conn, channel_id, start, end)?)
+}
+
+/// Analyzes the channel readings between start and end, returns None if there's not enough data.
+fn load_range_recommendation(ctx: &Context, channel_id: IdType, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<Option<RangeRecommendation>> {
+    use crate::schema::channel::dsl;
+
+    let conn = ctx.get_connection()?;
+    let channel = dsl::channel.find(channel_id)
+        .first::<Channel>(&*conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Channel".to_string()))?;
+    std::mem::drop(conn);
+
+    let ids = match channel.query_cnr_ids(ctx)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let invalid = channel_invalid_intervals(ctx, channel_id, start, end, include_invalid)?;
+    let readings = load_channel_readings(&ctx.app.sensor_pool, &ids, start, end, &invalid)?;
+
+    Ok(recommend_range(&readings))
+}
+
+/// Hourly averages of the channel readings, empty if the channel has no cnr ids
+fn load_hourly_series(ctx: &Context, channel: &Channel, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<Vec<(NaiveDateTime, f64)>> {
+    let ids = match channel.query_cnr_ids(ctx)? {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = channel_invalid_intervals(ctx, channel.id, start, end, include_invalid)?;
+    let readings = load_channel_readings_aggregated(&ctx.app.sensor_pool, &ids, start, end, &invalid, ReadingsAggregation::Hourly)?;
+    Ok(readings.iter().map(|x| (x.date, reading_value(x))).collect())
+}
+
+#[juniper::object(
+    description = "A sensor channel",
+    Context = Context,
+)]
+impl Channel {
+    pub fn id(&self) -> IdType {
+        self.id
+    }
+
+    pub fn sensor_id(&self) -> IdType {
+        self.sensor_id
+    }
+
+    pub fn id_cnr(&self) -> Option<&str> {
+        self.id_cnr.as_ref().map(|x| x.as_str())
+    }
+
+    /// When the channel was moved to the trash, null if it isn't deleted
+    pub fn deleted_at(&self) -> Option<NaiveDateTime> {
+        self.deleted_at
+    }
+
+    /// Last change of the channel, used by changesSince
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+
+    /// The name in the requested locale, the default name is used when there's no translation
+    pub fn name(&self, locale: Option<String>) -> Option<String> {
+        localized_name(&self.name, &self.name_translations, locale.as_deref())
+    }
+
+    pub fn name_translations(&self) -> Vec<NameTranslation> {
+        name_translations(&self.name_translations)
+    }
+
+    pub fn measure_unit(&self) -> Option<&str> {
+        self.measure_unit.as_ref().map(|x| x.as_str())
+    }
+
+
+    pub fn range_min(&self) -> Option<f64> {
+        self.range_min.as_ref().and_then(|x| x.to_f64())
+    }
+
+    pub fn range_max(&self) -> Option<f64> {
+        self.range_max.as_ref().and_then(|x| x.to_f64())
+    }
+
+    /// Fastest change allowed between two consecutive readings (per hour), faster changes raise
+    /// an alarm even if the value is in range
+    pub fn max_delta_per_hour(&self) -> Option<f64> {
+        self.max_delta_per_hour.as_ref().and_then(|x| x.to_f64())
+    }
+
+    /// Margin inside the range that the readings must reach for the alarm to end
+    pub fn hysteresis(&self) -> Option<f64> {
+        self.hysteresis.as_ref().and_then(|x| x.to_f64())
+    }
+
+    /// Seconds that the channel must stay out of range before the alarm begins
+    pub fn alarm_delay(&self) -> Option<i32> {
+        self.alarm_delay
+    }
+
+    /// The channel is out of range since then but its alarm delay isn't over yet
+    pub fn alarm_pending_since(&self) -> Option<NaiveDateTime> {
+        self.alarm_pending_since
+    }
+
+    pub fn alarmed(&self) -> bool {
+        self.alarmed
+    }
+
+    pub fn alarm_info(&self) -> ChannelAlarmInfo {
+        ChannelAlarmInfo {
+            alarmed: self.alarmed,
+            pending_since: self.alarm_pending_since,
+            flapping: self.flapping_since.is_some(),
+            flapping_since: self.flapping_since,
+        }
+    }
+
+    /// The alarms of the channel aren't notified until this date (None if it's not muted)
+    pub fn muted_until(&self, ctx: &Context) -> ServiceResult<Option<NaiveDateTime>> {
+        use crate::schema::channel_mute::dsl;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("Channel.mutedUntil", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+        Ok(dsl::channel_mute.find(self.id)
+            .filter(dsl::muted_until.gt(Utc::now().naive_utc()))
+            .select(dsl::muted_until)
+            .first(&*connection)
+            .optional()?)
+    }
+
+    pub fn sensor(&self, ctx: &Context) -> ServiceResult<Sensor> {
+        use crate::schema::sensor::dsl::*;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("Channel.sensor", ctx.costs().db_query);
+        let connection = ctx.get_connection()?;
+        Ok(sensor.find(self.sensor_id).first::<Sensor>(&*connection)?)
+    }
+
+    /// Alarms of the channel that overlap the start-end range, newest first
+    pub fn alarm_history(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<AlarmEvent>> {
+        use crate::schema::alarm_event::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+
+        let events = dsl::alarm_event
+            .filter(dsl::channel_id.eq(self.id))
+            .filter(dsl::started_at.le(end))
+            .filter(dsl::ended_at.is_null().or(dsl::ended_at.ge(start)))
+            .order(dsl::started_at.desc())
+            .limit(ALARM_HISTORY_MAX_EVENTS)
+            .load::<AlarmEvent>(&*connection)?;
+        ctx.spend_request_coins("Channel.alarmHistory", ctx.costs().db_query);
+        Ok(events)
+    }
+
+    /// Anomalous periods of the channel that overlap the start-end range, newest first
+    pub fn anomalies(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<ChannelAnomaly>> {
+        use crate::schema::channel_anomaly::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+
+        let anomalies = dsl::channel_anomaly
+            .filter(dsl::channel_id.eq(self.id))
+            .filter(dsl::started_at.le(end))
+            .filter(dsl::ended_at.ge(start))
+            .order(dsl::started_at.desc())
+            .limit(ALARM_HISTORY_MAX_EVENTS)
+            .load::<ChannelAnomaly>(&*connection)?;
+        ctx.spend_request_coins("Channel.anomalies", ctx.costs().db_query);
+        Ok(anomalies)
+    }
+
+    /// Intervals of the channel readings marked as invalid that overlap the start-end range
+    pub fn annotations(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<ReadingAnnotation>> {
+        use crate::schema::reading_annotation::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+
+        let annotations = dsl::reading_annotation
+            .filter(dsl::channel_id.eq(self.id))
+            .filter(dsl::start_time.le(end))
+            .filter(dsl::end_time.ge(start))
+            .order(dsl::start_time)
+            .load::<ReadingAnnotation>(&*connection)?;
+        ctx.spend_request_coins("Channel.annotations", ctx.costs().db_query);
+        Ok(annotations)
+    }
+
+    /// Readings of the channel entered by hand between start and end, they are also part of the
+    /// channel readings
+    pub fn manual_readings(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Vec<ManualReading>> {
+        use crate::schema::manual_reading::dsl;
+        ctx.check_request_balance()?;
+        let connection = ctx.get_connection()?;
+
+        let readings = dsl::manual_reading
+            .filter(dsl::channel_id.eq(self.id))
+            .filter(dsl::taken_at.ge(start))
+            .filter(dsl::taken_at.le(end))
+            .order(dsl::taken_at)
+            .load::<ManualReading>(&*connection)?;
+        ctx.spend_request_coins("Channel.manualReadings", ctx.costs().db_query);
+        Ok(readings)
+    }
+
+    /// Modbus register used to acquire the channel readings, if any (admin only)
+    pub fn modbus_register(&self, ctx: &Context) -> ServiceResult<Option<ModbusRegister>> {
+        use crate::schema::modbus_register::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
+        let connection = ctx.get_connection()?;
+
+        Ok(dsl::modbus_register.find(self.id)
+            .first::<ModbusRegister>(&*connection)
+            .optional()?)
+    }
+
+    /// Age of the newest reading, null if the channel has no readings
+    pub fn data_latency(&self, ctx: &Context) -> ServiceResult<Option<DataLatency>> {
+        ctx.check_request_balance()?;
+        let ids = match self.query_cnr_ids(ctx)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        ctx.spend_request_coins("Channel.dataLatency", ctx.costs().db_query);
+
+        let latencies = load_data_latencies(&ctx.app.sensor_pool, &[(self.id, ids)], Utc::now().naive_utc())?;
+        Ok(latencies.into_iter().next())
+    }
+
+    /// Readings between start and end, the aggregation (RAW by default) groups them on the
+    /// database side to reduce the returned points.
+    /// The readings marked as invalid are skipped unless includeInvalid is true
+    pub fn readings(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime, aggregation: Option<ReadingsAggregation>, include_invalid: Option<bool>) -> ServiceResult<Vec<ReadingData>> {
+        ctx.check_request_balance()?;
+
+        let ids = self.query_cnr_ids(ctx)?;
+
+        let ids = match ids {
+            Some(x) => x,
+            None => return Ok(Vec::new()),
+        };
+
+        let aggregation = aggregation.unwrap_or(ReadingsAggregation::Raw);
+        let invalid = channel_invalid_intervals(ctx, self.id, start, end, include_invalid)?;
+        let now = Utc::now().naive_utc();
+        let data = ctx.app.readings_cache.load(&ctx.app.sensor_pool, &ids, start, end, &invalid, aggregation, now)?;
+
+        ctx.count_rows(data.len());
+        ctx.spend_request_coins("Channel.readings", ctx.costs().db_query * 10); // TODO: adjust value
+
+        Ok(data)
+    }
+
+    /// Count, min, max, mean, standard deviation and 95th percentile of the readings between
+    /// start and end, computed by the sensor database.
+    /// The readings marked as invalid are skipped unless includeInvalid is true
+    pub fn stats(&self, ctx: &Context, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<ReadingStats> {
+        ctx.check_request_balance()?;
+
+        let ids = match self.query_cnr_ids(ctx)? {
+            Some(x) => x,
+            None => return Ok(ReadingStats::empty()),
+        };
+
+        let invalid = channel_invalid_intervals(ctx, self.id, start, end, include_invalid)?;
+        let stats = load_channel_reading_stats(&ctx.app.sensor_pool, &ids, start, end, &invalid)?;
+        ctx.spend_request_coins("Channel.stats", ctx.costs().db_query * 10);
+        Ok(stats)
+    }
+}
+
+
+pub struct QueryRoot;
+
+#[juniper::object(
+    Context = Context
+)]
+impl QueryRoot {
+    fn api_version() -> &str {
+        "1.0"
+    }
+
+    fn user_me(ctx: &Context) -> ServiceResult<Option<User>> {
+        ctx.get_user()
+    }
+
+    /// Sessions of the current user opened by the login, the most recently used first
+    fn my_sessions(ctx: &Context) -> ServiceResult<Vec<UserSession>> {
+        use crate::schema::user_session::dsl;
+        let user = ctx.get_user_required()?;
+
+        Ok(dsl::user_session
+            .filter(dsl::user_id.eq(user.id))
+            .order((dsl::last_seen_at.desc(), dsl::id.desc()))
+            .load::<UserSession>(&*ctx.get_connection()?)?)
+    }
+
+    /// Settings stored by the clients for the current user (ex. default site, units, chart
+    /// settings), shared between the devices of the user
+    fn my_preferences(ctx: &Context) -> ServiceResult<Vec<UserPreference>> {
+        use crate::schema::user_preference::dsl;
+        let user = ctx.get_user_required()?;
+
+        Ok(dsl::user_preference
+            .filter(dsl::user_id.eq(user.id))
+            .order(dsl::key)
+            .load::<UserPreference>(&*ctx.get_connection()?)?)
+    }
+
+    /// Last measures of the site shared with the public token, doesn't need an account so the
+    /// lobby screens can send it with GET and let the CDNs cache it
+    fn public_site(ctx: &Context, token: String) -> ServiceResult<SiteConditions> {
+        ctx.check_request_balance()?;
+        let conditions = load_site_conditions(&ctx.app, &token)?;
+        ctx.spend_request_coins("publicSite", ctx.costs().db_query * 2);
+        Ok(conditions)
+    }
+
+    fn my_report_subscriptions(ctx: &Context) -> ServiceResult<Vec<ReportSubscription>> {
+        use crate::schema::report_subscription::dsl;
+        let user = ctx.get_user_required()?;
+
+        Ok(dsl::report_subscription
+            .filter(dsl::user_id.eq(user.id))
+            .order(dsl::id)
+            .load::<ReportSubscription>(&*ctx.get_connection()?)?)
+    }
+
+    /// HTML report of the last completed period of the site, as it would be emailed to the
+    /// subscribed users
+    fn site_report_preview(ctx: &Context, site_id: IdType, period: ReportPeriod) -> ServiceResult<String> {
+        ctx.get_user_required()?.ensure_site_visible(&ctx.app, site_id)?;
+        ctx.check_request_balance()?;
+
+        let end = period.last_end(Utc::now().naive_utc());
+        let summary = build_site_summary(&ctx.app.pool, &ctx.app.sensor_pool, site_id, period, end)
+            .map_err(ServiceError::InternalServerError)?;
+        ctx.spend_request_coins("siteReportPreview", ctx.costs().db_query * (1 + summary.channels.len() as i64));
+        Ok(render_html(&summary))
+    }
+
+    /// Newest n readings of the channel (10 by default, at most 100) without any date range, only
+    /// available in development mode to help exploring the API
+    fn sample_readings(ctx: &Context, channel_id: IdType, n: Option<i32>) -> ServiceResult<Vec<ReadingData>> {
+        use crate::schema::channel::dsl;
+
+        if !ctx.app.dev_mode {
+            return Err(ServiceError::BadRequest("sampleReadings is only available in development mode".to_string()))
+        }
+        let n = n.unwrap_or(10);
+        if n < 1 || n > 100 {
+            return Err(ServiceError::BadRequest("n must be between 1 and 100".to_string()))
+        }
+        ctx.get_user_required()?.ensure_channel_visible(&ctx.app, channel_id)?;
+        ctx.check_request_balance()?;
+
+        let id_cnr = dsl::channel.find(channel_id)
+            .select(dsl::id_cnr)
+            .first::<Option<String>>(&*ctx.get_connection()?)?;
+        let readings = match query_channel_cnr_ids(&ctx.app.pool, channel_id, id_cnr.as_deref())? {
+            Some(ids) => load_last_channel_readings(&ctx.app.sensor_pool, &ids, n as u32)?,
+            None => Vec::new(),
+        };
+        ctx.spend_request_coins("sampleReadings", ctx.costs().db_query * 2);
+        Ok(readings)
+    }
+
+    fn my_quota(ctx: &Context) -> ServiceResult<Vec<QuotaInfo>> {
+        let user = ctx.user.borrow().clone().ok_or(ServiceError::LoginRequired)?;
+        let now = Instant::now();
+
+        // Empty if the quota is disabled
+        Ok([QuotaPool::Read, QuotaPool::Write].iter()
+            .filter_map(|pool| load_quota_info(ctx, user.id, *pool, now))
+            .collect())
+    }
+
+    /// Balance of the current user in the pool (READ by default), null if the quota is disabled
+    fn quota_status(ctx: &Context, pool: Option<QuotaPool>) -> ServiceResult<Option<QuotaStatus>> {
+        let user = ctx.user.borrow().clone().ok_or(ServiceError::LoginRequired)?;
+        let pool = pool.unwrap_or(QuotaPool::Read);
+
+        Ok(load_quota_info(ctx, user.id, pool, Instant::now()).map(QuotaStatus::from))
+    }
+
+    /// Compares the hourly readings of the channel with the average of the other channels of the
+    /// peer group, returns null if there's no overlapping data.
+    /// The readings marked as invalid are skipped unless includeInvalid is true
+    fn compare_with_peers(ctx: &Context, channel_id: IdType, peer_group_id: IdType, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<Option<PeerDivergence>> {
+        use crate::schema::channel::dsl as channel_dsl;
+        use crate::schema::peer_group_channel::dsl as peer_dsl;
+
+        let user = ctx.get_user_required()?;
+        ctx.check_request_balance()?;
+        user.ensure_channel_visible(&ctx.app, channel_id)?;
+
+        let connection = ctx.get_connection()?;
+        let mut channels: Vec<Channel> = peer_dsl::peer_group_channel.inner_join(channel_dsl::channel)
+            .filter(peer_dsl::peer_group_id.eq(peer_group_id))
+            .filter(channel_dsl::deleted_at.is_null())
+            .select(CHANNEL_ALL_COLUMNS)
+            .load::<Channel>(&*connection)?;
+        std::mem::drop(connection);
+
+        let target_index = channels.iter().position(|x| x.id == channel_id)
+            .ok_or_else(|| ServiceError::BadRequest("The channel is not in the peer group".to_string()))?;
+        let target = channels.remove(target_index);
+
+        let target_series = load_hourly_series(ctx, &target, start, end, include_invalid)?;
+        let peer_series = channels.iter()
+            .map(|x| load_hourly_series(ctx, x, start, end, include_invalid))
+            .collect::<ServiceResult<Vec<_>>>()?;
+        ctx.spend_request_coins("compareWithPeers", ctx.costs().db_query * 10 * (1 + peer_series.len() as i64));
+
+        let peer_series: Vec<_> = peer_series.into_iter().filter(|x| !x.is_empty()).collect();
+        Ok(compare_with_peers(&target_series, &peer_series))
+    }
+
+    /// Manifests of the exports whose file has the SHA-256 (hex encoded), empty if the file
+    /// wasn't exported by the server or was modified. Only the exports of the visible channels
+    /// are returned
+    fn verify_export(ctx: &Context, sha256: String) -> ServiceResult<Vec<ExportManifest>> {
+        let user = ctx.get_user_required()?;
+        let sha256 = parse_sha256(&sha256)
+            .ok_or_else(|| ServiceError::BadRequest("Invalid SHA-256".to_string()))?;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("verifyExport", ctx.costs().db_query);
+
+        let manifests = find_manifests(&*ctx.get_connection()?, &sha256)?;
+        if user.permission == PermissionType::Admin {
+            return Ok(manifests)
+        }
+        // The channels deleted since the export are only visible to the admins
+        Ok(manifests.into_iter()
+            .filter(|x| user.ensure_channel_visible(&ctx.app, x.channel_id).is_ok())
+            .collect())
+    }
+
+    fn sites(ctx: &Context, ids: Option<Vec<IdType>>) -> ServiceResult<Vec<Site>> {
+        ctx.refund_on_client_error(|| {
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+
+            let len = ids.as_ref().map(|x| x.len());
+
+            // TODO: LIMIT
+            let sites: Vec<Site> = match user.permission {
+                PermissionType::Admin => {
+                    use crate::schema::site::dsl as site_dsl;
+
+                    let conn = ctx.get_connection()?;
+                    if let Some(filter_ids) = ids {
+                        site_dsl::site.filter(site_dsl::id.eq_any(filter_ids))
+                            .filter(site_dsl::deleted_at.is_null())
+                            .load::<Site>(&*conn)?
+                    } else {
+                        site_dsl::site.filter(site_dsl::deleted_at.is_null()).load::<Site>(&*conn)?
+                    }
+                },
+                PermissionType::User | PermissionType::SiteManager => {
+                    if let Some(filter_ids) = ids {
+                        load_user_sites_filtered(ctx, user.id, filter_ids)?
+                    } else {
+                        load_user_sites(ctx, user.id)?
+                    }
+                }
+            };
+
+            if let Some(l) = len {
+                if l != sites.len() {
+                    return Err(ctx.app.access_policy.hidden("Site"))
+                }
+            }
+
+            Ok(sites)
+        })
+    }
+
+    fn sensors(ctx: &Context, ids: Vec<IdType>) -> ServiceResult<Vec<Sensor>> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::user_access::dsl as user_access;
+            use crate::schema::site::dsl as site_dsl;
+            use crate::schema::sensor::dsl as sensor_dsl;
+
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            let conn = ctx.get_connection()?;
+
+            let is_admin = user.permission == PermissionType::Admin;
+            let ids_len = ids.len();
+
+            let sensors = if is_admin {
+                sensor_dsl::sensor
+                    .filter(sensor_dsl::id.eq_any(ids))
+                    .filter(sensor_dsl::deleted_at.is_null())
+                    .load::<Sensor>(&*conn)?
+            } else {
+                let sensors = user_access::user_access
+                    .filter(user_access::user_id.eq(user.id))
+                    .inner_join(site_dsl::site.inner_join(sensor_dsl::sensor))
+                    .filter(sensor_dsl::id.eq_any(ids))
+                    .filter(sensor_dsl::deleted_at.is_null())
+                    .select(SENSOR_ALL_COLUMNS)
+                    .load::<Sensor>(&*conn)?;
+                ctx.count_rows(sensors.len());
+                ctx.spend_request_coins("sensors", sensors.len() as i64 * ctx.costs().db_query);
+                sensors
+            };
+
+            if sensors.len() != ids_len {
+                return Err(ctx.app.access_policy.hidden("Sensor"))
+            }
+            Ok(sensors)
+        })
+    }
+
+    fn channels(ctx: &Context, ids: Vec<IdType>) -> ServiceResult<Vec<Channel>> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::user_access::dsl as user_access;
+            use crate::schema::site::dsl as site_dsl;
+            use crate::schema::sensor::dsl as sensor_dsl;
+            use crate::schema::channel::dsl as channel_dsl;
+
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            let conn = ctx.get_connection()?;
+
+            let is_admin = user.permission == PermissionType::Admin;
+            let ids_len = ids.len();
+
+            let channels = if is_admin {
+                channel_dsl::channel
+                    .filter(channel_dsl::id.eq_any(ids))
+                    .filter(channel_dsl::deleted_at.is_null())
+                    .load::<Channel>(&*conn)?
+            } else {
+                let channels = user_access::user_access
+                    .filter(user_access::user_id.eq(user.id))
+                    .inner_join(site_dsl::site.inner_join(sensor_dsl::sensor.inner_join(channel_dsl::channel)))
+                    .filter(channel_dsl::id.eq_any(ids))
+                    .filter(channel_dsl::deleted_at.is_null())
+                    .select(CHANNEL_ALL_COLUMNS)
+                    .load::<Channel>(&*conn)?;
+                ctx.count_rows(channels.len());
+                ctx.spend_request_coins("channels", channels.len() as i64 * ctx.costs().db_query);
+                channels
+            };
+
+            if channels.len() != ids_len {
+                return Err(ctx.app.access_policy.hidden("Channel"))
+            }
+            Ok(channels)
+        })
+    }
+
+    /// The current user, the admins look up the other users through the admin schema
+    fn user(ctx: &Context, id: IdType) -> ServiceResult<User> {
+        let user = ctx.get_user_required()?;
+        if id != user.id {
+            return Err(ServiceError::Unauthorized)
+        }
+        Ok(user)
+    }
+
+    fn site(ctx: &Context, id: IdType) -> ServiceResult<Site> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::site::dsl;
+
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            ctx.spend_request_coins("site", 2 * ctx.costs().db_query);
+            user.ensure_site_visible(&ctx.app, id)?;// TODO: single query?
+
+            let conn = ctx.get_connection()?;
+
+            let site: Site = dsl::site.find(id)
+                .filter(dsl::deleted_at.is_null())
+                .first::<Site>(&*conn)
+                .optional()
+                .map_err(ServiceError::from)?
+                .ok_or_else(|| ServiceError::NotFound("Site".to_string()))?;
+            ctx.touch_site(site.id);
+            Ok(site)
+        })
+    }
+
+    fn room(ctx: &Context, id: IdType) -> ServiceResult<Room> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::room::dsl;
+
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            ctx.spend_request_coins("room", 2 * ctx.costs().db_query);
+            user.ensure_site_visible(&ctx.app, room_site_id(ctx, id)?)?;
+
+            let conn = ctx.get_connection()?;
+            let room = dsl::room.find(id)
+                .first::<Room>(&*conn)?;
+            ctx.touch_site(room.site_id);
+            Ok(room)
+        })
+    }
+
+    fn sensor(ctx: &Context, id: IdType) -> ServiceResult<Sensor> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::sensor::dsl;
+
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            ctx.spend_request_coins("sensor", 2 * ctx.costs().db_query);
+            user.ensure_sensor_visible(&ctx.app, id)?;
+
+            let conn = ctx.get_connection()?;
+
+            let site: Sensor = dsl::sensor.find(id)
+                .filter(dsl::deleted_at.is_null())
+                .first::<Sensor>(&*conn)
+                .optional()
+                .map_err(ServiceError::from)?
+                .ok_or_else(|| ServiceError::NotFound("Sensor".to_string()))?;
+            ctx.touch_site(site.site_id);
+            Ok(site)
+        })
+    }
+
+    fn channel(ctx: &Context, id: IdType) -> ServiceResult<Channel> {
+        ctx.refund_on_client_error(|| {
+            use crate::schema::channel::dsl;
+
+            let user = ctx.get_user_required()?;
+
+            ctx.check_request_balance()?;
+            ctx.spend_request_coins("channel", 2 * ctx.costs().db_query);
+            user.ensure_channel_visible(&ctx.app, id)?;
+            ctx.touch_site(channel_site_id(&ctx.app, id)?);
+
+            let conn = ctx.get_connection()?;
+
+            let site: Channel = dsl::channel.find(id)
+                .filter(dsl::deleted_at.is_null())
+                .first::<Channel>(&*conn)
+                .optional()
+                .map_err(ServiceError::from)?
+                .ok_or_else(|| ServiceError::NotFound("Channel".to_string()))?;
+            Ok(site)
+        })
+    }
+
+    /// Sites, sensors, channels and alarm events created, updated or deleted after the cursor
+    /// (everything visible if it's null), the returned nextCursor is used for the following sync
+    fn changes_since(ctx: &Context, cursor: Option<String>) -> ServiceResult<SyncChanges> {
+        ctx.refund_on_client_error(|| {
+            let user = ctx.get_user_required()?;
+            let since = cursor.as_deref().map(parse_cursor).transpose()?;
+            ctx.check_request_balance()?;
+
+            let user_id = if user.permission == PermissionType::Admin { None } else { Some(user.id) };
+            let changes = load_changes(&*ctx.get_connection()?, user_id, since)?;
+            ctx.count_rows(changes.len());
+            ctx.spend_request_coins("changesSince", (1 + changes.len() as i64) * ctx.costs().db_query);
+            Ok(changes)
+        })
+    }
+
+    /// Configuration of the site as JSON (sensors, channels, thresholds, rooms and map metadata),
+    /// it can be loaded on another server with importSiteConfig
+    fn export_site_config(ctx: &Context, site_id: IdType) -> ServiceResult<String> {
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        ctx.check_request_balance()?;
+        ctx.spend_request_coins("exportSiteConfig", ctx.costs().db_query * 5);
+
+        let config = load_site_config(&*ctx.get_connection()?, site_id)?;
+        serde_json::to_string_pretty(&config)
+            .map_err(|x| ServiceError::InternalServerError(x.to_string()))
+    }
+}
+
+pub struct MutationRoot;
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct AuthInput {
+    username: String,
+    password: String,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct ApiKeyInput {
+    name: String,
+    /// Restricts the key to a single site, every site can be read if null
+    site_id: Option<IdType>,
+    /// Allows the key to push readings to /api/ingest/readings, false by default
+    can_ingest: Option<bool>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct UserInput {
+    username: String,
+    password: String,
+    permission: PermissionType,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct UserUpdateInput {
+    username: Option<String>,
+    password: Option<String>,
+    permission: Option<PermissionType>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct SiteCreateInput {
+    name: Option<String>,
+    id_cnr: Option<String>,
+    auto_create: Option<bool>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
+#[table_name="site"]
+pub struct SiteUpdateInput {
+    name: Option<String>,
+    id_cnr: Option<String>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
+#[table_name="sensor"]
+pub struct SensorUpdateInput {
+    pub id_cnr: Option<String>,
+
+    pub name: Option<String>,
+    pub enabled: Option<bool>,
+
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+
+    /// Seconds without new readings after which the NoData alarm is raised, 0 disables it
+    pub max_silence: Option<i32>,
+
+    /// Room of the sensor, it must be in the same site
+    pub room_id: Option<IdType>,
+    /// Map the location refers to, it must be in the same site
+    pub map_id: Option<IdType>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct SensorPositionInput {
+    pub sensor_id: IdType,
+    /// Position on the map of the sensor, within the size of its image
+    pub loc_x: i32,
+    pub loc_y: i32,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct SensorCreateInput {
+    pub id_cnr: Option<String>,
+
+    pub name: Option<String>,
+    pub enabled: Option<bool>,
+
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+
+    /// Seconds without new readings after which the NoData alarm is raised, 0 disables it
+    pub max_silence: Option<i32>,
+
+    pub room_id: Option<IdType>,
+    pub map_id: Option<IdType>,
+
+    pub auto_create: Option<bool>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
+#[table_name="site_map"]
+pub struct SiteMapInput {
+    pub name: String,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
+#[table_name="room"]
+pub struct RoomInput {
+    pub name: String,
+    pub floor: Option<i32>,
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
+#[table_name="maintenance_window"]
+pub struct MaintenanceWindowInput {
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+    pub description: Option<String>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable)]
+#[table_name="site_webhook"]
+pub struct SiteWebhookInput {
+    /// http or https url that receives the POST requests
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent in the X-OldMusa-Signature header
+    pub secret: String,
+    /// Also post the changes of the site status, false by default
+    pub status_changes: Option<bool>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct EscalationPolicyInput {
+    /// Site of the alarms escalated, null for every site
+    pub site_id: Option<IdType>,
+    pub delay_minutes: i32,
+    pub target: EscalationTarget,
+    /// Users notified when the target is USERS
+    pub user_ids: Option<Vec<IdType>>,
+}
+
+/// Row of an escalation policy, a null site is saved on update too
+#[derive(Insertable, AsChangeset)]
+#[table_name="escalation_policy"]
+#[changeset_options(treat_none_as_null="true")]
+struct EscalationPolicyValues {
+    site_id: Option<IdType>,
+    delay_minutes: i32,
+    target: String,
+    user_ids: Vec<IdType>,
+}
+
+fn escalation_policy_values(data: EscalationPolicyInput) -> ServiceResult<EscalationPolicyValues> {
+    let user_ids = data.user_ids.unwrap_or_default();
+    if data.delay_minutes < 0 {
+        return Err(ServiceError::BadRequest("Negative escalation delay".to_string()))
+    }
+    if data.target == EscalationTarget::Users && user_ids.is_empty() {
+        return Err(ServiceError::BadRequest("No users to escalate to".to_string()))
+    }
+    Ok(EscalationPolicyValues {
+        site_id: data.site_id,
+        delay_minutes: data.delay_minutes,
+        target: data.target.to_char().to_string(),
+        user_ids,
+    })
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct AuditLogFilter {
+    pub user_id: Option<IdType>,
+    pub action: Option<String>,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct ReadingInput {
+    pub timestamp: NaiveDateTime,
+    pub value: f64,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct RequestLogFilter {
+    pub user_id: Option<IdType>,
+    pub site_id: Option<IdType>,
+    pub operation_name: Option<String>,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable)]
+#[table_name="sensor_calibration"]
+pub struct SensorCalibrationInput {
+    pub calibrated_at: NaiveDateTime,
+    pub certificate_id: String,
+    pub next_due_at: NaiveDateTime,
+}
+
+#[derive(juniper::GraphQLInputObject, Insertable)]
+#[table_name="reading_annotation"]
+pub struct ReadingAnnotationInput {
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+    pub reason: String,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct TtnDeviceInput {
+    pub device_id: String,
+    pub payload_format: TtnPayloadFormat,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct ModbusRegisterInput {
+    pub host: String,
+    pub port: Option<i32>,
+    pub unit_id: Option<i32>,
+    pub address: i32,
+    pub register_type: ModbusRegisterType,
+    pub value_type: ModbusValueType,
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name="modbus_register"]
+pub struct ModbusRegisterInputDb {
+    pub host: String,
+    pub port: i32,
+    pub unit_id: i32,
+    pub address: i32,
+    pub register_type: String,
+    pub value_type: String,
+    pub scale: f64,
+    pub value_offset: f64,
+}
+
+impl From<ModbusRegisterInput> for ModbusRegisterInputDb {
+    fn from(x: ModbusRegisterInput) -> ModbusRegisterInputDb {
+        ModbusRegisterInputDb {
+            host: x.host,
+            port: x.port.unwrap_or(502),
+            unit_id: x.unit_id.unwrap_or(1),
+            address: x.address,
+            register_type: x.register_type.to_char().to_string(),
+            value_type: x.value_type.to_char().to_string(),
+            scale: x.scale.unwrap_or(1.0),
+            value_offset: x.offset.unwrap_or(0.0),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct ChannelInput {
+    pub id_cnr: Option<String>,
+
+    pub name: Option<String>,
+
+    pub measure_unit: Option<String>,
+
+    pub range_min: Option<f64>,
+    pub range_max: Option<f64>,
+
+    /// 0 removes the limit
+    pub max_delta_per_hour: Option<f64>,
+
+    pub hysteresis: Option<f64>,
+    /// In seconds, 0 begins the alarms on the first reading out of range
+    pub alarm_delay: Option<i32>,
+}
+
+/// Cnr ids of a channel notified by the discovery job
+#[derive(juniper::GraphQLInputObject)]
+pub struct DiscoveredChannelInput {
+    pub sensor_id_cnr: String,
+    pub channel_id_cnr: String,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name="channel"]
+pub struct ChannelInputDb {
+    pub id_cnr: Option<String>,
+
+    pub name: Option<String>,
+
+    pub measure_unit: Option<String>,
+
+    pub range_min: Option<BigDecimal>,
+    pub range_max: Option<BigDecimal>,
+
+    pub max_delta_per_hour: Option<BigDecimal>,
+
+    pub hysteresis: Option<BigDecimal>,
+    pub alarm_delay: Option<i32>,
+}
+
+impl From<ChannelInput> for ChannelInputDb {
+    fn from(x: ChannelInput) -> ChannelInputDb {
+        ChannelInputDb {
+            id_cnr: x.id_cnr,
+            name: x.name,
+            measure_unit: x.measure_unit,
+            range_min: x.range_min.map(|p| p.into()),
+            range_max: x.range_max.map(|p| p.into()),
+            max_delta_per_hour: x.max_delta_per_hour.map(|p| p.into()),
+            hysteresis: x.hysteresis.map(|p| p.into()),
+            alarm_delay: x.alarm_delay,
+        }
+    }
+}
+
+
+#[juniper::object(
+    Context = Context
+)]
+impl MutationRoot {
+    /// Opens a session kept by the identity cookie. After some wrong passwords the username and
+    /// the address have to wait before trying again (LOGIN_THROTTLED error)
+    fn login(ctx: &Context, auth: AuthInput) -> ServiceResult<User> {
+        ctx.charge_writes(|| {
+            let user = ctx.app.auth_cache.verify_user(&ctx.app, auth.username, auth.password, ctx.client_address())?;
+
+            ctx.save_user(user.clone())?;
+            ctx.spend_request_coins("login", ctx.costs().login);
+            Ok(user)
+        })
+    }
+
+    /// Like login but returns a bearer token instead of setting the identity cookie
+    fn login_token(ctx: &Context, auth: AuthInput) -> ServiceResult<AuthToken> {
+        ctx.charge_writes(|| {
+            let user = ctx.app.auth_cache.verify_user(&ctx.app, auth.username, auth.password, ctx.client_address())?;
+            let (token, expires_at) = ctx.app.auth_cache.create_token(&user);
+
+            ctx.spend_request_coins("login", ctx.costs().login);
+            Ok(AuthToken { token, expires_at, user })
+        })
+    }
+
+    fn logout(ctx: &Context) -> bool {// Logout cannot fail
+        if let (Some(user_id), Some(session_id)) = (ctx.raw_user_id(), ctx.session_id()) {
+            if let Err(err) = ctx.app.auth_cache.revoke_sessions(&ctx.app, user_id, Some(session_id)) {
+                error!("Cannot revoke the session {}: {}", session_id, err);
+            }
+        }
+        ctx.forget_user();
+        true
+    }
+
+    /// Logs out one of the sessions of the user, returns false if it doesn't exist
+    fn revoke_session(ctx: &Context, id: IdType) -> ServiceResult<bool> {
         let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
+        let current = ctx.session_id() == Some(id);
+        let revoked = ctx.app.auth_cache.revoke_sessions(&ctx.app, user.id, Some(id))? > 0;
+        if current {
+            ctx.forget_user();
+        }
+        Ok(revoked)
+    }
+
+    /// Logs out every session of the user (the current one included), returns how many
+    /// sessions have been revoked. The bearer tokens aren't affected
+    fn revoke_all_sessions(ctx: &Context) -> ServiceResult<i32> {
+        let user = ctx.get_user_required()?;
+        let revoked = ctx.app.auth_cache.revoke_sessions(&ctx.app, user.id, None)?;
+        if ctx.session_id().is_some() {
+            ctx.forget_user();
+        }
+        Ok(revoked as i32)
+    }
+
+    /// Changes the password of the current user, the admins edit the other users (and the
+    /// usernames or permissions) through the admin schema
+    fn update_user(ctx: &Context, id: IdType, data: UserUpdateInput) -> ServiceResult<User> {
+        let user = ctx.get_user_required()?;
+        if id != user.id || data.username.is_some() || data.permission.is_some() {
+            return Err(ServiceError::Unauthorized)
+        }
+        update_user_account(ctx, id, data)
+    }
+
+    /// Gives access to the sites (or changes the access level) as a site admin, only to the sites
+    /// administered by the user. The admins use the admin schema
+    #[graphql(arguments(level(description = "Defaults to VIEW")))]
+    fn give_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>, level: Option<AccessLevel>) -> ServiceResult<bool> {
+        give_site_access(ctx, user_id, site_ids, level)
+    }
+
+    /// Revokes the access to the sites as a site admin, only to the sites administered by the user
+    fn revoke_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>) -> ServiceResult<bool> {
+        revoke_site_access(ctx, user_id, site_ids)
+    }
+
+    fn add_fcm_contact(ctx: &Context, registration_id: String) -> ServiceResult<bool> {
+        use crate::schema::fcm_user_contact::dsl;
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+
+            if registration_id.len() > 255 {
+                return Err(ServiceError::BadRequest("registration_id too long".to_owned()))
+            }
+            ctx.spend_request_coins("addFcmContact", ctx.costs().fcm_op);
+
+            let conn = ctx.get_connection()?;
+
+            diesel::insert_into(dsl::fcm_user_contact)
+                .values(FcmUserContact {
+                    registration_id,
+                    user_id: user.id,
+                })
+                .on_conflict_do_nothing()
+                .execute(&*conn)?;
+
+            Ok(true)
+        })
+    }
+
+    fn delete_fcm_contact(ctx: &Context, registration_id: String) -> ServiceResult<bool> {
+        use crate::schema::fcm_user_contact::dsl;
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+            ctx.spend_request_coins("deleteFcmContact", ctx.costs().fcm_op);
+
+            if registration_id.len() > 255 {
+                return Ok(true)// Not even going to query the db, the string cannot be present
+            }
+
+            let conn = ctx.get_connection()?;
+
+            diesel::delete(dsl::fcm_user_contact)
+                .filter(dsl::registration_id.eq(registration_id))
+                .filter(dsl::user_id.eq(user.id))
+                .execute(&*conn)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Registers an email address that will receive the alarms of the sites visible to the user,
+    /// only the verified email of the account is accepted (see setEmail)
+    fn add_email_contact(ctx: &Context, email: String) -> ServiceResult<bool> {
+        use crate::schema::email_user_contact::dsl;
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+
+            if email.len() > 255 {
+                return Err(ServiceError::BadRequest("email too long".to_owned()))
+            }
+            if lettre::EmailAddress::new(email.clone()).is_err() {
+                return Err(ServiceError::BadRequest("Invalid email".to_owned()))
+            }
+            let verified = user.email_verified_at.is_some() &&
+                user.email.as_ref().is_some_and(|x| x.to_lowercase() == email.to_lowercase());
+            if !verified {
+                return Err(ServiceError::BadRequest("Only the verified email of the account can be added".to_owned()))
+            }
+            ctx.spend_request_coins("addEmailContact", ctx.costs().email_op);
+
+            let conn = ctx.get_connection()?;
+
+            diesel::insert_into(dsl::email_user_contact)
+                .values(EmailUserContact {
+                    email,
+                    user_id: user.id,
+                })
+                .on_conflict_do_nothing()
+                .execute(&*conn)?;
+
+            Ok(true)
+        })
+    }
+
+    fn delete_email_contact(ctx: &Context, email: String) -> ServiceResult<bool> {
+        use crate::schema::email_user_contact::dsl;
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+            ctx.spend_request_coins("deleteEmailContact", ctx.costs().email_op);
+
+            if email.len() > 255 {
+                return Ok(true)// Not even going to query the db, the string cannot be present
+            }
+
+            let conn = ctx.get_connection()?;
+
+            diesel::delete(dsl::email_user_contact)
+                .filter(dsl::email.eq(email))
+                .filter(dsl::user_id.eq(user.id))
+                .execute(&*conn)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Changes the email of the current user (null removes it), a verification code is sent to
+    /// the new email and nothing else is emailed to it until it's verified with verifyEmail
+    fn set_email(ctx: &Context, email: Option<String>) -> ServiceResult<User> {
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+
+            if let Some(email) = &email {
+                if email.len() > 255 {
+                    return Err(ServiceError::BadRequest("email too long".to_owned()))
+                }
+                if lettre::EmailAddress::new(email.clone()).is_err() {
+                    return Err(ServiceError::BadRequest("Invalid email".to_owned()))
+                }
+            }
+            ctx.spend_request_coins("setEmail", ctx.costs().email_op);
+
+            let (res, token) = ctx.app.auth_cache.set_email(&ctx.app, user.id, email)?;
+            if let (Some(email), Some(token)) = (res.email.clone(), token) {
+                let data = EmailVerificationData {
+                    username: res.username.clone(),
+                    email,
+                    token,
+                };
+                ctx.email_verifications.borrow_mut().push(data);
+            }
+            Ok(res)
+        })
+    }
+
+    /// Verifies the email of the current user with the code sent to it by setEmail
+    fn verify_email(ctx: &Context, token: String) -> ServiceResult<User> {
+        ctx.charge_writes(|| {
+            ctx.check_request_balance()?;
+            let user = ctx.get_user_required()?;
+            ctx.spend_request_coins("verifyEmail", 2 * ctx.costs().db_query);
+
+            ctx.app.auth_cache.verify_email(&ctx.app, user.id, token.trim())
+        })
+    }
+
+    /// Stores a preference of the current user, a null value deletes it
+    fn set_preference(ctx: &Context, key: String, value: Option<String>) -> ServiceResult<bool> {
+        use crate::schema::user_preference::dsl;
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+
+            if key.is_empty() || key.len() > 255 {
+                return Err(ServiceError::BadRequest("Invalid preference key".to_owned()))
+            }
+            let conn = ctx.get_connection()?;
+
+            let value = match value {
+                Some(x) => x,
+                None => {
+                    diesel::delete(dsl::user_preference.find((user.id, key)))
+                        .execute(&*conn)?;
+                    return Ok(true)
+                },
+            };
+            if value.len() > USER_PREFERENCE_MAX_VALUE_LEN {
+                return Err(ServiceError::BadRequest("Preference value too long".to_owned()))
+            }
+            let count = dsl::user_preference
+                .filter(dsl::user_id.eq(user.id))
+                .filter(dsl::key.ne(&key))
+                .count()
+                .get_result::<i64>(&*conn)?;
+            if count >= USER_PREFERENCES_MAX_COUNT {
+                return Err(ServiceError::BadRequest("Too many preferences".to_owned()))
+            }
+
+            let preference = UserPreference {
+                user_id: user.id,
+                key,
+                value,
+                updated_at: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(dsl::user_preference)
+                .values(&preference)
+                .on_conflict((dsl::user_id, dsl::key))
+                .do_update()
+                .set(&preference)
+                .execute(&*conn)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Emails a summary of the site to the current user every day or week, subscribing again to
+    /// the same site and period changes the format
+    fn subscribe_site_report(ctx: &Context, site_id: IdType, period: ReportPeriod, format: ReportFormat) -> ServiceResult<ReportSubscription> {
+        use crate::schema::report_subscription::dsl;
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            user.ensure_site_visible(&ctx.app, site_id)?;
+
+            Ok(diesel::insert_into(dsl::report_subscription)
+                .values((
+                    dsl::user_id.eq(user.id),
+                    dsl::site_id.eq(site_id),
+                    dsl::period.eq(period.to_char()),
+                    dsl::format.eq(format.to_char()),
+                    dsl::created_at.eq(Utc::now().naive_utc()),
+                ))
+                .on_conflict((dsl::user_id, dsl::site_id, dsl::period))
+                .do_update()
+                .set(dsl::format.eq(format.to_char()))
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
+
+    fn unsubscribe_site_report(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::report_subscription::dsl;
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+
+            let del_count = diesel::delete(dsl::report_subscription.find(id).filter(dsl::user_id.eq(user.id)))
+                .execute(&*ctx.get_connection()?)?;
+
+            if del_count != 1 {
+                Err(ServiceError::NotFound("Report subscription".to_string()))
+            } else {
+                Ok(true)
+            }
+        })
+    }
+
+    /// Creates a new public token for the site (invalidating the old one), the token gives access
+    /// to the current conditions of the site at /api/public/site/{token}/current
+    fn regenerate_site_public_token(ctx: &Context, id: IdType) -> ServiceResult<String> {
+        use crate::schema::site_public_token::dsl;
+
+        ctx.get_user_required()?.ensure_site_level(&ctx.app, id, AccessLevel::Admin)?;
+        let conn = ctx.get_connection()?;
+
+        let token = Uuid::new_v4().to_simple().to_string();
+
+        let res = diesel::insert_into(dsl::site_public_token)
+            .values(SitePublicToken { site_id: id, token: token.clone() })
+            .on_conflict(dsl::site_id)
+            .do_update()
+            .set(dsl::token.eq(&token))
+            .execute(&*conn);
+
+        match res {
+            Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                Err(ServiceError::NotFound("Site".to_string()))
+            },
+            Err(x) => Err(x.into()),
+            Ok(_) => Ok(token),
+        }
+    }
+
+    fn revoke_site_public_token(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::site_public_token::dsl;
+
+        ctx.get_user_required()?.ensure_site_level(&ctx.app, id, AccessLevel::Admin)?;
+        let conn = ctx.get_connection()?;
+
+        let deleted = diesel::delete(dsl::site_public_token.find(id))
+            .execute(&*conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Sets the name of a site, sensor or channel in the locale (ex. "en" or "it-IT"), a null name
+    /// removes the translation
+    fn set_name_translation(ctx: &Context, target: TranslatableType, id: IdType, locale: String, name: Option<String>) -> ServiceResult<bool> {
+        use diesel::sql_types::{Integer, Text};
+
+        let user = ctx.get_user_required()?;
+        match target {
+            TranslatableType::Site => user.ensure_site_level(&ctx.app, id, AccessLevel::Admin)?,
+            TranslatableType::Sensor => user.ensure_sensor_manager(&ctx.app, id)?,
+            TranslatableType::Channel => user.ensure_channel_manager(&ctx.app, id)?,
+        }
+        if locale.is_empty() || locale.len() > 35 || !locale.chars().all(|x| x.is_ascii_alphanumeric() || x == '-') {
+            return Err(ServiceError::BadRequest("Invalid locale".to_string()))
+        }
+
+        let table = target.table_name();
+        ctx.audited("setNameTranslation", |_| format!("{} {}", table, id), || {
+            let conn = ctx.get_connection()?;
+
+            let updated = match name {
+                Some(name) => diesel::sql_query(format!(
+                    "UPDATE {} SET name_translations = name_translations || jsonb_build_object($1::text, $2::text) WHERE id = $3",
+                    table
+                )).bind::<Text, _>(&locale).bind::<Text, _>(&name).bind::<Integer, _>(id).execute(&*conn)?,
+                None => diesel::sql_query(format!(
+                    "UPDATE {} SET name_translations = name_translations - $1::text WHERE id = $2",
+                    table
+                )).bind::<Text, _>(&locale).bind::<Integer, _>(id).execute(&*conn)?,
+            };
+
+            if updated == 0 {
+                return Err(ServiceError::NotFound(format!("{:?}", target)))
+            }
+            Ok(true)
+        })
+    }
+
+    fn update_site(ctx: &Context, id: IdType, data: SiteUpdateInput) -> ServiceResult<Site> {
+        use crate::schema::site::dsl;
+
+        ctx.get_user_required()?.ensure_site_level(&ctx.app, id, AccessLevel::Admin)?;
+        ctx.audited("updateSite", |_| format!("site {}", id), || {
+            let conn = ctx.get_connection()?;
+
+            Ok(diesel::update(dsl::site.find(id))
+                .set(&data)
+                .get_result(&*conn)?)
+        })
+    }
+
+    /// Links the sensor to a TTN device (replacing the previous one), the uplinks of the device
+    /// are then stored as readings of the sensor channels
+    fn set_sensor_ttn_device(ctx: &Context, sensor_id: IdType, data: TtnDeviceInput) -> ServiceResult<TtnDevice> {
+        use crate::schema::ttn_device::dsl;
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, sensor_id)?;
+        if data.device_id.is_empty() || data.device_id.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid device id".to_string()))
+        }
+        let conn = ctx.get_connection()?;
+
+        let device = TtnDevice {
+            sensor_id,
+            device_id: data.device_id,
+            payload_format: data.payload_format.to_char().to_string(),
+        };
+
+        Ok(diesel::insert_into(dsl::ttn_device)
+            .values(&device)
+            .on_conflict(dsl::sensor_id)
+            .do_update()
+            .set(&device)
+            .get_result(&*conn)?)
+    }
+
+    fn delete_sensor_ttn_device(ctx: &Context, sensor_id: IdType) -> ServiceResult<bool> {
+        use crate::schema::ttn_device::dsl;
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, sensor_id)?;
         let conn = ctx.get_connection()?;
 
-        let is_admin =  PermissionType::from_char(user.permission.as_str()).unwrap_or(PermissionType::User) == PermissionType::Admin;
-        let ids_len = ids.len();
+        let del_count = diesel::delete(dsl::ttn_device.find(sensor_id))
+            .execute(&*conn)?;
+
+        if del_count != 1 {
+            Err(ServiceError::NotFound("TTN device".to_string()))
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Adds a map to the site, its image is then uploaded to /api/site_map/{siteId}/{id}
+    fn add_site_map(ctx: &Context, site_id: IdType, data: SiteMapInput) -> ServiceResult<SiteMap> {
+        use crate::schema::site_map::dsl;
+
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if data.name.is_empty() || data.name.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid map name".to_string()))
+        }
+
+        ctx.audited("addSiteMap", |x: &SiteMap| format!("map {} of site {}", x.id, site_id), || {
+            Ok(diesel::insert_into(dsl::site_map)
+                .values((data, dsl::site_id.eq(site_id)))
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
+
+    fn update_site_map(ctx: &Context, id: IdType, data: SiteMapInput) -> ServiceResult<SiteMap> {
+        use crate::schema::site_map::dsl;
+
+        let site_id = map_site_id(ctx, id)?;
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if data.name.is_empty() || data.name.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid map name".to_string()))
+        }
+
+        ctx.audited("updateSiteMap", |_| format!("map {} of site {}", id, site_id), || {
+            Ok(diesel::update(dsl::site_map.find(id))
+                .set(&data)
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
+
+    /// Deletes the map and its image, the sensors placed on it go back to the main map without
+    /// a location
+    fn delete_site_map(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, map_site_id(ctx, id)?)?;
+        ctx.approved(PendingActionKind::DeleteSiteMap, id, || delete_site_map_now(ctx, id))
+    }
+
+    fn add_room(ctx: &Context, site_id: IdType, data: RoomInput) -> ServiceResult<Room> {
+        use crate::schema::room::dsl;
+
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if data.name.is_empty() || data.name.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid room name".to_string()))
+        }
+
+        ctx.audited("addRoom", |x: &Room| format!("room {} of site {}", x.id, site_id), || {
+            Ok(diesel::insert_into(dsl::room)
+                .values((data, dsl::site_id.eq(site_id)))
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
+
+    fn update_room(ctx: &Context, id: IdType, data: RoomInput) -> ServiceResult<Room> {
+        use crate::schema::room::dsl;
+
+        let site_id = room_site_id(ctx, id)?;
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if data.name.is_empty() || data.name.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid room name".to_string()))
+        }
+
+        ctx.audited("updateRoom", |_| format!("room {} of site {}", id, site_id), || {
+            Ok(diesel::update(dsl::room.find(id))
+                .set(&data)
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
+
+    /// Deletes the room, its sensors are kept without a room
+    fn delete_room(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::room::dsl;
+
+        let site_id = room_site_id(ctx, id)?;
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+
+        ctx.audited("deleteRoom", |_| format!("room {} of site {}", id, site_id), || {
+            let del_count = diesel::delete(dsl::room.find(id))
+                .execute(&*ctx.get_connection()?)?;
+
+            if del_count != 1 {
+                Err(ServiceError::NotFound("Room".to_string()))
+            } else {
+                Ok(true)
+            }
+        })
+    }
+
+    fn add_sensor(ctx: &Context, site_id: IdType, data: SensorCreateInput) -> ServiceResult<Sensor> {
+        use crate::schema::sensor::dsl;
+
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+
+        ctx.audited("addSensor", |x: &Sensor| format!("sensor {}", x.id), || {
+            let auto_create = data.auto_create.unwrap_or(false);
+            if auto_create && data.id_cnr.is_none() {
+                return Err(ServiceError::BadRequest("Trying to auto-create sensor without an id_cnr".to_string()))
+            }
+
+            let conn = ctx.get_connection()?;
+            if let Some(room_id) = data.room_id {
+                ensure_room_in_site(&conn, room_id, site_id)?;
+            }
+            if let Some(map_id) = data.map_id {
+                ensure_map_in_site(&conn, map_id, site_id)?;
+            }
 
-        let channels = if is_admin {
-            channel_dsl::channel
-                .filter(channel_dsl::id.eq_any(ids))
-                .load::<Channel>(&conn)?
-        } else {
-            let channels = user_access::user_access
-                .filter(user_access::user_id.eq(user.id))
-                .inner_join(site_dsl::site.inner_join(sensor_dsl::sensor.inner_join(channel_dsl::channel)))
-                .filter(channel_dsl::id.eq_any(ids))
-                .select(CHANNEL_ALL_COLUMNS)
-                .load::<Channel>(&conn)?;
-            ctx.spend_request_coins(channels.len() as i64 * REQ_COINS_MODIFIER_DB_QUERY);
-            channels
+            let db_data = SensorUpdateInput {
+                id_cnr: data.id_cnr,
+                name: data.name,
+                enabled: data.enabled,
+                loc_x: data.loc_x,
+                loc_y: data.loc_y,
+                max_silence: data.max_silence,
+                room_id: data.room_id,
+                map_id: data.map_id,
+            };
+
+            let res = diesel::insert_into(dsl::sensor)
+                .values((db_data, dsl::site_id.eq(site_id)))
+                .get_result::<Sensor>(&*conn)?;
+
+            if auto_create {
+                use crate::schema::site::dsl as site_dsl;
+
+                let site_cnr_id: Option<String> = site_dsl::site.find(site_id)
+                    .select(site_dsl::id_cnr)
+                    .get_result(&*conn)?;
+
+                auto_create_sensor(site_cnr_id.as_deref().unwrap_or(""), res.id, res.id_cnr.as_deref().unwrap_or(""), ctx.app.tuning.discovery_sensor_readings, &conn, &ctx.app.sensor_pool)?;
+            }
+
+            Ok(res)
+        })
+    }
+
+    fn update_sensor(ctx: &Context, id: IdType, data: SensorUpdateInput) -> ServiceResult<Sensor> {
+        use crate::schema::sensor::dsl;
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, id)?;
+        ctx.audited("updateSensor", |_| format!("sensor {}", id), || {
+            let conn = ctx.get_connection()?;
+            if data.room_id.is_some() || data.map_id.is_some() {
+                let site_id = dsl::sensor.find(id)
+                    .select(dsl::site_id)
+                    .first::<IdType>(&*conn)?;
+                if let Some(room_id) = data.room_id {
+                    ensure_room_in_site(&conn, room_id, site_id)?;
+                }
+                if let Some(map_id) = data.map_id {
+                    ensure_map_in_site(&conn, map_id, site_id)?;
+                }
+            }
+
+            Ok(diesel::update(dsl::sensor.find(id))
+                .set(&data)
+                .get_result(&*conn)?)
+        })
+    }
+
+    /// Moves the sensor to the trash with its channels
+    fn delete_sensor(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+        };
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, id)?;
+        ctx.audited("deleteSensor", |_| format!("sensor {}", id), || ctx.transaction(|| {
+            let conn = ctx.get_connection()?;
+            let now = Utc::now().naive_utc();
+
+            let del_count = diesel::update(sensor_dsl::sensor.find(id).filter(sensor_dsl::deleted_at.is_null()))
+                .set(sensor_dsl::deleted_at.eq(now))
+                .execute(&*conn)?;
+            if del_count != 1 {
+                return Err(ServiceError::NotFound("Sensor".to_string()))
+            }
+
+            diesel::update(channel_dsl::channel.filter(channel_dsl::sensor_id.eq(id)))
+                .filter(channel_dsl::deleted_at.is_null())
+                .set(channel_dsl::deleted_at.eq(now))
+                .execute(&*conn)?;
+            Ok(true)
+        }))
+    }
+
+    /// Takes the sensor out of the trash with the channels deleted together with it, the site
+    /// must not be deleted
+    fn restore_sensor(ctx: &Context, id: IdType) -> ServiceResult<Sensor> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, id)?;
+        ctx.audited("restoreSensor", |_| format!("sensor {}", id), || ctx.transaction(|| {
+            let conn = ctx.get_connection()?;
+
+            let dates = sensor_dsl::sensor.find(id)
+                .inner_join(site_dsl::site)
+                .select((sensor_dsl::deleted_at, site_dsl::deleted_at))
+                .first::<(Option<NaiveDateTime>, Option<NaiveDateTime>)>(&*conn)
+                .optional()?;
+            if let Some((_, Some(_))) = dates {
+                return Err(ServiceError::BadRequest("The site of the sensor is deleted".to_string()))
+            }
+            let deleted_at = trashed_at(dates.map(|x| x.0), "Sensor")?;
+
+            diesel::update(channel_dsl::channel.filter(channel_dsl::sensor_id.eq(id)))
+                .filter(channel_dsl::deleted_at.eq(deleted_at))
+                .set(channel_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .execute(&*conn)?;
+            Ok(diesel::update(sensor_dsl::sensor.find(id))
+                .set(sensor_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .get_result::<Sensor>(&*conn)?)
+        }))
+    }
+
+    /// Moves many sensors of the site at once, either every sensor is moved or none is.
+    /// Every position must be within the image of the map the sensor is placed on
+    fn update_sensor_positions(ctx: &Context, site_id: IdType, positions: Vec<SensorPositionInput>) -> ServiceResult<Vec<Sensor>> {
+        use crate::schema::{
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+            site_map::dsl as map_dsl,
+        };
+
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        let target = format!("site {} sensors {:?}", site_id, positions.iter().map(|x| x.sensor_id).collect::<Vec<_>>());
+        ctx.audited("updateSensorPositions", |_| target, || ctx.transaction(|| {
+            let conn = ctx.get_connection()?;
+
+            let sensor_ids: HashSet<IdType> = positions.iter().map(|x| x.sensor_id).collect();
+            if sensor_ids.len() != positions.len() {
+                return Err(ServiceError::BadRequest("A sensor is moved more than once".to_string()))
+            }
+            let sensor_maps: HashMap<IdType, Option<IdType>> = sensor_dsl::sensor
+                .filter(sensor_dsl::site_id.eq(site_id))
+                .filter(sensor_dsl::id.eq_any(sensor_ids.iter().cloned().collect::<Vec<_>>()))
+                .filter(sensor_dsl::deleted_at.is_null())
+                .select((sensor_dsl::id, sensor_dsl::map_id))
+                .load::<(IdType, Option<IdType>)>(&*conn)?
+                .into_iter()
+                .collect();
+            if sensor_maps.len() != sensor_ids.len() {
+                return Err(ServiceError::NotFound("Sensor".to_string()))
+            }
+
+            // Size of the image of every map, None is the main map of the site
+            let mut map_sizes: HashMap<Option<IdType>, (Option<i32>, Option<i32>)> = map_dsl::site_map
+                .filter(map_dsl::site_id.eq(site_id))
+                .select((map_dsl::id, map_dsl::image_width, map_dsl::image_height))
+                .load::<(IdType, Option<i32>, Option<i32>)>(&*conn)?
+                .into_iter()
+                .map(|(id, width, height)| (Some(id), (width, height)))
+                .collect();
+            map_sizes.insert(None, site_dsl::site.find(site_id)
+                .select((site_dsl::image_width, site_dsl::image_height))
+                .first::<(Option<i32>, Option<i32>)>(&*conn)?);
+
+            let mut res = Vec::with_capacity(positions.len());
+            for position in positions.iter() {
+                let size = map_sizes.get(&sensor_maps[&position.sensor_id]).cloned();
+                let (width, height) = match size {
+                    Some((Some(width), Some(height))) => (width, height),
+                    _ => return Err(ServiceError::BadRequest(format!("The map of the sensor {} has no image", position.sensor_id))),
+                };
+                if position.loc_x < 0 || position.loc_x > width || position.loc_y < 0 || position.loc_y > height {
+                    return Err(ServiceError::BadRequest(format!("The sensor {} is outside of its map", position.sensor_id)))
+                }
+
+                res.push(diesel::update(sensor_dsl::sensor.find(position.sensor_id))
+                    .set((
+                        sensor_dsl::loc_x.eq(position.loc_x),
+                        sensor_dsl::loc_y.eq(position.loc_y),
+                    ))
+                    .get_result::<Sensor>(&*conn)?);
+            }
+            Ok(res)
+        }))
+    }
+
+    /// Moves the channels (with their alarm history) and the calibrations of the source sensor to
+    /// the target one and deletes the source, used to clean up sensors created twice.
+    /// The target keeps its identity, name is an optional new name for it.
+    fn merge_sensors(ctx: &Context, source_id: IdType, target_id: IdType, name: Option<String>) -> ServiceResult<Sensor> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+            sensor_calibration::dsl as calibration_dsl,
+            ttn_device::dsl as ttn_dsl,
         };
 
-        if channels.len() != ids_len {
-            return Err(ServiceError::NotFound("Channel".to_string()))
+        let user = ctx.get_user_required()?;
+        user.ensure_sensor_manager(&ctx.app, source_id)?;
+        user.ensure_sensor_manager(&ctx.app, target_id)?;
+        if source_id == target_id {
+            return Err(ServiceError::BadRequest("Cannot merge a sensor with itself".to_string()))
         }
-        Ok(channels)
+
+        let target = format!("sensor {} into {}", source_id, target_id);
+        ctx.audited("mergeSensors", |_| target, || ctx.transaction(|| {
+            let conn = ctx.get_connection()?;
+
+            let site_ids = sensor_dsl::sensor
+                .filter(sensor_dsl::id.eq_any(vec![source_id, target_id]))
+                .filter(sensor_dsl::deleted_at.is_null())
+                .select(sensor_dsl::site_id)
+                .load::<IdType>(&*conn)?;
+            if site_ids.len() != 2 {
+                return Err(ServiceError::NotFound("Sensor".to_string()))
+            }
+            if site_ids[0] != site_ids[1] {
+                return Err(ServiceError::BadRequest("Cannot merge sensors of different sites".to_string()))
+            }
+
+            diesel::update(channel_dsl::channel.filter(channel_dsl::sensor_id.eq(source_id)))
+                .set(channel_dsl::sensor_id.eq(target_id))
+                .execute(&*conn)?;
+            diesel::update(calibration_dsl::sensor_calibration.filter(calibration_dsl::sensor_id.eq(source_id)))
+                .set(calibration_dsl::sensor_id.eq(target_id))
+                .execute(&*conn)?;
+
+            // A sensor has at most one TTN device, the one of the target wins
+            let target_has_device = ttn_dsl::ttn_device.find(target_id)
+                .select(ttn_dsl::sensor_id)
+                .first::<IdType>(&*conn)
+                .optional()?
+                .is_some();
+            if !target_has_device {
+                diesel::update(ttn_dsl::ttn_device.find(source_id))
+                    .set(ttn_dsl::sensor_id.eq(target_id))
+                    .execute(&*conn)?;
+            }
+
+            diesel::delete(sensor_dsl::sensor.find(source_id))
+                .execute(&*conn)?;
+
+            let res = match name {
+                Some(name) => diesel::update(sensor_dsl::sensor.find(target_id))
+                    .set(sensor_dsl::name.eq(name))
+                    .get_result::<Sensor>(&*conn)?,
+                None => sensor_dsl::sensor.find(target_id)
+                    .get_result::<Sensor>(&*conn)?,
+            };
+            Ok(res)
+        }))
     }
 
-    fn user(ctx: &Context, id: IdType) -> ServiceResult<User> {
+    fn add_sensor_calibration(ctx: &Context, sensor_id: IdType, data: SensorCalibrationInput) -> ServiceResult<SensorCalibration> {
+        use crate::schema::sensor_calibration::dsl;
+
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, sensor_id)?;
+        if data.next_due_at < data.calibrated_at {
+            return Err(ServiceError::BadRequest("Calibration due before being done".to_string()))
+        }
+        if data.certificate_id.len() > 255 {
+            return Err(ServiceError::BadRequest("certificateId too long".to_string()))
+        }
+        let conn = ctx.get_connection()?;
+
+        Ok(diesel::insert_into(dsl::sensor_calibration)
+            .values((data, dsl::sensor_id.eq(sensor_id)))
+            .get_result(&*conn)?)
+    }
+
+    fn delete_sensor_calibration(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::sensor_calibration::dsl;
+
         let user = ctx.get_user_required()?;
+        let conn = ctx.get_connection()?;
+
+        let sensor_id = dsl::sensor_calibration.find(id)
+            .select(dsl::sensor_id)
+            .first::<IdType>(&*conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::NotFound("Sensor calibration".to_string()))?;
+        user.ensure_sensor_manager(&ctx.app, sensor_id)?;
+
+        let del_count = diesel::delete(dsl::sensor_calibration.find(id))
+            .execute(&*conn)?;
 
-        if id == user.id {
-            return Ok(user);
+        if del_count != 1 {
+            Err(ServiceError::NotFound("Sensor calibration".to_string()))
+        } else {
+            Ok(true)
         }
+    }
 
-        user.ensure_admin()?;// Only if the user didn't query himself
+    fn add_site_webhook(ctx: &Context, site_id: IdType, data: SiteWebhookInput) -> ServiceResult<SiteWebhook> {
+        use crate::schema::site_webhook::dsl;
 
-        match ctx.app.auth_cache.find_user_by_id(&ctx.app, id)? {
-            Some(user) => Ok(user),
-            None => Err(ServiceError::NotFound("User".to_string()))
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if !data.url.starts_with("http://") && !data.url.starts_with("https://") {
+            return Err(ServiceError::BadRequest("The webhook url must be http or https".to_string()))
+        }
+        if data.secret.is_empty() {
+            return Err(ServiceError::BadRequest("Empty webhook secret".to_string()))
         }
+
+        ctx.audited("addSiteWebhook", |x: &SiteWebhook| format!("webhook {} of site {}", x.id, site_id), || {
+            let conn = ctx.get_connection()?;
+            Ok(diesel::insert_into(dsl::site_webhook)
+                .values((data, dsl::site_id.eq(site_id)))
+                .get_result(&*conn)?)
+        })
     }
 
-    fn site(ctx: &Context, id: IdType) -> ServiceResult<Site> {
-        use crate::schema::site::dsl;
+    fn delete_site_webhook(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::site_webhook::dsl;
 
         let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(2 * REQ_COINS_MODIFIER_DB_QUERY);
-        user.ensure_site_visible(&ctx.app, id)?;// TODO: single query?
+        let site_id = dsl::site_webhook.find(id)
+            .select(dsl::site_id)
+            .first::<IdType>(&*ctx.get_connection()?)
+            .optional()?
+            .ok_or_else(|| ServiceError::NotFound("Site webhook".to_string()))?;
+        user.ensure_site_manager(&ctx.app, site_id)?;
+
+        ctx.audited("deleteSiteWebhook", |_| format!("webhook {} of site {}", id, site_id), || {
+            let del_count = diesel::delete(dsl::site_webhook.find(id))
+                .execute(&*ctx.get_connection()?)?;
+
+            if del_count != 1 {
+                Err(ServiceError::NotFound("Site webhook".to_string()))
+            } else {
+                Ok(true)
+            }
+        })
+    }
 
+    fn add_maintenance_window(ctx: &Context, site_id: IdType, data: MaintenanceWindowInput) -> ServiceResult<MaintenanceWindow> {
+        use crate::schema::maintenance_window::dsl;
+
+        ctx.get_user_required()?.ensure_site_manager(&ctx.app, site_id)?;
+        if data.end_time < data.start_time {
+            return Err(ServiceError::BadRequest("Maintenance ends before starting".to_string()))
+        }
         let conn = ctx.get_connection()?;
 
-        let site: Site = dsl::site.find(id)
-            .first::<Site>(&conn)
-            .optional()
-            .map_err(ServiceError::from)?
-            .ok_or_else(|| ServiceError::NotFound("Site".to_string()))?;
-        Ok(site)
+        Ok(diesel::insert_into(dsl::maintenance_window)
+            .values((data, dsl::site_id.eq(site_id)))
+            .get_result(&*conn)?)
     }
 
-    fn sensor(ctx: &Context, id: IdType) -> ServiceResult<Sensor> {
-        use crate::schema::sensor::dsl;
+    fn delete_maintenance_window(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::maintenance_window::dsl;
 
         let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(2 * REQ_COINS_MODIFIER_DB_QUERY);
-        user.ensure_sensor_visible(&ctx.app, id)?;
+        let conn = ctx.get_connection()?;
+
+        let site_id = dsl::maintenance_window.find(id)
+            .select(dsl::site_id)
+            .first::<IdType>(&*conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::NotFound("Maintenance window".to_string()))?;
+        user.ensure_site_manager(&ctx.app, site_id)?;
+
+        let del_count = diesel::delete(dsl::maintenance_window.find(id))
+            .execute(&*conn)?;
+
+        if del_count != 1 {
+            Err(ServiceError::NotFound("Maintenance window".to_string()))
+        } else {
+            Ok(true)
+        }
+    }
 
+    /// Marks the channel readings between startTime and endTime as invalid, they will be skipped
+    /// by the alarms, the exports and the statistics
+    fn add_reading_annotation(ctx: &Context, channel_id: IdType, data: ReadingAnnotationInput) -> ServiceResult<ReadingAnnotation> {
+        use crate::schema::reading_annotation::dsl;
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, channel_id)?;
+        if data.end_time < data.start_time {
+            return Err(ServiceError::BadRequest("Interval ends before starting".to_string()))
+        }
         let conn = ctx.get_connection()?;
 
-        let site: Sensor = dsl::sensor.find(id)
-            .first::<Sensor>(&conn)
-            .optional()
-            .map_err(ServiceError::from)?
-            .ok_or_else(|| ServiceError::NotFound("Sensor".to_string()))?;
-        Ok(site)
+        Ok(diesel::insert_into(dsl::reading_annotation)
+            .values((data, dsl::channel_id.eq(channel_id)))
+            .get_result(&*conn)?)
     }
 
-    fn channel(ctx: &Context, id: IdType) -> ServiceResult<Channel> {
-        use crate::schema::channel::dsl;
+    fn delete_reading_annotation(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::reading_annotation::dsl;
 
         let user = ctx.get_user_required()?;
+        let conn = ctx.get_connection()?;
 
-        ctx.check_request_balance()?;
-        ctx.spend_request_coins(2 * REQ_COINS_MODIFIER_DB_QUERY);
-        user.ensure_channel_visible(&ctx.app, id)?;
+        let channel_id = dsl::reading_annotation.find(id)
+            .select(dsl::channel_id)
+            .first::<IdType>(&*conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::NotFound("Reading annotation".to_string()))?;
+        user.ensure_channel_manager(&ctx.app, channel_id)?;
+
+        let del_count = diesel::delete(dsl::reading_annotation.find(id))
+            .execute(&*conn)?;
+
+        if del_count != 1 {
+            Err(ServiceError::NotFound("Reading annotation".to_string()))
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Adds a spot measurement taken with a handheld instrument, it's stored with the channel
+    /// readings (under the "manual" station) so that it's shown by the charts and the exports
+    fn add_manual_reading(ctx: &Context, channel_id: IdType, timestamp: NaiveDateTime, value: f64, note: Option<String>) -> ServiceResult<ManualReading> {
+        use crate::schema::channel::dsl as channel_dsl;
+        use crate::schema::manual_reading::dsl;
+
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            user.ensure_channel_visible(&ctx.app, channel_id)?;
+
+            if note.as_ref().is_some_and(|x| x.len() > 255) {
+                return Err(ServiceError::BadRequest("note too long".to_string()))
+            }
+            ctx.spend_request_coins("addManualReading", 10 * ctx.costs().db_query);
+
+            let conn = ctx.get_connection()?;
+            let channel = channel_dsl::channel.find(channel_id)
+                .first::<Channel>(&*conn)
+                .optional()?
+                .ok_or_else(|| ServiceError::NotFound("Channel".to_string()))?;
+            let (site_id, sensor_id, cnr_channel_id) = channel.query_cnr_ids(ctx)?
+                .ok_or_else(|| ServiceError::BadRequest("The channel has no cnr id".to_string()))?;
+
+            // The record is rolled back if the reading cannot be stored
+            conn.transaction::<_, ServiceError, _>(|| {
+                let res = diesel::insert_into(dsl::manual_reading)
+                    .values((
+                        dsl::channel_id.eq(channel_id),
+                        dsl::user_id.eq(user.id),
+                        dsl::taken_at.eq(timestamp),
+                        dsl::value.eq(value),
+                        dsl::note.eq(&note),
+                    ))
+                    .get_result::<ManualReading>(&*conn)?;
+
+                let readings = [NewReading {
+                    site_id,
+                    room_id: "".to_string(),
+                    station_id: MANUAL_STATION_ID.to_string(),
+                    sensor_id,
+                    channel_id: cnr_channel_id,
+                    value,
+                    measure_unit: channel.measure_unit.clone().unwrap_or_default(),
+                    date: timestamp,
+                }];
+                ctx.app.sensor_pool.insert_readings(&readings)?;
+                ctx.app.readings_cache.invalidate(&readings, Utc::now().naive_utc());
+                Ok(res)
+            })
+        })
+    }
 
+    /// Acknowledges the ongoing alarm of the channel, it won't be notified again until it ends
+    fn acknowledge_alarm(ctx: &Context, channel_id: IdType, note: Option<String>) -> ServiceResult<AlarmEvent> {
+        use crate::schema::alarm_event::dsl;
+
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            user.ensure_channel_visible(&ctx.app, channel_id)?;
+            if note.as_ref().is_some_and(|x| x.len() > 255) {
+                return Err(ServiceError::BadRequest("note too long".to_string()))
+            }
+            let conn = ctx.get_connection()?;
+
+            diesel::update(dsl::alarm_event
+                .filter(dsl::channel_id.eq(channel_id))
+                .filter(dsl::ended_at.is_null()))
+                .set((
+                    dsl::acknowledged_at.eq(Utc::now().naive_utc()),
+                    dsl::acknowledged_by.eq(user.id),
+                    dsl::acknowledge_note.eq(&note),
+                ))
+                .returning(ALARM_EVENT_ALL_COLUMNS)
+                .get_result::<AlarmEvent>(&*conn)
+                .optional()?
+                .ok_or_else(|| ServiceError::BadRequest("The channel isn't in alarm".to_string()))
+        })
+    }
+
+    /// Stops the notifications of the channel alarms until the given date, a date in the past
+    /// removes the mute
+    fn mute_channel(ctx: &Context, channel_id: IdType, until: NaiveDateTime) -> ServiceResult<Channel> {
+        use crate::schema::channel::dsl as channel_dsl;
+        use crate::schema::channel_mute::dsl;
+
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            user.ensure_channel_manager(&ctx.app, channel_id)?;
+            let conn = ctx.get_connection()?;
+            let now = Utc::now().naive_utc();
+
+            if until <= now {
+                diesel::delete(dsl::channel_mute.find(channel_id))
+                    .execute(&*conn)?;
+            } else {
+                let mute = ChannelMute {
+                    channel_id,
+                    muted_until: until,
+                    muted_by: Some(user.id),
+                    created_at: now,
+                };
+                diesel::insert_into(dsl::channel_mute)
+                    .values(&mute)
+                    .on_conflict(dsl::channel_id)
+                    .do_update()
+                    .set(&mute)
+                    .execute(&*conn)?;
+            }
+
+            Ok(channel_dsl::channel.find(channel_id)
+                .select(CHANNEL_ALL_COLUMNS)
+                .first::<Channel>(&*conn)?)
+        })
+    }
+
+    /// Stores a batch of readings of the channel (at most 1000), like the ingest endpoint does
+    /// for the api keys. Returns the number of stored readings
+    fn ingest_readings(ctx: &Context, channel_id: IdType, readings: Vec<ReadingInput>) -> ServiceResult<i32> {
+        ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            user.ensure_channel_manager(&ctx.app, channel_id)?;
+            ctx.spend_request_coins("ingestReadings", readings.len() as i64 * ctx.costs().db_query);
+
+            let readings: Vec<(NaiveDateTime, f64)> = readings.iter()
+                .map(|x| (x.timestamp, x.value))
+                .collect();
+            Ok(ingest_channel_readings(&ctx.app, channel_id, &readings)? as i32)
+        })
+    }
+
+    /// Configures the Modbus register that the server polls to acquire the channel readings,
+    /// replacing the previous one
+    fn set_channel_modbus_register(ctx: &Context, channel_id: IdType, data: ModbusRegisterInput) -> ServiceResult<ModbusRegister> {
+        use crate::schema::modbus_register::dsl;
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, channel_id)?;
+        let data: ModbusRegisterInputDb = data.into();
+        if data.host.is_empty() || data.host.len() > 255 {
+            return Err(ServiceError::BadRequest("Invalid host".to_string()))
+        }
+        if data.port < 1 || data.port > 65535 {
+            return Err(ServiceError::BadRequest("Invalid port".to_string()))
+        }
+        if data.unit_id < 0 || data.unit_id > 255 {
+            return Err(ServiceError::BadRequest("Invalid unit id".to_string()))
+        }
+        if data.address < 0 || data.address > 65535 {
+            return Err(ServiceError::BadRequest("Invalid address".to_string()))
+        }
         let conn = ctx.get_connection()?;
 
-        let site: Channel = dsl::channel.find(id)
-            .first::<Channel>(&conn)
-            .optional()
-            .map_err(ServiceError::from)?
-            .ok_or_else(|| ServiceError::NotFound("Channel".to_string()))?;
-        Ok(site)
+        Ok(diesel::insert_into(dsl::modbus_register)
+            .values((&data, dsl::channel_id.eq(channel_id)))
+            .on_conflict(dsl::channel_id)
+            .do_update()
+            .set(&data)
+            .get_result(&*conn)?)
     }
 
-    /// Guesses the cnr site ids using the readings on the database,
-    /// Admin privileges are required for this operation as it puts some stress on the database
-    fn cnr_site_ids(ctx: &Context) -> ServiceResult<Vec<String>> {
-        ctx.get_user_required()?.ensure_admin()?;
-        let conn = &ctx.app.sensor_pool;
+    fn delete_channel_modbus_register(ctx: &Context, channel_id: IdType) -> ServiceResult<bool> {
+        use crate::schema::modbus_register::dsl;
 
-        let res = conn.prep_exec("SELECT DISTINCT idsito FROM t_rilevamento_dati;", ())?;
-        let names: Vec<String> = res.map(|row| {
-            mysql::from_row::<String>(row.unwrap())
-        }).collect();
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, channel_id)?;
+        let conn = ctx.get_connection()?;
 
-        Ok(names)
+        let del_count = diesel::delete(dsl::modbus_register.find(channel_id))
+            .execute(&*conn)?;
+
+        if del_count != 1 {
+            Err(ServiceError::NotFound("Modbus register".to_string()))
+        } else {
+            Ok(true)
+        }
     }
-}
 
-pub struct MutationRoot;
+    fn add_channel(ctx: &Context, sensor_id: IdType, data: ChannelInput) -> ServiceResult<Channel> {
+        use crate::schema::channel::dsl;
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct AuthInput {
-    username: String,
-    password: String,
-}
+        ctx.get_user_required()?.ensure_sensor_manager(&ctx.app, sensor_id)?;
+        ctx.audited("addChannel", |x: &Channel| format!("channel {}", x.id), || {
+            let conn = ctx.get_connection()?;
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct UserInput {
-    username: String,
-    password: String,
-    permission: PermissionType,
-}
+            let data: ChannelInputDb = data.into();
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct UserUpdateInput {
-    username: Option<String>,
-    password: Option<String>,
-    permission: Option<PermissionType>,
-}
+            Ok(diesel::insert_into(dsl::channel)
+                .values((data, dsl::sensor_id.eq(sensor_id)))
+                .get_result(&*conn)?)
+        })
+    }
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct SiteCreateInput {
-    name: Option<String>,
-    id_cnr: Option<String>,
-    auto_create: Option<bool>,
-}
+    /// Creates the channels notified by the discovery job with their sensors, named after their
+    /// cnr ids. The sensors and the channels that already exist are reused.
+    /// It's on the main schema as the site managers run it, charged to the write quota.
+    fn create_discovered_channels(ctx: &Context, site_id: IdType, channels: Vec<DiscoveredChannelInput>) -> ServiceResult<Vec<Channel>> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            discovered_channel::dsl as discovered_dsl,
+            sensor::dsl as sensor_dsl,
+        };
 
-#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
-#[table_name="site"]
-pub struct SiteUpdateInput {
-    name: Option<String>,
-    id_cnr: Option<String>,
-}
+        let target = format!("site {} channels {}", site_id, channels.len());
+        ctx.audited("createDiscoveredChannels", |_| target, || ctx.charge_writes(|| {
+            let user = ctx.get_user_required()?;
+            ctx.check_request_balance()?;
+            user.ensure_site_manager(&ctx.app, site_id)?;
+            ctx.spend_request_coins("createDiscoveredChannels", 4 * channels.len() as i64 * ctx.costs().db_query);
 
-#[derive(juniper::GraphQLInputObject, Insertable, AsChangeset)]
-#[table_name="sensor"]
-pub struct SensorUpdateInput {
-    pub id_cnr: Option<String>,
+            ctx.transaction(|| {
+                let conn = ctx.get_connection()?;
 
-    pub name: Option<String>,
-    pub enabled: Option<bool>,
+                let mut res = Vec::with_capacity(channels.len());
+                for input in channels.iter() {
+                    let sensor_id = sensor_dsl::sensor
+                        .filter(sensor_dsl::site_id.eq(site_id))
+                        .filter(sensor_dsl::id_cnr.eq(&input.sensor_id_cnr))
+                        .filter(sensor_dsl::deleted_at.is_null())
+                        .select(sensor_dsl::id)
+                        .first::<IdType>(&*conn)
+                        .optional()?;
+                    let sensor_id = match sensor_id {
+                        Some(x) => x,
+                        None => diesel::insert_into(sensor_dsl::sensor)
+                            .values((
+                                sensor_dsl::site_id.eq(site_id),
+                                sensor_dsl::id_cnr.eq(&input.sensor_id_cnr),
+                                sensor_dsl::name.eq(&input.sensor_id_cnr),
+                            ))
+                            .returning(sensor_dsl::id)
+                            .get_result::<IdType>(&*conn)?,
+                    };
+
+                    let channel = channel_dsl::channel
+                        .filter(channel_dsl::sensor_id.eq(sensor_id))
+                        .filter(channel_dsl::id_cnr.eq(&input.channel_id_cnr))
+                        .filter(channel_dsl::deleted_at.is_null())
+                        .select(CHANNEL_ALL_COLUMNS)
+                        .first::<Channel>(&*conn)
+                        .optional()?;
+                    let channel = match channel {
+                        Some(x) => x,
+                        None => diesel::insert_into(channel_dsl::channel)
+                            .values((
+                                channel_dsl::sensor_id.eq(sensor_id),
+                                channel_dsl::id_cnr.eq(&input.channel_id_cnr),
+                                channel_dsl::name.eq(&input.channel_id_cnr),
+                            ))
+                            .get_result::<Channel>(&*conn)?,
+                    };
+
+                    diesel::delete(discovered_dsl::discovered_channel.find((site_id, &input.sensor_id_cnr, &input.channel_id_cnr)))
+                        .execute(&*conn)?;
+                    res.push(channel);
+                }
+                Ok(res)
+            })
+        }))
+    }
 
-    pub loc_x: Option<i32>,
-    pub loc_y: Option<i32>,
+    fn update_channel(ctx: &Context, id: IdType, data: ChannelInput) -> ServiceResult<Channel> {
+        use crate::schema::channel::dsl;
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, id)?;
+        ctx.audited("updateChannel", |_| format!("channel {}", id), || {
+            let conn = ctx.get_connection()?;
+
+            let data: ChannelInputDb = data.into();
+
+            Ok(diesel::update(dsl::channel.find(id))
+                .set(&data)
+                .get_result(&*conn)?)
+        })
+    }
+
+    /// Sets the channel range to the one suggested by recommendRanges
+    fn apply_range_recommendation(ctx: &Context, channel_id: IdType, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<Channel> {
+        use crate::schema::channel::dsl;
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, channel_id)?;
+        ctx.audited("applyRangeRecommendation", |_| format!("channel {}", channel_id), || {
+            let recommendation = load_range_recommendation(ctx, channel_id, start, end, None)?
+                .ok_or_else(|| ServiceError::BadRequest("Not enough readings".to_string()))?;
+            let conn = ctx.get_connection()?;
+
+            let range_min: BigDecimal = recommendation.range_min.into();
+            let range_max: BigDecimal = recommendation.range_max.into();
+            Ok(diesel::update(dsl::channel.find(channel_id))
+                .set((dsl::range_min.eq(range_min), dsl::range_max.eq(range_max)))
+                .get_result(&*conn)?)
+        })
+    }
+
+    /// Moves the channel to the trash
+    fn delete_channel(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::channel::dsl;
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, id)?;
+        ctx.audited("deleteChannel", |_| format!("channel {}", id), || {
+            let conn = ctx.get_connection()?;
+
+            let del_count = diesel::update(dsl::channel.find(id).filter(dsl::deleted_at.is_null()))
+                .set(dsl::deleted_at.eq(Utc::now().naive_utc()))
+                .execute(&*conn)?;
+
+            if del_count != 1 {
+                Err(ServiceError::NotFound("Channel".to_string()))
+            } else {
+                Ok(true)
+            }
+        })
+    }
+
+    /// Takes the channel out of the trash, the sensor must not be deleted
+    fn restore_channel(ctx: &Context, id: IdType) -> ServiceResult<Channel> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+        };
+
+        ctx.get_user_required()?.ensure_channel_manager(&ctx.app, id)?;
+        ctx.audited("restoreChannel", |_| format!("channel {}", id), || {
+            let conn = ctx.get_connection()?;
+
+            let dates = channel_dsl::channel.find(id)
+                .inner_join(sensor_dsl::sensor)
+                .select((channel_dsl::deleted_at, sensor_dsl::deleted_at))
+                .first::<(Option<NaiveDateTime>, Option<NaiveDateTime>)>(&*conn)
+                .optional()?;
+            if let Some((_, Some(_))) = dates {
+                return Err(ServiceError::BadRequest("The sensor of the channel is deleted".to_string()))
+            }
+            trashed_at(dates.map(|x| x.0), "Channel")?;
+
+            Ok(diesel::update(channel_dsl::channel.find(id))
+                .set(channel_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .get_result::<Channel>(&*conn)?)
+        })
+    }
 }
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct SensorCreateInput {
-    pub id_cnr: Option<String>,
+/// Admin operations served at /api/admin/graphql, the endpoint only accepts the admins (from the
+/// admin networks, when they're configured) so that the main schema used by the app is smaller
+pub struct AdminQueryRoot;
+
+#[juniper::object(
+    Context = Context
+)]
+impl AdminQueryRoot {
+    /// Any user (admin only)
+    fn user(ctx: &Context, id: IdType) -> ServiceResult<User> {
+        ctx.get_user_required()?.ensure_admin()?;
+
+        match ctx.app.auth_cache.find_user_by_id(&ctx.app, id)? {
+            Some(user) => Ok(user),
+            None => Err(ServiceError::NotFound("User".to_string()))
+        }
+    }
+
+    /// Every user (admin only)
+    fn users(ctx: &Context) -> ServiceResult<Vec<User>> {
+        use crate::schema::user_account::dsl::*;
+        ctx.get_user_required()?.ensure_admin()?;
 
-    pub name: Option<String>,
-    pub enabled: Option<bool>,
+        let connection = ctx.get_connection()?;
+        Ok(user_account.load::<User>(&*connection)?)
+    }
 
-    pub loc_x: Option<i32>,
-    pub loc_y: Option<i32>,
+    /// Operations done by the admins, newest first (admin only)
+    fn audit_log(ctx: &Context, filter: Option<AuditLogFilter>) -> ServiceResult<Vec<AuditLogEntry>> {
+        use crate::schema::audit_log::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
 
-    pub auto_create: Option<bool>,
-}
+        let connection = ctx.get_connection()?;
+        let mut query = dsl::audit_log.into_boxed();
+        if let Some(filter) = filter {
+            if let Some(x) = filter.user_id {
+                query = query.filter(dsl::user_id.eq(x));
+            }
+            if let Some(x) = filter.action {
+                query = query.filter(dsl::action.eq(x));
+            }
+            if let Some(x) = filter.start {
+                query = query.filter(dsl::created_at.ge(x));
+            }
+            if let Some(x) = filter.end {
+                query = query.filter(dsl::created_at.le(x));
+            }
+        }
+        Ok(query.order(dsl::id.desc())
+            .limit(AUDIT_LOG_MAX_ENTRIES)
+            .load::<AuditLogEntry>(&*connection)?)
+    }
 
-#[derive(juniper::GraphQLInputObject)]
-pub struct ChannelInput {
-    pub id_cnr: Option<String>,
+    /// Sampled GraphQL requests, newest first (admin only)
+    fn request_log(ctx: &Context, filter: Option<RequestLogFilter>) -> ServiceResult<Vec<RequestLogEntry>> {
+        use crate::schema::request_log::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
 
-    pub name: Option<String>,
+        let connection = ctx.get_connection()?;
+        let mut query = dsl::request_log.into_boxed();
+        if let Some(filter) = filter {
+            if let Some(x) = filter.user_id {
+                query = query.filter(dsl::user_id.eq(x));
+            }
+            if let Some(x) = filter.site_id {
+                query = query.filter(dsl::site_id.eq(x));
+            }
+            if let Some(x) = filter.operation_name {
+                query = query.filter(dsl::operation_name.eq(x));
+            }
+            if let Some(x) = filter.start {
+                query = query.filter(dsl::created_at.ge(x));
+            }
+            if let Some(x) = filter.end {
+                query = query.filter(dsl::created_at.le(x));
+            }
+        }
+        Ok(query.order(dsl::id.desc())
+            .limit(REQUEST_LOG_MAX_ENTRIES)
+            .load::<RequestLogEntry>(&*connection)?)
+    }
 
-    pub measure_unit: Option<String>,
+    /// Totals of the sampled GraphQL requests grouped by user and site (admin only)
+    fn request_usage(ctx: &Context, start: Option<NaiveDateTime>, end: Option<NaiveDateTime>) -> ServiceResult<Vec<RequestUsage>> {
+        ctx.get_user_required()?.ensure_admin()?;
 
-    pub range_min: Option<f64>,
-    pub range_max: Option<f64>,
-}
+        let connection = ctx.get_connection()?;
+        Ok(load_usage(&connection, start, end)?)
+    }
 
-#[derive(Insertable, AsChangeset)]
-#[table_name="channel"]
-pub struct ChannelInputDb {
-    pub id_cnr: Option<String>,
+    /// Request log statistics between start and end, to spot the heaviest users,
+    /// operations and sites (admin only)
+    fn usage_stats(ctx: &Context, start: NaiveDateTime, end: NaiveDateTime) -> ServiceResult<UsageStats> {
+        ctx.get_user_required()?.ensure_admin()?;
 
-    pub name: Option<String>,
+        let connection = ctx.get_connection()?;
+        Ok(load_usage_stats(&connection, start, end)?)
+    }
 
-    pub measure_unit: Option<String>,
+    /// Guesses the cnr site ids using the readings on the database,
+    /// Admin privileges are required for this operation as it puts some stress on the database
+    fn cnr_site_ids(ctx: &Context) -> ServiceResult<Vec<String>> {
+        ctx.get_user_required()?.ensure_admin()?;
+        let conn = &ctx.app.sensor_pool;
 
-    pub range_min: Option<BigDecimal>,
-    pub range_max: Option<BigDecimal>,
-}
+        let res = conn.prep_exec("SELECT DISTINCT idsito FROM t_rilevamento_dati;", ())?;
+        let names: Vec<String> = res.map(|row| {
+            mysql::from_row::<String>(row.unwrap())
+        }).collect();
 
-impl From<ChannelInput> for ChannelInputDb {
-    fn from(x: ChannelInput) -> ChannelInputDb {
-        ChannelInputDb {
-            id_cnr: x.id_cnr,
-            name: x.name,
-            measure_unit: x.measure_unit,
-            range_min: x.range_min.map(|p| p.into()),
-            range_max: x.range_max.map(|p| p.into()),
-        }
+        Ok(names)
     }
-}
 
+    /// Lists the sensors and channels whose cnr ids produced no readings after since (7 days ago
+    /// by default) and the cnr ids with readings that map to no channel.
+    /// Admin privileges are required as it scans every recent reading
+    fn cnr_orphans(ctx: &Context, since: Option<NaiveDateTime>) -> ServiceResult<OrphanReport> {
+        ctx.get_user_required()?.ensure_admin()?;
+        let since = since.unwrap_or_else(|| Utc::now().naive_utc() - chrono::Duration::days(7));
 
-#[juniper::object(
-    Context = Context
-)]
-impl MutationRoot {
-    // TODO: client can strain the server with loop { login, logout }
-    fn login(ctx: &Context, auth: AuthInput) -> ServiceResult<User> {
-        let user = ctx.app.auth_cache.verify_user(&ctx.app, auth.username, auth.password)?;
+        // The disabled sensors aren't expected to produce readings
+        let (sensors, channels) = load_mapped_ids(&*ctx.get_connection()?, true)?;
+        let activity = load_cnr_activity(&ctx.app.sensor_pool, since)?;
+        Ok(find_orphans(&sensors, &channels, activity))
+    }
 
-        ctx.save_user(Some(user.clone()));
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_LOGIN);
-        Ok(user)
+    /// Health of the storage and of the other resources of the server (admin only)
+    fn system_status(ctx: &Context) -> ServiceResult<SystemStatus> {
+        ctx.get_user_required()?.ensure_admin()?;
+
+        Ok(SystemStatus {
+            storage: ctx.app.site_maps.check(),
+        })
     }
 
-    fn logout(ctx: &Context) -> bool {// Logout cannot fail
-        ctx.save_user(None);
-        true
+    /// Notifications enabled and credentials stored in the database, the secret values are
+    /// never returned (admin only)
+    fn integration_status(ctx: &Context) -> ServiceResult<IntegrationStatus> {
+        ctx.get_user_required()?.ensure_admin()?;
+
+        let (fcm_enabled, email_enabled) = ctx.app.contacter.enabled_clients();
+        Ok(IntegrationStatus {
+            fcm_enabled,
+            email_enabled,
+            secrets_enabled: ctx.app.secret_box.is_some(),
+            secrets: list_secrets(&ctx.app)?,
+        })
     }
 
-    fn add_user(ctx: &Context, data: UserInput) -> ServiceResult<User> {
+    /// What changed in the schema since the previous release: added, removed and (un)deprecated
+    /// types, fields, arguments and enum values (admin only)
+    fn schema_changes(ctx: &Context) -> ServiceResult<Vec<SchemaChange>> {
         ctx.get_user_required()?.ensure_admin()?;
-        ctx.app.auth_cache.add_user(&ctx.app, data.username, data.password, data.permission)
+
+        let current = current_snapshot(&ctx.app.graphql_schema, ctx)?;
+        Ok(diff_snapshots(&previous_release_snapshot()?, &current))
     }
 
-    fn update_user(ctx: &Context, id: IdType, data: UserUpdateInput) -> ServiceResult<User> {
-        let user = ctx.get_user_required()?;
-        ctx.check_request_balance()?;
+    /// Every api key, revoked ones included (admin only)
+    fn api_keys(ctx: &Context) -> ServiceResult<Vec<ApiKey>> {
+        use crate::schema::api_key::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
 
-        if id != user.id || data.username.as_ref().is_some() || data.permission.as_ref().is_some() {
-            user.ensure_admin()?
-        }
+        let connection = ctx.get_connection()?;
+        Ok(dsl::api_key.order(dsl::id).load::<ApiKey>(&*connection)?)
+    }
 
-        let own_password_changed = id == user.id && data.password.as_ref().is_some();
-        ctx.spend_request_coins(10 * REQ_COINS_MODIFIER_DB_QUERY + if own_password_changed { REQ_COINS_MODIFIER_PASSWORD_CHANGE } else { 0 });
+    /// Destructive actions waiting for the confirmation of a second admin (admin only)
+    fn pending_actions(ctx: &Context) -> ServiceResult<Vec<PendingAction>> {
+        use crate::schema::pending_action::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
 
-        let res = ctx.app.auth_cache.update_user(&ctx.app, id, data.username, data.password, data.permission)?;
+        let connection = ctx.get_connection()?;
+        Ok(dsl::pending_action
+            .filter(dsl::expires_at.gt(Utc::now().naive_utc()))
+            .order(dsl::id)
+            .load::<PendingAction>(&*connection)?)
+    }
 
-        if own_password_changed {
-            ctx.save_user(Some(res.clone()));
-        }
+    /// Bulk exports not yet expired, newest first (admin only)
+    fn bulk_export_jobs(ctx: &Context) -> ServiceResult<Vec<BulkExportJob>> {
+        use crate::schema::bulk_export_job::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
 
-        Ok(res)
+        let connection = ctx.get_connection()?;
+        Ok(dsl::bulk_export_job.order(dsl::id.desc()).load::<BulkExportJob>(&*connection)?)
     }
 
-    fn delete_user(ctx: &Context, id: IdType) -> ServiceResult<bool> {
-        let user = ctx.get_user_required()?;
-        user.ensure_admin()?;
-        if user.id == id {
-            return Err(ServiceError::Unauthorized)// TODO: different error
-        }
-        ctx.app.auth_cache.delete_user(&ctx.app, id)?;
-        Ok(true)
+    /// Sites, sensors and channels in the trash, ordered by deletion date.
+    /// They're purged after the retention time
+    fn trash(ctx: &Context) -> ServiceResult<Trash> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
+        ctx.get_user_required()?.ensure_admin()?;
+        let conn = ctx.get_connection()?;
+
+        let sites = site_dsl::site
+            .filter(site_dsl::deleted_at.is_not_null())
+            .order((site_dsl::deleted_at, site_dsl::id))
+            .load::<Site>(&*conn)?;
+        let sensors = sensor_dsl::sensor
+            .inner_join(site_dsl::site)
+            .filter(sensor_dsl::deleted_at.is_not_null())
+            .filter(site_dsl::deleted_at.is_null())
+            .order((sensor_dsl::deleted_at, sensor_dsl::id))
+            .select(SENSOR_ALL_COLUMNS)
+            .load::<Sensor>(&*conn)?;
+        let channels = channel_dsl::channel
+            .inner_join(sensor_dsl::sensor)
+            .filter(channel_dsl::deleted_at.is_not_null())
+            .filter(sensor_dsl::deleted_at.is_null())
+            .order((channel_dsl::deleted_at, channel_dsl::id))
+            .select(CHANNEL_ALL_COLUMNS)
+            .load::<Channel>(&*conn)?;
+        Ok(Trash { sites, sensors, channels })
     }
 
-    fn give_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>) -> ServiceResult<bool> {
+    fn peer_groups(ctx: &Context) -> ServiceResult<Vec<PeerGroup>> {
+        use crate::schema::peer_group::dsl;
         ctx.get_user_required()?.ensure_admin()?;
-        for site_id in site_ids {
-            ctx.app.auth_cache.give_access(&ctx.app, user_id, site_id)?;
-        }
-        Ok(true)
+
+        let connection = ctx.get_connection()?;
+        Ok(dsl::peer_group.order(dsl::id).load::<PeerGroup>(&*connection)?)
     }
 
-    fn revoke_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>) -> ServiceResult<bool> {
+    /// Escalation policies of the unacknowledged alarms, if siteId is given only the ones that
+    /// apply to the site (admin only)
+    fn escalation_policies(ctx: &Context, site_id: Option<IdType>) -> ServiceResult<Vec<EscalationPolicy>> {
+        use crate::schema::escalation_policy::dsl;
         ctx.get_user_required()?.ensure_admin()?;
-        for site_id in site_ids {
-            ctx.app.auth_cache.revoke_access(&ctx.app, user_id, site_id)?;
+
+        let connection = ctx.get_connection()?;
+        let mut query = dsl::escalation_policy.into_boxed();
+        if let Some(site_id) = site_id {
+            query = query.filter(dsl::site_id.eq(site_id).or(dsl::site_id.is_null()));
         }
-        Ok(true)
+        Ok(query.order(dsl::id)
+            .load::<EscalationPolicy>(&*connection)?)
     }
 
-    fn add_fcm_contact(ctx: &Context, registration_id: String) -> ServiceResult<bool> {
-        use crate::schema::fcm_user_contact::dsl;
-        ctx.check_request_balance()?;
-        let user = ctx.get_user_required()?;
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_FCM_OP);
+    /// Last calibration of every sensor that must be calibrated again before the date (admin only)
+    fn calibrations_due(ctx: &Context, before: NaiveDateTime) -> ServiceResult<Vec<SensorCalibration>> {
+        ctx.get_user_required()?.ensure_admin()?;
 
-        if registration_id.len() > 255 {
-            return Err(ServiceError::BadRequest("registration_id too long".to_owned()))
-        }
+        let connection = ctx.get_connection()?;
+        Ok(latest_calibrations(&connection)?
+            .into_iter()
+            .filter(|x| x.next_due_at <= before)
+            .collect())
+    }
 
-        let conn = ctx.get_connection()?;
+    /// Suggests the range of the channel analyzing the readings between start and end (it should
+    /// span at least a year to include every season), admin only.
+    /// The readings marked as invalid are skipped unless includeInvalid is true
+    fn recommend_ranges(ctx: &Context, channel_id: IdType, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<Option<RangeRecommendation>> {
+        ctx.get_user_required()?.ensure_admin()?;
+        load_range_recommendation(ctx, channel_id, start, end, include_invalid)
+    }
+}
 
-        diesel::insert_into(dsl::fcm_user_contact)
-            .values(FcmUserContact {
-                registration_id,
-                user_id: user.id,
-            })
-            .on_conflict_do_nothing()
-            .execute(&conn)?;
+pub struct AdminMutationRoot;
 
-        Ok(true)
+#[juniper::object(
+    Context = Context
+)]
+impl AdminMutationRoot {
+    fn add_user(ctx: &Context, data: UserInput) -> ServiceResult<User> {
+        ctx.get_user_required()?.ensure_admin()?;
+        ctx.audited("addUser", |x: &User| format!("user {}", x.id), || {
+            ctx.app.auth_cache.add_user(&ctx.app, data.username, data.password, data.permission)
+        })
     }
 
-    fn delete_fcm_contact(ctx: &Context, registration_id: String) -> ServiceResult<bool> {
-        use crate::schema::fcm_user_contact::dsl;
-        ctx.check_request_balance()?;
+    fn delete_user(ctx: &Context, id: IdType) -> ServiceResult<bool> {
         let user = ctx.get_user_required()?;
-        ctx.spend_request_coins(REQ_COINS_MODIFIER_FCM_OP);
-
-        if registration_id.len() > 255 {
-            return Ok(true)// Not even going to query the db, the string cannot be present
+        user.ensure_admin()?;
+        if user.id == id {
+            return Err(ServiceError::Unauthorized)// TODO: different error
         }
+        ctx.approved(PendingActionKind::DeleteUser, id, || delete_user_now(ctx, id))
+    }
 
-        let conn = ctx.get_connection()?;
+    fn update_user(ctx: &Context, id: IdType, data: UserUpdateInput) -> ServiceResult<User> {
+        update_user_account(ctx, id, data)
+    }
 
-        diesel::delete(dsl::fcm_user_contact)
-            .filter(dsl::registration_id.eq(registration_id))
-            .filter(dsl::user_id.eq(user.id))
-            .execute(&conn)?;
+    /// Gives access to the sites (or changes the access level)
+    #[graphql(arguments(level(description = "Defaults to VIEW")))]
+    fn give_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>, level: Option<AccessLevel>) -> ServiceResult<bool> {
+        give_site_access(ctx, user_id, site_ids, level)
+    }
 
-        Ok(true)
+    fn revoke_user_access(ctx: &Context, user_id: IdType, site_ids: Vec<IdType>) -> ServiceResult<bool> {
+        revoke_site_access(ctx, user_id, site_ids)
     }
 
     #[graphql(arguments(data(description = "Initial site data")))]
@@ -957,170 +4820,301 @@ fn add_site(ctx: &Context, data: SiteCreateInput) -> ServiceResult<Site> {
 
         ctx.get_user_required()?.ensure_admin()?;
 
-        let auto_create = data.auto_create.unwrap_or(false);
-        if auto_create && data.id_cnr.is_none() {
-            return Err(ServiceError::BadRequest("Trying to auto-create site without an id_cnr".to_string()))
-        }
+        // The site isn't created if the auto-creation of its sensors fails
+        ctx.audited("addSite", |x: &Site| format!("site {}", x.id), || ctx.transaction(|| {
+            let auto_create = data.auto_create.unwrap_or(false);
+            if auto_create && data.id_cnr.is_none() {
+                return Err(ServiceError::BadRequest("Trying to auto-create site without an id_cnr".to_string()))
+            }
 
-        let conn = ctx.get_connection()?;
+            let conn = ctx.get_connection()?;
 
-        let now = Utc::now().naive_utc();
+            let now = Utc::now().naive_utc();
 
-        let db_data = SiteUpdateInput {
-            name: data.name,
-            id_cnr: data.id_cnr.clone(),
-        };
+            let db_data = SiteUpdateInput {
+                name: data.name,
+                id_cnr: data.id_cnr.clone(),
+            };
 
-        let site = diesel::insert_into(site_dsl::site)
-            .values((db_data, site_dsl::clock.eq(now)))
-            .get_result::<Site>(&conn)?;
+            let site = diesel::insert_into(site_dsl::site)
+                .values((db_data, site_dsl::clock.eq(now)))
+                .get_result::<Site>(&*conn)?;
 
-        if auto_create {
-            auto_create_site(site.id, data.id_cnr.as_deref().unwrap_or(""), &conn, &ctx.app.sensor_pool)?;
-        }
+            if auto_create {
+                auto_create_site(site.id, data.id_cnr.as_deref().unwrap_or(""), ctx.app.tuning.discovery_site_readings, &conn, &ctx.app.sensor_pool)?;
+            }
 
-        Ok(site)
+            Ok(site)
+        }))
     }
 
-    fn update_site(ctx: &Context, id: IdType, data: SiteUpdateInput) -> ServiceResult<Site> {
-        use crate::schema::site::dsl;
-
+    /// Creates a new site from the JSON given by exportSiteConfig, either everything is created
+    /// or nothing is. The map images have to be uploaded again
+    fn import_site_config(ctx: &Context, config: String) -> ServiceResult<Site> {
         ctx.get_user_required()?.ensure_admin()?;
-        let conn = ctx.get_connection()?;
+        let config: SiteConfig = serde_json::from_str(&config)
+            .map_err(|x| ServiceError::BadRequest(format!("Invalid site config: {}", x)))?;
 
-        Ok(diesel::update(dsl::site.find(id))
-            .set(&data)
-            .get_result(&conn)?)
+        ctx.audited("importSiteConfig", |x: &Site| format!("site {}", x.id), || ctx.transaction(|| {
+            import_site_config(&*ctx.get_connection()?, &config, Utc::now().naive_utc())
+        }))
     }
 
+    /// Moves the site to the trash with its sensors and channels, it can be restored until it's
+    /// purged
     #[graphql(arguments(id(description = "Id of the site to delete")))]
     fn delete_site(ctx: &Context, id: IdType) -> ServiceResult<bool> {
-        use crate::schema::site::dsl;
+        ctx.get_user_required()?.ensure_admin()?;
+        ctx.approved(PendingActionKind::DeleteSite, id, || delete_site_now(ctx, id))
+    }
+
+    /// Takes the site out of the trash with the sensors and channels deleted together with it
+    fn restore_site(ctx: &Context, id: IdType) -> ServiceResult<Site> {
+        use crate::schema::{
+            channel::dsl as channel_dsl,
+            sensor::dsl as sensor_dsl,
+            site::dsl as site_dsl,
+        };
 
         ctx.get_user_required()?.ensure_admin()?;
-        let conn = ctx.get_connection()?;
+        ctx.audited("restoreSite", |_| format!("site {}", id), || ctx.transaction(|| {
+            let conn = ctx.get_connection()?;
+
+            let deleted_at = site_dsl::site.find(id)
+                .select(site_dsl::deleted_at)
+                .first::<Option<NaiveDateTime>>(&*conn)
+                .optional()?;
+            let deleted_at = trashed_at(deleted_at, "Site")?;
+
+            let sensor_ids = sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(id)).select(sensor_dsl::id);
+            diesel::update(channel_dsl::channel.filter(channel_dsl::sensor_id.eq_any(sensor_ids)))
+                .filter(channel_dsl::deleted_at.eq(deleted_at))
+                .set(channel_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .execute(&*conn)?;
+            diesel::update(sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(id)))
+                .filter(sensor_dsl::deleted_at.eq(deleted_at))
+                .set(sensor_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .execute(&*conn)?;
+            Ok(diesel::update(site_dsl::site.find(id))
+                .set(site_dsl::deleted_at.eq(None::<NaiveDateTime>))
+                .get_result::<Site>(&*conn)?)
+        }))
+    }
 
-        let del_count = diesel::delete(dsl::site.find(id))
-            .execute(&conn)?;
+    /// Runs a destructive action requested by another admin, it's discarded once it succeeds
+    fn confirm_pending_action(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        let user = ctx.get_user_required()?;
+        user.ensure_admin()?;
 
-        if del_count != 1 {
-            return Err(ServiceError::NotFound("Site".to_string()))
-        }
+        let (kind, target_id) = find_pending_action(&*ctx.get_connection()?, id, user.id, Utc::now().naive_utc())?;
+        ctx.audited("confirmPendingAction", |_| format!("pending action {} {} {}", id, kind.name(), target_id), || {
+            let res = match kind {
+                PendingActionKind::DeleteSite => delete_site_now(ctx, target_id)?,
+                PendingActionKind::DeleteUser => delete_user_now(ctx, target_id)?,
+                PendingActionKind::DeleteSiteMap => delete_site_map_now(ctx, target_id)?,
+            };
+            // Deleting the user that requested it also discards the action
+            discard_pending_action(&*ctx.get_connection()?, id)?;
+            Ok(res)
+        })
+    }
 
-        // Delete site image
-        let image_path = match get_file_from_site(id) {
-            Ok(x) => x,
-            Err(e) => return Err(ServiceError::InternalServerError(e.to_string())),
-        };
-        if image_path.exists() {
-            fs::remove_file(image_path)
-                .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
-        }
+    fn cancel_pending_action(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        ctx.get_user_required()?.ensure_admin()?;
+        ctx.audited("cancelPendingAction", |_| format!("pending action {}", id), || {
+            if !discard_pending_action(&*ctx.get_connection()?, id)? {
+                return Err(ServiceError::NotFound("Pending action".to_string()))
+            }
+            Ok(true)
+        })
+    }
 
-        Ok(true)
+    /// Exports every channel of the sites between start and end as a zip of CSV files (one per
+    /// channel), written in the background. The admin is notified with the download link when
+    /// it's ready (admin only)
+    #[graphql(arguments(include_invalid(description = "Also export the readings marked as invalid, defaults to false")))]
+    fn start_bulk_export(ctx: &Context, site_ids: Vec<IdType>, start: NaiveDateTime, end: NaiveDateTime, include_invalid: Option<bool>) -> ServiceResult<BulkExportJob> {
+        let user = ctx.get_user_required()?;
+        user.ensure_admin()?;
+        let target = format!("sites {:?} from {} to {}", site_ids, start, end);
+        ctx.audited("startBulkExport", |_| target, || {
+            start_bulk_export(&ctx.app, BulkExportRequest {
+                user_id: user.id,
+                site_ids,
+                start,
+                end,
+                include_invalid: include_invalid.unwrap_or(false),
+            })
+        })
     }
 
-    fn add_sensor(ctx: &Context, site_id: IdType, data: SensorCreateInput) -> ServiceResult<Sensor> {
-        use crate::schema::sensor::dsl;
+    /// Creates a read-only api key, the key is only returned here
+    fn create_api_key(ctx: &Context, data: ApiKeyInput) -> ServiceResult<CreatedApiKey> {
+        use crate::schema::api_key::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
+        ctx.audited("createApiKey", |x: &CreatedApiKey| format!("api key {}", x.api_key.id), || {
+            let conn = ctx.get_connection()?;
+            let key = format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple());
+
+            let res = diesel::insert_into(dsl::api_key)
+                .values((
+                    dsl::name.eq(data.name),
+                    dsl::key_hash.eq(hash_api_key(&key)),
+                    dsl::site_id.eq(data.site_id),
+                    dsl::created_at.eq(Utc::now().naive_utc()),
+                    dsl::can_ingest.eq(data.can_ingest.unwrap_or(false)),
+                ))
+                .get_result::<ApiKey>(&*conn);
+
+            match res {
+                Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                    Err(ServiceError::NotFound("Site".to_string()))
+                },
+                Err(x) => Err(x.into()),
+                Ok(api_key) => Ok(CreatedApiKey { key, api_key }),
+            }
+        })
+    }
 
-        let auto_create = data.auto_create.unwrap_or(false);
-        if auto_create && data.id_cnr.is_none() {
-            return Err(ServiceError::BadRequest("Trying to auto-create sensor without an id_cnr".to_string()))
-        }
+    /// Revokes the api key, returns false if it was already revoked
+    fn revoke_api_key(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::api_key::dsl;
 
-        let conn = ctx.get_connection()?;
+        ctx.get_user_required()?.ensure_admin()?;
+        ctx.audited("revokeApiKey", |_| format!("api key {}", id), || {
+            let conn = ctx.get_connection()?;
+            let revoked = diesel::update(dsl::api_key.find(id).filter(dsl::revoked_at.is_null()))
+                .set(dsl::revoked_at.eq(Utc::now().naive_utc()))
+                .execute(&*conn)?;
+            Ok(revoked > 0)
+        })
+    }
 
-        let db_data = SensorUpdateInput {
-            id_cnr: data.id_cnr,
-            name: data.name,
-            enabled: data.enabled,
-            loc_x: data.loc_x,
-            loc_y: data.loc_y,
-        };
+    /// Stores the credential encrypted and applies it to the notifications right away,
+    /// it replaces the matching environment variable (admin only)
+    fn set_integration_secret(ctx: &Context, name: IntegrationSecretName, value: String) -> ServiceResult<bool> {
+        ctx.get_user_required()?.ensure_admin()?;
+        if value.is_empty() {
+            return Err(ServiceError::BadRequest("Empty secret".to_string()))
+        }
+        ctx.audited("setIntegrationSecret", |_| format!("secret {}", name.to_key()), || {
+            store_secret(&ctx.app, name, &value)?;
+            apply_secrets(&ctx.app)?;
+            Ok(true)
+        })
+    }
+
+    /// Deletes the stored credential, the environment one is used again.
+    /// Returns false if it wasn't stored (admin only)
+    fn delete_integration_secret(ctx: &Context, name: IntegrationSecretName) -> ServiceResult<bool> {
+        ctx.get_user_required()?.ensure_admin()?;
+        ctx.audited("deleteIntegrationSecret", |_| format!("secret {}", name.to_key()), || {
+            let deleted = delete_secret(&ctx.app, name)?;
+            apply_secrets(&ctx.app)?;
+            Ok(deleted)
+        })
+    }
 
-        let res = diesel::insert_into(dsl::sensor)
-            .values((db_data, dsl::site_id.eq(site_id)))
-            .get_result::<Sensor>(&conn)?;
+    fn add_escalation_policy(ctx: &Context, data: EscalationPolicyInput) -> ServiceResult<EscalationPolicy> {
+        use crate::schema::escalation_policy::dsl;
 
-        if auto_create {
-            use crate::schema::site::dsl as site_dsl;
+        ctx.get_user_required()?.ensure_admin()?;
+        let values = escalation_policy_values(data)?;
 
-            let site_cnr_id: Option<String> = site_dsl::site.find(site_id)
-                .select(site_dsl::id_cnr)
-                .get_result(&conn)?;
+        ctx.audited("addEscalationPolicy", |x: &EscalationPolicy| format!("escalation policy {}", x.id), || {
+            Ok(diesel::insert_into(dsl::escalation_policy)
+                .values(values)
+                .get_result(&*ctx.get_connection()?)?)
+        })
+    }
 
-            auto_create_sensor(site_cnr_id.as_deref().unwrap_or(""), res.id, res.id_cnr.as_deref().unwrap_or(""), &conn, &ctx.app.sensor_pool)?;
-        }
+    fn update_escalation_policy(ctx: &Context, id: IdType, data: EscalationPolicyInput) -> ServiceResult<EscalationPolicy> {
+        use crate::schema::escalation_policy::dsl;
 
-        Ok(res)
+        ctx.get_user_required()?.ensure_admin()?;
+        let values = escalation_policy_values(data)?;
+
+        ctx.audited("updateEscalationPolicy", |_| format!("escalation policy {}", id), || {
+            diesel::update(dsl::escalation_policy.find(id))
+                .set(values)
+                .get_result(&*ctx.get_connection()?)
+                .optional()?
+                .ok_or_else(|| ServiceError::NotFound("Escalation policy".to_string()))
+        })
     }
 
-    fn update_sensor(ctx: &Context, id: IdType, data: SensorUpdateInput) -> ServiceResult<Sensor> {
-        use crate::schema::sensor::dsl;
+    fn delete_escalation_policy(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::escalation_policy::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
-        let conn = ctx.get_connection()?;
 
-        Ok(diesel::update(dsl::sensor.find(id))
-            .set(&data)
-            .get_result(&conn)?)
+        ctx.audited("deleteEscalationPolicy", |_| format!("escalation policy {}", id), || {
+            let del_count = diesel::delete(dsl::escalation_policy.find(id))
+                .execute(&*ctx.get_connection()?)?;
+
+            if del_count != 1 {
+                Err(ServiceError::NotFound("Escalation policy".to_string()))
+            } else {
+                Ok(true)
+            }
+        })
     }
 
-    fn delete_sensor(ctx: &Context, id: IdType) -> ServiceResult<bool> {
-        use crate::schema::sensor::dsl;
+    fn add_peer_group(ctx: &Context, name: String) -> ServiceResult<PeerGroup> {
+        use crate::schema::peer_group::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
+        if name.len() > 255 {
+            return Err(ServiceError::BadRequest("name too long".to_string()))
+        }
         let conn = ctx.get_connection()?;
 
-        let del_count = diesel::delete(dsl::sensor.find(id))
-            .execute(&conn)?;
-
-        if del_count != 1 {
-            Err(ServiceError::NotFound("Sensor".to_string()))
-        } else {
-            Ok(true)
-        }
+        Ok(diesel::insert_into(dsl::peer_group)
+            .values(dsl::name.eq(name))
+            .get_result(&*conn)?)
     }
 
-    fn add_channel(ctx: &Context, sensor_id: IdType, data: ChannelInput) -> ServiceResult<Channel> {
-        use crate::schema::channel::dsl;
+    fn delete_peer_group(ctx: &Context, id: IdType) -> ServiceResult<bool> {
+        use crate::schema::peer_group::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
         let conn = ctx.get_connection()?;
 
-        let data: ChannelInputDb = data.into();
+        let del_count = diesel::delete(dsl::peer_group.find(id))
+            .execute(&*conn)?;
 
-        Ok(diesel::insert_into(dsl::channel)
-            .values((data, dsl::sensor_id.eq(sensor_id)))
-            .get_result(&conn)?)
+        if del_count != 1 {
+            Err(ServiceError::NotFound("Peer group".to_string()))
+        } else {
+            Ok(true)
+        }
     }
 
-    fn update_channel(ctx: &Context, id: IdType, data: ChannelInput) -> ServiceResult<Channel> {
-        use crate::schema::channel::dsl;
+    fn add_peer_group_channel(ctx: &Context, peer_group_id: IdType, channel_id: IdType) -> ServiceResult<bool> {
+        use crate::schema::peer_group_channel::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
         let conn = ctx.get_connection()?;
 
-        let data: ChannelInputDb = data.into();
-
-        Ok(diesel::update(dsl::channel.find(id))
-            .set(&data)
-            .get_result(&conn)?)
+        diesel::insert_into(dsl::peer_group_channel)
+            .values(PeerGroupChannel { peer_group_id, channel_id })
+            .on_conflict_do_nothing()
+            .execute(&*conn)?;
+        Ok(true)
     }
 
-    fn delete_channel(ctx: &Context, id: IdType) -> ServiceResult<bool> {
-        use crate::schema::channel::dsl;
+    fn remove_peer_group_channel(ctx: &Context, peer_group_id: IdType, channel_id: IdType) -> ServiceResult<bool> {
+        use crate::schema::peer_group_channel::dsl;
 
         ctx.get_user_required()?.ensure_admin()?;
         let conn = ctx.get_connection()?;
 
-        let del_count = diesel::delete(dsl::channel.find(id))
-            .execute(&conn)?;
+        let del_count = diesel::delete(dsl::peer_group_channel.find((peer_group_id, channel_id)))
+            .execute(&*conn)?;
 
         if del_count != 1 {
-            Err(ServiceError::NotFound("Channel".to_string()))
+            Err(ServiceError::NotFound("Peer group channel".to_string()))
         } else {
             Ok(true)
         }
@@ -1129,6 +5123,12 @@ fn delete_channel(ctx: &Context, id: IdType) -> ServiceResult<bool> {
 
 pub type Schema = RootNode<'static, QueryRoot, MutationRoot>;
 
+pub type AdminSchema = RootNode<'static, AdminQueryRoot, AdminMutationRoot>;
+
 pub fn create_schema() -> Schema {
     Schema::new(QueryRoot {}, MutationRoot {})
 }
+
+pub fn create_admin_schema() -> AdminSchema {
+    AdminSchema::new(AdminQueryRoot {}, AdminMutationRoot {})
+}
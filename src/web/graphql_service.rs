@@ -1,35 +1,245 @@
 use actix_identity::Identity;
-use actix_web::{Error, http::PathAndQuery, http::Uri, HttpRequest, HttpResponse, web};
+use actix_web::{Error, http::header, http::PathAndQuery, http::Uri, HttpRequest, HttpResponse, web};
 use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
+use log::{debug, error, Level, log_enabled};
+use serde::Deserialize;
+use serde_json::json;
 
 use crate::AppData;
+use crate::contact::QuotaWarningData;
+use crate::models::IdType;
+use crate::quota::QuotaPool;
+use crate::redact::redact_json;
+use crate::security::{PermissionCheckable, SessionClient};
 
+use super::access_monitor::{AccessSource, report_failures};
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::errors::{ServiceError, ServiceResult};
+use super::graphql_cache::{body_etag, etag_matches, operation_kind, OperationKind, query_hash};
 use super::graphql_schema;
+use super::request_log::{record_request, RequestStats};
 use std::time::Instant;
 
 pub async fn graphql(
     ctx: web::Data<AppData>,
+    req: HttpRequest,
     identity: Identity,
     data: web::Json<GraphQLRequest>,
 ) -> Result<HttpResponse, Error> {
+    let (body, _, retry_after) = execute_graphql(ctx, &req, &identity, data.into_inner(), Surface::Main).await?;
+
+    let mut res = HttpResponse::Ok();
+    if let Some(secs) = retry_after {
+        res.header(header::RETRY_AFTER, secs.to_string());
+    }
+    Ok(res.content_type("application/json")
+        .body(serde_json::to_string(&body)?))
+}
+
+/// Runs a request of the admin schema, only with POST as the admin responses are never cached
+pub async fn admin_graphql(
+    ctx: web::Data<AppData>,
+    req: HttpRequest,
+    identity: Identity,
+    data: web::Json<GraphQLRequest>,
+) -> Result<HttpResponse, Error> {
+    let (body, _, _) = execute_graphql(ctx, &req, &identity, data.into_inner(), Surface::Admin).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&body)?))
+}
+
+#[derive(Deserialize)]
+pub struct GraphQLGetQuery {
+    query: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    /// JSON encoded variables
+    variables: Option<String>,
+    /// JSON encoded extensions, only `persistedQuery.sha256Hash` is used
+    extensions: Option<String>,
+}
+
+/// Runs a GraphQL query sent with GET, see the graphql_cache module
+pub async fn graphql_get(
+    ctx: web::Data<AppData>,
+    req: HttpRequest,
+    identity: Identity,
+    params: web::Query<GraphQLGetQuery>,
+) -> Result<HttpResponse, Error> {
+    let params = params.into_inner();
+    let parse_json = |name: &str, value: Option<String>| value
+        .map(|x| serde_json::from_str::<serde_json::Value>(&x))
+        .transpose()
+        .map_err(|_| ServiceError::BadRequest(format!("Invalid {}", name)));
+    let variables = parse_json("variables", params.variables)?;
+    let extensions = parse_json("extensions", params.extensions)?;
+    let hash = match extensions.as_ref().map(|x| &x["persistedQuery"]["sha256Hash"]) {
+        Some(serde_json::Value::String(x)) => Some(x.to_lowercase()),
+        Some(serde_json::Value::Null) | None => None,
+        Some(_) => return Err(ServiceError::BadRequest("Invalid persisted query hash".to_string()).into()),
+    };
+
+    let cache = &ctx.graphql_cache;
+    let query = match (params.query, hash) {
+        (Some(query), Some(hash)) => {
+            if query_hash(&query) != hash {
+                return Err(ServiceError::BadRequest("The persisted query hash doesn't match the query".to_string()).into())
+            }
+            cache.persist_query(hash, query.clone());
+            query
+        },
+        (Some(query), None) => query,
+        (None, Some(hash)) => match cache.persisted_query(&hash) {
+            Some(x) => x,
+            None => return Ok(HttpResponse::Ok()
+                .header("Cache-Control", "no-store")
+                .json(json!({
+                    "errors": [{ "message": "PersistedQueryNotFound", "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" } }]
+                }))),
+        },
+        (None, None) => return Err(ServiceError::BadRequest("Missing query".to_string()).into()),
+    };
+
+    if operation_kind(&query, params.operation_name.as_deref()) != Some(OperationKind::Query) {
+        return Err(ServiceError::BadRequest("Only queries can be sent with GET".to_string()).into())
+    }
+
+    let variables = variables.map(serde_json::from_value)
+        .transpose()
+        .map_err(|_| ServiceError::BadRequest("Invalid variables".to_string()))?;
+    let data = GraphQLRequest::new(query, params.operation_name, variables);
+    let max_age = cache.config.max_age;
+    let (body, anonymous, retry_after) = execute_graphql(ctx, &req, &identity, data, Surface::Main).await?;
+    let has_errors = body.get("errors").is_some();
+    let body = serde_json::to_string(&body)?;
+
+    if has_errors {
+        let mut res = HttpResponse::Ok();
+        if let Some(secs) = retry_after {
+            res.header(header::RETRY_AFTER, secs.to_string());
+        }
+        return Ok(res.header("Cache-Control", "no-store")
+            .content_type("application/json")
+            .body(body))
+    }
+
+    // The responses change with the credentials, the shared caches must not mix them up
+    let cache_control = if anonymous {
+        format!("public, max-age={}", max_age)
+    } else {
+        "private, no-cache".to_string()
+    };
+    let etag = body_etag(body.as_bytes());
+    let not_modified = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .map_or(false, |x| etag_matches(x, &etag));
+
+    let mut res = if not_modified { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+    res.header("Cache-Control", cache_control)
+        .header(header::ETAG, etag)
+        .header(header::VARY, "Cookie, Authorization, X-Api-Key");
+    if not_modified {
+        return Ok(res.finish())
+    }
+    Ok(res.content_type("application/json").body(body))
+}
+
+/// Schema that runs the request
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Surface {
+    Main,
+    /// Only the admins (from the admin networks) can use it
+    Admin,
+}
+
+/// Executes the GraphQL request with the quota and the identity of the requester, returns the
+/// response, whether the request was anonymous and the seconds to wait before retrying if the
+/// quota rejected a resolver
+async fn execute_graphql(
+    ctx: web::Data<AppData>,
+    req: &HttpRequest,
+    identity: &Identity,
+    data: GraphQLRequest,
+    surface: Surface,
+) -> Result<(serde_json::Value, bool, Option<u64>), Error> {
     let original_identity = identity.identity();
-    let user = original_identity.as_ref()
-        .and_then(|x| ctx.auth_cache.parse_identity(&ctx, x).transpose())
-        .transpose()?;
+    let auth = Authenticator::new(req, original_identity.clone());
+    let anonymous = !auth.is_present();
+    let auth_ctx = ctx.clone();
+    let user = run_blocking(move || auth.user(&auth_ctx)).await?;
 
-    let req_quota = if let (Some(bank), Some(user)) = (&ctx.quota_bank, &user) {
-        bank.get_quota_balance(Instant::now(), user.id)
+    let get_quota = |pool: QuotaPool| if let (Some(bank), Some(user)) = (&ctx.quota_bank, &user) {
+        bank.pool(pool).get_quota_balance(Instant::now(), user.id)
     } else {
         i64::max_value()
     };
+    let req_read_quota = get_quota(QuotaPool::Read);
+    let req_write_quota = get_quota(QuotaPool::Write);
+
+    let mut access_sources = Vec::new();
+    if let Some(user) = &user {
+        access_sources.push((AccessSource::User(user.id), format!("user {}", user.username)));
+    }
+    if let Some(addr) = req.peer_addr() {
+        access_sources.push((AccessSource::Address(addr.ip()), format!("address {}", addr.ip())));
+    }
+    let now = Instant::now();
+    if access_sources.iter().any(|(source, _)| ctx.access_monitor.is_throttled(now, *source)) {
+        return Err(ServiceError::TooManyRequests.into())
+    }
+    if surface == Surface::Admin {
+        // Rejected before running the request, the other users can't even read the admin schema
+        if !ctx.admin_network.as_ref().map_or(true, |x| x.allows(req)) {
+            return Err(ServiceError::Unauthorized.into())
+        }
+        user.as_ref().ok_or(ServiceError::LoginRequired)?.ensure_admin()?;
+    }
+
+    let req_ctx = graphql_schema::Context::new(ctx.into_inner(), original_identity.clone(), user, req_read_quota, req_write_quota);
+    req_ctx.set_client(SessionClient {
+        user_agent: req.headers().get(header::USER_AGENT).and_then(|x| x.to_str().ok()).map(|x| x.to_string()),
+        address: req.peer_addr().map(|x| x.ip()),
+    });
 
-    let req_ctx = graphql_schema::Context::new(ctx.into_inner(), original_identity.clone(), user, req_quota);
+    if log_enabled!(Level::Debug) {
+        let variables = serde_json::to_value(&data).map(|x| redact_json(&x["variables"])).unwrap_or_default();
+        debug!("GraphQL request {}: {}", data.operation_name().unwrap_or("<unnamed>"), variables);
+    }
 
-    let (body, context) = web::block(move || {
-        let res = data.execute(&req_ctx.app.graphql_schema, &req_ctx);
-        Ok::<_, serde_json::error::Error>((serde_json::to_string(&res)?, req_ctx))
+    let (mut body, failures, context) = web::block(move || {
+        let start = Instant::now();
+        let res = match surface {
+            Surface::Main => data.execute(&req_ctx.app.graphql_schema, &req_ctx),
+            Surface::Admin => data.execute(&req_ctx.app.admin_graphql_schema, &req_ctx),
+        };
+        record_request(&req_ctx.app, RequestStats {
+            user_id: req_ctx.raw_user_id(),
+            site_id: req_ctx.site_id(),
+            operation_name: data.operation_name(),
+            complexity: req_ctx.spent_coins(),
+            row_count: req_ctx.row_count(),
+            duration_ms: start.elapsed().as_millis().min(i32::max_value() as u128) as i32,
+            quota_rejected: req_ctx.quota_rejected(),
+        });
+        let res = serde_json::to_value(&res)?;
+        let failures = access_failures(&res);
+        Ok::<_, serde_json::error::Error>((res, failures, req_ctx))
     }).await?;
 
+    report_failures(&context.app, &access_sources, failures);
+
+    for data in context.take_email_verifications() {
+        let contacter = context.app.contacter.clone();
+        actix_rt::spawn(async move {
+            if let Err(err) = contacter.send_email_verification(&data).await {
+                error!("Cannot send the verification email to {}: {}", data.username, err);
+            }
+        });
+    }
+
     let new_identity = context.identity.replace(Some(String::new()));
     if new_identity != original_identity {
         match new_identity {
@@ -38,24 +248,143 @@ pub async fn graphql(
         }
     }
 
-    let final_coins = context.get_quota_coins();
-    if req_quota != final_coins {
-        if let (Some(bank), Some(user)) = (&context.app.quota_bank, context.raw_user_id()) {
-            let coin_diff = final_coins - req_quota;
-            bank.add_quota_balance(Instant::now(), user, coin_diff)
+    if let (Some(bank), Some(user)) = (&context.app.quota_bank, context.raw_user_id()) {
+        let now = Instant::now();
+        for (pool, operation, coins) in context.take_spending() {
+            bank.pool(pool).record_spending(now, user, operation, coins);
         }
     }
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .body(body))
+    let mut warnings = Vec::new();
+    for (pool, req_quota) in [(QuotaPool::Read, req_read_quota), (QuotaPool::Write, req_write_quota)].iter() {
+        let final_coins = context.get_quota_coins(*pool);
+        if *req_quota != final_coins {
+            if let (Some(bank), Some(user)) = (&context.app.quota_bank, context.raw_user_id()) {
+                let coin_diff = final_coins - req_quota;
+                bank.pool(*pool).add_quota_balance(Instant::now(), user, coin_diff)
+            }
+        }
+        if let Some(user) = context.raw_user_id() {
+            warnings.extend(quota_warning(&context.app, user, *pool, *req_quota, final_coins));
+        }
+    }
+    if !warnings.is_empty() {
+        body["extensions"]["quotaWarnings"] = json!(warnings);
+    }
+
+    let retry_after = context.rejected_pool().and_then(|pool| quota_retry_after(&context.app, context.raw_user_id()?, pool));
+    if let Some(secs) = retry_after {
+        body["extensions"]["retryAfter"] = json!(secs);
+    }
+
+    Ok((body, anonymous, retry_after))
+}
+
+/// Seconds before the pool has enough coins for a new request, rounded up so that the client
+/// doesn't retry too early. None if the pool is never refilled.
+pub(crate) fn quota_retry_after(app: &AppData, user_id: IdType, pool: QuotaPool) -> Option<u64> {
+    let wait = app.quota_bank.as_ref()?.pool(pool).get_quota_wait(Instant::now(), user_id, 1)?;
+    Some((wait.as_millis() as u64 + 999) / 1000)
 }
 
-pub fn graphiql(request: HttpRequest) -> HttpResponse {
+/// Warning added to the response when the user spent most of the quota of the pool, the push
+/// notification is only sent by the request that crosses the threshold
+fn quota_warning(app: &AppData, user_id: IdType, pool: QuotaPool, req_quota: i64, final_coins: i64) -> Option<serde_json::Value> {
+    let pool_data = app.quota_bank.as_ref()?.pool(pool);
+    let max_balance = pool_data.max_balance();
+    let threshold = app.quota_warning.threshold(max_balance)?;
+    if final_coins >= threshold {
+        return None
+    }
+
+    let pool_name = match pool {
+        QuotaPool::Read => "READ",
+        QuotaPool::Write => "WRITE",
+    };
+    if app.quota_warning.push && req_quota >= threshold {
+        let data = QuotaWarningData {
+            user_id,
+            pool: pool_name.to_string(),
+            balance: final_coins,
+            max_balance,
+        };
+        let contacter = app.contacter.clone();
+        match app.pool.get() {
+            Ok(connection) => {
+                actix_rt::spawn(async move {
+                    if let Err(err) = contacter.send_quota_warning(&connection, &data).await {
+                        error!("Error sending quota warning: {}", err);
+                    }
+                });
+            },
+            Err(err) => error!("Error in connection pool: {}", err),
+        }
+    }
+
+    let refill_rate = pool_data.balance_per_second() as i64;
+    Some(json!({
+        "pool": pool_name,
+        "balance": final_coins,
+        "maxBalance": max_balance,
+        // Seconds before the balance is back above the warning threshold
+        "recoverAfter": if refill_rate == 0 { 0 } else { (threshold - final_coins + refill_rate - 1) / refill_rate },
+    }))
+}
+
+/// Number of errors caused by a resource that doesn't exist or can't be accessed
+fn access_failures(response: &serde_json::Value) -> usize {
+    response["errors"].as_array().into_iter().flatten()
+        .filter(|x| matches!(x["extensions"]["type"].as_str(), Some("UNAUTHORIZED") | Some("NOT_FOUND")))
+        .count()
+}
+
+/// First channel (with its site) that the playground can use as example, in development mode
+fn load_example_ids(app: &AppData) -> ServiceResult<Option<(IdType, IdType)>> {
+    use diesel::prelude::*;
+    use crate::schema::{channel::dsl as channel_dsl, sensor::dsl as sensor_dsl, site::dsl as site_dsl};
+
+    let conn = app.pool.get()?;
+    Ok(channel_dsl::channel
+        .inner_join(sensor_dsl::sensor.inner_join(site_dsl::site))
+        .filter(channel_dsl::deleted_at.is_null())
+        .filter(sensor_dsl::deleted_at.is_null())
+        .filter(site_dsl::deleted_at.is_null())
+        .order(channel_dsl::id)
+        .select((site_dsl::id, channel_dsl::id))
+        .first::<(IdType, IdType)>(&conn)
+        .optional()?)
+}
+
+fn example_query(site_id: IdType, channel_id: IdType) -> String {
+    format!(
+        "# Development mode, the ids below exist in this database\n\
+         query Examples {{\n  site(id: {}) {{\n    id\n    name\n    sensors {{ id name channels {{ id name }} }}\n  }}\n  \
+         sampleReadings(channelId: {}, n: 5) {{ date valueMin valueAvg valueMax }}\n}}\n",
+        site_id, channel_id
+    )
+}
+
+pub async fn graphiql(ctx: web::Data<AppData>, request: HttpRequest) -> HttpResponse {
     let mut orig = request.uri().clone().into_parts();
     orig.path_and_query = Some(PathAndQuery::from_static("/api/graphql"));
     let uri = Uri::from_parts(orig).expect("Cannot build URI");
-    let html = graphiql_source(&uri.to_string());
+    let mut html = graphiql_source(&uri.to_string());
+
+    if ctx.dev_mode {
+        let app = ctx.clone();
+        match run_blocking(move || load_example_ids(&app)).await {
+            Ok(Some((site_id, channel_id))) => {
+                // The query is a JS string literal, "</" would close the script tag
+                let query = serde_json::to_string(&example_query(site_id, channel_id))
+                    .unwrap_or_default()
+                    .replace("</", "<\\/");
+                html = html.replacen("fetcher: graphQLFetcher,", &format!("fetcher: graphQLFetcher, defaultQuery: {},", query), 1);
+            },
+            Ok(None) => {},
+            Err(e) => error!("Cannot load the playground examples: {}", e),
+        }
+    }
+
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(html)
@@ -0,0 +1,87 @@
+//! Probes for the orchestrators: /healthz only tells that the process answers, /readyz also checks
+//! the databases so that a server whose connection pool is exhausted (or whose database went away)
+//! is restarted instead of failing every request.
+
+use std::time::Duration;
+
+use actix_web::{HttpResponse, web};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::AppData;
+
+use super::blocking::run_blocking;
+
+/// Time given to each database to hand out a connection
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    fn from_result<E: ToString>(res: Result<(), E>) -> Self {
+        match res {
+            Ok(()) => CheckResult { ok: true, error: None },
+            Err(e) => CheckResult { ok: false, error: Some(e.to_string()) },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub postgres: CheckResult,
+    pub sensor_database: CheckResult,
+    /// Only informative, the push notifications can be disabled on purpose
+    pub fcm_enabled: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.postgres.ok && self.sensor_database.ok
+    }
+}
+
+fn check_postgres(app: &AppData) -> Result<(), String> {
+    let conn = app.pool.get_timeout(READY_CHECK_TIMEOUT).map_err(|x| x.to_string())?;
+    diesel::sql_query("SELECT 1").execute(&conn).map_err(|x| x.to_string())?;
+    Ok(())
+}
+
+fn check_sensor_database(app: &AppData) -> Result<(), String> {
+    let mut conn = app.sensor_pool.try_get_conn(READY_CHECK_TIMEOUT.as_millis() as u32)
+        .map_err(|x| x.to_string())?;
+    conn.query("SELECT 1").map_err(|x| x.to_string())?;
+    Ok(())
+}
+
+pub fn check_readiness(app: &AppData) -> Readiness {
+    Readiness {
+        postgres: CheckResult::from_result(check_postgres(app)),
+        sensor_database: CheckResult::from_result(check_sensor_database(app)),
+        fcm_enabled: app.contacter.enabled_clients().0,
+    }
+}
+
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok()
+        .header("Cache-Control", "no-store")
+        .body("ok")
+}
+
+pub async fn readyz(ctx: web::Data<AppData>) -> HttpResponse {
+    let readiness = run_blocking(move || Ok(check_readiness(&ctx))).await;
+
+    match readiness {
+        Ok(readiness) => {
+            let mut res = if readiness.is_ready() { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+            res.header("Cache-Control", "no-store").json(readiness)
+        },
+        Err(e) => HttpResponse::ServiceUnavailable()
+            .header("Cache-Control", "no-store")
+            .body(e.to_string()),
+    }
+}
@@ -0,0 +1,127 @@
+//! Attributes of the auth cookie set by the identity middleware.
+//!
+//! The defaults keep the cookie usable over plain HTTP on the same site, a deploy behind HTTPS
+//! should set `COOKIE_SECURE=true` and a dashboard embedded by another site also needs
+//! `COOKIE_SAME_SITE=none` (browsers drop the SameSite=None cookies that aren't secure).
+
+use actix_identity::CookieIdentityPolicy;
+use actix_web::cookie::SameSite;
+use serde::Deserialize;
+
+use crate::config::Resolver;
+
+pub const AUTH_COOKIE_NAME: &str = "auth-cookie";
+
+#[derive(Clone, Debug)]
+pub struct IdentityCookieConfig {
+    /// None to bind the cookie to the host of the request
+    pub domain: Option<String>,
+    pub path: String,
+    /// Only sent over HTTPS
+    pub secure: bool,
+    /// None leaves the choice to the browser
+    pub same_site: Option<SameSite>,
+    /// Lifetime of the cookie in seconds, None to drop it when the browser is closed
+    pub max_age: Option<i64>,
+}
+
+impl Default for IdentityCookieConfig {
+    fn default() -> Self {
+        IdentityCookieConfig {
+            domain: None,
+            path: "/".to_string(),
+            secure: false,
+            same_site: None,
+            max_age: None,
+        }
+    }
+}
+
+/// `[cookie]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityCookieSection {
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: Option<bool>,
+    pub same_site: Option<String>,
+    pub max_age_secs: Option<i64>,
+}
+
+pub fn parse_same_site(name: &str) -> Option<SameSite> {
+    match name.to_lowercase().as_str() {
+        "strict" => Some(SameSite::Strict),
+        "lax" => Some(SameSite::Lax),
+        "none" => Some(SameSite::None),
+        _ => None,
+    }
+}
+
+impl IdentityCookieConfig {
+    /// Reads DOMAIN (localhost by default), COOKIE_PATH, COOKIE_SECURE, COOKIE_SAME_SITE (strict,
+    /// lax or none) and COOKIE_MAX_AGE_SECS, the cookie is always secure if the server terminates
+    /// TLS itself.
+    pub fn resolve(r: &mut Resolver, file: IdentityCookieSection, tls: bool) -> Self {
+        let default = IdentityCookieConfig::default();
+        let config = IdentityCookieConfig {
+            domain: Some(r.optional("DOMAIN", file.domain).unwrap_or_else(|| "localhost".to_string())),
+            path: r.optional("COOKIE_PATH", file.path).unwrap_or(default.path),
+            secure: tls || r.optional("COOKIE_SECURE", file.secure).unwrap_or(default.secure),
+            same_site: r.parsed("COOKIE_SAME_SITE", file.same_site, |x| {
+                parse_same_site(x).ok_or_else(|| format!("unknown value {}, it should be strict, lax or none", x))
+            }),
+            max_age: r.optional("COOKIE_MAX_AGE_SECS", file.max_age_secs),
+        };
+        if let Err(e) = config.validate() {
+            r.error(e);
+        }
+        config
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err("COOKIE_SAME_SITE=none requires COOKIE_SECURE=true".to_string())
+        }
+        Ok(())
+    }
+
+    /// Builds the identity policy, it can't be shared between the workers so every one builds its own
+    pub fn policy(&self, key: &[u8]) -> CookieIdentityPolicy {
+        let mut policy = CookieIdentityPolicy::new(key)
+            .name(AUTH_COOKIE_NAME)
+            .path(self.path.as_str())
+            .secure(self.secure);
+        if let Some(domain) = &self.domain {
+            policy = policy.domain(domain.as_str());
+        }
+        if let Some(same_site) = self.same_site {
+            policy = policy.same_site(same_site);
+        }
+        if let Some(max_age) = self.max_age {
+            policy = policy.max_age(max_age);
+        }
+        policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_same_site() {
+        assert_eq!(parse_same_site("Strict"), Some(SameSite::Strict));
+        assert_eq!(parse_same_site("lax"), Some(SameSite::Lax));
+        assert_eq!(parse_same_site("none"), Some(SameSite::None));
+        assert_eq!(parse_same_site("always"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(IdentityCookieConfig::default().validate().is_ok());
+        let insecure = IdentityCookieConfig { same_site: Some(SameSite::None), ..Default::default() };
+        assert!(insecure.validate().is_err());
+        let secure = IdentityCookieConfig { secure: true, ..insecure };
+        assert!(secure.validate().is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+//! Direct ingestion of the readings, for the sensors that can push their data to the server
+//! without passing through the CNR acquisition chain.
+//!
+//! The readings are sent in batches to `/api/ingest/readings` using an api key that has been
+//! created with `canIngest`, they are stored in the readings store like the TTN and Modbus ones.
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+use crate::AppData;
+use crate::alarm::{NewReading, ReadingsWriter};
+use crate::models::IdType;
+use crate::security::PermissionCheckable;
+
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::db_helper::{load_live_channel, query_channel_cnr_ids};
+use super::errors::{ServiceError, ServiceResult};
+
+/// Station id used for the pushed readings
+const INGEST_STATION_ID: &str = "ingest";
+/// Maximum number of readings of a single batch
+pub const INGEST_MAX_READINGS: usize = 1000;
+
+#[derive(Deserialize)]
+struct IngestReading {
+    timestamp: DateTime<Utc>,
+    value: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IngestBatch {
+    channel_id: IdType,
+    readings: Vec<IngestReading>,
+}
+
+/// Stores the readings (timestamp, value) of the channel, the caller must have already checked
+/// that it can write them.
+/// Returns the number of stored readings.
+pub fn ingest_channel_readings(ctx: &AppData, channel_id: IdType, readings: &[(NaiveDateTime, f64)]) -> ServiceResult<usize> {
+    if readings.len() > INGEST_MAX_READINGS {
+        return Err(ServiceError::BadRequest(format!("Too many readings, the maximum is {}", INGEST_MAX_READINGS)))
+    }
+    if readings.iter().any(|(_, value)| !value.is_finite()) {
+        return Err(ServiceError::BadRequest("Invalid reading value".to_string()))
+    }
+
+    let channel = load_live_channel(&*ctx.pool.get()?, channel_id)?;
+
+    let (site_id, sensor_id, cnr_channel_id) = query_channel_cnr_ids(&ctx.pool, channel.id, channel.id_cnr.as_deref())?
+        .ok_or_else(|| ServiceError::BadRequest("The channel has no cnr id".to_string()))?;
+    let measure_unit = channel.measure_unit.unwrap_or_default();
+
+    let readings: Vec<NewReading> = readings.iter()
+        .map(|(date, value)| NewReading {
+            site_id: site_id.clone(),
+            room_id: "".to_string(),
+            station_id: INGEST_STATION_ID.to_string(),
+            sensor_id: sensor_id.clone(),
+            channel_id: cnr_channel_id.clone(),
+            value: *value,
+            measure_unit: measure_unit.clone(),
+            date: *date,
+        })
+        .collect();
+
+    ctx.sensor_pool.insert_readings(&readings)?;
+    ctx.readings_cache.invalidate(&readings, Utc::now().naive_utc());
+    Ok(readings.len())
+}
+
+fn ingest_batch(ctx: &AppData, auth: &Authenticator, batch: IngestBatch) -> ServiceResult<usize> {
+    let api_key = auth.api_key(ctx)?
+        .filter(|x| x.can_ingest)
+        .ok_or_else(|| ctx.access_policy.forbidden())?;
+    api_key.ensure_channel_visible(ctx, batch.channel_id)?;
+
+    let readings: Vec<(NaiveDateTime, f64)> = batch.readings.iter()
+        .map(|x| (x.timestamp.naive_utc(), x.value))
+        .collect();
+    ingest_channel_readings(ctx, batch.channel_id, &readings)
+}
+
+pub async fn ingest_readings(
+    ctx: web::Data<AppData>,
+    auth: Authenticator,
+    body: web::Bytes,
+) -> ServiceResult<HttpResponse> {
+    // A full batch doesn't fit the default json limit
+    let batch: IngestBatch = serde_json::from_slice(&body)
+        .map_err(|x| ServiceError::BadRequest(x.to_string()))?;
+
+    let count = run_blocking(move || ingest_batch(&ctx, &auth, batch)).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "stored": count })))
+}
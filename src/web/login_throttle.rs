@@ -0,0 +1,226 @@
+//! Throttling of the password guesses, both per username (a distributed attack on an account) and
+//! per peer address (an attack on many accounts).
+//!
+//! After `LOGIN_THROTTLE_FREE_FAILURES` wrong passwords (default 3) every new attempt of the source
+//! has to wait an exponential delay, from `LOGIN_THROTTLE_BASE_DELAY_SECS` (default 1) up to
+//! `LOGIN_THROTTLE_MAX_DELAY_SECS` (default 60). After `LOGIN_LOCKOUT_FAILURES` wrong passwords
+//! (default 10) the source is locked out for `LOGIN_LOCKOUT_SECS` (default 900).
+//! The failures are forgotten after `LOGIN_THROTTLE_RESET_SECS` (default 3600) without failures,
+//! the ones of the username also after a successful login (the address ones are kept, or an
+//! attacker could reset them logging in its own account between the guesses).
+//! Every setting can also be given in the `[login_throttle]` section of the configuration file.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::Resolver;
+
+/// Sources remembered at most, the ones without recent failures are evicted first.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LoginSource {
+    /// The usernames are compared ignoring the case, the guesses shouldn't get more attempts by
+    /// changing it
+    Username(String),
+    Address(IpAddr),
+}
+
+impl LoginSource {
+    pub fn username(username: &str) -> Self {
+        LoginSource::Username(username.to_lowercase())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginThrottleConfig {
+    /// Wrong passwords accepted without delay
+    pub free_failures: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Wrong passwords after which the source is locked out, 0 disables the lockout
+    pub lockout_failures: u32,
+    pub lockout: Duration,
+    /// The failures are forgotten after this time without new ones
+    pub reset_after: Duration,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        LoginThrottleConfig {
+            free_failures: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            lockout_failures: 10,
+            lockout: Duration::from_secs(15 * 60),
+            reset_after: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// `[login_throttle]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginThrottleSection {
+    pub free_failures: Option<u32>,
+    pub base_delay_secs: Option<u64>,
+    pub max_delay_secs: Option<u64>,
+    pub lockout_failures: Option<u32>,
+    pub lockout_secs: Option<u64>,
+    pub reset_secs: Option<u64>,
+}
+
+impl LoginThrottleConfig {
+    pub fn resolve(r: &mut Resolver, file: LoginThrottleSection) -> Self {
+        let default = LoginThrottleConfig::default();
+        let secs = |x: Option<u64>, default: Duration| x.map_or(default, Duration::from_secs);
+
+        LoginThrottleConfig {
+            free_failures: r.optional("LOGIN_THROTTLE_FREE_FAILURES", file.free_failures).unwrap_or(default.free_failures),
+            base_delay: secs(r.optional("LOGIN_THROTTLE_BASE_DELAY_SECS", file.base_delay_secs), default.base_delay),
+            max_delay: secs(r.optional("LOGIN_THROTTLE_MAX_DELAY_SECS", file.max_delay_secs), default.max_delay),
+            lockout_failures: r.optional("LOGIN_LOCKOUT_FAILURES", file.lockout_failures).unwrap_or(default.lockout_failures),
+            lockout: secs(r.optional("LOGIN_LOCKOUT_SECS", file.lockout_secs), default.lockout),
+            reset_after: secs(r.optional("LOGIN_THROTTLE_RESET_SECS", file.reset_secs), default.reset_after),
+        }
+    }
+
+    /// Delay imposed after the failures, zero while they're free
+    fn delay(&self, failures: u32) -> Duration {
+        if failures < self.free_failures {
+            return Duration::from_secs(0)
+        }
+        let exponent = (failures - self.free_failures).min(31);
+        self.base_delay.checked_mul(1 << exponent)
+            .map_or(self.max_delay, |x| x.min(self.max_delay))
+    }
+}
+
+struct SourceState {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub struct LoginThrottle {
+    pub config: LoginThrottleConfig,
+    sources: Arc<Mutex<HashMap<LoginSource, SourceState>>>,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        LoginThrottle::new(LoginThrottleConfig::default())
+    }
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginThrottleConfig) -> Self {
+        LoginThrottle {
+            config,
+            sources: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns how long the sources have to wait before trying again, None if they can try now
+    pub fn wait_time(&self, now: Instant, sources: &[LoginSource]) -> Option<Duration> {
+        let states = self.sources.lock().unwrap();
+        sources.iter()
+            .filter_map(|x| states.get(x))
+            .filter(|x| now.duration_since(x.last_failure) < self.config.reset_after)
+            .filter_map(|x| {
+                let until = x.locked_until.unwrap_or(x.last_failure + self.config.delay(x.failures));
+                until.checked_duration_since(now).filter(|x| *x > Duration::from_secs(0))
+            })
+            .max()
+    }
+
+    /// Records a wrong password of the sources
+    pub fn record_failure(&self, now: Instant, sources: &[LoginSource]) {
+        let mut states = self.sources.lock().unwrap();
+        if states.len() >= MAX_TRACKED_SOURCES {
+            let reset_after = self.config.reset_after;
+            states.retain(|_, x| now.duration_since(x.last_failure) < reset_after);
+        }
+
+        for source in sources {
+            let state = states.entry(source.clone()).or_insert(SourceState {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if now.duration_since(state.last_failure) >= self.config.reset_after ||
+                    state.locked_until.is_some_and(|x| x <= now) {
+                state.failures = 0;
+                state.locked_until = None;
+            }
+            state.failures += 1;
+            state.last_failure = now;
+            if self.config.lockout_failures > 0 && state.failures >= self.config.lockout_failures {
+                state.locked_until = Some(now + self.config.lockout);
+            }
+        }
+    }
+
+    /// Forgets the failures of the usernames in the sources, called after a successful login.
+    /// The failures of the addresses only expire with the reset time
+    pub fn record_success(&self, sources: &[LoginSource]) {
+        let mut states = self.sources.lock().unwrap();
+        for source in sources.iter().filter(|x| matches!(x, LoginSource::Username(_))) {
+            states.remove(source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_throttle() {
+        let throttle = LoginThrottle::new(LoginThrottleConfig {
+            free_failures: 2,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(4),
+            lockout_failures: 6,
+            lockout: Duration::from_secs(60),
+            reset_after: Duration::from_secs(3600),
+        });
+        let user = [LoginSource::username("Mario"), LoginSource::Address("10.0.0.1".parse().unwrap())];
+        let other = [LoginSource::username("luigi"), LoginSource::Address("10.0.0.2".parse().unwrap())];
+        let start = Instant::now();
+        let secs = |x: u64| start + Duration::from_secs(x);
+
+        throttle.record_failure(start, &user);
+        assert_eq!(throttle.wait_time(start, &user), None);
+        throttle.record_failure(start, &user);
+        assert_eq!(throttle.wait_time(start, &user), Some(Duration::from_secs(1)));
+        // The username is throttled whatever the address and the case
+        assert_eq!(throttle.wait_time(start, &[LoginSource::username("MARIO")]), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.wait_time(start, &other), None);
+
+        // The delay doubles until the max
+        throttle.record_failure(secs(1), &user);
+        assert_eq!(throttle.wait_time(secs(1), &user), Some(Duration::from_secs(2)));
+        throttle.record_failure(secs(3), &user);
+        throttle.record_failure(secs(7), &user);
+        assert_eq!(throttle.wait_time(secs(7), &user), Some(Duration::from_secs(4)));
+
+        // Locked out
+        throttle.record_failure(secs(11), &user);
+        assert_eq!(throttle.wait_time(secs(11), &user), Some(Duration::from_secs(60)));
+        assert_eq!(throttle.wait_time(secs(71), &user), None);
+
+        throttle.record_failure(start, &other);
+        throttle.record_failure(start, &other);
+        throttle.record_success(&other);
+        assert_eq!(throttle.wait_time(start, &other[..1]), None);
+        // Logging in doesn't reset the guesses from the address
+        assert_eq!(throttle.wait_time(start, &other), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.wait_time(secs(3600), &other), None);
+    }
+}
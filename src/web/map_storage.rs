@@ -0,0 +1,379 @@
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::{StreamExt, TryStreamExt};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_core::credential::StaticProvider;
+use rusoto_s3::{DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest, PutObjectRequest, S3, S3Client};
+use serde::Deserialize;
+
+use crate::config::Resolver;
+
+/// Where the site map images are kept, the keys are the ones given by
+/// site_map_service::get_file_from_site and get_file_from_site_map.
+/// Every method blocks: the async handlers must call them inside web::block.
+pub trait MapStorage: Send + Sync {
+    /// Prepares the storage at startup (ex. creates the directory)
+    fn setup(&self) -> Result<(), String>;
+
+    /// None if the image doesn't exist
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+
+    fn write(&self, key: &str, data: Vec<u8>) -> Result<(), String>;
+
+    /// Returns false if the image didn't exist
+    fn delete(&self, key: &str) -> Result<bool, String>;
+
+    fn exists(&self, key: &str) -> Result<bool, String>;
+
+    /// Checks that the images can be written and that there's enough space left
+    fn check(&self) -> StorageStatus;
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageStatus {
+    /// Location of the images (a directory or a bucket url)
+    pub directory: String,
+    pub writable: bool,
+    /// Space available to the server, None if it can't be read or if the storage has no limit
+    pub free_bytes: Option<u64>,
+    pub min_free_bytes: u64,
+    pub error: Option<String>,
+}
+
+impl StorageStatus {
+    pub fn healthy(&self) -> bool {
+        self.writable && self.error.is_none() && self.free_bytes.map_or(true, |x| x >= self.min_free_bytes)
+    }
+}
+
+/// `[site_maps]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapStorageSection {
+    /// fs (the default) or s3
+    pub storage: Option<String>,
+    pub directory: Option<PathBuf>,
+    pub min_free_mb: Option<u64>,
+    pub s3: S3Section,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Section {
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub prefix: Option<String>,
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub enum MapStorageConfig {
+    Filesystem(FilesystemStorage),
+    S3(S3Config),
+}
+
+impl MapStorageConfig {
+    /// Reads the backend from SITE_MAPS_STORAGE ("fs", the default, or "s3")
+    pub fn resolve(r: &mut Resolver, file: MapStorageSection) -> Self {
+        match r.optional::<String>("SITE_MAPS_STORAGE", file.storage).as_deref() {
+            None | Some("fs") => {},
+            Some("s3") => if let Some(config) = S3Config::resolve(r, file.s3) {
+                return MapStorageConfig::S3(config)
+            },
+            Some(x) => r.error(format!("Unknown SITE_MAPS_STORAGE {}, it should be fs or s3", x)),
+        }
+
+        let default = FilesystemStorage::default();
+        MapStorageConfig::Filesystem(FilesystemStorage {
+            directory: r.optional("SITE_MAPS_DIR", file.directory).unwrap_or(default.directory),
+            min_free_bytes: r.optional("SITE_MAPS_MIN_FREE_MB", file.min_free_mb).map_or(default.min_free_bytes, |x| x * 1024 * 1024),
+        })
+    }
+}
+
+pub fn open(config: MapStorageConfig) -> Arc<dyn MapStorage> {
+    match config {
+        MapStorageConfig::Filesystem(x) => Arc::new(x),
+        MapStorageConfig::S3(x) => Arc::new(S3Storage::new(x)),
+    }
+}
+
+/// Stores the images in a local directory, only usable with a single replica of the server
+#[derive(Clone, Debug)]
+pub struct FilesystemStorage {
+    pub directory: PathBuf,
+    /// The storage is reported as unhealthy when the free space drops below this
+    pub min_free_bytes: u64,
+}
+
+impl Default for FilesystemStorage {
+    fn default() -> Self {
+        FilesystemStorage {
+            directory: PathBuf::from("site_maps"),
+            min_free_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl MapStorage for FilesystemStorage {
+    fn setup(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|x| format!("Cannot create {}: {}", self.directory.display(), x))
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match fs::read(self.directory.join(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn write(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        fs::write(self.directory.join(key), data).map_err(|x| x.to_string())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, String> {
+        match fs::remove_file(self.directory.join(key)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, String> {
+        Ok(self.directory.join(key).exists())
+    }
+
+    /// Checks that the directory exists, that it's writable and that it has enough free space
+    fn check(&self) -> StorageStatus {
+        let mut status = StorageStatus {
+            directory: self.directory.display().to_string(),
+            writable: false,
+            free_bytes: None,
+            min_free_bytes: self.min_free_bytes,
+            error: None,
+        };
+        if !self.directory.is_dir() {
+            status.error = Some("The directory doesn't exist".to_string());
+            return status
+        }
+
+        let probe = self.directory.join(".write_check");
+        match fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+            Ok(()) => status.writable = true,
+            Err(err) => status.error = Some(format!("The directory isn't writable: {}", err)),
+        }
+        match nix::sys::statvfs::statvfs(&self.directory) {
+            Ok(stat) => status.free_bytes = Some(stat.blocks_available() * stat.fragment_size()),
+            Err(err) => status.error = status.error.or_else(|| Some(format!("Cannot read the free space: {}", err))),
+        }
+        status
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Url of the S3-compatible service (ex. https://s3.eu-south-1.amazonaws.com or a MinIO server)
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to the keys of the images, so that the bucket can be shared
+    pub prefix: String,
+    pub timeout: Duration,
+}
+
+impl S3Config {
+    /// None if a required setting is missing, the error has already been reported
+    fn resolve(r: &mut Resolver, file: S3Section) -> Option<Self> {
+        let endpoint = r.required("SITE_MAPS_S3_ENDPOINT", "site_maps.s3.endpoint", file.endpoint);
+        let bucket = r.required("SITE_MAPS_S3_BUCKET", "site_maps.s3.bucket", file.bucket);
+        let access_key = r.required("SITE_MAPS_S3_ACCESS_KEY", "site_maps.s3.access_key", file.access_key);
+        let secret_key = r.required("SITE_MAPS_S3_SECRET_KEY", "site_maps.s3.secret_key", file.secret_key);
+        let region = r.optional("SITE_MAPS_S3_REGION", file.region).unwrap_or_else(|| "us-east-1".to_string());
+        let prefix = r.optional("SITE_MAPS_S3_PREFIX", file.prefix).unwrap_or_else(|| "site_maps/".to_string());
+        let timeout = r.optional("SITE_MAPS_S3_TIMEOUT_SECS", file.timeout_secs).unwrap_or(30);
+
+        Some(S3Config {
+            endpoint: endpoint?.trim_end_matches('/').to_string(),
+            bucket: bucket?,
+            region,
+            access_key: access_key?,
+            secret_key: secret_key?,
+            prefix,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+type S3Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Extra time given to the storage thread over the request timeout before giving up on it
+const S3_REPLY_MARGIN: Duration = Duration::from_secs(5);
+
+/// Stores the images in a S3-compatible bucket (path-style addressing), so that every replica of
+/// the server sees the same maps.
+/// The requests run concurrently in a dedicated thread with its own runtime so that they can
+/// also be sent from the blocking code (the GraphQL resolvers).
+pub struct S3Storage {
+    config: S3Config,
+    client: S3Client,
+    jobs: mpsc::UnboundedSender<S3Job>,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        let region = Region::Custom {
+            name: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let credentials = StaticProvider::new_minimal(config.access_key.clone(), config.secret_key.clone());
+        let http_client = HttpClient::new().expect("Cannot create the map storage client");
+        let client = S3Client::new_with(http_client, credentials, region);
+
+        let (sender, mut receiver) = mpsc::unbounded::<S3Job>();
+        thread::Builder::new()
+            .name("map-storage".to_string())
+            .spawn(move || {
+                let mut runtime = actix_rt::Runtime::new().expect("Cannot start the map storage runtime");
+                runtime.block_on(async move {
+                    while let Some(job) = receiver.next().await {
+                        actix_rt::spawn(job);
+                    }
+                });
+            })
+            .expect("Cannot start the map storage thread");
+
+        S3Storage {
+            config,
+            client,
+            jobs: sender,
+        }
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        format!("{}{}", self.config.prefix, key)
+    }
+
+    /// Runs the request in the storage thread, the timeout covers the whole exchange (the body
+    /// included) so that a stalled response doesn't block the caller
+    fn run<T, F, R>(&self, request: R) -> Result<T, String>
+        where T: Send + 'static,
+              F: Future<Output = Result<T, String>> + Send + 'static,
+              R: FnOnce(S3Client, String) -> F {
+        let (reply, response) = std_mpsc::channel();
+        let timeout = self.config.timeout;
+        let request = request(self.client.clone(), self.config.bucket.clone());
+        let job = async move {
+            let res = actix_rt::time::timeout(timeout, request).await
+                .unwrap_or_else(|_| Err("Request timed out".to_string()));
+            // The caller may have given up
+            let _ = reply.send(res);
+        };
+
+        self.jobs.unbounded_send(Box::pin(job))
+            .map_err(|_| "The map storage thread stopped".to_string())?;
+        response.recv_timeout(timeout + S3_REPLY_MARGIN)
+            .map_err(|_| "The map storage thread isn't responding".to_string())?
+    }
+}
+
+/// HEAD responses have no body, so a missing object isn't always reported as NoSuchKey
+fn is_not_found<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Unknown(res) => res.status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+impl MapStorage for S3Storage {
+    fn setup(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let key = self.object_key(key);
+        self.run(move |client, bucket| async move {
+            let output = match client.get_object(GetObjectRequest { bucket, key, ..Default::default() }).await {
+                Ok(x) => x,
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+                Err(ref err) if is_not_found(err) => return Ok(None),
+                Err(err) => return Err(err.to_string()),
+            };
+            match output.body {
+                Some(body) => body.map_ok(|x| x.to_vec()).try_concat().await
+                    .map(Some)
+                    .map_err(|x| x.to_string()),
+                None => Ok(Some(Vec::new())),
+            }
+        })
+    }
+
+    fn write(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        let key = self.object_key(key);
+        self.run(move |client, bucket| async move {
+            client.put_object(PutObjectRequest { bucket, key, body: Some(data.into()), ..Default::default() }).await
+                .map(|_| ())
+                .map_err(|x| x.to_string())
+        })
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, String> {
+        // S3 doesn't tell if the deleted object existed
+        if !self.exists(key)? {
+            return Ok(false)
+        }
+        let key = self.object_key(key);
+        self.run(move |client, bucket| async move {
+            client.delete_object(DeleteObjectRequest { bucket, key, ..Default::default() }).await
+                .map(|_| true)
+                .map_err(|x| x.to_string())
+        })
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, String> {
+        let key = self.object_key(key);
+        self.run(move |client, bucket| async move {
+            match client.head_object(HeadObjectRequest { bucket, key, ..Default::default() }).await {
+                Ok(_) => Ok(true),
+                Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+                Err(ref err) if is_not_found(err) => Ok(false),
+                Err(err) => Err(err.to_string()),
+            }
+        })
+    }
+
+    fn check(&self) -> StorageStatus {
+        let probe = ".write_check";
+        let res = self.write(probe, Vec::new())
+            .and_then(|_| {
+                let key = self.object_key(probe);
+                self.run(move |client, bucket| async move {
+                    client.delete_object(DeleteObjectRequest { bucket, key, ..Default::default() }).await
+                        .map(|_| ())
+                        .map_err(|x| x.to_string())
+                })
+            });
+        StorageStatus {
+            directory: format!("{}/{}/{}", self.config.endpoint, self.config.bucket, self.config.prefix),
+            writable: res.is_ok(),
+            free_bytes: None,
+            min_free_bytes: 0,
+            error: res.err().map(|x| format!("The bucket isn't writable: {}", x)),
+        }
+    }
+}
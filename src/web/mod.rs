@@ -1,7 +1,41 @@
+pub mod access_monitor;
+pub mod admin_network;
 pub mod api_service;
+pub mod approval;
+pub mod auth;
+pub mod blocking;
+pub mod bulk_export;
+pub mod calendar_service;
+pub mod chart_service;
+pub mod cnr_orphans;
+pub mod cors;
+pub mod data_latency;
+pub mod db_connection;
 pub mod db_helper;
 pub mod errors;
+pub mod export_job;
+pub mod export_manifest;
+pub mod export_service;
+pub mod grafana_service;
+pub mod graphql_cache;
 pub mod graphql_schema;
 pub mod graphql_service;
+pub mod health_service;
+pub mod identity_cookie;
+pub mod ingest_service;
+pub mod login_throttle;
+pub mod map_storage;
+pub mod pagination;
+pub mod peer_comparison;
+pub mod policy;
+pub mod public_service;
 pub mod quota;
+pub mod range_recommendation;
+pub mod readings_cache;
+pub mod request_log;
+pub mod rest_service;
+pub mod schema_changes;
+pub mod site_config;
 pub mod site_map_service;
+pub mod sync;
+pub mod ttn_service;
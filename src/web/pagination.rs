@@ -0,0 +1,87 @@
+use crate::models::IdType;
+
+use super::errors::{ServiceError, ServiceResult};
+
+/// Maximum number of elements returned in a single page
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct PageInfo {
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub start_cursor: Option<String>,
+    pub end_cursor: Option<String>,
+}
+
+/// Cursor based page request (following the relay connection arguments), the elements are
+/// ordered by id and the cursors are opaque strings that point to a single id.
+pub struct PageRequest {
+    /// Only the elements with an id greater than this are returned
+    pub after: Option<IdType>,
+    /// Only the elements with an id lower than this are returned
+    pub before: Option<IdType>,
+    /// Maximum number of elements in the page
+    pub limit: i64,
+    /// True if the last elements should be returned (so the query should be reversed)
+    pub backwards: bool,
+}
+
+fn parse_cursor(cursor: Option<String>) -> ServiceResult<Option<IdType>> {
+    cursor.map(|x| x.parse::<IdType>().map_err(|_| ServiceError::BadRequest("Invalid cursor".to_string())))
+        .transpose()
+}
+
+fn format_cursor(id: IdType) -> String {
+    id.to_string()
+}
+
+impl PageRequest {
+    pub fn new(first: Option<i32>, after: Option<String>, last: Option<i32>, before: Option<String>) -> ServiceResult<PageRequest> {
+        let (limit, backwards) = match (first, last) {
+            (Some(_), Some(_)) => return Err(ServiceError::BadRequest("Cannot use both first and last".to_string())),
+            (Some(x), None) => (x, false),
+            (None, Some(x)) => (x, true),
+            (None, None) => (MAX_PAGE_SIZE as i32, false),
+        };
+        if limit < 0 {
+            return Err(ServiceError::BadRequest("Negative page size".to_string()))
+        }
+
+        Ok(PageRequest {
+            after: parse_cursor(after)?,
+            before: parse_cursor(before)?,
+            limit: (limit as i64).min(MAX_PAGE_SIZE),
+            backwards,
+        })
+    }
+
+    /// Number of elements that the query should load, an extra element is loaded to know if there
+    /// are other pages.
+    pub fn query_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Builds the page from the elements loaded by the query (that should be ordered by id,
+    /// descending if the request is backwards).
+    pub fn finish<T, F: Fn(&T) -> IdType>(&self, mut elements: Vec<T>, get_id: F) -> (Vec<T>, PageInfo) {
+        let has_more = elements.len() as i64 > self.limit;
+        elements.truncate(self.limit as usize);
+        if self.backwards {
+            elements.reverse();
+        }
+
+        let (has_next_page, has_previous_page) = if self.backwards {
+            (self.before.is_some(), has_more)
+        } else {
+            (has_more, self.after.is_some())
+        };
+
+        let page_info = PageInfo {
+            has_next_page,
+            has_previous_page,
+            start_cursor: elements.first().map(|x| format_cursor(get_id(x))),
+            end_cursor: elements.last().map(|x| format_cursor(get_id(x))),
+        };
+        (elements, page_info)
+    }
+}
@@ -0,0 +1,97 @@
+//! Comparison of a channel against the average of its peers (channels that should behave the same,
+//! ex. rooms with the same target climate).
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+
+#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+pub struct PeerDivergence {
+    /// Number of peers the channel was compared to
+    pub peer_count: i32,
+    /// Number of aligned points (where both the channel and the peers have a reading)
+    pub sample_count: i32,
+    /// Average of (channel - peers), positive if the channel reads higher
+    pub mean_difference: f64,
+    pub mean_absolute_difference: f64,
+    pub max_absolute_difference: f64,
+    pub root_mean_square_difference: f64,
+    /// Pearson correlation between the channel and the peers average, null if either is constant
+    pub correlation: Option<f64>,
+}
+
+/// Compares the target series with the average of the peer series, the points are aligned by
+/// date so the series should be aggregated (ex. hourly) before.
+/// Returns None if there are no aligned points.
+pub fn compare_with_peers(target: &[(NaiveDateTime, f64)], peers: &[Vec<(NaiveDateTime, f64)>]) -> Option<PeerDivergence> {
+    let mut peer_sums: HashMap<NaiveDateTime, (f64, usize)> = HashMap::new();
+    for peer in peers {
+        for (date, value) in peer {
+            let entry = peer_sums.entry(*date).or_insert((0.0, 0));
+            entry.0 += value;
+            entry.1 += 1;
+        }
+    }
+
+    let pairs: Vec<(f64, f64)> = target.iter()
+        .filter_map(|(date, value)| peer_sums.get(date).map(|(sum, count)| (*value, sum / *count as f64)))
+        .collect();
+    if pairs.is_empty() {
+        return None
+    }
+    let count = pairs.len() as f64;
+
+    let differences: Vec<f64> = pairs.iter().map(|(a, b)| a - b).collect();
+    let mean_difference = differences.iter().sum::<f64>() / count;
+    let mean_absolute_difference = differences.iter().map(|x| x.abs()).sum::<f64>() / count;
+    let max_absolute_difference = differences.iter().map(|x| x.abs()).fold(0.0, f64::max);
+    let root_mean_square_difference = (differences.iter().map(|x| x * x).sum::<f64>() / count).sqrt();
+
+    let target_mean = pairs.iter().map(|x| x.0).sum::<f64>() / count;
+    let peers_mean = pairs.iter().map(|x| x.1).sum::<f64>() / count;
+    let (covariance, target_variance, peers_variance) = pairs.iter().fold((0.0, 0.0, 0.0), |acc, (a, b)| {
+        let (da, db) = (a - target_mean, b - peers_mean);
+        (acc.0 + da * db, acc.1 + da * da, acc.2 + db * db)
+    });
+    let correlation = if target_variance > 0.0 && peers_variance > 0.0 {
+        Some(covariance / (target_variance * peers_variance).sqrt())
+    } else {
+        None
+    };
+
+    Some(PeerDivergence {
+        peer_count: peers.len().min(i32::MAX as usize) as i32,
+        sample_count: pairs.len().min(i32::MAX as usize) as i32,
+        mean_difference,
+        mean_absolute_difference,
+        max_absolute_difference,
+        root_mean_square_difference,
+        correlation,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_with_peers() {
+        let hour = |x: i64| NaiveDateTime::from_timestamp(1583020800 + x * 3600, 0);
+
+        let target = vec![(hour(0), 22.0), (hour(1), 23.0), (hour(2), 24.0), (hour(5), 40.0)];
+        let peers = vec![
+            vec![(hour(0), 19.0), (hour(1), 20.0), (hour(2), 21.0)],
+            vec![(hour(0), 21.0), (hour(1), 22.0)],
+        ];
+        let res = compare_with_peers(&target, &peers).unwrap();
+
+        // Peers average: 20, 21, 21 (the last hour has no peers)
+        assert_eq!(res.peer_count, 2);
+        assert_eq!(res.sample_count, 3);
+        assert!((res.mean_difference - 7.0 / 3.0).abs() < 1e-9);
+        assert_eq!(res.max_absolute_difference, 3.0);
+        assert!(res.correlation.unwrap() > 0.8);
+
+        assert_eq!(compare_with_peers(&target, &[]), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Authorization decisions shared by the GraphQL api, the REST endpoints and the site map routes.
+//!
+//! A resource that the principal can't see is reported as missing (NOT_FOUND), exactly like a
+//! resource that doesn't exist, so that the ids of the other sites can't be enumerated. An action
+//! on a visible resource that requires a higher access level is reported as UNAUTHORIZED.
+//! With `ACCESS_POLICY_HIDDEN=unauthorized` the hidden resources are reported as UNAUTHORIZED too,
+//! for the clients that still expect the old behaviour.
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::Resolver;
+use crate::models::AccessLevel;
+
+use super::errors::{ServiceError, ServiceResult};
+
+/// Error given for the resources that the principal can't see
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HiddenResourceError {
+    NotFound,
+    Unauthorized,
+}
+
+impl FromStr for HiddenResourceError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "not_found" => Ok(HiddenResourceError::NotFound),
+            "unauthorized" => Ok(HiddenResourceError::Unauthorized),
+            _ => Err(format!("Invalid hidden resource error '{}', expected not_found or unauthorized", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessPolicy {
+    pub hidden_error: HiddenResourceError,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        AccessPolicy {
+            hidden_error: HiddenResourceError::NotFound,
+        }
+    }
+}
+
+/// `[access_policy]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessPolicySection {
+    /// not_found (the default) or unauthorized
+    pub hidden: Option<String>,
+}
+
+impl AccessPolicy {
+    pub fn resolve(r: &mut Resolver, file: AccessPolicySection) -> Self {
+        let hidden_error = r.parsed("ACCESS_POLICY_HIDDEN", file.hidden, |x| x.parse())
+            .unwrap_or(HiddenResourceError::NotFound);
+        AccessPolicy { hidden_error }
+    }
+
+    /// Error for a resource that the principal can't see (or that doesn't exist at all, the two
+    /// cases must be indistinguishable)
+    pub fn hidden(&self, resource: &str) -> ServiceError {
+        match self.hidden_error {
+            HiddenResourceError::NotFound => ServiceError::NotFound(resource.to_string()),
+            HiddenResourceError::Unauthorized => ServiceError::Unauthorized,
+        }
+    }
+
+    /// Error for an action that the principal can't do on a visible resource
+    pub fn forbidden(&self) -> ServiceError {
+        ServiceError::Unauthorized
+    }
+
+    pub fn check_visible(&self, resource: &str, visible: bool) -> ServiceResult<()> {
+        if visible {
+            Ok(())
+        } else {
+            Err(self.hidden(resource))
+        }
+    }
+
+    /// Decides an access to the resource given the level granted to the principal, None if the
+    /// resource isn't visible at all
+    pub fn check_level(&self, resource: &str, granted: Option<AccessLevel>, required: AccessLevel) -> ServiceResult<()> {
+        match granted {
+            None => Err(self.hidden(resource)),
+            Some(x) if x < required => Err(self.forbidden()),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_type(res: ServiceResult<()>) -> Option<&'static str> {
+        match res {
+            Ok(()) => None,
+            Err(ServiceError::NotFound(_)) => Some("NOT_FOUND"),
+            Err(ServiceError::Unauthorized) => Some("UNAUTHORIZED"),
+            Err(_) => Some("OTHER"),
+        }
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let policy = AccessPolicy::default();
+        assert_eq!(error_type(policy.check_level("Site", None, AccessLevel::View)), Some("NOT_FOUND"));
+        assert_eq!(error_type(policy.check_level("Site", Some(AccessLevel::View), AccessLevel::Manage)), Some("UNAUTHORIZED"));
+        assert_eq!(error_type(policy.check_level("Site", Some(AccessLevel::Manage), AccessLevel::View)), None);
+        assert_eq!(error_type(policy.check_visible("Channel", true)), None);
+
+        let policy = AccessPolicy { hidden_error: "unauthorized".parse().unwrap() };
+        assert_eq!(error_type(policy.check_visible("Channel", false)), Some("UNAUTHORIZED"));
+        assert!("hidden".parse::<HiddenResourceError>().is_err());
+    }
+}
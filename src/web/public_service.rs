@@ -0,0 +1,115 @@
+use actix_web::{HttpResponse, web};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::AppData;
+use crate::alarm::ReadingsStore;
+use crate::models::{Channel, CHANNEL_ALL_COLUMNS, IdType, Sensor, SENSOR_ALL_COLUMNS, Site, SITE_ALL_COLUMNS};
+
+use super::blocking::run_blocking;
+use super::errors::{ServiceError, ServiceResult};
+
+/// Seconds the clients (and the proxies in between) can cache the current conditions
+const CURRENT_CONDITIONS_MAX_AGE: u32 = 60;
+
+#[derive(Serialize, juniper::GraphQLObject)]
+pub struct ChannelConditions {
+    name: Option<String>,
+    measure_unit: Option<String>,
+    value_min: f64,
+    value_max: f64,
+    date: NaiveDateTime,
+}
+
+#[derive(Serialize, juniper::GraphQLObject)]
+pub struct RoomConditions {
+    name: Option<String>,
+    channels: Vec<ChannelConditions>,
+}
+
+/// Last measures of the rooms of a site shared with a public token
+#[derive(Serialize, juniper::GraphQLObject)]
+pub struct SiteConditions {
+    name: Option<String>,
+    rooms: Vec<RoomConditions>,
+}
+
+pub fn load_site_conditions(ctx: &AppData, token: &str) -> ServiceResult<SiteConditions> {
+    use crate::schema::site::dsl as site_dsl;
+    use crate::schema::site_public_token::dsl as token_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::channel::dsl as channel_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let site = token_dsl::site_public_token
+        .inner_join(site_dsl::site)
+        .filter(token_dsl::token.eq(token))
+        .filter(site_dsl::deleted_at.is_null())
+        .select(SITE_ALL_COLUMNS)
+        .first::<Site>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Site".to_string()))?;
+
+    let sensors: Vec<Sensor> = sensor_dsl::sensor
+        .filter(sensor_dsl::site_id.eq(site.id))
+        .filter(sensor_dsl::enabled.eq(true))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .order(sensor_dsl::id)
+        .select(SENSOR_ALL_COLUMNS)
+        .load(&conn)?;
+
+    let channels: Vec<Channel> = channel_dsl::channel
+        .filter(channel_dsl::sensor_id.eq_any(sensors.iter().map(|x| x.id).collect::<Vec<IdType>>()))
+        .filter(channel_dsl::deleted_at.is_null())
+        .order(channel_dsl::id)
+        .select(CHANNEL_ALL_COLUMNS)
+        .load(&conn)?;
+
+    let site_cnr_id = match site.id_cnr.as_ref() {
+        Some(x) => x,
+        None => return Ok(SiteConditions { name: site.name, rooms: Vec::new() }),
+    };
+
+    let cnr_ids: Vec<(&str, &str)> = channels.iter()
+        .filter_map(|channel| {
+            let sensor = sensors.iter().find(|x| x.id == channel.sensor_id)?;
+            Some((sensor.id_cnr.as_deref()?, channel.id_cnr.as_deref()?))
+        })
+        .collect();
+    let mut measures = ctx.sensor_pool.load_last_channel_measures(site_cnr_id, &cnr_ids)?;
+
+    let rooms = sensors.iter().map(|sensor| {
+        let channels = channels.iter()
+            .filter(|channel| channel.sensor_id == sensor.id)
+            .filter_map(|channel| {
+                let key = (sensor.id_cnr.clone()?, channel.id_cnr.clone()?);
+                let (value_min, value_max, date) = measures.remove(&key)?;
+                Some(ChannelConditions {
+                    name: channel.name.clone(),
+                    measure_unit: channel.measure_unit.clone(),
+                    value_min,
+                    value_max,
+                    date,
+                })
+            })
+            .collect();
+        RoomConditions {
+            name: sensor.name.clone(),
+            channels,
+        }
+    }).collect();
+
+    Ok(SiteConditions { name: site.name, rooms })
+}
+
+/// Returns the last measures of every room (sensor) of the site, the site is identified by its
+/// public token so that the endpoint can be used by lobby screens without an account.
+pub async fn current_conditions(ctx: web::Data<AppData>, token: web::Path<String>) -> ServiceResult<HttpResponse> {
+    let conditions = run_blocking(move || load_site_conditions(&ctx, &token)).await?;
+
+    Ok(HttpResponse::Ok()
+        .header("Cache-Control", format!("public, max-age={}", CURRENT_CONDITIONS_MAX_AGE))
+        .json(conditions))
+}
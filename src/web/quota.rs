@@ -1,11 +1,144 @@
 use std::cmp::{Ordering, Reverse};
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 
+use crate::config::Resolver;
 use crate::models::IdType;
 use actix::{Actor, Context, Message, Handler, AsyncContext, SpawnHandle, Addr};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
 use std::time::{Instant, Duration};
 use priority_queue::PriorityQueue;
+use serde::Deserialize;
+
+/// Spending older than this is not shown in the recent spending of the user.
+const RECENT_SPENDING_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Maximum number of spending entries remembered for every user.
+const RECENT_SPENDING_MAX_ENTRIES: usize = 256;
+
+/// Coins charged by the resolvers, the defaults can be changed with the QUOTA_COST_* variables.
+/// Single operations (ex. `Channel.readings` or `login`, the names shown in the recent spending)
+/// can be given a fixed cost with QUOTA_COST_OVERRIDES, ex. `Channel.readings=500,login=100`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuotaCostConfig {
+    pub db_query: i64,
+    pub fcm_op: i64,
+    pub email_op: i64,
+    pub password_change: i64,
+    pub login: i64,
+    pub overrides: HashMap<String, i64>,
+}
+
+impl Default for QuotaCostConfig {
+    fn default() -> Self {
+        QuotaCostConfig {
+            db_query: 10,
+            fcm_op: 300,
+            email_op: 300,
+            password_change: 400,
+            login: 300,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+/// `[quota_costs]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaCostSection {
+    pub db_query: Option<i64>,
+    pub fcm_op: Option<i64>,
+    pub email_op: Option<i64>,
+    pub password_change: Option<i64>,
+    pub login: Option<i64>,
+    pub overrides: Option<HashMap<String, i64>>,
+}
+
+impl QuotaCostConfig {
+    pub fn resolve(r: &mut Resolver, file: QuotaCostSection) -> Self {
+        let default = QuotaCostConfig::default();
+        let overrides = r.parsed("QUOTA_COST_OVERRIDES", None, |x| {
+            parse_cost_overrides(x).ok_or_else(|| "expected operation=cost pairs".to_string())
+        });
+
+        QuotaCostConfig {
+            db_query: r.optional("QUOTA_COST_DB_QUERY", file.db_query).unwrap_or(default.db_query),
+            fcm_op: r.optional("QUOTA_COST_FCM_OP", file.fcm_op).unwrap_or(default.fcm_op),
+            email_op: r.optional("QUOTA_COST_EMAIL_OP", file.email_op).unwrap_or(default.email_op),
+            password_change: r.optional("QUOTA_COST_PASSWORD_CHANGE", file.password_change).unwrap_or(default.password_change),
+            login: r.optional("QUOTA_COST_LOGIN", file.login).unwrap_or(default.login),
+            overrides: overrides.or(file.overrides).unwrap_or_default(),
+        }
+    }
+
+    /// Cost of the operation, the override (if any) replaces the computed amount
+    pub fn operation_cost(&self, operation: &str, amount: i64) -> i64 {
+        self.overrides.get(operation).copied().unwrap_or(amount)
+    }
+}
+
+/// Parses a comma separated list of `operation=cost`, returns None if an entry is invalid
+pub fn parse_cost_overrides(overrides: &str) -> Option<HashMap<String, i64>> {
+    overrides.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let operation = parts.next()?.trim();
+            let cost = parts.next()?.trim().parse().ok()?;
+            if operation.is_empty() {
+                return None
+            }
+            Some((operation.to_string(), cost))
+        })
+        .collect()
+}
+
+/// Share of the quota after which the clients are warned, so that they can slow down (ex. poll
+/// less often) before their requests are rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuotaWarningConfig {
+    /// Percentage of the max balance spent, 0 disables the warnings
+    pub percent: i64,
+    /// Also sends a push notification when the user crosses the threshold
+    pub push: bool,
+}
+
+impl Default for QuotaWarningConfig {
+    fn default() -> Self {
+        QuotaWarningConfig {
+            percent: 80,
+            push: false,
+        }
+    }
+}
+
+/// `[quota_warning]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaWarningSection {
+    pub percent: Option<i64>,
+    pub push: Option<bool>,
+}
+
+impl QuotaWarningConfig {
+    pub fn resolve(r: &mut Resolver, file: QuotaWarningSection) -> Self {
+        let default = QuotaWarningConfig::default();
+        QuotaWarningConfig {
+            percent: r.optional("QUOTA_WARNING_PERCENT", file.percent).unwrap_or(default.percent),
+            push: r.flag("QUOTA_WARNING_PUSH", file.push),
+        }
+    }
+
+    /// Balance under which the user is warned, None if the warnings are disabled
+    pub fn threshold(&self, max_balance: i64) -> Option<i64> {
+        if self.percent <= 0 {
+            return None
+        }
+        Some(max_balance - max_balance * self.percent.min(100) / 100)
+    }
+}
 
 #[inline]
 fn get_accumulated_balance(passed: Duration, balance_per_second: u128) -> u128 {
@@ -13,10 +146,14 @@ fn get_accumulated_balance(passed: Duration, balance_per_second: u128) -> u128 {
     (passed.as_millis() * balance_per_second) / 1000
 }
 
+/// None if the balance is never refilled
 #[inline]
-fn get_balance_wait(bal: u128, balance_per_sec: u128) -> Duration {
+fn get_balance_wait(bal: u128, balance_per_sec: u128) -> Option<Duration> {
+    if balance_per_sec == 0 {
+        return None
+    }
     // inverse of get_accumulated_balance, how much should I wait to accumulate bal?
-    Duration::from_millis(((1000 * bal) / balance_per_sec) as u64)
+    Some(Duration::from_millis(((1000 * bal) / balance_per_sec) as u64))
 }
 
 pub struct QuotaControlActor {
@@ -88,11 +225,18 @@ pub struct UserData {
     last_balance_update: Instant,
 }
 
+struct SpendingEntry {
+    date: Instant,
+    operation: String,
+    coins: i64,
+}
+
 pub struct Data {
     max_balance: i64,
     balance_per_second: u64,
     users: HashMap<IdType, UserData>,
-    next_expiration: PriorityQueue<IdType, Reverse<Instant>>
+    next_expiration: PriorityQueue<IdType, Reverse<Instant>>,
+    recent_spending: HashMap<IdType, VecDeque<SpendingEntry>>,
 }
 
 impl Data {
@@ -102,13 +246,75 @@ pub fn new(max_balance: i64, balance_per_second: u64) -> Self {
             balance_per_second,
             users: HashMap::new(),
             next_expiration: PriorityQueue::with_capacity(256),
+            recent_spending: HashMap::new(),
+        }
+    }
+
+    fn prune_spending(&mut self, now: Instant, user_id: IdType) {
+        let entries = match self.recent_spending.get_mut(&user_id) {
+            Some(x) => x,
+            None => return,
+        };
+        while let Some(entry) = entries.front() {
+            if now.duration_since(entry.date) <= RECENT_SPENDING_WINDOW {
+                break
+            }
+            entries.pop_front();
+        }
+        if entries.is_empty() {
+            self.recent_spending.remove(&user_id);
+        }
+    }
+
+    pub fn record_spending(&mut self, now: Instant, user_id: IdType, operation: &str, coins: i64) {
+        self.prune_spending(now, user_id);
+        let entries = self.recent_spending.entry(user_id).or_default();
+        if entries.len() >= RECENT_SPENDING_MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(SpendingEntry {
+            date: now,
+            operation: operation.to_string(),
+            coins,
+        });
+    }
+
+    /// Returns the coins spent by the user in the recent past, grouped by operation.
+    pub fn get_recent_spending(&mut self, now: Instant, user_id: IdType) -> Vec<(String, i64)> {
+        self.prune_spending(now, user_id);
+        let mut res: Vec<(String, i64)> = Vec::new();
+        for entry in self.recent_spending.get(&user_id).into_iter().flatten() {
+            match res.iter_mut().find(|x| x.0 == entry.operation) {
+                Some(x) => x.1 += entry.coins,
+                None => res.push((entry.operation.clone(), entry.coins)),
+            }
         }
+        res
     }
 
     pub fn get_balance(&mut self, now: Instant, user_id: IdType) -> i64 {
         self.add_balance(now, user_id, 0)
     }
 
+    /// Time before the balance of the user reaches the target, None if it's never refilled
+    pub fn get_wait(&mut self, now: Instant, user_id: IdType, target: i64) -> Option<Duration> {
+        let balance = self.get_balance(now, user_id);
+        if balance >= target {
+            return Some(Duration::from_secs(0))
+        }
+        get_balance_wait((target as i128 - balance as i128) as u128, self.balance_per_second as u128)
+    }
+
+    /// Balances of the users that haven't refilled their quota yet
+    pub fn balances(&mut self, now: Instant) -> Vec<(IdType, i64)> {
+        let max_balance = self.max_balance;
+        let user_ids: Vec<IdType> = self.users.keys().cloned().collect();
+        user_ids.into_iter()
+            .map(|user_id| (user_id, self.get_balance(now, user_id)))
+            .filter(|x| x.1 < max_balance)
+            .collect()
+    }
+
     pub fn replace_balance(&mut self, now: Instant, user_id: IdType, new_balance: i64) -> i64 {
         if new_balance >= self.max_balance {
             self.users.remove(&user_id);
@@ -120,8 +326,10 @@ pub fn replace_balance(&mut self, now: Instant, user_id: IdType, new_balance: i6
             last_balance_update: now,
         });
 
-        let wait_time = get_balance_wait((self.max_balance as i128 - new_balance as i128) as u128, self.balance_per_second as u128);
-        self.next_expiration.push(user_id, Reverse(now + wait_time));
+        // A balance that's never refilled never expires
+        if let Some(wait_time) = get_balance_wait((self.max_balance as i128 - new_balance as i128) as u128, self.balance_per_second as u128) {
+            self.next_expiration.push(user_id, Reverse(now + wait_time));
+        }
 
         new_balance
     }
@@ -155,11 +363,32 @@ pub struct AppData {
 }
 
 impl AppData {
+    pub fn max_balance(&self) -> i64 {
+        self.handle.lock().unwrap().max_balance
+    }
+
+    pub fn balance_per_second(&self) -> u64 {
+        self.handle.lock().unwrap().balance_per_second
+    }
+
+    pub fn record_spending(&self, now: Instant, user_id: IdType, operation: &str, coins: i64) {
+        self.handle.lock().unwrap().record_spending(now, user_id, operation, coins);
+    }
+
+    pub fn get_recent_spending(&self, now: Instant, user_id: IdType) -> Vec<(String, i64)> {
+        self.handle.lock().unwrap().get_recent_spending(now, user_id)
+    }
+
     pub fn get_quota_balance(&self, now: Instant, user_id: IdType) -> i64 {
         let mut data = self.handle.lock().unwrap();
         data.get_balance(now, user_id)
     }
 
+    /// Time before the user can spend `coins` again, None if the balance is never refilled
+    pub fn get_quota_wait(&self, now: Instant, user_id: IdType, coins: i64) -> Option<Duration> {
+        self.handle.lock().unwrap().get_wait(now, user_id, coins)
+    }
+
     pub fn set_quota_balance(&self, now: Instant, user_id: IdType, balance: i64) {
         let mut data = self.handle.lock().unwrap();
         data.replace_balance(now, user_id, balance);
@@ -172,6 +401,113 @@ pub fn add_quota_balance(&self, now: Instant, user_id: IdType, balance_diff: i64
 
         self.actor_addr.do_send(QuotaUpdateMessage());
     }
+
+    pub fn balances(&self, now: Instant) -> Vec<(IdType, i64)> {
+        self.handle.lock().unwrap().balances(now)
+    }
+
+    /// Restores a balance saved some time ago, adding what the user would have accumulated since
+    pub fn restore_quota_balance(&self, now: Instant, user_id: IdType, balance: i64, elapsed: Duration) {
+        let refill = get_accumulated_balance(elapsed, self.balance_per_second() as u128).min(i64::max_value() as u128) as i64;
+        self.set_quota_balance(now, user_id, balance.saturating_add(refill));
+    }
+}
+
+/// Category of a resolver, every category has its own balance so that heavy reads (ex. charts)
+/// cannot starve the operations that modify the server state (ex. acknowledging an alarm).
+#[derive(Clone, Copy, Debug, PartialEq, juniper::GraphQLEnum)]
+pub enum QuotaPool {
+    Read,
+    Write,
+}
+
+impl QuotaPool {
+    pub fn from_char(name: &str) -> Option<QuotaPool> {
+        match name {
+            "r" => Some(QuotaPool::Read),
+            "w" => Some(QuotaPool::Write),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            QuotaPool::Read => "r",
+            QuotaPool::Write => "w",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QuotaBank {
+    read: AppData,
+    write: AppData,
+}
+
+impl QuotaBank {
+    pub fn new(read: AppData, write: AppData) -> Self {
+        QuotaBank { read, write }
+    }
+
+    pub fn pool(&self, pool: QuotaPool) -> &AppData {
+        match pool {
+            QuotaPool::Read => &self.read,
+            QuotaPool::Write => &self.write,
+        }
+    }
+
+    /// Saves the balances that aren't full, so that a restart doesn't refill the quota of every
+    /// user. Returns the number of balances saved.
+    pub fn save_snapshot(&self, conn: &PgConnection, now: NaiveDateTime) -> QueryResult<usize> {
+        use crate::schema::quota_snapshot::dsl;
+
+        let instant = Instant::now();
+        let mut rows = Vec::new();
+        for pool in [QuotaPool::Read, QuotaPool::Write].iter() {
+            for (user_id, balance) in self.pool(*pool).balances(instant) {
+                rows.push((
+                    dsl::user_id.eq(user_id),
+                    dsl::pool.eq(pool.to_char()),
+                    dsl::balance.eq(balance),
+                    dsl::saved_at.eq(now),
+                ));
+            }
+        }
+
+        conn.transaction(|| {
+            diesel::delete(dsl::quota_snapshot).execute(conn)?;
+            diesel::insert_into(dsl::quota_snapshot)
+                .values(&rows)
+                .execute(conn)
+        })
+    }
+
+    /// Loads the balances saved by the last shutdown, the snapshot is deleted so that it's only
+    /// restored once. Returns the number of balances restored.
+    pub fn restore_snapshot(&self, conn: &PgConnection, now: NaiveDateTime) -> QueryResult<usize> {
+        use crate::schema::quota_snapshot::dsl;
+
+        let rows = conn.transaction(|| {
+            let rows = dsl::quota_snapshot
+                .select((dsl::user_id, dsl::pool, dsl::balance, dsl::saved_at))
+                .load::<(IdType, String, i64, NaiveDateTime)>(conn)?;
+            diesel::delete(dsl::quota_snapshot).execute(conn)?;
+            Ok::<_, diesel::result::Error>(rows)
+        })?;
+
+        let instant = Instant::now();
+        let mut restored = 0;
+        for (user_id, pool, balance, saved_at) in rows {
+            let pool = match QuotaPool::from_char(&pool) {
+                Some(x) => x,
+                None => continue,
+            };
+            let elapsed = (now - saved_at).to_std().unwrap_or_default();
+            self.pool(pool).restore_quota_balance(instant, user_id, balance, elapsed);
+            restored += 1;
+        }
+        Ok(restored)
+    }
 }
 
 pub fn init(max_balance: i64, balance_per_second: u64) -> AppData {
@@ -191,3 +527,54 @@ pub fn init(max_balance: i64, balance_per_second: u64) -> AppData {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cost_overrides() {
+        let overrides = parse_cost_overrides("Channel.readings=500, login = 100,").unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["Channel.readings"], 500);
+        assert_eq!(overrides["login"], 100);
+
+        assert_eq!(parse_cost_overrides(""), Some(HashMap::new()));
+        assert_eq!(parse_cost_overrides("login"), None);
+        assert_eq!(parse_cost_overrides("login=many"), None);
+        assert_eq!(parse_cost_overrides("=10"), None);
+    }
+
+    #[test]
+    fn test_warning_threshold() {
+        let config = QuotaWarningConfig::default();
+        assert_eq!(config.threshold(1000), Some(200));
+        assert_eq!(QuotaWarningConfig { percent: 150, push: false }.threshold(1000), Some(0));
+        assert_eq!(QuotaWarningConfig { percent: 0, push: false }.threshold(1000), None);
+    }
+
+    #[test]
+    fn test_balances() {
+        let now = Instant::now();
+        let mut data = Data::new(1000, 1);
+        data.replace_balance(now, 1, 200);
+        data.replace_balance(now, 2, 1000);
+        assert_eq!(data.balances(now), vec![(1, 200)]);
+        assert_eq!(data.balances(now + Duration::from_secs(800)), vec![]);
+    }
+
+    #[test]
+    fn test_balance_wait() {
+        let now = Instant::now();
+        let mut data = Data::new(1000, 4);
+        data.replace_balance(now, 1, -10);
+        assert_eq!(data.get_wait(now, 1, 1), Some(Duration::from_millis(2750)));
+        assert_eq!(data.get_wait(now + Duration::from_secs(3), 1, 1), Some(Duration::from_secs(0)));
+        assert_eq!(data.get_wait(now, 2, 1), Some(Duration::from_secs(0)));
+
+        // A pool that's never refilled must not divide by zero
+        let mut data = Data::new(1000, 0);
+        data.replace_balance(now, 1, 0);
+        assert_eq!(data.get_wait(now, 1, 1), None);
+    }
+}
@@ -0,0 +1,126 @@
+//! Expected range analysis following the approach of EN 15757: the seasonal cycle is the 30 day
+//! centered moving average of the readings and the short term fluctuations around it are limited
+//! by their 7th and 93rd percentiles.
+
+use std::collections::BTreeMap;
+
+use chrono::{Duration, NaiveDate};
+
+use super::graphql_schema::ReadingData;
+
+/// Half width (in days) of the centered moving average
+const SEASONAL_HALF_WINDOW_DAYS: i64 = 15;
+const LOWER_PERCENTILE: f64 = 0.07;
+const UPPER_PERCENTILE: f64 = 0.93;
+/// Readings needed before a recommendation is made
+pub const RECOMMENDATION_MIN_SAMPLES: usize = 30;
+
+#[derive(Debug, juniper::GraphQLObject, PartialEq)]
+pub struct RangeRecommendation {
+    /// Suggested range_min of the channel
+    pub range_min: f64,
+    /// Suggested range_max of the channel
+    pub range_max: f64,
+    /// Minimum of the seasonal cycle (30 day moving average)
+    pub seasonal_min: f64,
+    /// Maximum of the seasonal cycle (30 day moving average)
+    pub seasonal_max: f64,
+    /// 7th percentile of the short term fluctuations
+    pub lower_fluctuation: f64,
+    /// 93rd percentile of the short term fluctuations
+    pub upper_fluctuation: f64,
+    pub sample_count: i32,
+}
+
+/// Single value of a reading, the average if the sensor provides it
+pub fn reading_value(reading: &ReadingData) -> f64 {
+    reading.value_avg.unwrap_or_else(|| match reading.value_max {
+        Some(max) => (reading.value_min + max) / 2.0,
+        None => reading.value_min,
+    })
+}
+
+/// Linear interpolation between the closest ranks, the values must be sorted
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Returns None if there aren't enough readings to make a meaningful recommendation.
+pub fn recommend_range(readings: &[ReadingData]) -> Option<RangeRecommendation> {
+    if readings.len() < RECOMMENDATION_MIN_SAMPLES {
+        return None
+    }
+
+    // Daily means first, so that a period with more frequent readings doesn't weigh more
+    let mut days: BTreeMap<NaiveDate, (f64, usize)> = BTreeMap::new();
+    for reading in readings {
+        let entry = days.entry(reading.date.date()).or_insert((0.0, 0));
+        entry.0 += reading_value(reading);
+        entry.1 += 1;
+    }
+    let daily_means: BTreeMap<NaiveDate, f64> = days.into_iter()
+        .map(|(day, (sum, count))| (day, sum / count as f64))
+        .collect();
+
+    let seasonal: BTreeMap<NaiveDate, f64> = daily_means.keys().map(|day| {
+        let window = daily_means.range(*day - Duration::days(SEASONAL_HALF_WINDOW_DAYS)..=*day + Duration::days(SEASONAL_HALF_WINDOW_DAYS));
+        let (sum, count) = window.fold((0.0, 0), |(sum, count), (_, value)| (sum + value, count + 1));
+        (*day, sum / count as f64)
+    }).collect();
+
+    let mut fluctuations: Vec<f64> = readings.iter()
+        .map(|reading| reading_value(reading) - seasonal[&reading.date.date()])
+        .collect();
+    fluctuations.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let lower_fluctuation = percentile(&fluctuations, LOWER_PERCENTILE);
+    let upper_fluctuation = percentile(&fluctuations, UPPER_PERCENTILE);
+    let seasonal_min = seasonal.values().cloned().fold(f64::INFINITY, f64::min);
+    let seasonal_max = seasonal.values().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+    Some(RangeRecommendation {
+        range_min: seasonal_min + lower_fluctuation,
+        range_max: seasonal_max + upper_fluctuation,
+        seasonal_min,
+        seasonal_max,
+        lower_fluctuation,
+        upper_fluctuation,
+        sample_count: readings.len().min(i32::MAX as usize) as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn reading(timestamp: i64, value: f64) -> ReadingData {
+        ReadingData {
+            date: NaiveDateTime::from_timestamp(timestamp, 0),
+            value_min: value,
+            value_avg: Some(value),
+            value_max: Some(value),
+            deviation: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_recommend_range() {
+        assert_eq!(recommend_range(&[reading(0, 20.0)]), None);
+
+        // Constant seasonal cycle at 50 with fluctuations between -5 and 5
+        let readings: Vec<ReadingData> = (0..100)
+            .map(|i| reading(1583020800 + i * 3600, 50.0 + ((i % 11) as f64 - 5.0)))
+            .collect();
+        let res = recommend_range(&readings).unwrap();
+
+        assert_eq!(res.sample_count, 100);
+        assert!(res.range_min < res.seasonal_min && res.range_max > res.seasonal_max);
+        assert!(res.range_min > 44.0 && res.range_max < 56.0);
+    }
+}
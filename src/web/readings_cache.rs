@@ -0,0 +1,496 @@
+//! Read-through cache of the channel readings, so that reloading a dashboard doesn't query the
+//! sensor database again for readings that can't change anymore.
+//!
+//! The requested range is split in fixed buckets (aligned to the epoch, their length depends on the
+//! aggregation), a bucket is cached once it ended more than `READINGS_CACHE_SETTLE_MINUTES` ago
+//! (the loggers upload their readings late) while the current one and the partial buckets at the
+//! edges of the range are always read from the database.
+//! The buckets are kept in memory (up to `READINGS_CACHE_MAX_READINGS` readings, the least recently
+//! used are dropped first) or, with more replicas, in Redis (`READINGS_CACHE_REDIS_ADDR`).
+//! The readings written by the server in a cached bucket (ex. manual readings) drop it, with Redis
+//! there's no memory copy so a bucket dropped by a replica is dropped for all of them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use log::warn;
+use redis::RedisResult;
+use serde::Deserialize;
+
+use crate::alarm::NewReading;
+use crate::config::Resolver;
+
+use super::db_helper::{InvalidInterval, load_channel_readings_aggregated, ReadingsAggregation};
+use super::errors::ServiceResult;
+use super::graphql_schema::ReadingData;
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+/// Redis is not used for this time after a failure, so that an unreachable server doesn't slow
+/// down every request
+const REDIS_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Cnr ids of the channel (site, sensor and channel)
+pub type ChannelCnrIds = (String, String, String);
+
+#[derive(Clone, Debug)]
+pub struct ReadingsCacheConfig {
+    /// Readings kept in memory when Redis is not used, 0 disables the memory cache
+    pub max_readings: usize,
+    /// Time after the end of a bucket before it's cached
+    pub settle: Duration,
+    /// Redis server (host:port) that shares the cache between the replicas
+    pub redis_addr: Option<String>,
+    /// Expiration of the buckets stored in Redis
+    pub redis_ttl: Duration,
+}
+
+impl Default for ReadingsCacheConfig {
+    fn default() -> Self {
+        ReadingsCacheConfig {
+            max_readings: 200_000,
+            settle: Duration::from_secs(6 * 3600),
+            redis_addr: None,
+            redis_ttl: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+/// `[readings_cache]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadingsCacheSection {
+    pub max_readings: Option<usize>,
+    pub settle_minutes: Option<u64>,
+    pub redis_addr: Option<String>,
+    pub redis_ttl_hours: Option<u64>,
+}
+
+impl ReadingsCacheConfig {
+    pub fn resolve(r: &mut Resolver, file: ReadingsCacheSection) -> Self {
+        let default = ReadingsCacheConfig::default();
+        ReadingsCacheConfig {
+            max_readings: r.optional("READINGS_CACHE_MAX_READINGS", file.max_readings).unwrap_or(default.max_readings),
+            settle: r.optional("READINGS_CACHE_SETTLE_MINUTES", file.settle_minutes).map_or(default.settle, |x| Duration::from_secs(x * 60)),
+            redis_addr: r.parsed("READINGS_CACHE_REDIS_ADDR", file.redis_addr, |x| match redis_client(x) {
+                Ok(_) => Ok(x.to_string()),
+                Err(e) => Err(e.to_string()),
+            }),
+            redis_ttl: r.optional("READINGS_CACHE_REDIS_TTL_HOURS", file.redis_ttl_hours).map_or(default.redis_ttl, |x| Duration::from_secs(x * 3600)),
+        }
+    }
+}
+
+fn redis_client(addr: &str) -> RedisResult<redis::Client> {
+    redis::Client::open(format!("redis://{}/", addr))
+}
+
+/// Length of the buckets in seconds, every bucket contains whole hours and days
+fn bucket_span(aggregation: ReadingsAggregation) -> i64 {
+    match aggregation {
+        ReadingsAggregation::Raw => 24 * 3600,
+        ReadingsAggregation::Hourly => 7 * 24 * 3600,
+        ReadingsAggregation::Daily => 56 * 24 * 3600,
+    }
+}
+
+/// Start of the bucket that contains the time
+fn bucket_start(time: NaiveDateTime, span: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(time.timestamp().div_euclid(span) * span, 0)
+}
+
+/// Last instant of the bucket, the readings are selected with inclusive bounds
+fn bucket_last(start: NaiveDateTime, span: i64) -> NaiveDateTime {
+    start + chrono::Duration::seconds(span) - chrono::Duration::microseconds(1)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    ids: ChannelCnrIds,
+    aggregation: ReadingsAggregation,
+    start: NaiveDateTime,
+    /// The invalid intervals that overlap the bucket, a new annotation changes the key
+    excluded: Vec<InvalidInterval>,
+}
+
+impl CacheKey {
+    fn contains(&self, ids: &ChannelCnrIds, date: NaiveDateTime) -> bool {
+        &self.ids == ids && self.start <= date && date <= bucket_last(self.start, bucket_span(self.aggregation))
+    }
+
+    fn redis_key(&self) -> String {
+        redis_key(&self.ids, self.aggregation, self.start)
+    }
+
+    fn redis_field(&self) -> String {
+        if self.excluded.is_empty() {
+            return "all".to_string()
+        }
+        self.excluded.iter()
+            .map(|(start, end)| format!("{}-{}", start.timestamp_millis(), end.timestamp_millis()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Every variant of a bucket (one per set of invalid intervals) is a field of the same Redis hash,
+/// so they're dropped together
+fn redis_key(ids: &ChannelCnrIds, aggregation: ReadingsAggregation, start: NaiveDateTime) -> String {
+    format!("oldmusa:readings:{}:{}:{}:{:?}:{}", ids.0, ids.1, ids.2, aggregation, start.timestamp())
+}
+
+/// Buckets kept in memory, the least recently used are dropped when the readings exceed the limit
+struct LruStore {
+    max_readings: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<CacheKey, (u64, Arc<Vec<ReadingData>>)>,
+    order: BTreeMap<u64, CacheKey>,
+}
+
+impl LruStore {
+    fn new(max_readings: usize) -> Self {
+        LruStore {
+            max_readings,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// An empty bucket still takes a slot
+    fn entry_size(readings: &[ReadingData]) -> usize {
+        readings.len() + 1
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<ReadingData>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (last_used, readings) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(readings.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, readings: Arc<Vec<ReadingData>>) {
+        let size = LruStore::entry_size(&readings);
+        if size > self.max_readings {
+            return
+        }
+        self.remove(&key);
+        while self.size + size > self.max_readings {
+            let oldest = match self.order.keys().next() {
+                Some(x) => *x,
+                None => break,
+            };
+            let oldest_key = self.order[&oldest].clone();
+            self.remove(&oldest_key);
+        }
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, readings));
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((last_used, readings)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.size -= LruStore::entry_size(&readings);
+        }
+    }
+
+    fn remove_where<F: Fn(&CacheKey) -> bool>(&mut self, filter: F) {
+        let keys: Vec<CacheKey> = self.entries.keys().filter(|x| filter(x)).cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+/// A single connection is kept and opened again after an error.
+/// While Redis is skipped (see REDIS_RETRY_DELAY) the commands do nothing and the reads miss
+struct RedisStore {
+    client: redis::Client,
+    ttl: Duration,
+    connection: Mutex<Option<redis::Connection>>,
+    skip_until: Mutex<Option<Instant>>,
+}
+
+impl RedisStore {
+    fn new(client: redis::Client, ttl: Duration) -> Self {
+        RedisStore {
+            client,
+            ttl,
+            connection: Mutex::new(None),
+            skip_until: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> RedisResult<redis::Connection> {
+        let connection = self.client.get_connection_with_timeout(REDIS_TIMEOUT)?;
+        connection.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        connection.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        Ok(connection)
+    }
+
+    fn command<T, F>(&self, command: F) -> RedisResult<T>
+        where T: Default, F: FnOnce(&mut redis::Connection) -> RedisResult<T> {
+        // Taken out of the mutex, the other threads open their own connection instead of waiting
+        let connection = self.connection.lock().unwrap().take();
+        let mut connection = match connection {
+            Some(x) => x,
+            None => {
+                if self.skip_until.lock().unwrap().map_or(false, |x| Instant::now() < x) {
+                    return Ok(T::default())
+                }
+                self.connect().map_err(|e| self.fail(e))?
+            },
+        };
+        let res = command(&mut connection).map_err(|e| self.fail(e))?;
+        self.connection.lock().unwrap().get_or_insert(connection);
+        Ok(res)
+    }
+
+    fn fail(&self, error: redis::RedisError) -> redis::RedisError {
+        *self.skip_until.lock().unwrap() = Some(Instant::now() + REDIS_RETRY_DELAY);
+        error
+    }
+
+    fn get(&self, key: &CacheKey) -> RedisResult<Option<Vec<ReadingData>>> {
+        let data: Option<Vec<u8>> = self.command(|con| redis::cmd("HGET").arg(key.redis_key()).arg(key.redis_field()).query(con))?;
+        Ok(data.and_then(|x| serde_json::from_slice(&x).ok()))
+    }
+
+    fn set(&self, key: &CacheKey, readings: &[ReadingData]) -> RedisResult<()> {
+        let data = serde_json::to_vec(readings)
+            .map_err(|e| redis::RedisError::from((redis::ErrorKind::TypeError, "Cannot serialize the readings", e.to_string())))?;
+        let redis_key = key.redis_key();
+        self.command(|con| redis::pipe().atomic()
+            .hset(&redis_key, key.redis_field(), data).ignore()
+            .expire(&redis_key, self.ttl.as_secs() as usize).ignore()
+            .query(con))
+    }
+
+    fn delete(&self, keys: &[String]) -> RedisResult<()> {
+        if keys.is_empty() {
+            return Ok(())
+        }
+        self.command(|con| redis::cmd("DEL").arg(keys).query(con))
+    }
+}
+
+struct CacheInner {
+    settle: chrono::Duration,
+    memory: Mutex<LruStore>,
+    redis: Option<RedisStore>,
+}
+
+impl CacheInner {
+    fn load_bucket(&self, sensor_pool: &mysql::Pool, key: CacheKey) -> ServiceResult<Arc<Vec<ReadingData>>> {
+        if let Some(x) = self.memory.lock().unwrap().get(&key) {
+            return Ok(x)
+        }
+
+        if let Some(redis) = &self.redis {
+            match redis.get(&key) {
+                Ok(Some(readings)) => {
+                    let readings = Arc::new(readings);
+                    self.memory.lock().unwrap().insert(key, readings.clone());
+                    return Ok(readings)
+                },
+                Ok(None) => {},
+                Err(e) => warn!("Cannot read the readings cache from Redis: {}", e),
+            }
+        }
+
+        let end = bucket_last(key.start, bucket_span(key.aggregation));
+        let readings = load_channel_readings_aggregated(sensor_pool, &key.ids, key.start, end, &key.excluded, key.aggregation)?;
+        if let Some(redis) = &self.redis {
+            if let Err(e) = redis.set(&key, &readings) {
+                warn!("Cannot write the readings cache to Redis: {}", e);
+            }
+        }
+        let readings = Arc::new(readings);
+        self.memory.lock().unwrap().insert(key, readings.clone());
+        Ok(readings)
+    }
+}
+
+/// Cache in front of load_channel_readings_aggregated, the default one is disabled
+#[derive(Clone, Default)]
+pub struct ReadingsCache {
+    inner: Option<Arc<CacheInner>>,
+}
+
+impl ReadingsCache {
+    pub fn new(config: ReadingsCacheConfig) -> Self {
+        let redis = config.redis_addr.and_then(|addr| match redis_client(&addr) {
+            Ok(client) => Some(RedisStore::new(client, config.redis_ttl)),
+            Err(e) => {
+                warn!("Invalid Redis address {}, the readings cache is only kept in memory: {}", addr, e);
+                None
+            },
+        });
+        // The memory copy of a replica wouldn't see the buckets dropped by the other ones
+        let max_readings = if redis.is_some() { 0 } else { config.max_readings };
+        ReadingsCache {
+            inner: Some(Arc::new(CacheInner {
+                settle: chrono::Duration::from_std(config.settle).unwrap_or_else(|_| chrono::Duration::max_value()),
+                memory: Mutex::new(LruStore::new(max_readings)),
+                redis,
+            })),
+        }
+    }
+
+    /// Same as load_channel_readings_aggregated, the buckets that ended before now (minus the
+    /// settle time) are read from the cache
+    pub fn load(&self, sensor_pool: &mysql::Pool, ids: &ChannelCnrIds, start: NaiveDateTime, end: NaiveDateTime, excluded: &[InvalidInterval], aggregation: ReadingsAggregation, now: NaiveDateTime) -> ServiceResult<Vec<ReadingData>> {
+        let inner = match &self.inner {
+            Some(x) => x,
+            None => return load_channel_readings_aggregated(sensor_pool, ids, start, end, excluded, aggregation),
+        };
+        let closed_until = now.checked_sub_signed(inner.settle)
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+        let span = bucket_span(aggregation);
+        let is_cacheable = |bucket: NaiveDateTime| {
+            let last = bucket_last(bucket, span);
+            last <= end && last < closed_until
+        };
+
+        let mut bucket = bucket_start(start, span);
+        if bucket < start {
+            bucket += chrono::Duration::seconds(span);
+        }
+        if !is_cacheable(bucket) {
+            return load_channel_readings_aggregated(sensor_pool, ids, start, end, excluded, aggregation)
+        }
+
+        let mut readings = Vec::new();
+        if start < bucket {
+            let last = bucket - chrono::Duration::microseconds(1);
+            readings.extend(load_channel_readings_aggregated(sensor_pool, ids, start, last, excluded, aggregation)?);
+        }
+        while is_cacheable(bucket) {
+            let last = bucket_last(bucket, span);
+            let key = CacheKey {
+                ids: ids.clone(),
+                aggregation,
+                start: bucket,
+                excluded: excluded.iter()
+                    .filter(|(from, to)| *from <= last && *to >= bucket)
+                    .cloned()
+                    .collect(),
+            };
+            readings.extend(inner.load_bucket(sensor_pool, key)?.iter().cloned());
+            bucket += chrono::Duration::seconds(span);
+        }
+        if bucket <= end {
+            readings.extend(load_channel_readings_aggregated(sensor_pool, ids, bucket, end, excluded, aggregation)?);
+        }
+        Ok(readings)
+    }
+
+    /// Drops the cached buckets that contain the new readings
+    pub fn invalidate(&self, new_readings: &[NewReading], now: NaiveDateTime) {
+        let inner = match &self.inner {
+            Some(x) => x,
+            None => return,
+        };
+        // The buckets after this aren't cached yet
+        let closed_until = now.checked_sub_signed(inner.settle)
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+        let touched: HashSet<(ChannelCnrIds, NaiveDateTime)> = new_readings.iter()
+            .filter(|x| x.date < closed_until)
+            .map(|x| ((x.site_id.clone(), x.sensor_id.clone(), x.channel_id.clone()), x.date))
+            .collect();
+        if touched.is_empty() {
+            return
+        }
+
+        inner.memory.lock().unwrap()
+            .remove_where(|key| touched.iter().any(|(ids, date)| key.contains(ids, *date)));
+
+        if let Some(redis) = &inner.redis {
+            let keys: HashSet<String> = touched.iter()
+                .flat_map(|(ids, date)| {
+                    [ReadingsAggregation::Raw, ReadingsAggregation::Hourly, ReadingsAggregation::Daily].iter()
+                        .map(|x| redis_key(ids, *x, bucket_start(*date, bucket_span(*x))))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            if let Err(e) = redis.delete(&keys.into_iter().collect::<Vec<_>>()) {
+                warn!("Cannot drop the readings cache from Redis: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(start: i64) -> CacheKey {
+        CacheKey {
+            ids: ("site".to_string(), "sensor".to_string(), "1".to_string()),
+            aggregation: ReadingsAggregation::Raw,
+            start: NaiveDateTime::from_timestamp(start, 0),
+            excluded: Vec::new(),
+        }
+    }
+
+    fn readings(count: i64) -> Arc<Vec<ReadingData>> {
+        Arc::new((0..count).map(|x| ReadingData {
+            date: NaiveDateTime::from_timestamp(x, 0),
+            value_min: x as f64,
+            value_avg: None,
+            value_max: None,
+            deviation: None,
+            error: None,
+        }).collect())
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        let span = bucket_span(ReadingsAggregation::Raw);
+        let start = bucket_start(NaiveDateTime::from_timestamp(1609000000, 0), span);
+        assert_eq!(start, NaiveDateTime::from_timestamp(1608940800, 0));
+        assert_eq!(bucket_last(start, span), NaiveDateTime::from_timestamp(1609027199, 999_999_000));
+        assert!(key(1608940800).contains(&key(0).ids, NaiveDateTime::from_timestamp(1609000000, 0)));
+        assert!(!key(1608940800).contains(&key(0).ids, NaiveDateTime::from_timestamp(1609027200, 0)));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut store = LruStore::new(10);
+        store.insert(key(0), readings(4));
+        store.insert(key(1), readings(4));
+        assert!(store.get(&key(0)).is_some());
+        // The second bucket is the least recently used one
+        store.insert(key(2), readings(4));
+        assert!(store.get(&key(0)).is_some());
+        assert!(store.get(&key(1)).is_none());
+        assert!(store.get(&key(2)).is_some());
+        assert_eq!(store.size, 10);
+
+        // Too big to be kept
+        store.insert(key(3), readings(20));
+        assert!(store.get(&key(3)).is_none());
+
+        store.remove_where(|x| x.start == NaiveDateTime::from_timestamp(0, 0));
+        assert!(store.get(&key(0)).is_none());
+        assert_eq!(store.size, 5);
+    }
+
+    #[test]
+    fn test_redis_skipped_after_failure() {
+        // Nothing listens on the port, the connection is refused
+        let store = RedisStore::new(redis_client("127.0.0.1:1").unwrap(), Duration::from_secs(60));
+        assert!(store.get(&key(0)).is_err());
+        // The next commands don't try to connect again
+        assert!(store.get(&key(0)).unwrap().is_none());
+        assert!(store.delete(&["key".to_string()]).is_ok());
+    }
+}
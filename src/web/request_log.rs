@@ -0,0 +1,230 @@
+//! Usage log of the GraphQL requests, it records who did what and how much it cost so that the
+//! admins can see which users and sites are loading the server.
+//!
+//! Only a fraction of the requests is recorded when `REQUEST_LOG_SAMPLE_RATE` is below 1.
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::error;
+
+use crate::AppData;
+use crate::models::IdType;
+
+use super::graphql_schema::Context;
+
+/// Statistics of a single GraphQL request
+pub struct RequestStats<'a> {
+    pub user_id: Option<IdType>,
+    pub site_id: Option<IdType>,
+    pub operation_name: Option<&'a str>,
+    /// Quota coins spent by the resolvers
+    pub complexity: i64,
+    /// Rows loaded by the list and readings resolvers
+    pub row_count: i64,
+    pub duration_ms: i32,
+    pub quota_rejected: bool,
+}
+
+/// Maximum length of the recorded operation name, longer names are truncated
+const OPERATION_NAME_MAX_LEN: usize = 128;
+
+fn is_sampled(sample_rate: f64) -> bool {
+    sample_rate >= 1.0 || (sample_rate > 0.0 && rand::random::<f64>() < sample_rate)
+}
+
+/// Records the request if it's sampled, a failure is only logged as it should never fail the
+/// request itself
+pub fn record_request(app: &AppData, stats: RequestStats) {
+    use crate::schema::request_log::dsl;
+
+    if !is_sampled(app.request_log_sample_rate) {
+        return
+    }
+
+    let operation_name = stats.operation_name
+        .map(|x| x.chars().take(OPERATION_NAME_MAX_LEN).collect::<String>());
+
+    let inserted = app.pool.get().map_err(|x| x.to_string()).and_then(|conn| {
+        diesel::insert_into(dsl::request_log)
+            .values((
+                dsl::created_at.eq(Utc::now().naive_utc()),
+                dsl::user_id.eq(stats.user_id),
+                dsl::site_id.eq(stats.site_id),
+                dsl::operation_name.eq(operation_name),
+                dsl::complexity.eq(stats.complexity),
+                dsl::row_count.eq(stats.row_count),
+                dsl::duration_ms.eq(stats.duration_ms),
+                dsl::quota_rejected.eq(stats.quota_rejected),
+            ))
+            .execute(&conn)
+            .map_err(|x| x.to_string())
+    });
+    if let Err(e) = inserted {
+        error!("Cannot record request in the request log: {}", e);
+    }
+}
+
+/// Requests done by an user on a site (or without a site) in a period
+#[derive(Debug, QueryableByName)]
+pub struct RequestUsage {
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Integer>"]
+    pub user_id: Option<IdType>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Integer>"]
+    pub site_id: Option<IdType>,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub requests: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub complexity: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub row_count: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub duration_ms: i64,
+}
+
+#[juniper::object(
+    description = "Requests done by an user on a site (or without a site) in a period",
+    Context = Context,
+)]
+impl RequestUsage {
+    /// Null for anonymous requests or deleted users
+    pub fn user_id(&self) -> Option<IdType> {
+        self.user_id
+    }
+
+    /// Null for requests that aren't about a single site
+    pub fn site_id(&self) -> Option<IdType> {
+        self.site_id
+    }
+
+    pub fn requests(&self) -> f64 {
+        self.requests as f64
+    }
+
+    /// Quota coins spent by the requests
+    pub fn complexity(&self) -> f64 {
+        self.complexity as f64
+    }
+
+    pub fn row_count(&self) -> f64 {
+        self.row_count as f64
+    }
+
+    pub fn duration_ms(&self) -> f64 {
+        self.duration_ms as f64
+    }
+}
+
+/// Sums the recorded requests by user and site, the busiest pairs come first.
+/// The totals only cover the sampled requests.
+pub fn load_usage(conn: &PgConnection, start: Option<NaiveDateTime>, end: Option<NaiveDateTime>) -> QueryResult<Vec<RequestUsage>> {
+    use diesel::sql_types::{Nullable, Timestamp};
+
+    diesel::sql_query("SELECT user_id, site_id, COUNT(*) AS requests, SUM(complexity)::BIGINT AS complexity, \
+            SUM(row_count)::BIGINT AS row_count, SUM(duration_ms)::BIGINT AS duration_ms \
+            FROM request_log \
+            WHERE ($1 IS NULL OR created_at >= $1) AND ($2 IS NULL OR created_at <= $2) \
+            GROUP BY user_id, site_id \
+            ORDER BY complexity DESC, user_id, site_id")
+        .bind::<Nullable<Timestamp>, _>(start)
+        .bind::<Nullable<Timestamp>, _>(end)
+        .load(conn)
+}
+
+/// Maximum number of operations returned by the usage statistics
+const USAGE_STATS_MAX_OPERATIONS: i64 = 20;
+
+/// Requests done by an user in a period
+#[derive(Debug, QueryableByName, juniper::GraphQLObject)]
+pub struct UserUsage {
+    /// Null for anonymous requests or deleted users
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Integer>"]
+    pub user_id: Option<IdType>,
+    #[sql_type = "diesel::sql_types::Double"]
+    pub requests: f64,
+    /// Quota coins spent by the requests
+    #[sql_type = "diesel::sql_types::Double"]
+    pub complexity: f64,
+    /// Requests rejected for exceeding the quota
+    #[sql_type = "diesel::sql_types::Double"]
+    pub quota_rejections: f64,
+}
+
+/// Requests with the same operation name in a period
+#[derive(Debug, QueryableByName, juniper::GraphQLObject)]
+pub struct OperationUsage {
+    /// Null for the requests sent without an operation name
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Varchar>"]
+    pub operation_name: Option<String>,
+    #[sql_type = "diesel::sql_types::Double"]
+    pub requests: f64,
+    /// Quota coins spent by the requests
+    #[sql_type = "diesel::sql_types::Double"]
+    pub complexity: f64,
+    #[sql_type = "diesel::sql_types::Double"]
+    pub avg_duration_ms: f64,
+}
+
+/// Requests about a site in a period
+#[derive(Debug, QueryableByName, juniper::GraphQLObject)]
+pub struct SiteUsage {
+    #[sql_type = "diesel::sql_types::Integer"]
+    pub site_id: IdType,
+    #[sql_type = "diesel::sql_types::Double"]
+    pub requests: f64,
+    /// Rows loaded by the requests
+    #[sql_type = "diesel::sql_types::Double"]
+    pub row_count: f64,
+}
+
+/// Aggregated request log of a period, the totals only cover the sampled requests
+#[derive(Debug, juniper::GraphQLObject)]
+pub struct UsageStats {
+    /// Users sorted by spent quota
+    pub requests_per_user: Vec<UserUsage>,
+    /// Operations sorted by spent quota, only the most expensive ones are returned
+    pub expensive_operations: Vec<OperationUsage>,
+    /// Requests rejected for exceeding the quota
+    pub quota_rejections: f64,
+    /// Sites sorted by number of requests
+    pub site_volume: Vec<SiteUsage>,
+}
+
+pub fn load_usage_stats(conn: &PgConnection, start: NaiveDateTime, end: NaiveDateTime) -> QueryResult<UsageStats> {
+    use diesel::sql_types::{BigInt, Timestamp};
+
+    let requests_per_user: Vec<UserUsage> = diesel::sql_query("SELECT user_id, COUNT(*)::FLOAT8 AS requests, \
+            SUM(complexity)::FLOAT8 AS complexity, COUNT(*) FILTER (WHERE quota_rejected)::FLOAT8 AS quota_rejections \
+            FROM request_log WHERE created_at BETWEEN $1 AND $2 \
+            GROUP BY user_id \
+            ORDER BY complexity DESC, user_id")
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .load(conn)?;
+
+    let expensive_operations = diesel::sql_query("SELECT operation_name, COUNT(*)::FLOAT8 AS requests, \
+            SUM(complexity)::FLOAT8 AS complexity, AVG(duration_ms)::FLOAT8 AS avg_duration_ms \
+            FROM request_log WHERE created_at BETWEEN $1 AND $2 \
+            GROUP BY operation_name \
+            ORDER BY complexity DESC, operation_name \
+            LIMIT $3")
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .bind::<BigInt, _>(USAGE_STATS_MAX_OPERATIONS)
+        .load(conn)?;
+
+    let site_volume = diesel::sql_query("SELECT site_id, COUNT(*)::FLOAT8 AS requests, SUM(row_count)::FLOAT8 AS row_count \
+            FROM request_log WHERE created_at BETWEEN $1 AND $2 AND site_id IS NOT NULL \
+            GROUP BY site_id \
+            ORDER BY requests DESC, site_id")
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .load(conn)?;
+
+    Ok(UsageStats {
+        quota_rejections: requests_per_user.iter().map(|x| x.quota_rejections).sum(),
+        requests_per_user,
+        expensive_operations,
+        site_volume,
+    })
+}
@@ -0,0 +1,435 @@
+//! REST facade over the core resources (sites, sensors, channels and readings) for the
+//! integrators that can't use GraphQL, the OpenAPI document is served at `/api/v1/openapi.json`.
+//!
+//! The resources are read-only, the same permission checks and quota costs of the GraphQL resolvers
+//! are used and the api keys are accepted like in the other REST endpoints.
+
+use std::time::Instant;
+
+use actix_web::{HttpResponse, web};
+use actix_web::http::header;
+use bigdecimal::ToPrimitive;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::AppData;
+use crate::models::{Channel, IdType, PermissionType, Sensor, Site};
+use crate::security::{PermissionCheckable, Principal};
+
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
+use super::db_helper::{load_invalid_intervals, load_live_channel, query_channel_cnr_ids, ReadingsAggregation};
+use super::errors::{ServiceError, ServiceResult};
+use super::graphql_schema::ReadingData;
+use super::graphql_service::quota_retry_after;
+use super::quota::{QuotaCostConfig, QuotaPool};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "OldMusa REST API", description = "Read-only access to the sites, sensors, channels and readings"),
+    paths(list_sites, get_site, list_site_sensors, get_sensor, list_sensor_channels, get_channel, list_channel_readings),
+    components(schemas(RestSite, RestSensor, RestChannel, RestReading, ReadingsAggregation)),
+)]
+pub struct RestApiDoc;
+
+#[derive(Serialize, ToSchema)]
+pub struct RestSite {
+    pub id: IdType,
+    pub name: Option<String>,
+    pub id_cnr: Option<String>,
+    /// Timestamp of the last measures checked by the alarms
+    pub clock: NaiveDateTime,
+}
+
+impl From<Site> for RestSite {
+    fn from(site: Site) -> Self {
+        RestSite {
+            id: site.id,
+            name: site.name,
+            id_cnr: site.id_cnr,
+            clock: site.clock,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestSensor {
+    pub id: IdType,
+    pub site_id: IdType,
+    pub id_cnr: Option<String>,
+    pub name: Option<String>,
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+    pub enabled: bool,
+    pub room_id: Option<IdType>,
+    /// Map the sensor is placed on, null for the main map of the site
+    pub map_id: Option<IdType>,
+}
+
+impl From<Sensor> for RestSensor {
+    fn from(sensor: Sensor) -> Self {
+        RestSensor {
+            id: sensor.id,
+            site_id: sensor.site_id,
+            id_cnr: sensor.id_cnr,
+            name: sensor.name,
+            loc_x: sensor.loc_x,
+            loc_y: sensor.loc_y,
+            enabled: sensor.enabled,
+            room_id: sensor.room_id,
+            map_id: sensor.map_id,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestChannel {
+    pub id: IdType,
+    pub sensor_id: IdType,
+    pub id_cnr: Option<String>,
+    pub name: Option<String>,
+    pub measure_unit: Option<String>,
+    pub range_min: Option<f64>,
+    pub range_max: Option<f64>,
+    pub alarmed: bool,
+}
+
+impl From<Channel> for RestChannel {
+    fn from(channel: Channel) -> Self {
+        RestChannel {
+            id: channel.id,
+            sensor_id: channel.sensor_id,
+            id_cnr: channel.id_cnr,
+            name: channel.name,
+            measure_unit: channel.measure_unit,
+            range_min: channel.range_min.as_ref().and_then(|x| x.to_f64()),
+            range_max: channel.range_max.as_ref().and_then(|x| x.to_f64()),
+            alarmed: channel.alarmed,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestReading {
+    pub date: NaiveDateTime,
+    pub value_min: f64,
+    pub value_avg: Option<f64>,
+    pub value_max: Option<f64>,
+    pub deviation: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl From<ReadingData> for RestReading {
+    fn from(reading: ReadingData) -> Self {
+        RestReading {
+            date: reading.date,
+            value_min: reading.value_min,
+            value_avg: reading.value_avg,
+            value_max: reading.value_max,
+            deviation: reading.deviation,
+            error: reading.error,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReadingsQuery {
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    /// How the readings are grouped, raw by default
+    aggregation: Option<ReadingsAggregation>,
+    /// Also return the readings marked as invalid
+    include_invalid: Option<bool>,
+}
+
+fn load_sites(ctx: &AppData, principal: &Principal) -> ServiceResult<Vec<Site>> {
+    use crate::schema::site::dsl as site_dsl;
+    use crate::schema::user_access::dsl as access_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let query = site_dsl::site
+        .filter(site_dsl::deleted_at.is_null())
+        .order(site_dsl::id)
+        .into_boxed();
+
+    let query = match principal {
+        Principal::User(user) if user.get_permission() == PermissionType::Admin => query,
+        Principal::User(user) => {
+            let visible_sites = access_dsl::user_access
+                .filter(access_dsl::user_id.eq(user.id))
+                .select(access_dsl::site_id);
+            query.filter(site_dsl::id.eq_any(visible_sites))
+        },
+        Principal::ApiKey(key) => match key.site_id {
+            Some(site_id) => query.filter(site_dsl::id.eq(site_id)),
+            None => query,
+        },
+    };
+
+    Ok(query.load::<Site>(&conn)?)
+}
+
+fn load_site(ctx: &AppData, principal: &Principal, site_id: IdType) -> ServiceResult<Site> {
+    use crate::schema::site::dsl;
+
+    principal.ensure_site_visible(ctx, site_id)?;
+    let conn = ctx.pool.get()?;
+    dsl::site.find(site_id)
+        .filter(dsl::deleted_at.is_null())
+        .first::<Site>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Site".to_string()))
+}
+
+fn load_site_sensors(ctx: &AppData, principal: &Principal, site_id: IdType) -> ServiceResult<Vec<Sensor>> {
+    use crate::schema::sensor::dsl;
+
+    load_site(ctx, principal, site_id)?;
+    let conn = ctx.pool.get()?;
+    Ok(dsl::sensor.filter(dsl::site_id.eq(site_id))
+        .filter(dsl::deleted_at.is_null())
+        .order(dsl::id)
+        .load::<Sensor>(&conn)?)
+}
+
+fn load_sensor(ctx: &AppData, principal: &Principal, sensor_id: IdType) -> ServiceResult<Sensor> {
+    use crate::schema::sensor::dsl;
+
+    principal.ensure_sensor_visible(ctx, sensor_id)?;
+    let conn = ctx.pool.get()?;
+    dsl::sensor.find(sensor_id)
+        .filter(dsl::deleted_at.is_null())
+        .first::<Sensor>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Sensor".to_string()))
+}
+
+fn load_sensor_channels(ctx: &AppData, principal: &Principal, sensor_id: IdType) -> ServiceResult<Vec<Channel>> {
+    use crate::schema::channel::dsl;
+
+    load_sensor(ctx, principal, sensor_id)?;
+    let conn = ctx.pool.get()?;
+    Ok(dsl::channel.filter(dsl::sensor_id.eq(sensor_id))
+        .filter(dsl::deleted_at.is_null())
+        .order(dsl::id)
+        .load::<Channel>(&conn)?)
+}
+
+fn load_channel(ctx: &AppData, principal: &Principal, channel_id: IdType) -> ServiceResult<Channel> {
+    principal.ensure_channel_visible(ctx, channel_id)?;
+    load_live_channel(&*ctx.pool.get()?, channel_id)
+}
+
+fn load_readings(ctx: &AppData, principal: &Principal, channel_id: IdType, query: &ReadingsQuery) -> ServiceResult<Vec<ReadingData>> {
+    let channel = load_channel(ctx, principal, channel_id)?;
+
+    if query.end < query.start {
+        return Err(ServiceError::BadRequest("start is after end".to_string()))
+    }
+
+    let ids = match query_channel_cnr_ids(&ctx.pool, channel_id, channel.id_cnr.as_deref())? {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = if query.include_invalid.unwrap_or(false) {
+        Vec::new()
+    } else {
+        load_invalid_intervals(&*ctx.pool.get()?, channel_id, query.start, query.end)?
+    };
+    let aggregation = query.aggregation.unwrap_or(ReadingsAggregation::Raw);
+    ctx.readings_cache.load(&ctx.sensor_pool, &ids, query.start, query.end, &invalid, aggregation, Utc::now().naive_utc())
+}
+
+/// User charged for the request, the admins and the api keys (like the anonymous GraphQL
+/// requests) don't have a quota
+fn quota_user(ctx: &AppData, principal: &Principal) -> Option<IdType> {
+    match principal {
+        Principal::User(user) if ctx.quota_bank.is_some() && user.get_permission() != PermissionType::Admin => Some(user.id),
+        _ => None,
+    }
+}
+
+/// Authenticates the request and runs the loader on the blocking thread pool, the result is
+/// converted to its REST representation.
+/// The read is charged to the read pool of the user as the GraphQL resolver named `operation`
+/// would be, the request is rejected with a Retry-After header when the pool is empty.
+async fn run_loader<T, R, F>(
+    ctx: web::Data<AppData>,
+    auth: Authenticator,
+    operation: &'static str,
+    cost: fn(&QuotaCostConfig, &T) -> i64,
+    loader: F,
+) -> ServiceResult<HttpResponse>
+    where T: Into<R> + Send + 'static,
+          R: Serialize,
+          F: FnOnce(&AppData, &Principal) -> ServiceResult<T> + Send + 'static {
+    let res = run_blocking(move || {
+        let principal = auth.principal_required(&ctx)?;
+        let quota_user = quota_user(&ctx, &principal);
+        if let (Some(bank), Some(user)) = (&ctx.quota_bank, quota_user) {
+            if bank.pool(QuotaPool::Read).get_quota_balance(Instant::now(), user) <= 0 {
+                return Ok(Err(quota_retry_after(&ctx, user, QuotaPool::Read)))
+            }
+        }
+
+        let res = loader(&ctx, &principal)?;
+
+        if let (Some(bank), Some(user)) = (&ctx.quota_bank, quota_user) {
+            let coins = ctx.quota_costs.operation_cost(operation, cost(&ctx.quota_costs, &res));
+            let pool = bank.pool(QuotaPool::Read);
+            let now = Instant::now();
+            pool.add_quota_balance(now, user, -coins);
+            pool.record_spending(now, user, operation, coins);
+        }
+        Ok(Ok(res))
+    }).await?;
+
+    match res {
+        Ok(res) => Ok(HttpResponse::Ok().json(res.into())),
+        Err(Some(secs)) => Ok(HttpResponse::TooManyRequests()
+            .header(header::RETRY_AFTER, secs.to_string())
+            .finish()),
+        Err(None) => Err(ServiceError::TooManyRequests),
+    }
+}
+
+/// Lists the sites visible to the user (or to the api key)
+#[utoipa::path(
+    get,
+    path = "/api/v1/sites",
+    responses(
+        (status = 200, description = "Visible sites", body = [RestSite]),
+        (status = 401, description = "Login required"),
+    )
+)]
+pub async fn list_sites(ctx: web::Data<AppData>, auth: Authenticator) -> ServiceResult<HttpResponse> {
+    run_loader::<_, Vec<RestSite>, _>(ctx, auth, "sites", |costs, x| x.len() as i64 * costs.db_query, |ctx, principal| {
+        Ok(load_sites(ctx, principal)?.into_iter().map(RestSite::from).collect::<Vec<_>>())
+    }).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sites/{id}",
+    params(("id" = i32, Path, description = "Id of the site")),
+    responses(
+        (status = 200, body = RestSite),
+        (status = 404, description = "Site not found"),
+    )
+)]
+pub async fn get_site(ctx: web::Data<AppData>, auth: Authenticator, id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    run_loader::<_, RestSite, _>(ctx, auth, "site", |costs, _| 2 * costs.db_query, move |ctx, principal| load_site(ctx, principal, id)).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sites/{id}/sensors",
+    params(("id" = i32, Path, description = "Id of the site")),
+    responses(
+        (status = 200, description = "Sensors of the site", body = [RestSensor]),
+        (status = 404, description = "Site not found"),
+    )
+)]
+pub async fn list_site_sensors(ctx: web::Data<AppData>, auth: Authenticator, id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    run_loader::<_, Vec<RestSensor>, _>(ctx, auth, "Site.sensors", |costs, x| x.len() as i64 * costs.db_query, move |ctx, principal| {
+        Ok(load_site_sensors(ctx, principal, id)?.into_iter().map(RestSensor::from).collect::<Vec<_>>())
+    }).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{id}",
+    params(("id" = i32, Path, description = "Id of the sensor")),
+    responses(
+        (status = 200, body = RestSensor),
+        (status = 404, description = "Sensor not found"),
+    )
+)]
+pub async fn get_sensor(ctx: web::Data<AppData>, auth: Authenticator, id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    run_loader::<_, RestSensor, _>(ctx, auth, "sensor", |costs, _| 2 * costs.db_query, move |ctx, principal| load_sensor(ctx, principal, id)).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{id}/channels",
+    params(("id" = i32, Path, description = "Id of the sensor")),
+    responses(
+        (status = 200, description = "Channels of the sensor", body = [RestChannel]),
+        (status = 404, description = "Sensor not found"),
+    )
+)]
+pub async fn list_sensor_channels(ctx: web::Data<AppData>, auth: Authenticator, id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    run_loader::<_, Vec<RestChannel>, _>(ctx, auth, "Sensor.channels", |costs, x| x.len() as i64 * costs.db_query, move |ctx, principal| {
+        Ok(load_sensor_channels(ctx, principal, id)?.into_iter().map(RestChannel::from).collect::<Vec<_>>())
+    }).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/channels/{id}",
+    params(("id" = i32, Path, description = "Id of the channel")),
+    responses(
+        (status = 200, body = RestChannel),
+        (status = 404, description = "Channel not found"),
+    )
+)]
+pub async fn get_channel(ctx: web::Data<AppData>, auth: Authenticator, id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    run_loader::<_, RestChannel, _>(ctx, auth, "channel", |costs, _| 2 * costs.db_query, move |ctx, principal| load_channel(ctx, principal, id)).await
+}
+
+/// Readings of the channel between start and end, the readings marked as invalid are skipped
+/// unless include_invalid is true
+#[utoipa::path(
+    get,
+    path = "/api/v1/channels/{id}/readings",
+    params(("id" = i32, Path, description = "Id of the channel"), ReadingsQuery),
+    responses(
+        (status = 200, description = "Readings of the channel", body = [RestReading]),
+        (status = 400, description = "Start is after end"),
+        (status = 404, description = "Channel not found"),
+        (status = 429, description = "Read quota exhausted, retry after the Retry-After seconds"),
+    )
+)]
+pub async fn list_channel_readings(
+    ctx: web::Data<AppData>,
+    auth: Authenticator,
+    id: web::Path<IdType>,
+    query: web::Query<ReadingsQuery>,
+) -> ServiceResult<HttpResponse> {
+    let id = *id;
+    let query = query.into_inner();
+    run_loader::<_, Vec<RestReading>, _>(ctx, auth, "Channel.readings", |costs, _| costs.db_query * 10, move |ctx, principal| {
+        Ok(load_readings(ctx, principal, id, &query)?.into_iter().map(RestReading::from).collect::<Vec<_>>())
+    }).await
+}
+
+pub async fn openapi_json() -> ServiceResult<HttpResponse> {
+    let document = RestApiDoc::openapi().to_json()
+        .map_err(|e| ServiceError::InternalServerError(format!("OpenAPI error: {}", e)))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(document))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/v1")
+            .service(web::resource("/openapi.json").route(web::get().to(openapi_json)))
+            .service(web::resource("/sites").route(web::get().to(list_sites)))
+            .service(web::resource("/sites/{id}").route(web::get().to(get_site)))
+            .service(web::resource("/sites/{id}/sensors").route(web::get().to(list_site_sensors)))
+            .service(web::resource("/sensors/{id}").route(web::get().to(get_sensor)))
+            .service(web::resource("/sensors/{id}/channels").route(web::get().to(list_sensor_channels)))
+            .service(web::resource("/channels/{id}").route(web::get().to(get_channel)))
+            .service(web::resource("/channels/{id}/readings").route(web::get().to(list_channel_readings)))
+    );
+}
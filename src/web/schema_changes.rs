@@ -0,0 +1,168 @@
+//! Differences between the running GraphQL schema and the one of the previous release, so that
+//! the client developers can see what changed after an upgrade.
+//!
+//! The schema is flattened to a map of paths (`Type`, `Type.field`, `Type.field(arg)`) to their
+//! deprecation reason (null if the member isn't deprecated). The snapshot of the previous release
+//! is embedded at compile time, it's regenerated with
+//! `cargo test --test graphql_test update_schema_snapshot -- --ignored` before every release.
+
+use std::collections::BTreeMap;
+
+use juniper::IntrospectionFormat;
+use serde_json::Value;
+
+use super::errors::{ServiceError, ServiceResult};
+use super::graphql_schema::{Context, Schema};
+
+/// Flattened schema of the previous release
+const PREVIOUS_RELEASE_SNAPSHOT: &str = include_str!("schema_snapshot.json");
+
+/// Path of every type, field, argument and enum value mapped to its deprecation reason
+pub type SchemaSnapshot = BTreeMap<String, Option<String>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum SchemaChangeKind {
+    Added,
+    Removed,
+    Deprecated,
+    Undeprecated,
+}
+
+#[derive(Debug, PartialEq, juniper::GraphQLObject)]
+pub struct SchemaChange {
+    pub kind: SchemaChangeKind,
+    /// Changed member (ex. Site.name or Site.name(locale))
+    pub path: String,
+    /// Deprecation reason, only for deprecated members
+    pub reason: Option<String>,
+}
+
+fn deprecation(member: &Value) -> Option<String> {
+    if member["isDeprecated"].as_bool().unwrap_or(false) {
+        Some(member["deprecationReason"].as_str().unwrap_or_default().to_string())
+    } else {
+        None
+    }
+}
+
+fn members(value: &Value) -> impl Iterator<Item=&Value> {
+    value.as_array().into_iter().flatten()
+}
+
+/// Flattens the result of the introspection query, the introspection types are skipped.
+pub fn flatten_introspection(introspection: &Value) -> SchemaSnapshot {
+    let mut res = SchemaSnapshot::new();
+
+    let types = members(&introspection["__schema"]["types"])
+        .filter(|x| x["name"].as_str().is_some_and(|name| !name.starts_with("__")));
+    for schema_type in types {
+        let type_name = schema_type["name"].as_str().unwrap_or_default();
+        res.insert(type_name.to_string(), None);
+
+        for field in members(&schema_type["fields"]) {
+            let field_name = field["name"].as_str().unwrap_or_default();
+            res.insert(format!("{}.{}", type_name, field_name), deprecation(field));
+            for arg in members(&field["args"]) {
+                let arg_name = arg["name"].as_str().unwrap_or_default();
+                res.insert(format!("{}.{}({})", type_name, field_name, arg_name), None);
+            }
+        }
+        for member in members(&schema_type["inputFields"]).chain(members(&schema_type["enumValues"])) {
+            let member_name = member["name"].as_str().unwrap_or_default();
+            res.insert(format!("{}.{}", type_name, member_name), deprecation(member));
+        }
+    }
+
+    res
+}
+
+/// Flattened snapshot of the running schema
+pub fn current_snapshot(schema: &Schema, ctx: &Context) -> ServiceResult<SchemaSnapshot> {
+    let (introspection, errors) = juniper::introspect(schema, ctx, IntrospectionFormat::WithoutDescriptions)
+        .map_err(|x| ServiceError::InternalServerError(format!("Introspection error: {:?}", x)))?;
+    if !errors.is_empty() {
+        return Err(ServiceError::InternalServerError(format!("Introspection error: {:?}", errors)))
+    }
+    let introspection = serde_json::to_value(&introspection)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+    Ok(flatten_introspection(&introspection))
+}
+
+pub fn previous_release_snapshot() -> ServiceResult<SchemaSnapshot> {
+    serde_json::from_str(PREVIOUS_RELEASE_SNAPSHOT)
+        .map_err(|x| ServiceError::InternalServerError(format!("Invalid schema snapshot: {}", x)))
+}
+
+/// Changes needed to go from the previous schema to the current one, sorted by path
+pub fn diff_snapshots(previous: &SchemaSnapshot, current: &SchemaSnapshot) -> Vec<SchemaChange> {
+    let removed = previous.keys()
+        .filter(|x| !current.contains_key(*x))
+        .map(|path| SchemaChange {
+            kind: SchemaChangeKind::Removed,
+            path: path.clone(),
+            reason: None,
+        });
+
+    let changed = current.iter().filter_map(|(path, reason)| {
+        let kind = match (previous.get(path), reason) {
+            (None, _) => SchemaChangeKind::Added,
+            (Some(None), Some(_)) => SchemaChangeKind::Deprecated,
+            (Some(Some(_)), None) => SchemaChangeKind::Undeprecated,
+            _ => return None,
+        };
+        Some(SchemaChange {
+            kind,
+            path: path.clone(),
+            reason: reason.clone(),
+        })
+    });
+
+    let mut res: Vec<SchemaChange> = removed.chain(changed).collect();
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_snapshots() {
+        let previous = flatten_introspection(&json!({ "__schema": { "types": [
+            { "name": "__Type", "fields": [] },
+            { "name": "Site", "fields": [
+                { "name": "id", "args": [], "isDeprecated": false },
+                { "name": "imageUrl", "args": [], "isDeprecated": false },
+                { "name": "clock", "args": [], "isDeprecated": true, "deprecationReason": "Unused" },
+            ] },
+        ] } }));
+        assert!(!previous.contains_key("__Type"));
+
+        let current = flatten_introspection(&json!({ "__schema": { "types": [
+            { "name": "Site", "fields": [
+                { "name": "id", "args": [], "isDeprecated": false },
+                { "name": "name", "args": [{ "name": "locale" }], "isDeprecated": false },
+                { "name": "clock", "args": [], "isDeprecated": false },
+            ] },
+            { "name": "Locale", "enumValues": [
+                { "name": "IT", "isDeprecated": true, "deprecationReason": null },
+            ] },
+        ] } }));
+
+        let change = |kind, path: &str, reason: Option<&str>| SchemaChange {
+            kind,
+            path: path.to_string(),
+            reason: reason.map(|x| x.to_string()),
+        };
+        assert_eq!(diff_snapshots(&previous, &current), vec![
+            change(SchemaChangeKind::Added, "Locale", None),
+            change(SchemaChangeKind::Added, "Locale.IT", Some("")),
+            change(SchemaChangeKind::Undeprecated, "Site.clock", None),
+            change(SchemaChangeKind::Removed, "Site.imageUrl", None),
+            change(SchemaChangeKind::Added, "Site.name", None),
+            change(SchemaChangeKind::Added, "Site.name(locale)", None),
+        ]);
+    }
+}
@@ -0,0 +1,311 @@
+//! Configuration of a whole site as JSON, used to clone a museum setup to a staging server or to
+//! back it up before risky edits.
+//!
+//! Only the configuration is included: the readings, the alarm history and the map images are
+//! left out. The sensors refer to their room and map by position in the rooms and maps lists.
+
+use std::collections::BTreeMap;
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Channel, IdType, Room, Sensor, Site, SiteMap};
+
+use super::errors::{ServiceError, ServiceResult};
+
+/// Increased when the format changes in an incompatible way
+pub const SITE_CONFIG_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SiteConfig {
+    pub version: u32,
+    pub name: Option<String>,
+    pub id_cnr: Option<String>,
+    #[serde(default)]
+    pub name_translations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub rooms: Vec<RoomConfig>,
+    #[serde(default)]
+    pub maps: Vec<MapConfig>,
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RoomConfig {
+    pub name: String,
+    pub floor: Option<i32>,
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+}
+
+/// Additional map of the site, the sizes are only informative as the images aren't copied
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct MapConfig {
+    pub name: String,
+    pub image_width: Option<i32>,
+    pub image_height: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SensorConfig {
+    pub id_cnr: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub name_translations: BTreeMap<String, String>,
+    pub loc_x: Option<i32>,
+    pub loc_y: Option<i32>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub max_silence: Option<i32>,
+    /// Position of the room in the rooms of the site
+    pub room: Option<usize>,
+    /// Position of the map in the maps of the site, None for the main map
+    pub map: Option<usize>,
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ChannelConfig {
+    pub id_cnr: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub name_translations: BTreeMap<String, String>,
+    pub measure_unit: Option<String>,
+    pub range_min: Option<f64>,
+    pub range_max: Option<f64>,
+    pub max_delta_per_hour: Option<f64>,
+    pub hysteresis: Option<f64>,
+    pub alarm_delay: Option<i32>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn translations_from_json(value: &serde_json::Value) -> BTreeMap<String, String> {
+    value.as_object()
+        .map(|x| x.iter()
+            .filter_map(|(locale, name)| name.as_str().map(|name| (locale.clone(), name.to_string())))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn translations_to_json(translations: &BTreeMap<String, String>) -> serde_json::Value {
+    serde_json::Value::Object(translations.iter()
+        .map(|(locale, name)| (locale.clone(), serde_json::Value::String(name.clone())))
+        .collect())
+}
+
+fn decimal_to_f64(x: &Option<BigDecimal>) -> Option<f64> {
+    x.as_ref().and_then(|x| x.to_f64())
+}
+
+fn decimal_from_f64(x: Option<f64>) -> Option<BigDecimal> {
+    x.map(BigDecimal::from)
+}
+
+/// Reads the configuration of the site, the deleted sensors and channels are left out
+pub fn load_site_config(conn: &PgConnection, site_id: IdType) -> ServiceResult<SiteConfig> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        room::dsl as room_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+        site_map::dsl as map_dsl,
+    };
+
+    let site = site_dsl::site.find(site_id)
+        .filter(site_dsl::deleted_at.is_null())
+        .first::<Site>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Site".to_string()))?;
+    let rooms = room_dsl::room.filter(room_dsl::site_id.eq(site_id))
+        .order(room_dsl::id)
+        .load::<Room>(conn)?;
+    let maps = map_dsl::site_map.filter(map_dsl::site_id.eq(site_id))
+        .order(map_dsl::id)
+        .load::<SiteMap>(conn)?;
+    let sensors = sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(site_id))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .order(sensor_dsl::id)
+        .load::<Sensor>(conn)?;
+    let channels = channel_dsl::channel
+        .filter(channel_dsl::sensor_id.eq_any(sensors.iter().map(|x| x.id).collect::<Vec<_>>()))
+        .filter(channel_dsl::deleted_at.is_null())
+        .order(channel_dsl::id)
+        .load::<Channel>(conn)?;
+
+    let sensors = sensors.into_iter()
+        .map(|sensor| SensorConfig {
+            room: sensor.room_id.and_then(|id| rooms.iter().position(|x| x.id == id)),
+            map: sensor.map_id.and_then(|id| maps.iter().position(|x| x.id == id)),
+            channels: channels.iter()
+                .filter(|x| x.sensor_id == sensor.id)
+                .map(|x| ChannelConfig {
+                    id_cnr: x.id_cnr.clone(),
+                    name: x.name.clone(),
+                    name_translations: translations_from_json(&x.name_translations),
+                    measure_unit: x.measure_unit.clone(),
+                    range_min: decimal_to_f64(&x.range_min),
+                    range_max: decimal_to_f64(&x.range_max),
+                    max_delta_per_hour: decimal_to_f64(&x.max_delta_per_hour),
+                    hysteresis: decimal_to_f64(&x.hysteresis),
+                    alarm_delay: x.alarm_delay,
+                })
+                .collect(),
+            id_cnr: sensor.id_cnr,
+            name: sensor.name,
+            name_translations: translations_from_json(&sensor.name_translations),
+            loc_x: sensor.loc_x,
+            loc_y: sensor.loc_y,
+            enabled: sensor.enabled,
+            max_silence: sensor.max_silence,
+        })
+        .collect();
+
+    Ok(SiteConfig {
+        version: SITE_CONFIG_VERSION,
+        name: site.name,
+        id_cnr: site.id_cnr,
+        name_translations: translations_from_json(&site.name_translations),
+        rooms: rooms.into_iter()
+            .map(|x| RoomConfig { name: x.name, floor: x.floor, loc_x: x.loc_x, loc_y: x.loc_y })
+            .collect(),
+        maps: maps.into_iter()
+            .map(|x| MapConfig { name: x.name, image_width: x.image_width, image_height: x.image_height })
+            .collect(),
+        sensors,
+    })
+}
+
+/// Checks the version and the room and map references before anything is written
+pub fn validate_site_config(config: &SiteConfig) -> ServiceResult<()> {
+    if config.version != SITE_CONFIG_VERSION {
+        return Err(ServiceError::BadRequest(format!("Unsupported site config version {}", config.version)))
+    }
+    for sensor in config.sensors.iter() {
+        if sensor.room.is_some_and(|x| x >= config.rooms.len()) {
+            return Err(ServiceError::BadRequest("A sensor refers to a missing room".to_string()))
+        }
+        if sensor.map.is_some_and(|x| x >= config.maps.len()) {
+            return Err(ServiceError::BadRequest("A sensor refers to a missing map".to_string()))
+        }
+    }
+    Ok(())
+}
+
+/// Creates a new site from the configuration, it should be run in a transaction so that a
+/// failure doesn't leave a partial site. The maps are created without an image.
+pub fn import_site_config(conn: &PgConnection, config: &SiteConfig, now: NaiveDateTime) -> ServiceResult<Site> {
+    use crate::schema::{
+        channel::dsl as channel_dsl,
+        room::dsl as room_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+        site_map::dsl as map_dsl,
+    };
+
+    validate_site_config(config)?;
+
+    let site = diesel::insert_into(site_dsl::site)
+        .values((
+            site_dsl::name.eq(&config.name),
+            site_dsl::id_cnr.eq(&config.id_cnr),
+            site_dsl::clock.eq(now),
+            site_dsl::name_translations.eq(translations_to_json(&config.name_translations)),
+        ))
+        .get_result::<Site>(conn)?;
+
+    let mut room_ids = Vec::with_capacity(config.rooms.len());
+    for room in config.rooms.iter() {
+        room_ids.push(diesel::insert_into(room_dsl::room)
+            .values((
+                room_dsl::site_id.eq(site.id),
+                room_dsl::name.eq(&room.name),
+                room_dsl::floor.eq(room.floor),
+                room_dsl::loc_x.eq(room.loc_x),
+                room_dsl::loc_y.eq(room.loc_y),
+            ))
+            .returning(room_dsl::id)
+            .get_result::<IdType>(conn)?);
+    }
+
+    let mut map_ids = Vec::with_capacity(config.maps.len());
+    for map in config.maps.iter() {
+        map_ids.push(diesel::insert_into(map_dsl::site_map)
+            .values((
+                map_dsl::site_id.eq(site.id),
+                map_dsl::name.eq(&map.name),
+            ))
+            .returning(map_dsl::id)
+            .get_result::<IdType>(conn)?);
+    }
+
+    for sensor in config.sensors.iter() {
+        let sensor_id = diesel::insert_into(sensor_dsl::sensor)
+            .values((
+                sensor_dsl::site_id.eq(site.id),
+                sensor_dsl::id_cnr.eq(&sensor.id_cnr),
+                sensor_dsl::name.eq(&sensor.name),
+                sensor_dsl::name_translations.eq(translations_to_json(&sensor.name_translations)),
+                sensor_dsl::loc_x.eq(sensor.loc_x),
+                sensor_dsl::loc_y.eq(sensor.loc_y),
+                sensor_dsl::enabled.eq(sensor.enabled),
+                sensor_dsl::max_silence.eq(sensor.max_silence),
+                sensor_dsl::room_id.eq(sensor.room.map(|x| room_ids[x])),
+                sensor_dsl::map_id.eq(sensor.map.map(|x| map_ids[x])),
+            ))
+            .returning(sensor_dsl::id)
+            .get_result::<IdType>(conn)?;
+
+        for channel in sensor.channels.iter() {
+            diesel::insert_into(channel_dsl::channel)
+                .values((
+                    channel_dsl::sensor_id.eq(sensor_id),
+                    channel_dsl::id_cnr.eq(&channel.id_cnr),
+                    channel_dsl::name.eq(&channel.name),
+                    channel_dsl::name_translations.eq(translations_to_json(&channel.name_translations)),
+                    channel_dsl::measure_unit.eq(&channel.measure_unit),
+                    channel_dsl::range_min.eq(decimal_from_f64(channel.range_min)),
+                    channel_dsl::range_max.eq(decimal_from_f64(channel.range_max)),
+                    channel_dsl::max_delta_per_hour.eq(decimal_from_f64(channel.max_delta_per_hour)),
+                    channel_dsl::hysteresis.eq(decimal_from_f64(channel.hysteresis)),
+                    channel_dsl::alarm_delay.eq(channel.alarm_delay),
+                ))
+                .execute(conn)?;
+        }
+    }
+
+    Ok(site)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_site_config() {
+        let mut config: SiteConfig = serde_json::from_str(r#"{
+            "version": 1,
+            "name": "Museum",
+            "id_cnr": null,
+            "rooms": [{ "name": "Hall", "floor": 0, "loc_x": null, "loc_y": null }],
+            "sensors": [{ "id_cnr": "S1", "name": null, "loc_x": 3, "loc_y": 4, "max_silence": null, "room": 0, "map": null }]
+        }"#).unwrap();
+        assert!(config.sensors[0].enabled);
+        assert!(config.maps.is_empty());
+        assert!(validate_site_config(&config).is_ok());
+
+        config.sensors[0].map = Some(0);
+        assert!(validate_site_config(&config).is_err());
+        config.sensors[0].map = None;
+
+        config.version = SITE_CONFIG_VERSION + 1;
+        assert!(validate_site_config(&config).is_err());
+    }
+}
@@ -1,176 +1,398 @@
-use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::Cursor;
 use std::string::ToString;
 
-use actix_files::NamedFile;
-use actix_identity::Identity;
-use actix_web::{error, Error, HttpResponse, web};
+use actix_web::{Error, HttpRequest, HttpResponse, web};
 use actix_web::error::BlockingError;
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 use futures::StreamExt;
 use serde::Deserialize;
 use diesel::prelude::*;
 
 use crate::AppData;
-use crate::models::{IdType, User};
+use crate::config::Resolver;
+use crate::models::IdType;
 use crate::security::PermissionCheckable;
 
+use super::auth::Authenticator;
+use super::blocking::run_blocking;
 use super::errors::{ServiceError, ServiceResult};
+use super::map_storage::MapStorage;
 
-#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct ImageSizeData {
-    #[serde(rename = "width")]
     to_w: i32,
-    #[serde(rename = "height")]
     to_h: i32,
 }
 
-pub fn get_file_from_site(site_id: IdType) -> std::io::Result<PathBuf> {
-    let mut file_path = PathBuf::new();
-    file_path.push("site_maps");
-    if !file_path.exists() {
-        fs::create_dir(&file_path)?;
+/// Size used to place the sensors on the map, the one of the image if missing
+#[derive(Clone, Copy, Deserialize)]
+pub struct ImageSizeQuery {
+    width: Option<i32>,
+    height: Option<i32>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct ImageDownloadQuery {
+    /// Downloads the small preview of the map instead of the full image
+    thumbnail: Option<bool>,
+}
+
+/// Content types accepted for the site maps
+const MAP_CONTENT_TYPES: [&str; 2] = ["image/png", "image/jpeg"];
+
+/// Limits of the uploaded site maps
+#[derive(Clone, Debug)]
+pub struct MapImageConfig {
+    pub max_bytes: usize,
+    /// Maximum width and height of the image
+    pub max_dimension: u32,
+    /// The thumbnails fit in a square of this side
+    pub thumbnail_size: u32,
+}
+
+impl Default for MapImageConfig {
+    fn default() -> Self {
+        MapImageConfig {
+            max_bytes: 10 * 1024 * 1024,
+            max_dimension: 8192,
+            thumbnail_size: 256,
+        }
+    }
+}
+
+/// `[map_images]` section of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapImageSection {
+    pub max_size_mb: Option<usize>,
+    pub max_dimension: Option<u32>,
+    pub thumbnail_size: Option<u32>,
+}
+
+impl MapImageConfig {
+    pub fn resolve(r: &mut Resolver, file: MapImageSection) -> Self {
+        let default = MapImageConfig::default();
+        MapImageConfig {
+            max_bytes: r.optional("SITE_MAPS_MAX_SIZE_MB", file.max_size_mb).map_or(default.max_bytes, |x| x * 1024 * 1024),
+            max_dimension: r.optional("SITE_MAPS_MAX_DIMENSION", file.max_dimension).unwrap_or(default.max_dimension),
+            thumbnail_size: r.optional("SITE_MAPS_THUMBNAIL_SIZE", file.thumbnail_size).unwrap_or(default.thumbnail_size),
+        }
     }
-    file_path.push(format!("{}", site_id));
-    Ok(file_path)
 }
 
-fn parse_user_required(ctx: &AppData, identity: Identity) -> ServiceResult<User> {
-    Ok(identity.identity().as_ref()
-        .and_then(|x| ctx.auth_cache.parse_identity(&ctx, x).transpose())
-        .ok_or(ServiceError::LoginRequired)??)
+/// Key of the main map of the site in the map storage
+pub fn get_file_from_site(site_id: IdType) -> String {
+    site_id.to_string()
 }
 
-fn ensure_admin(ctx: &AppData, identity: Identity) -> ServiceResult<()> {
-    parse_user_required(ctx, identity)?.ensure_admin()
+/// Key of one of the additional maps of the site (see the site_map table)
+pub fn get_file_from_site_map(site_id: IdType, map_id: IdType) -> String {
+    format!("{}_{}", site_id, map_id)
 }
 
-fn ensure_site_visible(ctx: &AppData, identity: Identity, site_id: IdType) -> ServiceResult<()> {
-    parse_user_required(ctx, identity)?.ensure_site_visible(ctx, site_id)
+/// Key of the thumbnail of a map
+pub fn get_thumbnail_file(key: &str) -> String {
+    format!("{}_thumbnail", key)
 }
 
-pub async fn image_download(ctx: web::Data<AppData>, identity: Identity, site_id: web::Path<IdType>) -> ServiceResult<NamedFile> {
-    ensure_site_visible(&ctx, identity, *site_id)?;
-    let path = get_file_from_site(*site_id)
-        .map_err(|x| ServiceError::InternalServerError(x.to_string()))
-        .and_then(|path| {
-            if path.exists() {
-                NamedFile::open(path).map_err(|x| ServiceError::InternalServerError(x.to_string()))
-            } else {
-                Err(ServiceError::NotFound("Image".to_string()))
-            }
-        })?;
+/// Checks that the data is an image within the limits and stores it with its thumbnail, returns
+/// the size of the image
+pub fn store_image(storage: &dyn MapStorage, config: &MapImageConfig, key: &str, data: Vec<u8>) -> ServiceResult<(u32, u32)> {
+    let invalid_image = |_| ServiceError::BadRequest("Invalid image".to_string());
+
+    // The size is read from the header first so that huge images aren't decoded
+    let (width, height) = image::io::Reader::new(Cursor::new(&data))
+        .with_guessed_format()
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?
+        .into_dimensions()
+        .map_err(invalid_image)?;
+    if width > config.max_dimension || height > config.max_dimension {
+        return Err(ServiceError::BadRequest(format!("The image is bigger than {0}x{0}", config.max_dimension)))
+    }
+
+    let image = image::load_from_memory(&data).map_err(invalid_image)?;
+    let mut thumbnail = Vec::new();
+    image.thumbnail(config.thumbnail_size, config.thumbnail_size)
+        .write_to(&mut Cursor::new(&mut thumbnail), image::ImageOutputFormat::Png)
+        .map_err(|x| ServiceError::InternalServerError(x.to_string()))?;
+
+    storage.write(key, data).map_err(ServiceError::InternalServerError)?;
+    storage.write(&get_thumbnail_file(key), thumbnail).map_err(ServiceError::InternalServerError)?;
+    Ok((width, height))
+}
+
+/// Deletes the image and its thumbnail, returns false if the image didn't exist
+pub fn delete_image(storage: &dyn MapStorage, key: &str) -> Result<bool, String> {
+    storage.delete(&get_thumbnail_file(key))?;
+    storage.delete(key)
+}
+
+/// Runs a storage operation in the blocking thread pool
+async fn run_storage<T, F>(ctx: &AppData, operation: F) -> ServiceResult<T>
+    where T: Send + 'static,
+          F: FnOnce(&dyn MapStorage) -> Result<T, String> + Send + 'static {
+    let storage = ctx.site_maps.clone();
+    web::block(move || operation(&*storage)).await.map_err(|x| match x {
+        BlockingError::Error(e) => ServiceError::InternalServerError(e),
+        BlockingError::Canceled => ServiceError::InternalServerError("Storage operation canceled".to_string()),
+    })
+}
+
+fn ensure_site_manager(ctx: &AppData, auth: &Authenticator, site_id: IdType) -> ServiceResult<()> {
+    auth.user_required(ctx)?.ensure_site_manager(ctx, site_id)
+}
 
-    Ok(path)
+fn ensure_site_visible(ctx: &AppData, auth: &Authenticator, site_id: IdType) -> ServiceResult<()> {
+    auth.principal_required(ctx)?.ensure_site_visible(ctx, site_id)
+}
+
+/// Fails with NotFound if the map isn't one of the site's
+fn ensure_map_in_site(ctx: &AppData, site_id: IdType, map_id: IdType) -> ServiceResult<()> {
+    use crate::schema::site_map::dsl;
+
+    let conn = ctx.pool.get()?;
+    dsl::site_map.find(map_id)
+        .filter(dsl::site_id.eq(site_id))
+        .select(dsl::id)
+        .first::<IdType>(&conn)
+        .optional()?
+        .map(|_| ())
+        .ok_or_else(|| ServiceError::NotFound("Site map".to_string()))
+}
+
+async fn download_file(ctx: &AppData, key: String, query: ImageDownloadQuery) -> ServiceResult<HttpResponse> {
+    let key = if query.thumbnail.unwrap_or(false) { get_thumbnail_file(&key) } else { key };
+    let data = run_storage(ctx, move |storage| storage.read(&key)).await?
+        .ok_or_else(|| ServiceError::NotFound("Image".to_string()))?;
+    let content_type = match image::guess_format(&data) {
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        _ => "application/octet-stream",
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .body(data))
+}
+
+/// Validates the uploaded image and writes it to the storage with its thumbnail, returns its
+/// length and the size used for the sensors (the one of the image if the query has none)
+async fn save_payload(ctx: &AppData, req: &HttpRequest, key: String, mut payload: web::Payload, query: ImageSizeQuery) -> Result<(i64, ImageSizeData), Error> {
+    let content_type = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    if !MAP_CONTENT_TYPES.contains(&content_type) {
+        return Err(ServiceError::BadRequest(format!("Unsupported content type, expected one of {:?}", MAP_CONTENT_TYPES)).into())
+    }
+
+    let config = ctx.map_images.clone();
+    let mut data = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() > config.max_bytes {
+            return Err(ServiceError::BadRequest(format!("The image is bigger than {} bytes", config.max_bytes)).into())
+        }
+    }
+
+    let len = data.len() as i64;
+    let storage = ctx.site_maps.clone();
+    let (width, height) = run_blocking(move || store_image(&*storage, &config, &key, data)).await?;
+
+    let size = ImageSizeData {
+        to_w: query.width.unwrap_or(width as i32),
+        to_h: query.height.unwrap_or(height as i32),
+    };
+    Ok((len, size))
+}
+
+/// Deletes the image from the storage, fails with NotFound if it doesn't exist
+async fn delete_file(ctx: &AppData, key: String) -> ServiceResult<()> {
+    if !run_storage(ctx, move |storage| delete_image(storage, &key)).await? {
+        return Err(ServiceError::NotFound("Image".to_string()))
+    }
+    Ok(())
+}
+
+/// Scales the locations of the sensors placed on a map to its new size, map_id is None for the
+/// main map of the site.
+/// The locations are removed if the map has no new size (its image has been deleted).
+fn update_sensor_locations(conn: &PgConnection, site_id: IdType, map_id: Option<IdType>, old_size: (Option<i32>, Option<i32>), size: Option<ImageSizeData>) -> ServiceResult<()> {
+    use crate::schema::sensor::dsl as sensor_dsl;
+
+    let mut query = sensor_dsl::sensor
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .select(sensor_dsl::id)
+        .into_boxed();
+    query = match map_id {
+        Some(x) => query.filter(sensor_dsl::map_id.eq(x)),
+        None => query.filter(sensor_dsl::map_id.is_null()),
+    };
+    let sensors = sensor_dsl::sensor.filter(sensor_dsl::id.eq_any(query));
+
+    match (size, old_size) {
+        (Some(size), (Some(old_w), Some(old_h))) => {
+            diesel::update(sensors)
+                .set((
+                    sensor_dsl::loc_x.eq(sensor_dsl::loc_x * (size.to_w / old_w)),
+                    sensor_dsl::loc_y.eq(sensor_dsl::loc_y * (size.to_h / old_h))
+                ))
+                .execute(conn)?;
+        },
+        (Some(_), _) => {},
+        (None, _) => {
+            diesel::update(sensors)
+                .set((
+                    sensor_dsl::loc_x.eq(Option::<i32>::None),
+                    sensor_dsl::loc_y.eq(Option::<i32>::None)
+                ))
+                .execute(conn)?;
+        },
+    }
+    Ok(())
+}
+
+pub async fn image_download(ctx: web::Data<AppData>, auth: Authenticator, site_id: web::Path<IdType>, query: web::Query<ImageDownloadQuery>) -> ServiceResult<HttpResponse> {
+    let site_id = *site_id;
+    let check_ctx = ctx.clone();
+    run_blocking(move || ensure_site_visible(&check_ctx, &auth, site_id)).await?;
+    download_file(&ctx, get_file_from_site(site_id), *query).await
 }
 
 pub async fn image_upload(
     ctx: web::Data<AppData>,
-    identity: Identity,
+    req: HttpRequest,
+    auth: Authenticator,
     site_id: web::Path<IdType>,
-    mut payload: web::Payload,
-    size_data: web::Query<ImageSizeData>
+    payload: web::Payload,
+    size_data: web::Query<ImageSizeQuery>
 ) -> Result<HttpResponse, Error> {
     use crate::schema::site::dsl as site_dsl;
-    use crate::schema::sensor::dsl as sensor_dsl;
 
-    let size: ImageSizeData = *size_data;
+    let site_id = *site_id;
+    let check_ctx = ctx.clone();
+    run_blocking(move || ensure_site_manager(&check_ctx, &auth, site_id)).await?;
+
+    let (len, size) = save_payload(&ctx, &req, get_file_from_site(site_id), payload, *size_data).await?;
+
+    run_blocking(move || {
+        let conn = ctx.pool.get()?;
+
+        let old_size_data: (Option<i32>, Option<i32>) = site_dsl::site.find(site_id)
+            .select((site_dsl::image_width, site_dsl::image_height))
+            .first::<(Option<i32>, Option<i32>)>(&conn)?;
+
+        update_sensor_locations(&conn, site_id, None, old_size_data, Some(size))?;
+        // Update image_width and image_height
+        diesel::update(site_dsl::site.find(site_id))
+            .set((
+                site_dsl::image_width.eq(size.to_w),
+                site_dsl::image_height.eq(size.to_h)
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(len))
+}
+
+pub async fn image_delete(ctx: web::Data<AppData>, auth: Authenticator, site_id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
+    use crate::schema::site::dsl as site_dsl;
 
-    if let Err(x) = ensure_admin(&ctx, identity) {
-        return Err(x.into());
-    };
     let site_id = *site_id;
+    let check_ctx = ctx.clone();
+    run_blocking(move || ensure_site_manager(&check_ctx, &auth, site_id)).await?;
 
-    let mut file = match get_file_from_site(site_id).and_then(fs::File::create) {
-        Ok(file) => file,
-        Err(e) => return Err(error::ErrorInternalServerError(e)),
-    };
+    delete_file(&ctx, get_file_from_site(site_id)).await?;
 
-    let mut len: i64 = 0;
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        let chunk_len = chunk.len() as i64;
-
-        let res: Result<File, BlockingError<error::PayloadError>> = web::block(move || {
-            file.write_all(chunk.as_ref()).map_err(|e| {
-                eprintln!("file.write_all failed: {:?}", e);
-                error::PayloadError::Io(e)
-            })?;
-            Ok(file)
-        }).await;
-        file = res?;
-
-        len += chunk_len;
-    }
+    run_blocking(move || {
+        let conn = ctx.pool.get()?;
+
+        update_sensor_locations(&conn, site_id, None, (None, None), None)?;
+
+        diesel::update(site_dsl::site.find(site_id))
+            .set((
+                site_dsl::image_width.eq(Option::<i32>::None),
+                site_dsl::image_height.eq(Option::<i32>::None)
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }).await?;
+
+    Ok(HttpResponse::new(StatusCode::NO_CONTENT))
+}
+
+pub async fn map_image_download(ctx: web::Data<AppData>, auth: Authenticator, path: web::Path<(IdType, IdType)>, query: web::Query<ImageDownloadQuery>) -> ServiceResult<HttpResponse> {
+    let (site_id, map_id) = *path;
+    let check_ctx = ctx.clone();
+    run_blocking(move || {
+        ensure_site_visible(&check_ctx, &auth, site_id)?;
+        ensure_map_in_site(&check_ctx, site_id, map_id)
+    }).await?;
+    download_file(&ctx, get_file_from_site_map(site_id, map_id), *query).await
+}
+
+pub async fn map_image_upload(
+    ctx: web::Data<AppData>,
+    req: HttpRequest,
+    auth: Authenticator,
+    path: web::Path<(IdType, IdType)>,
+    payload: web::Payload,
+    size_data: web::Query<ImageSizeQuery>
+) -> Result<HttpResponse, Error> {
+    use crate::schema::site_map::dsl;
+
+    let (site_id, map_id) = *path;
+    let check_ctx = ctx.clone();
+    run_blocking(move || {
+        ensure_site_manager(&check_ctx, &auth, site_id)?;
+        ensure_map_in_site(&check_ctx, site_id, map_id)
+    }).await?;
 
-    let conn =  ctx.pool.get()
-        .map_err(ServiceError::from)?;
+    let (len, size) = save_payload(&ctx, &req, get_file_from_site_map(site_id, map_id), payload, *size_data).await?;
 
-    let old_size_data: (Option<i32>, Option<i32>) = site_dsl::site.find(site_id)
-        .select((site_dsl::image_width, site_dsl::image_height))
-        .first::<(Option<i32>, Option<i32>)>(&conn)
-        .map_err(ServiceError::from)?;
+    run_blocking(move || {
+        let conn = ctx.pool.get()?;
 
-    if let (Some(old_w), Some(old_h)) = old_size_data {
-        let mult_x = size.to_w / old_w;
-        let mult_y = size.to_h / old_h;
+        let old_size_data = dsl::site_map.find(map_id)
+            .select((dsl::image_width, dsl::image_height))
+            .first::<(Option<i32>, Option<i32>)>(&conn)?;
 
-        diesel::update(sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(site_id)))
+        update_sensor_locations(&conn, site_id, Some(map_id), old_size_data, Some(size))?;
+        diesel::update(dsl::site_map.find(map_id))
             .set((
-                sensor_dsl::loc_x.eq(sensor_dsl::loc_x * mult_x),
-                sensor_dsl::loc_y.eq(sensor_dsl::loc_y * mult_y)
+                dsl::image_width.eq(size.to_w),
+                dsl::image_height.eq(size.to_h)
             ))
-            .execute(&conn)
-            .map_err(ServiceError::from)?;
-    }
-    // Update image_width and image_height
-    diesel::update(site_dsl::site.find(site_id))
-        .set((
-            site_dsl::image_width.eq(size.to_w),
-            site_dsl::image_height.eq(size.to_h)
-        ))
-        .execute(&conn)
-        .map_err(ServiceError::from)?;
+            .execute(&conn)?;
+        Ok(())
+    }).await?;
 
     Ok(HttpResponse::Ok().json(len))
 }
 
-pub async fn image_delete(ctx: web::Data<AppData>, identity: Identity, site_id: web::Path<IdType>) -> ServiceResult<HttpResponse> {
-    use crate::schema::site::dsl as site_dsl;
-    use crate::schema::sensor::dsl as sensor_dsl;
+pub async fn map_image_delete(ctx: web::Data<AppData>, auth: Authenticator, path: web::Path<(IdType, IdType)>) -> ServiceResult<HttpResponse> {
+    use crate::schema::site_map::dsl;
 
-    let site_id = *site_id;
+    let (site_id, map_id) = *path;
+    let check_ctx = ctx.clone();
+    run_blocking(move || {
+        ensure_site_manager(&check_ctx, &auth, site_id)?;
+        ensure_map_in_site(&check_ctx, site_id, map_id)
+    }).await?;
+
+    delete_file(&ctx, get_file_from_site_map(site_id, map_id)).await?;
+
+    run_blocking(move || {
+        let conn = ctx.pool.get()?;
 
-    ensure_admin(&ctx, identity)?;
-    get_file_from_site(site_id)
-        .map_err(|x| ServiceError::InternalServerError(x.to_string()))
-        .and_then(|x| {
-            if x.exists() {
-                fs::remove_file(x).map_err(|x| ServiceError::InternalServerError(x.to_string()))
-            } else { Err(ServiceError::NotFound("Image".to_string())) }
-        })?;
-
-
-    let conn =  ctx.pool.get()
-        .map_err(ServiceError::from)?;
-
-    diesel::update(sensor_dsl::sensor.filter(sensor_dsl::site_id.eq(site_id)))
-        .set((
-            sensor_dsl::loc_x.eq(Option::<i32>::None),
-            sensor_dsl::loc_y.eq(Option::<i32>::None)
-        ))
-        .execute(&conn)
-        .map_err(ServiceError::from)?;
-
-    diesel::update(site_dsl::site.find(site_id))
-        .set((
-            site_dsl::image_width.eq(Option::<i32>::None),
-            site_dsl::image_height.eq(Option::<i32>::None)
-        ))
-        .execute(&conn)
-        .map_err(ServiceError::from)?;
+        update_sensor_locations(&conn, site_id, Some(map_id), (None, None), None)?;
+        diesel::update(dsl::site_map.find(map_id))
+            .set((
+                dsl::image_width.eq(Option::<i32>::None),
+                dsl::image_height.eq(Option::<i32>::None)
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }).await?;
 
     Ok(HttpResponse::new(StatusCode::NO_CONTENT))
 }
@@ -0,0 +1,175 @@
+//! Delta sync used by the mobile app to work offline: the client keeps a copy of the sites,
+//! sensors, channels and alarm events it can see and asks for the changes made after its cursor.
+//!
+//! The rows are selected by updated_at, which is set by a database trigger to the start of the
+//! changing transaction: a transaction that commits after the sync could still write an older
+//! timestamp, so the returned cursor is moved back by a safety margin and some rows may be sent
+//! twice (the clients apply them by id).
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::PgConnection;
+
+use crate::models::{ALARM_EVENT_ALL_COLUMNS, AlarmEvent, Channel, CHANNEL_ALL_COLUMNS, IdType, Sensor, Site};
+
+use super::errors::{ServiceError, ServiceResult};
+use super::graphql_schema::Context;
+
+/// How far back the next cursor is moved from the time of the sync
+pub const CURSOR_SAFETY_MARGIN_SECS: i64 = 60;
+
+const CURSOR_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+
+/// Changes made after the cursor, the first sync (without a cursor) returns every visible entity
+/// and no deleted ids
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context, scalar = juniper::DefaultScalarValue)]
+pub struct SyncChanges {
+    pub sites: Vec<Site>,
+    pub sensors: Vec<Sensor>,
+    pub channels: Vec<Channel>,
+    /// Without a cursor only the alarms that are ongoing or not acknowledged are returned
+    pub alarm_events: Vec<AlarmEvent>,
+    pub deleted_site_ids: Vec<IdType>,
+    pub deleted_sensor_ids: Vec<IdType>,
+    pub deleted_channel_ids: Vec<IdType>,
+    /// Cursor to pass to the next sync
+    pub next_cursor: String,
+}
+
+impl SyncChanges {
+    /// Number of entities returned
+    pub fn len(&self) -> usize {
+        self.sites.len() + self.sensors.len() + self.channels.len() + self.alarm_events.len() +
+            self.deleted_site_ids.len() + self.deleted_sensor_ids.len() + self.deleted_channel_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn parse_cursor(cursor: &str) -> ServiceResult<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(cursor, CURSOR_FORMAT)
+        .map_err(|_| ServiceError::BadRequest("Invalid cursor".to_string()))
+}
+
+pub fn format_cursor(time: NaiveDateTime) -> String {
+    time.format(CURSOR_FORMAT).to_string()
+}
+
+/// Sites visible to the user, None if every site is visible (admin).
+/// The deleted sites are included so that their deletion is synced
+fn visible_site_ids(conn: &PgConnection, user_id: Option<IdType>) -> ServiceResult<Option<Vec<IdType>>> {
+    use crate::schema::user_access::dsl;
+
+    let user_id = match user_id {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    Ok(Some(dsl::user_access
+        .filter(dsl::user_id.eq(user_id))
+        .select(dsl::site_id)
+        .load::<IdType>(conn)?))
+}
+
+/// Splits the entities in the changed ones and the ids of the deleted ones
+fn split_deleted<T>(entities: Vec<T>, deleted: impl Fn(&T) -> bool, id: impl Fn(&T) -> IdType) -> (Vec<T>, Vec<IdType>) {
+    let (removed, changed): (Vec<T>, Vec<T>) = entities.into_iter().partition(|x| deleted(x));
+    (changed, removed.iter().map(id).collect())
+}
+
+/// Loads the changes visible to the user (None for the admins) made after since
+pub fn load_changes(conn: &PgConnection, user_id: Option<IdType>, since: Option<NaiveDateTime>) -> ServiceResult<SyncChanges> {
+    use crate::schema::{
+        alarm_event::dsl as alarm_dsl,
+        channel::dsl as channel_dsl,
+        sensor::dsl as sensor_dsl,
+        site::dsl as site_dsl,
+    };
+
+    // Read before the changes so that nothing committed in the meantime is skipped
+    let now: NaiveDateTime = diesel::select(diesel::dsl::now).get_result(conn)?;
+    let next_cursor = format_cursor(now - Duration::seconds(CURSOR_SAFETY_MARGIN_SECS));
+
+    let site_ids = visible_site_ids(conn, user_id)?;
+
+    let mut sites = site_dsl::site.into_boxed();
+    let mut sensors = sensor_dsl::sensor.into_boxed();
+    let mut channels = channel_dsl::channel.inner_join(sensor_dsl::sensor)
+        .select(CHANNEL_ALL_COLUMNS)
+        .into_boxed();
+    let mut alarm_events = alarm_dsl::alarm_event.inner_join(channel_dsl::channel.inner_join(sensor_dsl::sensor))
+        .filter(channel_dsl::deleted_at.is_null())
+        .select(ALARM_EVENT_ALL_COLUMNS)
+        .into_boxed();
+
+    if let Some(ids) = site_ids {
+        sites = sites.filter(site_dsl::id.eq_any(ids.clone()));
+        sensors = sensors.filter(sensor_dsl::site_id.eq_any(ids.clone()));
+        channels = channels.filter(sensor_dsl::site_id.eq_any(ids.clone()));
+        alarm_events = alarm_events.filter(sensor_dsl::site_id.eq_any(ids));
+    }
+
+    if let Some(since) = since {
+        sites = sites.filter(site_dsl::updated_at.ge(since));
+        sensors = sensors.filter(sensor_dsl::updated_at.ge(since));
+        channels = channels.filter(channel_dsl::updated_at.ge(since));
+        alarm_events = alarm_events.filter(alarm_dsl::updated_at.ge(since));
+    } else {
+        sites = sites.filter(site_dsl::deleted_at.is_null());
+        sensors = sensors.filter(sensor_dsl::deleted_at.is_null());
+        channels = channels.filter(channel_dsl::deleted_at.is_null());
+        alarm_events = alarm_events.filter(alarm_dsl::ended_at.is_null().or(alarm_dsl::acknowledged_at.is_null()));
+    }
+
+    let (sites, deleted_site_ids) = split_deleted(
+        sites.order(site_dsl::id).load::<Site>(conn)?,
+        |x| x.deleted_at.is_some(), |x| x.id
+    );
+    let (sensors, deleted_sensor_ids) = split_deleted(
+        sensors.order(sensor_dsl::id).load::<Sensor>(conn)?,
+        |x| x.deleted_at.is_some(), |x| x.id
+    );
+    let (channels, deleted_channel_ids) = split_deleted(
+        channels.order(channel_dsl::id).load::<Channel>(conn)?,
+        |x| x.deleted_at.is_some(), |x| x.id
+    );
+    let alarm_events = alarm_events.order(alarm_dsl::id).load::<AlarmEvent>(conn)?;
+
+    Ok(SyncChanges {
+        sites,
+        sensors,
+        channels,
+        alarm_events,
+        deleted_site_ids,
+        deleted_sensor_ids,
+        deleted_channel_ids,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let time = NaiveDateTime::parse_from_str("2020-04-18 10:20:30.123456", "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        assert_eq!(parse_cursor(&format_cursor(time)).unwrap(), time);
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        assert!(parse_cursor("").is_err());
+        assert!(parse_cursor("42").is_err());
+        assert!(parse_cursor("2020-04-18").is_err());
+    }
+
+    #[test]
+    fn test_split_deleted() {
+        let (changed, deleted) = split_deleted(vec![(1, false), (2, true), (3, false)], |x| x.1, |x| x.0);
+        assert_eq!(changed, vec![(1, false), (3, false)]);
+        assert_eq!(deleted, vec![2]);
+    }
+}
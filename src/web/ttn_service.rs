@@ -0,0 +1,241 @@
+//! The Things Network (v3) uplink webhook, the readings sent by the LoRaWAN sensors are decoded
+//! and stored in the readings store like the ones of the CNR acquisition chain.
+
+use std::collections::HashMap;
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::AppData;
+use crate::alarm::{NewReading, ReadingsWriter};
+use crate::models::{Channel, CHANNEL_ALL_COLUMNS, IdType};
+
+use super::blocking::run_blocking;
+use super::db_helper::query_channel_cnr_ids;
+use super::errors::{ServiceError, ServiceResult};
+
+/// Station id used for the readings received from TTN
+const TTN_STATION_ID: &str = "ttn";
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum TtnPayloadFormat {
+    /// The payload is decoded by TTN (payload formatters), every field is a channel
+    Decoded,
+    /// Cayenne LPP, every LPP channel number is a channel
+    CayenneLpp,
+}
+
+impl TtnPayloadFormat {
+    pub fn from_char(name: &str) -> Option<TtnPayloadFormat> {
+        match name {
+            "d" => Some(TtnPayloadFormat::Decoded),
+            "c" => Some(TtnPayloadFormat::CayenneLpp),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> &'static str {
+        match self {
+            TtnPayloadFormat::Decoded => "d",
+            TtnPayloadFormat::CayenneLpp => "c",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UplinkQuery {
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct EndDeviceIds {
+    device_id: String,
+}
+
+#[derive(Deserialize)]
+struct UplinkMessage {
+    frm_payload: Option<String>,
+    decoded_payload: Option<HashMap<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct Uplink {
+    end_device_ids: EndDeviceIds,
+    received_at: Option<DateTime<Utc>>,
+    uplink_message: Option<UplinkMessage>,
+}
+
+/// Decodes the data of a single Cayenne LPP value
+type LppDecoder = fn(&[u8]) -> f64;
+
+fn site_uplink_data(site_id: IdType) -> String {
+    format!("ttn/site/{}", site_id)
+}
+
+/// Returns the path (with the signature) that TTN should use to send the uplinks of the site.
+pub fn ttn_uplink_path(ctx: &AppData, site_id: IdType) -> String {
+    let signature = ctx.auth_cache.sign_url(&site_uplink_data(site_id));
+    format!("/api/ttn/site/{}/uplink?signature={}", site_id, signature)
+}
+
+/// Decodes the scalar values of a Cayenne LPP payload, the multi-value types (accelerometer,
+/// gyrometer, gps) are skipped.
+fn decode_cayenne_lpp(data: &[u8]) -> Result<HashMap<String, f64>, ServiceError> {
+    let mut res = HashMap::new();
+    let mut index = 0;
+
+    while index + 2 <= data.len() {
+        let channel = data[index];
+        let data_type = data[index + 1];
+        index += 2;
+
+        let (size, value): (usize, Option<LppDecoder>) = match data_type {
+            0 | 1 | 102 => (1, Some(|x| x[0] as f64)),// Digital input/output, presence
+            2 | 3 => (2, Some(|x| i16::from_be_bytes([x[0], x[1]]) as f64 / 100.0)),// Analog input/output
+            101 => (2, Some(|x| u16::from_be_bytes([x[0], x[1]]) as f64)),// Illuminance
+            103 => (2, Some(|x| i16::from_be_bytes([x[0], x[1]]) as f64 / 10.0)),// Temperature
+            104 => (1, Some(|x| x[0] as f64 / 2.0)),// Humidity
+            115 => (2, Some(|x| u16::from_be_bytes([x[0], x[1]]) as f64 / 10.0)),// Barometer
+            113 | 134 => (6, None),// Accelerometer, gyrometer
+            136 => (9, None),// GPS
+            _ => return Err(ServiceError::BadRequest(format!("Unknown Cayenne LPP type {}", data_type))),
+        };
+
+        let raw = data.get(index..index + size)
+            .ok_or_else(|| ServiceError::BadRequest("Truncated Cayenne LPP payload".to_string()))?;
+        if let Some(decoder) = value {
+            res.insert(channel.to_string(), decoder(raw));
+        }
+        index += size;
+    }
+
+    Ok(res)
+}
+
+fn decode_payload(format: TtnPayloadFormat, message: &UplinkMessage) -> Result<HashMap<String, f64>, ServiceError> {
+    match format {
+        TtnPayloadFormat::Decoded => {
+            let fields = message.decoded_payload.as_ref()
+                .ok_or_else(|| ServiceError::BadRequest("Missing decoded payload".to_string()))?;
+            Ok(fields.iter()
+                .filter_map(|(name, value)| value.as_f64().map(|x| (name.clone(), x)))
+                .collect())
+        },
+        TtnPayloadFormat::CayenneLpp => {
+            let payload = message.frm_payload.as_ref()
+                .and_then(|x| base64::decode(x).ok())
+                .ok_or_else(|| ServiceError::BadRequest("Invalid frm_payload".to_string()))?;
+            decode_cayenne_lpp(&payload)
+        },
+    }
+}
+
+/// Stores the readings of the uplink, every value is written to the channel of the device's
+/// sensor that has the same (cnr) channel id.
+/// Returns the number of stored readings.
+fn store_uplink(ctx: &AppData, site_id: IdType, uplink: Uplink) -> ServiceResult<usize> {
+    use crate::schema::channel::dsl as channel_dsl;
+    use crate::schema::sensor::dsl as sensor_dsl;
+    use crate::schema::ttn_device::dsl as ttn_dsl;
+
+    let conn = ctx.pool.get()?;
+
+    let (sensor_id, format) = ttn_dsl::ttn_device
+        .inner_join(sensor_dsl::sensor)
+        .filter(sensor_dsl::site_id.eq(site_id))
+        .filter(ttn_dsl::device_id.eq(&uplink.end_device_ids.device_id))
+        .filter(sensor_dsl::deleted_at.is_null())
+        .select((ttn_dsl::sensor_id, ttn_dsl::payload_format))
+        .first::<(IdType, String)>(&conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("Device".to_string()))?;
+
+    let format = TtnPayloadFormat::from_char(&format)
+        .ok_or_else(|| ServiceError::InternalServerError("Invalid payload format".to_string()))?;
+
+    let message = match uplink.uplink_message {
+        Some(x) => x,
+        None => return Ok(0),// Not an uplink, nothing to store
+    };
+    let values = decode_payload(format, &message)?;
+
+    let channels: Vec<Channel> = channel_dsl::channel
+        .filter(channel_dsl::sensor_id.eq(sensor_id))
+        .filter(channel_dsl::deleted_at.is_null())
+        .select(CHANNEL_ALL_COLUMNS)
+        .load(&conn)?;
+    std::mem::drop(conn);
+
+    let date: NaiveDateTime = uplink.received_at.unwrap_or_else(Utc::now).naive_utc();
+    let mut readings = Vec::new();
+
+    for channel in channels {
+        let ids = query_channel_cnr_ids(&ctx.pool, channel.id, channel.id_cnr.as_deref())?;
+        let (site_id, sensor_id, channel_id) = match ids {
+            Some(x) => x,
+            None => continue,
+        };
+
+        if let Some(value) = values.get(&channel_id) {
+            readings.push(NewReading {
+                site_id,
+                room_id: "".to_string(),
+                station_id: TTN_STATION_ID.to_string(),
+                sensor_id,
+                channel_id,
+                value: *value,
+                measure_unit: channel.measure_unit.unwrap_or_default(),
+                date,
+            });
+        }
+    }
+
+    ctx.sensor_pool.insert_readings(&readings)?;
+    ctx.readings_cache.invalidate(&readings, Utc::now().naive_utc());
+    Ok(readings.len())
+}
+
+pub async fn ttn_uplink(
+    ctx: web::Data<AppData>,
+    site_id: web::Path<IdType>,
+    query: web::Query<UplinkQuery>,
+    body: web::Bytes,
+) -> ServiceResult<HttpResponse> {
+    let site_id = *site_id;
+    if !ctx.auth_cache.verify_url_signature(&site_uplink_data(site_id), &query.signature) {
+        return Err(ServiceError::Unauthorized)
+    }
+
+    // The uplinks carry the metadata of every gateway, they don't fit the default json limit
+    let uplink: Uplink = serde_json::from_slice(&body)
+        .map_err(|x| ServiceError::BadRequest(x.to_string()))?;
+
+    let count = run_blocking(move || store_uplink(&ctx, site_id, uplink)).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "stored": count })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cayenne_lpp() {
+        // Temperature 27.2 on channel 3, humidity 50% on channel 5, gps on channel 1
+        let payload = [
+            3, 103, 0x01, 0x10,
+            5, 104, 100,
+            1, 136, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let values = decode_cayenne_lpp(&payload).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["3"], 27.2);
+        assert_eq!(values["5"], 50.0);
+
+        assert!(decode_cayenne_lpp(&[3, 103, 0x01]).is_err());
+        assert!(decode_cayenne_lpp(&[3, 250, 0x01]).is_err());
+    }
+}
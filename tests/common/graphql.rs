@@ -8,7 +8,7 @@
 
 use actix_http::Request;
 use actix_http::cookie::CookieJar;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_identity::IdentityService;
 use actix_web::{App, test};
 use actix_web::dev::{PayloadStream, Service, ServiceResponse};
 use actix_web::http::{header, StatusCode};
@@ -23,6 +23,7 @@
 use serde_json::Value;
 
 use oldmusa_server::*;
+use oldmusa_server::web::identity_cookie::IdentityCookieConfig;
 
 lazy_static! {
     static ref MIGRATION_SETUP: Mutex<()> = Mutex::new(());
@@ -35,7 +36,7 @@ pub struct ExecutionError {
     pub locations: Value,
     pub path: Option<Vec<String>>,
     pub message: String,
-    pub extensions: Option<HashMap<String, String>>,
+    pub extensions: Option<HashMap<String, Value>>,
 }
 
 #[derive(Deserialize)]
@@ -79,6 +80,11 @@ pub fn add_variable<S: Into<String>, V: Into<Value>>(mut self, name: S, value: V
         self.variables.insert(name.into(), value.into());
         self
     }
+
+    pub fn operation_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.operation_name = Some(name.into());
+        self
+    }
 }
 
 pub fn query<S: Into<String>>(query: S) -> GraphQlQueryBuilder {
@@ -110,8 +116,13 @@ pub fn create_random_username() -> String {
 pub trait GraphQlTester : Clone {
     fn submit_raw<R: Into<GraphQLRequest>>(&mut self, query: R) -> Result<Value, Vec<ExecutionError>>;
 
+    /// Like submit_raw but against the admin schema
+    fn submit_admin_raw<R: Into<GraphQLRequest>>(&mut self, query: R) -> Result<Value, Vec<ExecutionError>>;
+
     fn submit_raw_req(&mut self, req: TestRequest) -> (StatusCode, Bytes);
 
+    fn app_data(&self) -> &AppData;
+
     fn submit<R: Into<GraphQLRequest>>(&mut self, query: R) -> Value {
         let x = self.submit_raw(query);
         match x {
@@ -120,6 +131,21 @@ fn submit<R: Into<GraphQLRequest>>(&mut self, query: R) -> Value {
         }
     }
 
+    fn submit_admin<R: Into<GraphQLRequest>>(&mut self, query: R) -> Value {
+        match self.submit_admin_raw(query) {
+            Ok(val) => json_object_extract_first(&val).expect("Cannot parse value"),
+            Err(errors) => Self::manage_errors(errors),
+        }
+    }
+
+    /// Status of a request to the admin schema, the users that can't use it are rejected before
+    /// running the query
+    fn submit_admin_status<R: Into<GraphQLRequest>>(&mut self, query: R) -> StatusCode {
+        self.submit_raw_req(TestRequest::post()
+            .uri("/api/admin/graphql")
+            .set_json(&query.into())).0
+    }
+
     fn submit_all<R: Into<GraphQLRequest>>(&mut self, query: R) -> Value {
         let x = self.submit_raw(query);
         match x {
@@ -154,7 +180,7 @@ fn create_random_user(&mut self, password: &str) -> (i64, String) {
         let mut last_execution_error: Option<Vec<ExecutionError>> = None;
         for _ in 0..10 {
             let username = create_random_username();
-            let res = self.submit_raw(query(r#"mutation addUser($auth: UserInput!) {
+            let res = self.submit_admin_raw(query(r#"mutation addUser($auth: UserInput!) {
                 addUser(data: $auth) { id }
             }"#).add_variable("auth", json!({
                 "username": &username,
@@ -189,7 +215,15 @@ impl<S, B, E> GraphQlTester for GraphQlTesterImpl<S, B, E>
           E: std::fmt::Debug,
 {
     fn submit_raw<R: Into<GraphQLRequest>>(&mut self, query: R) -> Result<Value, Vec<ExecutionError>> {
-        exec_graphql_raw(self.service.borrow_mut().deref_mut(), &mut self.cookies, query)
+        exec_graphql_raw(self.service.borrow_mut().deref_mut(), &mut self.cookies, "/api/graphql", query)
+    }
+
+    fn submit_admin_raw<R: Into<GraphQLRequest>>(&mut self, query: R) -> Result<Value, Vec<ExecutionError>> {
+        exec_graphql_raw(self.service.borrow_mut().deref_mut(), &mut self.cookies, "/api/admin/graphql", query)
+    }
+
+    fn app_data(&self) -> &AppData {
+        &self.data
     }
 
     fn submit_raw_req(&mut self, mut req: TestRequest) -> (StatusCode, Bytes) {
@@ -241,23 +275,28 @@ fn clone(&self) -> Self {
 }
 
 pub fn init_app() -> impl GraphQlTester {
+    init_app_with(|_| {})
+}
+
+/// Creates the test app changing the configuration of the AppData
+pub fn init_app_with<F: FnOnce(&mut AppData)>(configure: F) -> impl GraphQlTester {
     dotenv::dotenv().ok();
     let database_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
     let sensor_database_url = std::env::var("SENSOR_DATABASE_URL").expect("SENSOR_DATABASE_URL must be set");
-    let data = AppData::new("a".repeat(32), database_url, sensor_database_url, contact::Contacter::new(None), None);
+    let mut data = AppData::new("a".repeat(32), database_url, sensor_database_url, contact::Contacter::new(None, None), None);
+    data.secret_box = Some(secrets::SecretBox::new([3; 32]));
+    configure(&mut data);
 
     {
         let _guard = MIGRATION_SETUP.lock().unwrap();
         data.setup_migrations().unwrap();
+        data.setup_storage().unwrap();
     }
 
     let service = block_on(test::init_service(
         App::new()
             .data(data.clone())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&[41; 32]) // <- create cookie identity policy
-                    .name("auth-cookie")
-                    .secure(false)))
+            .wrap(IdentityService::new(IdentityCookieConfig::default().policy(&[41; 32])))
             .configure(api_service::config)
     ));
 
@@ -268,9 +307,9 @@ pub fn init_app() -> impl GraphQlTester {
     }
 }
 
-fn graphql_request<R: Into<GraphQLRequest>>(request: R, cookies: &CookieJar) -> Request<PayloadStream> {
+fn graphql_request<R: Into<GraphQLRequest>>(uri: &str, request: R, cookies: &CookieJar) -> Request<PayloadStream> {
     let mut partial = test::TestRequest::post()
-        .uri("/api/graphql")
+        .uri(uri)
         .header(header::CONTENT_TYPE, "application/json")
         .set_json(&request.into());
 
@@ -281,14 +320,14 @@ fn graphql_request<R: Into<GraphQLRequest>>(request: R, cookies: &CookieJar) ->
     partial.to_request()
 }
 
-fn exec_graphql_raw<S, B, E, R>(app: &mut S, cookies: &mut CookieJar, req: R) -> Result<Value, Vec<ExecutionError>>
+fn exec_graphql_raw<S, B, E, R>(app: &mut S, cookies: &mut CookieJar, uri: &str, req: R) -> Result<Value, Vec<ExecutionError>>
     where
         S: Service<Request = actix_http::Request, Response = ServiceResponse<B>, Error = E>,
         B: actix_http::body::MessageBody + 'static,
         E: std::fmt::Debug,
         R: Into<GraphQLRequest>
 {
-    let greq = graphql_request(req, cookies);
+    let greq = graphql_request(uri, req, cookies);
 
     let result = block_on(test::call_service(app, greq));
     for cookie in result.response().cookies() {
@@ -342,3 +381,12 @@ pub fn assert_eq_set(mut left: Value, mut right: Value) {
     right.sort_by_cached_key(|x| format!("{}", x));
     assert_eq!(left, right)
 }
+
+/// A blank png image, accepted as a site map
+pub fn png_image(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    image::DynamicImage::new_rgb8(width, height)
+        .write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png)
+        .unwrap();
+    data
+}
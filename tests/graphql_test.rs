@@ -2,13 +2,41 @@
 extern crate lazy_static;
 
 use std::panic;
+use std::time::Duration;
 
-use serde_json::json;
+use serde_json::{json, Value};
 
 use common::graphql::*;
 use actix_web::test::TestRequest;
 use actix_web::http::header;
 use actix_http::http::StatusCode;
+use chrono::NaiveDateTime;
+use diesel::RunQueryDsl;
+use futures::executor::block_on;
+use oldmusa_server::alarm::{check_flapping, clear_flapping, DatabaseError, escalate_alarms, FlappingPolicy, NewReading, ReadingsWriter, update_site_status};
+use oldmusa_server::calibration::send_calibration_reminders;
+use oldmusa_server::demo::seed_demo;
+use oldmusa_server::discovery::discovery_mutation;
+use oldmusa_server::mqtt::{MqttMessage, store_messages};
+use oldmusa_server::quota;
+use oldmusa_server::report::{prepare_due_reports, send_reports};
+use oldmusa_server::security::hash_api_key;
+use oldmusa_server::siem::{SiemConfig, SiemFormat, SiemSink, SiemTarget};
+use oldmusa_server::trash::purge_trash;
+use oldmusa_server::warmup::{warm_up, WarmupConfig};
+use oldmusa_server::web::access_monitor::{AccessMonitor, AccessMonitorConfig};
+use oldmusa_server::web::admin_network::AdminNetworkPolicy;
+use oldmusa_server::web::approval::ApprovalPolicy;
+use oldmusa_server::web::errors::{ServiceError, ServiceResult};
+use oldmusa_server::web::graphql_cache::{body_etag, query_hash};
+use oldmusa_server::web::graphql_schema::Context;
+use oldmusa_server::web::health_service::check_readiness;
+use oldmusa_server::web::login_throttle::{LoginThrottle, LoginThrottleConfig};
+use oldmusa_server::web::policy::{AccessPolicy, HiddenResourceError};
+use oldmusa_server::web::quota::QuotaPool;
+use oldmusa_server::web::schema_changes::current_snapshot;
+use oldmusa_server::web::map_storage::{FilesystemStorage, MapStorage};
+use oldmusa_server::web::site_map_service::{get_file_from_site, get_file_from_site_map};
 
 
 mod common;
@@ -19,7 +47,7 @@ fn test_generic() {
 
     tester.login_root();
     // Create site
-    let site_id = tester.submit(query(r#"mutation {
+    let site_id = tester.submit_admin(query(r#"mutation {
         addSite(data: {}) { id }
     }"#))["id"].to_i64();
 
@@ -54,8 +82,30 @@ fn test_generic() {
     let channel_id = res["id"].to_i64();
     assert_eq!(res, json!({"id": channel_id, "name": "pioppo", "measureUnit": "nonno"}));
 
+    // No alarms yet
+    let res = tester.submit(query(r#"query alarmHistory($id: Int!) {
+        channel(id: $id) { alarmHistory(start: 0, end: 2000000000) { id, peakValue, extremeType } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({"alarmHistory": []}));
+
+    // Nor anomalies
+    let res = tester.submit(query(r#"query anomalies($id: Int!) {
+        channel(id: $id) { anomalies(start: 0, end: 2000000000) { id, score, kind } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({"anomalies": []}));
+
+    // The channel has no cnr id, so no readings (aggregated or not)
+    let res = tester.submit(query(r#"query readings($id: Int!) {
+        channel(id: $id) { readings(start: 0, end: 2000000000, aggregation: HOURLY) { date, valueMin } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({"readings": []}));
+    let res = tester.submit(query(r#"query stats($id: Int!) {
+        channel(id: $id) { stats(start: 0, end: 2000000000) { count, min, max, mean, stddev, p95 } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({"stats": { "count": 0, "min": null, "max": null, "mean": null, "stddev": null, "p95": null }}));
+
     // Cleanup
-    tester.submit(query(r#"mutation deleteSite($id: Int!) {
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
         deleteSite(id: $id)
     }"#).add_variable("id", site_id));
 }
@@ -68,14 +118,14 @@ fn test_permission_view() {
     tester.login_root();
 
     let site_ids: Vec<i64> = (0..3).map(|_| {
-        tester.submit(query(r#"mutation {
+        tester.submit_admin(query(r#"mutation {
             addSite(data: {}) { id }
         }"#))["id"].to_i64()
     }).collect();
 
     let (user_id, user_name) = tester.create_random_user("123");
 
-    tester.submit(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
         giveUserAccess(userId: $userId, siteIds: $siteIds)
     }"#).add_variable("userId", user_id).add_variable("siteIds", &site_ids[0..=1]));
 
@@ -226,11 +276,11 @@ fn test_permission_view() {
 
     // Cleanup
     for id in site_ids {
-        tester.submit(query(r#"mutation deleteSite($id: Int!) {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
             deleteSite(id: $id)
         }"#).add_variable("id", id));
     }
-    tester.submit(query(r#"mutation deleteUser($id: Int!) {
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
         deleteUser(id: $id)
     }"#).add_variable("id", user_id));
 }
@@ -242,7 +292,7 @@ fn test_delete_cascade() {
     tester.login_root();
 
     // Create site
-    let site_id = tester.submit(query(r#"mutation {
+    let site_id = tester.submit_admin(query(r#"mutation {
         addSite(data: {}) { id }
     }"#))["id"].to_i64();
 
@@ -257,7 +307,7 @@ fn test_delete_cascade() {
     }"#).add_variable("sensorId", sensor_id))["id"].to_i64();
 
     // Delete site
-    tester.submit(query(r#"mutation deleteSite($id: Int!) {
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
         deleteSite(id: $id)
     }"#).add_variable("id", site_id));
 
@@ -284,7 +334,7 @@ fn test_user_password_misc() {
     tester.login_root();
 
     // Create site
-    let site_id = tester.submit(query(r#"mutation {
+    let site_id = tester.submit_admin(query(r#"mutation {
         addSite(data: {}) { id }
     }"#))["id"].to_i64();
 
@@ -294,7 +344,7 @@ fn test_user_password_misc() {
 
     // An admin should be able to change any user's data (both username and password)
     let user1_name = create_random_username();
-    tester.submit(
+    tester.submit_admin(
         query(r#"mutation changeUserQuery1($userId: Int!, $newName: String!, $newPass: String!) {
             updateUser(id: $userId, data: {
                 username: $newName,
@@ -316,8 +366,23 @@ fn test_user_password_misc() {
             .add_variable("newName", create_random_username())
     ).expect_service_error("UNAUTHORIZED");
 
+    // Nor its own username, that's changed by the admins in the admin schema
+    user_tester.submit_raw(
+        query(r#"mutation changeUserNameMutation($userId: Int!, $newName: String!) {
+            updateUser(id: $userId, data: { username: $newName }) { id }
+        }"#)
+            .add_variable("userId", user1_id)
+            .add_variable("newName", create_random_username())
+    ).expect_service_error("UNAUTHORIZED");
+
+    // The other users can only be looked up by the admins
+    let user_query = || query(r#"query user($id: Int!) { user(id: $id) { id } }"#).add_variable("id", user2_id);
+    user_tester.submit_raw(user_query()).expect_service_error("UNAUTHORIZED");
+    tester.submit_raw(user_query()).expect_service_error("UNAUTHORIZED");
+    assert_eq!(tester.submit_admin(user_query()), json!({ "id": user2_id }));
+
     // Check token invalidation on password change
-    tester.submit(
+    tester.submit_admin(
         query(r#"mutation changeUserQuery2($userId: Int!, $newPass: String!) {
             updateUser(id: $userId, data: {
                 password: $newPass,
@@ -330,86 +395,3644 @@ fn test_user_password_misc() {
     assert_eq!(res, json!(null));
 
     // Cleanup
-    tester.submit(
-        query(r#"mutation cleanupUserPasswordMisc($siteId: Int!, $user1Id: Int!, $user2Id: Int!) {
-            a1: deleteSite(id: $siteId)
-            a2: deleteUser(id: $user1Id)
-            a3: deleteUser(id: $user2Id)
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    tester.submit_admin(
+        query(r#"mutation cleanupUserPasswordMisc($user1Id: Int!, $user2Id: Int!) {
+            a1: deleteUser(id: $user1Id)
+            a2: deleteUser(id: $user2Id)
         }"#)
-            .add_variable("siteId", site_id)
             .add_variable("user1Id", user1_id)
             .add_variable("user2Id", user2_id)
     );
 }
 
-// TODO: test alarm controller
+#[test]
+fn test_user_cache_invalidation() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let (user_id, username) = tester.create_random_user("password34");
+    user_tester.login(&username, "password34");
+    let res = user_tester.submit(query("query { userMe { permission } }"));
+    assert_eq!(res, json!({ "permission": "USER" }));
+
+    // The cached user must not survive a permission change
+    tester.submit_admin(query(r#"mutation updateUser($id: Int!) {
+        updateUser(id: $id, data: { permission: ADMIN }) { id }
+    }"#).add_variable("id", user_id));
+    let res = user_tester.submit(query("query { userMe { permission } }"));
+    assert_eq!(res, json!({ "permission": "ADMIN" }));
+
+    // Nor its deletion
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+    let res = user_tester.submit(query("query { userMe { id } }"));
+    assert_eq!(res, json!(null));
+}
 
 #[test]
-fn test_image_resize() {
+fn test_bearer_token() {
     let mut tester = init_app();
+    let mut anon_tester = tester.clone();
     tester.login_root();
 
-    // Create site
-    let site_id = tester.submit(query(r#"mutation {
+    let (user_id, username) = tester.create_random_user("password21");
+
+    let res = anon_tester.submit(query(r#"mutation loginToken($auth: AuthInput!) {
+        loginToken(auth: $auth) { token user { id } }
+    }"#).add_variable("auth", json!({ "username": username, "password": "password21" })));
+    assert_eq!(res["user"]["id"].to_i64(), user_id);
+    let token = res["token"].as_str().unwrap().to_string();
+
+    // No cookie should be set, the token is the only way to authenticate
+    let res = anon_tester.submit(query("query { userMe { id } }"));
+    assert_eq!(res, json!(null));
+
+    fn user_me(tester: &mut impl GraphQlTester, token: &str) -> Value {
+        let (status, body) = tester.submit_raw_req(TestRequest::post()
+            .uri("/api/graphql")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&json!({ "query": "query { userMe { id } }" })));
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice::<Value>(&body).unwrap()["data"]["userMe"].clone()
+    }
+    assert_eq!(user_me(&mut anon_tester, &token), json!({ "id": user_id }));
+
+    // Tampered tokens are ignored
+    let mut tampered = token.clone();
+    tampered.push('A');
+    assert_eq!(user_me(&mut anon_tester, &tampered), json!(null));
+
+    // A password change invalidates the token
+    tester.submit_admin(query(r#"mutation changePassword($id: Int!) {
+        updateUser(id: $id, data: { password: "password22" }) { id }
+    }"#).add_variable("id", user_id));
+    assert_eq!(user_me(&mut anon_tester, &token), json!(null));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_site_manager() {
+    let mut tester = init_app();
+    let mut manager_tester = tester.clone();
+    tester.login_root();
+
+    let managed_site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let other_site_id = tester.submit_admin(query(r#"mutation {
         addSite(data: {}) { id }
     }"#))["id"].to_i64();
-    let site_map_uri = format!("/api/site_map/{}", site_id);
 
-    let res = tester.submit_raw_req(
-        TestRequest::post()
-            .uri(&format!("{}?width={}&height={}", site_map_uri, 3840, 2160))
-            .header(header::CONTENT_TYPE, "image/png")
-            .set_payload("first png image")
-    );
+    let username = create_random_username();
+    let manager_id = tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: SITE_MANAGER }) { id }
+    }"#).add_variable("username", username.clone()))["id"].to_i64();
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", manager_id).add_variable("siteIds", vec![managed_site_id]));
+    manager_tester.login(&username, "password41");
+
+    let res = manager_tester.submit(query("query { userMe { permission } }"));
+    assert_eq!(res, json!({ "permission": "SITE_MANAGER" }));
+
+    // The manager can edit the sensors and the channels of the managed site
+    let sensor_id = manager_tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "managed" }) { id }
+    }"#).add_variable("id", managed_site_id))["id"].to_i64();
+    let channel_id = manager_tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+    let res = manager_tester.submit(query(r#"mutation updateChannel($id: Int!) {
+        updateChannel(id: $id, data: { name: "temperature" }) { name }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({ "name": "temperature" }));
+
+    let res = manager_tester.submit_raw_req(TestRequest::post()
+        .uri(&format!("/api/site_map/{}?width=10&height=10", managed_site_id))
+        .header(header::CONTENT_TYPE, "image/png")
+        .set_payload(png_image(10, 10)));
     assert_eq!(StatusCode::OK, res.0);
+    let res = manager_tester.submit_raw_req(TestRequest::delete().uri(&format!("/api/site_map/{}", managed_site_id)));
+    assert_eq!(StatusCode::NO_CONTENT, res.0);
 
-    // Create sensors
-    let res = tester.submit_all(
-        query(r#"mutation createSensorsTIR($siteId: Int!) {
-            s1: addSensor(siteId: $siteId, data: { locX: 10,  locY: 20  }) { id }
-            s2: addSensor(siteId: $siteId, data: { locX: 2,   locY: 3   }) { id }
-            s3: addSensor(siteId: $siteId, data: { locX: 413, locY: 125 }) { id }
-        }"#)
-            .add_variable("siteId", site_id)
-    );
-    let s1 = res["s1"]["id"].to_i64();
-    let s2 = res["s2"]["id"].to_i64();
-    let s3 = res["s3"]["id"].to_i64();
+    // But not the other sites or the sites themselves
+    manager_tester.submit_raw(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", other_site_id)).expect_service_error("NOT_FOUND");
+    let res = manager_tester.submit_raw_req(TestRequest::post()
+        .uri(&format!("/api/site_map/{}?width=10&height=10", other_site_id))
+        .header(header::CONTENT_TYPE, "image/png")
+        .set_payload(png_image(10, 10)));
+    assert_ne!(StatusCode::OK, res.0);
+    manager_tester.submit_raw(query(r#"mutation updateSite($id: Int!) {
+        updateSite(id: $id, data: { name: "renamed" }) { id }
+    }"#).add_variable("id", managed_site_id)).expect_service_error("UNAUTHORIZED");
 
-    let res = tester.submit_raw_req(TestRequest::get().uri(&site_map_uri));
+    manager_tester.submit(query(r#"mutation deleteSensor($id: Int!) {
+        deleteSensor(id: $id)
+    }"#).add_variable("id", sensor_id));
+
+    // Plain users can't edit anything
+    let mut user_tester = init_app();
+    let (user_id, user_name) = tester.create_random_user("password42");
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![managed_site_id]));
+    user_tester.login(&user_name, "password42");
+    user_tester.submit_raw(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", managed_site_id)).expect_service_error("UNAUTHORIZED");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation cleanupSiteManager($site1: Int!, $site2: Int!) {
+        a1: deleteSite(id: $site1)
+        a2: deleteSite(id: $site2)
+    }"#)
+        .add_variable("site1", managed_site_id)
+        .add_variable("site2", other_site_id));
+    tester.submit_admin(query(r#"mutation cleanupSiteManagerUsers($user1: Int!, $user2: Int!) {
+        a1: deleteUser(id: $user1)
+        a2: deleteUser(id: $user2)
+    }"#)
+        .add_variable("user1", manager_id)
+        .add_variable("user2", user_id));
+}
+
+#[test]
+fn test_site_access_levels() {
+    let mut tester = init_app();
+    let mut curator_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let other_site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let (curator_id, curator_name) = tester.create_random_user("password51");
+    let (viewer_id, _viewer_name) = tester.create_random_user("password52");
+    curator_tester.login(&curator_name, "password51");
+
+    let give_access = r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!, $level: AccessLevel) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds, level: $level)
+    }"#;
+    tester.submit_admin(query(give_access)
+        .add_variable("userId", curator_id)
+        .add_variable("siteIds", vec![site_id]));
+
+    // VIEW only allows reading
+    let add_sensor = r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#;
+    curator_tester.submit_raw(query(add_sensor).add_variable("id", site_id)).expect_service_error("UNAUTHORIZED");
+
+    // Changing the level of an existing access
+    tester.submit_admin(query(give_access)
+        .add_variable("userId", curator_id)
+        .add_variable("siteIds", vec![site_id])
+        .add_variable("level", "MANAGE"));
+    tester.submit_admin_raw(query(give_access)
+        .add_variable("userId", curator_id)
+        .add_variable("siteIds", vec![site_id])
+        .add_variable("level", "MANAGE")).expect_service_error("ALREADY_PRESENT");
+    curator_tester.submit(query(add_sensor).add_variable("id", site_id));
+    curator_tester.submit_raw(query(r#"mutation updateSite($id: Int!) {
+        updateSite(id: $id, data: { name: "curated" }) { id }
+    }"#).add_variable("id", site_id)).expect_service_error("UNAUTHORIZED");
+
+    // ADMIN allows editing the site and giving access to it, but only to it
+    tester.submit_admin(query(give_access)
+        .add_variable("userId", curator_id)
+        .add_variable("siteIds", vec![site_id])
+        .add_variable("level", "ADMIN"));
+    let res = curator_tester.submit(query("query { userMe { siteAccess { siteId level } } }"));
+    assert_eq!(res, json!({ "siteAccess": [{ "siteId": site_id, "level": "ADMIN" }] }));
+
+    curator_tester.submit(query(r#"mutation updateSite($id: Int!) {
+        updateSite(id: $id, data: { name: "curated" }) { id }
+    }"#).add_variable("id", site_id));
+    curator_tester.submit(query(give_access)
+        .add_variable("userId", viewer_id)
+        .add_variable("siteIds", vec![site_id]));
+    curator_tester.submit_raw(query(give_access)
+        .add_variable("userId", viewer_id)
+        .add_variable("siteIds", vec![other_site_id])).expect_service_error("NOT_FOUND");
+    // The site admins can't use the admin schema
+    assert_eq!(curator_tester.submit_admin_status(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id)), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation cleanupAccessLevels($site1: Int!, $site2: Int!) {
+        a1: deleteSite(id: $site1)
+        a2: deleteSite(id: $site2)
+    }"#)
+        .add_variable("site1", site_id)
+        .add_variable("site2", other_site_id));
+    tester.submit_admin(query(r#"mutation cleanupAccessLevelsUsers($user1: Int!, $user2: Int!) {
+        a1: deleteUser(id: $user1)
+        a2: deleteUser(id: $user2)
+    }"#)
+        .add_variable("user1", curator_id)
+        .add_variable("user2", viewer_id));
+}
+
+#[test]
+fn test_audit_log() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+
+    let res = tester.submit_admin(query(r#"query {
+        auditLog(filter: { action: "deleteSite" }) { username, action, target }
+    }"#));
+    assert!(res.as_array().unwrap().contains(&json!({
+        "username": "root",
+        "action": "deleteSite",
+        "target": format!("site {}", site_id),
+    })));
+
+    // Failed operations are not recorded
+    tester.submit_admin_raw(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id)).expect_service_error("NOT_FOUND");
+    let res = tester.submit_admin(query(r#"query {
+        auditLog(filter: { action: "deleteSite" }) { target }
+    }"#));
+    let site_target = json!({ "target": format!("site {}", site_id) });
+    assert_eq!(res.as_array().unwrap().iter().filter(|x| **x == site_target).count(), 1);
+
+    // Only the admins can read the log
+    let (user_id, username) = tester.create_random_user("password");
+    user_tester.login(&username, "password");
+    assert_eq!(user_tester.submit_admin_status(query(r#"query {
+        auditLog { id }
+    }"#)), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_email_contact() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let (user_id, username) = tester.create_random_user("password45");
+    user_tester.login(&username, "password45");
+    user_tester.submit(query(r#"mutation {
+        setEmail(email: "Alarms@example.com") { id }
+    }"#));
+
+    // Only the verified email of the account can receive the alarms
+    user_tester.submit_raw(query(r#"mutation {
+        addEmailContact(email: "alarms@example.com")
+    }"#)).expect_service_error("BAD_REQUEST");
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        diesel::sql_query(format!(
+            "UPDATE user_account SET email_token_hash = '{}' WHERE id = {}",
+            hash_api_key("contact-token"), user_id
+        )).execute(&conn).unwrap();
+    }
+    user_tester.submit(query(r#"mutation {
+        verifyEmail(token: "contact-token") { id }
+    }"#));
+
+    // Adding the same address twice is not an error, the case is ignored
+    for _ in 0..2 {
+        let res = user_tester.submit(query(r#"mutation {
+            addEmailContact(email: "alarms@example.com")
+        }"#));
+        assert_eq!(res, json!(true));
+    }
+
+    user_tester.submit_raw(query(r#"mutation {
+        addEmailContact(email: "other@example.com")
+    }"#)).expect_service_error("BAD_REQUEST");
+    user_tester.submit_raw(query(r#"mutation {
+        addEmailContact(email: "not an email")
+    }"#)).expect_service_error("BAD_REQUEST");
+
+    let res = user_tester.submit(query(r#"mutation {
+        deleteEmailContact(email: "alarms@example.com")
+    }"#));
+    assert_eq!(res, json!(true));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_email_verification() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let (user_id, username) = tester.create_random_user("password99");
+    user_tester.login(&username, "password99");
+    let res = user_tester.submit(query("query { userMe { email emailVerified } }"));
+    assert_eq!(res, json!({ "email": null, "emailVerified": false }));
+
+    user_tester.submit_raw(query(r#"mutation {
+        setEmail(email: "not an email") { id }
+    }"#)).expect_service_error("BAD_REQUEST");
+    let res = user_tester.submit(query(r#"mutation {
+        setEmail(email: "curator@example.com") { email emailVerified }
+    }"#));
+    assert_eq!(res, json!({ "email": "curator@example.com", "emailVerified": false }));
+
+    let verify_query = r#"mutation verifyEmail($token: String!) {
+        verifyEmail(token: $token) { email emailVerified }
+    }"#;
+    user_tester.submit_raw(query(verify_query).add_variable("token", "wrong"))
+        .expect_service_error("BAD_REQUEST");
+
+    // The token is only sent by email, the test replaces it with a known one
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        diesel::sql_query(format!(
+            "UPDATE user_account SET email_token_hash = '{}' WHERE id = {}",
+            hash_api_key("known-token"), user_id
+        )).execute(&conn).unwrap();
+    }
+    let res = user_tester.submit(query(verify_query).add_variable("token", "known-token"));
+    assert_eq!(res, json!({ "email": "curator@example.com", "emailVerified": true }));
+    // The token can only be used once
+    user_tester.submit_raw(query(verify_query).add_variable("token", "known-token"))
+        .expect_service_error("BAD_REQUEST");
+
+    let res = user_tester.submit(query(r#"mutation {
+        setEmail(email: null) { email emailVerified }
+    }"#));
+    assert_eq!(res, json!({ "email": null, "emailVerified": false }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_modbus_register() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let set_query = r#"mutation setRegister($id: Int!, $data: ModbusRegisterInput!) {
+        setChannelModbusRegister(channelId: $id, data: $data) { host, port, unitId, address, registerType, valueType, scale, offset }
+    }"#;
+
+    let res = tester.submit(query(set_query).add_variable("id", channel_id).add_variable("data", json!({
+        "host": "10.0.0.5",
+        "address": 100,
+        "registerType": "INPUT",
+        "valueType": "I16",
+        "scale": 0.1,
+    })));
+    assert_eq!(res, json!({
+        "host": "10.0.0.5", "port": 502, "unitId": 1, "address": 100,
+        "registerType": "INPUT", "valueType": "I16", "scale": 0.1, "offset": 0.0,
+    }));
+
+    // Setting it again replaces the old register
+    tester.submit(query(set_query).add_variable("id", channel_id).add_variable("data", json!({
+        "host": "10.0.0.6",
+        "address": 4,
+        "registerType": "HOLDING",
+        "valueType": "F32",
+    })));
+    let res = tester.submit(query(r#"query getRegister($id: Int!) {
+        channel(id: $id) { modbusRegister { host, address, valueType } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res["modbusRegister"], json!({ "host": "10.0.0.6", "address": 4, "valueType": "F32" }));
+
+    tester.submit_raw(query(set_query).add_variable("id", channel_id).add_variable("data", json!({
+        "host": "10.0.0.6",
+        "address": 70000,
+        "registerType": "HOLDING",
+        "valueType": "U16",
+    }))).expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit(query(r#"mutation deleteRegister($id: Int!) {
+        deleteChannelModbusRegister(channelId: $id)
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!(true));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_ttn_uplink() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { name: "temperature" }) { id }
+    }"#).add_variable("id", sensor_id));
+
+    let device_id = format!("lora-{}", sensor_id);
+    let res = tester.submit(query(r#"mutation setDevice($id: Int!, $deviceId: String!) {
+        setSensorTtnDevice(sensorId: $id, data: { deviceId: $deviceId, payloadFormat: CAYENNE_LPP }) { deviceId, payloadFormat }
+    }"#).add_variable("id", sensor_id).add_variable("deviceId", device_id.clone()));
+    assert_eq!(res, json!({ "deviceId": device_id, "payloadFormat": "CAYENNE_LPP" }));
+
+    let uplink_url = tester.submit(query(r#"query getUrl($id: Int!) {
+        site(id: $id) { ttnUplinkUrl }
+    }"#).add_variable("id", site_id))["ttnUplinkUrl"].to_str().to_string();
+
+    let uplink = |device_id: &str| json!({
+        "end_device_ids": { "device_id": device_id },
+        "received_at": "2020-03-16T10:00:00Z",
+        "uplink_message": { "f_port": 1, "frm_payload": "A2cBEA==" },
+    });
+
+    // The channel has no cnr id, so nothing is stored
+    let res = tester.submit_raw_req(TestRequest::post().uri(&uplink_url).set_json(&uplink(&device_id)));
     assert_eq!(StatusCode::OK, res.0);
-    assert_eq!("first png image", res.1);
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body, json!({ "stored": 0 }));
 
-    let res = tester.submit_raw_req(
-        TestRequest::post()
-            .uri(&format!("{}?width={}&height={}", site_map_uri, 7680, 4320))
-            .header(header::CONTENT_TYPE, "image/png")
-            .set_payload("second png image")
+    let res = tester.submit_raw_req(TestRequest::post().uri(&uplink_url).set_json(&uplink("unknown-device")));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+
+    let wrong_url = format!("/api/ttn/site/{}/uplink?signature=00", site_id);
+    let res = tester.submit_raw_req(TestRequest::post().uri(&wrong_url).set_json(&uplink(&device_id)));
+    assert_eq!(StatusCode::FORBIDDEN, res.0);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_recommend_ranges() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    // Without readings there's nothing to recommend
+    let res = tester.submit_admin(query(r#"query recommend($id: Int!) {
+        recommendRanges(channelId: $id, start: 1551398400, end: 1583020800) { rangeMin, rangeMax }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!(null));
+
+    tester.submit_raw(query(r#"mutation apply($id: Int!) {
+        applyRangeRecommendation(channelId: $id, start: 1551398400, end: 1583020800) { id }
+    }"#).add_variable("id", channel_id)).expect_service_error("BAD_REQUEST");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_export_readings() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let export_uri = format!(
+        "/api/export/channel/{}/readings?start=2020-01-01T00:00:00&end=2020-01-02T00:00:00",
+        channel_id
     );
+
+    // The channel has no cnr id, the export only contains the header
+    let res = tester.submit_raw_req(TestRequest::get().uri(&export_uri));
     assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(res.1.as_ref(), b"date,value_min,value_avg,value_max,deviation,error\n");
 
-    // Check that the images have been resized
-    let res = tester.submit(
-        query(r#"
-        query querySitesTIR($siteId: Int!) {
-            site(id: $siteId) {
-                sensors {
-                    id, locX, locY
-                }
-            }
-        }"#).add_variable("siteId", site_id)
-    );
-    assert_eq_set(
-        json!([
-            { "id": s1, "locX": 20, "locY": 40 },
-            { "id": s2, "locX": 4, "locY": 6 },
-            { "id": s3, "locX": 826, "locY": 250 }
-        ]),
-        res["sensors"].clone()
-    );
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!("{}&format=xlsx", export_uri)));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(&res.1[0..2], b"PK");
+
+    // The checksum of the exported file is stored in its manifest
+    let checksum = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(res.1.as_ref()));
+    let verify = r#"query verifyExport($sha256: String!) {
+        verifyExport(sha256: $sha256) { channelId, format, rangeStart, rowCount, sha256 }
+    }"#;
+    let res = tester.submit(query(verify).add_variable("sha256", checksum.to_uppercase()));
+    assert_eq!(res, json!([{
+        "channelId": channel_id,
+        "format": "xlsx",
+        "rangeStart": 1577836800.0,
+        "rowCount": 0,
+        "sha256": checksum,
+    }]));
+    let res = tester.submit(query(verify).add_variable("sha256", "0".repeat(64)));
+    assert_eq!(res, json!([]));
+    tester.submit_raw(query(verify).add_variable("sha256", "checksum"))
+        .expect_service_error("BAD_REQUEST");
+
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_uri));
+    assert_ne!(StatusCode::OK, res.0);
 
     // Cleanup
-    tester.submit(query(r#"mutation deleteSite($id: Int!) {
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
         deleteSite(id: $id)
     }"#).add_variable("id", site_id));
 }
+
+#[test]
+fn test_bulk_export() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "Museo" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "Hall" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    for _ in 0..2 {
+        tester.submit(query(r#"mutation addChannel($id: Int!) {
+            addChannel(sensorId: $id, data: {}) { id }
+        }"#).add_variable("id", sensor_id));
+    }
+
+    let start = r#"mutation startBulkExport($ids: [Int!]!, $start: NaiveDateTime!, $end: NaiveDateTime!) {
+        startBulkExport(siteIds: $ids, start: $start, end: $end) { id status siteIds downloadPath }
+    }"#;
+    tester.submit_admin_raw(query(start)
+        .add_variable("ids", vec![site_id])
+        .add_variable("start", 1577923200.0)
+        .add_variable("end", 1577836800.0))
+        .expect_service_error("BAD_REQUEST");
+    tester.submit_admin_raw(query(start)
+        .add_variable("ids", vec![site_id, -1])
+        .add_variable("start", 1577836800.0)
+        .add_variable("end", 1577923200.0))
+        .expect_service_error("NOT_FOUND");
+
+    let job = tester.submit_admin(query(start)
+        .add_variable("ids", vec![site_id])
+        .add_variable("start", 1577836800.0)
+        .add_variable("end", 1577923200.0));
+    assert_eq!(job["siteIds"], json!([site_id]));
+    let job_id = job["id"].to_i64();
+    let download_path = job["downloadPath"].as_str().unwrap().to_string();
+
+    // The zip is written in the background
+    let mut status = Value::Null;
+    for _ in 0..50 {
+        let jobs = tester.submit_admin(query("{ bulkExportJobs { id status channelCount rowCount } }"));
+        status = jobs.as_array().unwrap().iter()
+            .find(|x| x["id"].to_i64() == job_id)
+            .unwrap()
+            .clone();
+        if status["status"] != "RUNNING" {
+            break
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(status, json!({ "id": job_id, "status": "DONE", "channelCount": 2, "rowCount": 0 }));
+
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&download_path));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(&res.1[0..2], b"PK");
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("/api/export/bulk/{}?signature=abc", job_id)));
+    assert_ne!(StatusCode::OK, res.0);
+
+    // Only the admins can export
+    assert_eq!(anon_tester.submit_admin_status(query(start)
+        .add_variable("ids", vec![site_id])
+        .add_variable("start", 1577836800.0)
+        .add_variable("end", 1577923200.0)), StatusCode::UNAUTHORIZED);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_rest_api() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "rest" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "probe" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { name: "temperature", measureUnit: "C" }) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    fn get_json<T: GraphQlTester>(tester: &mut T, uri: String) -> Value {
+        let res = tester.submit_raw_req(TestRequest::get().uri(&uri));
+        assert_eq!(StatusCode::OK, res.0, "{}", uri);
+        serde_json::from_slice(res.1.as_ref()).unwrap()
+    }
+
+    let sites = get_json(&mut tester, "/api/v1/sites".to_string());
+    assert!(sites.as_array().unwrap().iter().any(|x| x["id"].to_i64() == site_id));
+    assert_eq!(get_json(&mut tester, format!("/api/v1/sites/{}", site_id))["name"], json!("rest"));
+    let sensors = get_json(&mut tester, format!("/api/v1/sites/{}/sensors", site_id));
+    assert_eq!(sensors[0]["id"].to_i64(), sensor_id);
+    assert_eq!(get_json(&mut tester, format!("/api/v1/sensors/{}", sensor_id))["name"], json!("probe"));
+    let channels = get_json(&mut tester, format!("/api/v1/sensors/{}/channels", sensor_id));
+    assert_eq!(channels[0]["id"].to_i64(), channel_id);
+    assert_eq!(get_json(&mut tester, format!("/api/v1/channels/{}", channel_id))["measure_unit"], json!("C"));
+    // The channel has no cnr id, so it has no readings
+    let readings = get_json(&mut tester, format!(
+        "/api/v1/channels/{}/readings?start=2020-01-01T00:00:00&end=2020-01-02T00:00:00&aggregation=hourly",
+        channel_id
+    ));
+    assert_eq!(readings, json!([]));
+
+    let document = get_json(&mut tester, "/api/v1/openapi.json".to_string());
+    assert!(document["paths"]["/api/v1/channels/{id}/readings"]["get"].is_object());
+    assert!(document["components"]["schemas"]["RestChannel"].is_object());
+
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!(
+        "/api/v1/channels/{}/readings?start=2020-01-02T00:00:00&end=2020-01-01T00:00:00", channel_id
+    )));
+    assert_eq!(StatusCode::BAD_REQUEST, res.0);
+
+    // Same permission checks of the GraphQL api
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("/api/v1/sites/{}", site_id)));
+    assert_eq!(StatusCode::UNAUTHORIZED, res.0);
+
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!("/api/v1/sensors/{}", sensor_id)));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+}
+
+#[test]
+fn test_api_key() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let mut site_ids = Vec::new();
+    let mut channel_ids = Vec::new();
+    for _ in 0..2 {
+        let site_id = tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64();
+        let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+            addSensor(siteId: $id, data: {}) { id }
+        }"#).add_variable("id", site_id))["id"].to_i64();
+        let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+            addChannel(sensorId: $id, data: {}) { id }
+        }"#).add_variable("id", sensor_id))["id"].to_i64();
+        site_ids.push(site_id);
+        channel_ids.push(channel_id);
+    }
+
+    let res = tester.submit_admin(query(r#"mutation createApiKey($siteId: Int!) {
+        createApiKey(data: { name: "dashboard", siteId: $siteId }) { key apiKey { id site { id } revokedAt } }
+    }"#).add_variable("siteId", site_ids[0]));
+    assert_eq!(res["apiKey"]["site"]["id"].to_i64(), site_ids[0]);
+    assert_eq!(res["apiKey"]["revokedAt"], json!(null));
+    let key_id = res["apiKey"]["id"].to_i64();
+    let key = res["key"].as_str().unwrap().to_string();
+
+    tester.submit_admin_raw(query(r#"mutation {
+        createApiKey(data: { name: "missing", siteId: -1 }) { key }
+    }"#)).expect_service_error("NOT_FOUND");
+
+    let export_uri = |channel_id: i64| format!(
+        "/api/export/channel/{}/readings?start=2020-01-01T00:00:00&end=2020-01-02T00:00:00",
+        channel_id
+    );
+    let mut anon_tester = init_app();
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_uri(channel_ids[0])).header("X-Api-Key", key.as_str()));
+    assert_eq!(StatusCode::OK, res.0);
+    // The key can only read its site
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_uri(channel_ids[1])).header("X-Api-Key", key.as_str()));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+
+    let res = anon_tester.submit_raw_req(TestRequest::post().uri("/api/grafana/search")
+        .header("X-Api-Key", key.as_str())
+        .set_json(&json!({ "target": "" })));
+    assert_eq!(StatusCode::OK, res.0);
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    let targets: Vec<&str> = body.as_array().unwrap().iter().map(|x| x["value"].as_str().unwrap()).collect();
+    assert_eq!(targets, vec![channel_ids[0].to_string()]);
+
+    // The key doesn't work with graphql
+    let res = anon_tester.submit_raw_req(TestRequest::post().uri("/api/graphql")
+        .header("X-Api-Key", key.as_str())
+        .set_json(&json!({ "query": "query { userMe { id } }" })));
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body["data"]["userMe"], json!(null));
+
+    let res = tester.submit_admin(query(r#"mutation revokeApiKey($id: Int!) {
+        revokeApiKey(id: $id)
+    }"#).add_variable("id", key_id));
+    assert_eq!(res, json!(true));
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_uri(channel_ids[0])).header("X-Api-Key", key.as_str()));
+    assert_eq!(StatusCode::FORBIDDEN, res.0);
+
+    let res = tester.submit_admin(query("query { apiKeys { id revokedAt } }"));
+    let revoked = res.as_array().unwrap().iter().find(|x| x["id"].to_i64() == key_id).unwrap();
+    assert_ne!(revoked["revokedAt"], json!(null));
+
+    // Cleanup
+    for site_id in site_ids {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", site_id));
+    }
+}
+
+#[test]
+fn test_name_translations() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "Museo" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "Sala" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+
+    let set_translation = r#"mutation setTranslation($target: TranslatableType!, $id: Int!, $locale: String!, $name: String) {
+        setNameTranslation(target: $target, id: $id, locale: $locale, name: $name)
+    }"#;
+    tester.submit(query(set_translation)
+        .add_variable("target", "SITE")
+        .add_variable("id", site_id)
+        .add_variable("locale", "en")
+        .add_variable("name", "Museum"));
+    tester.submit(query(set_translation)
+        .add_variable("target", "SENSOR")
+        .add_variable("id", sensor_id)
+        .add_variable("locale", "en-GB")
+        .add_variable("name", "Hall"));
+
+    let site_query = query(r#"query site($id: Int!) {
+        site(id: $id) {
+            default: name
+            en: name(locale: "en")
+            enUs: name(locale: "en-US")
+            de: name(locale: "de")
+            nameTranslations { locale name }
+            sensors { en: name(locale: "en") enGb: name(locale: "en-GB") }
+        }
+    }"#);
+    let res = tester.submit(site_query.add_variable("id", site_id));
+    assert_eq!(res, json!({
+        "default": "Museo",
+        "en": "Museum",
+        "enUs": "Museum",
+        "de": "Museo",
+        "nameTranslations": [{ "locale": "en", "name": "Museum" }],
+        "sensors": [{ "en": "Sala", "enGb": "Hall" }],
+    }));
+
+    // A null name removes the translation
+    tester.submit(query(set_translation)
+        .add_variable("target", "SITE")
+        .add_variable("id", site_id)
+        .add_variable("locale", "en"));
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { name(locale: "en") nameTranslations { locale } }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res, json!({ "name": "Museo", "nameTranslations": [] }));
+
+    tester.submit_raw(query(set_translation)
+        .add_variable("target", "CHANNEL")
+        .add_variable("id", -1)
+        .add_variable("locale", "en")
+        .add_variable("name", "Temperature")).expect_service_error("NOT_FOUND");
+    tester.submit_raw(query(set_translation)
+        .add_variable("target", "SITE")
+        .add_variable("id", site_id)
+        .add_variable("locale", "en'; --")
+        .add_variable("name", "Museum")).expect_service_error("BAD_REQUEST");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_schema_changes() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    // The admin operations moved to the admin schema are missing from the main one
+    let res = tester.submit_admin(query("query { schemaChanges { kind path reason } }"));
+    assert!(res.as_array().unwrap().contains(&json!({
+        "kind": "REMOVED",
+        "path": "MutationRoot.addSite",
+        "reason": null,
+    })));
+
+    let (user_id, username) = tester.create_random_user("password31");
+    user_tester.login(&username, "password31");
+    assert_eq!(user_tester.submit_admin_status(query("query { schemaChanges { path } }")), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_request_log() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let root_id = tester.submit(query("query { userMe { id } }"))["id"].to_i64();
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    for _ in 0..2 {
+        tester.submit(query(r#"query siteDetails($id: Int!) {
+            site(id: $id) { id sensors { id } }
+        }"#).add_variable("id", site_id).operation_name("siteDetails"));
+    }
+
+    let res = tester.submit_admin(query(r#"query requestLog($siteId: Int!) {
+        requestLog(filter: { siteId: $siteId }) { userId siteId operationName rowCount }
+    }"#).add_variable("siteId", site_id));
+    let entry = json!({
+        "userId": root_id,
+        "siteId": site_id,
+        "operationName": "siteDetails",
+        "rowCount": 0.0,
+    });
+    assert_eq!(res, json!([entry, entry]));
+
+    let res = tester.submit_admin(query("query { requestUsage { userId siteId requests } }"));
+    assert!(res.as_array().unwrap().contains(&json!({
+        "userId": root_id,
+        "siteId": site_id,
+        "requests": 2.0,
+    })));
+
+    // Only the admins can read the log
+    let (user_id, username) = tester.create_random_user("password32");
+    user_tester.login(&username, "password32");
+    assert_eq!(user_tester.submit_admin_status(query("query { requestLog { id } }")), StatusCode::FORBIDDEN);
+    assert_eq!(user_tester.submit_admin_status(query("query { requestUsage { requests } }")), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_usage_stats() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let (user_id, username) = tester.create_random_user("password33");
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![site_id]));
+
+    user_tester.login(&username, "password33");
+    for _ in 0..3 {
+        user_tester.submit(query(r#"query usageStatsSite($id: Int!) {
+            site(id: $id) { id }
+        }"#).add_variable("id", site_id).operation_name("usageStatsSite"));
+    }
+
+    let res = tester.submit_admin(query(r#"query usageStats($start: NaiveDateTime!, $end: NaiveDateTime!) {
+        usageStats(start: $start, end: $end) {
+            requestsPerUser { userId requests quotaRejections }
+            expensiveOperations { operationName }
+            quotaRejections
+            siteVolume { siteId requests }
+        }
+    }"#).add_variable("start", 0.0).add_variable("end", 1e10));
+    // The login is counted too
+    assert!(res["requestsPerUser"].as_array().unwrap().contains(&json!({
+        "userId": user_id,
+        "requests": 4.0,
+        "quotaRejections": 0.0,
+    })));
+    assert!(res["expensiveOperations"].as_array().unwrap().contains(&json!({
+        "operationName": "usageStatsSite",
+    })));
+    assert!(res["quotaRejections"].is_number());
+    assert!(res["siteVolume"].as_array().unwrap().contains(&json!({
+        "siteId": site_id,
+        "requests": 3.0,
+    })));
+
+    assert_eq!(user_tester.submit_admin_status(query(r#"query usageStats($start: NaiveDateTime!, $end: NaiveDateTime!) {
+        usageStats(start: $start, end: $end) { quotaRejections }
+    }"#).add_variable("start", 0.0).add_variable("end", 1e10)), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_integration_secrets() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let res = tester.submit_admin(query("query { integrationStatus { fcmEnabled secretsEnabled } }"));
+    assert_eq!(res, json!({ "fcmEnabled": false, "secretsEnabled": true }));
+
+    tester.submit_admin_raw(query(r#"mutation {
+        setIntegrationSecret(name: FCM_API_KEY, value: "")
+    }"#)).expect_service_error("BAD_REQUEST");
+    tester.submit_admin(query(r#"mutation {
+        setIntegrationSecret(name: FCM_API_KEY, value: "fcm-key")
+    }"#));
+    let res = tester.submit_admin(query("query { integrationStatus { fcmEnabled secrets { name } } }"));
+    assert_eq!(res["fcmEnabled"], json!(true));
+    assert!(res["secrets"].as_array().unwrap().contains(&json!({ "name": "FCM_API_KEY" })));
+
+    // Without the stored key FCM falls back to the environment, where it's not configured
+    let res = tester.submit_admin(query(r#"mutation {
+        deleteIntegrationSecret(name: FCM_API_KEY)
+    }"#));
+    assert_eq!(res, json!(true));
+    let res = tester.submit_admin(query("query { integrationStatus { fcmEnabled } }"));
+    assert_eq!(res, json!({ "fcmEnabled": false }));
+
+    let (user_id, username) = tester.create_random_user("password35");
+    user_tester.login(&username, "password35");
+    assert_eq!(user_tester.submit_admin_status(query(r#"mutation {
+        setIntegrationSecret(name: SMTP_PASSWORD, value: "password")
+    }"#)), StatusCode::FORBIDDEN);
+    assert_eq!(user_tester.submit_admin_status(query("query { integrationStatus { fcmEnabled } }")), StatusCode::FORBIDDEN);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_quota_status() {
+    let mut tester = init_app();
+    let mut anon_tester = tester.clone();
+    tester.login_root();
+
+    anon_tester.submit_raw(query("query { quotaStatus { remaining } }")).expect_service_error("LOGIN_REQUIRED");
+
+    // The test server runs without quota
+    let res = tester.submit(query("query { quotaStatus(pool: WRITE) { remaining maxBalance refillPerSecond } }"));
+    assert_eq!(res, json!(null));
+}
+
+#[test]
+fn test_refund_on_client_error() {
+    let tester = init_app();
+    let ctx = Context::new(std::sync::Arc::new(tester.app_data().clone()), None, None, 100, 100);
+
+    // Rejected because of the request (ex. a mistyped id), the coins are given back
+    for error in vec![ServiceError::BadRequest("wrong input".to_string()), ServiceError::NotFound("Site".to_string())] {
+        let res: ServiceResult<()> = ctx.refund_on_client_error(|| {
+            ctx.spend_request_coins("refundTest", 10);
+            Err(error)
+        });
+        assert!(res.is_err());
+        assert_eq!(ctx.get_quota_coins(QuotaPool::Read), 100);
+        assert_eq!(ctx.spent_coins(), 0);
+    }
+
+    // The failures of the server are charged like the successes
+    let res: ServiceResult<()> = ctx.refund_on_client_error(|| {
+        ctx.spend_request_coins("refundTest", 10);
+        Err(ServiceError::InternalServerError("database down".to_string()))
+    });
+    assert!(res.is_err());
+    assert_eq!(ctx.get_quota_coins(QuotaPool::Read), 90);
+    assert_eq!(ctx.spent_coins(), 10);
+
+    ctx.refund_on_client_error(|| {
+        ctx.spend_request_coins("refundTest", 5);
+        Ok(())
+    }).unwrap();
+    assert_eq!(ctx.get_quota_coins(QuotaPool::Read), 85);
+    assert_eq!(ctx.spent_coins(), 15);
+}
+
+#[test]
+fn test_quota_warnings() {
+    // The quota actors need a running system
+    let _system = actix_rt::System::new("test_quota_warnings");
+    let mut tester = init_app_with(|data| {
+        data.quota_bank = Some(quota::QuotaBank::new(quota::init(1000, 1), quota::init(1000, 1)));
+        data.quota_costs.overrides = vec![("login".to_string(), 0), ("site".to_string(), 450)].into_iter().collect();
+    });
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let site_query = || json!({
+        "query": "query site($id: Int!) { site(id: $id) { id } }",
+        "variables": { "id": site_id },
+    });
+
+    let res = tester.submit_raw_req(TestRequest::post().uri("/api/graphql").set_json(&site_query()));
+    let body = serde_json::from_slice::<Value>(&res.1).unwrap();
+    assert_eq!(body["data"]["site"]["id"], site_id);
+    assert_eq!(body["extensions"], json!(null));
+
+    // Less than 20% of the read quota is left, the request still succeeds
+    let res = tester.submit_raw_req(TestRequest::post().uri("/api/graphql").set_json(&site_query()));
+    let body = serde_json::from_slice::<Value>(&res.1).unwrap();
+    assert_eq!(body["data"]["site"]["id"], site_id);
+    let warnings = body["extensions"]["quotaWarnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["pool"], "READ");
+    assert_eq!(warnings[0]["maxBalance"], 1000);
+    assert!(warnings[0]["balance"].as_i64().unwrap() < 200);
+    assert!(warnings[0]["recoverAfter"].as_i64().unwrap() > 0);
+}
+
+#[test]
+fn test_quota_retry_after() {
+    let _system = actix_rt::System::new("test_quota_retry_after");
+    let mut tester = init_app_with(|data| {
+        data.quota_bank = Some(quota::QuotaBank::new(quota::init(1000, 1), quota::init(1000, 1)));
+        data.quota_costs.overrides = vec![("login".to_string(), 0), ("site".to_string(), 1500)].into_iter().collect();
+    });
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let username = create_random_username();
+    let user_id = tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password97", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()))["id"].to_i64();
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![site_id]));
+    user_tester.login(&username, "password97");
+
+    let site_query = || json!({
+        "query": "query site($id: Int!) { site(id: $id) { id } }",
+        "variables": { "id": site_id },
+    });
+    // The first request overdraws the read pool
+    let res = user_tester.submit_raw_req(TestRequest::post().uri("/api/graphql").set_json(&site_query()));
+    let body = serde_json::from_slice::<Value>(&res.1).unwrap();
+    assert_eq!(body["data"]["site"]["id"], site_id);
+    assert_eq!(body["extensions"]["retryAfter"], json!(null));
+
+    // The client is told when the pool will have a coin again
+    let res = user_tester.submit_raw_req(TestRequest::post().uri("/api/graphql").set_json(&site_query()));
+    let body = serde_json::from_slice::<Value>(&res.1).unwrap();
+    assert_eq!(body["errors"][0]["extensions"]["type"], "TOO_MANY_REQUESTS");
+    let retry_after = body["extensions"]["retryAfter"].as_i64().unwrap();
+    assert!(retry_after > 490 && retry_after <= 501, "retryAfter: {}", retry_after);
+
+    // The REST facade is charged to the same pool
+    let res = user_tester.submit_raw_req(TestRequest::get().uri(&format!("/api/v1/sites/{}", site_id)));
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.0);
+
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_quota_snapshot() {
+    let _system = actix_rt::System::new("test_quota_snapshot");
+    let bank = || quota::QuotaBank::new(quota::init(1000, 1), quota::init(1000, 1));
+    let mut tester = init_app_with(|data| data.quota_bank = Some(bank()));
+    tester.login_root();
+    let user_id = tester.submit(query("query { userMe { id } }"))["id"].to_i64() as i32;
+
+    let data = tester.app_data();
+    let conn = data.pool.get().unwrap();
+    let saved_at = chrono::Utc::now().naive_utc();
+    let now = std::time::Instant::now();
+    data.quota_bank.as_ref().unwrap().pool(quota::QuotaPool::Write).set_quota_balance(now, user_id, 100);
+    assert_eq!(data.quota_bank.as_ref().unwrap().save_snapshot(&conn, saved_at).unwrap(), 1);
+
+    // A restarted server gets the balance back, plus what was refilled while it was down
+    let restarted = bank();
+    let restored_at = saved_at + chrono::Duration::seconds(50);
+    assert_eq!(restarted.restore_snapshot(&conn, restored_at).unwrap(), 1);
+    assert_eq!(restarted.pool(quota::QuotaPool::Write).get_quota_balance(now, user_id), 150);
+    assert_eq!(restarted.pool(quota::QuotaPool::Read).get_quota_balance(now, user_id), 1000);
+
+    // The snapshot is only restored once
+    assert_eq!(bank().restore_snapshot(&conn, restored_at).unwrap(), 0);
+}
+
+#[test]
+fn test_admin_network() {
+    let mut tester = init_app_with(|data| {
+        data.admin_network = Some(AdminNetworkPolicy {
+            networks: vec!["10.0.0.0/8".parse().unwrap()],
+            vpn_header: Some("X-Vpn-Gateway".to_string()),
+        });
+    });
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    fn delete_site(tester: &mut impl GraphQlTester, req: TestRequest, site_id: i64) -> (StatusCode, Value) {
+        let (status, body) = tester.submit_raw_req(req
+            .uri("/api/admin/graphql")
+            .set_json(&json!({
+                "query": "mutation deleteSite($id: Int!) { deleteSite(id: $id) }",
+                "variables": { "id": site_id },
+            })));
+        (status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null))
+    }
+
+    let mut unrestricted_tester = init_app();
+    unrestricted_tester.login_root();
+    let mut add_site = || unrestricted_tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+
+    // The main schema is still allowed
+    let site_id = add_site();
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res, json!({ "id": site_id }));
+
+    let (status, _) = delete_site(&mut tester, TestRequest::post(), site_id);
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (_, res) = delete_site(&mut tester, TestRequest::post().peer_addr("10.1.2.3:4000".parse().unwrap()), site_id);
+    assert_eq!(res["data"]["deleteSite"], json!(true));
+
+    let site_id = add_site();
+    let (_, res) = delete_site(&mut tester, TestRequest::post().header("X-Vpn-Gateway", "wg0"), site_id);
+    assert_eq!(res["data"]["deleteSite"], json!(true));
+
+    // The users can still change their own password from anywhere
+    let (user_id, username) = unrestricted_tester.create_random_user("password98");
+    user_tester.login(&username, "password98");
+    let res = user_tester.submit(query(r#"mutation changePassword($id: Int!) {
+        updateUser(id: $id, data: { password: "password22" }) { id }
+    }"#).add_variable("id", user_id));
+    assert_eq!(res, json!({ "id": user_id }));
+    user_tester.login(&username, "password22");
+
+    // Cleanup
+    unrestricted_tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_admin_schema() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    let mut anon_tester = tester.clone();
+    tester.login_root();
+
+    let (user_id, username) = tester.create_random_user("password98");
+    let res = tester.submit_admin(query("query { users { id username } }"));
+    assert!(res.as_array().unwrap().contains(&json!({ "id": user_id, "username": username })));
+
+    // The admin operations aren't in the main schema anymore
+    assert!(tester.submit_raw(query("query { users { id } }")).is_err());
+    assert!(tester.submit_raw(query("query { apiKeys { id } }")).is_err());
+
+    user_tester.login(&username, "password98");
+    assert_eq!(user_tester.submit_admin_status(query("query { users { id } }")), StatusCode::FORBIDDEN);
+    assert_eq!(anon_tester.submit_admin_status(query("query { users { id } }")), StatusCode::UNAUTHORIZED);
+
+    // The admin networks also restrict the admin schema
+    let mut restricted_tester = init_app_with(|data| {
+        data.admin_network = Some(AdminNetworkPolicy {
+            networks: vec!["10.0.0.0/8".parse().unwrap()],
+            vpn_header: None,
+        });
+    });
+    restricted_tester.login_root();
+    let users_query = || json!({ "query": "query { users { id } }" });
+    let (status, _) = restricted_tester.submit_raw_req(TestRequest::post()
+        .uri("/api/admin/graphql")
+        .set_json(&users_query()));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = restricted_tester.submit_raw_req(TestRequest::post()
+        .uri("/api/admin/graphql")
+        .peer_addr("10.1.2.3:4000".parse().unwrap())
+        .set_json(&users_query()));
+    assert_eq!(status, StatusCode::OK);
+    assert!(serde_json::from_slice::<Value>(&body).unwrap()["data"]["users"].is_array());
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+}
+
+#[test]
+fn test_ingest_readings() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let read_key = tester.submit_admin(query(r#"mutation {
+        createApiKey(data: { name: "dashboard" }) { key apiKey { canIngest } }
+    }"#));
+    assert_eq!(read_key["apiKey"]["canIngest"], json!(false));
+    let read_key = read_key["key"].as_str().unwrap().to_string();
+    let ingest_key = tester.submit_admin(query(r#"mutation {
+        createApiKey(data: { name: "gateway", canIngest: true }) { key apiKey { canIngest } }
+    }"#));
+    assert_eq!(ingest_key["apiKey"]["canIngest"], json!(true));
+    let ingest_key = ingest_key["key"].as_str().unwrap().to_string();
+
+    let reading = json!({ "timestamp": "2020-04-03T10:00:00Z", "value": 21.5 });
+    let batch = |channel_id: i64, count: usize| json!({
+        "channelId": channel_id,
+        "readings": vec![reading.clone(); count],
+    });
+    let mut anon_tester = init_app();
+    let mut ingest = |key: Option<&str>, body: serde_json::Value| {
+        let mut req = TestRequest::post().uri("/api/ingest/readings");
+        if let Some(key) = key {
+            req = req.header("X-Api-Key", key);
+        }
+        anon_tester.submit_raw_req(req.set_json(&body)).0
+    };
+
+    assert_eq!(StatusCode::UNAUTHORIZED, ingest(None, batch(channel_id, 1)));
+    // The read-only keys can't push readings
+    assert_eq!(StatusCode::FORBIDDEN, ingest(Some(&read_key), batch(channel_id, 1)));
+    assert_eq!(StatusCode::BAD_REQUEST, ingest(Some(&ingest_key), json!({ "readings": [] })));
+    assert_eq!(StatusCode::BAD_REQUEST, ingest(Some(&ingest_key), batch(channel_id, 1001)));
+    assert_eq!(StatusCode::NOT_FOUND, ingest(Some(&ingest_key), batch(-1, 1)));
+
+    let username = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()));
+    user_tester.login(&username, "password41");
+    // The channels of the sites without access are hidden
+    user_tester.submit_raw(query(r#"mutation ingestReadings($channelId: Int!) {
+        ingestReadings(channelId: $channelId, readings: [{ timestamp: 1585908000, value: 21.5 }])
+    }"#).add_variable("channelId", channel_id)).expect_service_error("NOT_FOUND");
+}
+
+#[test]
+fn test_access_alerts() {
+    let mut tester = init_app_with(|data| {
+        data.access_monitor = AccessMonitor::new(AccessMonitorConfig {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            throttle: Some(Duration::from_secs(60)),
+        });
+    });
+    let mut user_testers = [tester.clone(), tester.clone()];
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    for user_tester in user_testers.iter_mut() {
+        let username = create_random_username();
+        tester.submit_admin(query(r#"mutation addUser($username: String!) {
+            addUser(data: { username: $username, password: "password41", permission: USER }) { id }
+        }"#).add_variable("username", username.clone()));
+        user_tester.login(&username, "password41");
+    }
+
+    // The user keeps asking for a site it can't see
+    for _ in 0..3 {
+        user_testers[0].submit_raw(query(r#"query site($id: Int!) {
+            site(id: $id) { id }
+        }"#).add_variable("id", site_id)).expect_service_error("NOT_FOUND");
+    }
+    let res = user_testers[0].submit_raw_req(TestRequest::post().uri("/api/graphql")
+        .set_json(&json!({ "query": "query { userMe { id } }" })));
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.0);
+
+    // The other users aren't affected
+    let res = user_testers[1].submit(query("query { userMe { id } }"));
+    assert!(res["id"].is_number());
+}
+
+#[derive(Default)]
+struct MemoryReadingsWriter {
+    readings: std::cell::RefCell<Vec<NewReading>>,
+}
+
+impl ReadingsWriter for MemoryReadingsWriter {
+    fn insert_readings(&self, readings: &[NewReading]) -> Result<(), DatabaseError> {
+        self.readings.borrow_mut().extend_from_slice(readings);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mqtt_store_messages() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_cnr_id = create_random_username();
+    let site_id = tester.submit_admin(query(r#"mutation addSite($idCnr: String!) {
+        addSite(data: { idCnr: $idCnr }) { id }
+    }"#).add_variable("idCnr", site_cnr_id.clone()))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { idCnr: "S1" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { idCnr: "C1", measureUnit: "°C" }) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let received_at = NaiveDateTime::from_timestamp(1585908000, 0);
+    let message = |topic: String, payload: &str| (MqttMessage { topic, payload: payload.as_bytes().to_vec() }, received_at);
+    let topic = format!("site/{}/sensor/{}/channel/{}", site_cnr_id, sensor_id, channel_id);
+    let messages = vec![
+        message(topic.clone(), "21.5"),
+        message(topic.clone(), r#"{"value": 22, "timestamp": "2020-04-03T08:00:00Z"}"#),
+        message(topic.clone(), "invalid"),
+        // The channel doesn't belong to the site
+        message(format!("site/other/sensor/{}/channel/{}", sensor_id, channel_id), "1"),
+        message("unrelated/topic".to_string(), "1"),
+    ];
+
+    let writer = MemoryReadingsWriter::default();
+    let count = store_messages(&tester.app_data().pool, &writer, &tester.app_data().readings_cache, &messages).unwrap();
+    assert_eq!(count, 2);
+    let readings = writer.readings.into_inner();
+    assert_eq!(readings[0], NewReading {
+        site_id: site_cnr_id,
+        room_id: "".to_string(),
+        station_id: "mqtt".to_string(),
+        sensor_id: "S1".to_string(),
+        channel_id: "C1".to_string(),
+        value: 21.5,
+        measure_unit: "°C".to_string(),
+        date: received_at,
+    });
+    assert_eq!(readings[1].date, NaiveDateTime::from_timestamp(1585900800, 0));
+    assert_eq!(readings[1].value, 22.0);
+}
+
+#[test]
+fn test_mutation_transactions() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_ids: Vec<i64> = (0..2).map(|_| {
+        tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64()
+    }).collect();
+    let (user_id, username) = tester.create_random_user("123");
+    user_tester.login(&username, "123");
+
+    // The missing site rolls back the access to the first one
+    tester.submit_admin_raw(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![site_ids[0], -1])).expect_service_error("NOT_FOUND");
+    assert_eq!(user_tester.submit(query("query { sites { id } }")), json!([]));
+
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", site_ids.clone()));
+    tester.submit_admin_raw(query(r#"mutation revokeAccess($userId: Int!, $siteIds: [Int!]!) {
+        revokeUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![site_ids[0], -1])).expect_service_error("NOT_FOUND");
+    assert_eq!(user_tester.submit(query("query { sites { id } }")), json!([
+        {"id": site_ids[0]},
+        {"id": site_ids[1]},
+    ]));
+
+    // The sensors cannot be created without the readings store, so neither is the site
+    let id_cnr = create_random_username();
+    tester.submit_admin_raw(query(r#"mutation addSite($idCnr: String!) {
+        addSite(data: { idCnr: $idCnr, autoCreate: true }) { id }
+    }"#).add_variable("idCnr", id_cnr.clone())).expect_err("The site shouldn't be created");
+    let res = tester.submit(query("query { sites { idCnr } }"));
+    assert!(res.as_array().unwrap().iter().all(|x| x["idCnr"] != json!(id_cnr)));
+}
+
+#[test]
+fn test_alarm_acknowledgement() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { enabled: true }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let acknowledge = r#"mutation acknowledge($id: Int!) {
+        acknowledgeAlarm(channelId: $id, note: "Window left open") { acknowledgedBy, acknowledgeNote, endedAt }
+    }"#;
+    tester.submit_raw(query(acknowledge).add_variable("id", channel_id)).expect_service_error("BAD_REQUEST");
+
+    // Simulates the beginning of an alarm
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        diesel::sql_query(format!("UPDATE channel SET alarmed = TRUE WHERE id = {}", channel_id))
+            .execute(&conn).unwrap();
+        diesel::sql_query(format!(
+            "INSERT INTO alarm_event (channel_id, started_at, peak_value, extreme_type) VALUES ({}, NOW(), 40, 'M')",
+            channel_id
+        )).execute(&conn).unwrap();
+    }
+    let status_query = r#"query status($id: Int!) {
+        sensor(id: $id) { status }
+    }"#;
+    let res = tester.submit(query(status_query).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "status": "ALARM" }));
+
+    // Users that can't see the channel can't acknowledge its alarms
+    let (user_id, username) = tester.create_random_user("password61");
+    user_tester.login(&username, "password61");
+    user_tester.submit_raw(query(acknowledge).add_variable("id", channel_id)).expect_service_error("NOT_FOUND");
+
+    let res = tester.submit(query(acknowledge).add_variable("id", channel_id));
+    assert_eq!(res["acknowledgeNote"], json!("Window left open"));
+    assert_eq!(res["endedAt"], json!(null));
+    assert!(res["acknowledgedBy"].is_number());
+    let res = tester.submit(query(status_query).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "status": "ALARM_ACKNOWLEDGED" }));
+
+    // Muting
+    let mute = r#"mutation mute($id: Int!, $until: NaiveDateTime!) {
+        muteChannel(channelId: $id, until: $until) { mutedUntil }
+    }"#;
+    user_tester.submit_raw(query(mute).add_variable("id", channel_id).add_variable("until", 4102444800.0))
+        .expect_service_error("NOT_FOUND");
+    let res = tester.submit(query(mute).add_variable("id", channel_id).add_variable("until", 4102444800.0));
+    assert_eq!(res, json!({ "mutedUntil": 4102444800.0 }));
+    let res = tester.submit(query(mute).add_variable("id", channel_id).add_variable("until", 0.0));
+    assert_eq!(res, json!({ "mutedUntil": null }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_access_policy() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    let mut strict_user_tester = init_app_with(|data| {
+        data.access_policy = AccessPolicy { hidden_error: HiddenResourceError::Unauthorized };
+    });
+    tester.login_root();
+
+    let visible_site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let hidden_site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let (user_id, username) = tester.create_random_user("password62");
+    tester.submit_admin(query(r#"mutation giveAccess($userId: Int!, $siteIds: [Int!]!) {
+        giveUserAccess(userId: $userId, siteIds: $siteIds)
+    }"#).add_variable("userId", user_id).add_variable("siteIds", vec![visible_site_id]));
+    user_tester.login(&username, "password62");
+    strict_user_tester.login(&username, "password62");
+
+    let site_query = r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#;
+    let site_map_uri = |id: i64| format!("/api/site_map/{}", id);
+
+    // GraphQL and the map routes give the same answers
+    user_tester.submit_raw(query(site_query).add_variable("id", hidden_site_id)).expect_service_error("NOT_FOUND");
+    let res = user_tester.submit_raw_req(TestRequest::get().uri(&site_map_uri(hidden_site_id)));
+    assert_eq!(res.0, StatusCode::NOT_FOUND);
+    user_tester.submit_raw(query("query sites($ids: [Int!]) { sites(ids: $ids) { id } }")
+        .add_variable("ids", vec![visible_site_id, hidden_site_id])).expect_service_error("NOT_FOUND");
+
+    // Forbidden actions on visible resources
+    let res = user_tester.submit_raw_req(TestRequest::delete().uri(&site_map_uri(visible_site_id)));
+    assert_eq!(res.0, StatusCode::FORBIDDEN);
+    user_tester.submit_raw(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", visible_site_id)).expect_service_error("UNAUTHORIZED");
+
+    // The hidden resources can be reported as unauthorized
+    strict_user_tester.submit_raw(query(site_query).add_variable("id", hidden_site_id)).expect_service_error("UNAUTHORIZED");
+    let res = strict_user_tester.submit_raw_req(TestRequest::get().uri(&site_map_uri(hidden_site_id)));
+    assert_eq!(res.0, StatusCode::FORBIDDEN);
+    let res = strict_user_tester.submit(query(site_query).add_variable("id", visible_site_id));
+    assert_eq!(res, json!({ "id": visible_site_id }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id));
+    for id in [visible_site_id, hidden_site_id] {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", id));
+    }
+}
+
+#[test]
+fn test_sensor_no_data() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { enabled: true, maxSilence: 3600 }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+
+    let status_query = r#"query status($id: Int!) {
+        sensor(id: $id) { status, maxSilence, noDataSince }
+    }"#;
+    let res = tester.submit(query(status_query).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "status": "OK", "maxSilence": 3600, "noDataSince": null }));
+
+    // Simulates the alarm raised by the controller
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        diesel::sql_query(format!("UPDATE sensor SET no_data_since = TO_TIMESTAMP(1577836800) WHERE id = {}", sensor_id))
+            .execute(&conn).unwrap();
+    }
+    let res = tester.submit(query(status_query).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "status": "NO_DATA", "maxSilence": 3600, "noDataSince": 1577836800.0 }));
+
+    let res = tester.submit(query(r#"mutation updateSensor($id: Int!) {
+        updateSensor(id: $id, data: { maxSilence: 0 }) { maxSilence }
+    }"#).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "maxSilence": 0 }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_export_job() {
+    use diesel::ExpressionMethods;
+    use oldmusa_server::schema::export_job::dsl;
+    use oldmusa_server::web::export_job::export_job_path;
+
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "export" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "room" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: { name: "temperature" }) { id }
+    }"#).add_variable("sensorId", sensor_id))["id"].to_i64() as i32;
+
+    // The readings can't be counted without the sensor database, the jobs are registered by hand
+    let data = tester.app_data().clone();
+    let insert_job = |status: &str| {
+        let conn = data.pool.get().unwrap();
+        diesel::insert_into(dsl::export_job)
+            .values((
+                dsl::channel_id.eq(channel_id),
+                dsl::format.eq("csv"),
+                dsl::status.eq(status),
+                dsl::row_count.eq(1i64),
+                dsl::created_at.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .returning(dsl::id)
+            .get_result::<i32>(&conn)
+            .unwrap()
+    };
+    let running_id = insert_job("r");
+    let done_id = insert_job("d");
+    std::fs::create_dir_all(&data.export_jobs.directory).unwrap();
+    let file = data.export_jobs.directory.join(format!("job_{}.csv", done_id));
+    std::fs::write(&file, "date,value_min\n").unwrap();
+
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_job_path(&data, running_id)));
+    assert_eq!(StatusCode::ACCEPTED, res.0);
+    let body: Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body["status"], "RUNNING");
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&export_job_path(&data, done_id)));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(res.1.as_ref(), b"date,value_min\n");
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("/api/export/job/{}?signature=00", done_id)));
+    assert_eq!(StatusCode::FORBIDDEN, res.0);
+
+    // Cleanup
+    std::fs::remove_file(&file).unwrap();
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_channel_max_delta() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { enabled: true }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let res = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { rangeMin: 40, rangeMax: 60, maxDeltaPerHour: 12.5 }) { id, maxDeltaPerHour }
+    }"#).add_variable("id", sensor_id));
+    assert_eq!(res["maxDeltaPerHour"], json!(12.5));
+    let channel_id = res["id"].to_i64();
+
+    // Simulates a rate of change alarm raised by the controller
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        diesel::sql_query(format!(
+            "INSERT INTO alarm_event (channel_id, started_at, ended_at, peak_value, extreme_type) VALUES ({}, TO_TIMESTAMP(1577836800), TO_TIMESTAMP(1577840400), 30, 'd')",
+            channel_id
+        )).execute(&conn).unwrap();
+    }
+    let res = tester.submit(query(r#"query alarmHistory($id: Int!) {
+        channel(id: $id) { alarmHistory(start: 0, end: 2000000000) { peakValue, extremeType } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({"alarmHistory": [{ "peakValue": 30.0, "extremeType": "DELTA" }]}));
+
+    let res = tester.submit(query(r#"mutation updateChannel($id: Int!) {
+        updateChannel(id: $id, data: { maxDeltaPerHour: 0 }) { maxDeltaPerHour, rangeMax }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({ "maxDeltaPerHour": 0.0, "rangeMax": 60.0 }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_channel_alarm_debounce() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { enabled: true }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let res = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { rangeMin: 40, rangeMax: 60, hysteresis: 2.5, alarmDelay: 600 }) {
+            id, hysteresis, alarmDelay, alarmPendingSince
+        }
+    }"#).add_variable("id", sensor_id));
+    assert_eq!(res["hysteresis"], json!(2.5));
+    assert_eq!(res["alarmDelay"], json!(600));
+    assert_eq!(res["alarmPendingSince"], json!(null));
+    let channel_id = res["id"].to_i64();
+
+    let res = tester.submit(query(r#"mutation updateChannel($id: Int!) {
+        updateChannel(id: $id, data: { alarmDelay: 0 }) { hysteresis, alarmDelay }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({ "hysteresis": 2.5, "alarmDelay": 0 }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_seed_demo() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let conn = tester.app_data().pool.get().unwrap();
+    diesel::sql_query("DELETE FROM site WHERE id_cnr IN ('DEMO-HALL', 'DEMO-ARCHIVE')")
+        .execute(&conn)
+        .unwrap();
+
+    let writer = MemoryReadingsWriter::default();
+    let now = NaiveDateTime::from_timestamp(1586419200, 0);
+    let report = seed_demo(tester.app_data(), &writer, now, 1).unwrap();
+    assert_eq!(report.site_ids.len(), 2);
+    assert_eq!(report.sensors, 5);
+    assert_eq!(report.channels, 12);
+    // One reading every 15 minutes, both ends included
+    assert_eq!(report.readings, 12 * 97);
+    assert_eq!(writer.readings.borrow().len(), report.readings);
+    assert!(writer.readings.borrow().iter().all(|x| x.value.is_finite() && x.date <= now));
+
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { idCnr, imageWidth, sensors { name, channels { name, rangeMin, rangeMax } } }
+    }"#).add_variable("id", report.site_ids[0]));
+    assert_eq!(res["idCnr"], "DEMO-HALL");
+    assert_eq!(res["imageWidth"], 800);
+    assert_eq!(res["sensors"].as_array().unwrap().len(), 3);
+    assert_eq!(res["sensors"][0]["channels"][0], json!({ "name": "Temperature", "rangeMin": 18.0, "rangeMax": 24.0 }));
+
+    // The floor plan is a png
+    let plan = tester.app_data().site_maps.read(&get_file_from_site(report.site_ids[0])).unwrap().unwrap();
+    assert_eq!(&plan[1..4], b"PNG");
+
+    // The demo museum can't be seeded twice
+    assert!(seed_demo(tester.app_data(), &writer, now, 1).is_err());
+
+    for site_id in report.site_ids.iter() {
+        assert!(tester.app_data().site_maps.delete(&get_file_from_site(*site_id)).unwrap());
+    }
+    diesel::sql_query("DELETE FROM site WHERE id_cnr IN ('DEMO-HALL', 'DEMO-ARCHIVE')")
+        .execute(&conn)
+        .unwrap();
+}
+
+#[test]
+fn test_site_webhooks() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+
+    let add_webhook = r#"mutation addSiteWebhook($id: Int!, $url: String!) {
+        addSiteWebhook(siteId: $id, data: { url: $url, secret: "s3cret" }) { id, siteId, url }
+    }"#;
+    tester.submit_raw(query(add_webhook).add_variable("id", site_id).add_variable("url", "ftp://bms.local/alarms"))
+        .expect_service_error("BAD_REQUEST");
+    let webhook = tester.submit(query(add_webhook).add_variable("id", site_id).add_variable("url", "https://bms.local/alarms"));
+    assert_eq!(webhook["siteId"], site_id);
+    assert_eq!(webhook["url"], "https://bms.local/alarms");
+
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { webhooks { id, url } }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res["webhooks"], json!([{ "id": webhook["id"], "url": "https://bms.local/alarms" }]));
+
+    let delete_webhook = r#"mutation deleteSiteWebhook($id: Int!) {
+        deleteSiteWebhook(id: $id)
+    }"#;
+    assert_eq!(tester.submit(query(delete_webhook).add_variable("id", webhook["id"].to_i64())), true);
+    tester.submit_raw(query(delete_webhook).add_variable("id", webhook["id"].to_i64()))
+        .expect_service_error("NOT_FOUND");
+}
+
+#[test]
+fn test_site_status() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "Hall" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { name: "Temperature" }) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+    let webhook = tester.submit(query(r#"mutation addSiteWebhook($id: Int!) {
+        addSiteWebhook(siteId: $id, data: { url: "http://127.0.0.1:9/status", secret: "s3cret", statusChanges: true }) { statusChanges }
+    }"#).add_variable("id", site_id));
+    assert_eq!(webhook["statusChanges"], true);
+
+    fn site_status(tester: &mut impl GraphQlTester, site_id: i64) -> Value {
+        tester.submit(query(r#"query site($id: Int!) { site(id: $id) { status } }"#).add_variable("id", site_id))["status"].clone()
+    }
+    assert_eq!(site_status(&mut tester, site_id), "OK");
+
+    let data = tester.app_data().clone();
+    let conn = data.pool.get().unwrap();
+    let mut system = actix_rt::System::new("test_site_status");
+    let mut update = |statements: &[String]| {
+        for sql in statements {
+            diesel::sql_query(sql.as_str()).execute(&conn).unwrap();
+        }
+        system.block_on(update_site_status(&data.contacter, &conn, site_id as i32, chrono::Utc::now().naive_utc())).unwrap()
+    };
+
+    assert!(update(&[format!("UPDATE sensor SET no_data_since = NOW() WHERE id = {}", sensor_id)]).is_some());
+    assert_eq!(site_status(&mut tester, site_id), "STALE");
+    // The alarm hides the missing data
+    assert!(update(&[format!("UPDATE channel SET alarmed = TRUE WHERE id = {}", channel_id)]).is_some());
+    assert_eq!(site_status(&mut tester, site_id), "ALARM");
+    assert!(update(&[]).is_none());
+    assert!(update(&[
+        format!("UPDATE channel SET alarmed = FALSE WHERE id = {}", channel_id),
+        format!("UPDATE sensor SET no_data_since = NULL WHERE id = {}", sensor_id),
+    ]).is_some());
+    assert_eq!(site_status(&mut tester, site_id), "OK");
+}
+
+#[test]
+fn test_create_discovered_channels() {
+    use diesel::QueryDsl;
+    use oldmusa_server::schema::discovered_channel::dsl;
+
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { idCnr: "SITE" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { idCnr: "S1", name: "Hall" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+
+    // The job needs the sensor database, the notified channel is registered by hand
+    let conn = tester.app_data().pool.get().unwrap();
+    diesel::sql_query(format!(
+        "INSERT INTO discovered_channel VALUES ({}, 'S2', '3', NOW(), NOW())", site_id
+    )).execute(&conn).unwrap();
+
+    let mutation = discovery_mutation(site_id as i32, &[
+        ("S1".to_string(), "1".to_string()),
+        ("S2".to_string(), "3".to_string()),
+    ]);
+    let created = tester.submit(query(&mutation));
+    assert_eq!(created.as_array().unwrap().len(), 2);
+    assert_eq!(created[1]["name"], "3");
+    // Running it again reuses the channels
+    let again = tester.submit(query(&mutation));
+    assert_eq!(again, created);
+
+    let site = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { sensors { id idCnr name channels { idCnr } } }
+    }"#).add_variable("id", site_id));
+    let sensors = site["sensors"].as_array().unwrap();
+    assert_eq!(sensors.len(), 2);
+    assert_eq!(sensors[0]["id"].to_i64(), sensor_id);
+    assert_eq!(sensors[0]["name"], "Hall");
+    assert_eq!(sensors[0]["channels"][0]["idCnr"], "1");
+    assert_eq!(sensors[1]["idCnr"], "S2");
+    assert_eq!(sensors[1]["name"], "S2");
+
+    let remaining: i64 = dsl::discovered_channel.count().get_result(&conn).unwrap();
+    assert_eq!(remaining, 0);
+}
+
+#[test]
+fn test_escalation_policies() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_ids: Vec<i64> = (0..3).map(|_| {
+        tester.submit(query(r#"mutation addChannel($id: Int!) {
+            addChannel(sensorId: $id, data: {}) { id }
+        }"#).add_variable("id", sensor_id))["id"].to_i64()
+    }).collect();
+
+    tester.submit_admin_raw(query(r#"mutation {
+        addEscalationPolicy(data: { delayMinutes: 10, target: USERS }) { id }
+    }"#)).expect_service_error("BAD_REQUEST");
+    let policy = tester.submit_admin(query(r#"mutation {
+        addEscalationPolicy(data: { delayMinutes: 30, target: ADMINS }) { id, siteId, delayMinutes, target, userIds }
+    }"#));
+    let policy_id = policy["id"].to_i64();
+    assert_eq!(policy, json!({ "id": policy_id, "siteId": null, "delayMinutes": 30, "target": "ADMINS", "userIds": [] }));
+
+    let res = tester.submit_admin(query(r#"mutation updatePolicy($id: Int!, $siteId: Int!) {
+        updateEscalationPolicy(id: $id, data: { siteId: $siteId, delayMinutes: 10, target: SITE_MANAGERS }) { siteId, delayMinutes, target }
+    }"#).add_variable("id", policy_id).add_variable("siteId", site_id));
+    assert_eq!(res, json!({ "siteId": site_id, "delayMinutes": 10, "target": "SITE_MANAGERS" }));
+
+    let res = tester.submit_admin(query(r#"query policies($siteId: Int!) {
+        escalationPolicies(siteId: $siteId) { id }
+    }"#).add_variable("siteId", site_id));
+    assert!(res.as_array().unwrap().contains(&json!({ "id": policy_id })));
+
+    // An alarm waiting for 20 minutes, an acknowledged one and one too recent to be escalated
+    let now = NaiveDateTime::from_timestamp(1609000000, 0);
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        for (channel_id, started_at, acknowledged) in &[(channel_ids[0], 1608998800, "NULL"), (channel_ids[1], 1608998800, "TO_TIMESTAMP(1608999000)"), (channel_ids[2], 1608999700, "NULL")] {
+            diesel::sql_query(format!(
+                "INSERT INTO alarm_event (channel_id, started_at, peak_value, extreme_type, acknowledged_at) VALUES ({}, TO_TIMESTAMP({}), 30, 'M', {})",
+                channel_id, started_at, acknowledged
+            )).execute(&conn).unwrap();
+        }
+    }
+
+    let data = tester.app_data();
+    let conn = data.pool.get().unwrap();
+    assert_eq!(block_on(escalate_alarms(&data.contacter, &conn, now)), Ok(1));
+    // Every alarm is escalated only once
+    assert_eq!(block_on(escalate_alarms(&data.contacter, &conn, now)), Ok(0));
+
+    let delete_policy = r#"mutation deletePolicy($id: Int!) {
+        deleteEscalationPolicy(id: $id)
+    }"#;
+    assert_eq!(tester.submit_admin(query(delete_policy).add_variable("id", policy_id)), true);
+    tester.submit_admin_raw(query(delete_policy).add_variable("id", policy_id))
+        .expect_service_error("NOT_FOUND");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_user_sessions() {
+    let mut tester = init_app();
+    let mut phone = tester.clone();
+    let mut laptop = tester.clone();
+    tester.login_root();
+
+    let username = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password42", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()));
+    phone.login(&username, "password42");
+    laptop.login(&username, "password42");
+
+    let my_sessions = "{ mySessions { id current } }";
+    let revoke = r#"mutation revokeSession($id: Int!) { revokeSession(id: $id) }"#;
+    let sessions = phone.submit(query(my_sessions)).as_array().unwrap().clone();
+    assert_eq!(sessions.len(), 2);
+    let phone_session = sessions.iter().find(|x| x["current"] == json!(true)).unwrap()["id"].to_i64();
+    let laptop_session = sessions.iter().find(|x| x["current"] == json!(false)).unwrap()["id"].to_i64();
+
+    // A stolen cookie is logged out by revoking its session
+    assert_eq!(phone.submit(query(revoke).add_variable("id", laptop_session)), json!(true));
+    laptop.submit_raw(query(my_sessions)).expect_service_error("LOGIN_REQUIRED");
+    assert_eq!(phone.submit(query(revoke).add_variable("id", laptop_session)), json!(false));
+    // The sessions of the other users can't be revoked
+    assert_eq!(tester.submit(query(revoke).add_variable("id", phone_session)), json!(false));
+    assert_eq!(phone.submit(query(my_sessions)), json!([{ "id": phone_session, "current": true }]));
+
+    laptop.login(&username, "password42");
+    assert_eq!(laptop.submit(query("mutation { revokeAllSessions }")), json!(2));
+    phone.submit_raw(query(my_sessions)).expect_service_error("LOGIN_REQUIRED");
+    laptop.submit_raw(query(my_sessions)).expect_service_error("LOGIN_REQUIRED");
+
+    // Logging out ends the session
+    phone.login(&username, "password42");
+    laptop.login(&username, "password42");
+    phone.submit(query("mutation { logout }"));
+    let sessions = laptop.submit(query(my_sessions));
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_login_throttle() {
+    let mut tester = init_app_with(|data| {
+        data.auth_cache.login_throttle = LoginThrottle::new(LoginThrottleConfig {
+            free_failures: 1,
+            base_delay: Duration::from_secs(60),
+            ..LoginThrottleConfig::default()
+        });
+    });
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let username = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password43", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()));
+
+    let login = |username: &str, password: &str| query(r#"mutation login($auth: AuthInput!) {
+        login(auth: $auth) { id }
+    }"#).add_variable("auth", json!({ "username": username, "password": password }));
+
+    user_tester.submit_raw(login(&username, "password")).expect_service_error("WRONG_PASSWORD");
+    // Even the right password has to wait
+    user_tester.submit_raw(login(&username, "password43")).expect_service_error("LOGIN_THROTTLED");
+    user_tester.submit_raw(login(&username.to_uppercase(), "password43")).expect_service_error("LOGIN_THROTTLED");
+    // The other users aren't affected
+    let other = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password44", permission: USER }) { id }
+    }"#).add_variable("username", other.clone()));
+    user_tester.submit(login(&other, "password44"));
+}
+
+#[test]
+fn test_user_preferences() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let username = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()));
+    user_tester.login(&username, "password41");
+
+    let set_preference = r#"mutation setPreference($key: String!, $value: String) {
+        setPreference(key: $key, value: $value)
+    }"#;
+    let my_preferences = r#"query {
+        myPreferences { key, value }
+    }"#;
+    assert_eq!(user_tester.submit(query(my_preferences)), json!([]));
+
+    user_tester.submit(query(set_preference).add_variable("key", "units").add_variable("value", "metric"));
+    user_tester.submit(query(set_preference).add_variable("key", "defaultSite").add_variable("value", "12"));
+    user_tester.submit(query(set_preference).add_variable("key", "defaultSite").add_variable("value", "13"));
+    assert_eq!(user_tester.submit(query(my_preferences)), json!([
+        { "key": "defaultSite", "value": "13" },
+        { "key": "units", "value": "metric" },
+    ]));
+
+    user_tester.submit_raw(query(set_preference).add_variable("key", "").add_variable("value", "x"))
+        .expect_service_error("BAD_REQUEST");
+    user_tester.submit_raw(query(set_preference).add_variable("key", "chart").add_variable("value", "x".repeat(16 * 1024 + 1)))
+        .expect_service_error("BAD_REQUEST");
+
+    // The preferences are private
+    let res = tester.submit(query(my_preferences));
+    assert!(!res.as_array().unwrap().iter().any(|x| x["key"] == "units"));
+
+    user_tester.submit(query(set_preference).add_variable("key", "units"));
+    assert_eq!(user_tester.submit(query(my_preferences)), json!([{ "key": "defaultSite", "value": "13" }]));
+}
+
+#[test]
+fn test_rooms() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_ids: Vec<i64> = (0..2).map(|_| {
+        tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64()
+    }).collect();
+
+    let add_room = r#"mutation addRoom($siteId: Int!, $name: String!) {
+        addRoom(siteId: $siteId, data: { name: $name, floor: 1, locX: 10, locY: 20 }) { id, siteId, name, floor, locX, locY }
+    }"#;
+    tester.submit_raw(query(add_room).add_variable("siteId", site_ids[0]).add_variable("name", ""))
+        .expect_service_error("BAD_REQUEST");
+    let room = tester.submit(query(add_room).add_variable("siteId", site_ids[0]).add_variable("name", "Sala 3"));
+    let room_id = room["id"].to_i64();
+    assert_eq!(room, json!({ "id": room_id, "siteId": site_ids[0], "name": "Sala 3", "floor": 1, "locX": 10, "locY": 20 }));
+    let other_room_id = tester.submit(query(add_room).add_variable("siteId", site_ids[1]).add_variable("name", "Sala 1"))["id"].to_i64();
+
+    let add_sensor = r#"mutation addSensor($siteId: Int!, $roomId: Int!) {
+        addSensor(siteId: $siteId, data: { roomId: $roomId }) { id, roomId }
+    }"#;
+    // The room must be in the site of the sensor
+    tester.submit_raw(query(add_sensor).add_variable("siteId", site_ids[0]).add_variable("roomId", other_room_id))
+        .expect_service_error("BAD_REQUEST");
+    let sensor_id = tester.submit(query(add_sensor).add_variable("siteId", site_ids[0]).add_variable("roomId", room_id))["id"].to_i64();
+    tester.submit_raw(query(r#"mutation updateSensor($id: Int!, $roomId: Int!) {
+        updateSensor(id: $id, data: { roomId: $roomId }) { id }
+    }"#).add_variable("id", sensor_id).add_variable("roomId", other_room_id))
+        .expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit(query(r#"mutation updateRoom($id: Int!) {
+        updateRoom(id: $id, data: { name: "Sala 4", floor: 2 }) { name, floor, locX }
+    }"#).add_variable("id", room_id));
+    assert_eq!(res, json!({ "name": "Sala 4", "floor": 2, "locX": 10 }));
+
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { rooms { name, sensors { id, room { name } } } }
+    }"#).add_variable("id", site_ids[0]));
+    assert_eq!(res, json!({ "rooms": [{ "name": "Sala 4", "sensors": [{ "id": sensor_id, "room": { "name": "Sala 4" } }] }] }));
+
+    // The sensors of a deleted room are kept
+    let delete_room = r#"mutation deleteRoom($id: Int!) {
+        deleteRoom(id: $id)
+    }"#;
+    assert_eq!(tester.submit(query(delete_room).add_variable("id", room_id)), true);
+    tester.submit_raw(query(delete_room).add_variable("id", room_id))
+        .expect_service_error("NOT_FOUND");
+    let res = tester.submit(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { roomId, room { id } }
+    }"#).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "roomId": null, "room": null }));
+
+    // Cleanup
+    for site_id in site_ids {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", site_id));
+    }
+}
+
+#[test]
+fn test_merge_sensors() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_ids: Vec<i64> = (0..2).map(|_| {
+        tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64()
+    }).collect();
+
+    let add_sensor = r#"mutation addSensor($siteId: Int!) {
+        addSensor(siteId: $siteId, data: { name: "logger" }) { id }
+    }"#;
+    let source_id = tester.submit(query(add_sensor).add_variable("siteId", site_ids[0]))["id"].to_i64();
+    let target_id = tester.submit(query(add_sensor).add_variable("siteId", site_ids[0]))["id"].to_i64();
+    let other_id = tester.submit(query(add_sensor).add_variable("siteId", site_ids[1]))["id"].to_i64();
+
+    let add_channel = r#"mutation addChannel($sensorId: Int!, $name: String!) {
+        addChannel(sensorId: $sensorId, data: { name: $name }) { id }
+    }"#;
+    let source_channel = tester.submit(query(add_channel).add_variable("sensorId", source_id).add_variable("name", "temp"))["id"].to_i64();
+    let target_channel = tester.submit(query(add_channel).add_variable("sensorId", target_id).add_variable("name", "hum"))["id"].to_i64();
+
+    let merge = r#"mutation mergeSensors($sourceId: Int!, $targetId: Int!) {
+        mergeSensors(sourceId: $sourceId, targetId: $targetId, name: "logger 1") { id, name, channels { id } }
+    }"#;
+    tester.submit_raw(query(merge).add_variable("sourceId", source_id).add_variable("targetId", source_id))
+        .expect_service_error("BAD_REQUEST");
+    tester.submit_raw(query(merge).add_variable("sourceId", other_id).add_variable("targetId", target_id))
+        .expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit(query(merge).add_variable("sourceId", source_id).add_variable("targetId", target_id));
+    assert_eq!(res["id"], target_id);
+    assert_eq!(res["name"], "logger 1");
+    assert_eq_set(res["channels"].clone(), json!([{ "id": source_channel }, { "id": target_channel }]));
+    tester.submit_raw(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { id }
+    }"#).add_variable("id", source_id))
+        .expect_service_error("NOT_FOUND");
+
+    // Cleanup
+    for site_id in site_ids {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", site_id));
+    }
+}
+
+#[test]
+fn test_sensor_positions() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_ids: Vec<i64> = (0..2).map(|_| {
+        tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64()
+    }).collect();
+    let map_id = tester.submit(query(r#"mutation addSiteMap($siteId: Int!) {
+        addSiteMap(siteId: $siteId, data: { name: "Cellar" }) { id }
+    }"#).add_variable("siteId", site_ids[0]))["id"].to_i64();
+
+    let res = tester.submit_all(query(r#"mutation createSensors($siteId: Int!, $otherSiteId: Int!, $mapId: Int!) {
+        s1: addSensor(siteId: $siteId, data: { locX: 1, locY: 1 }) { id }
+        s2: addSensor(siteId: $siteId, data: { locX: 1, locY: 1, mapId: $mapId }) { id }
+        s3: addSensor(siteId: $otherSiteId, data: {}) { id }
+    }"#).add_variable("siteId", site_ids[0]).add_variable("otherSiteId", site_ids[1]).add_variable("mapId", map_id));
+    let s1 = res["s1"]["id"].to_i64();
+    let s2 = res["s2"]["id"].to_i64();
+    let s3 = res["s3"]["id"].to_i64();
+
+    let move_sensors = r#"mutation updateSensorPositions($siteId: Int!, $positions: [SensorPositionInput!]!) {
+        updateSensorPositions(siteId: $siteId, positions: $positions) { id, locX, locY }
+    }"#;
+    let positions = |x2: i32| json!([{ "sensorId": s1, "locX": 90, "locY": 40 }, { "sensorId": s2, "locX": x2, "locY": 150 }]);
+
+    // The maps have no image yet
+    tester.submit_raw(query(move_sensors).add_variable("siteId", site_ids[0]).add_variable("positions", positions(150)))
+        .expect_service_error("BAD_REQUEST");
+    let upload = |uri: String, width: u32, height: u32| {
+        TestRequest::post()
+            .uri(&uri)
+            .header(header::CONTENT_TYPE, "image/png")
+            .set_payload(png_image(width, height))
+    };
+    assert_eq!(StatusCode::OK, tester.submit_raw_req(upload(format!("/api/site_map/{}", site_ids[0]), 100, 50)).0);
+    assert_eq!(StatusCode::OK, tester.submit_raw_req(upload(format!("/api/site_map/{}/{}", site_ids[0], map_id), 200, 200)).0);
+
+    // Nothing is moved if a position is invalid
+    tester.submit_raw(query(move_sensors).add_variable("siteId", site_ids[0]).add_variable("positions", positions(250)))
+        .expect_service_error("BAD_REQUEST");
+    tester.submit_raw(query(move_sensors).add_variable("siteId", site_ids[0])
+        .add_variable("positions", json!([{ "sensorId": s1, "locX": 90, "locY": 40 }, { "sensorId": s3, "locX": 1, "locY": 1 }])))
+        .expect_service_error("NOT_FOUND");
+    let res = tester.submit(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { locX }
+    }"#).add_variable("id", s1));
+    assert_eq!(res, json!({ "locX": 1 }));
+
+    let res = tester.submit(query(move_sensors).add_variable("siteId", site_ids[0]).add_variable("positions", positions(150)));
+    assert_eq!(res, json!([{ "id": s1, "locX": 90, "locY": 40 }, { "id": s2, "locX": 150, "locY": 150 }]));
+
+    // Cleanup
+    for site_id in site_ids {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", site_id));
+    }
+}
+
+#[test]
+fn test_data_latency() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($siteId: Int!) {
+        addSensor(siteId: $siteId, data: {}) { id }
+    }"#).add_variable("siteId", site_id))["id"].to_i64();
+    tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: {}) { id }
+    }"#).add_variable("sensorId", sensor_id));
+
+    // Without cnr ids there are no readings to look for
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { worstDataLatency { channelId }, sensors { channels { dataLatency { seconds } } } }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res, json!({ "worstDataLatency": null, "sensors": [{ "channels": [{ "dataLatency": null }] }] }));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_soft_delete() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let res = tester.submit_all(query(r#"mutation createSensors($siteId: Int!) {
+        s1: addSensor(siteId: $siteId, data: {}) { id }
+        s2: addSensor(siteId: $siteId, data: {}) { id }
+    }"#).add_variable("siteId", site_id));
+    let s1 = res["s1"]["id"].to_i64();
+    let s2 = res["s2"]["id"].to_i64();
+    let res = tester.submit_all(query(r#"mutation createChannels($s1: Int!, $s2: Int!) {
+        c1: addChannel(sensorId: $s1, data: {}) { id }
+        c2: addChannel(sensorId: $s2, data: {}) { id }
+    }"#).add_variable("s1", s1).add_variable("s2", s2));
+    let c1 = res["c1"]["id"].to_i64();
+    let c2 = res["c2"]["id"].to_i64();
+
+    let delete_site = r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#;
+    let restore_site = r#"mutation restoreSite($id: Int!) {
+        restoreSite(id: $id) { id, deletedAt, sensors { id, channels { id } } }
+    }"#;
+    let restore_sensor = r#"mutation restoreSensor($id: Int!) {
+        restoreSensor(id: $id) { id, channels { id } }
+    }"#;
+    let trash = r#"{
+        trash { sites { id }, sensors { id }, channels { id } }
+    }"#;
+
+    // The sensor deleted on its own isn't restored with the site
+    tester.submit(query(r#"mutation deleteSensor($id: Int!) {
+        deleteSensor(id: $id)
+    }"#).add_variable("id", s2));
+    tester.submit_admin(query(delete_site).add_variable("id", site_id));
+    tester.submit_admin_raw(query(delete_site).add_variable("id", site_id))
+        .expect_service_error("NOT_FOUND");
+
+    let res = tester.submit(query(r#"{ sites { id } }"#));
+    assert!(!res.as_array().unwrap().contains(&json!({ "id": site_id })));
+    tester.submit_raw(query(r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#).add_variable("id", site_id)).expect_service_error("NOT_FOUND");
+    tester.submit_raw(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { id }
+    }"#).add_variable("id", s1)).expect_service_error("NOT_FOUND");
+
+    let res = tester.submit_admin(query(trash));
+    assert!(res["sites"].as_array().unwrap().contains(&json!({ "id": site_id })));
+    assert!(!res["sensors"].as_array().unwrap().contains(&json!({ "id": s2 })));
+
+    tester.submit_raw(query(restore_sensor).add_variable("id", s2))
+        .expect_service_error("BAD_REQUEST");
+    let res = tester.submit_admin(query(restore_site).add_variable("id", site_id));
+    assert_eq!(res, json!({ "id": site_id, "deletedAt": null, "sensors": [{ "id": s1, "channels": [{ "id": c1 }] }] }));
+    tester.submit_admin_raw(query(restore_site).add_variable("id", site_id))
+        .expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit_admin(query(trash));
+    assert!(res["sensors"].as_array().unwrap().contains(&json!({ "id": s2 })));
+    let res = tester.submit(query(restore_sensor).add_variable("id", s2));
+    assert_eq!(res, json!({ "id": s2, "channels": [{ "id": c2 }] }));
+
+    tester.submit(query(r#"mutation deleteChannel($id: Int!) {
+        deleteChannel(id: $id)
+    }"#).add_variable("id", c1));
+    let res = tester.submit(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { channels { id } }
+    }"#).add_variable("id", s1));
+    assert_eq!(res, json!({ "channels": [] }));
+    let res = tester.submit(query(r#"mutation restoreChannel($id: Int!) {
+        restoreChannel(id: $id) { id, deletedAt }
+    }"#).add_variable("id", c1));
+    assert_eq!(res, json!({ "id": c1, "deletedAt": null }));
+
+    // The purge deletes the site for good
+    tester.submit_admin(query(delete_site).add_variable("id", site_id));
+    let report = purge_trash(tester.app_data(), chrono::Utc::now().naive_utc()).unwrap();
+    assert!(report.sites >= 1 && report.sensors >= 2 && report.channels >= 2);
+    tester.submit_admin_raw(query(restore_site).add_variable("id", site_id))
+        .expect_service_error("NOT_FOUND");
+}
+
+#[test]
+fn test_changes_since() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($siteId: Int!) {
+        addSensor(siteId: $siteId, data: {}) { id }
+    }"#).add_variable("siteId", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: {}) { id }
+    }"#).add_variable("sensorId", sensor_id))["id"].to_i64();
+
+    let changes = r#"query changes($cursor: String) {
+        changesSince(cursor: $cursor) {
+            sites { id }, sensors { id, locX }, channels { id },
+            deletedSiteIds, deletedSensorIds, deletedChannelIds, nextCursor
+        }
+    }"#;
+
+    let res = tester.submit(query(changes));
+    assert!(res["sites"].as_array().unwrap().contains(&json!({ "id": site_id })));
+    assert!(res["channels"].as_array().unwrap().contains(&json!({ "id": channel_id })));
+    assert_eq!(res["deletedChannelIds"], json!([]));
+    let cursor = res["nextCursor"].as_str().unwrap().to_string();
+
+    tester.submit(query(r#"mutation updateSensor($id: Int!) {
+        updateSensor(id: $id, data: { locX: 42 }) { id }
+    }"#).add_variable("id", sensor_id));
+    tester.submit(query(r#"mutation deleteChannel($id: Int!) {
+        deleteChannel(id: $id)
+    }"#).add_variable("id", channel_id));
+
+    let res = tester.submit(query(changes).add_variable("cursor", cursor));
+    assert!(res["sensors"].as_array().unwrap().contains(&json!({ "id": sensor_id, "locX": 42 })));
+    assert!(!res["channels"].as_array().unwrap().contains(&json!({ "id": channel_id })));
+    assert!(res["deletedChannelIds"].as_array().unwrap().contains(&json!(channel_id)));
+
+    tester.submit_raw(query(changes).add_variable("cursor", "yesterday"))
+        .expect_service_error("BAD_REQUEST");
+
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_pending_actions() {
+    let mut tester = init_app_with(|data| {
+        data.approvals = Some(ApprovalPolicy { window: Duration::from_secs(3600) });
+    });
+    let mut second_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let username = create_random_username();
+    let admin_id = tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: ADMIN }) { id }
+    }"#).add_variable("username", username.clone()))["id"].to_i64();
+    second_tester.login(&username, "password41");
+
+    let delete_site = r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#;
+    let confirm = r#"mutation confirmPendingAction($id: Int!) {
+        confirmPendingAction(id: $id)
+    }"#;
+
+    // The site is only deleted after the confirmation of another admin
+    let errors = tester.submit_admin_raw(query(delete_site).add_variable("id", site_id)).unwrap_err();
+    let extensions = errors[0].extensions.clone().unwrap();
+    assert_eq!(extensions["type"], "APPROVAL_PENDING");
+    let action_id = extensions["pendingActionId"].to_i64();
+    tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#).add_variable("id", site_id));
+
+    let res = second_tester.submit_admin(query(r#"{
+        pendingActions { id, action, targetId, requestedBy { username } }
+    }"#));
+    assert!(res.as_array().unwrap().contains(&json!({
+        "id": action_id, "action": "DELETE_SITE", "targetId": site_id, "requestedBy": { "username": "root" }
+    })));
+
+    tester.submit_admin_raw(query(confirm).add_variable("id", action_id))
+        .expect_service_error("BAD_REQUEST");
+    assert_eq!(second_tester.submit_admin(query(confirm).add_variable("id", action_id)), true);
+    tester.submit_raw(query(r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#).add_variable("id", site_id)).expect_service_error("NOT_FOUND");
+    second_tester.submit_admin_raw(query(confirm).add_variable("id", action_id))
+        .expect_service_error("NOT_FOUND");
+
+    let res = tester.submit_admin(query(r#"{
+        auditLog(filter: { action: "confirmPendingAction" }) { username, target }
+    }"#));
+    assert_eq!(res[0], json!({ "username": username, "target": format!("pending action {} deleteSite {}", action_id, site_id) }));
+
+    // A cancelled action can't be confirmed
+    let user_id = tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: USER }) { id }
+    }"#).add_variable("username", create_random_username()))["id"].to_i64();
+    let errors = second_tester.submit_admin_raw(query(r#"mutation deleteUser($id: Int!) {
+        deleteUser(id: $id)
+    }"#).add_variable("id", user_id)).unwrap_err();
+    let action_id = errors[0].extensions.clone().unwrap()["pendingActionId"].to_i64();
+    assert_eq!(second_tester.submit_admin(query(r#"mutation cancelPendingAction($id: Int!) {
+        cancelPendingAction(id: $id)
+    }"#).add_variable("id", action_id)), true);
+    tester.submit_admin_raw(query(confirm).add_variable("id", action_id))
+        .expect_service_error("NOT_FOUND");
+
+    // Cleanup, the users can't be deleted without a second admin
+    let conn = tester.app_data().pool.get().unwrap();
+    diesel::sql_query(format!("DELETE FROM user_account WHERE id IN ({}, {})", admin_id, user_id))
+        .execute(&conn)
+        .unwrap();
+}
+
+#[test]
+fn test_site_config() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "Config museum", idCnr: "M1" }) { id }
+    }"#))["id"].to_i64();
+    let room_id = tester.submit(query(r#"mutation addRoom($siteId: Int!) {
+        addRoom(siteId: $siteId, data: { name: "Hall", floor: 1 }) { id }
+    }"#).add_variable("siteId", site_id))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($siteId: Int!, $roomId: Int!) {
+        addSensor(siteId: $siteId, data: { idCnr: "S1", name: "Case", locX: 3, locY: 4, roomId: $roomId }) { id }
+    }"#).add_variable("siteId", site_id).add_variable("roomId", room_id))["id"].to_i64();
+    tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: { idCnr: "T", measureUnit: "C", rangeMin: 18.5, rangeMax: 24, alarmDelay: 60 }) { id }
+    }"#).add_variable("sensorId", sensor_id));
+
+    let export = r#"query exportSiteConfig($siteId: Int!) {
+        exportSiteConfig(siteId: $siteId)
+    }"#;
+    let import = r#"mutation importSiteConfig($config: String!) {
+        importSiteConfig(config: $config) { id, name, sensors { idCnr, roomId, channels { rangeMin, rangeMax } } }
+    }"#;
+
+    let config = tester.submit(query(export).add_variable("siteId", site_id));
+    let config = config.as_str().unwrap().to_string();
+    let parsed: Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(parsed["rooms"], json!([{ "name": "Hall", "floor": 1, "loc_x": null, "loc_y": null }]));
+    assert_eq!(parsed["sensors"][0]["room"], json!(0));
+
+    let res = tester.submit_admin(query(import).add_variable("config", config.as_str()));
+    let copy_id = res["id"].to_i64();
+    assert_ne!(copy_id, site_id);
+    assert_eq!(res["name"], json!("Config museum"));
+    assert_ne!(res["sensors"][0]["roomId"], json!(room_id));
+    assert_eq!(res["sensors"][0]["channels"], json!([{ "rangeMin": 18.5, "rangeMax": 24.0 }]));
+
+    let copy = tester.submit(query(export).add_variable("siteId", copy_id));
+    assert_eq!(copy.as_str().unwrap(), config);
+
+    // Nothing is created when the config is invalid
+    let mut broken = parsed.clone();
+    broken["sensors"][0]["room"] = json!(5);
+    tester.submit_admin_raw(query(import).add_variable("config", broken.to_string()))
+        .expect_service_error("BAD_REQUEST");
+    let mut broken = parsed.clone();
+    broken["version"] = json!(99);
+    tester.submit_admin_raw(query(import).add_variable("config", broken.to_string()))
+        .expect_service_error("BAD_REQUEST");
+    tester.submit_admin_raw(query(import).add_variable("config", "{"))
+        .expect_service_error("BAD_REQUEST");
+    let sites = tester.submit(query(r#"{ sites { name } }"#));
+    assert_eq!(sites.as_array().unwrap().iter().filter(|x| x["name"] == json!("Config museum")).count(), 2);
+
+    for id in [site_id, copy_id] {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", id));
+    }
+}
+
+#[test]
+fn test_channel_flapping() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($siteId: Int!) {
+        addSensor(siteId: $siteId, data: {}) { id }
+    }"#).add_variable("siteId", site_id))["id"].to_i64();
+    let res = tester.submit_all(query(r#"mutation addChannels($sensorId: Int!) {
+        c1: addChannel(sensorId: $sensorId, data: {}) { id }
+        c2: addChannel(sensorId: $sensorId, data: {}) { id }
+    }"#).add_variable("sensorId", sensor_id));
+    let flapping_id = res["c1"]["id"].to_i64();
+    let stable_id = res["c2"]["id"].to_i64();
+
+    // Three alarms in the last hour for the first channel, one for the second
+    let now = NaiveDateTime::from_timestamp(1609000000, 0);
+    {
+        let conn = tester.app_data().pool.get().unwrap();
+        for (channel_id, started_at) in &[(flapping_id, 1608997000), (flapping_id, 1608998000), (flapping_id, 1608999000), (stable_id, 1608999000)] {
+            diesel::sql_query(format!(
+                "INSERT INTO alarm_event (channel_id, started_at, ended_at, peak_value, extreme_type) VALUES ({}, TO_TIMESTAMP({}), TO_TIMESTAMP({}), 30, 'M')",
+                channel_id, started_at, started_at + 300
+            )).execute(&conn).unwrap();
+        }
+    }
+
+    let policy = FlappingPolicy { max_alarms: 2, window: Duration::from_secs(3600) };
+    let data = tester.app_data();
+    let conn = data.pool.get().unwrap();
+    assert!(!block_on(check_flapping(&data.contacter, &conn, &policy, stable_id as i32, now)).unwrap());
+    assert!(block_on(check_flapping(&data.contacter, &conn, &policy, flapping_id as i32, now)).unwrap());
+    // Already marked, it stays suppressed
+    assert!(block_on(check_flapping(&data.contacter, &conn, &policy, flapping_id as i32, now + chrono::Duration::minutes(10))).unwrap());
+
+    let alarm_info = r#"query channel($id: Int!) {
+        channel(id: $id) { alarmInfo { alarmed, pendingSince, flapping, flappingSince } }
+    }"#;
+    let res = tester.submit(query(alarm_info).add_variable("id", flapping_id));
+    assert_eq!(res["alarmInfo"], json!({ "alarmed": false, "pendingSince": null, "flapping": true, "flappingSince": 1609000000.0 }));
+    let res = tester.submit(query(alarm_info).add_variable("id", stable_id));
+    assert_eq!(res["alarmInfo"]["flapping"], json!(false));
+
+    // Cleared only once a whole window passes without new alarms
+    assert_eq!(clear_flapping(&conn, &policy, site_id as i32, now + chrono::Duration::minutes(30)), Ok(0));
+    diesel::sql_query(format!(
+        "INSERT INTO alarm_event (channel_id, started_at, peak_value, extreme_type) VALUES ({}, TO_TIMESTAMP({}), 30, 'M')",
+        flapping_id, 1609003000
+    )).execute(&conn).unwrap();
+    assert_eq!(clear_flapping(&conn, &policy, site_id as i32, now + chrono::Duration::minutes(70)), Ok(0));
+    assert_eq!(clear_flapping(&conn, &policy, site_id as i32, now + chrono::Duration::minutes(120)), Ok(1));
+    let res = tester.submit(query(alarm_info).add_variable("id", flapping_id));
+    assert_eq!(res["alarmInfo"]["flappingSince"], json!(null));
+
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_warmup() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    // Recorded in the request log, the default sample rate records every request
+    tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { id }
+    }"#).add_variable("id", site_id));
+
+    let config = WarmupConfig { sensor_connections: 2, ..Default::default() };
+    let report = warm_up(tester.app_data(), &config, chrono::Utc::now().naive_utc());
+    assert_eq!(report.sensor_connections, 2);
+    assert!(report.users >= 1);
+    assert!(report.sites >= 1);
+
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_siem_forwarding() {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let mut tester = init_app_with(move |data| {
+        data.siem = SiemSink::new(SiemConfig { target: SiemTarget::Syslog(addr), format: SiemFormat::Json });
+    });
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+
+    let mut buffer = [0u8; 2048];
+    let len = socket.recv(&mut buffer).unwrap();
+    let frame = std::str::from_utf8(&buffer[..len]).unwrap();
+    // <local0.notice>1 timestamp hostname oldmusa pid - - message
+    assert!(frame.starts_with("<133>1 "), "{}", frame);
+    let message: Value = serde_json::from_str(&frame[frame.find('{').unwrap()..]).unwrap();
+    assert_eq!(message["type"], json!("audit"));
+    assert_eq!(message["username"], json!("root"));
+    assert_eq!(message["action"], json!("addSite"));
+    assert_eq!(message["target"], json!(format!("site {}", site_id)));
+
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_peer_groups() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_ids: Vec<i64> = (0..3).map(|_| {
+        tester.submit(query(r#"mutation addChannel($id: Int!) {
+            addChannel(sensorId: $id, data: {}) { id }
+        }"#).add_variable("id", sensor_id))["id"].to_i64()
+    }).collect();
+
+    let group_id = tester.submit_admin(query(r#"mutation {
+        addPeerGroup(name: "showrooms") { id }
+    }"#))["id"].to_i64();
+    for channel_id in channel_ids[0..2].iter() {
+        tester.submit_admin(query(r#"mutation addPeer($groupId: Int!, $channelId: Int!) {
+            addPeerGroupChannel(peerGroupId: $groupId, channelId: $channelId)
+        }"#).add_variable("groupId", group_id).add_variable("channelId", *channel_id));
+    }
+
+    let res = tester.submit_admin(query(r#"query {
+        peerGroups { id, name, channels { id } }
+    }"#));
+    assert!(res.as_array().unwrap().contains(&json!({
+        "id": group_id,
+        "name": "showrooms",
+        "channels": [{ "id": channel_ids[0] }, { "id": channel_ids[1] }],
+    })));
+
+    let compare_query = r#"query compare($channelId: Int!, $groupId: Int!) {
+        compareWithPeers(channelId: $channelId, peerGroupId: $groupId, start: 1577836800, end: 1580515200) { meanDifference }
+    }"#;
+
+    // No readings, nothing to compare
+    let res = tester.submit(query(compare_query).add_variable("channelId", channel_ids[0]).add_variable("groupId", group_id));
+    assert_eq!(res, json!(null));
+
+    tester.submit_raw(query(compare_query).add_variable("channelId", channel_ids[2]).add_variable("groupId", group_id))
+        .expect_service_error("BAD_REQUEST");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation cleanupPeerGroups($siteId: Int!, $groupId: Int!) {
+        a1: deletePeerGroup(id: $groupId)
+        a2: deleteSite(id: $siteId)
+    }"#).add_variable("siteId", site_id).add_variable("groupId", group_id));
+}
+
+#[test]
+fn test_reading_annotations() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    let annotation_id = tester.submit(query(r#"mutation annotate($id: Int!) {
+        addReadingAnnotation(channelId: $id, data: { startTime: 1577836800, endTime: 1577923200, reason: "Probe fault" }) { id }
+    }"#).add_variable("id", channel_id))["id"].to_i64();
+
+    tester.submit_raw(query(r#"mutation annotate($id: Int!) {
+        addReadingAnnotation(channelId: $id, data: { startTime: 1577923200, endTime: 1577836800, reason: "Calibration" }) { id }
+    }"#).add_variable("id", channel_id)).expect_service_error("BAD_REQUEST");
+
+    let annotations_query = r#"query annotations($id: Int!, $start: NaiveDateTime!, $end: NaiveDateTime!) {
+        channel(id: $id) {
+            annotations(start: $start, end: $end) { id, channelId, reason }
+            readings(start: $start, end: $end, includeInvalid: true) { date }
+        }
+    }"#;
+    let res = tester.submit(query(annotations_query)
+        .add_variable("id", channel_id)
+        .add_variable("start", 1577880000.0)
+        .add_variable("end", 1580515200.0));
+    assert_eq!(res, json!({
+        "annotations": [{ "id": annotation_id, "channelId": channel_id, "reason": "Probe fault" }],
+        "readings": [],
+    }));
+
+    // Outside of the annotated interval
+    let res = tester.submit(query(annotations_query)
+        .add_variable("id", channel_id)
+        .add_variable("start", 1580000000.0)
+        .add_variable("end", 1580515200.0));
+    assert_eq!(res["annotations"], json!([]));
+
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!(
+        "/api/export/channel/{}/readings?start=2020-01-01T00:00:00&end=2020-01-02T00:00:00&include_invalid=true",
+        channel_id
+    )));
+    assert_eq!(StatusCode::OK, res.0);
+
+    let res = tester.submit(query(r#"mutation deleteAnnotation($id: Int!) {
+        deleteReadingAnnotation(id: $id)
+    }"#).add_variable("id", annotation_id));
+    assert_eq!(res, json!(true));
+
+    tester.submit_raw(query(r#"mutation deleteAnnotation($id: Int!) {
+        deleteReadingAnnotation(id: $id)
+    }"#).add_variable("id", annotation_id)).expect_service_error("NOT_FOUND");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_manual_reading() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    // Without the cnr ids there's nowhere to store the reading
+    tester.submit_raw(query(r#"mutation manual($id: Int!) {
+        addManualReading(channelId: $id, timestamp: 1577836800, value: 21.5, note: "Verification") { id }
+    }"#).add_variable("id", channel_id)).expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit(query(r#"query manualReadings($id: Int!) {
+        channel(id: $id) { manualReadings(start: 0, end: 2000000000) { id } }
+    }"#).add_variable("id", channel_id));
+    assert_eq!(res, json!({ "manualReadings": [] }));
+
+    tester.submit_raw(query(r#"mutation {
+        addManualReading(channelId: -1, timestamp: 1577836800, value: 21.5) { id }
+    }"#)).expect_service_error("NOT_FOUND");
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_sensor_calibration() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+
+    let add_calibration = r#"mutation calibrate($id: Int!, $calibratedAt: NaiveDateTime!, $certificate: String!, $nextDueAt: NaiveDateTime!) {
+        addSensorCalibration(sensorId: $id, data: { calibratedAt: $calibratedAt, certificateId: $certificate, nextDueAt: $nextDueAt }) { id }
+    }"#;
+    // 2019-01-01 due 2020-01-01, then 2020-01-01 due 2021-01-01
+    tester.submit(query(add_calibration)
+        .add_variable("id", sensor_id)
+        .add_variable("calibratedAt", 1546300800.0)
+        .add_variable("certificate", "LAT-001")
+        .add_variable("nextDueAt", 1577836800.0));
+    let last_id = tester.submit(query(add_calibration)
+        .add_variable("id", sensor_id)
+        .add_variable("calibratedAt", 1577836800.0)
+        .add_variable("certificate", "LAT-002")
+        .add_variable("nextDueAt", 1609459200.0))["id"].to_i64();
+
+    tester.submit_raw(query(add_calibration)
+        .add_variable("id", sensor_id)
+        .add_variable("calibratedAt", 1609459200.0)
+        .add_variable("certificate", "LAT-003")
+        .add_variable("nextDueAt", 1577836800.0)).expect_service_error("BAD_REQUEST");
+
+    let res = tester.submit(query(r#"query calibrations($id: Int!) {
+        sensor(id: $id) { calibrations { certificateId } }
+    }"#).add_variable("id", sensor_id));
+    assert_eq!(res, json!({ "calibrations": [{ "certificateId": "LAT-002" }, { "certificateId": "LAT-001" }] }));
+
+    // Only the last calibration counts
+    let due_query = r#"query due($before: NaiveDateTime!) {
+        calibrationsDue(before: $before) { id, sensorId, reminderSent }
+    }"#;
+    let res = tester.submit_admin(query(due_query).add_variable("before", 1600000000.0));
+    assert!(!res.as_array().unwrap().iter().any(|x| x["sensorId"] == json!(sensor_id)));
+
+    let now = NaiveDateTime::from_timestamp(1609000000, 0);
+    let data = tester.app_data();
+    block_on(send_calibration_reminders(&data.contacter, &data.pool.get().unwrap(), now)).unwrap();
+    let res = tester.submit_admin(query(due_query).add_variable("before", 1609459200.0));
+    assert!(res.as_array().unwrap().contains(&json!({ "id": last_id, "sensorId": sensor_id, "reminderSent": true })));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_site_reports() {
+    let mut tester = init_app();
+    let mut user_tester = tester.clone();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "Report museum" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "Hall" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: { name: "Temperature", measureUnit: "C" }) { id }
+    }"#).add_variable("id", sensor_id));
+
+    let username = create_random_username();
+    tester.submit_admin(query(r#"mutation addUser($username: String!) {
+        addUser(data: { username: $username, password: "password41", permission: USER }) { id }
+    }"#).add_variable("username", username.clone()));
+    user_tester.login(&username, "password41");
+
+    let subscribe = r#"mutation subscribe($siteId: Int!, $period: ReportPeriod!, $format: ReportFormat!) {
+        subscribeSiteReport(siteId: $siteId, period: $period, format: $format) { id, siteId, period, format, lastPeriodEnd }
+    }"#;
+    // The site isn't visible to the user
+    user_tester.submit_raw(query(subscribe)
+        .add_variable("siteId", site_id)
+        .add_variable("period", "DAILY")
+        .add_variable("format", "HTML")).expect_service_error("NOT_FOUND");
+
+    tester.submit(query(subscribe)
+        .add_variable("siteId", site_id)
+        .add_variable("period", "DAILY")
+        .add_variable("format", "HTML"));
+    // Subscribing again only changes the format
+    let res = tester.submit(query(subscribe)
+        .add_variable("siteId", site_id)
+        .add_variable("period", "DAILY")
+        .add_variable("format", "PDF"));
+    let subscription_id = res["id"].to_i64();
+    assert_eq!(res, json!({ "id": subscription_id, "siteId": site_id, "period": "DAILY", "format": "PDF", "lastPeriodEnd": null }));
+
+    let res = tester.submit(query(r#"query preview($siteId: Int!) {
+        siteReportPreview(siteId: $siteId, period: WEEKLY)
+    }"#).add_variable("siteId", site_id));
+    let html = res.as_str().unwrap();
+    assert!(html.contains("Weekly report"));
+    assert!(html.contains("<td>Hall</td><td>Temperature</td><td>0</td><td>0h 00m</td>"));
+
+    // 2020-01-08 10:00, the daily report covers 2020-01-07
+    let now = NaiveDateTime::from_timestamp(1578477600, 0);
+    let data = tester.app_data();
+    let reports = prepare_due_reports(&data.pool, &data.sensor_pool, now).unwrap();
+    let report = reports.iter().find(|x| x.subscription_id == subscription_id as i32).unwrap();
+    assert_eq!(report.data.title, "Daily report 2020-01-07");
+    assert!(report.data.pdf.as_ref().unwrap().starts_with(b"%PDF-"));
+
+    block_on(send_reports(&data.contacter, &data.pool.get().unwrap(), reports)).unwrap();
+    let reports = prepare_due_reports(&data.pool, &data.sensor_pool, now).unwrap();
+    assert!(!reports.iter().any(|x| x.subscription_id == subscription_id as i32));
+
+    let my_subscriptions = r#"query { myReportSubscriptions { id, lastPeriodEnd } }"#;
+    let res = tester.submit(query(my_subscriptions));
+    assert!(res.as_array().unwrap().contains(&json!({ "id": subscription_id, "lastPeriodEnd": 1578441600.0 })));
+
+    let unsubscribe = r#"mutation unsubscribe($id: Int!) {
+        unsubscribeSiteReport(id: $id)
+    }"#;
+    user_tester.submit_raw(query(unsubscribe).add_variable("id", subscription_id))
+        .expect_service_error("NOT_FOUND");
+    tester.submit(query(unsubscribe).add_variable("id", subscription_id));
+    let res = tester.submit(query(my_subscriptions));
+    assert!(!res.as_array().unwrap().iter().any(|x| x["id"] == json!(subscription_id)));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+// TODO: test alarm controller
+
+#[test]
+fn test_system_status() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let res = tester.submit_admin(query(r#"query {
+        systemStatus { storage { directory, writable, freeBytes, healthy, error } }
+    }"#));
+    assert_eq!(res["storage"]["directory"], "site_maps");
+    assert_eq!(res["storage"]["writable"], true);
+    assert!(res["storage"]["freeBytes"].as_f64().unwrap() > 0.0);
+    assert!(res["storage"]["error"].is_null());
+
+    let missing = FilesystemStorage {
+        directory: "missing_site_maps".into(),
+        min_free_bytes: 0,
+    };
+    let status = missing.check();
+    assert!(!status.writable && !status.healthy());
+    assert!(status.error.is_some());
+}
+
+#[test]
+fn test_health_probes() {
+    let mut tester = init_app();
+
+    let res = tester.submit_raw_req(TestRequest::get().uri("/healthz"));
+    assert_eq!(StatusCode::OK, res.0);
+
+    let res = tester.submit_raw_req(TestRequest::get().uri("/readyz"));
+    assert_eq!(StatusCode::OK, res.0);
+    let body: Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body["postgres"], json!({ "ok": true }));
+    assert_eq!(body["sensor_database"], json!({ "ok": true }));
+    assert_eq!(body["fcm_enabled"], false);
+    assert!(check_readiness(tester.app_data()).is_ready());
+}
+
+#[test]
+fn test_cors() {
+    use actix_web::{App, HttpResponse, test, web};
+    use oldmusa_server::config::CorsConfig;
+    use oldmusa_server::web::cors::cors_middleware;
+
+    let config = CorsConfig {
+        allowed_origins: vec!["https://dashboard.example.com".to_string()],
+        allow_credentials: true,
+        max_age: 600,
+    };
+    let preflight = |origin: &str| TestRequest::with_uri("/api/graphql")
+        .method(actix_web::http::Method::OPTIONS)
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .to_request();
+
+    actix_rt::System::new("test_cors").block_on(async move {
+        let mut service = test::init_service(App::new()
+            .wrap(cors_middleware(&config))
+            .route("/api/graphql", web::post().to(|| HttpResponse::Ok())))
+            .await;
+
+        let res = test::call_service(&mut service, preflight("https://dashboard.example.com")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://dashboard.example.com");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        let res = test::call_service(&mut service, preflight("https://evil.example.com")).await;
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        // Without origins the middleware is disabled
+        let mut service = test::init_service(App::new()
+            .wrap(cors_middleware(&CorsConfig { allowed_origins: vec![], ..config }))
+            .route("/api/graphql", web::post().to(|| HttpResponse::Ok())))
+            .await;
+        let res = test::call_service(&mut service, TestRequest::post().uri("/api/graphql")
+            .header(header::ORIGIN, "https://dashboard.example.com")
+            .to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    });
+}
+
+#[test]
+fn test_sample_readings() {
+    let sample = r#"query sampleReadings($id: Int!, $n: Int) {
+        sampleReadings(channelId: $id, n: $n) { date, valueMin }
+    }"#;
+
+    let mut tester = init_app();
+    tester.login_root();
+    tester.submit_raw(query(sample).add_variable("id", 1)).expect_service_error("BAD_REQUEST");
+
+    let mut tester = init_app_with(|data| data.dev_mode = true);
+    tester.login_root();
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($id: Int!) {
+        addChannel(sensorId: $id, data: {}) { id }
+    }"#).add_variable("id", sensor_id))["id"].to_i64();
+
+    // Without cnr ids the channel has no readings
+    assert_eq!(tester.submit(query(sample).add_variable("id", channel_id)), json!([]));
+    tester.submit_raw(query(sample).add_variable("id", channel_id).add_variable("n", 1000))
+        .expect_service_error("BAD_REQUEST");
+
+    // The playground starts with a query on existing ids
+    let (status, body) = tester.submit_raw_req(TestRequest::get().uri("/api/graphiql"));
+    assert_eq!(status, StatusCode::OK);
+    let html = String::from_utf8(body.to_vec()).unwrap();
+    assert!(html.contains("defaultQuery: "));
+    assert!(html.contains("sampleReadings(channelId: "));
+}
+
+#[test]
+fn test_image_resize() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    // Create site
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let site_map_uri = format!("/api/site_map/{}", site_id);
+
+    let first_image = png_image(384, 216);
+    let res = tester.submit_raw_req(
+        TestRequest::post()
+            .uri(&format!("{}?width={}&height={}", site_map_uri, 3840, 2160))
+            .header(header::CONTENT_TYPE, "image/png")
+            .set_payload(first_image.clone())
+    );
+    assert_eq!(StatusCode::OK, res.0);
+
+    // Create sensors
+    let res = tester.submit_all(
+        query(r#"mutation createSensorsTIR($siteId: Int!) {
+            s1: addSensor(siteId: $siteId, data: { locX: 10,  locY: 20  }) { id }
+            s2: addSensor(siteId: $siteId, data: { locX: 2,   locY: 3   }) { id }
+            s3: addSensor(siteId: $siteId, data: { locX: 413, locY: 125 }) { id }
+        }"#)
+            .add_variable("siteId", site_id)
+    );
+    let s1 = res["s1"]["id"].to_i64();
+    let s2 = res["s2"]["id"].to_i64();
+    let s3 = res["s3"]["id"].to_i64();
+
+    let res = tester.submit_raw_req(TestRequest::get().uri(&site_map_uri));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(first_image, res.1);
+
+    let res = tester.submit_raw_req(
+        TestRequest::post()
+            .uri(&format!("{}?width={}&height={}", site_map_uri, 7680, 4320))
+            .header(header::CONTENT_TYPE, "image/png")
+            .set_payload(png_image(768, 432))
+    );
+    assert_eq!(StatusCode::OK, res.0);
+
+    // Check that the images have been resized
+    let res = tester.submit(
+        query(r#"
+        query querySitesTIR($siteId: Int!) {
+            site(id: $siteId) {
+                sensors {
+                    id, locX, locY
+                }
+            }
+        }"#).add_variable("siteId", site_id)
+    );
+    assert_eq_set(
+        json!([
+            { "id": s1, "locX": 20, "locY": 40 },
+            { "id": s2, "locX": 4, "locY": 6 },
+            { "id": s3, "locX": 826, "locY": 250 }
+        ]),
+        res["sensors"].clone()
+    );
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_map_image_validation() {
+    let mut tester = init_app_with(|data| {
+        data.map_images.max_dimension = 1000;
+        data.map_images.max_bytes = 100_000;
+        data.map_images.thumbnail_size = 64;
+    });
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let site_map_uri = format!("/api/site_map/{}", site_id);
+    let upload = |content_type: &str, payload: Vec<u8>| {
+        TestRequest::post()
+            .uri(&site_map_uri)
+            .header(header::CONTENT_TYPE, content_type)
+            .set_payload(payload)
+    };
+
+    assert_eq!(StatusCode::BAD_REQUEST, tester.submit_raw_req(upload("text/plain", png_image(10, 10))).0);
+    assert_eq!(StatusCode::BAD_REQUEST, tester.submit_raw_req(upload("image/png", b"not an image".to_vec())).0);
+    assert_eq!(StatusCode::BAD_REQUEST, tester.submit_raw_req(upload("image/png", png_image(1200, 10))).0);
+    assert_eq!(StatusCode::BAD_REQUEST, tester.submit_raw_req(upload("image/png", vec![0; 200_000])).0);
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { hasImage }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res["hasImage"], false);
+
+    // Without a size the one of the image is used
+    assert_eq!(StatusCode::OK, tester.submit_raw_req(upload("image/png", png_image(400, 200))).0);
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { imageWidth, imageHeight, hasImage }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res, json!({ "imageWidth": 400, "imageHeight": 200, "hasImage": true }));
+
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!("{}?thumbnail=true", site_map_uri)));
+    assert_eq!(StatusCode::OK, res.0);
+    let thumbnail = image::load_from_memory(&res.1).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (64, 32));
+
+    assert_eq!(StatusCode::NO_CONTENT, tester.submit_raw_req(TestRequest::delete().uri(&site_map_uri)).0);
+    let res = tester.submit_raw_req(TestRequest::get().uri(&format!("{}?thumbnail=true", site_map_uri)));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_site_maps() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_ids: Vec<i64> = (0..2).map(|_| {
+        tester.submit_admin(query(r#"mutation {
+            addSite(data: {}) { id }
+        }"#))["id"].to_i64()
+    }).collect();
+    let add_map = r#"mutation addSiteMap($siteId: Int!) {
+        addSiteMap(siteId: $siteId, data: { name: "First floor" }) { id, siteId, name, imageWidth, hasImage }
+    }"#;
+    let map = tester.submit(query(add_map).add_variable("siteId", site_ids[0]));
+    let map_id = map["id"].to_i64();
+    assert_eq!(map, json!({ "id": map_id, "siteId": site_ids[0], "name": "First floor", "imageWidth": null, "hasImage": false }));
+    let other_map_id = tester.submit(query(add_map).add_variable("siteId", site_ids[1]))["id"].to_i64();
+
+    let map_uri = format!("/api/site_map/{}/{}", site_ids[0], map_id);
+    let upload = |uri: &str, width: i32, payload: Vec<u8>| {
+        TestRequest::post()
+            .uri(&format!("{}?width={}&height={}", uri, width, width))
+            .header(header::CONTENT_TYPE, "image/png")
+            .set_payload(payload)
+    };
+    assert_eq!(StatusCode::OK, tester.submit_raw_req(upload(&map_uri, 100, png_image(10, 10))).0);
+    // The map must be one of the site's
+    let other_uri = format!("/api/site_map/{}/{}", site_ids[0], other_map_id);
+    assert_eq!(StatusCode::NOT_FOUND, tester.submit_raw_req(upload(&other_uri, 100, png_image(10, 10))).0);
+
+    let res = tester.submit_all(query(r#"mutation createSensors($siteId: Int!, $mapId: Int!) {
+        s1: addSensor(siteId: $siteId, data: { locX: 10, locY: 20, mapId: $mapId }) { id, mapId }
+        s2: addSensor(siteId: $siteId, data: { locX: 10, locY: 20 }) { id, mapId }
+    }"#).add_variable("siteId", site_ids[0]).add_variable("mapId", map_id));
+    let s1 = res["s1"]["id"].to_i64();
+    let s2 = res["s2"]["id"].to_i64();
+    assert_eq!(res["s1"]["mapId"], map_id);
+    tester.submit_raw(query(r#"mutation updateSensor($id: Int!, $mapId: Int!) {
+        updateSensor(id: $id, data: { mapId: $mapId }) { id }
+    }"#).add_variable("id", s2).add_variable("mapId", other_map_id))
+        .expect_service_error("BAD_REQUEST");
+
+    // Only the sensors placed on the map are moved
+    let bigger_image = png_image(20, 20);
+    assert_eq!(StatusCode::OK, tester.submit_raw_req(upload(&map_uri, 200, bigger_image.clone())).0);
+    let res = tester.submit_raw_req(TestRequest::get().uri(&map_uri));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(bigger_image, res.1);
+    let res = tester.submit(query(r#"query site($id: Int!) {
+        site(id: $id) { maps { imageWidth, hasImage, sensors { id } }, sensors { id, locX } }
+    }"#).add_variable("id", site_ids[0]));
+    assert_eq!(res["maps"], json!([{ "imageWidth": 200, "hasImage": true, "sensors": [{ "id": s1 }] }]));
+    assert_eq_set(json!([{ "id": s1, "locX": 20 }, { "id": s2, "locX": 10 }]), res["sensors"].clone());
+
+    let res = tester.submit(query(r#"mutation deleteSiteMap($id: Int!) {
+        deleteSiteMap(id: $id)
+    }"#).add_variable("id", map_id));
+    assert_eq!(res, true);
+    assert!(!tester.app_data().site_maps.exists(&get_file_from_site_map(site_ids[0] as i32, map_id as i32)).unwrap());
+    let res = tester.submit(query(r#"query sensor($id: Int!) {
+        sensor(id: $id) { mapId, locX }
+    }"#).add_variable("id", s1));
+    assert_eq!(res, json!({ "mapId": null, "locX": null }));
+    assert_eq!(StatusCode::NOT_FOUND, tester.submit_raw_req(TestRequest::get().uri(&map_uri)).0);
+
+    // Cleanup
+    for site_id in site_ids {
+        tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+            deleteSite(id: $id)
+        }"#).add_variable("id", site_id));
+    }
+}
+
+#[test]
+fn test_public_site_token() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "lobby" }) { id }
+    }"#))["id"].to_i64();
+
+    let token = tester.submit(query(r#"mutation regenerateToken($id: Int!) {
+        regenerateSitePublicToken(id: $id)
+    }"#).add_variable("id", site_id)).to_str().to_string();
+
+    let res = tester.submit(query(r#"query siteToken($id: Int!) {
+        site(id: $id) { publicToken }
+    }"#).add_variable("id", site_id));
+    assert_eq!(res["publicToken"], token);
+
+    // The endpoint doesn't need any login
+    let mut anon_tester = init_app();
+    let current_uri = format!("/api/public/site/{}/current", token);
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&current_uri));
+    assert_eq!(StatusCode::OK, res.0);
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body, json!({ "name": "lobby", "rooms": [] }));
+
+    tester.submit(query(r#"mutation revokeToken($id: Int!) {
+        revokeSitePublicToken(id: $id)
+    }"#).add_variable("id", site_id));
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&current_uri));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|x| if x.is_ascii_alphanumeric() { (x as char).to_string() } else { format!("%{:02X}", x) })
+        .collect()
+}
+
+#[test]
+fn test_graphql_get() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "widget" }) { id }
+    }"#))["id"].to_i64();
+    let token = tester.submit(query(r#"mutation regenerateToken($id: Int!) {
+        regenerateSitePublicToken(id: $id)
+    }"#).add_variable("id", site_id)).to_str().to_string();
+
+    let mut anon_tester = init_app();
+    let public_query = "query publicSite($token: String!) { publicSite(token: $token) { name, rooms { name } } }";
+    let variables = url_encode(&json!({ "token": token }).to_string());
+    let uri = format!("/api/graphql?query={}&variables={}", url_encode(public_query), variables);
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&uri));
+    assert_eq!(StatusCode::OK, res.0);
+    let expected = json!({ "data": { "publicSite": { "name": "widget", "rooms": [] } } });
+    assert_eq!(serde_json::from_slice::<Value>(res.1.as_ref()).unwrap(), expected);
+
+    // Conditional request
+    let etag = body_etag(res.1.as_ref());
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&uri).header(header::IF_NONE_MATCH, etag));
+    assert_eq!(StatusCode::NOT_MODIFIED, res.0);
+
+    // Persisted query, it must be registered with the full query first
+    let extensions = url_encode(&json!({ "persistedQuery": { "version": 1, "sha256Hash": query_hash(public_query) } }).to_string());
+    let hashed_uri = format!("/api/graphql?extensions={}&variables={}", extensions, variables);
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&hashed_uri));
+    assert_eq!(serde_json::from_slice::<Value>(res.1.as_ref()).unwrap()["errors"][0]["message"], "PersistedQueryNotFound");
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("{}&query={}", hashed_uri, url_encode(public_query))));
+    assert_eq!(StatusCode::OK, res.0);
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&hashed_uri));
+    assert_eq!(serde_json::from_slice::<Value>(res.1.as_ref()).unwrap(), expected);
+
+    let wrong_hash_uri = format!("{}&query={}", hashed_uri, url_encode("{ apiVersion }"));
+    assert_eq!(StatusCode::BAD_REQUEST, anon_tester.submit_raw_req(TestRequest::get().uri(&wrong_hash_uri)).0);
+
+    // Only the queries can be sent with GET
+    let mutation_uri = format!("/api/graphql?query={}", url_encode("mutation { logout }"));
+    assert_eq!(StatusCode::BAD_REQUEST, tester.submit_raw_req(TestRequest::get().uri(&mutation_uri)).0);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_channel_chart() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: {}) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: {}) { id }
+    }"#).add_variable("sensorId", sensor_id))["id"].to_i64();
+
+    let chart_uri = format!(
+        "/api/chart/channel/{}.png?start=2020-01-01T00:00:00&end=2020-01-02T00:00:00&width=64&height=32",
+        channel_id
+    );
+    let res = tester.submit_raw_req(TestRequest::get().uri(&chart_uri));
+    assert_eq!(StatusCode::OK, res.0);
+    assert_eq!(&res.1[1..4], b"PNG");
+
+    // Anonymous users need the public token of the site
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&chart_uri));
+    assert_eq!(StatusCode::UNAUTHORIZED, res.0);
+
+    let token = tester.submit(query(r#"mutation regenerateToken($id: Int!) {
+        regenerateSitePublicToken(id: $id)
+    }"#).add_variable("id", site_id)).to_str().to_string();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("{}&token={}", chart_uri, token)));
+    assert_eq!(StatusCode::OK, res.0);
+
+    // The channels of a trashed site have no chart
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    let res = tester.submit_raw_req(TestRequest::get().uri(&chart_uri));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+}
+
+#[test]
+fn test_sensor_pagination() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: {}) { id }
+    }"#))["id"].to_i64();
+
+    let sensor_ids: Vec<i64> = (0..5).map(|_| {
+        tester.submit(query(r#"mutation addSensor($id: Int!) {
+            addSensor(siteId: $id, data: {}) { id }
+        }"#).add_variable("id", site_id))["id"].to_i64()
+    }).collect();
+
+    let page_query = r#"query sensorPage($id: Int!, $first: Int, $after: String, $last: Int, $before: String) {
+        site(id: $id) {
+            sensorPage(first: $first, after: $after, last: $last, before: $before) {
+                nodes { id }
+                pageInfo { hasNextPage, hasPreviousPage, startCursor, endCursor }
+            }
+        }
+    }"#;
+
+    let res = tester.submit(query(page_query).add_variable("id", site_id).add_variable("first", 2));
+    let page = &res["sensorPage"];
+    assert_eq!(page["nodes"], json!([{ "id": sensor_ids[0] }, { "id": sensor_ids[1] }]));
+    assert_eq!(page["pageInfo"]["hasNextPage"], true);
+    assert_eq!(page["pageInfo"]["hasPreviousPage"], false);
+
+    let end_cursor = page["pageInfo"]["endCursor"].to_str().to_string();
+    let res = tester.submit(query(page_query).add_variable("id", site_id)
+        .add_variable("first", 10).add_variable("after", end_cursor));
+    let page = &res["sensorPage"];
+    assert_eq!(page["nodes"], json!([{ "id": sensor_ids[2] }, { "id": sensor_ids[3] }, { "id": sensor_ids[4] }]));
+    assert_eq!(page["pageInfo"]["hasNextPage"], false);
+    assert_eq!(page["pageInfo"]["hasPreviousPage"], true);
+
+    let res = tester.submit(query(page_query).add_variable("id", site_id).add_variable("last", 2));
+    let page = &res["sensorPage"];
+    assert_eq!(page["nodes"], json!([{ "id": sensor_ids[3] }, { "id": sensor_ids[4] }]));
+    assert_eq!(page["pageInfo"]["hasPreviousPage"], true);
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+#[test]
+fn test_site_calendar() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "museum" }) { id }
+    }"#))["id"].to_i64();
+
+    tester.submit(query(r#"mutation addMaintenance($id: Int!) {
+        addMaintenanceWindow(siteId: $id, data: {
+            startTime: 1583827200, endTime: 1583841600, description: "Sensor battery swap"
+        }) { id }
+    }"#).add_variable("id", site_id));
+
+    let calendar_url = tester.submit(query(r#"query calendarUrl($id: Int!) {
+        site(id: $id) { calendarUrl }
+    }"#).add_variable("id", site_id))["calendarUrl"].to_str().to_string();
+
+    let mut anon_tester = init_app();
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&calendar_url));
+    assert_eq!(StatusCode::OK, res.0);
+    let body = std::str::from_utf8(res.1.as_ref()).unwrap();
+    assert!(body.starts_with("BEGIN:VCALENDAR\r\n"));
+    assert!(body.contains("DTSTART:20200310T080000Z\r\n"));
+    assert!(body.contains("DESCRIPTION:Sensor battery swap\r\n"));
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&format!("/api/calendar/site/{}.ics?signature=00", site_id)));
+    assert_eq!(StatusCode::FORBIDDEN, res.0);
+
+    // The feed of a site in the trash is gone (this also cleans up)
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri(&calendar_url));
+    assert_eq!(StatusCode::NOT_FOUND, res.0);
+}
+
+#[test]
+fn test_grafana_datasource() {
+    let mut tester = init_app();
+    tester.login_root();
+
+    let site_id = tester.submit_admin(query(r#"mutation {
+        addSite(data: { name: "grafana" }) { id }
+    }"#))["id"].to_i64();
+    let sensor_id = tester.submit(query(r#"mutation addSensor($id: Int!) {
+        addSensor(siteId: $id, data: { name: "room" }) { id }
+    }"#).add_variable("id", site_id))["id"].to_i64();
+    let channel_id = tester.submit(query(r#"mutation addChannel($sensorId: Int!) {
+        addChannel(sensorId: $sensorId, data: { name: "temperature" }) { id }
+    }"#).add_variable("sensorId", sensor_id))["id"].to_i64();
+
+    let mut anon_tester = init_app();
+    let auth = format!("Basic {}", base64::encode("root:password"));
+
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri("/api/grafana/"));
+    assert_eq!(StatusCode::UNAUTHORIZED, res.0);
+    let res = anon_tester.submit_raw_req(TestRequest::get().uri("/api/grafana/").header(header::AUTHORIZATION, auth.as_str()));
+    assert_eq!(StatusCode::OK, res.0);
+
+    let res = anon_tester.submit_raw_req(TestRequest::post().uri("/api/grafana/search")
+        .header(header::AUTHORIZATION, auth.as_str())
+        .set_json(&json!({ "target": "" })));
+    assert_eq!(StatusCode::OK, res.0);
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert!(body.as_array().unwrap().contains(&json!({
+        "text": "grafana / room / temperature",
+        "value": channel_id.to_string(),
+    })));
+
+    let res = anon_tester.submit_raw_req(TestRequest::post().uri("/api/grafana/query")
+        .header(header::AUTHORIZATION, auth.as_str())
+        .set_json(&json!({
+            "range": { "from": "2020-01-01T00:00:00Z", "to": "2020-01-02T00:00:00Z" },
+            "targets": [{ "target": channel_id.to_string() }],
+            "maxDataPoints": 100,
+        })));
+    assert_eq!(StatusCode::OK, res.0);
+    let body: serde_json::Value = serde_json::from_slice(res.1.as_ref()).unwrap();
+    assert_eq!(body, json!([{ "target": channel_id.to_string(), "datapoints": [] }]));
+
+    // Cleanup
+    tester.submit_admin(query(r#"mutation deleteSite($id: Int!) {
+        deleteSite(id: $id)
+    }"#).add_variable("id", site_id));
+}
+
+/// Regenerates the schema snapshot compared by schemaChanges, run it before every release
+#[test]
+#[ignore]
+fn update_schema_snapshot() {
+    let tester = init_app();
+    let data = std::sync::Arc::new(tester.app_data().clone());
+    let ctx = Context::new(data.clone(), None, None, 0, 0);
+
+    let snapshot = current_snapshot(&data.graphql_schema, &ctx).unwrap();
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/web/schema_snapshot.json");
+    std::fs::write(path, serde_json::to_string_pretty(&snapshot).unwrap() + "\n").unwrap();
+}
@@ -0,0 +1,163 @@
+//! Runs the queries in tests/snapshots against the fixtures and compares the results (and the
+//! database connections taken by the resolvers) with the saved snapshots, so that a change in the
+//! output or a new N+1 query pattern shows up in the diff.
+//! The connections are counted twice: the ones asked to the context (`connections`) and the
+//! ones checked out of the pool by the resolvers that skip the context (`checkouts`).
+//!
+//! The queries named viewer_* are run by a user with view access to the first site, the other ones
+//! by an admin. Every query runs in a transaction that is rolled back, so mutations don't change the
+//! fixtures seen by the next ones.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::event::{CheckoutEvent, HandleEvent};
+use juniper::Variables;
+use serde_json::{json, Value};
+
+use oldmusa_server::{AppData, contact};
+use oldmusa_server::models::{IdType, User};
+use oldmusa_server::web::errors::ServiceError;
+use oldmusa_server::web::graphql_schema::Context;
+
+const ADMIN_ID: IdType = 900001;
+const VIEWER_ID: IdType = 900002;
+
+/// Every fixture has an id between 900000 and 900099. The rows pointing to them are found in the
+/// foreign keys of the schema, so the ones kept by ON DELETE SET NULL (ex. the audit log) don't
+/// outlive the fixtures and a new table doesn't need to be listed here.
+const CLEANUP: &str = r#"
+    DO $$
+    DECLARE fk record;
+    BEGIN
+        FOR fk IN
+            SELECT c.conrelid::regclass AS referencing, a.attname AS column_name
+            FROM pg_constraint c
+            JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = c.conkey[1]
+            WHERE c.contype = 'f'
+              AND c.confrelid = ANY(ARRAY['channel', 'sensor', 'site', 'user_account']::regclass[])
+        LOOP
+            EXECUTE format('DELETE FROM %s WHERE %I BETWEEN 900000 AND 900099', fk.referencing, fk.column_name);
+        END LOOP;
+        DELETE FROM channel WHERE id BETWEEN 900000 AND 900099;
+        DELETE FROM sensor WHERE id BETWEEN 900000 AND 900099;
+        DELETE FROM site WHERE id BETWEEN 900000 AND 900099;
+        DELETE FROM user_account WHERE id BETWEEN 900000 AND 900099;
+    END $$;
+"#;
+
+/// Counts the connections checked out of the pool
+#[derive(Debug)]
+struct CheckoutCounter(Arc<AtomicI64>);
+
+impl HandleEvent for CheckoutCounter {
+    fn handle_checkout(&self, _event: CheckoutEvent) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn snapshot_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots")
+}
+
+fn init_data(checkouts: &Arc<AtomicI64>) -> Arc<AppData> {
+    dotenv::dotenv().ok();
+    let database_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let sensor_database_url = std::env::var("SENSOR_DATABASE_URL").expect("SENSOR_DATABASE_URL must be set");
+    let mut data = AppData::new("a".repeat(32), database_url.clone(), sensor_database_url, contact::Contacter::new(None, None), None);
+    data.pool = Pool::builder()
+        .event_handler(Box::new(CheckoutCounter(checkouts.clone())))
+        .build(ConnectionManager::new(database_url))
+        .unwrap();
+    data.setup_migrations().unwrap();
+    Arc::new(data)
+}
+
+fn run_query(data: &Arc<AppData>, checkouts: &AtomicI64, name: &str, source: &str) -> Value {
+    use oldmusa_server::schema::user_account::dsl;
+
+    let user_id = if name.starts_with("viewer_") { VIEWER_ID } else { ADMIN_ID };
+    let user = dsl::user_account.find(user_id)
+        .first::<User>(&data.pool.get().unwrap())
+        .unwrap();
+    let ctx = Context::new(data.clone(), None, Some(user), 1_000_000, 1_000_000);
+
+    let mut snapshot = None;
+    let res = ctx.transaction(|| {
+        // The connection of the transaction has already been checked out
+        let before = ctx.connection_count();
+        let checkouts_before = checkouts.load(Ordering::SeqCst);
+        let (value, errors) = juniper::execute(source, None, &*data.graphql_schema, &Variables::new(), &ctx)
+            .unwrap_or_else(|err| panic!("Invalid query {}: {:?}", name, err));
+        let after = ctx.connection_count();
+        let checkouts_after = checkouts.load(Ordering::SeqCst);
+
+        let errors: Vec<String> = errors.iter()
+            .map(|x| x.error().message().to_string())
+            .collect();
+        snapshot = Some(json!({
+            "data": serde_json::to_value(&value).unwrap(),
+            "errors": errors,
+            "connections": after - before,
+            "checkouts": checkouts_after - checkouts_before,
+        }));
+        Err::<(), _>(ServiceError::InternalServerError("rollback".to_string()))
+    });
+    assert!(res.is_err());
+    snapshot.unwrap()
+}
+
+/// Runs every query of the corpus, returns the name of the snapshot file, the expected and the
+/// current result
+fn run_corpus() -> Vec<(PathBuf, Option<Value>, Value)> {
+    let checkouts = Arc::new(AtomicI64::new(0));
+    let data = init_data(&checkouts);
+    let conn = data.pool.get().unwrap();
+    conn.batch_execute(CLEANUP).unwrap();
+    conn.batch_execute(&std::fs::read_to_string(snapshot_dir().join("fixtures.sql")).unwrap()).unwrap();
+
+    let mut queries: Vec<PathBuf> = std::fs::read_dir(snapshot_dir()).unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().map_or(false, |x| x == "graphql"))
+        .collect();
+    queries.sort();
+
+    let results = queries.iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let source = std::fs::read_to_string(path).unwrap();
+            let snapshot_path = path.with_extension("json");
+            let expected = std::fs::read_to_string(&snapshot_path).ok()
+                .map(|x| serde_json::from_str::<Value>(&x).unwrap());
+            (snapshot_path, expected, run_query(&data, &checkouts, name, &source))
+        })
+        .collect();
+
+    conn.batch_execute(CLEANUP).unwrap();
+    results
+}
+
+#[test]
+fn test_query_snapshots() {
+    let mismatches: Vec<String> = run_corpus().into_iter()
+        .filter(|(_, expected, current)| expected.as_ref() != Some(current))
+        .map(|(path, _, current)| format!("{}:\n{}", path.display(), serde_json::to_string_pretty(&current).unwrap()))
+        .collect();
+
+    if !mismatches.is_empty() {
+        panic!("Snapshots not matching (run update_query_snapshots if the change is expected):\n{}", mismatches.join("\n"));
+    }
+}
+
+/// Regenerates the snapshots of the query corpus, review the diff before committing them
+#[test]
+#[ignore]
+fn update_query_snapshots() {
+    for (path, _, current) in run_corpus() {
+        std::fs::write(path, serde_json::to_string_pretty(&current).unwrap() + "\n").unwrap();
+    }
+}
@@ -0,0 +1,26 @@
+-- Fixtures of the query snapshots, the ids are fixed (and far from the ones used by the other
+-- tests) so that they can appear in the snapshots. They're deleted after the run.
+INSERT INTO user_account (id, username, password_hash, last_password_change, permission) VALUES
+	(900001, 'snapshot_admin', '-', TO_TIMESTAMP(1577836800), 'admin'),
+	(900002, 'snapshot_viewer', '-', TO_TIMESTAMP(1577836800), 'user');
+
+INSERT INTO site (id, name, clock) VALUES
+	(900001, 'Snapshot museum', TO_TIMESTAMP(1577836800)),
+	(900002, 'Snapshot archive', TO_TIMESTAMP(1577836800));
+
+INSERT INTO user_access (user_id, site_id, level) VALUES (900002, 900001, 'view');
+
+INSERT INTO sensor (id, site_id, name, loc_x, loc_y, enabled) VALUES
+	(900011, 900001, 'Hall', 10, 20, TRUE),
+	(900012, 900001, 'Storage', 30, 40, FALSE),
+	(900013, 900002, 'Reading room', NULL, NULL, TRUE);
+
+INSERT INTO channel (id, sensor_id, name, measure_unit, range_min, range_max, alarmed) VALUES
+	(900021, 900011, 'Temperature', '°C', 18, 24, TRUE),
+	(900022, 900011, 'Humidity', '%', 40, 60, FALSE),
+	(900023, 900012, 'Temperature', '°C', NULL, NULL, FALSE),
+	(900024, 900013, 'Light', 'lx', NULL, 50, FALSE);
+
+INSERT INTO alarm_event (channel_id, started_at, ended_at, peak_value, extreme_type) VALUES
+	(900021, TO_TIMESTAMP(1577840400), TO_TIMESTAMP(1577844000), 26.5, 'M'),
+	(900021, TO_TIMESTAMP(1577930400), NULL, 17, 'm');